@@ -42,6 +42,11 @@ pub struct FillLog {
 
     pub price: i64,
     pub quantity: i64, // number of base lots
+
+    // Breakdown of the fee rates above. referrer_fee is the referral surcharge or share included
+    // in taker_fee; maker_rebate is -maker_fee when the maker is paid a rebate, else 0
+    pub referrer_fee: i128, // I80F48
+    pub maker_rebate: i128, // I80F48
 }
 
 #[event]
@@ -53,6 +58,24 @@ pub struct TokenBalanceLog {
     pub borrow: i128,
 }
 
+/// Like TokenBalanceLog, but with deposit and borrow in native units of the token
+#[event]
+pub struct NativeTokenBalanceLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub token_index: u64,
+    pub native_deposit: i128, // I80F48
+    pub native_borrow: i128,  // I80F48
+}
+
+#[event]
+pub struct TokenPositionFlipLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub token_index: u64,
+    pub is_borrow: bool, // true if the position went from net deposit to net borrow
+}
+
 #[event]
 pub struct CachePricesLog {
     pub lyrae_group: Pubkey,
@@ -90,6 +113,8 @@ pub struct SettleFeesLog {
     pub lyrae_account: Pubkey,
     pub market_index: u64,
     pub settlement: i128, // I80F48
+    pub to_fees_vault: u64,
+    pub to_insurance_vault: u64,
 }
 
 #[event]
@@ -104,6 +129,8 @@ pub struct LiquidateTokenAndTokenLog {
     pub asset_price: i128,    // I80F48
     pub liab_price: i128,     // I80F48
     pub bankruptcy: bool,
+    pub asset_liquidation_fee: i128, // I80F48; after applying the group's min_liquidation_fee
+    pub liab_liquidation_fee: i128,  // I80F48
 }
 
 #[event]
@@ -120,6 +147,21 @@ pub struct LiquidateTokenAndPerpLog {
     pub asset_transfer: i128, // I80F48
     pub liab_transfer: i128,  // I80F48
     pub bankruptcy: bool,
+    pub liquidation_fee: i128, // I80F48; fee of the token side after the group's floor
+}
+
+/// Emitted when consume_events pops a LiquidateEvent; mirrors its fields
+#[event]
+pub struct LiquidateEventLog {
+    pub lyrae_group: Pubkey,
+    pub market_index: u64,
+    pub timestamp: u64,
+    pub seq_num: u64,
+    pub liqee: Pubkey,
+    pub liqor: Pubkey,
+    pub price: i128,           // I80F48
+    pub quantity: i64,         // number of base lots moved from liqee to liqor
+    pub liquidation_fee: i128, // I80F48
 }
 
 #[event]
@@ -132,6 +174,13 @@ pub struct LiquidatePerpMarketLog {
     pub base_transfer: i64,
     pub quote_transfer: i128, // I80F48
     pub bankruptcy: bool,
+    pub liquidation_fee: i128, // I80F48; after applying the group's min_liquidation_fee
+}
+
+#[event]
+pub struct RemoveSpotMarketLog {
+    pub lyrae_group: Pubkey,
+    pub market_index: u64,
 }
 
 #[event]
@@ -159,6 +208,22 @@ pub struct TokenBankruptcyLog {
     pub cache_deposit_index: i128, // I80F48
 }
 
+/// Insurance vault balance before and after a bankruptcy resolution drew from it
+#[event]
+pub struct InsuranceFundLog {
+    pub lyrae_group: Pubkey,
+    pub insurance_vault: Pubkey,
+    pub pre_balance: u64,
+    pub post_balance: u64,
+}
+
+/// The insurance vault is empty; further bankruptcies socialize their losses
+#[event]
+pub struct InsuranceFundDrainedLog {
+    pub lyrae_group: Pubkey,
+    pub insurance_vault: Pubkey,
+}
+
 #[event]
 pub struct UpdateRootBankLog {
     pub lyrae_group: Pubkey,
@@ -197,13 +262,24 @@ pub struct LyrAccrualLog {
     pub lyr_accrual: u64,
 }
 
+#[event]
+pub struct LyrAccruedLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    /// markets with unredeemed lyr and the amount pending in each
+    pub market_indexes: Vec<u64>,
+    pub lyr_accrued: Vec<u64>,
+    pub total_lyr_accrued: u64,
+}
+
 #[event]
 pub struct WithdrawLog {
     pub lyrae_group: Pubkey,
     pub lyrae_account: Pubkey,
     pub owner: Pubkey,
     pub token_index: u64,
-    pub quantity: u64,
+    pub quantity: u64, // debited from the account; the owner receives quantity - fee
+    pub fee: u64,
 }
 
 #[event]
@@ -212,7 +288,8 @@ pub struct DepositLog {
     pub lyrae_account: Pubkey,
     pub owner: Pubkey,
     pub token_index: u64,
-    pub quantity: u64,
+    pub quantity: u64, // taken from the owner; the account is credited quantity - fee
+    pub fee: u64,
 }
 
 #[event]
@@ -246,6 +323,8 @@ pub struct PerpBalanceLog {
     pub short_funding: i128, // I80F48
 }
 
+/// Emitted when a taker's perp order matches and part of its fee goes to its referrer. Referral
+/// fees are charged when the order is placed, not when the FillEvents are consumed.
 #[event]
 pub struct ReferralFeeAccrualLog {
     pub lyrae_group: Pubkey,
@@ -254,3 +333,233 @@ pub struct ReferralFeeAccrualLog {
     pub market_index: u64,
     pub referral_fee_accrual: i128, // I80F48
 }
+
+#[event]
+pub struct MmExemptFeeLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub market_index: u64,
+}
+
+#[event]
+pub struct HealthComputeEstimateLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub num_spot_markets: u64, // spot markets valued in health
+    pub num_open_orders: u64,  // of those, open orders accounts loaded
+    pub num_perp_markets: u64, // perp markets valued in health
+}
+
+#[event]
+pub struct LiquidationStatusLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub being_liquidated: bool,
+    pub init_health: i128, // I80F48
+}
+
+#[event]
+pub struct SimulatedWithdrawLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub token_index: u64,
+    pub quantity: u64,
+    pub allow_borrow: bool,
+    pub pre_init_health: i128,  // I80F48
+    pub post_init_health: i128, // I80F48
+}
+
+#[event]
+pub struct SimulatedLiquidationLog {
+    pub lyrae_group: Pubkey,
+    pub liqee: Pubkey,
+    pub liqor: Pubkey,
+    pub market_index: u64,
+    pub price: i128, // I80F48
+    pub base_transfer: i64,
+    pub quote_transfer: i128,     // I80F48
+    pub liquidation_fee: i128,    // I80F48
+    pub liqee_init_health: i128,  // I80F48; after the transfer
+    pub liqee_maint_health: i128, // I80F48; after the transfer
+    pub liqor_init_health: i128,  // I80F48; after the transfer
+}
+
+#[event]
+pub struct NodeBankHealthLog {
+    pub lyrae_group: Pubkey,
+    pub node_bank: Pubkey,
+    pub token_index: u64,
+    pub native_deposits: i128, // I80F48
+    pub native_borrows: i128,  // I80F48
+    pub vault_amount: u64,
+    /// vault_amount - (native_deposits - native_borrows); positive means the vault holds more
+    pub difference: i128, // I80F48
+}
+
+#[event]
+pub struct AdvancedOrderLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub order_index: u64,
+    pub market_index: u64,
+    pub order_type: u8,
+    pub side: u8,
+    pub trigger_condition: u8,
+    pub reduce_only: bool,
+    pub trigger_price: i128, // I80F48
+    pub price: i64,
+    pub quantity: i64,
+    pub client_order_id: u64,
+    pub trail_offset: i128, // I80F48
+}
+
+#[event]
+pub struct GroupAdminChangeLog {
+    pub lyrae_group: Pubkey,
+    pub old_admin: Pubkey, // also the signer
+    pub new_admin: Pubkey,
+}
+
+/// Identifies the LyraeGroup field that changed in a GroupConfigChangeLog
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GroupConfigParam {
+    MaxLyraeAccounts = 0,
+    MinLiquidationHealthImprovement = 1,
+    PythConfFilterBps = 2,
+    MinCollateralToTrade = 3,
+    FeesInsuranceSharePct = 4,
+    Halted = 5,
+    Paused = 6,
+    RefSurchargeCentibps = 7,
+    RefShareCentibps = 8,
+    RefLyrRequired = 9,
+    MinLiquidationFeeBps = 10,
+}
+
+#[event]
+pub struct GroupConfigChangeLog {
+    pub lyrae_group: Pubkey,
+    pub admin: Pubkey,
+    pub param: u8, // GroupConfigParam
+    pub old_value: u64,
+    pub new_value: u64,
+}
+
+#[event]
+pub struct DexProgramIdChangeLog {
+    pub lyrae_group: Pubkey,
+    pub admin: Pubkey,
+    pub old_dex_program_id: Pubkey,
+    pub new_dex_program_id: Pubkey,
+}
+
+/// Emitted by EmergencySetAssetWeight; weights are I80F48 bits
+#[event]
+pub struct EmergencyAssetWeightLog {
+    pub lyrae_group: Pubkey,
+    pub admin: Pubkey,
+    pub market_index: u64,
+    pub old_init_asset_weight: i128,
+    pub old_maint_asset_weight: i128,
+    pub init_asset_weight: i128,
+    pub maint_asset_weight: i128,
+}
+
+#[event]
+pub struct MsrmFeeTierLog {
+    pub lyrae_group: Pubkey,
+    pub admin: Pubkey,
+    pub tier: u64,
+    pub old_min_msrm: u64,
+    pub old_discount_bps: u64,
+    pub min_msrm: u64,
+    pub discount_bps: u64,
+}
+
+#[event]
+pub struct SpotFillLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub market_index: u64,
+    pub side: u8,
+    /// native base and quote exchanged by taker fills during the order, fees included
+    pub filled_base: u64,
+    pub filled_quote: u64,
+    /// true if part of the order was left resting on the book
+    pub posted: bool,
+    pub base_change: i128,  // I80F48; net change of the base vault
+    pub quote_change: i128, // I80F48; net change of the quote vault
+}
+
+#[event]
+pub struct PerpPnlLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub market_index: u64,
+    pub base_position: i64,
+    pub quote_position: i128, // I80F48; adjusted for unsettled funding
+    pub price: i128,          // I80F48
+    /// What settle_pnl would realize at the cached price
+    pub unrealized_pnl: i128, // I80F48
+}
+
+#[event]
+pub struct CacheConsistencyLog {
+    pub lyrae_group: Pubkey,
+    pub num_oracles: u64,
+    /// indexes configured on the group whose cache entry has never been updated
+    pub uncached_price_indexes: Vec<u64>,
+    pub uncached_root_bank_indexes: Vec<u64>,
+    pub uncached_perp_market_indexes: Vec<u64>,
+}
+
+#[event]
+pub struct GroupTvlLog {
+    pub lyrae_group: Pubkey,
+    pub token_indexes: Vec<u64>,
+    pub native_deposits: Vec<i128>, // I80F48
+    pub native_borrows: Vec<i128>,  // I80F48
+    pub vault_amounts: Vec<u64>,
+    /// native deposits of all tokens above valued at cache prices, in native quote
+    pub total_deposits_value: i128, // I80F48
+}
+
+#[event]
+pub struct OpenOrdersSummaryLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub market_index: u64,
+    pub order_ids: Vec<i128>,
+    pub client_order_ids: Vec<u64>,
+    pub sides: Vec<u8>,
+    pub prices: Vec<i64>,     // in quote lots per base lot
+    pub quantities: Vec<i64>, // in base lots
+}
+
+#[event]
+pub struct BookCapacityLog {
+    pub lyrae_group: Pubkey,
+    pub market_index: u64,
+    pub bids_leaf_count: u64,
+    pub bids_used_nodes: u64,
+    pub bids_free_nodes: u64,
+    pub asks_leaf_count: u64,
+    pub asks_used_nodes: u64,
+    pub asks_free_nodes: u64,
+}
+
+#[event]
+pub struct CreateLyraeAccountLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub owner: Pubkey,
+    pub account_num: u64,
+}
+
+#[event]
+pub struct CloseLyraeAccountLog {
+    pub lyrae_group: Pubkey,
+    pub lyrae_account: Pubkey,
+    pub owner: Pubkey,
+}
@@ -123,8 +123,28 @@ pub enum LyraeErrorCode {
     InvalidOraclePrice,
     #[error("LyraeErrorCode::MaxAccountsReached The maximum number of accounts for this group has been reached")]
     MaxAccountsReached,
-
-    #[error("LyraeErrorCode::Default Check the source code for more info")] // 40
+    #[error("LyraeErrorCode::WouldExecuteAsTaker Post only order would have matched against the book")] // 40
+    WouldExecuteAsTaker,
+    #[error("LyraeErrorCode::BookFull The order book side is at capacity and the order is not better than the worst resting order")]
+    BookFull,
+    #[error("LyraeErrorCode::GroupHalted The group is halted; the admin must un-halt it first")]
+    GroupHalted,
+    #[error("LyraeErrorCode::EventQueueNotEmpty Run consume_events until the EventQueue is empty")]
+    EventQueueNotEmpty,
+    #[error("LyraeErrorCode::InvalidReferrer The referrer must be a LyraeAccount of the same group and must not create a referral loop")]
+    InvalidReferrer,
+    #[error("LyraeErrorCode::PriceNotCached The oracle price has never been cached; run cache_prices first")]
+    PriceNotCached,
+    #[error("LyraeErrorCode::InsufficientCollateral Account equity is below the group's min_collateral_to_trade; only risk reducing orders are allowed")]
+    InsufficientCollateral,
+    #[error("LyraeErrorCode::OrderPriceTooFarFromOracle The order price deviates from the oracle price by more than the market's max_order_price_deviation_bps")]
+    OrderPriceTooFarFromOracle,
+    #[error("LyraeErrorCode::GroupPaused The group is paused; deposits, borrows and new orders are rejected")]
+    GroupPaused,
+    #[error("LyraeErrorCode::LiquidationTooSmall The liquidation improves the liqee's init health by less than the group's min_liquidation_health_improvement")]
+    LiquidationTooSmall,
+
+    #[error("LyraeErrorCode::Default Check the source code for more info")]
     Default = u32::MAX_VALUE,
 }
 
@@ -1,5 +1,5 @@
 use crate::matching::{OrderType, Side};
-use crate::state::{AssetType, INFO_LEN};
+use crate::state::{AssetType, DELEGATE_ALL, INFO_LEN, MAX_EXECUTE_TRIGGER_ORDERS};
 use crate::state::{TriggerCondition, MAX_PAIRS};
 use arrayref::{array_ref, array_refs};
 use fixed::types::I80F48;
@@ -61,6 +61,12 @@ pub enum LyraeInstruction {
     /// 6. `[writable]` vault_ai - TokenAccount owned by LyraeGroup
     /// 7. `[]` token_prog_ai - acc pointed to by SPL token program id
     /// 8. `[writable]` owner_token_account_ai - TokenAccount owned by user which will be sending the funds
+    /// 9. `[writable]` fee_vault_ai - only needed if the RootBank has a deposit_fee_bps; the
+    ///    group's fees_vault for the quote token, else a token account owned by the admin
+    /// 9/10+... `[writable]` node_bank_ais - optional, all NodeBanks of the RootBank, following
+    ///    fee_vault_ai if it is passed. If passed, root_bank_ai and lyrae_cache_ai must be
+    ///    writable and the RootBank index is updated first when its cache is older than
+    ///    valid_interval
     Deposit {
         quantity: u64,
     },
@@ -80,9 +86,15 @@ pub enum LyraeInstruction {
     /// 8. `[read]` signer_ai,        -
     /// 9. `[read]` token_prog_ai,    -
     /// 10..+ `[]` open_orders_accs - open orders for each of the spot market
+    /// 25. `[write]` fee_vault_ai - only needed if the RootBank has a withdraw_fee_bps; same
+    ///     as in Deposit
+    /// 25/26. `[write]` dust_account_ai - only and always needed if close_dust; the last account
     Withdraw {
         quantity: u64,
         allow_borrow: bool,
+        /// Move a remaining deposit of less than one native unit to the DustAccount.
+        /// Optional to be backward compatible; default false
+        close_dust: bool,
     },
 
     /// Add a token to a lyrae group
@@ -217,7 +229,10 @@ pub enum LyraeInstruction {
     /// paid from the quote position. Only at this point the position balance
     /// is 100% refelecting the trade.
     ///
-    /// Accounts expected by this instruction (8 + `MAX_PAIRS` + (optional 1)):
+    /// The return data holds the taker fills, as signed base and quote lots, and the base lots
+    /// posted to the book, as three little endian i64s.
+    ///
+    /// Accounts expected by this instruction (8 + `MAX_PAIRS` + (optional 2)):
     /// 0. `[]` lyrae_group_ai - LyraeGroup
     /// 1. `[writable]` lyrae_account_ai - the LyraeAccount of owner
     /// 2. `[signer]` owner_ai - owner of LyraeAccount
@@ -228,6 +243,8 @@ pub enum LyraeInstruction {
     /// 7. `[writable]` event_queue_ai - EventQueue for this PerpMarket
     /// 8..23 `[]` open_orders_ais - array of open orders accounts on this LyraeAccount
     /// 23. `[writable]` referrer_lyrae_account_ai - optional, lyrae account of referrer
+    /// 23 or 24. `[]` msrm_fee_tiers_ai - optional, the group's MsrmFeeTiers to get the msrm fee
+    ///     tier discount on taker fees; may come before or without the referrer
     PlacePerpOrder {
         price: i64,
         quantity: i64,
@@ -249,6 +266,16 @@ pub enum LyraeInstruction {
         invalid_id_ok: bool,
     },
 
+    /// Process events from the front of a perp market's EventQueue. The trailing LyraeAccounts
+    /// may be any superset of the accounts the events reference; processing stops cleanly at the
+    /// first event whose account is missing. The number of events consumed is set as return data.
+    ///
+    /// Accounts expected by this instruction (4 + LyraeAccounts):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    /// 3. `[writable]` event_queue_ai - EventQueue
+    /// 4+... `[writable]` lyrae_account_ais - candidate LyraeAccounts
     ConsumeEvents {
         limit: usize,
     },
@@ -264,8 +291,13 @@ pub enum LyraeInstruction {
     UpdateFunding,
 
     /// Can only be used on a stub oracle in devnet
+    ///
+    /// `last_update` is optional in the instruction data. 0 stamps the oracle with the current
+    /// clock; any other value sets that timestamp explicitly, which is only allowed in devnet
+    /// builds so tests can keep stub prices valid after advancing the clock.
     SetOracle {
         price: I80F48,
+        last_update: u64,
     },
 
     /// Settle all funds from serum dex open orders
@@ -294,11 +326,15 @@ pub enum LyraeInstruction {
 
     /// Cancel an order using dex instruction
     ///
+    /// If `ok_invalid_id` is set and the order is no longer on the book, the cancel is skipped
+    /// instead of failing the transaction
+    ///
     /// Accounts expected by this instruction ():
     ///
     CancelSpotOrder {
         // 20
         order: serum_dex::instruction::CancelOrderInstructionV2,
+        ok_invalid_id: bool,
     },
 
     /// Update a root bank's indexes by providing all it's node banks
@@ -369,6 +405,10 @@ pub enum LyraeInstruction {
     /// Liquidator takes some of borrows at token at `liab_index` and receives some deposits from
     /// the token at `asset_index`
     ///
+    /// The transfer never exceeds what brings the liqee to init_health == 0. `max_health_fraction`
+    /// in (0, 1] further caps it to that fraction of the liqee's init health deficit for partial
+    /// liquidations; it is optional in the instruction data and defaults to 1.
+    ///
     /// Accounts expected: 9 + Liqee open orders accounts (MAX_PAIRS) + Liqor open orders accounts (MAX_PAIRS)
     /// 0. `[]` lyrae_group_ai - LyraeGroup
     /// 1. `[]` lyrae_cache_ai - LyraeCache
@@ -383,6 +423,7 @@ pub enum LyraeInstruction {
     /// 9+MAX_PAIRS... `[]` liqor_open_orders_ais - Liqor open orders accs
     LiquidateTokenAndToken {
         max_liab_transfer: I80F48,
+        max_health_fraction: I80F48,
     },
 
     /// Swap tokens for perp quote position if only and only if the base position in that market is 0
@@ -419,6 +460,11 @@ pub enum LyraeInstruction {
     /// 7+MAX_PAIRS... `[]` liqor_open_orders_ais - Liqor open orders accs
     LiquidatePerpMarket {
         base_transfer_request: i64,
+
+        /// Optional: clamp the transfer so the liqor's resulting base position (in base lots)
+        /// stays within this absolute size. None (or an old client) means no cap.
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        liqor_max_base_position: Option<u64>,
     },
 
     /// Take an account that has losses in the selected perp market to account for fees_accrued
@@ -434,6 +480,8 @@ pub enum LyraeInstruction {
     /// 7. `[writable]` fees_vault_ai - fee vault owned by lyrae DAO token governance
     /// 8. `[]` signer_ai - Group Signer Account
     /// 9. `[]` token_prog_ai - Token Program Account
+    /// 10. `[writable]` insurance_vault_ai - Insurance vault; only needed if the group's
+    ///     fees_insurance_share_pct is not 0
     SettleFees,
 
     /// Claim insurance fund and then socialize loss
@@ -478,6 +526,11 @@ pub enum LyraeInstruction {
     /// 14+MAX_PAIRS... `[]` liab_node_bank_ais - Lib token node banks
     ResolveTokenBankruptcy {
         max_liab_transfer: I80F48,
+
+        /// Optional: revert if the cached liab price used to convert from the insurance fund
+        /// is above this. None (or an old client that doesn't send it) means unbounded.
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_liab_price: Option<I80F48>,
     },
 
     /// Initialize open orders
@@ -647,6 +700,10 @@ pub enum LyraeInstruction {
     /// 5. `[]` perp_market_ai
     /// 6. `[]` system_prog_ai
     /// 7.. `[]` open_orders_ais - OpenOrders account for each serum dex market in margin basket
+    ///
+    /// For TrailingBelow and TrailingAbove, trigger_price is the initial trigger price and
+    /// trail_offset (must be positive) how far it trails the best oracle price seen by
+    /// executions. trail_offset must be 0 for other conditions and may be omitted.
     AddPerpTriggerOrder {
         order_type: OrderType,
         side: Side,
@@ -656,6 +713,7 @@ pub enum LyraeInstruction {
         price: i64,
         quantity: i64,
         trigger_price: I80F48,
+        trail_offset: I80F48,
     },
     /// Remove the order at the order_index
     RemoveAdvancedOrder {
@@ -723,6 +781,7 @@ pub enum LyraeInstruction {
     /// 0. `[writable]` lyrae_group_ai - LyraeGroup
     /// 1. `[writable]` perp_market_ai - PerpMarket
     /// 2. `[signer]` admin_ai - LyraeGroup admin
+    /// 3. `[writable]` bids_ai - bids of the PerpMarket, only needed with funding_impact_notional
     ChangePerpMarketParams2 {
         #[serde(serialize_with = "serialize_option_fixed_width")]
         maint_leverage: Option<I80F48>,
@@ -761,6 +820,17 @@ pub enum LyraeInstruction {
         version: Option<u8>,
         #[serde(serialize_with = "serialize_option_fixed_width")]
         lm_size_shift: Option<u8>,
+
+        /// Max distance in bps of a perp order's price from the oracle price; 0 disables the
+        /// check. Optional to be backward compatible
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        max_order_price_deviation_bps: Option<u16>,
+
+        /// Whole quote tokens walked into the book for the funding impact bid and ask, e.g.
+        /// 10000 for 10k USDC; 0 uses the prices 100 lots deep. Needs bids_ai. Optional to be
+        /// backward compatible
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        funding_impact_notional: Option<u32>,
     },
 
     /// Change the params for perp market.
@@ -774,6 +844,8 @@ pub enum LyraeInstruction {
     UpdateMarginBasket,
 
     /// Change the maximum number of closeable LyraeAccounts.v1 allowed
+    /// Setting it at or below the current number of accounts blocks creating new accounts
+    /// without affecting existing ones. u32::MAX is rejected.
     ///
     /// Accounts expected by this instruction (2):
     ///
@@ -849,7 +921,8 @@ pub enum LyraeInstruction {
         account_num: u64,
     },
 
-    /// Upgrade a V0 Lyrae Account to V1 allowing it to be closed
+    /// Upgrade a V0 Lyrae Account to V1 allowing it to be closed, and initialize its
+    /// last_activity_ts
     ///
     /// Accounts expected by this instruction (3):
     ///
@@ -876,12 +949,18 @@ pub enum LyraeInstruction {
     /// Set delegate authority to lyrae account which can do everything regular account can do
     /// except Withdraw and CloseLyraeAccount. Set to Pubkey::default() to revoke delegate
     ///
+    /// `permissions` is a non-zero mask of DELEGATE_PLACE, DELEGATE_CANCEL, DELEGATE_TRIGGER and
+    /// DELEGATE_MANAGE limiting the delegate, e.g. to cancel only for a risk bot. It is optional
+    /// in the instruction data and defaults to DELEGATE_ALL.
+    ///
     /// Accounts expected: 4
     /// 0. `[]` lyrae_group_ai - LyraeGroup
     /// 1. `[writable]` lyrae_account_ai - LyraeAccount
     /// 2. `[signer]` owner_ai - Owner of Lyrae Account
     /// 3. `[]` delegate_ai - delegate
-    SetDelegate,
+    SetDelegate {
+        permissions: u8,
+    },
 
     /// Change the params for a spot market.
     ///
@@ -929,20 +1008,28 @@ pub enum LyraeInstruction {
     CreateSpotOpenOrders, // instruction 60
 
     /// Set the `ref_surcharge_centibps`, `ref_share_centibps` and `ref_Lyr_required` on `LyraeGroup`
+    /// If `drain_required` is true, fails unless the EventQueue of every perp market is passed
+    /// in and empty, so no pending fill is logged with fees computed from the old params
     ///
-    /// Accounts expected by this instruction (2):
+    /// Accounts expected by this instruction (2 + 2 * number of perp markets if drain_required):
     /// 0. `[writable]` lyrae_group_ai - LyraeGroup that this lyrae account is for
     /// 1. `[signer]` admin_ai - lyrae_group.admin
+    /// 2+... `[]` perp_market_ai, `[writable]` event_queue_ai - a pair for each perp market on
+    ///     the group; the EventQueue is loaded mutably even though it is only read
     ChangeReferralFeeParams {
         ref_surcharge_centibps: u32,
         ref_share_centibps: u32,
         ref_lyr_required: u64,
+        drain_required: bool,
     },
     /// Store the referrer's LyraeAccount pubkey on the Referrer account
     /// It will create the Referrer account as a PDA of user's LyraeAccount if it doesn't exist
     /// This is primarily useful for the UI; the referrer address stored here is not necessarily
     /// who earns the ref fees.
     ///
+    /// Fails with InvalidReferrer if the referrer is not a LyraeAccount of this group, is the
+    /// referred account itself, or already has the referred account stored as its own referrer.
+    ///
     /// Accounts expected by this instruction (7):
     ///
     /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
@@ -952,6 +1039,8 @@ pub enum LyraeInstruction {
     /// 4. `[]` referrer_lyrae_account_ai - referrer's LyraeAccount
     /// 5. `[signer, writable]` payer_ai - payer for PDA; can be same as owner
     /// 6. `[]` system_prog_ai - System program
+    /// 7. `[]` referrers_referrer_memory_ai - optional, the referrer's own ReferrerMemory PDA;
+    ///    may be empty. The circular referral check is only done if it is passed
     SetReferrerMemory,
 
     /// Associate the referrer's LyraeAccount with a human readable `referrer_id` which can be used
@@ -967,465 +1056,790 @@ pub enum LyraeInstruction {
     RegisterReferrerId {
         referrer_id: [u8; INFO_LEN],
     },
-}
 
-impl LyraeInstruction {
-    pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&discrim, data) = array_refs![input, 4; ..;];
-        let discrim = u32::from_le_bytes(discrim);
-        Some(match discrim {
-            0 => {
-                let data = array_ref![data, 0, 64];
-                let (
-                    signer_nonce,
-                    valid_interval,
-                    quote_optimal_util,
-                    quote_optimal_rate,
-                    quote_max_rate,
-                ) = array_refs![data, 8, 8, 16, 16, 16];
+    /// Redeem the lyr_accrued in a PerpAccount straight to an external LYR token account
+    /// instead of crediting the LyraeAccount deposits. Like Withdraw, only the owner may do this,
+    /// not a delegate.
+    ///
+    /// Accounts expected by this instruction (8):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 2. `[signer]` owner_ai - LyraeAccount owner
+    /// 3. `[]` perp_market_ai - PerpMarket
+    /// 4. `[writable]` lyr_perp_vault_ai - PerpMarket's LYR vault
+    /// 5. `[writable]` token_account_ai - LYR token account receiving the redeemed LYR
+    /// 6. `[]` signer_ai - Group Signer Account
+    /// 7. `[]` token_prog_ai - SPL Token program id
+    RedeemLyrToTokenAccount,
 
-                LyraeInstruction::InitLyraeGroup {
-                    signer_nonce: u64::from_le_bytes(*signer_nonce),
-                    valid_interval: u64::from_le_bytes(*valid_interval),
-                    quote_optimal_util: I80F48::from_le_bytes(*quote_optimal_util),
-                    quote_optimal_rate: I80F48::from_le_bytes(*quote_optimal_rate),
-                    quote_max_rate: I80F48::from_le_bytes(*quote_max_rate),
-                }
-            }
-            1 => LyraeInstruction::InitLyraeAccount,
-            2 => {
-                let quantity = array_ref![data, 0, 8];
-                LyraeInstruction::Deposit {
-                    quantity: u64::from_le_bytes(*quantity),
-                }
-            }
-            3 => {
-                let data = array_ref![data, 0, 9];
-                let (quantity, allow_borrow) = array_refs![data, 8, 1];
+    /// Set the max fraction of a liqee's perp position that may be transferred in a single
+    /// liquidation, in bps. 0 disables the cap.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    ChangeMaxLiquidationFraction {
+        max_liquidation_fraction_bps: u16,
+    },
 
-                let allow_borrow = match allow_borrow {
-                    [0] => false,
-                    [1] => true,
-                    _ => return None,
-                };
-                LyraeInstruction::Withdraw {
-                    quantity: u64::from_le_bytes(*quantity),
-                    allow_borrow,
-                }
-            }
-            4 => {
-                let data = array_ref![data, 0, 96];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    optimal_util,
-                    optimal_rate,
-                    max_rate,
-                ) = array_refs![data, 16, 16, 16, 16, 16, 16];
-                LyraeInstruction::AddSpotMarket {
-                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
-                    init_leverage: I80F48::from_le_bytes(*init_leverage),
-                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
-                    optimal_util: I80F48::from_le_bytes(*optimal_util),
-                    optimal_rate: I80F48::from_le_bytes(*optimal_rate),
-                    max_rate: I80F48::from_le_bytes(*max_rate),
-                }
-            }
-            5 => {
-                let market_index = array_ref![data, 0, 8];
-                LyraeInstruction::AddToBasket {
-                    market_index: usize::from_le_bytes(*market_index),
-                }
-            }
-            6 => {
-                let quantity = array_ref![data, 0, 8];
-                LyraeInstruction::Borrow {
-                    quantity: u64::from_le_bytes(*quantity),
-                }
-            }
-            7 => LyraeInstruction::CachePrices,
-            8 => LyraeInstruction::CacheRootBanks,
-            9 => {
-                let data_arr = array_ref![data, 0, 46];
-                let order = unpack_dex_new_order_v3(data_arr)?;
-                LyraeInstruction::PlaceSpotOrder { order }
-            }
-            10 => LyraeInstruction::AddOracle,
-            11 => {
-                let exp = if data.len() > 144 { data[144] } else { 2 };
-                let data_arr = array_ref![data, 0, 144];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    base_lot_size,
-                    quote_lot_size,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8];
-                LyraeInstruction::AddPerpMarket {
-                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
-                    init_leverage: I80F48::from_le_bytes(*init_leverage),
-                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
-                    maker_fee: I80F48::from_le_bytes(*maker_fee),
-                    taker_fee: I80F48::from_le_bytes(*taker_fee),
-                    base_lot_size: i64::from_le_bytes(*base_lot_size),
-                    quote_lot_size: i64::from_le_bytes(*quote_lot_size),
-                    rate: I80F48::from_le_bytes(*rate),
-                    max_depth_bps: I80F48::from_le_bytes(*max_depth_bps),
-                    target_period_length: u64::from_le_bytes(*target_period_length),
-                    lyr_per_period: u64::from_le_bytes(*lyr_per_period),
-                    exp,
-                }
-            }
-            12 => {
-                let reduce_only = if data.len() > 26 {
-                    data[26] != 0
-                } else {
-                    false
-                };
-                let data_arr = array_ref![data, 0, 26];
-                let (price, quantity, client_order_id, side, order_type) =
-                    array_refs![data_arr, 8, 8, 8, 1, 1];
-                LyraeInstruction::PlacePerpOrder {
-                    price: i64::from_le_bytes(*price),
-                    quantity: i64::from_le_bytes(*quantity),
-                    client_order_id: u64::from_le_bytes(*client_order_id),
-                    side: Side::try_from_primitive(side[0]).ok()?,
-                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
-                    reduce_only,
-                }
-            }
-            13 => {
-                let data_arr = array_ref![data, 0, 9];
-                let (client_order_id, invalid_id_ok) = array_refs![data_arr, 8, 1];
+    /// Compute the init health before and after a hypothetical Withdraw and emit it in a
+    /// SimulatedWithdrawLog. Nothing is transferred and no state is changed.
+    ///
+    /// Accounts expected by this instruction (4 + MAX_PAIRS):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[]` lyrae_account_ai - the lyrae account to simulate the withdraw for
+    /// 2. `[]` lyrae_cache_ai - LyraeCache
+    /// 3. `[]` root_bank_ai - RootBank for the token to withdraw
+    /// 4..4+MAX_PAIRS `[]` open_orders_ais - open orders for each of the spot market
+    SimulateWithdraw {
+        quantity: u64,
+        allow_borrow: bool,
+    },
 
-                LyraeInstruction::CancelPerpOrderByClientId {
-                    client_order_id: u64::from_le_bytes(*client_order_id),
-                    invalid_id_ok: invalid_id_ok[0] != 0,
-                }
-            }
-            14 => {
-                let data_arr = array_ref![data, 0, 17];
-                let (order_id, invalid_id_ok) = array_refs![data_arr, 16, 1];
-                LyraeInstruction::CancelPerpOrder {
-                    order_id: i128::from_le_bytes(*order_id),
-                    invalid_id_ok: invalid_id_ok[0] != 0,
-                }
-            }
-            15 => {
-                let data_arr = array_ref![data, 0, 8];
-                LyraeInstruction::ConsumeEvents {
-                    limit: usize::from_le_bytes(*data_arr),
-                }
-            }
-            16 => LyraeInstruction::CachePerpMarkets,
-            17 => LyraeInstruction::UpdateFunding,
-            18 => {
-                let data_arr = array_ref![data, 0, 16];
-                LyraeInstruction::SetOracle {
-                    price: I80F48::from_le_bytes(*data_arr),
-                }
-            }
-            19 => LyraeInstruction::SettleFunds,
-            20 => {
-                let data_array = array_ref![data, 0, 20];
-                let fields = array_refs![data_array, 4, 16];
-                let side = match u32::from_le_bytes(*fields.0) {
-                    0 => serum_dex::matching::Side::Bid,
-                    1 => serum_dex::matching::Side::Ask,
-                    _ => return None,
-                };
-                let order_id = u128::from_le_bytes(*fields.1);
-                let order = serum_dex::instruction::CancelOrderInstructionV2 { side, order_id };
-                LyraeInstruction::CancelSpotOrder { order }
-            }
-            21 => LyraeInstruction::UpdateRootBank,
+    /// Compare a NodeBank's recorded deposits and borrows, converted to native with the cached
+    /// indexes, against the vault balance and emit a NodeBankHealthLog. Read only.
+    ///
+    /// Accounts expected by this instruction (5):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[]` root_bank_ai - RootBank
+    /// 3. `[]` node_bank_ai - NodeBank of the root bank
+    /// 4. `[]` vault_ai - vault of the node bank
+    LogNodeBankHealth,
+
+    /// Set a floor on the liquidation fee used by all liquidation instructions, in bps.
+    /// 0 leaves every market's own liquidation_fee in effect. Fails if the floor is not below
+    /// 1 - init_asset_weight and init_liab_weight - 1 of every listed spot and perp market.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    ChangeMinLiquidationFee {
+        min_liquidation_fee_bps: u16,
+    },
 
-            22 => {
-                let data_arr = array_ref![data, 0, 8];
+    /// Settle the serum referrer rebates accrued on a spot OpenOrders account into the
+    /// LyraeAccount's quote deposits. Free base and quote are settled along with them.
+    ///
+    /// Accounts expected by this instruction (18): same as SettleFunds
+    ClaimSerumReferrerRebates,
 
-                LyraeInstruction::SettlePnl {
-                    market_index: usize::from_le_bytes(*data_arr),
-                }
-            }
-            23 => {
-                let data = array_ref![data, 0, 16];
-                let (token_index, quantity) = array_refs![data, 8, 8];
+    /// Emit an AdvancedOrderLog for every active order in the LyraeAccount's AdvancedOrders.
+    /// Read only; meant to be simulated by clients.
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - the LyraeAccount owning the AdvancedOrders
+    /// 2. `[]` advanced_orders_ai - AdvancedOrders of the LyraeAccount
+    LogAdvancedOrders,
 
-                LyraeInstruction::SettleBorrow {
-                    token_index: usize::from_le_bytes(*token_index),
-                    quantity: u64::from_le_bytes(*quantity),
-                }
-            }
-            24 => {
-                let data_arr = array_ref![data, 0, 1];
+    /// Permissionless version of SettleFees that takes a batch of candidate LyraeAccounts and
+    /// settles fees_accrued from each one with negative pnl until fees_accrued is exhausted.
+    /// Accounts that don't qualify are skipped.
+    ///
+    /// Accounts expected: 9 + up to MAX_SETTLE_FEES_ACCOUNTS
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    /// 3. `[]` root_bank_ai - RootBank
+    /// 4. `[writable]` node_bank_ai - NodeBank
+    /// 5. `[writable]` bank_vault_ai - vault of the quote node bank
+    /// 6. `[writable]` fees_vault_ai - fee vault owned by lyrae DAO token governance
+    /// 7. `[]` signer_ai - Group Signer Account
+    /// 8. `[]` token_prog_ai - Token Program Account
+    /// 9. `[writable]` insurance_vault_ai - Insurance vault; only passed if the group's
+    ///    fees_insurance_share_pct is not 0, and then the candidates start at 10
+    /// 9+... `[writable]` lyrae_account_ais - candidate LyraeAccounts
+    CrankSettleFees,
+
+    /// Emit a BookCapacityLog with the used and free nodes of each side of a perp market's book.
+    /// Read only.
+    ///
+    /// Accounts expected by this instruction (4):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` perp_market_ai - PerpMarket
+    /// 2. `[]` bids_ai - Bids acc
+    /// 3. `[]` asks_ai - Asks acc
+    LogBookCapacity,
 
-                LyraeInstruction::ForceCancelSpotOrders {
-                    limit: u8::from_le_bytes(*data_arr),
-                }
-            }
-            25 => {
-                let data_arr = array_ref![data, 0, 1];
+    /// Create the OpenOrders PDA for a spot market and deposit funds in the same instruction.
+    /// Equivalent to CreateSpotOpenOrders followed by Deposit.
+    ///
+    /// Accounts expected by this instruction (15):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[writable]` lyrae_account_ai - the lyrae account for this user
+    /// 2. `[signer]` owner_ai - Solana account of owner of the lyrae account
+    /// 3. `[]` lyrae_cache_ai - LyraeCache
+    /// 4. `[]` root_bank_ai - RootBank owned by LyraeGroup
+    /// 5. `[writable]` node_bank_ai - NodeBank owned by RootBank
+    /// 6. `[writable]` vault_ai - TokenAccount owned by LyraeGroup
+    /// 7. `[]` token_prog_ai - acc pointed to by SPL token program id
+    /// 8. `[writable]` owner_token_account_ai - TokenAccount owned by user which will be sending the funds
+    /// 9. `[]` dex_prog_ai - program id of serum dex
+    /// 10. `[writable]` open_orders_ai - open orders PDA
+    /// 11. `[]` spot_market_ai - dex MarketState account
+    /// 12. `[]` signer_ai - Group Signer Account
+    /// 13. `[]` system_prog_ai - System program
+    /// 14. `[signer, writable]` payer_ai - pays for the PDA creation
+    /// 15. `[writable]` fee_vault_ai - only needed if the RootBank has a deposit_fee_bps, as
+    ///    in Deposit
+    /// 15/16+... `[writable]` node_bank_ais - optional, all NodeBanks of the RootBank, as in
+    ///    Deposit
+    DepositAndCreateSpotOpenOrders {
+        quantity: u64,
+    },
 
-                LyraeInstruction::ForceCancelPerpOrders {
-                    limit: u8::from_le_bytes(*data_arr),
-                }
-            }
-            26 => {
-                let data_arr = array_ref![data, 0, 16];
+    /// Set the cancel-on-disconnect TTL of a LyraeAccount and refresh its heartbeat.
+    /// It will create the CancelTtl account as a PDA of the LyraeAccount if it doesn't exist.
+    /// A cancel_ttl_secs of 0 disables the switch.
+    ///
+    /// Accounts expected by this instruction (6):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2. `[signer]` owner_ai - LyraeAccount owner
+    /// 3. `[writable]` cancel_ttl_ai - CancelTtl PDA; will be initialized if required
+    /// 4. `[signer, writable]` payer_ai - payer for PDA; can be same as owner
+    /// 5. `[]` system_prog_ai - System program
+    SetCancelTtl {
+        cancel_ttl_secs: u64,
+    },
 
-                LyraeInstruction::LiquidateTokenAndToken {
-                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
-                }
-            }
-            27 => {
-                let data = array_ref![data, 0, 34];
-                let (asset_type, asset_index, liab_type, liab_index, max_liab_transfer) =
-                    array_refs![data, 1, 8, 1, 8, 16];
+    /// Refresh the heartbeat of a LyraeAccount's CancelTtl
+    ///
+    /// Accounts expected by this instruction (4):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2. `[signer]` owner_ai - LyraeAccount owner or delegate
+    /// 3. `[writable]` cancel_ttl_ai - CancelTtl PDA of the LyraeAccount
+    Heartbeat,
 
-                LyraeInstruction::LiquidateTokenAndPerp {
-                    asset_type: AssetType::try_from(u8::from_le_bytes(*asset_type)).unwrap(),
-                    asset_index: usize::from_le_bytes(*asset_index),
-                    liab_type: AssetType::try_from(u8::from_le_bytes(*liab_type)).unwrap(),
-                    liab_index: usize::from_le_bytes(*liab_index),
-                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
-                }
-            }
-            28 => {
-                let data_arr = array_ref![data, 0, 8];
+    /// Permissionless; cancel the resting perp orders of a LyraeAccount whose heartbeat is older
+    /// than its cancel_ttl_secs, in each of the passed perp markets
+    ///
+    /// Accounts expected by this instruction (3 + 3 * number of markets):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 2. `[]` cancel_ttl_ai - CancelTtl PDA of the LyraeAccount
+    /// 3+3*i. `[writable]` perp_market_ai - PerpMarket
+    /// 4+3*i. `[writable]` bids_ai - Bids acc
+    /// 5+3*i. `[writable]` asks_ai - Asks acc
+    CrankExpiredCancels {
+        limit: u8, // max orders to cancel per market
+    },
 
-                LyraeInstruction::LiquidatePerpMarket {
-                    base_transfer_request: i64::from_le_bytes(*data_arr),
-                }
-            }
-            29 => LyraeInstruction::SettleFees,
-            30 => {
-                let data = array_ref![data, 0, 24];
-                let (liab_index, max_liab_transfer) = array_refs![data, 8, 16];
+    /// Emit a PerpPnlLog with the unrealized pnl of a LyraeAccount's position in a perp market,
+    /// computed the same way as SettlePnl. Read only.
+    ///
+    /// Accounts expected by this instruction (4):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2. `[]` lyrae_cache_ai - LyraeCache
+    /// 3. `[]` perp_market_ai - PerpMarket
+    LogPerpPnl,
 
-                LyraeInstruction::ResolvePerpBankruptcy {
-                    liab_index: usize::from_le_bytes(*liab_index),
-                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
-                }
-            }
-            31 => {
-                let data_arr = array_ref![data, 0, 16];
+    /// Emit a CacheConsistencyLog listing the oracles, root banks and perp markets configured on
+    /// the group whose LyraeCache entries have never been updated. Read only.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    LogCacheConsistency,
+
+    /// Point the group at a new serum dex program, e.g. after a dex migration.
+    /// WARNING: existing spot OpenOrders are owned by the old dex program and can no longer be
+    /// used by spot instructions. Migrate in this order:
+    /// 1. cancel and settle all spot orders
+    /// 2. ReassignSpotMarket for every listed spot market
+    /// 3. SetDexProgramId, which fails unless every listed spot market is owned by the new dex
+    /// 4. ReassignSpotOpenOrders for every LyraeAccount with an OpenOrders in those markets
+    ///
+    /// Accounts expected by this instruction (3 + number of listed spot markets):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    /// 2. `[]` dex_prog_ai - new serum dex program
+    /// 3+... `[]` spot_market_ais - every listed spot market, in market index order
+    SetDexProgramId,
 
-                LyraeInstruction::ResolveTokenBankruptcy {
-                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
-                }
-            }
-            32 => LyraeInstruction::InitSpotOpenOrders,
-            33 => LyraeInstruction::RedeemLyr,
-            34 => {
-                let info = array_ref![data, 0, INFO_LEN];
-                LyraeInstruction::AddLyraeAccountInfo { info: *info }
-            }
-            35 => {
-                let quantity = array_ref![data, 0, 8];
-                LyraeInstruction::DepositMsrm {
-                    quantity: u64::from_le_bytes(*quantity),
-                }
-            }
-            36 => {
-                let quantity = array_ref![data, 0, 8];
-                LyraeInstruction::WithdrawMsrm {
-                    quantity: u64::from_le_bytes(*quantity),
-                }
-            }
+    /// Same as PlacePerpOrder but the size is given as a quote notional in native units.
+    /// The quantity in base lots is quote_notional / (price * quote_lot_size), rounded down;
+    /// the order is rejected if that rounds to zero.
+    ///
+    /// Accounts expected by this instruction: same as PlacePerpOrder
+    PlacePerpOrderByNotional {
+        price: i64,
+        quote_notional: u64,
+        client_order_id: u64,
+        side: Side,
+        /// Can be 0 -> LIMIT, 1 -> IOC, 2 -> PostOnly, 3 -> Market, 4 -> PostOnlySlide
+        order_type: OrderType,
+        reduce_only: bool,
+    },
 
-            37 => {
-                let exp = if data.len() > 137 {
-                    unpack_u8_opt(&[data[137], data[138]])
-                } else {
-                    None
-                };
-                let data_arr = array_ref![data, 0, 137];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 17, 9, 9];
+    /// Permissionless version of RedeemLyr for a batch of LyraeAccounts on one perp market.
+    /// Accounts with nothing accrued are skipped; redemptions stop once the perp LYR vault is
+    /// empty.
+    ///
+    /// Accounts expected: 9 + up to MAX_REDEEM_LYR_ACCOUNTS
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[]` perp_market_ai - PerpMarket
+    /// 3. `[writable]` lyr_perp_vault_ai - LYR vault of the perp market
+    /// 4. `[]` lyr_root_bank_ai - LYR RootBank
+    /// 5. `[writable]` lyr_node_bank_ai - LYR NodeBank
+    /// 6. `[writable]` lyr_bank_vault_ai - vault of the LYR NodeBank
+    /// 7. `[]` signer_ai - Group Signer Account
+    /// 8. `[]` token_prog_ai - SPL token program
+    /// 9+... `[writable]` lyrae_account_ais - LyraeAccounts to redeem for
+    CrankRedeemLyr,
+
+    /// Emit a GroupTvlLog with the native deposits, borrows and vault balances of each token
+    /// passed in and the total deposits valued at the cached prices. Read only.
+    ///
+    /// Accounts expected by this instruction (2 + ...):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2+... `[]` for each token: root_bank_ai, then (node_bank_ai, vault_ai) for each of the
+    ///       root bank's node banks
+    LogGroupTvl,
 
-                LyraeInstruction::ChangePerpMarketParams {
-                    maint_leverage: unpack_i80f48_opt(maint_leverage),
-                    init_leverage: unpack_i80f48_opt(init_leverage),
-                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
-                    maker_fee: unpack_i80f48_opt(maker_fee),
-                    taker_fee: unpack_i80f48_opt(taker_fee),
-                    rate: unpack_i80f48_opt(rate),
-                    max_depth_bps: unpack_i80f48_opt(max_depth_bps),
-                    target_period_length: unpack_u64_opt(target_period_length),
-                    lyr_per_period: unpack_u64_opt(lyr_per_period),
-                    exp,
-                }
-            }
+    /// Halt or un-halt the group. While halted, adding oracles and spot or perp markets fails
+    /// with GroupHalted.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    SetGroupHalted {
+        halted: bool,
+    },
 
-            38 => LyraeInstruction::SetGroupAdmin,
+    /// Opt the LyraeAccount in or out of crank_auto_settle_funding
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 2. `[signer]` owner_ai - Owner of LyraeAccount
+    SetAutoSettleFunding {
+        auto_settle_funding: bool,
+    },
 
-            39 => {
-                let data_arr = array_ref![data, 0, 1];
-                LyraeInstruction::CancelAllPerpOrders {
-                    limit: u8::from_le_bytes(*data_arr),
-                }
-            }
+    /// Permissionless; settle the positive pnl (funding included) of opted in LyraeAccounts in a
+    /// perp market into their quote deposits, against a counterparty LyraeAccount with negative
+    /// pnl in the same market, the same way SettlePnl does. Accounts that did not opt in or have
+    /// no positive pnl are skipped. Stops once the counterparty's losses are used up.
+    ///
+    /// Accounts expected: 6 + up to MAX_AUTO_SETTLE_ACCOUNTS
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[]` root_bank_ai - quote RootBank
+    /// 3. `[writable]` node_bank_ai - quote NodeBank
+    /// 4. `[writable]` counterparty_ai - LyraeAccount with negative pnl in the market
+    /// 5+... `[writable]` lyrae_account_ais - opted in LyraeAccounts
+    CrankAutoSettleFunding {
+        market_index: usize,
+    },
 
-            40 => LyraeInstruction::ForceSettleQuotePositions,
-            41 => {
-                let data_arr = array_ref![data, 0, 46];
-                let order = unpack_dex_new_order_v3(data_arr)?;
-                LyraeInstruction::PlaceSpotOrder2 { order }
-            }
+    /// Do the cleanup needed before CloseLyraeAccount in one instruction:
+    /// - cancel all perp orders in each of the `num_perp_markets` markets passed in
+    /// - close the AdvancedOrders account if the LyraeAccount has one
+    /// - move sub-native deposits and borrows of each token passed in to the DustAccount
+    /// Spot OpenOrders still have to be settled and closed with SettleFunds and
+    /// CloseSpotOpenOrders, and positions, deposits and borrows closed out, beforehand. If all
+    /// markets and tokens don't fit in one transaction, split them over several of these.
+    ///
+    /// Accounts expected: 6 + 3 * num_perp_markets + 2 * number of tokens
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 2. `[writable, signer]` owner_ai - Owner of LyraeAccount; receives the AdvancedOrders rent
+    /// 3. `[]` lyrae_cache_ai - LyraeCache
+    /// 4. `[writable]` dust_account_ai - DustAccount PDA of the group
+    /// 5. `[writable]` advanced_orders_ai - AdvancedOrders; ignored if the account has none
+    /// 6+... `[writable]` (perp_market_ai, bids_ai, asks_ai) for each perp market
+    /// 6+3*num_perp_markets... (`[]` root_bank_ai, `[writable]` node_bank_ai) for each token
+    PrepareAccountClose {
+        num_perp_markets: u8,
+    },
 
-            42 => LyraeInstruction::InitAdvancedOrders,
+    /// Emit an OpenOrdersSummaryLog for each perp market passed in with the LyraeAccount's
+    /// resting orders in that market, as found on the book. Read only.
+    ///
+    /// Accounts expected: 2 + 3 * number of perp markets
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2+... `[]` (perp_market_ai, bids_ai, asks_ai) for each perp market
+    LogAllOpenOrders,
 
-            43 => {
-                let data_arr = array_ref![data, 0, 44];
-                let (
-                    order_type,
-                    side,
-                    trigger_condition,
-                    reduce_only,
-                    client_order_id,
-                    price,
-                    quantity,
-                    trigger_price,
-                ) = array_refs![data_arr, 1, 1, 1, 1, 8, 8, 8, 16];
-                LyraeInstruction::AddPerpTriggerOrder {
-                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
-                    side: Side::try_from_primitive(side[0]).ok()?,
-                    trigger_condition: TriggerCondition::try_from(u8::from_le_bytes(
-                        *trigger_condition,
-                    ))
-                    .unwrap(),
-                    reduce_only: reduce_only[0] != 0,
-                    client_order_id: u64::from_le_bytes(*client_order_id),
-                    price: i64::from_le_bytes(*price),
-                    quantity: i64::from_le_bytes(*quantity),
-                    trigger_price: I80F48::from_le_bytes(*trigger_price),
-                }
-            }
+    /// Set the fees taken on deposits and withdrawals of a token, at most MAX_TOKEN_FEE_BPS.
+    /// The fees go to the group's fees_vault for the quote token and to a token account owned by
+    /// the admin for other tokens.
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` root_bank_ai - RootBank of the token
+    /// 2. `[signer]` admin_ai - Admin
+    SetTokenFees {
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    },
 
-            44 => {
-                let order_index = array_ref![data, 0, 1][0];
-                LyraeInstruction::RemoveAdvancedOrder { order_index }
-            }
-            45 => {
-                let order_index = array_ref![data, 0, 1][0];
-                LyraeInstruction::ExecutePerpTriggerOrder { order_index }
-            }
-            46 => {
-                let data_arr = array_ref![data, 0, 148];
-                let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    base_lot_size,
-                    quote_lot_size,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                    exp,
-                    version,
-                    lm_size_shift,
-                    base_decimals,
-                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8, 1, 1, 1, 1];
-                LyraeInstruction::CreatePerpMarket {
-                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
-                    init_leverage: I80F48::from_le_bytes(*init_leverage),
-                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
-                    maker_fee: I80F48::from_le_bytes(*maker_fee),
-                    taker_fee: I80F48::from_le_bytes(*taker_fee),
-                    base_lot_size: i64::from_le_bytes(*base_lot_size),
-                    quote_lot_size: i64::from_le_bytes(*quote_lot_size),
-                    rate: I80F48::from_le_bytes(*rate),
-                    max_depth_bps: I80F48::from_le_bytes(*max_depth_bps),
-                    target_period_length: u64::from_le_bytes(*target_period_length),
-                    lyr_per_period: u64::from_le_bytes(*lyr_per_period),
-                    exp: exp[0],
-                    version: version[0],
-                    lm_size_shift: lm_size_shift[0],
-                    base_decimals: base_decimals[0],
-                }
-            }
-            47 => {
-                let data_arr = array_ref![data, 0, 143];
+    /// Set the max Pyth confidence interval, in bps of the price, for a Pyth price to be used.
+    /// 0 restores the default PYTH_CONF_FILTER. Not enforced in devnet builds.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    SetPythConfFilter {
+        conf_filter_bps: u16,
+    },
+
+    /// Compute what LiquidatePerpMarket would transfer without changing any state and emit a
+    /// SimulatedLiquidationLog. Meant to be run via simulateTransaction by liquidators.
+    /// Fails the same way LiquidatePerpMarket would, except that the liqor's health is reported
+    /// instead of checked.
+    ///
+    /// Accounts expected: 5 + Liqee open orders accounts (MAX_PAIRS) + Liqor open orders accounts (MAX_PAIRS)
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[]` perp_market_ai - PerpMarket
+    /// 3. `[]` liqee_lyrae_account_ai - LyraeAccount
+    /// 4. `[]` liqor_lyrae_account_ai - LyraeAccount
+    /// 5+... `[]` liqee_open_orders_ais - Liqee open orders accs
+    /// 5+MAX_PAIRS... `[]` liqor_open_orders_ais - Liqor open orders accs
+    SimulateLiquidatePerpMarket {
+        base_transfer_request: i64,
+
+        /// Same as in LiquidatePerpMarket
+        #[serde(serialize_with = "serialize_option_fixed_width")]
+        liqor_max_base_position: Option<u64>,
+    },
+
+    /// Set the label and parent LyraeAccount used by UIs to group accounts of the same owner.
+    /// It will create the AccountGroup account as a PDA of the LyraeAccount if it doesn't exist.
+    /// Leaving out the parent account clears it. This has no effect on health.
+    ///
+    /// Accounts expected by this instruction (6 + 1 optional):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup that this lyrae account is for
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2. `[signer]` owner_ai - LyraeAccount owner
+    /// 3. `[writable]` account_group_ai - AccountGroup PDA; will be initialized if required
+    /// 4. `[signer, writable]` payer_ai - payer for PDA; can be same as owner
+    /// 5. `[]` system_prog_ai - System program
+    /// 6. `[]` parent_lyrae_account_ai - (optional) LyraeAccount of the same owner and group
+    SetAccountGroup {
+        account_label: [u8; 8],
+    },
+
+    /// Update the indexes of several root banks and their LyraeCache entries in one instruction.
+    /// Each root bank is followed by exactly its `num_node_banks` node banks.
+    ///
+    /// Accounts expected: 2 + (1 + Node Banks) per Root Bank
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` lyrae_cache_ai - LyraeCache
+    /// 2+... `[writable]` root_bank_ai - RootBank, then `[]` node_bank_ais - its NodeBanks
+    UpdateRootBanks,
+
+    /// Remove a spot market and its token from the group. Fails with InvalidAccountState while any
+    /// node bank of the token still has deposits or borrows. The oracle is kept.
+    ///
+    /// Accounts expected: 3 + Node Banks
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    /// 2. `[]` root_bank_ai - RootBank of the token
+    /// 3+... `[]` node_bank_ais - all NodeBanks of the RootBank, in order
+    RemoveSpotMarket,
+
+    /// Execute the PerpTrigger orders of several LyraeAccounts on the same perp market, like
+    /// ExecutePerpTriggerOrder. Orders that are inactive, for another market or whose trigger
+    /// condition is not met are skipped, as are orders of accounts whose cache is stale or whose
+    /// open orders are not passed. The first `num_orders` entries of `order_indexes` are
+    /// used, one per (lyrae_account, advanced_orders) pair.
+    ///
+    /// Accounts expected: 7 + 2 * num_orders + open orders accounts of all the LyraeAccounts
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable,signer]` agent_ai - operator of the execution service (receives lamports)
+    /// 2. `[]` lyrae_cache_ai - LyraeCache for this LyraeGroup
+    /// 3. `[writable]` perp_market_ai
+    /// 4. `[writable]` bids_ai - bids account for this PerpMarket
+    /// 5. `[writable]` asks_ai - asks account for this PerpMarket
+    /// 6. `[writable]` event_queue_ai - EventQueue for this PerpMarket
+    /// 7+2*i `[writable]` lyrae_account_ai - LyraeAccount of the i-th order
+    /// 8+2*i `[writable]` advanced_orders_ai - its AdvancedOrders account
+    /// 7+2*num_orders... `[]` open_orders_ais - spot open orders in the margin baskets, any order
+    ExecutePerpTriggerOrdersMulti {
+        num_orders: u8,
+        order_indexes: [u8; MAX_EXECUTE_TRIGGER_ORDERS],
+    },
+
+    /// Cancel a resting perp order and place a new one on the same side, with a single health
+    /// check on the resulting state. Liquidity mining incentives of the cancelled order accrue
+    /// as in CancelPerpOrder.
+    ///
+    /// Accounts expected by this instruction (8 + `MAX_PAIRS` + (optional 2)):
+    /// same as PlacePerpOrder
+    PlacePerpOrderAmend {
+        /// Order to amend; interpreted as its client_order_id if `by_client_id`
+        order_id: i128,
+        by_client_id: bool,
+        price: i64,
+        quantity: i64,
+        client_order_id: u64,
+        order_type: OrderType,
+        reduce_only: bool,
+    },
+
+    /// Set the min equity, in whole quote tokens, e.g. 100 for 100 USDC, a LyraeAccount needs to
+    /// place orders that increase its risk. Reduce only perp orders and spot orders that don't
+    /// borrow are always allowed. 0 disables the check.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    SetMinCollateralToTrade {
+        min_collateral_to_trade: u32,
+    },
+
+    /// Like ConsumeEvents, but only processes the FillEvents in which `lyrae_account` is the
+    /// maker or the taker, among the first 64 events of the queue. Fills processed ahead of other
+    /// events are marked Consumed and popped once they reach the front.
+    ///
+    /// Accounts expected by this instruction (4 + the LyraeAccounts of the fills):
+    /// same as ConsumeEvents; the counterparties of the fills must be passed as well
+    ConsumeEventsForAccount {
+        lyrae_account: Pubkey,
+        limit: usize,
+    },
+
+    /// Like PlacePerpOrderAmend, but fails if the largest position the account can reach on the
+    /// market, its base position plus the resting orders of either side, would grow. Lets bots
+    /// re-quote exit orders without a window in which the old order is already cancelled.
+    ///
+    /// Accounts expected by this instruction (8 + `MAX_PAIRS` + (optional 1)):
+    /// same as PlacePerpOrder
+    ModifyPerpOrderReduceOnly {
+        /// Order to modify; interpreted as its client_order_id if `by_client_id`
+        order_id: i128,
+        by_client_id: bool,
+        price: i64,
+        quantity: i64,
+        client_order_id: u64,
+        order_type: OrderType,
+    },
+
+    /// Emit a NativeTokenBalanceLog for each token the LyraeAccount has a deposit or borrow in,
+    /// converted to native units with the cached deposit and borrow indexes. Read only.
+    ///
+    /// Accounts expected by this instruction (3):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2. `[]` lyrae_cache_ai - LyraeCache
+    LogTokenBalances,
+
+    /// Update funding for several perp markets and the indexes of several root banks, writing
+    /// the results to the LyraeCache in one instruction. At most MAX_UPDATE_ALL_CACHES_ITEMS
+    /// markets and root banks are processed; anything passed beyond that is left for a later call.
+    ///
+    /// Accounts expected: 2 + 3 * num_perp_markets + (1 + Node Banks) per Root Bank
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` lyrae_cache_ai - LyraeCache
+    /// 2+... `[writable]` perp_market_ai - PerpMarket, then `[]` bids_ai and `[]` asks_ai
+    /// then... `[writable]` root_bank_ai - RootBank, then `[]` node_bank_ais - its NodeBanks
+    UpdateAllCaches {
+        num_perp_markets: u8,
+    },
+
+    /// Exempt a LyraeAccount, e.g. a designated market maker, from the referral surcharge on
+    /// taker fees, or remove the exemption
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 2. `[signer]` admin_ai - Admin
+    SetMmExempt {
+        mm_exempt: bool,
+    },
+
+    /// Emit a HealthComputeEstimateLog with the number of spot markets, open orders accounts and
+    /// perp markets a health check of the LyraeAccount has to value. Clients can use it to warn
+    /// users whose instructions approach the compute limit. Read only.
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    LogHealthComputeEstimate,
+
+    /// Settle the free funds of a liqee's open orders account into the LyraeGroup vaults without
+    /// cancelling any orders. Lets a liquidation split ForceCancelSpotOrders and the settle across
+    /// transactions when one transaction runs out of compute. The liqee must be being liquidated.
+    ///
+    /// Accounts expected by this instruction (17):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[writable]` liqee_lyrae_account_ai - LyraeAccount
+    /// 3. `[]` base_root_bank_ai - RootBank
+    /// 4. `[writable]` base_node_bank_ai - NodeBank
+    /// 5. `[writable]` base_vault_ai - LyraeGroup base vault acc
+    /// 6. `[]` quote_root_bank_ai - RootBank
+    /// 7. `[writable]` quote_node_bank_ai - NodeBank
+    /// 8. `[writable]` quote_vault_ai - LyraeGroup quote vault acc
+    /// 9. `[writable]` spot_market_ai - SpotMarket
+    /// 10. `[writable]` open_orders_ai - Liqee open orders acc for this market
+    /// 11. `[]` signer_ai - LyraeGroup signer key
+    /// 12. `[writable]` dex_base_ai - base vault for dex MarketState
+    /// 13. `[writable]` dex_quote_ai - quote vault for dex MarketState
+    /// 14. `[]` dex_signer_ai - dex Market signer account
+    /// 15. `[]` dex_prog_ai - Dex Program acc
+    /// 16. `[]` token_prog_ai - Token Program acc
+    ForceSettleFunds,
+
+    /// Clear a stuck `being_liquidated` flag. Fails unless the account's init health, computed
+    /// from the passed cache and open orders, is positive.
+    ///
+    /// Accounts expected by this instruction (4 + MAX_PAIRS):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 3. `[signer]` admin_ai - Admin
+    /// 4+... `[]` open_orders_ais - array of MAX_PAIRS open orders accounts on this LyraeAccount
+    AdminClearLiquidationFlag,
+
+    /// Emit a TokenBalanceLog for every token the LyraeAccount still borrows. Read only.
+    /// A borrow can't be repaid from a deposit of the same token: every deposit or withdrawal
+    /// nets them, so an account never holds both. Repaying from other tokens needs a swap.
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[]` lyrae_account_ai - LyraeAccount
+    LogRemainingBorrows,
+    /// Compute the health of a LyraeAccount without changing any state. The return data holds
+    /// the I80F48 bits of init health, maint health and equity as three little endian i128s.
+    ///
+    /// Accounts expected by this instruction (3 + MAX_PAIRS):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    /// 2. `[]` lyrae_cache_ai - LyraeCache
+    /// 3+... `[]` open_orders_ais - array of MAX_PAIRS open orders accounts on this LyraeAccount
+    ComputeHealth,
+
+    /// Set the percent of settled perp fees that SettleFees and CrankSettleFees send to the
+    /// insurance vault instead of the fees vault. Both vaults hold the quote token.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    SetFeesInsuranceShare {
+        insurance_share_pct: u8,
+    },
+
+    /// Cancel the resting spot orders of a LyraeAccount in each of the passed markets, up to
+    /// `limit` orders per market. Markets not in the margin basket or without open orders are
+    /// skipped. The cancelled funds stay in the open orders until they are settled.
+    ///
+    /// Accounts expected: 5 + 5 per market
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` owner_ai - Owner or delegate of the LyraeAccount
+    /// 2. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 3. `[]` dex_prog_ai - Dex Program acc
+    /// 4. `[]` signer_ai - Group Signer Account
+    /// 5+... `[writable]` spot_market_ai, bids_ai, asks_ai, open_orders_ai, dex_event_queue_ai
+    ///    of each market
+    CancelAllSpotOrders {
+        limit: u8,
+    },
+
+    /// Emit a LyrAccruedLog with the LYR a LyraeAccount has accrued but not yet redeemed, per
+    /// perp market and in total. Read only.
+    ///
+    /// Accounts expected by this instruction (2):
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_account_ai - LyraeAccount
+    LogLyrAccrued,
+
+    /// Pause or unpause the group. While paused, Deposit, Withdraw with allow_borrow and all
+    /// spot and perp order placement, including trigger order execution, fail with GroupPaused.
+    /// Cancels, settling and liquidations still work.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    SetGroupPaused {
+        paused: bool,
+    },
+
+    /// Set the min improvement of the liqee's init health, in whole quote tokens, that a
+    /// LiquidateTokenAndToken must achieve when the transfer is capped by the liqor's
+    /// max_liab_transfer. Liquidations that bring the liqee to init_health == 0 or exhaust the
+    /// liab borrow or asset deposit are always allowed. 0 disables the check.
+    ///
+    /// Accounts expected by this instruction (2):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    ChangeMinLiquidationHealthImprovement {
+        min_health_improvement: u32,
+    },
+
+    /// After SetDexProgramId, replace the LyraeAccount's OpenOrders for a spot market, which is
+    /// owned by the old dex program, with a new one under the group's current dex program. The
+    /// old OpenOrders must be empty and settled; it is closed and its lamports go to the owner.
+    ///
+    /// Accounts expected by this instruction (11):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` lyrae_account_ai - LyraeAccount
+    /// 2. `[writable, signer]` owner_ai - Owner of the LyraeAccount
+    /// 3. `[]` old_dex_prog_ai - dex program that owns the old OpenOrders
+    /// 4. `[writable]` old_open_orders_ai - current OpenOrders of the LyraeAccount in the market
+    /// 5. `[]` old_spot_market_ai - market the old OpenOrders was opened on
+    /// 6. `[]` dex_prog_ai - the group's current dex program
+    /// 7. `[writable]` open_orders_ai - new, uninitialized OpenOrders owned by dex_prog_ai
+    /// 8. `[]` spot_market_ai - the group's spot market, already moved by ReassignSpotMarket
+    /// 9. `[]` signer_ai - Group Signer Account
+    /// 10. `[]` rent_ai - Rent sysvar
+    ReassignSpotOpenOrders,
+
+    /// Move the whole perp position (base and quote) of one LyraeAccount in a market to another
+    /// LyraeAccount of the same owner, after settling funding on both. The source must have no
+    /// open orders or unprocessed fills in the market. Both accounts must end with
+    /// init_health >= 0.
+    ///
+    /// Accounts expected by this instruction (6 + 2 * MAX_PAIRS):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` lyrae_cache_ai - LyraeCache
+    /// 2. `[writable]` perp_market_ai - PerpMarket
+    /// 3. `[writable]` src_lyrae_account_ai - LyraeAccount the position moves out of
+    /// 4. `[writable]` dst_lyrae_account_ai - LyraeAccount the position moves into
+    /// 5. `[signer]` owner_ai - Owner, or delegate with all permissions, of both LyraeAccounts
+    /// 6.. `[]` src_open_orders_ais - MAX_PAIRS open orders accounts of the source
+    /// 6 + MAX_PAIRS.. `[]` dst_open_orders_ais - MAX_PAIRS open orders accounts of the destination
+    TransferPerpPosition,
+
+    /// Emergency lever to lower the asset weights of a spot market, e.g. during a depeg.
+    /// Unlike ChangeSpotMarketParams this may decrease maint_asset_weight, which can make
+    /// accounts immediately liquidatable, so it only ever lowers the weights: each new weight
+    /// must be <= the current one, init_asset_weight >= 0 and maint_asset_weight >
+    /// init_asset_weight. Liab weights are left untouched. Raising the weights back goes through
+    /// ChangeSpotMarketParams.
+    ///
+    /// Accounts expected by this instruction (3):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[]` spot_market_ai - Market
+    /// 2. `[signer]` admin_ai - LyraeGroup admin
+    EmergencySetAssetWeight {
+        init_asset_weight: I80F48,
+        maint_asset_weight: I80F48,
+    },
+
+    /// Set one of the MAX_MSRM_FEE_TIERS msrm fee tiers. Takers whose LyraeAccount has at least
+    /// `min_msrm` deposited via DepositMsrm, and who pass the MsrmFeeTiers account with their
+    /// perp order, pay the perp taker fee reduced by `discount_bps`; the largest discount of the
+    /// tiers they qualify for applies. Referral fees are not discounted, and neither are trigger
+    /// orders executed by keepers. A `discount_bps` of 0 disables the tier.
+    ///
+    /// Accounts expected by this instruction (4):
+    ///
+    /// 0. `[]` lyrae_group_ai - LyraeGroup
+    /// 1. `[writable]` msrm_fee_tiers_ai - MsrmFeeTiers PDA, seeds [lyrae_group, "MsrmFeeTiers"];
+    ///     created on first use
+    /// 2. `[writable, signer]` admin_ai - Admin, pays for creating msrm_fee_tiers_ai
+    /// 3. `[]` system_prog_ai - System program
+    SetMsrmFeeTier {
+        tier: u8,
+        min_msrm: u64,
+        discount_bps: u16,
+    },
+
+    /// Before SetDexProgramId, point a listed spot market at its market under the new dex
+    /// program. The new market must trade the same base and quote mints. Spot instructions for
+    /// the market fail until SetDexProgramId is done.
+    ///
+    /// Accounts expected by this instruction (5):
+    ///
+    /// 0. `[writable]` lyrae_group_ai - LyraeGroup
+    /// 1. `[signer]` admin_ai - Admin
+    /// 2. `[]` dex_prog_ai - the new dex program
+    /// 3. `[]` old_spot_market_ai - listed market under the group's current dex program
+    /// 4. `[]` spot_market_ai - market under dex_prog_ai
+    ReassignSpotMarket,
+}
+
+impl LyraeInstruction {
+    pub fn unpack(input: &[u8]) -> Option<Self> {
+        let (&discrim, data) = array_refs![input, 4; ..;];
+        let discrim = u32::from_le_bytes(discrim);
+        Some(match discrim {
+            0 => {
+                let data = array_ref![data, 0, 64];
                 let (
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                    exp,
-                    version,
-                    lm_size_shift,
-                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 17, 9, 9, 2, 2, 2];
+                    signer_nonce,
+                    valid_interval,
+                    quote_optimal_util,
+                    quote_optimal_rate,
+                    quote_max_rate,
+                ) = array_refs![data, 8, 8, 16, 16, 16];
 
-                LyraeInstruction::ChangePerpMarketParams2 {
-                    maint_leverage: unpack_i80f48_opt(maint_leverage),
-                    init_leverage: unpack_i80f48_opt(init_leverage),
-                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
-                    maker_fee: unpack_i80f48_opt(maker_fee),
-                    taker_fee: unpack_i80f48_opt(taker_fee),
-                    rate: unpack_i80f48_opt(rate),
-                    max_depth_bps: unpack_i80f48_opt(max_depth_bps),
-                    target_period_length: unpack_u64_opt(target_period_length),
-                    lyr_per_period: unpack_u64_opt(lyr_per_period),
-                    exp: unpack_u8_opt(exp),
-                    version: unpack_u8_opt(version),
-                    lm_size_shift: unpack_u8_opt(lm_size_shift),
-                }
-            }
-            48 => LyraeInstruction::UpdateMarginBasket,
-            49 => {
-                let data_arr = array_ref![data, 0, 4];
-                LyraeInstruction::ChangeMaxLyraeAccounts {
-                    max_lyrae_accounts: u32::from_le_bytes(*data_arr),
+                LyraeInstruction::InitLyraeGroup {
+                    signer_nonce: u64::from_le_bytes(*signer_nonce),
+                    valid_interval: u64::from_le_bytes(*valid_interval),
+                    quote_optimal_util: I80F48::from_le_bytes(*quote_optimal_util),
+                    quote_optimal_rate: I80F48::from_le_bytes(*quote_optimal_rate),
+                    quote_max_rate: I80F48::from_le_bytes(*quote_max_rate),
                 }
             }
-            50 => LyraeInstruction::CloseLyraeAccount,
-            51 => LyraeInstruction::CloseSpotOpenOrders,
-            52 => LyraeInstruction::CloseAdvancedOrders,
-            53 => LyraeInstruction::CreateDustAccount,
-            54 => LyraeInstruction::ResolveDust,
-            55 => {
-                let account_num = array_ref![data, 0, 8];
-                LyraeInstruction::CreateLyraeAccount {
-                    account_num: u64::from_le_bytes(*account_num),
+            1 => LyraeInstruction::InitLyraeAccount,
+            2 => {
+                let quantity = array_ref![data, 0, 8];
+                LyraeInstruction::Deposit {
+                    quantity: u64::from_le_bytes(*quantity),
                 }
             }
-            56 => LyraeInstruction::UpgradeLyraeAccountV0V1,
-            57 => {
-                let data_arr = array_ref![data, 0, 2];
-                let (side, limit) = array_refs![data_arr, 1, 1];
+            3 => {
+                let close_dust = if data.len() > 9 {
+                    data[9] != 0
+                } else {
+                    false
+                };
+                let data = array_ref![data, 0, 9];
+                let (quantity, allow_borrow) = array_refs![data, 8, 1];
 
-                LyraeInstruction::CancelPerpOrdersSide {
-                    side: Side::try_from_primitive(side[0]).ok()?,
-                    limit: u8::from_le_bytes(*limit),
+                let allow_borrow = match allow_borrow {
+                    [0] => false,
+                    [1] => true,
+                    _ => return None,
+                };
+                LyraeInstruction::Withdraw {
+                    quantity: u64::from_le_bytes(*quantity),
+                    allow_borrow,
+                    close_dust,
                 }
             }
-            58 => LyraeInstruction::SetDelegate,
-            59 => {
-                let data_arr = array_ref![data, 0, 104];
+            4 => {
+                let data = array_ref![data, 0, 96];
                 let (
                     maint_leverage,
                     init_leverage,
@@ -1433,163 +1847,2080 @@ impl LyraeInstruction {
                     optimal_util,
                     optimal_rate,
                     max_rate,
-                    version,
-                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 2];
+                ) = array_refs![data, 16, 16, 16, 16, 16, 16];
+                LyraeInstruction::AddSpotMarket {
+                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
+                    init_leverage: I80F48::from_le_bytes(*init_leverage),
+                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
+                    optimal_util: I80F48::from_le_bytes(*optimal_util),
+                    optimal_rate: I80F48::from_le_bytes(*optimal_rate),
+                    max_rate: I80F48::from_le_bytes(*max_rate),
+                }
+            }
+            5 => {
+                let market_index = array_ref![data, 0, 8];
+                LyraeInstruction::AddToBasket {
+                    market_index: usize::from_le_bytes(*market_index),
+                }
+            }
+            6 => {
+                let quantity = array_ref![data, 0, 8];
+                LyraeInstruction::Borrow {
+                    quantity: u64::from_le_bytes(*quantity),
+                }
+            }
+            7 => LyraeInstruction::CachePrices,
+            8 => LyraeInstruction::CacheRootBanks,
+            9 => {
+                let data_arr = array_ref![data, 0, 46];
+                let order = unpack_dex_new_order_v3(data_arr)?;
+                LyraeInstruction::PlaceSpotOrder { order }
+            }
+            10 => LyraeInstruction::AddOracle,
+            11 => {
+                let exp = if data.len() > 144 { data[144] } else { 2 };
+                let data_arr = array_ref![data, 0, 144];
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    base_lot_size,
+                    quote_lot_size,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8];
+                LyraeInstruction::AddPerpMarket {
+                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
+                    init_leverage: I80F48::from_le_bytes(*init_leverage),
+                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
+                    maker_fee: I80F48::from_le_bytes(*maker_fee),
+                    taker_fee: I80F48::from_le_bytes(*taker_fee),
+                    base_lot_size: i64::from_le_bytes(*base_lot_size),
+                    quote_lot_size: i64::from_le_bytes(*quote_lot_size),
+                    rate: I80F48::from_le_bytes(*rate),
+                    max_depth_bps: I80F48::from_le_bytes(*max_depth_bps),
+                    target_period_length: u64::from_le_bytes(*target_period_length),
+                    lyr_per_period: u64::from_le_bytes(*lyr_per_period),
+                    exp,
+                }
+            }
+            12 => {
+                let reduce_only = if data.len() > 26 {
+                    data[26] != 0
+                } else {
+                    false
+                };
+                let data_arr = array_ref![data, 0, 26];
+                let (price, quantity, client_order_id, side, order_type) =
+                    array_refs![data_arr, 8, 8, 8, 1, 1];
+                LyraeInstruction::PlacePerpOrder {
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    side: Side::try_from_primitive(side[0]).ok()?,
+                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                    reduce_only,
+                }
+            }
+            13 => {
+                let data_arr = array_ref![data, 0, 9];
+                let (client_order_id, invalid_id_ok) = array_refs![data_arr, 8, 1];
 
-                LyraeInstruction::ChangeSpotMarketParams {
-                    maint_leverage: unpack_i80f48_opt(maint_leverage),
-                    init_leverage: unpack_i80f48_opt(init_leverage),
-                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
-                    optimal_util: unpack_i80f48_opt(optimal_util),
-                    optimal_rate: unpack_i80f48_opt(optimal_rate),
-                    max_rate: unpack_i80f48_opt(max_rate),
-                    version: unpack_u8_opt(version),
+                LyraeInstruction::CancelPerpOrderByClientId {
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    invalid_id_ok: invalid_id_ok[0] != 0,
                 }
             }
-            60 => LyraeInstruction::CreateSpotOpenOrders,
-            61 => {
+            14 => {
+                let data_arr = array_ref![data, 0, 17];
+                let (order_id, invalid_id_ok) = array_refs![data_arr, 16, 1];
+                LyraeInstruction::CancelPerpOrder {
+                    order_id: i128::from_le_bytes(*order_id),
+                    invalid_id_ok: invalid_id_ok[0] != 0,
+                }
+            }
+            15 => {
+                let data_arr = array_ref![data, 0, 8];
+                LyraeInstruction::ConsumeEvents {
+                    limit: usize::from_le_bytes(*data_arr),
+                }
+            }
+            16 => LyraeInstruction::CachePerpMarkets,
+            17 => LyraeInstruction::UpdateFunding,
+            18 => {
+                let last_update = if data.len() >= 24 {
+                    u64::from_le_bytes(*array_ref![data, 16, 8])
+                } else {
+                    0
+                };
+                let data_arr = array_ref![data, 0, 16];
+                LyraeInstruction::SetOracle {
+                    price: I80F48::from_le_bytes(*data_arr),
+                    last_update,
+                }
+            }
+            19 => LyraeInstruction::SettleFunds,
+            20 => {
+                let ok_invalid_id = if data.len() > 20 { data[20] != 0 } else { false };
+                let data_array = array_ref![data, 0, 20];
+                let fields = array_refs![data_array, 4, 16];
+                let side = match u32::from_le_bytes(*fields.0) {
+                    0 => serum_dex::matching::Side::Bid,
+                    1 => serum_dex::matching::Side::Ask,
+                    _ => return None,
+                };
+                let order_id = u128::from_le_bytes(*fields.1);
+                let order = serum_dex::instruction::CancelOrderInstructionV2 { side, order_id };
+                LyraeInstruction::CancelSpotOrder { order, ok_invalid_id }
+            }
+            21 => LyraeInstruction::UpdateRootBank,
+
+            22 => {
+                let data_arr = array_ref![data, 0, 8];
+
+                LyraeInstruction::SettlePnl {
+                    market_index: usize::from_le_bytes(*data_arr),
+                }
+            }
+            23 => {
                 let data = array_ref![data, 0, 16];
-                let (ref_surcharge_centibps, ref_share_centibps, ref_lyr_required) =
-                    array_refs![data, 4, 4, 8];
-                LyraeInstruction::ChangeReferralFeeParams {
-                    ref_surcharge_centibps: u32::from_le_bytes(*ref_surcharge_centibps),
-                    ref_share_centibps: u32::from_le_bytes(*ref_share_centibps),
-                    ref_lyr_required: u64::from_le_bytes(*ref_lyr_required),
+                let (token_index, quantity) = array_refs![data, 8, 8];
+
+                LyraeInstruction::SettleBorrow {
+                    token_index: usize::from_le_bytes(*token_index),
+                    quantity: u64::from_le_bytes(*quantity),
                 }
             }
-            62 => LyraeInstruction::SetReferrerMemory,
-            63 => {
-                let referrer_id = array_ref![data, 0, INFO_LEN];
-                LyraeInstruction::RegisterReferrerId {
-                    referrer_id: *referrer_id,
+            24 => {
+                let data_arr = array_ref![data, 0, 1];
+
+                LyraeInstruction::ForceCancelSpotOrders {
+                    limit: u8::from_le_bytes(*data_arr),
                 }
             }
-            _ => {
-                return None;
+            25 => {
+                let data_arr = array_ref![data, 0, 1];
+
+                LyraeInstruction::ForceCancelPerpOrders {
+                    limit: u8::from_le_bytes(*data_arr),
+                }
             }
-        })
-    }
-    pub fn pack(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
-    }
-}
+            26 => {
+                let max_health_fraction = if data.len() >= 32 {
+                    I80F48::from_le_bytes(*array_ref![data, 16, 16])
+                } else {
+                    I80F48::from_num(1)
+                };
+                let data_arr = array_ref![data, 0, 16];
+
+                LyraeInstruction::LiquidateTokenAndToken {
+                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
+                    max_health_fraction,
+                }
+            }
+            27 => {
+                let data = array_ref![data, 0, 34];
+                let (asset_type, asset_index, liab_type, liab_index, max_liab_transfer) =
+                    array_refs![data, 1, 8, 1, 8, 16];
+
+                LyraeInstruction::LiquidateTokenAndPerp {
+                    asset_type: AssetType::try_from(u8::from_le_bytes(*asset_type)).unwrap(),
+                    asset_index: usize::from_le_bytes(*asset_index),
+                    liab_type: AssetType::try_from(u8::from_le_bytes(*liab_type)).unwrap(),
+                    liab_index: usize::from_le_bytes(*liab_index),
+                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
+                }
+            }
+            28 => {
+                let liqor_max_base_position = if data.len() >= 17 {
+                    unpack_u64_opt(array_ref![data, 8, 9])
+                } else {
+                    None
+                };
+                let data_arr = array_ref![data, 0, 8];
+
+                LyraeInstruction::LiquidatePerpMarket {
+                    base_transfer_request: i64::from_le_bytes(*data_arr),
+                    liqor_max_base_position,
+                }
+            }
+            29 => LyraeInstruction::SettleFees,
+            30 => {
+                let data = array_ref![data, 0, 24];
+                let (liab_index, max_liab_transfer) = array_refs![data, 8, 16];
+
+                LyraeInstruction::ResolvePerpBankruptcy {
+                    liab_index: usize::from_le_bytes(*liab_index),
+                    max_liab_transfer: I80F48::from_le_bytes(*max_liab_transfer),
+                }
+            }
+            31 => {
+                let max_liab_price = if data.len() >= 33 {
+                    unpack_i80f48_opt(array_ref![data, 16, 17])
+                } else {
+                    None
+                };
+                let data_arr = array_ref![data, 0, 16];
+
+                LyraeInstruction::ResolveTokenBankruptcy {
+                    max_liab_transfer: I80F48::from_le_bytes(*data_arr),
+                    max_liab_price,
+                }
+            }
+            32 => LyraeInstruction::InitSpotOpenOrders,
+            33 => LyraeInstruction::RedeemLyr,
+            34 => {
+                let info = array_ref![data, 0, INFO_LEN];
+                LyraeInstruction::AddLyraeAccountInfo { info: *info }
+            }
+            35 => {
+                let quantity = array_ref![data, 0, 8];
+                LyraeInstruction::DepositMsrm {
+                    quantity: u64::from_le_bytes(*quantity),
+                }
+            }
+            36 => {
+                let quantity = array_ref![data, 0, 8];
+                LyraeInstruction::WithdrawMsrm {
+                    quantity: u64::from_le_bytes(*quantity),
+                }
+            }
+
+            37 => {
+                let exp = if data.len() > 137 {
+                    unpack_u8_opt(&[data[137], data[138]])
+                } else {
+                    None
+                };
+                let data_arr = array_ref![data, 0, 137];
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 17, 9, 9];
+
+                LyraeInstruction::ChangePerpMarketParams {
+                    maint_leverage: unpack_i80f48_opt(maint_leverage),
+                    init_leverage: unpack_i80f48_opt(init_leverage),
+                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
+                    maker_fee: unpack_i80f48_opt(maker_fee),
+                    taker_fee: unpack_i80f48_opt(taker_fee),
+                    rate: unpack_i80f48_opt(rate),
+                    max_depth_bps: unpack_i80f48_opt(max_depth_bps),
+                    target_period_length: unpack_u64_opt(target_period_length),
+                    lyr_per_period: unpack_u64_opt(lyr_per_period),
+                    exp,
+                }
+            }
+
+            38 => LyraeInstruction::SetGroupAdmin,
+
+            39 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::CancelAllPerpOrders {
+                    limit: u8::from_le_bytes(*data_arr),
+                }
+            }
+
+            40 => LyraeInstruction::ForceSettleQuotePositions,
+            41 => {
+                let data_arr = array_ref![data, 0, 46];
+                let order = unpack_dex_new_order_v3(data_arr)?;
+                LyraeInstruction::PlaceSpotOrder2 { order }
+            }
+
+            42 => LyraeInstruction::InitAdvancedOrders,
+
+            43 => {
+                let data_arr = array_ref![data, 0, 44];
+                let (
+                    order_type,
+                    side,
+                    trigger_condition,
+                    reduce_only,
+                    client_order_id,
+                    price,
+                    quantity,
+                    trigger_price,
+                ) = array_refs![data_arr, 1, 1, 1, 1, 8, 8, 8, 16];
+                LyraeInstruction::AddPerpTriggerOrder {
+                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                    side: Side::try_from_primitive(side[0]).ok()?,
+                    trigger_condition: TriggerCondition::try_from(u8::from_le_bytes(
+                        *trigger_condition,
+                    ))
+                    .unwrap(),
+                    reduce_only: reduce_only[0] != 0,
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    trigger_price: I80F48::from_le_bytes(*trigger_price),
+                    trail_offset: if data.len() >= 60 {
+                        I80F48::from_le_bytes(*array_ref![data, 44, 16])
+                    } else {
+                        I80F48::from_num(0)
+                    },
+                }
+            }
+
+            44 => {
+                let order_index = array_ref![data, 0, 1][0];
+                LyraeInstruction::RemoveAdvancedOrder { order_index }
+            }
+            45 => {
+                let order_index = array_ref![data, 0, 1][0];
+                LyraeInstruction::ExecutePerpTriggerOrder { order_index }
+            }
+            46 => {
+                let data_arr = array_ref![data, 0, 148];
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    base_lot_size,
+                    quote_lot_size,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                    exp,
+                    version,
+                    lm_size_shift,
+                    base_decimals,
+                ) = array_refs![data_arr, 16, 16, 16, 16, 16, 8, 8, 16, 16, 8, 8, 1, 1, 1, 1];
+                LyraeInstruction::CreatePerpMarket {
+                    maint_leverage: I80F48::from_le_bytes(*maint_leverage),
+                    init_leverage: I80F48::from_le_bytes(*init_leverage),
+                    liquidation_fee: I80F48::from_le_bytes(*liquidation_fee),
+                    maker_fee: I80F48::from_le_bytes(*maker_fee),
+                    taker_fee: I80F48::from_le_bytes(*taker_fee),
+                    base_lot_size: i64::from_le_bytes(*base_lot_size),
+                    quote_lot_size: i64::from_le_bytes(*quote_lot_size),
+                    rate: I80F48::from_le_bytes(*rate),
+                    max_depth_bps: I80F48::from_le_bytes(*max_depth_bps),
+                    target_period_length: u64::from_le_bytes(*target_period_length),
+                    lyr_per_period: u64::from_le_bytes(*lyr_per_period),
+                    exp: exp[0],
+                    version: version[0],
+                    lm_size_shift: lm_size_shift[0],
+                    base_decimals: base_decimals[0],
+                }
+            }
+            47 => {
+                let max_order_price_deviation_bps = if data.len() >= 146 {
+                    unpack_u16_opt(array_ref![data, 143, 3])
+                } else {
+                    None
+                };
+                let funding_impact_notional = if data.len() >= 151 {
+                    unpack_u32_opt(array_ref![data, 146, 5])
+                } else {
+                    None
+                };
+                let data_arr = array_ref![data, 0, 143];
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                    exp,
+                    version,
+                    lm_size_shift,
+                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 17, 9, 9, 2, 2, 2];
+
+                LyraeInstruction::ChangePerpMarketParams2 {
+                    maint_leverage: unpack_i80f48_opt(maint_leverage),
+                    init_leverage: unpack_i80f48_opt(init_leverage),
+                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
+                    maker_fee: unpack_i80f48_opt(maker_fee),
+                    taker_fee: unpack_i80f48_opt(taker_fee),
+                    rate: unpack_i80f48_opt(rate),
+                    max_depth_bps: unpack_i80f48_opt(max_depth_bps),
+                    target_period_length: unpack_u64_opt(target_period_length),
+                    lyr_per_period: unpack_u64_opt(lyr_per_period),
+                    exp: unpack_u8_opt(exp),
+                    version: unpack_u8_opt(version),
+                    lm_size_shift: unpack_u8_opt(lm_size_shift),
+                    max_order_price_deviation_bps,
+                    funding_impact_notional,
+                }
+            }
+            48 => LyraeInstruction::UpdateMarginBasket,
+            49 => {
+                let data_arr = array_ref![data, 0, 4];
+                LyraeInstruction::ChangeMaxLyraeAccounts {
+                    max_lyrae_accounts: u32::from_le_bytes(*data_arr),
+                }
+            }
+            50 => LyraeInstruction::CloseLyraeAccount,
+            51 => LyraeInstruction::CloseSpotOpenOrders,
+            52 => LyraeInstruction::CloseAdvancedOrders,
+            53 => LyraeInstruction::CreateDustAccount,
+            54 => LyraeInstruction::ResolveDust,
+            55 => {
+                let account_num = array_ref![data, 0, 8];
+                LyraeInstruction::CreateLyraeAccount {
+                    account_num: u64::from_le_bytes(*account_num),
+                }
+            }
+            56 => LyraeInstruction::UpgradeLyraeAccountV0V1,
+            57 => {
+                let data_arr = array_ref![data, 0, 2];
+                let (side, limit) = array_refs![data_arr, 1, 1];
+
+                LyraeInstruction::CancelPerpOrdersSide {
+                    side: Side::try_from_primitive(side[0]).ok()?,
+                    limit: u8::from_le_bytes(*limit),
+                }
+            }
+            58 => LyraeInstruction::SetDelegate {
+                permissions: if data.len() > 0 { data[0] } else { DELEGATE_ALL },
+            },
+            59 => {
+                let data_arr = array_ref![data, 0, 104];
+                let (
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    optimal_util,
+                    optimal_rate,
+                    max_rate,
+                    version,
+                ) = array_refs![data_arr, 17, 17, 17, 17, 17, 17, 2];
+
+                LyraeInstruction::ChangeSpotMarketParams {
+                    maint_leverage: unpack_i80f48_opt(maint_leverage),
+                    init_leverage: unpack_i80f48_opt(init_leverage),
+                    liquidation_fee: unpack_i80f48_opt(liquidation_fee),
+                    optimal_util: unpack_i80f48_opt(optimal_util),
+                    optimal_rate: unpack_i80f48_opt(optimal_rate),
+                    max_rate: unpack_i80f48_opt(max_rate),
+                    version: unpack_u8_opt(version),
+                }
+            }
+            60 => LyraeInstruction::CreateSpotOpenOrders,
+            61 => {
+                let drain_required = data.len() > 16 && data[16] != 0;
+                let data = array_ref![data, 0, 16];
+                let (ref_surcharge_centibps, ref_share_centibps, ref_lyr_required) =
+                    array_refs![data, 4, 4, 8];
+                LyraeInstruction::ChangeReferralFeeParams {
+                    ref_surcharge_centibps: u32::from_le_bytes(*ref_surcharge_centibps),
+                    ref_share_centibps: u32::from_le_bytes(*ref_share_centibps),
+                    ref_lyr_required: u64::from_le_bytes(*ref_lyr_required),
+                    drain_required,
+                }
+            }
+            62 => LyraeInstruction::SetReferrerMemory,
+            63 => {
+                let referrer_id = array_ref![data, 0, INFO_LEN];
+                LyraeInstruction::RegisterReferrerId {
+                    referrer_id: *referrer_id,
+                }
+            }
+            64 => LyraeInstruction::RedeemLyrToTokenAccount,
+            65 => {
+                let data_arr = array_ref![data, 0, 2];
+                LyraeInstruction::ChangeMaxLiquidationFraction {
+                    max_liquidation_fraction_bps: u16::from_le_bytes(*data_arr),
+                }
+            }
+            66 => {
+                let data = array_ref![data, 0, 9];
+                let (quantity, allow_borrow) = array_refs![data, 8, 1];
+
+                let allow_borrow = match allow_borrow {
+                    [0] => false,
+                    [1] => true,
+                    _ => return None,
+                };
+                LyraeInstruction::SimulateWithdraw {
+                    quantity: u64::from_le_bytes(*quantity),
+                    allow_borrow,
+                }
+            }
+            67 => LyraeInstruction::LogNodeBankHealth,
+            68 => {
+                let data_arr = array_ref![data, 0, 2];
+                LyraeInstruction::ChangeMinLiquidationFee {
+                    min_liquidation_fee_bps: u16::from_le_bytes(*data_arr),
+                }
+            }
+            69 => LyraeInstruction::ClaimSerumReferrerRebates,
+            70 => LyraeInstruction::LogAdvancedOrders,
+            71 => LyraeInstruction::CrankSettleFees,
+            72 => LyraeInstruction::LogBookCapacity,
+            73 => {
+                let quantity = array_ref![data, 0, 8];
+                LyraeInstruction::DepositAndCreateSpotOpenOrders {
+                    quantity: u64::from_le_bytes(*quantity),
+                }
+            }
+            74 => {
+                let cancel_ttl_secs = array_ref![data, 0, 8];
+                LyraeInstruction::SetCancelTtl {
+                    cancel_ttl_secs: u64::from_le_bytes(*cancel_ttl_secs),
+                }
+            }
+            75 => LyraeInstruction::Heartbeat,
+            76 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::CrankExpiredCancels { limit: data_arr[0] }
+            }
+            77 => LyraeInstruction::LogPerpPnl,
+            78 => LyraeInstruction::LogCacheConsistency,
+            79 => LyraeInstruction::SetDexProgramId,
+            80 => {
+                let data_arr = array_ref![data, 0, 27];
+                let (price, quote_notional, client_order_id, side, order_type, reduce_only) =
+                    array_refs![data_arr, 8, 8, 8, 1, 1, 1];
+                LyraeInstruction::PlacePerpOrderByNotional {
+                    price: i64::from_le_bytes(*price),
+                    quote_notional: u64::from_le_bytes(*quote_notional),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    side: Side::try_from_primitive(side[0]).ok()?,
+                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                    reduce_only: reduce_only[0] != 0,
+                }
+            }
+            81 => LyraeInstruction::CrankRedeemLyr,
+            82 => LyraeInstruction::LogGroupTvl,
+            83 => {
+                let data_arr = array_ref![data, 0, 1];
+                let halted = match data_arr {
+                    [0] => false,
+                    [1] => true,
+                    _ => return None,
+                };
+                LyraeInstruction::SetGroupHalted { halted }
+            }
+            84 => {
+                let data_arr = array_ref![data, 0, 1];
+                let auto_settle_funding = match data_arr {
+                    [0] => false,
+                    [1] => true,
+                    _ => return None,
+                };
+                LyraeInstruction::SetAutoSettleFunding { auto_settle_funding }
+            }
+            85 => {
+                let data_arr = array_ref![data, 0, 8];
+                LyraeInstruction::CrankAutoSettleFunding {
+                    market_index: usize::from_le_bytes(*data_arr),
+                }
+            }
+            86 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::PrepareAccountClose {
+                    num_perp_markets: u8::from_le_bytes(*data_arr),
+                }
+            }
+            87 => LyraeInstruction::LogAllOpenOrders,
+            88 => {
+                let data_arr = array_ref![data, 0, 4];
+                let (deposit_fee_bps, withdraw_fee_bps) = array_refs![data_arr, 2, 2];
+                LyraeInstruction::SetTokenFees {
+                    deposit_fee_bps: u16::from_le_bytes(*deposit_fee_bps),
+                    withdraw_fee_bps: u16::from_le_bytes(*withdraw_fee_bps),
+                }
+            }
+            89 => {
+                let data_arr = array_ref![data, 0, 2];
+                LyraeInstruction::SetPythConfFilter {
+                    conf_filter_bps: u16::from_le_bytes(*data_arr),
+                }
+            }
+            90 => {
+                let liqor_max_base_position = if data.len() >= 17 {
+                    unpack_u64_opt(array_ref![data, 8, 9])
+                } else {
+                    None
+                };
+                let data_arr = array_ref![data, 0, 8];
+
+                LyraeInstruction::SimulateLiquidatePerpMarket {
+                    base_transfer_request: i64::from_le_bytes(*data_arr),
+                    liqor_max_base_position,
+                }
+            }
+            91 => {
+                let data_arr = array_ref![data, 0, 8];
+                LyraeInstruction::SetAccountGroup {
+                    account_label: *data_arr,
+                }
+            }
+            92 => LyraeInstruction::UpdateRootBanks,
+            93 => LyraeInstruction::RemoveSpotMarket,
+            94 => {
+                let data = array_ref![data, 0, 1 + MAX_EXECUTE_TRIGGER_ORDERS];
+                let (num_orders, order_indexes) = array_refs![data, 1, MAX_EXECUTE_TRIGGER_ORDERS];
+                if num_orders[0] as usize > MAX_EXECUTE_TRIGGER_ORDERS {
+                    return None;
+                }
+                LyraeInstruction::ExecutePerpTriggerOrdersMulti {
+                    num_orders: num_orders[0],
+                    order_indexes: *order_indexes,
+                }
+            }
+            95 => {
+                let data_arr = array_ref![data, 0, 43];
+                let (
+                    order_id,
+                    by_client_id,
+                    price,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                    reduce_only,
+                ) = array_refs![data_arr, 16, 1, 8, 8, 8, 1, 1];
+                LyraeInstruction::PlacePerpOrderAmend {
+                    order_id: i128::from_le_bytes(*order_id),
+                    by_client_id: by_client_id[0] != 0,
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                    reduce_only: reduce_only[0] != 0,
+                }
+            }
+            96 => {
+                let data_arr = array_ref![data, 0, 4];
+                LyraeInstruction::SetMinCollateralToTrade {
+                    min_collateral_to_trade: u32::from_le_bytes(*data_arr),
+                }
+            }
+            97 => {
+                let data_arr = array_ref![data, 0, 40];
+                let (lyrae_account, limit) = array_refs![data_arr, 32, 8];
+                LyraeInstruction::ConsumeEventsForAccount {
+                    lyrae_account: Pubkey::new_from_array(*lyrae_account),
+                    limit: usize::from_le_bytes(*limit),
+                }
+            }
+            98 => {
+                let data_arr = array_ref![data, 0, 42];
+                let (order_id, by_client_id, price, quantity, client_order_id, order_type) =
+                    array_refs![data_arr, 16, 1, 8, 8, 8, 1];
+                LyraeInstruction::ModifyPerpOrderReduceOnly {
+                    order_id: i128::from_le_bytes(*order_id),
+                    by_client_id: by_client_id[0] != 0,
+                    price: i64::from_le_bytes(*price),
+                    quantity: i64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                    order_type: OrderType::try_from_primitive(order_type[0]).ok()?,
+                }
+            }
+            99 => LyraeInstruction::LogTokenBalances,
+            100 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::UpdateAllCaches { num_perp_markets: data_arr[0] }
+            }
+            101 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::SetMmExempt { mm_exempt: data_arr[0] != 0 }
+            }
+            102 => LyraeInstruction::LogHealthComputeEstimate,
+            103 => LyraeInstruction::ForceSettleFunds,
+            104 => LyraeInstruction::AdminClearLiquidationFlag,
+            105 => LyraeInstruction::LogRemainingBorrows,
+            106 => LyraeInstruction::ComputeHealth,
+            107 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::SetFeesInsuranceShare { insurance_share_pct: data_arr[0] }
+            }
+            108 => {
+                let data_arr = array_ref![data, 0, 1];
+                LyraeInstruction::CancelAllSpotOrders { limit: data_arr[0] }
+            }
+            109 => LyraeInstruction::LogLyrAccrued,
+            110 => {
+                let data_arr = array_ref![data, 0, 1];
+                let paused = match data_arr {
+                    [0] => false,
+                    [1] => true,
+                    _ => return None,
+                };
+                LyraeInstruction::SetGroupPaused { paused }
+            }
+            111 => {
+                let data_arr = array_ref![data, 0, 4];
+                LyraeInstruction::ChangeMinLiquidationHealthImprovement {
+                    min_health_improvement: u32::from_le_bytes(*data_arr),
+                }
+            }
+            112 => LyraeInstruction::ReassignSpotOpenOrders,
+            113 => LyraeInstruction::TransferPerpPosition,
+            114 => {
+                let data_arr = array_ref![data, 0, 32];
+                let (init_asset_weight, maint_asset_weight) = array_refs![data_arr, 16, 16];
+                LyraeInstruction::EmergencySetAssetWeight {
+                    init_asset_weight: I80F48::from_le_bytes(*init_asset_weight),
+                    maint_asset_weight: I80F48::from_le_bytes(*maint_asset_weight),
+                }
+            }
+            115 => {
+                let data_arr = array_ref![data, 0, 11];
+                let (tier, min_msrm, discount_bps) = array_refs![data_arr, 1, 8, 2];
+                LyraeInstruction::SetMsrmFeeTier {
+                    tier: tier[0],
+                    min_msrm: u64::from_le_bytes(*min_msrm),
+                    discount_bps: u16::from_le_bytes(*discount_bps),
+                }
+            }
+            116 => LyraeInstruction::ReassignSpotMarket,
+            _ => {
+                return None;
+            }
+        })
+    }
+    pub fn pack(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+fn unpack_u8_opt(data: &[u8; 2]) -> Option<u8> {
+    if data[0] == 0 {
+        None
+    } else {
+        Some(data[1])
+    }
+}
+
+fn unpack_u16_opt(data: &[u8; 3]) -> Option<u16> {
+    let (opt, val) = array_refs![data, 1, 2];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(u16::from_le_bytes(*val))
+    }
+}
+
+fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
+    let (opt, val) = array_refs![data, 1, 16];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(I80F48::from_le_bytes(*val))
+    }
+}
+fn unpack_u32_opt(data: &[u8; 5]) -> Option<u32> {
+    let (opt, val) = array_refs![data, 1, 4];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(u32::from_le_bytes(*val))
+    }
+}
+fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
+    let (opt, val) = array_refs![data, 1, 8];
+    if opt[0] == 0 {
+        None
+    } else {
+        Some(u64::from_le_bytes(*val))
+    }
+}
+
+fn unpack_dex_new_order_v3(
+    data: &[u8; 46],
+) -> Option<serum_dex::instruction::NewOrderInstructionV3> {
+    let (
+        &side_arr,
+        &price_arr,
+        &max_coin_qty_arr,
+        &max_native_pc_qty_arr,
+        &self_trade_behavior_arr,
+        &otype_arr,
+        &client_order_id_bytes,
+        &limit_arr,
+    ) = array_refs![data, 4, 8, 8, 8, 4, 4, 8, 2];
+
+    let side = serum_dex::matching::Side::try_from_primitive(
+        u32::from_le_bytes(side_arr).try_into().ok()?,
+    )
+    .ok()?;
+    let limit_price = NonZeroU64::new(u64::from_le_bytes(price_arr))?;
+    let max_coin_qty = NonZeroU64::new(u64::from_le_bytes(max_coin_qty_arr))?;
+    let max_native_pc_qty_including_fees =
+        NonZeroU64::new(u64::from_le_bytes(max_native_pc_qty_arr))?;
+    let self_trade_behavior = serum_dex::instruction::SelfTradeBehavior::try_from_primitive(
+        u32::from_le_bytes(self_trade_behavior_arr)
+            .try_into()
+            .ok()?,
+    )
+    .ok()?;
+    let order_type = serum_dex::matching::OrderType::try_from_primitive(
+        u32::from_le_bytes(otype_arr).try_into().ok()?,
+    )
+    .ok()?;
+    let client_order_id = u64::from_le_bytes(client_order_id_bytes);
+    let limit = u16::from_le_bytes(limit_arr);
+
+    Some(serum_dex::instruction::NewOrderInstructionV3 {
+        side,
+        limit_price,
+        max_coin_qty,
+        max_native_pc_qty_including_fees,
+        self_trade_behavior,
+        order_type,
+        client_order_id,
+        limit,
+    })
+}
+
+pub fn init_lyrae_group(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    quote_mint_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    insurance_vault_pk: &Pubkey,
+    msrm_vault_pk: &Pubkey, // send in Pubkey:default() if not using this feature
+    fees_vault_pk: &Pubkey,
+    lyrae_cache_ai: &Pubkey,
+    dex_program_pk: &Pubkey,
+
+    signer_nonce: u64,
+    valid_interval: u64,
+    quote_optimal_util: I80F48,
+    quote_optimal_rate: I80F48,
+    quote_max_rate: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new_readonly(*quote_mint_pk, false),
+        AccountMeta::new_readonly(*quote_vault_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*quote_root_bank_pk, false),
+        AccountMeta::new_readonly(*insurance_vault_pk, false),
+        AccountMeta::new_readonly(*msrm_vault_pk, false),
+        AccountMeta::new_readonly(*fees_vault_pk, false),
+        AccountMeta::new(*lyrae_cache_ai, false),
+        AccountMeta::new_readonly(*dex_program_pk, false),
+    ];
+
+    let instr = LyraeInstruction::InitLyraeGroup {
+        signer_nonce,
+        valid_interval,
+        quote_optimal_util,
+        quote_optimal_rate,
+        quote_max_rate,
+    };
+
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn init_lyrae_account(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+    ];
+
+    let instr = LyraeInstruction::InitLyraeAccount;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn close_lyrae_account(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+    ];
+
+    let instr = LyraeInstruction::CloseLyraeAccount;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn create_lyrae_account(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    system_prog_pk: &Pubkey,
+    payer_pk: &Pubkey,
+    account_num: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*system_prog_pk, false),
+        AccountMeta::new(*payer_pk, true),
+    ];
+
+    let instr = LyraeInstruction::CreateLyraeAccount { account_num };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn set_delegate(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    delegate_pk: &Pubkey,
+    permissions: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*delegate_pk, false),
+    ];
+
+    let instr = LyraeInstruction::SetDelegate { permissions };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn upgrade_lyrae_account_v0_v1(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+    ];
+
+    let instr = LyraeInstruction::UpgradeLyraeAccountV0V1;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn deposit(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    owner_token_account_pk: &Pubkey,
+    fee_vault_pk: Option<&Pubkey>, // needed if the root bank has a deposit fee
+    node_bank_pks: &[Pubkey],      // all node banks, to update a stale root bank first
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    // The cache and root bank are only written when the root bank gets updated
+    let update_root_bank = !node_bank_pks.is_empty();
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta { pubkey: *lyrae_cache_pk, is_signer: false, is_writable: update_root_bank },
+        AccountMeta { pubkey: *root_bank_pk, is_signer: false, is_writable: update_root_bank },
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*vault_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new(*owner_token_account_pk, false),
+    ];
+    if let Some(fee_vault_pk) = fee_vault_pk {
+        accounts.push(AccountMeta::new(*fee_vault_pk, false));
+    }
+    accounts.extend(node_bank_pks.iter().map(|pk| AccountMeta::new(*pk, false)));
+
+    let instr = LyraeInstruction::Deposit { quantity };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn add_spot_market(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    dex_program_pk: &Pubkey,
+    token_mint_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    admin_pk: &Pubkey,
+
+    maint_leverage: I80F48,
+    init_leverage: I80F48,
+    liquidation_fee: I80F48,
+    optimal_util: I80F48,
+    optimal_rate: I80F48,
+    max_rate: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*oracle_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*dex_program_pk, false),
+        AccountMeta::new_readonly(*token_mint_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new_readonly(*vault_pk, false),
+        AccountMeta::new(*root_bank_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = LyraeInstruction::AddSpotMarket {
+        maint_leverage,
+        init_leverage,
+        liquidation_fee,
+        optimal_util,
+        optimal_rate,
+        max_rate,
+    };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn add_perp_market(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    lyr_vault_pk: &Pubkey,
+    admin_pk: &Pubkey,
+
+    maint_leverage: I80F48,
+    init_leverage: I80F48,
+    liquidation_fee: I80F48,
+    maker_fee: I80F48,
+    taker_fee: I80F48,
+    base_lot_size: i64,
+    quote_lot_size: i64,
+    rate: I80F48,
+    max_depth_bps: I80F48,
+    target_period_length: u64,
+    lyr_per_period: u64,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new_readonly(*lyr_vault_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
+    ];
+
+    let instr = LyraeInstruction::AddPerpMarket {
+        maint_leverage,
+        init_leverage,
+        liquidation_fee,
+        maker_fee,
+        taker_fee,
+        base_lot_size,
+        quote_lot_size,
+        rate,
+        max_depth_bps,
+        target_period_length,
+        lyr_per_period,
+        exp: 2, // TODO add this to function signature
+    };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn place_perp_order(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_lyrae_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    side: Side,
+    price: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+    reduce_only: bool,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    accounts.extend(
+        open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    if let Some(referrer_lyrae_account_pk) = referrer_lyrae_account_pk {
+        accounts.push(AccountMeta::new(*referrer_lyrae_account_pk, false));
+    }
+
+    let instr = LyraeInstruction::PlacePerpOrder {
+        side,
+        price,
+        quantity,
+        client_order_id,
+        order_type,
+        reduce_only,
+    };
+    let data = instr.pack();
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cancel_perp_order_by_client_id(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,   // read
+    lyrae_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    client_order_id: u64,
+    invalid_id_ok: bool,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = LyraeInstruction::CancelPerpOrderByClientId {
+        client_order_id,
+        invalid_id_ok,
+    };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cancel_perp_order(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,   // read
+    lyrae_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    order_id: i128,
+    invalid_id_ok: bool,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = LyraeInstruction::CancelPerpOrder {
+        order_id,
+        invalid_id_ok,
+    };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cancel_all_perp_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,   // read
+    lyrae_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = LyraeInstruction::CancelAllPerpOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cancel_perp_orders_side(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,   // read
+    lyrae_account_pk: &Pubkey, // write
+    owner_pk: &Pubkey,         // read, signer
+    perp_market_pk: &Pubkey,   // write
+    bids_pk: &Pubkey,          // write
+    asks_pk: &Pubkey,          // write
+    side: Side,
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+    ];
+    let instr = LyraeInstruction::CancelPerpOrdersSide { side, limit };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn force_cancel_perp_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,         // read
+    lyrae_cache_pk: &Pubkey,         // read
+    perp_market_pk: &Pubkey,         // read
+    bids_pk: &Pubkey,                // write
+    asks_pk: &Pubkey,                // write
+    liqee_lyrae_account_pk: &Pubkey, // write
+    open_orders_pks: &[Pubkey],      // read
+    limit: u8,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*liqee_lyrae_account_pk, false),
+    ];
+    accounts.extend(
+        open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    let instr = LyraeInstruction::ForceCancelPerpOrders { limit };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn init_advanced_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,     // read
+    lyrae_account_pk: &Pubkey,   // write
+    owner_pk: &Pubkey,           // write & signer
+    advanced_orders_pk: &Pubkey, // write
+    system_prog_pk: &Pubkey,     // read
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(*advanced_orders_pk, false),
+        AccountMeta::new_readonly(*system_prog_pk, false),
+    ];
+    let instr = LyraeInstruction::InitAdvancedOrders {};
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn close_advanced_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    advanced_orders_pk: &Pubkey,
+    owner_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(*advanced_orders_pk, false),
+    ];
+
+    let instr = LyraeInstruction::CloseAdvancedOrders;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn add_perp_trigger_order(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,     // read
+    lyrae_account_pk: &Pubkey,   // read
+    owner_pk: &Pubkey,           // write & signer
+    advanced_orders_pk: &Pubkey, // write
+    lyrae_cache_pk: &Pubkey,     // read
+    perp_market_pk: &Pubkey,     // read
+    system_prog_pk: &Pubkey,     // read
+    order_type: OrderType,
+    side: Side,
+    trigger_condition: TriggerCondition,
+    reduce_only: bool,
+    client_order_id: u64,
+    price: i64,
+    quantity: i64,
+    trigger_price: I80F48,
+    trail_offset: I80F48,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(*advanced_orders_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*system_prog_pk, false),
+    ];
+    let instr = LyraeInstruction::AddPerpTriggerOrder {
+        order_type,
+        side,
+        trigger_condition,
+        reduce_only,
+        client_order_id,
+        price,
+        quantity,
+        trigger_price,
+        trail_offset,
+    };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn remove_advanced_order(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,     // read
+    lyrae_account_pk: &Pubkey,   // read
+    owner_pk: &Pubkey,           // write & signer
+    advanced_orders_pk: &Pubkey, // write
+    system_prog_pk: &Pubkey,     // read
+    order_index: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new(*advanced_orders_pk, false),
+        AccountMeta::new_readonly(*system_prog_pk, false),
+    ];
+    let instr = LyraeInstruction::RemoveAdvancedOrder { order_index };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn execute_perp_trigger_order(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,     // read
+    lyrae_account_pk: &Pubkey,   // write
+    advanced_orders_pk: &Pubkey, // write
+    agent_pk: &Pubkey,           // write & signer
+    lyrae_cache_pk: &Pubkey,     // read
+    perp_market_pk: &Pubkey,     // write
+    bids_pk: &Pubkey,            // write
+    asks_pk: &Pubkey,            // write
+    event_queue_pk: &Pubkey,     // write
+    order_index: u8,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new(*advanced_orders_pk, false),
+        AccountMeta::new(*agent_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    let instr = LyraeInstruction::ExecutePerpTriggerOrder { order_index };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn consume_events(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,      // read
+    lyrae_cache_pk: &Pubkey,      // read
+    perp_market_pk: &Pubkey,      // read
+    event_queue_pk: &Pubkey,      // write
+    lyrae_acc_pks: &mut [Pubkey], // write
+    limit: usize,
+) -> Result<Instruction, ProgramError> {
+    let fixed_accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    lyrae_acc_pks.sort();
+    let lyrae_accounts = lyrae_acc_pks
+        .into_iter()
+        .map(|pk| AccountMeta::new(*pk, false));
+    let accounts = fixed_accounts.into_iter().chain(lyrae_accounts).collect();
+    let instr = LyraeInstruction::ConsumeEvents { limit };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn consume_events_for_account(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,      // read
+    lyrae_cache_pk: &Pubkey,      // read
+    perp_market_pk: &Pubkey,      // read
+    event_queue_pk: &Pubkey,      // write
+    lyrae_account_pk: &Pubkey,    // write
+    lyrae_acc_pks: &mut [Pubkey], // write
+    limit: usize,
+) -> Result<Instruction, ProgramError> {
+    let fixed_accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    lyrae_acc_pks.sort();
+    let lyrae_accounts = std::iter::once(lyrae_account_pk)
+        .chain(lyrae_acc_pks.iter().filter(|pk| *pk != lyrae_account_pk))
+        .map(|pk| AccountMeta::new(*pk, false));
+    let accounts = fixed_accounts.into_iter().chain(lyrae_accounts).collect();
+    let instr =
+        LyraeInstruction::ConsumeEventsForAccount { lyrae_account: *lyrae_account_pk, limit };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn settle_pnl(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,     // read
+    lyrae_account_a_pk: &Pubkey, // write
+    lyrae_account_b_pk: &Pubkey, // write
+    lyrae_cache_pk: &Pubkey,     // read
+    root_bank_pk: &Pubkey,       // read
+    node_bank_pk: &Pubkey,       // write
+    market_index: usize,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_a_pk, false),
+        AccountMeta::new(*lyrae_account_b_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+    let instr = LyraeInstruction::SettlePnl { market_index };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn update_funding(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey, // read
+    lyrae_cache_pk: &Pubkey, // write
+    perp_market_pk: &Pubkey, // write
+    bids_pk: &Pubkey,        // read
+    asks_pk: &Pubkey,        // read
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new_readonly(*bids_pk, false),
+        AccountMeta::new_readonly(*asks_pk, false),
+    ];
+    let instr = LyraeInstruction::UpdateFunding {};
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn withdraw(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    token_account_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    quantity: u64,
+    allow_borrow: bool,
+) -> Result<Instruction, ProgramError> {
+    withdraw2(
+        program_id,
+        lyrae_group_pk,
+        lyrae_account_pk,
+        owner_pk,
+        lyrae_cache_pk,
+        root_bank_pk,
+        node_bank_pk,
+        vault_pk,
+        token_account_pk,
+        signer_pk,
+        open_orders_pks,
+        None,
+        None,
+        quantity,
+        allow_borrow,
+    )
+}
+
+/// Same as `withdraw`, also passing the fee vault and, to close the remaining dust, the
+/// DustAccount
+pub fn withdraw2(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    token_account_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+    fee_vault_pk: Option<&Pubkey>,    // needed if the root bank has a withdraw fee
+    dust_account_pk: Option<&Pubkey>, // passing it sets close_dust
+
+    quantity: u64,
+    allow_borrow: bool,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*vault_pk, false),
+        AccountMeta::new(*token_account_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
+
+    accounts.extend(
+        open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+
+    if let Some(fee_vault_pk) = fee_vault_pk {
+        accounts.push(AccountMeta::new(*fee_vault_pk, false));
+    }
+    if let Some(dust_account_pk) = dust_account_pk {
+        accounts.push(AccountMeta::new(*dust_account_pk, false));
+    }
+
+    let instr = LyraeInstruction::Withdraw {
+        quantity,
+        allow_borrow,
+        close_dust: dust_account_pk.is_some(),
+    };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn borrow(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    quantity: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+    ];
+
+    accounts.extend(
+        open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new(*pk, false)),
+    );
+
+    let instr = LyraeInstruction::Borrow { quantity };
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cache_prices(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    oracle_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_cache_pk, false),
+    ];
+    accounts.extend(
+        oracle_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    let instr = LyraeInstruction::CachePrices;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cache_root_banks(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_cache_pk, false),
+    ];
+    accounts.extend(
+        root_bank_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    let instr = LyraeInstruction::CacheRootBanks;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn cache_perp_markets(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    perp_market_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_cache_pk, false),
+    ];
+    accounts.extend(
+        perp_market_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    let instr = LyraeInstruction::CachePerpMarkets;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn init_spot_open_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+    ];
+
+    let instr = LyraeInstruction::InitSpotOpenOrders;
+    let data = instr.pack();
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn create_spot_open_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    payer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
+        AccountMeta::new(*payer_pk, true),
+    ];
+
+    let instr = LyraeInstruction::CreateSpotOpenOrders;
+    let data = instr.pack();
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn close_spot_open_orders(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+    ];
+
+    let instr = LyraeInstruction::CloseSpotOpenOrders;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn place_spot_order(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    dex_request_queue_pk: &Pubkey,
+    dex_event_queue_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
+    msrm_or_srm_vault_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    market_index: usize, // used to determine which of the open orders accounts should be passed in write
+    order: serum_dex::instruction::NewOrderInstructionV3,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*dex_request_queue_pk, false),
+        AccountMeta::new(*dex_event_queue_pk, false),
+        AccountMeta::new(*dex_base_pk, false),
+        AccountMeta::new(*dex_quote_pk, false),
+        AccountMeta::new_readonly(*base_root_bank_pk, false),
+        AccountMeta::new(*base_node_bank_pk, false),
+        AccountMeta::new(*base_vault_pk, false),
+        AccountMeta::new_readonly(*quote_root_bank_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*quote_vault_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+        AccountMeta::new_readonly(*dex_signer_pk, false),
+        AccountMeta::new_readonly(*msrm_or_srm_vault_pk, false),
+    ];
 
-fn unpack_u8_opt(data: &[u8; 2]) -> Option<u8> {
-    if data[0] == 0 {
-        None
-    } else {
-        Some(data[1])
-    }
-}
+    accounts.extend(open_orders_pks.iter().enumerate().map(|(i, pk)| {
+        if i == market_index {
+            AccountMeta::new(*pk, false)
+        } else {
+            AccountMeta::new_readonly(*pk, false)
+        }
+    }));
 
-fn unpack_i80f48_opt(data: &[u8; 17]) -> Option<I80F48> {
-    let (opt, val) = array_refs![data, 1, 16];
-    if opt[0] == 0 {
-        None
-    } else {
-        Some(I80F48::from_le_bytes(*val))
-    }
-}
-fn unpack_u64_opt(data: &[u8; 9]) -> Option<u64> {
-    let (opt, val) = array_refs![data, 1, 8];
-    if opt[0] == 0 {
-        None
-    } else {
-        Some(u64::from_le_bytes(*val))
-    }
+    let instr = LyraeInstruction::PlaceSpotOrder { order };
+    let data = instr.pack();
+
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
 }
 
-fn unpack_dex_new_order_v3(
-    data: &[u8; 46],
-) -> Option<serum_dex::instruction::NewOrderInstructionV3> {
-    let (
-        &side_arr,
-        &price_arr,
-        &max_coin_qty_arr,
-        &max_native_pc_qty_arr,
-        &self_trade_behavior_arr,
-        &otype_arr,
-        &client_order_id_bytes,
-        &limit_arr,
-    ) = array_refs![data, 4, 8, 8, 8, 4, 4, 8, 2];
+pub fn settle_funds(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*dex_base_pk, false),
+        AccountMeta::new(*dex_quote_pk, false),
+        AccountMeta::new_readonly(*base_root_bank_pk, false),
+        AccountMeta::new(*base_node_bank_pk, false),
+        AccountMeta::new_readonly(*quote_root_bank_pk, false),
+        AccountMeta::new(*quote_node_bank_pk, false),
+        AccountMeta::new(*base_vault_pk, false),
+        AccountMeta::new(*quote_vault_pk, false),
+        AccountMeta::new_readonly(*dex_signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+    ];
 
-    let side = serum_dex::matching::Side::try_from_primitive(
-        u32::from_le_bytes(side_arr).try_into().ok()?,
-    )
-    .ok()?;
-    let limit_price = NonZeroU64::new(u64::from_le_bytes(price_arr))?;
-    let max_coin_qty = NonZeroU64::new(u64::from_le_bytes(max_coin_qty_arr))?;
-    let max_native_pc_qty_including_fees =
-        NonZeroU64::new(u64::from_le_bytes(max_native_pc_qty_arr))?;
-    let self_trade_behavior = serum_dex::instruction::SelfTradeBehavior::try_from_primitive(
-        u32::from_le_bytes(self_trade_behavior_arr)
-            .try_into()
-            .ok()?,
-    )
-    .ok()?;
-    let order_type = serum_dex::matching::OrderType::try_from_primitive(
-        u32::from_le_bytes(otype_arr).try_into().ok()?,
-    )
-    .ok()?;
-    let client_order_id = u64::from_le_bytes(client_order_id_bytes);
-    let limit = u16::from_le_bytes(limit_arr);
+    let instr = LyraeInstruction::SettleFunds;
+    let data = instr.pack();
 
-    Some(serum_dex::instruction::NewOrderInstructionV3 {
-        side,
-        limit_price,
-        max_coin_qty,
-        max_native_pc_qty_including_fees,
-        self_trade_behavior,
-        order_type,
-        client_order_id,
-        limit,
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
     })
 }
 
-pub fn init_lyrae_group(
+pub fn add_oracle(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    signer_pk: &Pubkey,
+    oracle_pk: &Pubkey,
     admin_pk: &Pubkey,
-    quote_mint_pk: &Pubkey,
-    quote_vault_pk: &Pubkey,
-    quote_node_bank_pk: &Pubkey,
-    quote_root_bank_pk: &Pubkey,
-    insurance_vault_pk: &Pubkey,
-    msrm_vault_pk: &Pubkey, // send in Pubkey:default() if not using this feature
-    fees_vault_pk: &Pubkey,
-    lyrae_cache_ai: &Pubkey,
-    dex_program_pk: &Pubkey,
-
-    signer_nonce: u64,
-    valid_interval: u64,
-    quote_optimal_util: I80F48,
-    quote_optimal_rate: I80F48,
-    quote_max_rate: I80F48,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new(*oracle_pk, false),
         AccountMeta::new_readonly(*admin_pk, true),
-        AccountMeta::new_readonly(*quote_mint_pk, false),
-        AccountMeta::new_readonly(*quote_vault_pk, false),
-        AccountMeta::new(*quote_node_bank_pk, false),
-        AccountMeta::new(*quote_root_bank_pk, false),
-        AccountMeta::new_readonly(*insurance_vault_pk, false),
-        AccountMeta::new_readonly(*msrm_vault_pk, false),
-        AccountMeta::new_readonly(*fees_vault_pk, false),
-        AccountMeta::new(*lyrae_cache_ai, false),
-        AccountMeta::new_readonly(*dex_program_pk, false),
     ];
 
-    let instr = LyraeInstruction::InitLyraeGroup {
-        signer_nonce,
-        valid_interval,
-        quote_optimal_util,
-        quote_optimal_rate,
-        quote_max_rate,
-    };
+    let instr = LyraeInstruction::AddOracle;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn update_root_bank(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_cache_pk, false),
+        AccountMeta::new(*root_bank_pk, false),
+    ];
+
+    accounts.extend(
+        node_bank_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
 
+    let instr = LyraeInstruction::UpdateRootBank;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1598,19 +3929,21 @@ pub fn init_lyrae_group(
     })
 }
 
-pub fn init_lyrae_account(
+pub fn set_oracle(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
+    oracle_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    price: I80F48,
+    last_update: u64,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new(*oracle_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
     ];
 
-    let instr = LyraeInstruction::InitLyraeAccount;
+    let instr = LyraeInstruction::SetOracle { price, last_update };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1619,19 +3952,46 @@ pub fn init_lyrae_account(
     })
 }
 
-pub fn close_lyrae_account(
+pub fn liquidate_token_and_token(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    liqee_lyrae_account_pk: &Pubkey,
+    liqor_lyrae_account_pk: &Pubkey,
+    liqor_pk: &Pubkey,
+    asset_root_bank_pk: &Pubkey,
+    asset_node_bank_pk: &Pubkey,
+    liab_root_bank_pk: &Pubkey,
+    liab_node_bank_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    liqor_open_orders_pks: &[Pubkey],
+    max_liab_transfer: I80F48,
+    max_health_fraction: I80F48,
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*liqee_lyrae_account_pk, false),
+        AccountMeta::new(*liqor_lyrae_account_pk, false),
+        AccountMeta::new_readonly(*liqor_pk, true),
+        AccountMeta::new_readonly(*asset_root_bank_pk, false),
+        AccountMeta::new(*asset_node_bank_pk, false),
+        AccountMeta::new_readonly(*liab_root_bank_pk, false),
+        AccountMeta::new(*liab_node_bank_pk, false),
     ];
 
-    let instr = LyraeInstruction::CloseLyraeAccount;
+    accounts.extend(
+        liqee_open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    accounts.extend(
+        liqor_open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+
+    let instr = LyraeInstruction::LiquidateTokenAndToken { max_liab_transfer, max_health_fraction };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1640,24 +4000,36 @@ pub fn close_lyrae_account(
     })
 }
 
-pub fn create_lyrae_account(
+pub fn change_spot_market_params(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
-    system_prog_pk: &Pubkey,
-    payer_pk: &Pubkey,
-    account_num: u64,
+    spot_market_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    admin_pk: &Pubkey,
+    maint_leverage: Option<I80F48>,
+    init_leverage: Option<I80F48>,
+    liquidation_fee: Option<I80F48>,
+    optimal_util: Option<I80F48>,
+    optimal_rate: Option<I80F48>,
+    max_rate: Option<I80F48>,
+    version: Option<u8>,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*system_prog_pk, false),
-        AccountMeta::new(*payer_pk, true),
+        AccountMeta::new(*spot_market_pk, false),
+        AccountMeta::new(*root_bank_pk, false),
+        AccountMeta::new_readonly(*admin_pk, true),
     ];
 
-    let instr = LyraeInstruction::CreateLyraeAccount { account_num };
+    let instr = LyraeInstruction::ChangeSpotMarketParams {
+        maint_leverage,
+        init_leverage,
+        liquidation_fee,
+        optimal_util,
+        optimal_rate,
+        max_rate,
+        version,
+    };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1666,21 +4038,27 @@ pub fn create_lyrae_account(
     })
 }
 
-pub fn set_delegate(
+pub fn redeem_lyr_to_token_account(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
     owner_pk: &Pubkey,
-    delegate_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    lyr_perp_vault_pk: &Pubkey,
+    token_account_pk: &Pubkey,
+    signer_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*delegate_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new(*lyr_perp_vault_pk, false),
+        AccountMeta::new(*token_account_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
     ];
-
-    let instr = LyraeInstruction::SetDelegate {};
+    let instr = LyraeInstruction::RedeemLyrToTokenAccount;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1689,19 +4067,34 @@ pub fn set_delegate(
     })
 }
 
-pub fn upgrade_lyrae_account_v0_v1(
+pub fn simulate_withdraw(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    open_orders_pks: &[Pubkey],
+
+    quantity: u64,
+    allow_borrow: bool,
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
     ];
 
-    let instr = LyraeInstruction::UpgradeLyraeAccountV0V1;
+    accounts.extend(
+        open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+
+    let instr = LyraeInstruction::SimulateWithdraw {
+        quantity,
+        allow_borrow,
+    };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1710,32 +4103,22 @@ pub fn upgrade_lyrae_account_v0_v1(
     })
 }
 
-pub fn deposit(
+pub fn log_node_bank_health(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
     root_bank_pk: &Pubkey,
     node_bank_pk: &Pubkey,
     vault_pk: &Pubkey,
-    owner_token_account_pk: &Pubkey,
-
-    quantity: u64,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
         AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
-        AccountMeta::new(*vault_pk, false),
-        AccountMeta::new_readonly(spl_token::ID, false),
-        AccountMeta::new(*owner_token_account_pk, false),
+        AccountMeta::new_readonly(*node_bank_pk, false),
+        AccountMeta::new_readonly(*vault_pk, false),
     ];
-
-    let instr = LyraeInstruction::Deposit { quantity };
+    let instr = LyraeInstruction::LogNodeBankHealth;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1744,101 +4127,62 @@ pub fn deposit(
     })
 }
 
-pub fn add_spot_market(
+pub fn claim_serum_referrer_rebates(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
     spot_market_pk: &Pubkey,
-    dex_program_pk: &Pubkey,
-    token_mint_pk: &Pubkey,
-    node_bank_pk: &Pubkey,
-    vault_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
-    admin_pk: &Pubkey,
-
-    maint_leverage: I80F48,
-    init_leverage: I80F48,
-    liquidation_fee: I80F48,
-    optimal_util: I80F48,
-    optimal_rate: I80F48,
-    max_rate: I80F48,
+    open_orders_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    dex_base_pk: &Pubkey,
+    dex_quote_pk: &Pubkey,
+    base_root_bank_pk: &Pubkey,
+    base_node_bank_pk: &Pubkey,
+    quote_root_bank_pk: &Pubkey,
+    quote_node_bank_pk: &Pubkey,
+    base_vault_pk: &Pubkey,
+    quote_vault_pk: &Pubkey,
+    dex_signer_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new_readonly(*oracle_pk, false),
-        AccountMeta::new_readonly(*spot_market_pk, false),
-        AccountMeta::new_readonly(*dex_program_pk, false),
-        AccountMeta::new_readonly(*token_mint_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
-        AccountMeta::new_readonly(*vault_pk, false),
-        AccountMeta::new(*root_bank_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
-    ];
-
-    let instr = LyraeInstruction::AddSpotMarket {
-        maint_leverage,
-        init_leverage,
-        liquidation_fee,
-        optimal_util,
-        optimal_rate,
-        max_rate,
-    };
-    let data = instr.pack();
-    Ok(Instruction {
-        program_id: *program_id,
-        accounts,
-        data,
-    })
+    let mut instruction = settle_funds(
+        program_id,
+        lyrae_group_pk,
+        lyrae_cache_pk,
+        owner_pk,
+        lyrae_account_pk,
+        dex_prog_pk,
+        spot_market_pk,
+        open_orders_pk,
+        signer_pk,
+        dex_base_pk,
+        dex_quote_pk,
+        base_root_bank_pk,
+        base_node_bank_pk,
+        quote_root_bank_pk,
+        quote_node_bank_pk,
+        base_vault_pk,
+        quote_vault_pk,
+        dex_signer_pk,
+    )?;
+    instruction.data = LyraeInstruction::ClaimSerumReferrerRebates.pack();
+    Ok(instruction)
 }
 
-pub fn add_perp_market(
+pub fn log_advanced_orders(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
-    perp_market_pk: &Pubkey,
-    event_queue_pk: &Pubkey,
-    bids_pk: &Pubkey,
-    asks_pk: &Pubkey,
-    lyr_vault_pk: &Pubkey,
-    admin_pk: &Pubkey,
-
-    maint_leverage: I80F48,
-    init_leverage: I80F48,
-    liquidation_fee: I80F48,
-    maker_fee: I80F48,
-    taker_fee: I80F48,
-    base_lot_size: i64,
-    quote_lot_size: i64,
-    rate: I80F48,
-    max_depth_bps: I80F48,
-    target_period_length: u64,
-    lyr_per_period: u64,
+    lyrae_account_pk: &Pubkey,
+    advanced_orders_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*oracle_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*event_queue_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new_readonly(*lyr_vault_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*advanced_orders_pk, false),
     ];
-
-    let instr = LyraeInstruction::AddPerpMarket {
-        maint_leverage,
-        init_leverage,
-        liquidation_fee,
-        maker_fee,
-        taker_fee,
-        base_lot_size,
-        quote_lot_size,
-        rate,
-        max_depth_bps,
-        target_period_length,
-        lyr_per_period,
-        exp: 2, // TODO add this to function signature
-    };
+    let instr = LyraeInstruction::LogAdvancedOrders;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1847,54 +4191,37 @@ pub fn add_perp_market(
     })
 }
 
-pub fn place_perp_order(
+pub fn crank_settle_fees(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
     perp_market_pk: &Pubkey,
-    bids_pk: &Pubkey,
-    asks_pk: &Pubkey,
-    event_queue_pk: &Pubkey,
-    referrer_lyrae_account_pk: Option<&Pubkey>,
-    open_orders_pks: &[Pubkey; MAX_PAIRS],
-    side: Side,
-    price: i64,
-    quantity: i64,
-    client_order_id: u64,
-    order_type: OrderType,
-    reduce_only: bool,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    bank_vault_pk: &Pubkey,
+    fees_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    insurance_vault_pk: Option<&Pubkey>, // needed if fees_insurance_share_pct is not 0
+    lyrae_account_pks: &[Pubkey],
 ) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
+    let mut fixed_accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
         AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*bank_vault_pk, false),
+        AccountMeta::new(*fees_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
     ];
-    accounts.extend(
-        open_orders_pks
-            .iter()
-            .map(|pk| AccountMeta::new_readonly(*pk, false)),
-    );
-    if let Some(referrer_lyrae_account_pk) = referrer_lyrae_account_pk {
-        accounts.push(AccountMeta::new(*referrer_lyrae_account_pk, false));
+    if let Some(insurance_vault_pk) = insurance_vault_pk {
+        fixed_accounts.push(AccountMeta::new(*insurance_vault_pk, false));
     }
-
-    let instr = LyraeInstruction::PlacePerpOrder {
-        side,
-        price,
-        quantity,
-        client_order_id,
-        order_type,
-        reduce_only,
-    };
+    let lyrae_accounts = lyrae_account_pks.iter().map(|pk| AccountMeta::new(*pk, false));
+    let accounts = fixed_accounts.into_iter().chain(lyrae_accounts).collect();
+    let instr = LyraeInstruction::CrankSettleFees;
     let data = instr.pack();
-
     Ok(Instruction {
         program_id: *program_id,
         accounts,
@@ -1902,29 +4229,20 @@ pub fn place_perp_order(
     })
 }
 
-pub fn cancel_perp_order_by_client_id(
+pub fn log_book_capacity(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,   // read
-    lyrae_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    client_order_id: u64,
-    invalid_id_ok: bool,
+    lyrae_group_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*bids_pk, false),
+        AccountMeta::new_readonly(*asks_pk, false),
     ];
-    let instr = LyraeInstruction::CancelPerpOrderByClientId {
-        client_order_id,
-        invalid_id_ok,
-    };
+    let instr = LyraeInstruction::LogBookCapacity;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1933,29 +4251,50 @@ pub fn cancel_perp_order_by_client_id(
     })
 }
 
-pub fn cancel_perp_order(
+pub fn deposit_and_create_spot_open_orders(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,   // read
-    lyrae_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    order_id: i128,
-    invalid_id_ok: bool,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    vault_pk: &Pubkey,
+    owner_token_account_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    payer_pk: &Pubkey,
+    fee_vault_pk: Option<&Pubkey>, // needed if the root bank has a deposit fee
+    node_bank_pks: &[Pubkey],      // all node banks, to update a stale root bank first
+
+    quantity: u64,
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let update_root_bank = !node_bank_pks.is_empty();
+    let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta { pubkey: *lyrae_cache_pk, is_signer: false, is_writable: update_root_bank },
+        AccountMeta { pubkey: *root_bank_pk, is_signer: false, is_writable: update_root_bank },
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*vault_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new(*owner_token_account_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
+        AccountMeta::new(*payer_pk, true),
     ];
-    let instr = LyraeInstruction::CancelPerpOrder {
-        order_id,
-        invalid_id_ok,
-    };
+    if let Some(fee_vault_pk) = fee_vault_pk {
+        accounts.push(AccountMeta::new(*fee_vault_pk, false));
+    }
+    accounts.extend(node_bank_pks.iter().map(|pk| AccountMeta::new(*pk, false)));
+
+    let instr = LyraeInstruction::DepositAndCreateSpotOpenOrders { quantity };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1964,25 +4303,24 @@ pub fn cancel_perp_order(
     })
 }
 
-pub fn cancel_all_perp_orders(
+pub fn set_cancel_ttl(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,   // read
-    lyrae_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    limit: u8,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    cancel_ttl_pk: &Pubkey,
+    payer_pk: &Pubkey,
+    cancel_ttl_secs: u64,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*cancel_ttl_pk, false),
+        AccountMeta::new(*payer_pk, true),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
     ];
-    let instr = LyraeInstruction::CancelAllPerpOrders { limit };
+    let instr = LyraeInstruction::SetCancelTtl { cancel_ttl_secs };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -1991,26 +4329,20 @@ pub fn cancel_all_perp_orders(
     })
 }
 
-pub fn cancel_perp_orders_side(
+pub fn heartbeat(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,   // read
-    lyrae_account_pk: &Pubkey, // write
-    owner_pk: &Pubkey,         // read, signer
-    perp_market_pk: &Pubkey,   // write
-    bids_pk: &Pubkey,          // write
-    asks_pk: &Pubkey,          // write
-    side: Side,
-    limit: u8,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    cancel_ttl_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*cancel_ttl_pk, false),
     ];
-    let instr = LyraeInstruction::CancelPerpOrdersSide { side, limit };
+    let instr = LyraeInstruction::Heartbeat;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2019,31 +4351,25 @@ pub fn cancel_perp_orders_side(
     })
 }
 
-pub fn force_cancel_perp_orders(
+pub fn crank_expired_cancels(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,         // read
-    lyrae_cache_pk: &Pubkey,         // read
-    perp_market_pk: &Pubkey,         // read
-    bids_pk: &Pubkey,                // write
-    asks_pk: &Pubkey,                // write
-    liqee_lyrae_account_pk: &Pubkey, // write
-    open_orders_pks: &[Pubkey],      // read
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    cancel_ttl_pk: &Pubkey,
+    perp_market_pks: &[(Pubkey, Pubkey, Pubkey)], // (perp_market, bids, asks)
     limit: u8,
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new_readonly(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new(*liqee_lyrae_account_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*cancel_ttl_pk, false),
     ];
-    accounts.extend(
-        open_orders_pks
-            .iter()
-            .map(|pk| AccountMeta::new_readonly(*pk, false)),
-    );
-    let instr = LyraeInstruction::ForceCancelPerpOrders { limit };
+    for (perp_market_pk, bids_pk, asks_pk) in perp_market_pks.iter() {
+        accounts.push(AccountMeta::new(*perp_market_pk, false));
+        accounts.push(AccountMeta::new(*bids_pk, false));
+        accounts.push(AccountMeta::new(*asks_pk, false));
+    }
+    let instr = LyraeInstruction::CrankExpiredCancels { limit };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2052,22 +4378,20 @@ pub fn force_cancel_perp_orders(
     })
 }
 
-pub fn init_advanced_orders(
+pub fn log_perp_pnl(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,     // read
-    lyrae_account_pk: &Pubkey,   // write
-    owner_pk: &Pubkey,           // write & signer
-    advanced_orders_pk: &Pubkey, // write
-    system_prog_pk: &Pubkey,     // read
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new(*owner_pk, true),
-        AccountMeta::new(*advanced_orders_pk, false),
-        AccountMeta::new_readonly(*system_prog_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
     ];
-    let instr = LyraeInstruction::InitAdvancedOrders {};
+    let instr = LyraeInstruction::LogPerpPnl;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2076,21 +4400,16 @@ pub fn init_advanced_orders(
     })
 }
 
-pub fn close_advanced_orders(
+pub fn log_cache_consistency(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    advanced_orders_pk: &Pubkey,
-    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new(*owner_pk, true),
-        AccountMeta::new(*advanced_orders_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
     ];
-
-    let instr = LyraeInstruction::CloseAdvancedOrders;
+    let instr = LyraeInstruction::LogCacheConsistency;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2099,43 +4418,82 @@ pub fn close_advanced_orders(
     })
 }
 
-pub fn add_perp_trigger_order(
+pub fn place_perp_order_by_notional(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,     // read
-    lyrae_account_pk: &Pubkey,   // read
-    owner_pk: &Pubkey,           // write & signer
-    advanced_orders_pk: &Pubkey, // write
-    lyrae_cache_pk: &Pubkey,     // read
-    perp_market_pk: &Pubkey,     // read
-    system_prog_pk: &Pubkey,     // read
-    order_type: OrderType,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_lyrae_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
     side: Side,
-    trigger_condition: TriggerCondition,
-    reduce_only: bool,
-    client_order_id: u64,
     price: i64,
-    quantity: i64,
-    trigger_price: I80F48,
+    quote_notional: u64,
+    client_order_id: u64,
+    order_type: OrderType,
+    reduce_only: bool,
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let mut instruction = place_perp_order(
+        program_id,
+        lyrae_group_pk,
+        lyrae_account_pk,
+        owner_pk,
+        lyrae_cache_pk,
+        perp_market_pk,
+        bids_pk,
+        asks_pk,
+        event_queue_pk,
+        referrer_lyrae_account_pk,
+        open_orders_pks,
+        side,
+        price,
+        0,
+        client_order_id,
+        order_type,
+        reduce_only,
+    )?;
+    instruction.data = LyraeInstruction::PlacePerpOrderByNotional {
+        price,
+        quote_notional,
+        client_order_id,
+        side,
+        order_type,
+        reduce_only,
+    }
+    .pack();
+    Ok(instruction)
+}
+
+pub fn crank_redeem_lyr(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    perp_market_pk: &Pubkey,
+    lyr_perp_vault_pk: &Pubkey,
+    lyr_root_bank_pk: &Pubkey,
+    lyr_node_bank_pk: &Pubkey,
+    lyr_bank_vault_pk: &Pubkey,
+    signer_pk: &Pubkey,
+    lyrae_account_pks: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let fixed_accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new_readonly(*lyrae_account_pk, false),
-        AccountMeta::new(*owner_pk, true),
-        AccountMeta::new(*advanced_orders_pk, false),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
         AccountMeta::new_readonly(*perp_market_pk, false),
-        AccountMeta::new_readonly(*system_prog_pk, false),
+        AccountMeta::new(*lyr_perp_vault_pk, false),
+        AccountMeta::new_readonly(*lyr_root_bank_pk, false),
+        AccountMeta::new(*lyr_node_bank_pk, false),
+        AccountMeta::new(*lyr_bank_vault_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(spl_token::ID, false),
     ];
-    let instr = LyraeInstruction::AddPerpTriggerOrder {
-        order_type,
-        side,
-        trigger_condition,
-        reduce_only,
-        client_order_id,
-        price,
-        quantity,
-        trigger_price,
-    };
+    let lyrae_accounts = lyrae_account_pks.iter().map(|pk| AccountMeta::new(*pk, false));
+    let accounts = fixed_accounts.into_iter().chain(lyrae_accounts).collect();
+    let instr = LyraeInstruction::CrankRedeemLyr;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2144,23 +4502,20 @@ pub fn add_perp_trigger_order(
     })
 }
 
-pub fn remove_advanced_order(
+/// `bank_pks` holds each root bank followed by the (node bank, vault) pairs of its node banks
+pub fn log_group_tvl(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,     // read
-    lyrae_account_pk: &Pubkey,   // read
-    owner_pk: &Pubkey,           // write & signer
-    advanced_orders_pk: &Pubkey, // write
-    system_prog_pk: &Pubkey,     // read
-    order_index: u8,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    bank_pks: &[Pubkey],
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let fixed_accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new_readonly(*lyrae_account_pk, false),
-        AccountMeta::new(*owner_pk, true),
-        AccountMeta::new(*advanced_orders_pk, false),
-        AccountMeta::new_readonly(*system_prog_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
     ];
-    let instr = LyraeInstruction::RemoveAdvancedOrder { order_index };
+    let bank_accounts = bank_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false));
+    let accounts = fixed_accounts.into_iter().chain(bank_accounts).collect();
+    let instr = LyraeInstruction::LogGroupTvl;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2169,31 +4524,19 @@ pub fn remove_advanced_order(
     })
 }
 
-pub fn execute_perp_trigger_order(
+pub fn set_auto_settle_funding(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,     // read
-    lyrae_account_pk: &Pubkey,   // write
-    advanced_orders_pk: &Pubkey, // write
-    agent_pk: &Pubkey,           // write & signer
-    lyrae_cache_pk: &Pubkey,     // read
-    perp_market_pk: &Pubkey,     // write
-    bids_pk: &Pubkey,            // write
-    asks_pk: &Pubkey,            // write
-    event_queue_pk: &Pubkey,     // write
-    order_index: u8,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    auto_settle_funding: bool,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new(*advanced_orders_pk, false),
-        AccountMeta::new(*agent_pk, true),
-        AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
     ];
-    let instr = LyraeInstruction::ExecutePerpTriggerOrder { order_index };
+    let instr = LyraeInstruction::SetAutoSettleFunding { auto_settle_funding };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2202,27 +4545,26 @@ pub fn execute_perp_trigger_order(
     })
 }
 
-pub fn consume_events(
+pub fn crank_auto_settle_funding(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,      // read
-    lyrae_cache_pk: &Pubkey,      // read
-    perp_market_pk: &Pubkey,      // read
-    event_queue_pk: &Pubkey,      // write
-    lyrae_acc_pks: &mut [Pubkey], // write
-    limit: usize,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    root_bank_pk: &Pubkey,
+    node_bank_pk: &Pubkey,
+    counterparty_pk: &Pubkey,
+    lyrae_account_pks: &[Pubkey],
+    market_index: usize,
 ) -> Result<Instruction, ProgramError> {
     let fixed_accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new(*event_queue_pk, false),
+        AccountMeta::new_readonly(*root_bank_pk, false),
+        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*counterparty_pk, false),
     ];
-    lyrae_acc_pks.sort();
-    let lyrae_accounts = lyrae_acc_pks
-        .into_iter()
-        .map(|pk| AccountMeta::new(*pk, false));
+    let lyrae_accounts = lyrae_account_pks.iter().map(|pk| AccountMeta::new(*pk, false));
     let accounts = fixed_accounts.into_iter().chain(lyrae_accounts).collect();
-    let instr = LyraeInstruction::ConsumeEvents { limit };
+    let instr = LyraeInstruction::CrankAutoSettleFunding { market_index };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2231,25 +4573,37 @@ pub fn consume_events(
     })
 }
 
-pub fn settle_pnl(
+pub fn prepare_account_close(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,     // read
-    lyrae_account_a_pk: &Pubkey, // write
-    lyrae_account_b_pk: &Pubkey, // write
-    lyrae_cache_pk: &Pubkey,     // read
-    root_bank_pk: &Pubkey,       // read
-    node_bank_pk: &Pubkey,       // write
-    market_index: usize,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    dust_account_pk: &Pubkey,
+    advanced_orders_pk: &Pubkey,
+    perp_market_pks: &[(Pubkey, Pubkey, Pubkey)], // (perp_market, bids, asks)
+    bank_pks: &[(Pubkey, Pubkey)],                // (root_bank, node_bank)
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_a_pk, false),
-        AccountMeta::new(*lyrae_account_b_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*dust_account_pk, false),
+        AccountMeta::new(*advanced_orders_pk, false),
     ];
-    let instr = LyraeInstruction::SettlePnl { market_index };
+    for (perp_market_pk, bids_pk, asks_pk) in perp_market_pks.iter() {
+        accounts.push(AccountMeta::new(*perp_market_pk, false));
+        accounts.push(AccountMeta::new(*bids_pk, false));
+        accounts.push(AccountMeta::new(*asks_pk, false));
+    }
+    for (root_bank_pk, node_bank_pk) in bank_pks.iter() {
+        accounts.push(AccountMeta::new_readonly(*root_bank_pk, false));
+        accounts.push(AccountMeta::new(*node_bank_pk, false));
+    }
+    let instr = LyraeInstruction::PrepareAccountClose {
+        num_perp_markets: perp_market_pks.len() as u8,
+    };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2258,22 +4612,22 @@ pub fn settle_pnl(
     })
 }
 
-pub fn update_funding(
+pub fn log_all_open_orders(
     program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey, // read
-    lyrae_cache_pk: &Pubkey, // write
-    perp_market_pk: &Pubkey, // write
-    bids_pk: &Pubkey,        // read
-    asks_pk: &Pubkey,        // read
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    perp_market_pks: &[(Pubkey, Pubkey, Pubkey)], // (perp_market, bids, asks)
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_cache_pk, false),
-        AccountMeta::new(*perp_market_pk, false),
-        AccountMeta::new_readonly(*bids_pk, false),
-        AccountMeta::new_readonly(*asks_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
     ];
-    let instr = LyraeInstruction::UpdateFunding {};
+    for (perp_market_pk, bids_pk, asks_pk) in perp_market_pks.iter() {
+        accounts.push(AccountMeta::new_readonly(*perp_market_pk, false));
+        accounts.push(AccountMeta::new_readonly(*bids_pk, false));
+        accounts.push(AccountMeta::new_readonly(*asks_pk, false));
+    }
+    let instr = LyraeInstruction::LogAllOpenOrders;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2282,44 +4636,40 @@ pub fn update_funding(
     })
 }
 
-pub fn withdraw(
+pub fn simulate_liquidate_perp_market(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
-    node_bank_pk: &Pubkey,
-    vault_pk: &Pubkey,
-    token_account_pk: &Pubkey,
-    signer_pk: &Pubkey,
-    open_orders_pks: &[Pubkey],
-
-    quantity: u64,
-    allow_borrow: bool,
+    perp_market_pk: &Pubkey,
+    liqee_lyrae_account_pk: &Pubkey,
+    liqor_lyrae_account_pk: &Pubkey,
+    liqee_open_orders_pks: &[Pubkey],
+    liqor_open_orders_pks: &[Pubkey],
+    base_transfer_request: i64,
+    liqor_max_base_position: Option<u64>,
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
-        AccountMeta::new(*vault_pk, false),
-        AccountMeta::new(*token_account_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(spl_token::ID, false),
+        AccountMeta::new_readonly(*perp_market_pk, false),
+        AccountMeta::new_readonly(*liqee_lyrae_account_pk, false),
+        AccountMeta::new_readonly(*liqor_lyrae_account_pk, false),
     ];
 
     accounts.extend(
-        open_orders_pks
+        liqee_open_orders_pks
+            .iter()
+            .map(|pk| AccountMeta::new_readonly(*pk, false)),
+    );
+    accounts.extend(
+        liqor_open_orders_pks
             .iter()
             .map(|pk| AccountMeta::new_readonly(*pk, false)),
     );
 
-    let instr = LyraeInstruction::Withdraw {
-        quantity,
-        allow_borrow,
+    let instr = LyraeInstruction::SimulateLiquidatePerpMarket {
+        base_transfer_request,
+        liqor_max_base_position,
     };
     let data = instr.pack();
     Ok(Instruction {
@@ -2329,34 +4679,28 @@ pub fn withdraw(
     })
 }
 
-pub fn borrow(
+pub fn set_account_group(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
-    lyrae_cache_pk: &Pubkey,
     owner_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
-    node_bank_pk: &Pubkey,
-    open_orders_pks: &[Pubkey],
-
-    quantity: u64,
+    account_group_pk: &Pubkey,
+    payer_pk: &Pubkey,
+    parent_lyrae_account_pk: Option<&Pubkey>,
+    account_label: [u8; 8],
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new_readonly(*root_bank_pk, false),
-        AccountMeta::new(*node_bank_pk, false),
+        AccountMeta::new(*account_group_pk, false),
+        AccountMeta::new(*payer_pk, true),
+        AccountMeta::new_readonly(solana_program::system_program::ID, false),
     ];
-
-    accounts.extend(
-        open_orders_pks
-            .iter()
-            .map(|pk| AccountMeta::new(*pk, false)),
-    );
-
-    let instr = LyraeInstruction::Borrow { quantity };
+    if let Some(parent_lyrae_account_pk) = parent_lyrae_account_pk {
+        accounts.push(AccountMeta::new_readonly(*parent_lyrae_account_pk, false));
+    }
+    let instr = LyraeInstruction::SetAccountGroup { account_label };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2365,22 +4709,74 @@ pub fn borrow(
     })
 }
 
-pub fn cache_prices(
+pub fn update_root_banks(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
-    oracle_pks: &[Pubkey],
+    bank_pks: &[(Pubkey, Vec<Pubkey>)], // (root_bank, node_banks)
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new(*lyrae_cache_pk, false),
     ];
+    for (root_bank_pk, node_bank_pks) in bank_pks.iter() {
+        accounts.push(AccountMeta::new(*root_bank_pk, false));
+        accounts.extend(
+            node_bank_pks
+                .iter()
+                .map(|pk| AccountMeta::new_readonly(*pk, false)),
+        );
+    }
+
+    let instr = LyraeInstruction::UpdateRootBanks;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn execute_perp_trigger_orders_multi(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,         // read
+    agent_pk: &Pubkey,               // write & signer
+    lyrae_cache_pk: &Pubkey,         // read
+    perp_market_pk: &Pubkey,         // write
+    bids_pk: &Pubkey,                // write
+    asks_pk: &Pubkey,                // write
+    event_queue_pk: &Pubkey,         // write
+    orders: &[(Pubkey, Pubkey, u8)], // (lyrae_account, advanced_orders, order_index)
+    open_orders_pks: &[Pubkey],      // read
+) -> Result<Instruction, ProgramError> {
+    if orders.len() > MAX_EXECUTE_TRIGGER_ORDERS {
+        return Err(ProgramError::InvalidArgument);
+    }
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*agent_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
+    ];
+    let mut order_indexes = [0u8; MAX_EXECUTE_TRIGGER_ORDERS];
+    for (i, (lyrae_account_pk, advanced_orders_pk, order_index)) in orders.iter().enumerate() {
+        accounts.push(AccountMeta::new(*lyrae_account_pk, false));
+        accounts.push(AccountMeta::new(*advanced_orders_pk, false));
+        order_indexes[i] = *order_index;
+    }
     accounts.extend(
-        oracle_pks
+        open_orders_pks
             .iter()
             .map(|pk| AccountMeta::new_readonly(*pk, false)),
     );
-    let instr = LyraeInstruction::CachePrices;
+
+    let instr = LyraeInstruction::ExecutePerpTriggerOrdersMulti {
+        num_orders: orders.len() as u8,
+        order_indexes,
+    };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2389,22 +4785,54 @@ pub fn cache_prices(
     })
 }
 
-pub fn cache_root_banks(
+pub fn place_perp_order_amend(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
-    root_bank_pks: &[Pubkey],
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_lyrae_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    order_id: i128,
+    by_client_id: bool,
+    price: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+    reduce_only: bool,
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_cache_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
     ];
     accounts.extend(
-        root_bank_pks
+        open_orders_pks
             .iter()
             .map(|pk| AccountMeta::new_readonly(*pk, false)),
     );
-    let instr = LyraeInstruction::CacheRootBanks;
+    if let Some(referrer_lyrae_account_pk) = referrer_lyrae_account_pk {
+        accounts.push(AccountMeta::new(*referrer_lyrae_account_pk, false));
+    }
+
+    let instr = LyraeInstruction::PlacePerpOrderAmend {
+        order_id,
+        by_client_id,
+        price,
+        quantity,
+        client_order_id,
+        order_type,
+        reduce_only,
+    };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2413,22 +4841,52 @@ pub fn cache_root_banks(
     })
 }
 
-pub fn cache_perp_markets(
+pub fn modify_perp_order_reduce_only(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
-    perp_market_pks: &[Pubkey],
+    perp_market_pk: &Pubkey,
+    bids_pk: &Pubkey,
+    asks_pk: &Pubkey,
+    event_queue_pk: &Pubkey,
+    referrer_lyrae_account_pk: Option<&Pubkey>,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
+    order_id: i128,
+    by_client_id: bool,
+    price: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_cache_pk, false),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*bids_pk, false),
+        AccountMeta::new(*asks_pk, false),
+        AccountMeta::new(*event_queue_pk, false),
     ];
     accounts.extend(
-        perp_market_pks
+        open_orders_pks
             .iter()
             .map(|pk| AccountMeta::new_readonly(*pk, false)),
     );
-    let instr = LyraeInstruction::CachePerpMarkets;
+    if let Some(referrer_lyrae_account_pk) = referrer_lyrae_account_pk {
+        accounts.push(AccountMeta::new(*referrer_lyrae_account_pk, false));
+    }
+
+    let instr = LyraeInstruction::ModifyPerpOrderReduceOnly {
+        order_id,
+        by_client_id,
+        price,
+        quantity,
+        client_order_id,
+        order_type,
+    };
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2437,30 +4895,54 @@ pub fn cache_perp_markets(
     })
 }
 
-pub fn init_spot_open_orders(
+pub fn log_token_balances(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
-    dex_prog_pk: &Pubkey,
-    open_orders_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    signer_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*dex_prog_pk, false),
-        AccountMeta::new(*open_orders_pk, false),
-        AccountMeta::new_readonly(*spot_market_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+    ];
+    let instr = LyraeInstruction::LogTokenBalances;
+    let data = instr.pack();
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+pub fn update_all_caches(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
+    perp_market_pks: &[(Pubkey, Pubkey, Pubkey)], // (perp_market, bids, asks)
+    bank_pks: &[(Pubkey, Vec<Pubkey>)],            // (root_bank, node_banks)
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*lyrae_group_pk, false),
+        AccountMeta::new(*lyrae_cache_pk, false),
     ];
+    for (perp_market_pk, bids_pk, asks_pk) in perp_market_pks.iter() {
+        accounts.push(AccountMeta::new(*perp_market_pk, false));
+        accounts.push(AccountMeta::new_readonly(*bids_pk, false));
+        accounts.push(AccountMeta::new_readonly(*asks_pk, false));
+    }
+    for (root_bank_pk, node_bank_pks) in bank_pks.iter() {
+        accounts.push(AccountMeta::new(*root_bank_pk, false));
+        accounts.extend(
+            node_bank_pks
+                .iter()
+                .map(|pk| AccountMeta::new_readonly(*pk, false)),
+        );
+    }
 
-    let instr = LyraeInstruction::InitSpotOpenOrders;
+    let instr =
+        LyraeInstruction::UpdateAllCaches { num_perp_markets: perp_market_pks.len() as u8 };
     let data = instr.pack();
-
     Ok(Instruction {
         program_id: *program_id,
         accounts,
@@ -2468,32 +4950,17 @@ pub fn init_spot_open_orders(
     })
 }
 
-pub fn create_spot_open_orders(
+pub fn log_health_compute_estimate(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
-    dex_prog_pk: &Pubkey,
-    open_orders_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    signer_pk: &Pubkey,
-    payer_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
-        AccountMeta::new_readonly(*dex_prog_pk, false),
-        AccountMeta::new(*open_orders_pk, false),
-        AccountMeta::new_readonly(*spot_market_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(solana_program::system_program::ID, false),
-        AccountMeta::new(*payer_pk, true),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
     ];
-
-    let instr = LyraeInstruction::CreateSpotOpenOrders;
+    let instr = LyraeInstruction::LogHealthComputeEstimate;
     let data = instr.pack();
-
     Ok(Instruction {
         program_id: *program_id,
         accounts,
@@ -2501,27 +4968,19 @@ pub fn create_spot_open_orders(
     })
 }
 
-pub fn close_spot_open_orders(
+pub fn log_remaining_borrows(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
+    lyrae_cache_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
-    dex_prog_pk: &Pubkey,
-    open_orders_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    signer_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new(*owner_pk, true),
-        AccountMeta::new_readonly(*dex_prog_pk, false),
-        AccountMeta::new(*open_orders_pk, false),
-        AccountMeta::new_readonly(*spot_market_pk, false),
-        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(*lyrae_cache_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
     ];
 
-    let instr = LyraeInstruction::CloseSpotOpenOrders;
+    let instr = LyraeInstruction::LogRemainingBorrows;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2530,71 +4989,22 @@ pub fn close_spot_open_orders(
     })
 }
 
-pub fn place_spot_order(
+pub fn compute_health(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
-    owner_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
-    dex_prog_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    bids_pk: &Pubkey,
-    asks_pk: &Pubkey,
-    dex_request_queue_pk: &Pubkey,
-    dex_event_queue_pk: &Pubkey,
-    dex_base_pk: &Pubkey,
-    dex_quote_pk: &Pubkey,
-    base_root_bank_pk: &Pubkey,
-    base_node_bank_pk: &Pubkey,
-    base_vault_pk: &Pubkey,
-    quote_root_bank_pk: &Pubkey,
-    quote_node_bank_pk: &Pubkey,
-    quote_vault_pk: &Pubkey,
-    signer_pk: &Pubkey,
-    dex_signer_pk: &Pubkey,
-    msrm_or_srm_vault_pk: &Pubkey,
-    open_orders_pks: &[Pubkey],
-
-    market_index: usize, // used to determine which of the open orders accounts should be passed in write
-    order: serum_dex::instruction::NewOrderInstructionV3,
+    open_orders_pks: &[Pubkey; MAX_PAIRS],
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_account_pk, false),
-        AccountMeta::new_readonly(*owner_pk, true),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new_readonly(*dex_prog_pk, false),
-        AccountMeta::new(*spot_market_pk, false),
-        AccountMeta::new(*bids_pk, false),
-        AccountMeta::new(*asks_pk, false),
-        AccountMeta::new(*dex_request_queue_pk, false),
-        AccountMeta::new(*dex_event_queue_pk, false),
-        AccountMeta::new(*dex_base_pk, false),
-        AccountMeta::new(*dex_quote_pk, false),
-        AccountMeta::new_readonly(*base_root_bank_pk, false),
-        AccountMeta::new(*base_node_bank_pk, false),
-        AccountMeta::new(*base_vault_pk, false),
-        AccountMeta::new_readonly(*quote_root_bank_pk, false),
-        AccountMeta::new(*quote_node_bank_pk, false),
-        AccountMeta::new(*quote_vault_pk, false),
-        AccountMeta::new_readonly(spl_token::ID, false),
-        AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
-        AccountMeta::new_readonly(*dex_signer_pk, false),
-        AccountMeta::new_readonly(*msrm_or_srm_vault_pk, false),
     ];
+    accounts.extend(open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
 
-    accounts.extend(open_orders_pks.iter().enumerate().map(|(i, pk)| {
-        if i == market_index {
-            AccountMeta::new(*pk, false)
-        } else {
-            AccountMeta::new_readonly(*pk, false)
-        }
-    }));
-
-    let instr = LyraeInstruction::PlaceSpotOrder { order };
+    let instr = LyraeInstruction::ComputeHealth;
     let data = instr.pack();
-
     Ok(Instruction {
         program_id: *program_id,
         accounts,
@@ -2602,50 +5012,29 @@ pub fn place_spot_order(
     })
 }
 
-pub fn settle_funds(
+pub fn cancel_all_spot_orders(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    lyrae_cache_pk: &Pubkey,
     owner_pk: &Pubkey,
     lyrae_account_pk: &Pubkey,
     dex_prog_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    open_orders_pk: &Pubkey,
     signer_pk: &Pubkey,
-    dex_base_pk: &Pubkey,
-    dex_quote_pk: &Pubkey,
-    base_root_bank_pk: &Pubkey,
-    base_node_bank_pk: &Pubkey,
-    quote_root_bank_pk: &Pubkey,
-    quote_node_bank_pk: &Pubkey,
-    base_vault_pk: &Pubkey,
-    quote_vault_pk: &Pubkey,
-    dex_signer_pk: &Pubkey,
+    market_pks: &[[Pubkey; 5]], // spot_market, bids, asks, open_orders, dex_event_queue
+    limit: u8,
 ) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
+    let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new_readonly(*lyrae_cache_pk, false),
         AccountMeta::new_readonly(*owner_pk, true),
         AccountMeta::new(*lyrae_account_pk, false),
         AccountMeta::new_readonly(*dex_prog_pk, false),
-        AccountMeta::new(*spot_market_pk, false),
-        AccountMeta::new(*open_orders_pk, false),
         AccountMeta::new_readonly(*signer_pk, false),
-        AccountMeta::new(*dex_base_pk, false),
-        AccountMeta::new(*dex_quote_pk, false),
-        AccountMeta::new_readonly(*base_root_bank_pk, false),
-        AccountMeta::new(*base_node_bank_pk, false),
-        AccountMeta::new_readonly(*quote_root_bank_pk, false),
-        AccountMeta::new(*quote_node_bank_pk, false),
-        AccountMeta::new(*base_vault_pk, false),
-        AccountMeta::new(*quote_vault_pk, false),
-        AccountMeta::new_readonly(*dex_signer_pk, false),
-        AccountMeta::new_readonly(spl_token::ID, false),
     ];
+    for pks in market_pks.iter() {
+        accounts.extend(pks.iter().map(|pk| AccountMeta::new(*pk, false)));
+    }
 
-    let instr = LyraeInstruction::SettleFunds;
+    let instr = LyraeInstruction::CancelAllSpotOrders { limit };
     let data = instr.pack();
-
     Ok(Instruction {
         program_id: *program_id,
         accounts,
@@ -2653,47 +5042,16 @@ pub fn settle_funds(
     })
 }
 
-pub fn add_oracle(
+pub fn log_lyr_accrued(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
-    admin_pk: &Pubkey,
+    lyrae_account_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*oracle_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
-    ];
-
-    let instr = LyraeInstruction::AddOracle;
-    let data = instr.pack();
-    Ok(Instruction {
-        program_id: *program_id,
-        accounts,
-        data,
-    })
-}
-
-pub fn update_root_bank(
-    program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,
-    lyrae_cache_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
-    node_bank_pks: &[Pubkey],
-) -> Result<Instruction, ProgramError> {
-    let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*lyrae_cache_pk, false),
-        AccountMeta::new(*root_bank_pk, false),
+        AccountMeta::new_readonly(*lyrae_account_pk, false),
     ];
-
-    accounts.extend(
-        node_bank_pks
-            .iter()
-            .map(|pk| AccountMeta::new_readonly(*pk, false)),
-    );
-
-    let instr = LyraeInstruction::UpdateRootBank;
+    let instr = LyraeInstruction::LogLyrAccrued;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2702,20 +5060,34 @@ pub fn update_root_bank(
     })
 }
 
-pub fn set_oracle(
+pub fn reassign_spot_open_orders(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
-    oracle_pk: &Pubkey,
-    admin_pk: &Pubkey,
-    price: I80F48,
+    lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    old_dex_prog_pk: &Pubkey,
+    old_open_orders_pk: &Pubkey,
+    old_spot_market_pk: &Pubkey,
+    dex_prog_pk: &Pubkey,
+    open_orders_pk: &Pubkey,
+    spot_market_pk: &Pubkey,
+    signer_pk: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
-        AccountMeta::new(*oracle_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
+        AccountMeta::new(*lyrae_account_pk, false),
+        AccountMeta::new(*owner_pk, true),
+        AccountMeta::new_readonly(*old_dex_prog_pk, false),
+        AccountMeta::new(*old_open_orders_pk, false),
+        AccountMeta::new_readonly(*old_spot_market_pk, false),
+        AccountMeta::new_readonly(*dex_prog_pk, false),
+        AccountMeta::new(*open_orders_pk, false),
+        AccountMeta::new_readonly(*spot_market_pk, false),
+        AccountMeta::new_readonly(*signer_pk, false),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::ID, false),
     ];
 
-    let instr = LyraeInstruction::SetOracle { price };
+    let instr = LyraeInstruction::ReassignSpotOpenOrders;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2724,45 +5096,29 @@ pub fn set_oracle(
     })
 }
 
-pub fn liquidate_token_and_token(
+pub fn transfer_perp_position(
     program_id: &Pubkey,
     lyrae_group_pk: &Pubkey,
     lyrae_cache_pk: &Pubkey,
-    liqee_lyrae_account_pk: &Pubkey,
-    liqor_lyrae_account_pk: &Pubkey,
-    liqor_pk: &Pubkey,
-    asset_root_bank_pk: &Pubkey,
-    asset_node_bank_pk: &Pubkey,
-    liab_root_bank_pk: &Pubkey,
-    liab_node_bank_pk: &Pubkey,
-    liqee_open_orders_pks: &[Pubkey],
-    liqor_open_orders_pks: &[Pubkey],
-    max_liab_transfer: I80F48,
+    perp_market_pk: &Pubkey,
+    src_lyrae_account_pk: &Pubkey,
+    dst_lyrae_account_pk: &Pubkey,
+    owner_pk: &Pubkey,
+    src_open_orders_pks: &[Pubkey; MAX_PAIRS],
+    dst_open_orders_pks: &[Pubkey; MAX_PAIRS],
 ) -> Result<Instruction, ProgramError> {
     let mut accounts = vec![
         AccountMeta::new_readonly(*lyrae_group_pk, false),
         AccountMeta::new_readonly(*lyrae_cache_pk, false),
-        AccountMeta::new(*liqee_lyrae_account_pk, false),
-        AccountMeta::new(*liqor_lyrae_account_pk, false),
-        AccountMeta::new_readonly(*liqor_pk, true),
-        AccountMeta::new_readonly(*asset_root_bank_pk, false),
-        AccountMeta::new(*asset_node_bank_pk, false),
-        AccountMeta::new_readonly(*liab_root_bank_pk, false),
-        AccountMeta::new(*liab_node_bank_pk, false),
+        AccountMeta::new(*perp_market_pk, false),
+        AccountMeta::new(*src_lyrae_account_pk, false),
+        AccountMeta::new(*dst_lyrae_account_pk, false),
+        AccountMeta::new_readonly(*owner_pk, true),
     ];
+    accounts.extend(src_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
+    accounts.extend(dst_open_orders_pks.iter().map(|pk| AccountMeta::new_readonly(*pk, false)));
 
-    accounts.extend(
-        liqee_open_orders_pks
-            .iter()
-            .map(|pk| AccountMeta::new_readonly(*pk, false)),
-    );
-    accounts.extend(
-        liqor_open_orders_pks
-            .iter()
-            .map(|pk| AccountMeta::new_readonly(*pk, false)),
-    );
-
-    let instr = LyraeInstruction::LiquidateTokenAndToken { max_liab_transfer };
+    let instr = LyraeInstruction::TransferPerpPosition;
     let data = instr.pack();
     Ok(Instruction {
         program_id: *program_id,
@@ -2771,42 +5127,11 @@ pub fn liquidate_token_and_token(
     })
 }
 
-pub fn change_spot_market_params(
-    program_id: &Pubkey,
-    lyrae_group_pk: &Pubkey,
-    spot_market_pk: &Pubkey,
-    root_bank_pk: &Pubkey,
-    admin_pk: &Pubkey,
-    maint_leverage: Option<I80F48>,
-    init_leverage: Option<I80F48>,
-    liquidation_fee: Option<I80F48>,
-    optimal_util: Option<I80F48>,
-    optimal_rate: Option<I80F48>,
-    max_rate: Option<I80F48>,
-    version: Option<u8>,
-) -> Result<Instruction, ProgramError> {
-    let accounts = vec![
-        AccountMeta::new(*lyrae_group_pk, false),
-        AccountMeta::new(*spot_market_pk, false),
-        AccountMeta::new(*root_bank_pk, false),
-        AccountMeta::new_readonly(*admin_pk, true),
-    ];
-
-    let instr = LyraeInstruction::ChangeSpotMarketParams {
-        maint_leverage,
-        init_leverage,
-        liquidation_fee,
-        optimal_util,
-        optimal_rate,
-        max_rate,
-        version,
-    };
-    let data = instr.pack();
-    Ok(Instruction {
-        program_id: *program_id,
-        accounts,
-        data,
-    })
+/// Append the group's MsrmFeeTiers account to a PlacePerpOrder, PlacePerpOrderAmend or
+/// PlacePerpOrderByNotional instruction so the taker gets its msrm fee tier discount
+pub fn add_msrm_fee_tiers(mut instruction: Instruction, msrm_fee_tiers_pk: &Pubkey) -> Instruction {
+    instruction.accounts.push(AccountMeta::new_readonly(*msrm_fee_tiers_pk, false));
+    instruction
 }
 
 /// Serialize Option<T> as (bool, T). This gives the binary representation
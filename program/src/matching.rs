@@ -16,7 +16,7 @@ use solana_program::sysvar::Sysvar;
 use static_assertions::const_assert_eq;
 
 use lyrae_common::Loadable;
-use lyrae_logs::{lyrae_emit, ReferralFeeAccrualLog};
+use lyrae_logs::{lyrae_emit, MmExemptFeeLog, ReferralFeeAccrualLog};
 use lyrae_macro::{Loadable, Pod};
 
 use crate::error::{check_assert, LyraeError, LyraeErrorCode, LyraeResult, SourceFileId};
@@ -231,10 +231,15 @@ impl AsRef<AnyNode> for LeafNode {
 #[repr(u8)]
 #[serde(into = "u8", try_from = "u8")]
 pub enum OrderType {
+    /// Take existing orders up to price, then post the remainder
     Limit = 0,
+    /// Take existing orders up to price and never post the remainder
     ImmediateOrCancel = 1,
+    /// Never take any existing orders; fail if the order would cross the book
     PostOnly = 2,
+    /// Take existing orders at any price and never post the remainder
     Market = 3,
+    /// Never take any existing orders; move the price to just outside the book instead
     PostOnlySlide = 4,
 }
 
@@ -381,6 +386,18 @@ impl BookSide {
         Ok(state)
     }
 
+    /// Whole quote tokens walked into the book to find the funding impact bid and ask; 0 means
+    /// the prices IMPACT_QUANTITY lots deep are used. PerpMarket has no room left for it, so it
+    /// is kept in the bids' meta_data.extra_info[0..4] and is always 0 on the asks.
+    pub fn get_funding_impact_notional(&self) -> u32 {
+        let bytes = <[u8; 4]>::try_from(&self.meta_data.extra_info[..4]).unwrap();
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn set_funding_impact_notional(&mut self, quote_tokens: u32) {
+        self.meta_data.extra_info[..4].copy_from_slice(&quote_tokens.to_le_bytes());
+    }
+
     fn get_mut(&mut self, key: NodeHandle) -> Option<&mut AnyNode> {
         let node = &mut self.nodes[key as usize];
         let tag = NodeTag::try_from(node.tag);
@@ -562,7 +579,7 @@ impl BookSide {
         if self.free_list_len == 0 {
             check!(
                 self.bump_index < self.nodes.len() && self.bump_index < (u32::MAX as usize),
-                LyraeErrorCode::OutOfSpace
+                LyraeErrorCode::BookFull
             )?;
 
             self.nodes[self.bump_index] = *val;
@@ -658,6 +675,11 @@ impl BookSide {
     pub fn is_full(&self) -> bool {
         self.free_list_len <= 1 && self.bump_index >= self.nodes.len() - 1
     }
+
+    /// Number of nodes (inner and leaf) currently in use
+    pub fn used_nodes(&self) -> usize {
+        self.bump_index - self.free_list_len
+    }
 }
 
 pub struct Book<'a> {
@@ -725,6 +747,30 @@ impl<'a> Book<'a> {
         None
     }
 
+    /// Walk up the book until `quote_lots` worth of orders is reached and return the average
+    /// price of those orders. If that much is not on book, return None
+    pub fn get_average_impact_price(&self, side: Side, quote_lots: i64) -> Option<i64> {
+        let quote_lots = quote_lots.max(1);
+        let mut base = 0i64;
+        let mut quote = 0i64;
+        let book_side = match side {
+            Side::Bid => self.bids.iter(),
+            Side::Ask => self.asks.iter(),
+        };
+        for order in book_side {
+            let price = order.price();
+            // Only take as much of the last order as is needed to reach quote_lots
+            let remaining = quote_lots - quote;
+            let quantity = order.quantity.min((remaining + price - 1) / price);
+            base += quantity;
+            quote = quote.checked_add(quantity.checked_mul(price)?)?;
+            if quote >= quote_lots {
+                return Some(quote / base);
+            }
+        }
+        None
+    }
+
     /// Get the quantity of asks below and including the price
     pub fn get_asks_size_below(&self, price: i64, max_depth: i64) -> i64 {
         let mut s = 0;
@@ -779,6 +825,7 @@ impl<'a> Book<'a> {
         client_order_id: u64,
         now_ts: u64,
         referrer_lyrae_account_ai: Option<&AccountInfo>,
+        taker_fee_discount_bps: u16,
     ) -> LyraeResult {
         match side {
             Side::Bid => self.new_bid(
@@ -798,6 +845,7 @@ impl<'a> Book<'a> {
                 client_order_id,
                 now_ts,
                 referrer_lyrae_account_ai,
+                taker_fee_discount_bps,
             ),
             Side::Ask => self.new_ask(
                 program_id,
@@ -816,6 +864,7 @@ impl<'a> Book<'a> {
                 client_order_id,
                 now_ts,
                 referrer_lyrae_account_ai,
+                taker_fee_discount_bps,
             ),
         }
     }
@@ -844,7 +893,8 @@ impl<'a> Book<'a> {
                 } else {
                     price
                 };
-                (true, true, price)
+                // A best ask of 1 leaves no valid price to slide the bid to
+                (true, price > 0, price)
             }
         };
         if post_allowed {
@@ -1009,6 +1059,7 @@ impl<'a> Book<'a> {
         client_order_id: u64,
         now_ts: u64,
         referrer_lyrae_account_ai: Option<&AccountInfo>,
+        taker_fee_discount_bps: u16,
     ) -> LyraeResult {
         // TODO proper error handling
         // TODO handle the case where we run out of compute (right now just fails)
@@ -1023,10 +1074,12 @@ impl<'a> Book<'a> {
                 } else {
                     price
                 };
-                (true, true, price)
+                // A best ask of 1 leaves no valid price to slide the bid to
+                (true, price > 0, price)
             }
         };
         let info = &lyrae_group.perp_markets[market_index];
+        let taker_fee = info.discounted_taker_fee(taker_fee_discount_bps);
         if post_allowed {
             // price limit check computed lazily to save CU on average
             let native_price = market.lot_to_native_price(price);
@@ -1082,6 +1135,8 @@ impl<'a> Book<'a> {
                     lyrae_group_pk,
                     lyrae_cache,
                     lyrae_account,
+                    lyrae_account_pk,
+                    market_index,
                     referrer_lyrae_account_ai,
                     now_ts,
                 )?;
@@ -1104,10 +1159,11 @@ impl<'a> Book<'a> {
                 *lyrae_account_pk,
                 order_id,
                 client_order_id,
-                info.taker_fee + ref_fee_rate.unwrap(),
+                taker_fee + ref_fee_rate.unwrap(),
                 best_ask_price,
                 match_quantity,
                 best_ask.version,
+                taker_fee_discount_bps,
             );
             event_queue.push_back(cast(fill)).unwrap();
 
@@ -1124,7 +1180,7 @@ impl<'a> Book<'a> {
             if self.bids.is_full() {
                 // If this bid is higher than lowest bid, boot that bid and insert this one
                 let min_bid = self.bids.remove_min().unwrap();
-                check!(price > min_bid.price(), LyraeErrorCode::OutOfSpace)?;
+                check!(price > min_bid.price(), LyraeErrorCode::BookFull)?;
                 let event = OutEvent::new(
                     Side::Bid,
                     min_bid.owner_slot,
@@ -1174,6 +1230,7 @@ impl<'a> Book<'a> {
             apply_fees(
                 market,
                 info,
+                taker_fee,
                 lyrae_account,
                 lyrae_account_pk,
                 market_index,
@@ -1207,6 +1264,7 @@ impl<'a> Book<'a> {
         client_order_id: u64,
         now_ts: u64,
         referrer_lyrae_account_ai: Option<&AccountInfo>,
+        taker_fee_discount_bps: u16,
     ) -> LyraeResult {
         let (post_only, mut post_allowed, price) = match order_type {
             OrderType::Limit => (false, true, price),
@@ -1223,6 +1281,7 @@ impl<'a> Book<'a> {
             }
         };
         let info = &lyrae_group.perp_markets[market_index];
+        let taker_fee = info.discounted_taker_fee(taker_fee_discount_bps);
         if post_allowed {
             // price limit check computed lazily to save CU on average
             let native_price = market.lot_to_native_price(price);
@@ -1277,6 +1336,8 @@ impl<'a> Book<'a> {
                     lyrae_group_pk,
                     lyrae_cache,
                     lyrae_account,
+                    lyrae_account_pk,
+                    market_index,
                     referrer_lyrae_account_ai,
                     now_ts,
                 )?;
@@ -1299,10 +1360,11 @@ impl<'a> Book<'a> {
                 *lyrae_account_pk,
                 order_id,
                 client_order_id,
-                info.taker_fee + ref_fee_rate.unwrap(),
+                taker_fee + ref_fee_rate.unwrap(),
                 best_bid_price,
                 match_quantity,
                 best_bid.version,
+                taker_fee_discount_bps,
             );
 
             event_queue.push_back(cast(fill)).unwrap();
@@ -1320,7 +1382,7 @@ impl<'a> Book<'a> {
             if self.asks.is_full() {
                 // If this asks is lower than highest ask, boot that ask and insert this one
                 let max_ask = self.asks.remove_max().unwrap();
-                check!(price < max_ask.price(), LyraeErrorCode::OutOfSpace)?;
+                check!(price < max_ask.price(), LyraeErrorCode::BookFull)?;
                 let event = OutEvent::new(
                     Side::Ask,
                     max_ask.owner_slot,
@@ -1370,6 +1432,7 @@ impl<'a> Book<'a> {
             apply_fees(
                 market,
                 info,
+                taker_fee,
                 lyrae_account,
                 lyrae_account_pk,
                 market_index,
@@ -1395,7 +1458,8 @@ impl<'a> Book<'a> {
         }
     }
 
-    /// Used by force cancel so does not need to give liquidity incentives
+    /// Cancel orders without applying liquidity mining incentives. Used by force cancel so a
+    /// liqee can't earn LYR by getting its own orders canceled.
     pub fn cancel_all(
         &mut self,
         lyrae_account: &mut LyraeAccount,
@@ -1737,9 +1801,20 @@ fn determine_ref_vars<'a>(
     lyrae_group_pk: &Pubkey,
     lyrae_cache: &LyraeCache,
     lyrae_account: &LyraeAccount,
+    lyrae_account_pk: &Pubkey,
+    market_index: usize,
     referrer_lyrae_account_ai: Option<&'a AccountInfo>,
     now_ts: u64,
 ) -> LyraeResult<(I80F48, Option<RefMut<'a, LyraeAccount>>)> {
+    if lyrae_account.is_mm_exempt() {
+        lyrae_emit!(MmExemptFeeLog {
+            lyrae_group: *lyrae_group_pk,
+            lyrae_account: *lyrae_account_pk,
+            market_index: market_index as u64,
+        });
+        return Ok((ZERO_I80F48, None));
+    }
+
     let lyr_index = match lyrae_group.find_token_index(&lyr_token::id()) {
         None => return Ok((ZERO_I80F48, None)),
         Some(i) => i,
@@ -1778,10 +1853,12 @@ fn determine_ref_vars<'a>(
 }
 
 /// Apply taker fees to the taker account and update the markets' fees_accrued for
-/// both the maker and taker fees.
+/// both the maker and taker fees. `taker_fee` is the market's taker fee after the taker's
+/// msrm fee tier discount.
 fn apply_fees(
     market: &mut PerpMarket,
     info: &PerpMarketInfo,
+    taker_fee: I80F48,
     lyrae_account: &mut LyraeAccount,
     lyrae_account_pk: &Pubkey,
     market_index: usize,
@@ -1830,7 +1907,7 @@ fn apply_fees(
     // The maker fees apply to the maker's account only when the fill event is consumed.
     let maker_fees = taker_quote_native * info.maker_fee;
 
-    let taker_fees = taker_quote_native * info.taker_fee;
+    let taker_fees = taker_quote_native * taker_fee;
     lyrae_account.perp_accounts[market_index].quote_position -= taker_fees;
     market.fees_accrued += taker_fees + maker_fees;
 
@@ -1842,3 +1919,527 @@ fn apply_fees(
         perp_market_cache,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{AnyEvent, EventQueueHeader, EventType, Queue};
+    use crate::state::{MsrmFeeTiers, BPS_PER_UNIT, FREE_ORDER_SLOT};
+    use crate::utils::test_syscalls;
+    use bytemuck::{bytes_of_mut, Zeroable};
+    use fixed_macro::types::I80F48;
+    use std::cell::RefCell;
+
+    fn assert_close(a: I80F48, b: I80F48) {
+        assert!((a - b).abs() < I80F48!(0.000001), "{} != {}", a, b);
+    }
+
+    /// Quote position of a taker after apply_fees on 1000 quote lots with a 5 bps taker fee
+    fn taker_quote_after_fees(msrm_fee_tiers: &MsrmFeeTiers, msrm_amount: u64) -> I80F48 {
+        let mut market = PerpMarket::zeroed();
+        market.quote_lot_size = 100;
+        let mut info = PerpMarketInfo::zeroed();
+        info.taker_fee = I80F48!(0.0005);
+        let mut lyrae_account = LyraeAccount::zeroed();
+        lyrae_account.msrm_amount = msrm_amount;
+
+        let taker_fee_discount_bps = msrm_fee_tiers.get_taker_fee_discount_bps(msrm_amount);
+        apply_fees(
+            &mut market,
+            &info,
+            info.discounted_taker_fee(taker_fee_discount_bps),
+            &mut lyrae_account,
+            &Pubkey::default(),
+            0,
+            None,
+            None,
+            1000,
+            ZERO_I80F48,
+            &PerpMarketCache::zeroed(),
+        );
+        assert_eq!(market.fees_accrued, -lyrae_account.perp_accounts[0].quote_position);
+        lyrae_account.perp_accounts[0].quote_position
+    }
+
+    #[test]
+    fn msrm_fee_tiers_discount_taker_fees() {
+        let mut msrm_fee_tiers = MsrmFeeTiers::zeroed();
+        msrm_fee_tiers.min_msrm = [1, 5, 10];
+        msrm_fee_tiers.discount_bps = [1000, 2500, 0];
+
+        // 100_000 native quote traded at 5 bps
+        let untiered = taker_quote_after_fees(&msrm_fee_tiers, 0);
+        assert_close(untiered, I80F48!(-50));
+        let tier_0 = taker_quote_after_fees(&msrm_fee_tiers, 1);
+        assert_close(tier_0, I80F48!(-45));
+        let tier_1 = taker_quote_after_fees(&msrm_fee_tiers, 7);
+        assert_close(tier_1, I80F48!(-37.5));
+
+        // A disabled tier doesn't take away the discount of a lower one
+        let tier_2 = taker_quote_after_fees(&msrm_fee_tiers, 10);
+        assert_eq!(tier_2, tier_1);
+    }
+
+    #[test]
+    fn msrm_fee_tiers_do_not_reduce_rebates() {
+        let mut info = PerpMarketInfo::zeroed();
+        info.taker_fee = I80F48!(-0.0001);
+        assert_eq!(info.discounted_taker_fee(5000), info.taker_fee);
+        info.taker_fee = I80F48!(0.0004);
+        assert_close(info.discounted_taker_fee(5000), I80F48!(0.0002));
+        assert_eq!(info.discounted_taker_fee(BPS_PER_UNIT), ZERO_I80F48);
+    }
+
+    #[test]
+    fn referred_taker_fill_logs_referral_fee_accrual() {
+        test_syscalls::init();
+        let mut market = PerpMarket::zeroed();
+        market.quote_lot_size = 100;
+        let mut info = PerpMarketInfo::zeroed();
+        info.taker_fee = I80F48!(0.0005);
+        let (group_pk, taker_pk, referrer_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut taker = LyraeAccount::zeroed();
+        taker.lyrae_group = group_pk;
+        let mut referrer = Box::new(LyraeAccount::zeroed());
+        referrer.lyrae_group = group_pk;
+        let (mut referrer_lamports, program_id) = (0, Pubkey::new_unique());
+        let referrer_ai = AccountInfo::new(
+            &referrer_pk,
+            false,
+            true,
+            &mut referrer_lamports,
+            bytes_of_mut(&mut *referrer),
+            &program_id,
+            false,
+            0,
+        );
+
+        // 100_000 native quote traded with a 4 bps referral fee
+        apply_fees(
+            &mut market,
+            &info,
+            info.taker_fee,
+            &mut taker,
+            &taker_pk,
+            0,
+            Some(LyraeAccount::load_mut(&referrer_ai).unwrap()),
+            Some(&referrer_ai),
+            1000,
+            I80F48!(0.0004),
+            &PerpMarketCache::zeroed(),
+        );
+
+        let logs = test_syscalls::emitted::<ReferralFeeAccrualLog>();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].lyrae_group, group_pk);
+        assert_eq!(logs[0].referrer_lyrae_account, referrer_pk);
+        assert_eq!(logs[0].referree_lyrae_account, taker_pk);
+        assert_eq!(logs[0].market_index, 0);
+        let accrual = I80F48::from_bits(logs[0].referral_fee_accrual);
+        assert_close(accrual, I80F48!(40));
+        let referrer = LyraeAccount::load(&referrer_ai).unwrap();
+        assert_eq!(referrer.perp_accounts[0].quote_position, accrual);
+        assert_close(taker.perp_accounts[0].quote_position, I80F48!(-90));
+    }
+
+    const NUM_FUZZ_ACCOUNTS: usize = 3;
+    const FUZZ_EVENT_QUEUE_LEN: usize = 512;
+
+    /// Linear congruential generator so a failing sequence reproduces from its seed
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    /// A perp market with its book and event queue held in memory instead of in accounts
+    struct TestMarket {
+        program_id: Pubkey,
+        lyrae_group_pk: Pubkey,
+        lyrae_group: Box<LyraeGroup>,
+        lyrae_cache: Box<LyraeCache>,
+        perp_market: Box<PerpMarket>,
+        bids: RefCell<Box<BookSide>>,
+        asks: RefCell<Box<BookSide>>,
+        event_queue_header: RefCell<EventQueueHeader>,
+        event_queue_buf: RefCell<Vec<AnyEvent>>,
+        accounts: Vec<(Pubkey, Box<LyraeAccount>)>,
+    }
+
+    fn load_book<'a>(
+        bids: &'a RefCell<Box<BookSide>>,
+        asks: &'a RefCell<Box<BookSide>>,
+    ) -> Book<'a> {
+        Book {
+            bids: RefMut::map(bids.borrow_mut(), |b| &mut **b),
+            asks: RefMut::map(asks.borrow_mut(), |b| &mut **b),
+        }
+    }
+
+    fn load_event_queue<'a>(
+        header: &'a RefCell<EventQueueHeader>,
+        buf: &'a RefCell<Vec<AnyEvent>>,
+    ) -> EventQueue<'a> {
+        Queue::new(header.borrow_mut(), RefMut::map(buf.borrow_mut(), |b| b.as_mut_slice()))
+    }
+
+    impl TestMarket {
+        fn new(now_ts: u64) -> Self {
+            let mut lyrae_group = Box::new(LyraeGroup::zeroed());
+            lyrae_group.num_oracles = 1;
+            let info = &mut lyrae_group.perp_markets[0];
+            info.maint_asset_weight = I80F48!(0.95);
+            info.init_asset_weight = I80F48!(0.9);
+            info.maint_liab_weight = I80F48!(1.05);
+            info.init_liab_weight = I80F48!(1.1);
+            info.maker_fee = I80F48!(-0.0002);
+            info.taker_fee = I80F48!(0.0005);
+            info.base_lot_size = 10;
+            info.quote_lot_size = 1;
+
+            let mut perp_market = Box::new(PerpMarket::zeroed());
+            perp_market.meta_data =
+                MetaData::new_with_extra(DataType::PerpMarket, 1, true, [2, 0, 0, 0, 0]);
+            perp_market.base_lot_size = 10;
+            perp_market.quote_lot_size = 1;
+            perp_market.liquidity_mining_info.rate = I80F48!(0.0001);
+            perp_market.liquidity_mining_info.max_depth_bps = I80F48!(50);
+            perp_market.liquidity_mining_info.period_start = now_ts;
+            perp_market.liquidity_mining_info.target_period_length = 3600;
+            perp_market.liquidity_mining_info.lyr_left = 10_000;
+            perp_market.liquidity_mining_info.lyr_per_period = 10_000;
+
+            let mut bids = Box::new(BookSide::zeroed());
+            bids.meta_data = MetaData::new(DataType::Bids, 0, true);
+            let mut asks = Box::new(BookSide::zeroed());
+            asks.meta_data = MetaData::new(DataType::Asks, 0, true);
+
+            let accounts = (0..NUM_FUZZ_ACCOUNTS)
+                .map(|_| {
+                    let mut lyrae_account = Box::new(LyraeAccount::zeroed());
+                    lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+                    (Pubkey::new_unique(), lyrae_account)
+                })
+                .collect();
+
+            Self {
+                program_id: Pubkey::new_unique(),
+                lyrae_group_pk: Pubkey::new_unique(),
+                lyrae_group,
+                lyrae_cache: Box::new(LyraeCache::zeroed()),
+                perp_market,
+                bids: RefCell::new(bids),
+                asks: RefCell::new(asks),
+                event_queue_header: RefCell::new(EventQueueHeader::zeroed()),
+                event_queue_buf: RefCell::new(vec![AnyEvent::zeroed(); FUZZ_EVENT_QUEUE_LEN]),
+                accounts,
+            }
+        }
+
+        fn account_index(&self, pk: &Pubkey) -> usize {
+            self.accounts.iter().position(|(k, _)| k == pk).unwrap()
+        }
+
+        fn place(
+            &mut self,
+            account: usize,
+            side: Side,
+            price: i64,
+            quantity: i64,
+            order_type: OrderType,
+            now_ts: u64,
+        ) {
+            let mut book = load_book(&self.bids, &self.asks);
+            let mut event_queue = load_event_queue(&self.event_queue_header, &self.event_queue_buf);
+            let oracle_price = self.perp_market.lot_to_native_price(100);
+            let (lyrae_account_pk, lyrae_account) = &mut self.accounts[account];
+            book.new_order(
+                &self.program_id,
+                &self.lyrae_group,
+                &self.lyrae_group_pk,
+                &self.lyrae_cache,
+                &mut event_queue,
+                &mut self.perp_market,
+                oracle_price,
+                lyrae_account,
+                lyrae_account_pk,
+                0,
+                side,
+                price,
+                quantity,
+                order_type,
+                now_ts,
+                now_ts,
+                None,
+                0,
+            )
+            .unwrap();
+        }
+
+        /// Cancel one of the account's orders the way CancelPerpOrder does, incentives included
+        fn cancel(&mut self, account: usize, pick: u64, now_ts: u64) {
+            let mut book = load_book(&self.bids, &self.asks);
+            let lyrae_account = &mut self.accounts[account].1;
+            let slots: Vec<usize> = (0..MAX_PERP_OPEN_ORDERS)
+                .filter(|&i| lyrae_account.order_market[i] != FREE_ORDER_SLOT)
+                .collect();
+            if slots.is_empty() {
+                return;
+            }
+            let slot = slots[pick as usize % slots.len()];
+            let (order_id, side) = (lyrae_account.orders[slot], lyrae_account.order_side[slot]);
+
+            let max_depth: i64 = self.perp_market.liquidity_mining_info.max_depth_bps.to_num();
+            let best_final = match side {
+                Side::Bid => book.get_bids_size_above_order(order_id, max_depth),
+                Side::Ask => book.get_asks_size_below_order(order_id, max_depth),
+            };
+            let order = book.cancel_order(order_id, side).unwrap();
+            lyrae_account.remove_order(slot, order.quantity).unwrap();
+            lyrae_account.perp_accounts[0]
+                .apply_size_incentives(
+                    &mut self.perp_market,
+                    order.best_initial,
+                    best_final,
+                    order.timestamp,
+                    now_ts,
+                    order.quantity,
+                )
+                .unwrap();
+        }
+
+        /// Apply every event on the queue to the maker and taker accounts, like ConsumeEvents
+        fn consume_events(&mut self) {
+            let mut event_queue = load_event_queue(&self.event_queue_header, &self.event_queue_buf);
+            while let Some(event) = event_queue.peek_front() {
+                let event = *event;
+                match EventType::try_from(event.event_type).unwrap() {
+                    EventType::Fill => {
+                        let fill: &FillEvent = cast_ref(&event);
+                        let cache = &self.lyrae_cache.perp_market_cache[0];
+                        let maker = self.account_index(&fill.maker);
+                        let taker = self.account_index(&fill.taker);
+                        let perp_market = &mut self.perp_market;
+                        let accounts = &mut self.accounts;
+                        accounts[maker].1.execute_maker(0, perp_market, cache, fill).unwrap();
+                        accounts[taker].1.execute_taker(0, perp_market, cache, fill).unwrap();
+                    }
+                    EventType::Out => {
+                        let out: &OutEvent = cast_ref(&event);
+                        let owner = self.account_index(&out.owner);
+                        let lyrae_account = &mut self.accounts[owner].1;
+                        lyrae_account.remove_order(out.slot as usize, out.quantity).unwrap();
+                    }
+                    EventType::Liquidate | EventType::Consumed => unreachable!(),
+                }
+                event_queue.pop_front().unwrap();
+            }
+        }
+
+        fn check_invariants(&self, lyr_accrued_before: &[u64]) {
+            let bids = self.bids.borrow();
+            let asks = self.asks.borrow();
+            if let (Some(bid), Some(ask)) = (bids.get_max(), asks.get_min()) {
+                assert!(bid.price() < ask.price(), "book is crossed");
+            }
+
+            let mut total_base = 0;
+            let mut total_quote = self.perp_market.fees_accrued;
+            for (i, (pk, lyrae_account)) in self.accounts.iter().enumerate() {
+                let resting = |book_side: &BookSide| -> i64 {
+                    book_side
+                        .iter()
+                        .filter(|order| &order.owner == pk)
+                        .map(|order| {
+                            assert!(order.quantity > 0);
+                            order.quantity
+                        })
+                        .sum()
+                };
+                let pa = &lyrae_account.perp_accounts[0];
+                assert_eq!(pa.bids_quantity, resting(&bids));
+                assert_eq!(pa.asks_quantity, resting(&asks));
+                assert_eq!((pa.taker_base, pa.taker_quote), (0, 0));
+                assert!(pa.lyr_accrued >= lyr_accrued_before[i], "lyr_accrued went down");
+                total_base += pa.base_position;
+                total_quote += pa.quote_position;
+            }
+            assert_eq!(total_base, 0);
+            assert_close(total_quote, ZERO_I80F48);
+        }
+
+        fn lyr_accrued(&self) -> Vec<u64> {
+            self.accounts.iter().map(|(_, ma)| ma.perp_accounts[0].lyr_accrued).collect()
+        }
+    }
+
+    fn fuzz_matching(seed: u64, num_ops: usize) {
+        let mut rng = Lcg(seed);
+        let mut now_ts = 1_000_000;
+        let mut market = TestMarket::new(now_ts);
+        for _ in 0..num_ops {
+            now_ts += rng.below(60);
+            let lyr_accrued_before = market.lyr_accrued();
+            let account = rng.below(NUM_FUZZ_ACCOUNTS as u64) as usize;
+            let has_free_slot = market.accounts[account].1.next_order_slot().is_some();
+            if rng.below(4) == 0 || !has_free_slot {
+                market.cancel(account, rng.below(MAX_PERP_OPEN_ORDERS as u64), now_ts);
+            } else {
+                let side = if rng.below(2) == 0 { Side::Bid } else { Side::Ask };
+                let order_type = match rng.below(10) {
+                    0 => OrderType::ImmediateOrCancel,
+                    1 => OrderType::PostOnly,
+                    2 => OrderType::PostOnlySlide,
+                    3 => OrderType::Market,
+                    _ => OrderType::Limit,
+                };
+                let price = 94 + rng.below(13) as i64;
+                let quantity = 1 + rng.below(20) as i64;
+                market.place(account, side, price, quantity, order_type, now_ts);
+            }
+            market.consume_events();
+            market.check_invariants(&lyr_accrued_before);
+        }
+    }
+
+    #[test]
+    fn fuzz_place_cancel_consume() {
+        for seed in 0..8 {
+            fuzz_matching(seed, 500);
+        }
+    }
+
+    #[test]
+    fn average_impact_price_walks_quote_lots_into_the_book() {
+        let now_ts = 1_000_000;
+        let mut market = TestMarket::new(now_ts);
+        market.place(0, Side::Ask, 100, 2, OrderType::Limit, now_ts);
+        market.place(0, Side::Ask, 104, 3, OrderType::Limit, now_ts);
+        market.place(1, Side::Bid, 99, 4, OrderType::Limit, now_ts);
+        let book = load_book(&market.bids, &market.asks);
+
+        // Only as much of the last order as needed is taken
+        assert_eq!(book.get_average_impact_price(Side::Ask, 150), Some(100));
+        assert_eq!(book.get_average_impact_price(Side::Ask, 300), Some((200 + 104) / 3));
+        assert_eq!(book.get_average_impact_price(Side::Ask, 512), Some(512 / 5));
+        assert_eq!(book.get_average_impact_price(Side::Ask, 513), None);
+
+        assert_eq!(book.get_average_impact_price(Side::Bid, 0), Some(99));
+        assert_eq!(book.get_average_impact_price(Side::Bid, 300), Some(99));
+        assert_eq!(book.get_average_impact_price(Side::Bid, 397), None);
+    }
+
+    #[test]
+    fn immediate_or_cancel_fills_and_cancels_the_remainder() {
+        let now_ts = 1_000_000;
+        let mut market = TestMarket::new(now_ts);
+        market.place(0, Side::Ask, 100, 3, OrderType::Limit, now_ts);
+        market.place(0, Side::Ask, 102, 4, OrderType::Limit, now_ts);
+        market.place(1, Side::Bid, 101, 5, OrderType::ImmediateOrCancel, now_ts);
+
+        // Only the ask within the limit price is taken and the remaining 2 lots don't rest
+        let taker = &market.accounts[1].1;
+        assert_eq!(taker.perp_accounts[0].taker_base, 3);
+        assert_eq!(taker.perp_accounts[0].bids_quantity, 0);
+        assert_eq!(taker.order_market[0], FREE_ORDER_SLOT);
+        assert!(market.bids.borrow().get_max().is_none());
+        assert_eq!(market.asks.borrow().get_min().unwrap().price(), 102);
+
+        market.consume_events();
+        assert_eq!(market.accounts[1].1.perp_accounts[0].base_position, 3);
+        assert_eq!(market.accounts[0].1.perp_accounts[0].base_position, -3);
+        assert_eq!(market.accounts[0].1.perp_accounts[0].asks_quantity, 4);
+    }
+
+    #[test]
+    fn post_only_slide_reprices_without_filling() {
+        let now_ts = 1_000_000;
+        let mut market = TestMarket::new(now_ts);
+        market.place(0, Side::Ask, 100, 3, OrderType::Limit, now_ts);
+        let info = market.lyrae_group.perp_markets[0];
+        let oracle_price = market.perp_market.lot_to_native_price(100);
+        let simulated = load_book(&market.bids, &market.asks)
+            .sim_new_bid(&market.perp_market, &info, oracle_price, 103, 2, OrderType::PostOnlySlide)
+            .unwrap();
+        assert_eq!(simulated, (0, 0, 2, 0));
+
+        // The bid crossing the best ask slides to one tick below it
+        market.place(1, Side::Bid, 103, 2, OrderType::PostOnlySlide, now_ts);
+        let best_bid = *market.bids.borrow().get_max().unwrap();
+        assert_eq!((best_bid.price(), best_bid.quantity), (99, 2));
+        assert_eq!(market.accounts[1].1.perp_accounts[0].taker_base, 0);
+        assert!(load_event_queue(&market.event_queue_header, &market.event_queue_buf).empty());
+
+        // and an ask crossing the best bid to one tick above it
+        market.place(2, Side::Ask, 95, 1, OrderType::PostOnlySlide, now_ts);
+        let maker = &market.accounts[2].1;
+        assert_eq!((maker.orders[0] >> 64) as i64, 100);
+        assert_eq!(maker.perp_accounts[0].asks_quantity, 1);
+        assert!(load_event_queue(&market.event_queue_header, &market.event_queue_buf).empty());
+    }
+
+    #[test]
+    fn post_only_slide_bid_below_an_ask_of_one_is_not_posted() {
+        let now_ts = 1_000_000;
+        let mut market = TestMarket::new(now_ts);
+        let order_id = market.perp_market.gen_order_id(Side::Ask, 1);
+        let (owner, _) = market.accounts[0];
+        let ask = LeafNode::new(1, 0, order_id, owner, 3, 0, now_ts, 0, OrderType::Limit);
+        market.asks.borrow_mut().insert_leaf(&ask).unwrap();
+        let info = market.lyrae_group.perp_markets[0];
+        let oracle_price = market.perp_market.lot_to_native_price(1);
+        let simulated = load_book(&market.bids, &market.asks)
+            .sim_new_bid(&market.perp_market, &info, oracle_price, 5, 2, OrderType::PostOnlySlide)
+            .unwrap();
+        assert_eq!(simulated, (0, 0, 0, 0));
+
+        market.place(1, Side::Bid, 5, 2, OrderType::PostOnlySlide, now_ts);
+        assert!(market.bids.borrow().get_max().is_none());
+        assert_eq!(market.accounts[1].1.perp_accounts[0].bids_quantity, 0);
+        assert_eq!(market.asks.borrow().get_min().unwrap().quantity, 3);
+    }
+
+    #[test]
+    fn self_trade_nets_out_and_pays_one_taker_fee() {
+        let now_ts = 1_000_000;
+        let mut market = TestMarket::new(now_ts);
+        market.place(0, Side::Ask, 100, 3, OrderType::Limit, now_ts);
+        market.place(0, Side::Bid, 100, 3, OrderType::Limit, now_ts);
+        market.consume_events();
+
+        // 300 native quote traded: one 5 bps taker fee and one 2 bps maker rebate
+        let pa = &market.accounts[0].1.perp_accounts[0];
+        assert_eq!(pa.base_position, 0);
+        assert_eq!((pa.bids_quantity, pa.asks_quantity), (0, 0));
+        assert_close(pa.quote_position, I80F48!(-0.15) + I80F48!(0.06));
+        assert_close(market.perp_market.fees_accrued, -pa.quote_position);
+    }
+
+    #[test]
+    fn force_cancel_pays_no_lyr() {
+        let now_ts = 1_000_000;
+        let later = now_ts + 1800;
+        let mut canceled = TestMarket::new(now_ts);
+        let mut force_canceled = TestMarket::new(now_ts);
+        for market in [&mut canceled, &mut force_canceled] {
+            market.place(0, Side::Bid, 99, 5, OrderType::Limit, now_ts);
+            market.place(0, Side::Ask, 101, 5, OrderType::Limit, now_ts);
+        }
+
+        canceled.cancel(0, 0, later);
+        canceled.cancel(0, 0, later);
+        assert!(canceled.accounts[0].1.perp_accounts[0].lyr_accrued > 0);
+
+        let mut book = load_book(&force_canceled.bids, &force_canceled.asks);
+        book.cancel_all(&mut force_canceled.accounts[0].1, 0, 10).unwrap();
+        drop(book);
+        let pa = &force_canceled.accounts[0].1.perp_accounts[0];
+        assert_eq!((pa.bids_quantity, pa.asks_quantity), (0, 0));
+        assert_eq!(pa.lyr_accrued, 0);
+        force_canceled.check_invariants(&[0; NUM_FUZZ_ACCOUNTS]);
+    }
+}
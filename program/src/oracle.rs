@@ -1,10 +1,11 @@
+use arrayref::{array_ref, array_refs};
 use fixed::types::I80F48;
 use lyrae_common::Loadable;
 use lyrae_macro::{Loadable, Pod};
 use solana_program::{account_info::AccountInfo, pubkey::Pubkey, rent::Rent};
 use std::{cell::RefMut, mem::size_of};
 
-use crate::error::{check_assert, LyraeErrorCode, LyraeResult, SourceFileId};
+use crate::error::{check_assert, LyraeError, LyraeErrorCode, LyraeResult, SourceFileId};
 
 declare_check_assert_macros!(SourceFileId::Oracle);
 
@@ -64,6 +65,7 @@ pub enum OracleType {
     Stub,
     Pyth,
     Switchboard,
+    SwitchboardV2,
     Unknown,
 }
 
@@ -221,6 +223,113 @@ impl AccKey {
     }
 }
 
+// Start of switchboard v2 implementation
+
+/// Anchor discriminator of the switchboard v2 AggregatorAccountData account
+pub const SWITCHBOARD_V2_AGGREGATOR_DISCRIMINATOR: [u8; 8] = [217, 230, 65, 101, 201, 162, 27, 125];
+/// Offset of `latest_confirmed_round` in the packed AggregatorAccountData, discriminator included
+const SWITCHBOARD_V2_LATEST_CONFIRMED_ROUND_OFFSET: usize = 341;
+/// num_success, num_error, is_closed, round_open_slot, round_open_timestamp, result, std_deviation
+const SWITCHBOARD_V2_ROUND_LEN: usize = 4 + 4 + 1 + 8 + 8 + 20 + 20;
+/// A confirmed round older than this is stale; both limits are about a minute
+pub const SWITCHBOARD_V2_MAX_STALENESS_SLOTS: u64 = 150;
+pub const SWITCHBOARD_V2_MAX_STALENESS_SECS: i64 = 60;
+
+// decimal as mantissa * 10^-scale
+#[derive(Copy, Clone)]
+pub struct SwitchboardDecimal {
+    pub mantissa: i128,
+    pub scale: u32,
+}
+
+impl SwitchboardDecimal {
+    fn unpack(data: &[u8; 20]) -> Self {
+        let (mantissa, scale) = array_refs![data, 16, 4];
+        Self {
+            mantissa: i128::from_le_bytes(*mantissa),
+            scale: u32::from_le_bytes(*scale),
+        }
+    }
+
+    pub fn to_i80f48(&self) -> Option<I80F48> {
+        // Drop precision that I80F48 can't represent anyway
+        let mut mantissa = self.mantissa;
+        let mut scale = self.scale;
+        while scale > 0 && I80F48::checked_from_num(mantissa).is_none() {
+            mantissa /= 10;
+            scale -= 1;
+        }
+
+        let mut value = I80F48::checked_from_num(mantissa)?;
+        while scale > 0 {
+            let step = scale.min(18);
+            value = value.checked_div(I80F48::from_num(10u64.pow(step)))?;
+            scale -= step;
+        }
+        Some(value)
+    }
+}
+
+// The leading fields of AggregatorRound; the per-oracle responses that follow are not needed
+#[derive(Copy, Clone)]
+pub struct SwitchboardV2Round {
+    pub num_success: u32,
+    pub num_error: u32,
+    pub is_closed: bool,
+    pub round_open_slot: u64,
+    pub round_open_timestamp: i64,
+    pub result: SwitchboardDecimal,        // mean of the oracle responses
+    pub std_deviation: SwitchboardDecimal, // of the oracle responses
+}
+
+impl SwitchboardV2Round {
+    pub fn get_latest_confirmed_round<'a>(account: &'a AccountInfo) -> LyraeResult<Self> {
+        let borrowed = &account.data.borrow();
+        let min_len = SWITCHBOARD_V2_LATEST_CONFIRMED_ROUND_OFFSET + SWITCHBOARD_V2_ROUND_LEN;
+        check!(
+            borrowed.len() >= min_len && borrowed[..8] == SWITCHBOARD_V2_AGGREGATOR_DISCRIMINATOR,
+            LyraeErrorCode::InvalidOracleType
+        )?;
+        let round = array_ref![
+            borrowed,
+            SWITCHBOARD_V2_LATEST_CONFIRMED_ROUND_OFFSET,
+            SWITCHBOARD_V2_ROUND_LEN
+        ];
+        let (
+            num_success,
+            num_error,
+            is_closed,
+            round_open_slot,
+            round_open_timestamp,
+            result,
+            std_deviation,
+        ) = array_refs![round, 4, 4, 1, 8, 8, 20, 20];
+        Ok(Self {
+            num_success: u32::from_le_bytes(*num_success),
+            num_error: u32::from_le_bytes(*num_error),
+            is_closed: is_closed[0] != 0,
+            round_open_slot: u64::from_le_bytes(*round_open_slot),
+            round_open_timestamp: i64::from_le_bytes(*round_open_timestamp),
+            result: SwitchboardDecimal::unpack(result),
+            std_deviation: SwitchboardDecimal::unpack(std_deviation),
+        })
+    }
+
+    /// The mean of the round, if it has responses, is not stale and is positive
+    pub fn get_result(&self, now_ts: u64, now_slot: u64) -> LyraeResult<I80F48> {
+        check!(self.num_success > 0, LyraeErrorCode::InvalidOraclePrice)?;
+        check!(
+            now_slot.saturating_sub(self.round_open_slot) <= SWITCHBOARD_V2_MAX_STALENESS_SLOTS
+                && (now_ts as i64).saturating_sub(self.round_open_timestamp)
+                    <= SWITCHBOARD_V2_MAX_STALENESS_SECS,
+            LyraeErrorCode::InvalidOraclePrice
+        )?;
+        let value = self.result.to_i80f48().ok_or(math_err!())?;
+        check!(value.is_positive(), LyraeErrorCode::InvalidOraclePrice)?;
+        Ok(value)
+    }
+}
+
 pub fn determine_oracle_type<'a>(account: &'a AccountInfo) -> OracleType {
     let borrowed = &account.data.borrow();
     if borrowed[0] == 212 && borrowed[1] == 195 && borrowed[2] == 178 && borrowed[3] == 161 {
@@ -229,7 +338,101 @@ pub fn determine_oracle_type<'a>(account: &'a AccountInfo) -> OracleType {
         return OracleType::Stub;
     } else if borrowed.len() == 1000 {
         return OracleType::Switchboard;
+    } else if borrowed.len() >= 8 && borrowed[..8] == SWITCHBOARD_V2_AGGREGATOR_DISCRIMINATOR {
+        return OracleType::SwitchboardV2;
     } else {
         return OracleType::Unknown;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixed_macro::types::I80F48;
+
+    /// An AggregatorAccountData with only the discriminator and latest_confirmed_round set
+    fn aggregator_data(round: &SwitchboardV2Round) -> Vec<u8> {
+        let mut data = vec![0u8; 3851];
+        data[..8].copy_from_slice(&SWITCHBOARD_V2_AGGREGATOR_DISCRIMINATOR);
+        let mut round_data = Vec::with_capacity(SWITCHBOARD_V2_ROUND_LEN);
+        round_data.extend_from_slice(&round.num_success.to_le_bytes());
+        round_data.extend_from_slice(&round.num_error.to_le_bytes());
+        round_data.push(round.is_closed as u8);
+        round_data.extend_from_slice(&round.round_open_slot.to_le_bytes());
+        round_data.extend_from_slice(&round.round_open_timestamp.to_le_bytes());
+        for decimal in [round.result, round.std_deviation].iter() {
+            round_data.extend_from_slice(&decimal.mantissa.to_le_bytes());
+            round_data.extend_from_slice(&decimal.scale.to_le_bytes());
+        }
+        let offset = SWITCHBOARD_V2_LATEST_CONFIRMED_ROUND_OFFSET;
+        data[offset..offset + SWITCHBOARD_V2_ROUND_LEN].copy_from_slice(&round_data);
+        data
+    }
+
+    fn read_round(data: &mut [u8]) -> LyraeResult<SwitchboardV2Round> {
+        let (key, owner, mut lamports) = (Pubkey::new_unique(), Pubkey::new_unique(), 0);
+        let ai = AccountInfo::new(&key, false, false, &mut lamports, data, &owner, false, 0);
+        assert!(determine_oracle_type(&ai) == OracleType::SwitchboardV2);
+        SwitchboardV2Round::get_latest_confirmed_round(&ai)
+    }
+
+    #[test]
+    fn switchboard_v2_round_is_read_and_checked() {
+        let (now_ts, now_slot) = (1_650_000_000u64, 130_000_000u64);
+        let round = SwitchboardV2Round {
+            num_success: 3,
+            num_error: 1,
+            is_closed: false,
+            round_open_slot: now_slot - 10,
+            round_open_timestamp: now_ts as i64 - 5,
+            result: SwitchboardDecimal { mantissa: 4_250_125, scale: 5 },
+            std_deviation: SwitchboardDecimal { mantissa: 12, scale: 2 },
+        };
+        let parsed = read_round(&mut aggregator_data(&round)).unwrap();
+        assert_eq!(parsed.num_success, 3);
+        assert_eq!(parsed.round_open_slot, round.round_open_slot);
+        let std_deviation = parsed.std_deviation.to_i80f48().unwrap();
+        assert!((std_deviation - I80F48!(0.12)).abs() < I80F48!(0.000001));
+        let price = parsed.get_result(now_ts, now_slot).unwrap();
+        assert!((price - I80F48!(42.50125)).abs() < I80F48!(0.000001));
+
+        let stale_slot = SwitchboardV2Round {
+            round_open_slot: now_slot - SWITCHBOARD_V2_MAX_STALENESS_SLOTS - 1,
+            ..round
+        };
+        assert!(read_round(&mut aggregator_data(&stale_slot))
+            .unwrap()
+            .get_result(now_ts, now_slot)
+            .is_err());
+
+        let stale_ts = SwitchboardV2Round {
+            round_open_timestamp: now_ts as i64 - SWITCHBOARD_V2_MAX_STALENESS_SECS - 1,
+            ..round
+        };
+        assert!(read_round(&mut aggregator_data(&stale_ts))
+            .unwrap()
+            .get_result(now_ts, now_slot)
+            .is_err());
+
+        for mantissa in [0, -4_250_125].iter() {
+            let non_positive = SwitchboardV2Round {
+                result: SwitchboardDecimal { mantissa: *mantissa, scale: 5 },
+                ..round
+            };
+            assert!(read_round(&mut aggregator_data(&non_positive))
+                .unwrap()
+                .get_result(now_ts, now_slot)
+                .is_err());
+        }
+
+        let no_responses = SwitchboardV2Round { num_success: 0, ..round };
+        assert!(read_round(&mut aggregator_data(&no_responses))
+            .unwrap()
+            .get_result(now_ts, now_slot)
+            .is_err());
+
+        let mut truncated = aggregator_data(&round);
+        truncated.truncate(SWITCHBOARD_V2_LATEST_CONFIRMED_ROUND_OFFSET);
+        assert!(read_round(&mut truncated).is_err());
+    }
+}
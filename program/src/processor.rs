@@ -2,6 +2,7 @@ use std::cell::RefMut;
 use std::cmp::min;
 use std::convert::{identity, TryFrom};
 use std::mem::size_of;
+use std::ops::Deref;
 use std::vec;
 
 use anchor_lang::prelude::emit;
@@ -16,6 +17,7 @@ use solana_program::entrypoint::ProgramResult;
 use solana_program::instruction::{AccountMeta, Instruction};
 use solana_program::msg;
 use solana_program::program_error::ProgramError;
+use solana_program::program::set_return_data;
 use solana_program::program_pack::{IsInitialized, Pack};
 use solana_program::pubkey::Pubkey;
 use solana_program::rent::Rent;
@@ -25,34 +27,43 @@ use switchboard_program::FastRoundResultAccountData;
 
 use lyrae_common::Loadable;
 use lyrae_logs::{
-    lyrae_emit, CachePerpMarketsLog, CachePricesLog, CacheRootBanksLog, CancelAllPerpOrdersLog,
-    DepositLog, LiquidatePerpMarketLog, LiquidateTokenAndPerpLog, LiquidateTokenAndTokenLog,
-    LyrAccrualLog, OpenOrdersBalanceLog, PerpBankruptcyLog, RedeemLyrLog, SettleFeesLog,
-    SettlePnlLog, TokenBalanceLog, TokenBankruptcyLog, UpdateFundingLog, UpdateRootBankLog,
-    WithdrawLog,
+    lyrae_emit, AdvancedOrderLog, BookCapacityLog, CacheConsistencyLog, CachePerpMarketsLog,
+    CachePricesLog, CacheRootBanksLog, CancelAllPerpOrdersLog, CloseLyraeAccountLog,
+    CreateLyraeAccountLog, DepositLog, DexProgramIdChangeLog, EmergencyAssetWeightLog,
+    GroupAdminChangeLog, GroupConfigChangeLog, GroupConfigParam, GroupTvlLog,
+    HealthComputeEstimateLog, LiquidatePerpMarketLog, LiquidateTokenAndPerpLog,
+    LiquidateTokenAndTokenLog, LiquidationStatusLog, LyrAccrualLog, LyrAccruedLog, MsrmFeeTierLog,
+    NativeTokenBalanceLog, NodeBankHealthLog, OpenOrdersBalanceLog, OpenOrdersSummaryLog,
+    PerpBankruptcyLog, PerpPnlLog, RedeemLyrLog, RemoveSpotMarketLog, SettleFeesLog, SettlePnlLog,
+    SimulatedLiquidationLog, SimulatedWithdrawLog, SpotFillLog, TokenBalanceLog,
+    TokenBankruptcyLog, TokenPositionFlipLog, UpdateFundingLog, UpdateRootBankLog, WithdrawLog,
 };
 
 use crate::error::{check_assert, LyraeError, LyraeErrorCode, LyraeResult, SourceFileId};
 use crate::ids::{msrm_token, srm_token};
 use crate::instruction::LyraeInstruction;
-use crate::matching::{Book, BookSide, OrderType, Side};
+use crate::matching::{Book, BookSide, OrderType, Side, MAX_BOOK_NODES};
 #[cfg(not(feature = "devnet"))]
 use crate::oracle::PriceStatus;
-use crate::oracle::{determine_oracle_type, OracleType, Price, StubOracle};
+use crate::oracle::{determine_oracle_type, OracleType, Price, StubOracle, SwitchboardV2Round};
 use crate::queue::{EventQueue, EventType, FillEvent, LiquidateEvent, OutEvent};
-#[cfg(not(feature = "devnet"))]
-use crate::state::PYTH_CONF_FILTER;
 use crate::state::{
     check_open_orders, load_asks_mut, load_bids_mut, load_market_state, load_open_orders,
-    load_open_orders_accounts, AdvancedOrderType, AdvancedOrders, AssetType, DataType, HealthCache,
-    HealthType, LyraeAccount, LyraeCache, LyraeGroup, MetaData, NodeBank, PerpMarket,
-    PerpMarketCache, PerpMarketInfo, PerpTriggerOrder, PriceCache, ReferrerIdRecord,
-    ReferrerMemory, RootBank, RootBankCache, SpotMarketInfo, TokenInfo, TriggerCondition,
-    UserActiveAssets, ADVANCED_ORDER_FEE, FREE_ORDER_SLOT, INFO_LEN, MAX_ADVANCED_ORDERS,
-    MAX_NODE_BANKS, MAX_PAIRS, MAX_PERP_OPEN_ORDERS, MAX_TOKENS, NEG_ONE_I80F48, ONE_I80F48,
-    QUOTE_INDEX, ZERO_I80F48,
+    load_open_orders_accounts, AccountGroup, AdvancedOrderType, AdvancedOrders, AssetType,
+    CancelTtl, DataType, HealthCache, HealthType, LyraeAccount, LyraeCache, LyraeGroup, MetaData,
+    MsrmFeeTiers, NodeBank, PerpAccount, PerpMarket, PerpMarketCache, PerpMarketInfo,
+    PerpTriggerOrder, PriceCache, ReferrerIdRecord, ReferrerMemory, RootBank, RootBankCache,
+    SpotMarketInfo, TokenInfo, TriggerCondition, UserActiveAssets, ADVANCED_ORDER_FEE,
+    BPS_PER_UNIT, DELEGATE_ALL, DELEGATE_CANCEL, DELEGATE_MANAGE, DELEGATE_PLACE, DELEGATE_TRIGGER,
+    FREE_ORDER_SLOT, GROUP_HALTED, GROUP_PAUSED, INFO_LEN, MAX_ADVANCED_ORDERS,
+    MAX_AUTO_SETTLE_ACCOUNTS, MAX_EXECUTE_TRIGGER_ORDERS, MAX_MSRM_FEE_TIERS, MAX_NODE_BANKS,
+    MAX_PAIRS, MAX_PERP_OPEN_ORDERS, MAX_REDEEM_LYR_ACCOUNTS, MAX_SETTLE_FEES_ACCOUNTS, MAX_TOKENS,
+    MAX_TOKEN_FEE_BPS, MAX_UPDATE_ALL_CACHES_ITEMS, NEG_ONE_I80F48, ONE_I80F48, QUOTE_INDEX,
+    ZERO_I80F48,
+};
+use crate::utils::{
+    emit_insurance_fund_balance, emit_perp_balances, gen_signer_key, gen_signer_seeds,
 };
-use crate::utils::{emit_perp_balances, gen_signer_key, gen_signer_seeds};
 
 declare_check_assert_macros!(SourceFileId::Processor);
 
@@ -151,7 +162,8 @@ impl Processor {
             mint: *quote_mint_ai.key,
             root_bank: *quote_root_bank_ai.key,
             decimals: mint.decimals,
-            padding: [0u8; 7],
+            min_liquidation_health_improvement: [0u8; 4],
+            padding: [0u8; 3],
         };
 
         check!(admin_ai.is_signer, LyraeErrorCode::Default)?;
@@ -174,9 +186,71 @@ impl Processor {
         Ok(())
     }
 
-    #[allow(unused)]
+    #[inline(never)]
+    /// Retire a spot market and its token. Only allowed once every node bank of the token is
+    /// empty, which means no LyraeAccount holds deposits or borrows of it anymore.
+    /// The oracle stays so that a perp market at the same index keeps working.
     fn remove_spot_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        todo!()
+        const NUM_FIXED: usize = 3;
+        let (fixed_ais, node_bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai, // write
+            admin_ai,       // read, signer
+            root_bank_ai,   // read
+        ] = fixed_ais;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(!lyrae_group.is_halted(), LyraeErrorCode::GroupHalted)?;
+
+        let market_index = lyrae_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+        check!(market_index != QUOTE_INDEX, LyraeErrorCode::InvalidParam)?;
+        check!(market_index < lyrae_group.num_oracles, LyraeErrorCode::InvalidParam)?;
+        check!(!lyrae_group.spot_markets[market_index].is_empty(), LyraeErrorCode::InvalidMarket)?;
+
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check_eq!(root_bank.num_node_banks, node_bank_ais.len(), LyraeErrorCode::InvalidNodeBank)?;
+        for i in 0..root_bank.num_node_banks {
+            let node_bank_ai = &node_bank_ais[i];
+            check!(node_bank_ai.key == &root_bank.node_banks[i], LyraeErrorCode::InvalidNodeBank)?;
+            let node_bank = NodeBank::load_checked(node_bank_ai, program_id)?;
+            check!(
+                node_bank.deposits.is_zero() && node_bank.borrows.is_zero(),
+                LyraeErrorCode::InvalidAccountState
+            )?;
+        }
+
+        if lyrae_group.tokens[market_index].mint == srm_token::ID {
+            lyrae_group.srm_vault = Pubkey::default();
+        }
+
+        // A perp market at this index still needs the decimals
+        let decimals = lyrae_group.tokens[market_index].decimals;
+        lyrae_group.tokens[market_index] = TokenInfo {
+            mint: Pubkey::default(),
+            root_bank: Pubkey::default(),
+            decimals: if lyrae_group.perp_markets[market_index].is_empty() { 0 } else { decimals },
+            min_liquidation_health_improvement: [0u8; 4],
+            padding: [0u8; 3],
+        };
+        lyrae_group.spot_markets[market_index] = SpotMarketInfo {
+            spot_market: Pubkey::default(),
+            maint_asset_weight: ZERO_I80F48,
+            init_asset_weight: ZERO_I80F48,
+            maint_liab_weight: ZERO_I80F48,
+            init_liab_weight: ZERO_I80F48,
+            liquidation_fee: ZERO_I80F48,
+        };
+
+        lyrae_emit!(RemoveSpotMarketLog {
+            lyrae_group: *lyrae_group_ai.key,
+            market_index: market_index as u64,
+        });
+
+        Ok(())
     }
     #[inline(never)]
     /// DEPRECATED - if you use this instruction after v3.3.0 you will not be able to close your LyraeAccount
@@ -279,6 +353,82 @@ impl Processor {
         lyrae_account.in_margin_basket = [false; MAX_PAIRS];
         lyrae_account.info = [0; INFO_LEN];
 
+        lyrae_emit!(CloseLyraeAccountLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            owner: *owner_ai.key,
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Cancel perp orders, close AdvancedOrders and resolve dust so the account can be closed
+    fn prepare_account_close(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        num_perp_markets: u8,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 6;
+        let (fixed_ais, rest_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // write, signer
+            lyrae_cache_ai,     // read
+            dust_account_ai,    // write
+            advanced_orders_ai, // write
+        ] = fixed_ais;
+
+        let num_perp_ais = 3 * num_perp_markets as usize;
+        check!(rest_ais.len() >= num_perp_ais, LyraeErrorCode::InvalidParam)?;
+        let (perp_ais, bank_ais) = rest_ais.split_at(num_perp_ais);
+        check!(bank_ais.len() % 2 == 0, LyraeErrorCode::InvalidParam)?;
+
+        // Closing is only for the owner, not the delegate
+        let has_advanced_orders = {
+            let lyrae_account =
+                LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+            check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+            check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+            lyrae_account.advanced_orders_key != Pubkey::default()
+        };
+
+        for market_ais in perp_ais.chunks_exact(3) {
+            let cancel_ais = [
+                lyrae_group_ai.clone(),
+                lyrae_account_ai.clone(),
+                owner_ai.clone(),
+                market_ais[0].clone(),
+                market_ais[1].clone(),
+                market_ais[2].clone(),
+            ];
+            Self::cancel_all_perp_orders(program_id, &cancel_ais, MAX_PERP_OPEN_ORDERS as u8)?;
+        }
+
+        if has_advanced_orders {
+            let close_ais = [
+                lyrae_group_ai.clone(),
+                lyrae_account_ai.clone(),
+                owner_ai.clone(),
+                advanced_orders_ai.clone(),
+            ];
+            Self::close_advanced_orders(program_id, &close_ais)?;
+        }
+
+        for token_ais in bank_ais.chunks_exact(2) {
+            let dust_ais = [
+                lyrae_group_ai.clone(),
+                lyrae_account_ai.clone(),
+                owner_ai.clone(),
+                dust_account_ai.clone(),
+                token_ais[0].clone(),
+                token_ais[1].clone(),
+                lyrae_cache_ai.clone(),
+            ];
+            Self::resolve_dust(program_id, &dust_ais)?;
+        }
+
         Ok(())
     }
 
@@ -300,7 +450,7 @@ impl Processor {
         let mut lyrae_account =
             LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_MANAGE),
             LyraeErrorCode::InvalidOwner
         )?;
         check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
@@ -320,13 +470,7 @@ impl Processor {
 
         let mut dust_account =
             LyraeAccount::load_mut_checked(dust_account_ai, program_id, &lyrae_group_ai.key)?;
-
-        // Check dust account
-        let (pda_address, _bump_seed) = Pubkey::find_program_address(
-            &[&lyrae_group_ai.key.as_ref(), b"DustAccount"],
-            program_id,
-        );
-        check!(&pda_address == dust_account_ai.key, LyraeErrorCode::InvalidAccount)?;
+        check_dust_account(program_id, lyrae_group_ai.key, dust_account_ai)?;
 
         // Find the node_bank pubkey in root_bank, if not found error
         let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
@@ -398,7 +542,6 @@ impl Processor {
     /// Initialize a root bank and add it to the lyrae group
     /// Requires a price oracle for this asset priced in quote currency
     /// Only allow admin to add to LyraeGroup
-    // TODO - implement remove asset
     fn add_spot_market(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
@@ -431,6 +574,7 @@ impl Processor {
         let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
         check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(!lyrae_group.is_halted(), LyraeErrorCode::GroupHalted)?;
 
         let market_index = lyrae_group.find_oracle_index(oracle_ai.key).ok_or(throw!())?;
 
@@ -472,7 +616,8 @@ impl Processor {
             mint: *mint_ai.key,
             root_bank: *root_bank_ai.key,
             decimals: mint.decimals,
-            padding: [0u8; 7],
+            min_liquidation_health_improvement: [0u8; 4],
+            padding: [0u8; 3],
         };
 
         let (maint_asset_weight, maint_liab_weight) = get_leverage_weights(maint_leverage);
@@ -486,6 +631,10 @@ impl Processor {
             init_liab_weight,
             liquidation_fee,
         };
+        check!(
+            lyrae_group.is_valid_min_liquidation_fee(lyrae_group.get_min_liquidation_fee()),
+            LyraeErrorCode::InvalidParam
+        )?;
 
         let spot_market = load_market_state(spot_market_ai, dex_program_ai.key)?;
 
@@ -526,6 +675,7 @@ impl Processor {
         let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
         check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(!lyrae_group.is_halted(), LyraeErrorCode::GroupHalted)?;
 
         let oracle_type = determine_oracle_type(oracle_ai);
         match oracle_type {
@@ -535,6 +685,9 @@ impl Processor {
             OracleType::Switchboard => {
                 msg!("OracleType::Switchboard");
             }
+            OracleType::SwitchboardV2 => {
+                msg!("OracleType::SwitchboardV2");
+            }
             OracleType::Stub | OracleType::Unknown => {
                 msg!("OracleType: got unknown or stub");
                 let rent = Rent::get()?;
@@ -551,7 +704,12 @@ impl Processor {
     }
 
     #[inline(never)]
-    fn set_oracle(program_id: &Pubkey, accounts: &[AccountInfo], price: I80F48) -> LyraeResult<()> {
+    fn set_oracle(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        price: I80F48,
+        last_update: u64,
+    ) -> LyraeResult<()> {
         const NUM_FIXED: usize = 3;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
@@ -568,10 +726,18 @@ impl Processor {
         let oracle_type = determine_oracle_type(oracle_ai);
         check_eq!(oracle_type, OracleType::Stub, LyraeErrorCode::Default)?;
 
+        // A non-positive price would break every division by price downstream
+        check!(price.is_positive(), LyraeErrorCode::InvalidOraclePrice)?;
+
         let mut oracle = StubOracle::load_mut_checked(oracle_ai, program_id)?;
         oracle.price = price;
-        let clock = Clock::get()?;
-        oracle.last_update = clock.unix_timestamp as u64;
+        oracle.last_update = if last_update == 0 {
+            Clock::get()?.unix_timestamp as u64
+        } else {
+            // An explicit timestamp could keep a price valid forever; only allow it for testing
+            check!(cfg!(feature = "devnet"), LyraeErrorCode::InvalidParam)?;
+            last_update
+        };
         Ok(())
     }
 
@@ -624,6 +790,7 @@ impl Processor {
 
         check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(!lyrae_group.is_halted(), LyraeErrorCode::GroupHalted)?;
 
         let market_index = lyrae_group.find_oracle_index(oracle_ai.key).ok_or(throw!())?;
 
@@ -654,6 +821,10 @@ impl Processor {
             base_lot_size,
             quote_lot_size,
         };
+        check!(
+            lyrae_group.is_valid_min_liquidation_fee(lyrae_group.get_min_liquidation_fee()),
+            LyraeErrorCode::InvalidParam
+        )?;
 
         // Initialize the Bids
         let _bids = BookSide::load_and_init(bids_ai, program_id, DataType::Bids, &rent)?;
@@ -758,6 +929,7 @@ impl Processor {
         check!(&lyrae_group.signer_key == signer_ai.key, LyraeErrorCode::InvalidSignerKey)?;
         check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(!lyrae_group.is_halted(), LyraeErrorCode::GroupHalted)?;
 
         let market_index = lyrae_group.find_oracle_index(oracle_ai.key).ok_or(throw!())?;
 
@@ -870,6 +1042,10 @@ impl Processor {
             base_lot_size,
             quote_lot_size,
         };
+        check!(
+            lyrae_group.is_valid_min_liquidation_fee(lyrae_group.get_min_liquidation_fee()),
+            LyraeErrorCode::InvalidParam
+        )?;
 
         Ok(())
     }
@@ -877,9 +1053,8 @@ impl Processor {
     #[inline(never)]
     /// Deposit instruction
     fn deposit(program_id: &Pubkey, accounts: &[AccountInfo], quantity: u64) -> LyraeResult<()> {
-        // TODO - consider putting update crank here
         const NUM_FIXED: usize = 9;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let (fixed_ais, opt_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
         lyrae_group_ai,         // read
         lyrae_account_ai,       // write
@@ -890,10 +1065,11 @@ impl Processor {
             vault_ai,               // write
             token_prog_ai,          // read
             owner_token_account_ai, // write
-        ] = accounts;
+        ] = fixed_ais;
         check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
         let mut lyrae_account =
             LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
 
@@ -902,25 +1078,79 @@ impl Processor {
 
         check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-
         let token_index = lyrae_group
             .find_root_bank_index(root_bank_ai.key)
             .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
 
+        // The fee vault is only passed if the RootBank charges a deposit fee, so the optional
+        // accounts start directly with the node banks when the first one is a NodeBank
+        let (fee_vault_ai, node_bank_ais) = {
+            let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+            match opt_ais.split_first() {
+                Some((ai, rest)) if !root_bank.node_banks.contains(ai.key) => (Some(ai), rest),
+                _ => (None, opt_ais),
+            }
+        };
+
+        // If all the node banks were passed, bring a stale root bank index up to date first so
+        // the deposit is converted with freshly accrued interest
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        if !node_bank_ais.is_empty() {
+            let mut lyrae_cache =
+                LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+            if lyrae_cache.root_bank_cache[token_index].last_update
+                < now_ts - lyrae_group.valid_interval
+            {
+                let mut root_bank = RootBank::load_mut_checked(root_bank_ai, program_id)?;
+                update_root_bank_and_cache(
+                    program_id,
+                    lyrae_group_ai.key,
+                    &mut lyrae_cache,
+                    token_index,
+                    &mut root_bank,
+                    node_bank_ais,
+                    now_ts,
+                )?;
+            }
+        }
+
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+
         // Find the node_bank pubkey in root_bank, if not found error
         let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
         check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
         let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
         check_eq!(&node_bank.vault, vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
+        // Divert the deposit fee, if any, straight from the owner to the fee vault
+        let fee = RootBank::get_fee(quantity, root_bank.deposit_fee_bps);
+        if fee > 0 {
+            let fee_vault_ai = fee_vault_ai.ok_or(throw_err!(LyraeErrorCode::InvalidVault))?;
+            check_token_fee_vault(&lyrae_group, token_index, fee_vault_ai)?;
+            invoke_transfer(
+                token_prog_ai,
+                owner_token_account_ai,
+                fee_vault_ai,
+                owner_ai,
+                &[],
+                fee,
+            )?;
+        }
+        let net_quantity = quantity - fee;
+
         // deposit into node bank token vault using invoke_transfer
-        invoke_transfer(token_prog_ai, owner_token_account_ai, vault_ai, owner_ai, &[], quantity)?;
+        invoke_transfer(
+            token_prog_ai,
+            owner_token_account_ai,
+            vault_ai,
+            owner_ai,
+            &[],
+            net_quantity,
+        )?;
 
         // Check validity of root bank cache
-        let now_ts = Clock::get()?.unix_timestamp as u64;
         let root_bank_cache = &lyrae_cache.root_bank_cache[token_index];
-        let deposit = I80F48::from_num(quantity);
+        let deposit = I80F48::from_num(net_quantity);
         root_bank_cache.check_valid(&lyrae_group, now_ts)?;
 
         checked_change_net(
@@ -932,12 +1162,15 @@ impl Processor {
             deposit,
         )?;
 
+        lyrae_account.set_last_activity_ts(now_ts);
+
         lyrae_emit!(DepositLog {
             lyrae_group: *lyrae_group_ai.key,
             lyrae_account: *lyrae_account_ai.key,
             owner: *owner_ai.key,
             token_index: token_index as u64,
             quantity,
+            fee,
         });
 
         Ok(())
@@ -992,8 +1225,11 @@ impl Processor {
         exp: Option<u8>,
         version: Option<u8>,
         lm_size_shift: Option<u8>,
+        max_order_price_deviation_bps: Option<u16>,
+        funding_impact_notional: Option<u32>,
     ) -> LyraeResult<()> {
         const NUM_FIXED: usize = 3;
+        let bids_ai = accounts.get(NUM_FIXED); // write
         let accounts = array_ref![accounts, 0, NUM_FIXED];
 
         let [
@@ -1046,6 +1282,10 @@ impl Processor {
         info.init_asset_weight = init_asset_weight;
         info.maint_liab_weight = maint_liab_weight;
         info.init_liab_weight = init_liab_weight;
+        check!(
+            lyrae_group.is_valid_min_liquidation_fee(lyrae_group.get_min_liquidation_fee()),
+            LyraeErrorCode::InvalidParam
+        )?;
 
         let version = version.unwrap_or(perp_market.meta_data.version);
         check!(version == 0 || version == 1, LyraeErrorCode::InvalidParam)?;
@@ -1120,6 +1360,15 @@ impl Processor {
         }
 
         perp_market.meta_data.version = version;
+        if let Some(bps) = max_order_price_deviation_bps {
+            perp_market.set_max_order_price_deviation_bps(bps);
+        }
+        if let Some(notional) = funding_impact_notional {
+            let bids_ai = bids_ai.ok_or(throw_err!(LyraeErrorCode::InvalidAccount))?;
+            check!(bids_ai.key == &perp_market.bids, LyraeErrorCode::InvalidAccount)?;
+            let mut bids = BookSide::load_mut_checked(bids_ai, program_id, &perp_market)?;
+            bids.set_funding_impact_notional(notional);
+        }
         Ok(())
     }
 
@@ -1143,7 +1392,7 @@ impl Processor {
         for oracle_ai in oracle_ais.iter() {
             let oracle_index = lyrae_group.find_oracle_index(oracle_ai.key).ok_or(throw!())?;
 
-            if let Ok(price) = read_oracle(&lyrae_group, oracle_index, oracle_ai) {
+            if let Ok(price) = read_oracle(&lyrae_group, oracle_index, oracle_ai, &clock) {
                 lyrae_cache.price_cache[oracle_index] = PriceCache { price, last_update };
 
                 oracle_indexes.push(oracle_index as u64);
@@ -1249,15 +1498,25 @@ impl Processor {
 
     #[inline(never)]
     /// Withdraw a token from the bank if collateral ratio permits
+    /// With close_dust, the group's DustAccount is passed last, after the fee vault, and any
+    /// sub-native remainder left in the deposit is swept into it so the deposit ends up at zero
     fn withdraw(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         quantity: u64,
         allow_borrow: bool,
+        close_dust: bool,
     ) -> LyraeResult<()> {
         const NUM_FIXED: usize = 10;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let (fixed_ais, open_orders_ais, opt_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        // The DustAccount is only passed with close_dust and always comes last, so the fee vault
+        // is the first optional account either way
+        let (dust_account_ai, opt_ais) = match opt_ais.split_last() {
+            Some((ai, rest)) if close_dust => (Some(ai), rest),
+            _ => (None, opt_ais),
+        };
+        let fee_vault_ai = opt_ais.first(); // write
         let [
         lyrae_group_ai,     // read
         lyrae_account_ai,   // write
@@ -1273,6 +1532,8 @@ impl Processor {
         check_eq!(&spl_token::ID, token_prog_ai.key, LyraeErrorCode::InvalidProgramId)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        // Withdrawals that only draw down deposits stay possible while the group is paused
+        check!(!(allow_borrow && lyrae_group.is_paused()), LyraeErrorCode::GroupPaused)?;
         check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
         let mut lyrae_account =
@@ -1325,15 +1586,54 @@ impl Processor {
             -withdraw,
         )?;
 
+        // The withdraw fee, if any, is paid out of the withdrawn quantity
+        let fee = RootBank::get_fee(quantity, root_bank.withdraw_fee_bps);
         let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        if fee > 0 {
+            let fee_vault_ai = fee_vault_ai.ok_or(throw_err!(LyraeErrorCode::InvalidVault))?;
+            check_token_fee_vault(&lyrae_group, token_index, fee_vault_ai)?;
+            invoke_transfer(
+                token_prog_ai,
+                vault_ai,
+                fee_vault_ai,
+                signer_ai,
+                &[&signers_seeds],
+                fee,
+            )?;
+        }
         invoke_transfer(
             token_prog_ai,
             vault_ai,
             token_account_ai,
             signer_ai,
             &[&signers_seeds],
-            quantity,
-        )?;
+            quantity - fee,
+        )?;
+
+        // Sweep the fractional remainder to the DustAccount like resolve_dust
+        if close_dust {
+            let dust_account_ai =
+                dust_account_ai.ok_or(throw_err!(LyraeErrorCode::InvalidAccount))?;
+            let remaining = lyrae_account.get_native_deposit(root_bank_cache, token_index)?;
+            if remaining > ZERO_I80F48 && remaining < ONE_I80F48 {
+                check_dust_account(program_id, lyrae_group_ai.key, dust_account_ai)?;
+                let mut dust_account = LyraeAccount::load_mut_checked(
+                    dust_account_ai,
+                    program_id,
+                    &lyrae_group_ai.key,
+                )?;
+                transfer_token_internal(
+                    root_bank_cache,
+                    &mut node_bank,
+                    &mut lyrae_account,
+                    &mut dust_account,
+                    lyrae_account_ai.key,
+                    dust_account_ai.key,
+                    token_index,
+                    remaining,
+                )?;
+            }
+        }
 
         let mut health_cache = HealthCache::new(active_assets);
         health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
@@ -1343,6 +1643,7 @@ impl Processor {
 
         // If health is above Init then being liquidated should be false anyway
         lyrae_account.being_liquidated = false;
+        lyrae_account.set_last_activity_ts(now_ts);
 
         lyrae_emit!(WithdrawLog {
             lyrae_group: *lyrae_group_ai.key,
@@ -1350,1750 +1651,1898 @@ impl Processor {
             owner: *owner_ai.key,
             token_index: token_index as u64,
             quantity,
+            fee,
         });
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Call the init_open_orders instruction in serum dex and add this OpenOrders account to margin account
-    fn init_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 8;
+    /// Compare a NodeBank's recorded deposits and borrows against the actual vault balance
+    fn log_node_bank_health(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 5;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-        lyrae_group_ai,         // read
-        lyrae_account_ai,       // write
-            owner_ai,               // read
-            dex_prog_ai,            // read
-            open_orders_ai,         // write
-            spot_market_ai,         // read
-            signer_ai,              // read
-            rent_ai,                // read
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            root_bank_ai,       // read
+            node_bank_ai,       // read
+            vault_ai,           // read
         ] = accounts;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
-        check!(&lyrae_group.signer_key == signer_ai.key, LyraeErrorCode::InvalidSignerKey)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let token_index = lyrae_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
 
-        let market_index = lyrae_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
+        let node_bank = NodeBank::load_checked(node_bank_ai, program_id)?;
+        check_eq!(&node_bank.vault, vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        let root_bank_cache = &lyrae_cache.root_bank_cache[token_index];
+        let native_deposits = node_bank.deposits * root_bank_cache.deposit_index;
+        let native_borrows = node_bank.borrows * root_bank_cache.borrow_index;
+        let vault_amount = Account::unpack(&vault_ai.try_borrow_data()?)?.amount;
+        let difference = I80F48::from_num(vault_amount) - (native_deposits - native_borrows);
 
-        {
-            let open_orders = load_open_orders(open_orders_ai)?;
+        lyrae_emit!(NodeBankHealthLog {
+            lyrae_group: *lyrae_group_ai.key,
+            node_bank: *node_bank_ai.key,
+            token_index: token_index as u64,
+            native_deposits: native_deposits.to_bits(),
+            native_borrows: native_borrows.to_bits(),
+            vault_amount,
+            difference: difference.to_bits(),
+        });
 
-            // Make sure this open orders account has not been initialized already
-            check_eq!(open_orders.account_flags, 0, LyraeErrorCode::Default)?;
-        }
+        Ok(())
+    }
 
-        // Make sure there isn't already an open orders account for this market
-        check!(
-            lyrae_account.spot_open_orders[market_index] == Pubkey::default(),
-            LyraeErrorCode::Default
-        )?;
+    #[inline(never)]
+    /// Log every active order in a LyraeAccount's AdvancedOrders
+    fn log_advanced_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            advanced_orders_ai, // read
+        ] = accounts;
 
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_init_open_orders(
-            dex_prog_ai,
-            open_orders_ai,
-            signer_ai,
-            spot_market_ai,
-            rent_ai,
-            &[&signers_seeds],
-        )?;
+        let _lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let advanced_orders =
+            AdvancedOrders::load_checked(advanced_orders_ai, program_id, &lyrae_account)?;
 
-        lyrae_account.spot_open_orders[market_index] = *open_orders_ai.key;
+        for i in 0..MAX_ADVANCED_ORDERS {
+            let order = &advanced_orders.orders[i];
+            if !order.is_active || order.advanced_order_type != AdvancedOrderType::PerpTrigger {
+                continue;
+            }
+            let order: &PerpTriggerOrder = cast_ref(order);
+            lyrae_emit!(AdvancedOrderLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                order_index: i as u64,
+                market_index: order.market_index as u64,
+                order_type: order.order_type.into(),
+                side: order.side.into(),
+                trigger_condition: order.trigger_condition.into(),
+                reduce_only: order.reduce_only,
+                trigger_price: order.trigger_price.to_bits(),
+                price: order.price,
+                quantity: order.quantity,
+                client_order_id: order.client_order_id,
+                trail_offset: order.trail_offset.to_bits(),
+            });
+        }
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Create a new OpenOrders PDA then
-    /// Call the init_open_orders instruction in serum dex and add this OpenOrders account to margin account
-    fn create_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 8;
-        let fixed_accounts = array_ref![accounts, 0, NUM_FIXED];
+    /// Log the pnl that settle_pnl would realize for a perp position at the cached price
+    fn log_perp_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-        lyrae_group_ai,         // read
-        lyrae_account_ai,       // write
-            owner_ai,               // read (write if no payer passed) & signer
-            dex_prog_ai,            // read
-            open_orders_ai,         // write
-            spot_market_ai,         // read
-            signer_ai,              // read
-            system_prog_ai,         // read
-        ] = fixed_accounts;
-        let payer_ai = if accounts.len() > NUM_FIXED {
-            &accounts[NUM_FIXED] // write & signer
-        } else {
-            owner_ai
-        };
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
-        )?;
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // read
+        ] = accounts;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
-        check!(&lyrae_group.signer_key == signer_ai.key, LyraeErrorCode::InvalidSignerKey)?;
-
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
         let market_index = lyrae_group
-            .find_spot_market_index(spot_market_ai.key)
+            .find_perp_market_index(perp_market_ai.key)
             .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(payer_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let price_cache = &lyrae_cache.price_cache[market_index];
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
+        price_cache.check_valid(&lyrae_group, now_ts)?;
+        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
 
-        let open_orders_seeds: &[&[u8]] =
-            &[&lyrae_account_ai.key.as_ref(), &market_index.to_le_bytes(), b"OpenOrders"];
-        seed_and_create_pda(
-            program_id,
-            payer_ai,
-            &Rent::get()?,
-            size_of::<serum_dex::state::OpenOrders>() + 12,
-            dex_prog_ai.key,
-            system_prog_ai,
-            open_orders_ai,
-            open_orders_seeds,
-            &[],
-        )?;
+        let price = price_cache.price;
+        let pa = &lyrae_account.perp_accounts[market_index];
+        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
+        let quote_position = pa.get_quote_position(perp_market_cache);
+        let new_quote_pos = I80F48::from_num(-pa.base_position * contract_size) * price;
+        let unrealized_pnl = quote_position - new_quote_pos;
 
-        {
-            let open_orders = load_open_orders(open_orders_ai)?;
+        lyrae_emit!(PerpPnlLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_index: market_index as u64,
+            base_position: pa.base_position,
+            quote_position: quote_position.to_bits(),
+            price: price.to_bits(),
+            unrealized_pnl: unrealized_pnl.to_bits(),
+        });
 
-            // Make sure this open orders account has not been initialized already
-            check_eq!(open_orders.account_flags, 0, LyraeErrorCode::Default)?;
-        }
+        Ok(())
+    }
 
-        // Make sure there isn't already an open orders account for this market
-        check!(
-            lyrae_account.spot_open_orders[market_index] == Pubkey::default(),
-            LyraeErrorCode::Default
-        )?;
+    #[inline(never)]
+    /// Log which configured oracles, root banks and perp markets have never been cached
+    fn log_cache_consistency(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+        ] = accounts;
 
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_init_open_orders(
-            dex_prog_ai,
-            open_orders_ai,
-            signer_ai,
-            spot_market_ai,
-            system_prog_ai, // no need to send in rent ai
-            &[&signers_seeds],
-        )?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
 
-        lyrae_account.spot_open_orders[market_index] = *open_orders_ai.key;
+        let uncached_price_indexes: Vec<u64> = (0..lyrae_group.num_oracles)
+            .filter(|&i| lyrae_cache.price_cache[i].last_update == 0)
+            .map(|i| i as u64)
+            .collect();
+        let uncached_root_bank_indexes: Vec<u64> = (0..MAX_TOKENS)
+            .filter(|&i| {
+                !lyrae_group.tokens[i].is_empty()
+                    && lyrae_cache.root_bank_cache[i].last_update == 0
+            })
+            .map(|i| i as u64)
+            .collect();
+        let uncached_perp_market_indexes: Vec<u64> = (0..MAX_PAIRS)
+            .filter(|&i| {
+                !lyrae_group.perp_markets[i].is_empty()
+                    && lyrae_cache.perp_market_cache[i].last_update == 0
+            })
+            .map(|i| i as u64)
+            .collect();
+
+        lyrae_emit!(CacheConsistencyLog {
+            lyrae_group: *lyrae_group_ai.key,
+            num_oracles: lyrae_group.num_oracles as u64,
+            uncached_price_indexes,
+            uncached_root_bank_indexes,
+            uncached_perp_market_indexes,
+        });
 
         Ok(())
     }
 
     #[inline(never)]
-    fn close_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 7;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-
+    /// Log the deposits, borrows and vault balances of the passed tokens and their total value
+    fn log_group_tvl(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-        lyrae_group_ai,     // read
-        lyrae_account_ai,   // write
-            owner_ai,           // write, signer
-            dex_prog_ai,        // read
-            open_orders_ai,     // write
-            spot_market_ai,     // read
-            signer_ai,          // read
-        ] = accounts;
-
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+        ] = fixed_ais;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
-        check_eq!(signer_ai.key, &lyrae_group.signer_key, LyraeErrorCode::InvalidParam)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-        let market_index = lyrae_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let mut token_indexes = vec![];
+        let mut native_deposits = vec![];
+        let mut native_borrows = vec![];
+        let mut vault_amounts = vec![];
+        let mut total_deposits_value = ZERO_I80F48;
+
+        let mut i = 0;
+        while i < bank_ais.len() {
+            let root_bank_ai = &bank_ais[i];
+            let token_index = lyrae_group
+                .find_root_bank_index(root_bank_ai.key)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+            check!(!token_indexes.contains(&(token_index as u64)), LyraeErrorCode::InvalidParam)?;
+            let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+            let num_node_banks = root_bank.num_node_banks;
+            check!(bank_ais.len() > i + 2 * num_node_banks, LyraeErrorCode::InvalidAccount)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
-        check_eq!(&lyrae_account.owner, owner_ai.key, LyraeErrorCode::InvalidOwner)?;
-        check!(!lyrae_account.being_liquidated, LyraeErrorCode::BeingLiquidated)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check_eq!(
-            &lyrae_account.spot_open_orders[market_index],
-            open_orders_ai.key,
-            LyraeErrorCode::InvalidOpenOrdersAccount
-        )?;
+            let root_bank_cache = &lyrae_cache.root_bank_cache[token_index];
+            root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+            if token_index != QUOTE_INDEX {
+                lyrae_cache.price_cache[token_index].check_valid(&lyrae_group, now_ts)?;
+            }
 
-        if lyrae_account.in_margin_basket[market_index] {
-            let open_orders = load_open_orders(open_orders_ai)?;
-            lyrae_account.update_basket(market_index, &open_orders)?;
-            check!(
-                !lyrae_account.in_margin_basket[market_index],
-                LyraeErrorCode::InvalidAccountState
-            )?;
+            let mut deposits = ZERO_I80F48;
+            let mut borrows = ZERO_I80F48;
+            let mut vault_amount = 0u64;
+            for (j, node_bank_pk) in root_bank.node_banks[..num_node_banks].iter().enumerate() {
+                let node_bank_ai = &bank_ais[i + 1 + 2 * j];
+                let vault_ai = &bank_ais[i + 2 + 2 * j];
+                check_eq!(node_bank_ai.key, node_bank_pk, LyraeErrorCode::InvalidNodeBank)?;
+                let node_bank = NodeBank::load_checked(node_bank_ai, program_id)?;
+                check_eq!(&node_bank.vault, vault_ai.key, LyraeErrorCode::InvalidVault)?;
+
+                deposits += node_bank.deposits * root_bank_cache.deposit_index;
+                borrows += node_bank.borrows * root_bank_cache.borrow_index;
+                vault_amount = vault_amount
+                    .checked_add(Account::unpack(&vault_ai.try_borrow_data()?)?.amount)
+                    .ok_or(math_err!())?;
+            }
+
+            total_deposits_value += deposits * lyrae_cache.get_price(token_index);
+            token_indexes.push(token_index as u64);
+            native_deposits.push(deposits.to_bits());
+            native_borrows.push(borrows.to_bits());
+            vault_amounts.push(vault_amount);
+            i += 1 + 2 * num_node_banks;
         }
 
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_close_open_orders(
-            dex_prog_ai,
-            open_orders_ai,
-            signer_ai,
-            owner_ai,
-            spot_market_ai,
-            &[&signers_seeds],
-        )?;
+        lyrae_emit!(GroupTvlLog {
+            lyrae_group: *lyrae_group_ai.key,
+            token_indexes,
+            native_deposits,
+            native_borrows,
+            vault_amounts,
+            total_deposits_value: total_deposits_value.to_bits(),
+        });
 
-        lyrae_account.spot_open_orders[market_index] = Pubkey::default();
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log the native deposit and borrow of every token a LyraeAccount holds or owes
+    fn log_token_balances(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            lyrae_cache_ai,     // read
+        ] = accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        for i in 0..MAX_TOKENS {
+            if lyrae_group.tokens[i].is_empty()
+                || (lyrae_account.deposits[i].is_zero() && lyrae_account.borrows[i].is_zero())
+            {
+                continue;
+            }
+            let root_bank_cache = &lyrae_cache.root_bank_cache[i];
+            root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+
+            lyrae_emit!(NativeTokenBalanceLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                token_index: i as u64,
+                native_deposit: lyrae_account.get_native_deposit(root_bank_cache, i)?.to_bits(),
+                native_borrow: lyrae_account.get_native_borrow(root_bank_cache, i)?.to_bits(),
+            });
+        }
 
         Ok(())
     }
 
     #[inline(never)]
-    /// DEPRECATED
-    fn place_spot_order(
+    /// Log how many markets a health check of a LyraeAccount values, which drives its compute cost
+    fn log_health_compute_estimate(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order: serum_dex::instruction::NewOrderInstructionV3,
     ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 23;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+        ] = accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+
+        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
+        let mut num_spot_markets = 0;
+        let mut num_open_orders = 0;
+        let mut num_perp_markets = 0;
+        for i in 0..lyrae_group.num_oracles {
+            if active_assets.spot[i] {
+                num_spot_markets += 1;
+                // Same condition as HealthCache::init_vals for loading the open orders
+                if lyrae_account.in_margin_basket[i]
+                    && lyrae_account.spot_open_orders[i] != Pubkey::default()
+                {
+                    num_open_orders += 1;
+                }
+            }
+            if active_assets.perps[i] {
+                num_perp_markets += 1;
+            }
+        }
+
+        lyrae_emit!(HealthComputeEstimateLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            num_spot_markets,
+            num_open_orders,
+            num_perp_markets,
+        });
+
+        Ok(())
+    }
 
+    #[inline(never)]
+    /// Cancel up to `limit` resting spot orders of a LyraeAccount in each of the passed markets
+    fn cancel_all_spot_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 5;
+        let (fixed_ais, market_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-        lyrae_group_ai,         // read
-        lyrae_account_ai,       // write
-            owner_ai,               // read & signer
-        lyrae_cache_ai,         // read
-            dex_prog_ai,            // read
-            spot_market_ai,         // write
-            bids_ai,                // write
-            asks_ai,                // write
-            dex_request_queue_ai,   // write
-            dex_event_queue_ai,     // write
-            dex_base_ai,            // write
-            dex_quote_ai,           // write
-            base_root_bank_ai,      // read
-            base_node_bank_ai,      // write
-            base_vault_ai,          // write
-            quote_root_bank_ai,     // read
-            quote_node_bank_ai,     // write
-            quote_vault_ai,         // write
-            token_prog_ai,          // read
-            signer_ai,              // read
-            _rent_ai,               // read
-            dex_signer_ai,          // read
-            msrm_or_srm_vault_ai,   // read
+            lyrae_group_ai,     // read
+            owner_ai,           // read, signer
+            lyrae_account_ai,   // write
+            dex_prog_ai,        // read
+            signer_ai,          // read
         ] = fixed_ais;
-
-        // TODO OPT - reduce size of this transaction
-        // put bank info into group +64 bytes (can't do this now)
-        // remove settle_funds +64 bytes (can't do this for UX reasons)
-        // ask serum dex to use dynamic sysvars +32 bytes
-        // only send in open orders pubkeys we need +38 bytes
-        // shrink size of order instruction +10 bytes
+        check!(market_ais.len() % 5 == 0, LyraeErrorCode::InvalidParam)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
         check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
         check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
         let mut lyrae_account =
             LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_CANCEL),
             LyraeErrorCode::InvalidOwner
         )?;
         check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
         check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        for market_ais in market_ais.chunks_exact(5) {
+            let [spot_market_ai, bids_ai, asks_ai, open_orders_ai, dex_event_queue_ai] =
+                array_ref![market_ais, 0, 5];
+            let market_index = lyrae_group
+                .find_spot_market_index(spot_market_ai.key)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+            check_eq!(
+                &lyrae_account.spot_open_orders[market_index],
+                open_orders_ai.key,
+                LyraeErrorCode::InvalidOpenOrdersAccount
+            )?;
+            if !lyrae_account.in_margin_basket[market_index] {
+                continue;
+            }
+            check_open_orders(
+                open_orders_ai,
+                &lyrae_group.signer_key,
+                &lyrae_group.dex_program_id,
+            )?;
 
-        let market_index = lyrae_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+            let has_orders = load_open_orders(open_orders_ai)?.free_slot_bits != u128::MAX;
+            if !has_orders {
+                continue;
+            }
 
-        check!(
-            &lyrae_group.tokens[market_index].root_bank == base_root_bank_ai.key,
-            LyraeErrorCode::InvalidRootBank
-        )?;
-        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
+            invoke_cancel_orders(
+                open_orders_ai,
+                dex_prog_ai,
+                spot_market_ai,
+                bids_ai,
+                asks_ai,
+                signer_ai,
+                dex_event_queue_ai,
+                &[&signers_seeds],
+                limit,
+            )?;
 
-        check!(
-            base_root_bank.node_banks.contains(base_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
-        )?;
-        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
-
-        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
-
-        check!(
-            &lyrae_group.tokens[QUOTE_INDEX].root_bank == quote_root_bank_ai.key,
-            LyraeErrorCode::InvalidRootBank
-        )?;
-        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
-
-        check!(
-            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
-        )?;
-        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
-        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+            let open_orders = load_open_orders(open_orders_ai)?;
+            lyrae_account.update_basket(market_index, &open_orders)?;
 
-        // Fix the margin basket incase there are empty ones; main benefit is freeing up basket space
-        for i in 0..lyrae_group.num_oracles {
-            if lyrae_account.in_margin_basket[i] {
-                let open_orders = load_open_orders(&open_orders_ais[i])?;
-                lyrae_account.update_basket(i, &open_orders)?;
-            }
+            lyrae_emit!(OpenOrdersBalanceLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                base_total: open_orders.native_coin_total,
+                base_free: open_orders.native_coin_free,
+                quote_total: open_orders.native_pc_total,
+                quote_free: open_orders.native_pc_free,
+                referrer_rebates_accrued: open_orders.referrer_rebates_accrued
+            });
         }
 
-        // Adjust margin basket; this also makes this market an active asset
-        lyrae_account.add_to_basket(market_index)?;
-        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+        Ok(())
+    }
 
-        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+    #[inline(never)]
+    /// Log the accrued but unredeemed LYR of a LyraeAccount in each perp market
+    fn log_lyr_accrued(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+        ] = accounts;
 
-        let mut health_cache = HealthCache::new(active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
-        let pre_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
 
-        // update the being_liquidated flag
-        if lyrae_account.being_liquidated {
-            if pre_health >= ZERO_I80F48 {
-                lyrae_account.being_liquidated = false;
-            } else {
-                return Err(throw_err!(LyraeErrorCode::BeingLiquidated));
+        let mut market_indexes = vec![];
+        let mut lyr_accrued = vec![];
+        let mut total_lyr_accrued = 0u64;
+        for i in 0..lyrae_group.num_oracles {
+            let accrued = lyrae_account.perp_accounts[i].lyr_accrued;
+            if lyrae_group.perp_markets[i].is_empty() || accrued == 0 {
+                continue;
             }
+            market_indexes.push(i as u64);
+            lyr_accrued.push(accrued);
+            total_lyr_accrued = total_lyr_accrued.checked_add(accrued).ok_or(math_err!())?;
         }
 
-        // This means health must only go up
-        let reduce_only = pre_health < ZERO_I80F48;
-
-        // TODO maybe check that root bank was updated recently
-        // TODO maybe check oracle was updated recently
-
-        // TODO OPT - write a zero copy way to deserialize Account to reduce compute
-
-        // this is to keep track of the amount of funds transferred
-        let (pre_base, pre_quote) = {
-            (
-                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
-                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
-            )
-        };
-
-        let order_side = order.side;
-        let vault_ai = match order_side {
-            serum_dex::matching::Side::Bid => quote_vault_ai,
-            serum_dex::matching::Side::Ask => base_vault_ai,
-        };
-
-        // Enforce order price limits if the order is a limit order that goes on the book
-        let native_price = {
-            let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
-
-            I80F48::from_num(order.limit_price.get())
-                .checked_mul(I80F48::from_num(market.pc_lot_size))
-                .unwrap()
-                .checked_div(I80F48::from_num(market.coin_lot_size))
-                .unwrap()
-        };
-        let oracle_price = lyrae_cache.get_price(market_index);
-        let info = &lyrae_group.spot_markets[market_index];
-
-        // If not post_allowed, then pre_locked may not increase
-        let (post_allowed, pre_locked) = {
-            let open_orders = load_open_orders(&open_orders_ais[market_index])?;
-            match order_side {
-                serum_dex::matching::Side::Bid => (
-                    native_price.checked_div(oracle_price).unwrap() <= info.maint_liab_weight,
-                    open_orders.native_pc_total - open_orders.native_pc_free,
-                ),
-                serum_dex::matching::Side::Ask => (
-                    native_price.checked_div(oracle_price).unwrap() >= info.maint_asset_weight,
-                    open_orders.native_coin_total - open_orders.native_coin_free,
-                ),
-            }
-        };
+        lyrae_emit!(LyrAccruedLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_indexes,
+            lyr_accrued,
+            total_lyr_accrued,
+        });
 
-        // Send order to serum dex
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_new_order(
-            dex_prog_ai,
-            spot_market_ai,
-            &open_orders_ais[market_index],
-            dex_request_queue_ai,
-            dex_event_queue_ai,
-            bids_ai,
-            asks_ai,
-            vault_ai,
-            signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            token_prog_ai,
-            msrm_or_srm_vault_ai,
-            &[&signers_seeds],
-            order,
-        )?;
+        Ok(())
+    }
 
-        // Settle funds for this market
-        invoke_settle_funds(
-            dex_prog_ai,
-            spot_market_ai,
-            &open_orders_ais[market_index],
-            signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            base_vault_ai,
-            quote_vault_ai,
-            dex_signer_ai,
-            token_prog_ai,
-            &[&signers_seeds],
-        )?;
+    #[inline(never)]
+    /// Log the resting perp orders of a LyraeAccount in each of the passed markets
+    fn log_all_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+        ] = fixed_ais;
+        check!(market_ais.len() % 3 == 0, LyraeErrorCode::InvalidParam)?;
 
-        // See if we can remove this market from margin
-        let open_orders = load_open_orders(&open_orders_ais[market_index])?;
-        lyrae_account.update_basket(market_index, &open_orders)?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
 
-        let post_locked = match order_side {
-            serum_dex::matching::Side::Bid => {
-                open_orders.native_pc_total - open_orders.native_pc_free
-            }
-            serum_dex::matching::Side::Ask => {
-                open_orders.native_coin_total - open_orders.native_coin_free
+        for market_ais in market_ais.chunks_exact(3) {
+            let [perp_market_ai, bids_ai, asks_ai] = array_ref![market_ais, 0, 3];
+            let market_index = lyrae_group
+                .find_perp_market_index(perp_market_ai.key)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+            let perp_market =
+                PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+            let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+
+            let tracked_ids: Vec<i128> = (0..MAX_PERP_OPEN_ORDERS)
+                .filter(|&i| lyrae_account.order_market[i] == market_index as u8)
+                .map(|i| lyrae_account.orders[i])
+                .collect();
+
+            let mut order_ids = vec![];
+            let mut client_order_ids = vec![];
+            let mut sides = vec![];
+            let mut prices = vec![];
+            let mut quantities = vec![];
+            for (side, book_side) in [(Side::Bid, &book.bids), (Side::Ask, &book.asks)].iter() {
+                for leaf in book_side.iter() {
+                    if &leaf.owner == lyrae_account_ai.key && tracked_ids.contains(&leaf.key) {
+                        order_ids.push(leaf.key);
+                        client_order_ids.push(leaf.client_order_id);
+                        sides.push(*side as u8);
+                        prices.push(leaf.price());
+                        quantities.push(leaf.quantity);
+                    }
+                }
             }
-        };
-        check!(post_allowed || post_locked <= pre_locked, LyraeErrorCode::InvalidParam)?;
-        let (post_base, post_quote) = {
-            (
-                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
-                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
-            )
-        };
-
-        let quote_change = I80F48::from_num(post_quote) - I80F48::from_num(pre_quote);
-        let base_change = I80F48::from_num(post_base) - I80F48::from_num(pre_base);
 
-        checked_change_net(
-            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
-            &mut quote_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            QUOTE_INDEX,
-            quote_change,
-        )?;
+            lyrae_emit!(OpenOrdersSummaryLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                order_ids,
+                client_order_ids,
+                sides,
+                prices,
+                quantities,
+            });
+        }
 
-        checked_change_net(
-            &lyrae_cache.root_bank_cache[market_index],
-            &mut base_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            market_index,
-            base_change,
-        )?;
+        Ok(())
+    }
 
-        // Update health for tokens that may have changed
-        health_cache.update_quote(&lyrae_cache, &lyrae_account);
-        health_cache.update_spot_val(
-            &lyrae_group,
-            &lyrae_cache,
-            &lyrae_account,
-            &open_orders_ais[market_index],
-            market_index,
-        )?;
-        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+    #[inline(never)]
+    /// Log how much room is left on each side of a perp market's book
+    fn log_book_capacity(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            perp_market_ai,     // read
+            bids_ai,            // read
+            asks_ai,            // read
+        ] = accounts;
 
-        // If an account is in reduce_only mode, health must only go up
-        check!(
-            post_health >= ZERO_I80F48 || (reduce_only && post_health >= pre_health),
-            LyraeErrorCode::InsufficientFunds
-        )?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
 
-        lyrae_emit!(OpenOrdersBalanceLog {
+        let bids_used_nodes = book.bids.used_nodes();
+        let asks_used_nodes = book.asks.used_nodes();
+        lyrae_emit!(BookCapacityLog {
             lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
             market_index: market_index as u64,
-            base_total: open_orders.native_coin_total,
-            base_free: open_orders.native_coin_free,
-            quote_total: open_orders.native_pc_total,
-            quote_free: open_orders.native_pc_free,
-            referrer_rebates_accrued: open_orders.referrer_rebates_accrued
+            bids_leaf_count: book.bids.leaf_count as u64,
+            bids_used_nodes: bids_used_nodes as u64,
+            bids_free_nodes: (MAX_BOOK_NODES - bids_used_nodes) as u64,
+            asks_leaf_count: book.asks.leaf_count as u64,
+            asks_used_nodes: asks_used_nodes as u64,
+            asks_free_nodes: (MAX_BOOK_NODES - asks_used_nodes) as u64,
         });
 
         Ok(())
     }
 
     #[inline(never)]
-    fn place_spot_order2(
+    /// Compute the init health before and after a hypothetical withdraw without changing any state
+    fn simulate_withdraw(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order: serum_dex::instruction::NewOrderInstructionV3,
+        quantity: u64,
+        allow_borrow: bool,
     ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 22;
-        let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
-
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
         let [
-        lyrae_group_ai,         // read
-        lyrae_account_ai,       // write
-            owner_ai,               // read & signer
-        lyrae_cache_ai,         // read
-            dex_prog_ai,            // read
-            spot_market_ai,         // write
-            bids_ai,                // write
-            asks_ai,                // write
-            dex_request_queue_ai,   // write
-            dex_event_queue_ai,     // write
-            dex_base_ai,            // write
-            dex_quote_ai,           // write
-            base_root_bank_ai,      // read
-            base_node_bank_ai,      // write
-            base_vault_ai,          // write
-            quote_root_bank_ai,     // read
-            quote_node_bank_ai,     // write
-            quote_vault_ai,         // write
-            token_prog_ai,          // read
-            signer_ai,              // read
-            dex_signer_ai,          // read
-            msrm_or_srm_vault_ai,   // read
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            lyrae_cache_ai,     // read
+            root_bank_ai,       // read
         ] = fixed_ais;
 
-        // put bank info into group +64 bytes (can't do this now)
-        // remove settle_funds +64 bytes (can't do this for UX reasons)
-        // ask serum dex to use dynamic sysvars +31 bytes (done)
-        // only send in open orders pubkeys we need +38 bytes (done)
-        // shrink size of order instruction +10 bytes
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+
+        let token_index = lyrae_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidToken))?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &lyrae_account,
+            vec![(AssetType::Token, token_index)],
+        );
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+
+        let root_bank_cache = &lyrae_cache.root_bank_cache[token_index];
+        let native_deposit = lyrae_account.get_native_deposit(root_bank_cache, token_index)?;
+
+        // Same interpretation of quantity as in withdraw
+        let (withdraw, quantity) = if quantity == u64::MAX && !allow_borrow {
+            let floored = native_deposit.checked_floor().unwrap();
+            (floored, floored.to_num::<u64>())
+        } else {
+            (I80F48::from_num(quantity), quantity)
+        };
+        check!(native_deposit >= withdraw || allow_borrow, LyraeErrorCode::InsufficientFunds)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
+        let pre_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let post_health = health_cache.get_health_after_sim_token(
+            &lyrae_group,
+            &lyrae_cache,
+            &lyrae_account,
+            open_orders_ais,
+            token_index,
+            HealthType::Init,
+            -withdraw,
+        )?;
+
+        lyrae_emit!(SimulatedWithdrawLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            token_index: token_index as u64,
+            quantity,
+            allow_borrow,
+            pre_init_health: pre_health.to_bits(),
+            post_init_health: post_health.to_bits(),
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Call the init_open_orders instruction in serum dex and add this OpenOrders account to margin account
+    fn init_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 8;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+        lyrae_group_ai,         // read
+        lyrae_account_ai,       // write
+            owner_ai,               // read
+            dex_prog_ai,            // read
+            open_orders_ai,         // write
+            spot_market_ai,         // read
+            signer_ai,              // read
+            rent_ai,                // read
+        ] = accounts;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
         check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
-        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+        check!(&lyrae_group.signer_key == signer_ai.key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
         let mut lyrae_account =
             LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
         check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
         check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
+        {
+            let open_orders = load_open_orders(open_orders_ai)?;
 
-        let market_index = lyrae_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+            // Make sure this open orders account has not been initialized already
+            check_eq!(open_orders.account_flags, 0, LyraeErrorCode::Default)?;
+        }
 
+        // Make sure there isn't already an open orders account for this market
         check!(
-            &lyrae_group.tokens[market_index].root_bank == base_root_bank_ai.key,
-            LyraeErrorCode::InvalidRootBank
+            lyrae_account.spot_open_orders[market_index] == Pubkey::default(),
+            LyraeErrorCode::Default
         )?;
-        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
 
-        check!(
-            base_root_bank.node_banks.contains(base_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_init_open_orders(
+            dex_prog_ai,
+            open_orders_ai,
+            signer_ai,
+            spot_market_ai,
+            rent_ai,
+            &[&signers_seeds],
         )?;
-        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
 
-        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+        lyrae_account.spot_open_orders[market_index] = *open_orders_ai.key;
+
+        Ok(())
+    }
 
+    #[inline(never)]
+    /// Create a new OpenOrders PDA then
+    /// Call the init_open_orders instruction in serum dex and add this OpenOrders account to margin account
+    fn create_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 8;
+        let fixed_accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+        lyrae_group_ai,         // read
+        lyrae_account_ai,       // write
+            owner_ai,               // read (write if no payer passed) & signer
+            dex_prog_ai,            // read
+            open_orders_ai,         // write
+            spot_market_ai,         // read
+            signer_ai,              // read
+            system_prog_ai,         // read
+        ] = fixed_accounts;
+        let payer_ai = if accounts.len() > NUM_FIXED {
+            &accounts[NUM_FIXED] // write & signer
+        } else {
+            owner_ai
+        };
         check!(
-            &lyrae_group.tokens[QUOTE_INDEX].root_bank == quote_root_bank_ai.key,
-            LyraeErrorCode::InvalidRootBank
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
         )?;
-        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
 
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
+        check!(&lyrae_group.signer_key == signer_ai.key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
         check!(
-            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
+            LyraeErrorCode::InvalidOwner
         )?;
-        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
-        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(payer_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        let mut open_orders_ais =
-            lyrae_account.checked_unpack_open_orders(&lyrae_group, packed_open_orders_ais)?;
-        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
+        let open_orders_seeds: &[&[u8]] =
+            &[&lyrae_account_ai.key.as_ref(), &market_index.to_le_bytes(), b"OpenOrders"];
+        seed_and_create_pda(
+            program_id,
+            payer_ai,
+            &Rent::get()?,
+            size_of::<serum_dex::state::OpenOrders>() + 12,
+            dex_prog_ai.key,
+            system_prog_ai,
+            open_orders_ai,
+            open_orders_seeds,
+            &[],
+        )?;
 
-        // Fix the margin basket incase there are empty ones; main benefit is freeing up basket space
-        for i in 0..lyrae_group.num_oracles {
-            if lyrae_account.in_margin_basket[i] {
-                let open_orders = load_open_orders(open_orders_ais[i].unwrap())?;
-                lyrae_account.update_basket(i, &open_orders)?;
-            }
-        }
+        {
+            let open_orders = load_open_orders(open_orders_ai)?;
 
-        // Adjust margin basket; this also makes this market an active asset
-        lyrae_account.add_to_basket(market_index)?;
-        if open_orders_ais[market_index].is_none() {
-            open_orders_ais[market_index] = Some(lyrae_account.checked_unpack_open_orders_single(
-                &lyrae_group,
-                packed_open_orders_ais,
-                market_index,
-            )?);
+            // Make sure this open orders account has not been initialized already
+            check_eq!(open_orders.account_flags, 0, LyraeErrorCode::Default)?;
         }
 
-        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+        // Make sure there isn't already an open orders account for this market
+        check!(
+            lyrae_account.spot_open_orders[market_index] == Pubkey::default(),
+            LyraeErrorCode::Default
+        )?;
 
-        let mut health_cache = HealthCache::new(active_assets);
-        health_cache.init_vals_with_orders_vec(
-            &lyrae_group,
-            &lyrae_cache,
-            &lyrae_account,
-            &open_orders_accounts,
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_init_open_orders(
+            dex_prog_ai,
+            open_orders_ai,
+            signer_ai,
+            spot_market_ai,
+            system_prog_ai, // no need to send in rent ai
+            &[&signers_seeds],
         )?;
-        let pre_health = health_cache.get_health(&lyrae_group, HealthType::Init);
 
-        // update the being_liquidated flag
-        if lyrae_account.being_liquidated {
-            if pre_health >= ZERO_I80F48 {
-                lyrae_account.being_liquidated = false;
-            } else {
-                return Err(throw_err!(LyraeErrorCode::BeingLiquidated));
-            }
-        }
+        lyrae_account.spot_open_orders[market_index] = *open_orders_ai.key;
 
-        // This means health must only go up
-        let reduce_only = pre_health < ZERO_I80F48;
+        Ok(())
+    }
 
-        // TODO maybe check that root bank was updated recently
-        // TODO maybe check oracle was updated recently
+    #[inline(never)]
+    /// Create the spot OpenOrders PDA and deposit in one instruction to save a round trip when
+    /// onboarding a new spot trader
+    fn deposit_and_create_spot_open_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 15;
+        let (fixed_ais, deposit_opt_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let (deposit_ais, create_ais) = array_refs![fixed_ais, 9, 6];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // write
+            owner_ai,               // read & signer
+            _lyrae_cache_ai,        // read
+            _root_bank_ai,          // read
+            _node_bank_ai,          // write
+            _vault_ai,              // write
+            _token_prog_ai,         // read
+            _owner_token_account_ai, // write
+        ] = deposit_ais;
+        let [
+            dex_prog_ai,            // read
+            open_orders_ai,         // write
+            spot_market_ai,         // read
+            signer_ai,              // read
+            system_prog_ai,         // read
+            payer_ai,               // write & signer
+        ] = create_ais;
 
-        // TODO OPT - write a zero copy way to deserialize Account to reduce compute
-        // this is to keep track of the amount of funds transferred
-        let (pre_base, pre_quote) = {
-            (
-                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
-                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
-            )
-        };
-        let order_side = order.side;
-        let vault_ai = match order_side {
-            serum_dex::matching::Side::Bid => quote_vault_ai,
-            serum_dex::matching::Side::Ask => base_vault_ai,
-        };
-
-        // Enforce order price limits if the order is a limit order that goes on the book
-        let native_price = {
-            // Conver the price in
-            let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
-            I80F48::from_num(order.limit_price.get())
-                .checked_mul(I80F48::from_num(market.pc_lot_size))
-                .unwrap()
-                .checked_div(I80F48::from_num(market.coin_lot_size))
-                .unwrap()
-        };
-        let oracle_price = lyrae_cache.get_price(market_index);
-        let info = &lyrae_group.spot_markets[market_index];
-        let market_open_orders_ai = open_orders_ais[market_index].unwrap();
-
-        // If not post_allowed, then pre_locked may not increase
-        let (post_allowed, pre_locked) = {
-            let open_orders = load_open_orders(market_open_orders_ai)?;
-            match order_side {
-                serum_dex::matching::Side::Bid => (
-                    native_price.checked_div(oracle_price).unwrap() <= info.maint_liab_weight,
-                    open_orders.native_pc_total - open_orders.native_pc_free,
-                ),
-                serum_dex::matching::Side::Ask => (
-                    native_price.checked_div(oracle_price).unwrap() >= info.maint_asset_weight,
-                    open_orders.native_coin_total - open_orders.native_coin_free,
-                ),
-            }
-        };
-
-        // Send order to serum dex
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_new_order(
-            dex_prog_ai,
-            spot_market_ai,
-            market_open_orders_ai,
-            dex_request_queue_ai,
-            dex_event_queue_ai,
-            bids_ai,
-            asks_ai,
-            vault_ai,
-            signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            token_prog_ai,
-            msrm_or_srm_vault_ai,
-            &[&signers_seeds],
-            order,
-        )?;
-
-        // Settle funds for this market
-        invoke_settle_funds(
-            dex_prog_ai,
-            spot_market_ai,
-            market_open_orders_ai,
-            signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            base_vault_ai,
-            quote_vault_ai,
-            dex_signer_ai,
-            token_prog_ai,
-            &[&signers_seeds],
-        )?;
-
-        // See if we can remove this market from margin
-        let open_orders = load_open_orders(market_open_orders_ai)?;
-        lyrae_account.update_basket(market_index, &open_orders)?;
-
-        let post_locked = match order_side {
-            serum_dex::matching::Side::Bid => {
-                open_orders.native_pc_total - open_orders.native_pc_free
-            }
-            serum_dex::matching::Side::Ask => {
-                open_orders.native_coin_total - open_orders.native_coin_free
-            }
-        };
-
-        // If not post allowed, locked amount (i.e. amount on the order book) should not increase
-        check!(post_allowed || post_locked <= pre_locked, LyraeErrorCode::InvalidParam)?;
-
-        let (post_base, post_quote) = {
-            (
-                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
-                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
-            )
-        };
-
-        let quote_change = I80F48::from_num(post_quote) - I80F48::from_num(pre_quote);
-        let base_change = I80F48::from_num(post_base) - I80F48::from_num(pre_base);
-
-        checked_change_net(
-            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
-            &mut quote_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            QUOTE_INDEX,
-            quote_change,
-        )?;
-
-        checked_change_net(
-            &lyrae_cache.root_bank_cache[market_index],
-            &mut base_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            market_index,
-            base_change,
-        )?;
-
-        // Update health for tokens that may have changed
-        health_cache.update_quote(&lyrae_cache, &lyrae_account);
-        health_cache.update_spot_val(
-            &lyrae_group,
-            &lyrae_cache,
-            &lyrae_account,
-            market_open_orders_ai,
-            market_index,
-        )?;
-        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-
-        // If an account is in reduce_only mode, health must only go up
-        check!(
-            post_health >= ZERO_I80F48 || (reduce_only && post_health >= pre_health),
-            LyraeErrorCode::InsufficientFunds
-        )?;
+        let create_ais = [
+            lyrae_group_ai.clone(),
+            lyrae_account_ai.clone(),
+            owner_ai.clone(),
+            dex_prog_ai.clone(),
+            open_orders_ai.clone(),
+            spot_market_ai.clone(),
+            signer_ai.clone(),
+            system_prog_ai.clone(),
+            payer_ai.clone(),
+        ];
+        Self::create_spot_open_orders(program_id, &create_ais)?;
 
-        lyrae_emit!(OpenOrdersBalanceLog {
-            lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
-            market_index: market_index as u64,
-            base_total: open_orders.native_coin_total,
-            base_free: open_orders.native_coin_free,
-            quote_total: open_orders.native_pc_total,
-            quote_free: open_orders.native_pc_free,
-            referrer_rebates_accrued: open_orders.referrer_rebates_accrued
-        });
+        // The deposit's optional fee vault and node banks follow the fixed accounts
+        let deposit_ais: Vec<AccountInfo> =
+            deposit_ais.iter().chain(deposit_opt_ais.iter()).cloned().collect();
 
-        Ok(())
+        // Deposit also emits the DepositLog
+        Self::deposit(program_id, &deposit_ais, quantity)
     }
 
     #[inline(never)]
-    fn cancel_spot_order(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        data: Vec<u8>,
-    ) -> LyraeResult<()> {
-        // TODO add param `ok_invalid_id` to return Ok() instead of Err if order id or client id invalid
-
-        const NUM_FIXED: usize = 10;
+    fn close_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 7;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
 
         let [
         lyrae_group_ai,     // read
-            owner_ai,           // signer
-        lyrae_account_ai,   // read
+        lyrae_account_ai,   // write
+            owner_ai,           // write, signer
             dex_prog_ai,        // read
-            spot_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
             open_orders_ai,     // write
+            spot_market_ai,     // read
             signer_ai,          // read
-            dex_event_queue_ai, // write
         ] = accounts;
 
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
         check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
-        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+        check_eq!(signer_ai.key, &lyrae_group.signer_key, LyraeErrorCode::InvalidParam)?;
 
-        let lyrae_account =
-            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        let market_index = lyrae_group.find_spot_market_index(spot_market_ai.key).unwrap();
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
+        check_eq!(&lyrae_account.owner, owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(!lyrae_account.being_liquidated, LyraeErrorCode::BeingLiquidated)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
         check_eq!(
             &lyrae_account.spot_open_orders[market_index],
             open_orders_ai.key,
             LyraeErrorCode::InvalidOpenOrdersAccount
         )?;
+        check_open_orders(open_orders_ai, &lyrae_group.signer_key, &lyrae_group.dex_program_id)?;
 
-        let signer_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_cancel_order(
+        if lyrae_account.in_margin_basket[market_index] {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            lyrae_account.update_basket(market_index, &open_orders)?;
+            check!(
+                !lyrae_account.in_margin_basket[market_index],
+                LyraeErrorCode::InvalidAccountState
+            )?;
+        }
+
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_close_open_orders(
             dex_prog_ai,
-            spot_market_ai,
-            bids_ai,
-            asks_ai,
             open_orders_ai,
             signer_ai,
-            dex_event_queue_ai,
-            data,
-            &[&signer_seeds],
+            owner_ai,
+            spot_market_ai,
+            &[&signers_seeds],
         )?;
 
-        let open_orders = load_open_orders(open_orders_ai)?;
-        lyrae_emit!(OpenOrdersBalanceLog {
-            lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
-            market_index: market_index as u64,
-            base_total: open_orders.native_coin_total,
-            base_free: open_orders.native_coin_free,
-            quote_total: open_orders.native_pc_total,
-            quote_free: open_orders.native_pc_free,
-            referrer_rebates_accrued: open_orders.referrer_rebates_accrued
-        });
+        lyrae_account.spot_open_orders[market_index] = Pubkey::default();
 
         Ok(())
     }
 
     #[inline(never)]
-    fn settle_funds(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 18;
+    /// Replace a spot OpenOrders owned by a previous dex program with one under the current one
+    fn reassign_spot_open_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 11;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            lyrae_group_ai,         // read
-            lyrae_cache_ai,         // read
-            owner_ai,               // signer
-            lyrae_account_ai,       // write
-            dex_prog_ai,            // read
-            spot_market_ai,         // write
-            open_orders_ai,         // write
-            signer_ai,              // read
-            dex_base_ai,            // write
-            dex_quote_ai,           // write
-            base_root_bank_ai,      // read
-            base_node_bank_ai,      // write
-            quote_root_bank_ai,     // read
-            quote_node_bank_ai,     // write
-            base_vault_ai,          // write
-            quote_vault_ai,         // write
-            dex_signer_ai,          // read
-            token_prog_ai,          // read
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // write, signer
+            old_dex_prog_ai,    // read
+            old_open_orders_ai, // write
+            old_spot_market_ai, // read
+            dex_prog_ai,        // read
+            open_orders_ai,     // write
+            spot_market_ai,     // read
+            signer_ai,          // read
+            rent_ai,            // read
         ] = accounts;
 
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check_eq!(token_prog_ai.key, &spl_token::id(), LyraeErrorCode::InvalidProgramId)?;
         check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
-        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+        check_eq!(signer_ai.key, &lyrae_group.signer_key, LyraeErrorCode::InvalidParam)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-
-        // Make sure the spot market is valid
         let market_index = lyrae_group
             .find_spot_market_index(spot_market_ai.key)
             .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
-        check!(
-            base_root_bank_ai.key == &lyrae_group.tokens[market_index].root_bank,
-            LyraeErrorCode::InvalidRootBank
-        )?;
-
-        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
-        check!(
-            base_root_bank.node_banks.contains(base_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
+        check_eq!(&lyrae_account.owner, owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(!lyrae_account.being_liquidated, LyraeErrorCode::BeingLiquidated)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check_eq!(
+            &lyrae_account.spot_open_orders[market_index],
+            old_open_orders_ai.key,
+            LyraeErrorCode::InvalidOpenOrdersAccount
         )?;
-        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
-        check!(
-            quote_root_bank_ai.key == &lyrae_group.tokens[QUOTE_INDEX].root_bank,
-            LyraeErrorCode::InvalidRootBank
-        )?;
-        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        // Only OpenOrders left behind by a dex program change need reassigning; the program
+        // that owns the stored account is the one that must close it
         check!(
-            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
-        )?;
-        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
-
-        check_eq!(
-            &lyrae_account.spot_open_orders[market_index],
-            open_orders_ai.key,
-            LyraeErrorCode::Default
+            old_open_orders_ai.owner != dex_prog_ai.key,
+            LyraeErrorCode::InvalidOpenOrdersAccount
         )?;
+        check_eq!(old_open_orders_ai.owner, old_dex_prog_ai.key, LyraeErrorCode::InvalidProgramId)?;
+        check_open_orders(old_open_orders_ai, &lyrae_group.signer_key, old_dex_prog_ai.key)?;
 
-        if *open_orders_ai.key == Pubkey::default() {
-            return Ok(());
+        {
+            let old_open_orders = load_open_orders(old_open_orders_ai)?;
+            check_eq!(
+                identity(old_open_orders.market),
+                old_spot_market_ai.key.to_aligned_bytes(),
+                LyraeErrorCode::InvalidMarket
+            )?;
+            check!(
+                old_open_orders.free_slot_bits == u128::MAX
+                    && old_open_orders.native_coin_total == 0
+                    && old_open_orders.native_pc_total == 0
+                    && old_open_orders.referrer_rebates_accrued == 0,
+                LyraeErrorCode::InvalidAccountState
+            )?;
+            if lyrae_account.in_margin_basket[market_index] {
+                lyrae_account.update_basket(market_index, &old_open_orders)?;
+            }
         }
 
-        check_open_orders(open_orders_ai, &lyrae_group.signer_key, &lyrae_group.dex_program_id)?;
-
-        let (pre_base, pre_quote) = {
+        {
             let open_orders = load_open_orders(open_orders_ai)?;
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
-        };
 
-        let signer_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_settle_funds(
+            // Make sure this open orders account has not been initialized already
+            check_eq!(open_orders.account_flags, 0, LyraeErrorCode::Default)?;
+        }
+
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_close_open_orders(
+            old_dex_prog_ai,
+            old_open_orders_ai,
+            signer_ai,
+            owner_ai,
+            old_spot_market_ai,
+            &[&signers_seeds],
+        )?;
+        invoke_init_open_orders(
             dex_prog_ai,
-            spot_market_ai,
             open_orders_ai,
             signer_ai,
-            dex_base_ai,
-            dex_quote_ai,
-            base_vault_ai,
-            quote_vault_ai,
-            dex_signer_ai,
-            token_prog_ai,
-            &[&signer_seeds],
+            spot_market_ai,
+            rent_ai,
+            &[&signers_seeds],
         )?;
 
-        let (post_base, post_quote) = {
-            let open_orders = load_open_orders(open_orders_ai)?;
-            // remove from margin basket if it's empty
-            lyrae_account.update_basket(market_index, &open_orders)?;
-            lyrae_emit!(OpenOrdersBalanceLog {
-                lyrae_group: *lyrae_group_ai.key,
-                lyrae_account: *lyrae_account_ai.key,
-                market_index: market_index as u64,
-                base_total: open_orders.native_coin_total,
-                base_free: open_orders.native_coin_free,
-                quote_total: open_orders.native_pc_total,
-                quote_free: open_orders.native_pc_free,
-                referrer_rebates_accrued: open_orders.referrer_rebates_accrued
-            });
-
-            (
-                open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
-            )
-        };
+        msg!("old open_orders: {} new open_orders: {}", old_open_orders_ai.key, open_orders_ai.key);
+        lyrae_account.spot_open_orders[market_index] = *open_orders_ai.key;
 
-        // TODO OPT - remove sanity check if confident
-        check!(post_base <= pre_base, LyraeErrorCode::MathError)?;
-        check!(post_quote <= pre_quote, LyraeErrorCode::MathError)?;
+        Ok(())
+    }
 
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+    #[inline(never)]
+    /// After SetDexProgramId, point a listed spot market at its market under the new dex program
+    fn reassign_spot_market(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // write
+            admin_ai,           // read, signer
+            dex_prog_ai,        // read
+            old_spot_market_ai, // read
+            spot_market_ai,     // read
+        ] = accounts;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let base_root_bank_cache = &lyrae_cache.root_bank_cache[market_index];
-        let quote_root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(dex_prog_ai.executable, LyraeErrorCode::InvalidProgramId)?;
+        check!(dex_prog_ai.key != &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
 
-        base_root_bank_cache.check_valid(&lyrae_group, now_ts)?;
-        quote_root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        let market_index = lyrae_group
+            .find_spot_market_index(old_spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        checked_change_net(
-            base_root_bank_cache,
-            &mut base_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            market_index,
-            I80F48::from_num(pre_base - post_base),
+        // Only markets still under the group's current dex program are reassigned
+        check_eq!(
+            old_spot_market_ai.owner,
+            &lyrae_group.dex_program_id,
+            LyraeErrorCode::InvalidMarket
         )?;
-        checked_change_net(
-            quote_root_bank_cache,
-            &mut quote_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            QUOTE_INDEX,
-            I80F48::from_num(pre_quote - post_quote),
-        )
+        check!(
+            lyrae_group.find_spot_market_index(spot_market_ai.key).is_none(),
+            LyraeErrorCode::InvalidMarket
+        )?;
+
+        {
+            // Checks that the new market is owned by the group's dex program
+            let spot_market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
+            check_eq!(
+                identity(spot_market.coin_mint),
+                lyrae_group.tokens[market_index].mint.to_aligned_bytes(),
+                LyraeErrorCode::InvalidMarket
+            )?;
+            check_eq!(
+                identity(spot_market.pc_mint),
+                lyrae_group.tokens[QUOTE_INDEX].mint.to_aligned_bytes(),
+                LyraeErrorCode::InvalidMarket
+            )?;
+        }
+
+        msg!("old spot_market: {} new spot_market: {}", old_spot_market_ai.key, spot_market_ai.key);
+        lyrae_group.spot_markets[market_index].spot_market = *spot_market_ai.key;
+        Ok(())
     }
 
     #[inline(never)]
-    fn place_perp_order(
+    /// DEPRECATED
+    fn place_spot_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        side: Side,
-        price: i64,
-        quantity: i64,
-        client_order_id: u64,
-        order_type: OrderType,
-        reduce_only: bool,
-    ) -> LyraeResult {
-        check!(price > 0, LyraeErrorCode::InvalidParam)?;
-        check!(quantity > 0, LyraeErrorCode::InvalidParam)?;
+        order: serum_dex::instruction::NewOrderInstructionV3,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 23;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
 
-        const NUM_FIXED: usize = 8;
-        let (fixed_ais, open_orders_ais, opt_ais) =
-            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
         let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            lyrae_cache_ai,     // read
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-            event_queue_ai,     // write
+        lyrae_group_ai,         // read
+        lyrae_account_ai,       // write
+            owner_ai,               // read & signer
+        lyrae_cache_ai,         // read
+            dex_prog_ai,            // read
+            spot_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            dex_request_queue_ai,   // write
+            dex_event_queue_ai,     // write
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            base_vault_ai,          // write
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            quote_vault_ai,         // write
+            token_prog_ai,          // read
+            signer_ai,              // read
+            _rent_ai,               // read
+            dex_signer_ai,          // read
+            msrm_or_srm_vault_ai,   // read
         ] = fixed_ais;
 
-        let referrer_lyrae_account_ai = opt_ais.first();
+        // TODO OPT - reduce size of this transaction
+        // put bank info into group +64 bytes (can't do this now)
+        // remove settle_funds +64 bytes (can't do this for UX reasons)
+        // ask serum dex to use dynamic sysvars +32 bytes
+        // only send in open orders pubkeys we need +38 bytes
+        // shrink size of order instruction +10 bytes
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
+        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
         let mut lyrae_account =
             LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
-        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
         let clock = Clock::get()?;
         let now_ts = clock.unix_timestamp as u64;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
         let market_index = lyrae_group
-            .find_perp_market_index(perp_market_ai.key)
+            .find_spot_market_index(spot_market_ai.key)
             .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        let active_assets = UserActiveAssets::new(
-            &lyrae_group,
-            &lyrae_account,
-            vec![(AssetType::Perp, market_index)],
-        );
+        check!(
+            &lyrae_group.tokens[market_index].root_bank == base_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
 
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+        check!(
+            base_root_bank.node_banks.contains(base_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
 
-        let mut health_cache = HealthCache::new(active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
-        let pre_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        // update the being_liquidated flag
-        if lyrae_account.being_liquidated {
-            if pre_health >= ZERO_I80F48 {
-                lyrae_account.being_liquidated = false;
-            } else {
-                return Err(throw_err!(LyraeErrorCode::BeingLiquidated));
-            }
-        }
+        check!(
+            &lyrae_group.tokens[QUOTE_INDEX].root_bank == quote_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
 
-        // This means health must only go up
-        let health_up_only = pre_health < ZERO_I80F48;
-
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let mut event_queue =
-            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+        check!(
+            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        // If reduce_only, position must only go down
-        let quantity = if reduce_only {
-            let base_pos = lyrae_account.get_complete_base_pos(
-                market_index,
-                &event_queue,
-                lyrae_account_ai.key,
-            )?;
+        // The basket fix below reads the open orders, so they must be verified first
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
 
-            if (side == Side::Bid && base_pos > 0) || (side == Side::Ask && base_pos < 0) {
-                0
-            } else {
-                base_pos.abs().min(quantity)
+        // Fix the margin basket incase there are empty ones; main benefit is freeing up basket space
+        for i in 0..lyrae_group.num_oracles {
+            if lyrae_account.in_margin_basket[i] {
+                let open_orders = load_open_orders(&open_orders_ais[i])?;
+                lyrae_account.update_basket(i, &open_orders)?;
             }
-        } else {
-            quantity
-        };
-
-        if quantity == 0 {
-            return Ok(());
         }
 
-        book.new_order(
-            program_id,
-            &lyrae_group,
-            lyrae_group_ai.key,
-            &lyrae_cache,
-            &mut event_queue,
-            &mut perp_market,
-            lyrae_cache.get_price(market_index),
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            market_index,
-            side,
-            price,
-            quantity,
-            order_type,
-            client_order_id,
-            now_ts,
-            referrer_lyrae_account_ai,
-        )?;
+        // Adjust margin basket; this also makes this market an active asset
+        lyrae_account.add_to_basket(market_index)?;
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
 
-        health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &lyrae_account, market_index)?;
-        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        check!(
-            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
-            LyraeErrorCode::InsufficientFunds
-        )
-    }
+        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
 
-    #[inline(never)]
-    fn cancel_perp_order_by_client_id(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        client_order_id: u64,
-    ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
+        let (pre_health, being_liquidated) = health_cache
+            .get_pre_health_and_update_being_liquidated(&lyrae_group, &mut lyrae_account);
+        check!(!being_liquidated, LyraeErrorCode::BeingLiquidated)?;
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        // This means health must only go up
+        let reduce_only = pre_health < ZERO_I80F48;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
+        // TODO maybe check that root bank was updated recently
+        // TODO maybe check oracle was updated recently
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        // TODO OPT - write a zero copy way to deserialize Account to reduce compute
 
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        // this is to keep track of the amount of funds transferred
+        let (pre_base, pre_quote) = {
+            (
+                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
+                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
+            )
+        };
 
-        let (order_id, side) = lyrae_account
-            .find_order_with_client_id(market_index, client_order_id)
-            .ok_or(throw_err!(LyraeErrorCode::ClientIdNotFound))?;
+        let order_side = order.side;
+        let vault_ai = match order_side {
+            serum_dex::matching::Side::Bid => quote_vault_ai,
+            serum_dex::matching::Side::Ask => base_vault_ai,
+        };
 
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let best_final = if perp_market.meta_data.version == 0 {
-            match side {
-                Side::Bid => book.get_best_bid_price().unwrap(),
-                Side::Ask => book.get_best_ask_price().unwrap(),
+        // Enforce order price limits if the order is a limit order that goes on the book
+        let native_price = {
+            let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
+
+            I80F48::from_num(order.limit_price.get())
+                .checked_mul(I80F48::from_num(market.pc_lot_size))
+                .unwrap()
+                .checked_div(I80F48::from_num(market.coin_lot_size))
+                .unwrap()
+        };
+        let oracle_price = lyrae_cache.get_price(market_index);
+        let info = &lyrae_group.spot_markets[market_index];
+
+        // If not post_allowed, then pre_locked may not increase
+        let (post_allowed, pre_locked) = {
+            let open_orders = load_open_orders(&open_orders_ais[market_index])?;
+            match order_side {
+                serum_dex::matching::Side::Bid => (
+                    native_price.checked_div(oracle_price).unwrap() <= info.maint_liab_weight,
+                    open_orders.native_pc_total - open_orders.native_pc_free,
+                ),
+                serum_dex::matching::Side::Ask => (
+                    native_price.checked_div(oracle_price).unwrap() >= info.maint_asset_weight,
+                    open_orders.native_coin_total - open_orders.native_coin_free,
+                ),
             }
-        } else {
-            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
-            match side {
-                Side::Bid => book.get_bids_size_above_order(order_id, max_depth),
-                Side::Ask => book.get_asks_size_below_order(order_id, max_depth),
+        };
+
+        // Send order to serum dex
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_new_order(
+            dex_prog_ai,
+            spot_market_ai,
+            &open_orders_ais[market_index],
+            dex_request_queue_ai,
+            dex_event_queue_ai,
+            bids_ai,
+            asks_ai,
+            vault_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            token_prog_ai,
+            msrm_or_srm_vault_ai,
+            &[&signers_seeds],
+            order,
+        )?;
+
+        // Settle funds for this market
+        invoke_settle_funds(
+            dex_prog_ai,
+            spot_market_ai,
+            &open_orders_ais[market_index],
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            base_vault_ai,
+            quote_vault_ai,
+            dex_signer_ai,
+            token_prog_ai,
+            &[&signers_seeds],
+        )?;
+
+        // See if we can remove this market from margin
+        let open_orders = load_open_orders(&open_orders_ais[market_index])?;
+        lyrae_account.update_basket(market_index, &open_orders)?;
+
+        let post_locked = match order_side {
+            serum_dex::matching::Side::Bid => {
+                open_orders.native_pc_total - open_orders.native_pc_free
+            }
+            serum_dex::matching::Side::Ask => {
+                open_orders.native_coin_total - open_orders.native_coin_free
             }
         };
+        check!(post_allowed || post_locked <= pre_locked, LyraeErrorCode::InvalidParam)?;
+        let (post_base, post_quote) = {
+            (
+                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
+                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
+            )
+        };
 
-        let order = book.cancel_order(order_id, side)?;
-        check_eq!(&order.owner, lyrae_account_ai.key, LyraeErrorCode::InvalidOrderId)?;
-        lyrae_account.remove_order(order.owner_slot as usize, order.quantity)?;
+        let quote_change = I80F48::from_num(post_quote) - I80F48::from_num(pre_quote);
+        let base_change = I80F48::from_num(post_base) - I80F48::from_num(pre_base);
 
-        // If order version doesn't match the perp market version, no incentives
-        if order.version != perp_market.meta_data.version {
-            return Ok(());
-        }
+        // An order that borrows the token it pays with increases risk
+        let paying_index = match order_side {
+            serum_dex::matching::Side::Bid => QUOTE_INDEX,
+            serum_dex::matching::Side::Ask => market_index,
+        };
+        let pre_paying_borrows = lyrae_account.borrows[paying_index];
 
-        let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
-        if perp_market.meta_data.version == 0 {
-            lyrae_account.perp_accounts[market_index].apply_price_incentives(
-                &mut perp_market,
-                side,
-                order.price(),
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                Clock::get()?.unix_timestamp as u64,
-                order.quantity,
-            )?;
-        } else {
-            lyrae_account.perp_accounts[market_index].apply_size_incentives(
-                &mut perp_market,
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                Clock::get()?.unix_timestamp as u64,
-                order.quantity,
-            )?;
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+            &mut quote_node_bank,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            QUOTE_INDEX,
+            quote_change,
+        )?;
+
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[market_index],
+            &mut base_node_bank,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            market_index,
+            base_change,
+        )?;
+
+        // Update health for tokens that may have changed
+        health_cache.update_quote(&lyrae_cache, &lyrae_account);
+        health_cache.update_spot_val(
+            &lyrae_group,
+            &lyrae_cache,
+            &lyrae_account,
+            &open_orders_ais[market_index],
+            market_index,
+        )?;
+        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+
+        // If an account is in reduce_only mode, health must only go up
+        check!(
+            post_health >= ZERO_I80F48 || (reduce_only && post_health >= pre_health),
+            LyraeErrorCode::InsufficientFunds
+        )?;
+        if lyrae_account.borrows[paying_index] > pre_paying_borrows {
+            check_min_collateral_to_trade(&lyrae_group, &health_cache)?;
         }
 
-        lyrae_emit!(LyrAccrualLog {
+        lyrae_emit!(OpenOrdersBalanceLog {
             lyrae_group: *lyrae_group_ai.key,
             lyrae_account: *lyrae_account_ai.key,
             market_index: market_index as u64,
-            lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
+            base_total: open_orders.native_coin_total,
+            base_free: open_orders.native_coin_free,
+            quote_total: open_orders.native_pc_total,
+            quote_free: open_orders.native_pc_free,
+            referrer_rebates_accrued: open_orders.referrer_rebates_accrued
         });
 
         Ok(())
     }
 
     #[inline(never)]
-    fn cancel_perp_order(
+    fn place_spot_order2(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order_id: i128,
+        order: serum_dex::instruction::NewOrderInstructionV3,
     ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+        const NUM_FIXED: usize = 22;
+        let (fixed_ais, packed_open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
 
-        // TODO OPT put the liquidity incentive stuff in the bids and asks accounts so perp market
-        //  doesn't have to be passed in as write
+        let [
+        lyrae_group_ai,         // read
+        lyrae_account_ai,       // write
+            owner_ai,               // read & signer
+        lyrae_cache_ai,         // read
+            dex_prog_ai,            // read
+            spot_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            dex_request_queue_ai,   // write
+            dex_event_queue_ai,     // write
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            base_vault_ai,          // write
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            quote_vault_ai,         // write
+            token_prog_ai,          // read
+            signer_ai,              // read
+            dex_signer_ai,          // read
+            msrm_or_srm_vault_ai,   // read
+        ] = fixed_ais;
+
+        // put bank info into group +64 bytes (can't do this now)
+        // remove settle_funds +64 bytes (can't do this for UX reasons)
+        // ask serum dex to use dynamic sysvars +31 bytes (done)
+        // only send in open orders pubkeys we need +38 bytes (done)
+        // shrink size of order instruction +10 bytes
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
+        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
         let mut lyrae_account =
             LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
-        let side = lyrae_account
-            .find_order_side(market_index, order_id)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidOrderId))?;
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-
-        let best_final = if perp_market.meta_data.version == 0 {
-            match side {
-                Side::Bid => book.get_best_bid_price().unwrap(),
-                Side::Ask => book.get_best_ask_price().unwrap(),
-            }
-        } else {
-            let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
-            match side {
-                Side::Bid => book.get_bids_size_above_order(order_id, max_depth),
-                Side::Ask => book.get_asks_size_below_order(order_id, max_depth),
-            }
-        };
-
-        let order = book.cancel_order(order_id, side)?;
-        check_eq!(&order.owner, lyrae_account_ai.key, LyraeErrorCode::InvalidOrderId)?;
-        lyrae_account.remove_order(order.owner_slot as usize, order.quantity)?;
-
-        // If order version doesn't match the perp market version, no incentives
-        if order.version != perp_market.meta_data.version {
-            return Ok(());
-        }
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
 
-        let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
-        if perp_market.meta_data.version == 0 {
-            lyrae_account.perp_accounts[market_index].apply_price_incentives(
-                &mut perp_market,
-                side,
-                order.price(),
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                Clock::get()?.unix_timestamp as u64,
-                order.quantity,
-            )?;
-        } else {
-            lyrae_account.perp_accounts[market_index].apply_size_incentives(
-                &mut perp_market,
-                order.best_initial,
-                best_final,
-                order.timestamp,
-                Clock::get()?.unix_timestamp as u64,
-                order.quantity,
-            )?;
-        }
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        lyrae_emit!(LyrAccrualLog {
-            lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
-            market_index: market_index as u64,
-            lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
-        });
+        check!(
+            &lyrae_group.tokens[market_index].root_bank == base_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
 
-        Ok(())
-    }
+        check!(
+            base_root_bank.node_banks.contains(base_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
 
-    #[inline(never)]
-    fn cancel_all_perp_orders(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        limit: u8,
-    ) -> LyraeResult {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(
+            &lyrae_group.tokens[QUOTE_INDEX].root_bank == quote_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
+            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
         )?;
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        // The open orders for this market must exist before the first order on it is placed;
+        // otherwise a default key would pass check_open_orders and the market would be added
+        // to the basket without a backing OpenOrders account
+        check!(
+            lyrae_account.spot_open_orders[market_index] != Pubkey::default(),
+            LyraeErrorCode::InvalidOpenOrdersAccount
+        )?;
 
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let mut open_orders_ais =
+            lyrae_account.checked_unpack_open_orders(&lyrae_group, packed_open_orders_ais)?;
 
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
+        // Fix the margin basket incase there are empty ones; main benefit is freeing up basket space
+        for i in 0..lyrae_group.num_oracles {
+            if lyrae_account.in_margin_basket[i] {
+                let open_orders = load_open_orders(open_orders_ais[i].unwrap())?;
+                lyrae_account.update_basket(i, &open_orders)?;
+            }
+        }
 
-        if perp_market.meta_data.version == 0 {
-            book.cancel_all_with_price_incentives(
-                &mut lyrae_account,
-                &mut perp_market,
-                market_index,
-                limit,
-            )?;
-        } else {
-            let (all_order_ids, canceled_order_ids) = book.cancel_all_with_size_incentives(
-                &mut lyrae_account,
-                &mut perp_market,
+        // Adjust margin basket; this also makes this market an active asset
+        lyrae_account.add_to_basket(market_index)?;
+        if open_orders_ais[market_index].is_none() {
+            open_orders_ais[market_index] = Some(lyrae_account.checked_unpack_open_orders_single(
+                &lyrae_group,
+                packed_open_orders_ais,
                 market_index,
-                limit,
-            )?;
-            lyrae_emit!(CancelAllPerpOrdersLog {
-                lyrae_group: *lyrae_group_ai.key,
-                lyrae_account: *lyrae_account_ai.key,
-                market_index: market_index as u64,
-                all_order_ids,
-                canceled_order_ids
-            });
+            )?);
         }
+        lyrae_account.check_basket_consistency(&lyrae_group)?;
 
-        lyrae_emit!(LyrAccrualLog {
-            lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
-            market_index: market_index as u64,
-            lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
-        });
-        Ok(())
-    }
-
-    #[inline(never)]
-    /// Take two LyraeAccount and settle quote currency pnl between them
-    fn settle_pnl(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        market_index: usize,
-    ) -> LyraeResult<()> {
-        // TODO - what if someone has no collateral except other perps contracts
-        //  maybe you don't allow people to withdraw if they don't have enough
-        //  when liquidating, make sure you settle their pnl first?
-        // TODO consider doing this in batches of 32 accounts that are close to zero sum
-        // TODO write unit tests for this function
-
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_a_ai, // write
-            lyrae_account_b_ai, // write
-            lyrae_cache_ai,     // read
-            root_bank_ai,       // read
-            node_bank_ai,       // write
-        ] = accounts;
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-
-        let mut lyrae_account_a =
-            LyraeAccount::load_mut_checked(lyrae_account_a_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account_a.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-
-        let mut lyrae_account_b =
-            LyraeAccount::load_mut_checked(lyrae_account_b_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account_b.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-
-        match lyrae_group.find_root_bank_index(root_bank_ai.key) {
-            None => return Err(throw_err!(LyraeErrorCode::Default)),
-            Some(i) => check!(i == QUOTE_INDEX, LyraeErrorCode::Default)?,
-        }
-        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
-        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
-        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::Default)?;
+        // Load after the basket is adjusted so a market entering the basket for the first time
+        // has its OpenOrders included in the health computation below
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
 
+        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
         let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-
-        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
-        let price_cache = &lyrae_cache.price_cache[market_index];
-        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
-
-        root_bank_cache.check_valid(&lyrae_group, now_ts)?;
-        price_cache.check_valid(&lyrae_group, now_ts)?;
-        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
-
-        let price = price_cache.price;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
 
-        let a = &mut lyrae_account_a.perp_accounts[market_index];
-        let b = &mut lyrae_account_b.perp_accounts[market_index];
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals_with_orders_vec(
+            &lyrae_group,
+            &lyrae_cache,
+            &lyrae_account,
+            &open_orders_accounts,
+        )?;
+        let (pre_health, being_liquidated) = health_cache
+            .get_pre_health_and_update_being_liquidated(&lyrae_group, &mut lyrae_account);
+        // An account still flagged being_liquidated has negative Init health and can't place
+        // spot orders at all, not even risk reducing ones; same as the perp path
+        check!(!being_liquidated, LyraeErrorCode::BeingLiquidated)?;
 
-        // Account for unrealized funding payments before settling
-        a.settle_funding(perp_market_cache);
-        b.settle_funding(perp_market_cache);
+        // This means health must only go up
+        let reduce_only = pre_health < ZERO_I80F48;
 
-        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
-        let new_quote_pos_a = I80F48::from_num(-a.base_position * contract_size) * price;
-        let new_quote_pos_b = I80F48::from_num(-b.base_position * contract_size) * price;
-        let a_pnl: I80F48 = a.quote_position - new_quote_pos_a;
-        let b_pnl: I80F48 = b.quote_position - new_quote_pos_b;
+        // TODO maybe check that root bank was updated recently
+        // TODO maybe check oracle was updated recently
 
-        // pnl must be opposite signs for there to be a settlement
-        if a_pnl * b_pnl > 0 {
-            return Ok(());
+        // TODO OPT - write a zero copy way to deserialize Account to reduce compute
+        // this is to keep track of the amount of funds transferred
+        let (pre_base, pre_quote) = {
+            (
+                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
+                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
+            )
+        };
+        let order_side = order.side;
+        let vault_ai = match order_side {
+            serum_dex::matching::Side::Bid => quote_vault_ai,
+            serum_dex::matching::Side::Ask => base_vault_ai,
+        };
+
+        // Enforce order price limits if the order is a limit order that goes on the book
+        let native_price = {
+            // Conver the price in
+            let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
+            I80F48::from_num(order.limit_price.get())
+                .checked_mul(I80F48::from_num(market.pc_lot_size))
+                .unwrap()
+                .checked_div(I80F48::from_num(market.coin_lot_size))
+                .unwrap()
+        };
+        let oracle_price = lyrae_cache.get_price(market_index);
+        let info = &lyrae_group.spot_markets[market_index];
+        let market_open_orders_ai = open_orders_ais[market_index].unwrap();
+
+        // If not post_allowed, then pre_locked may not increase
+        // pre_received is the free balance of the token a fill would pay out
+        // pre_paying_total is the total balance of the token a fill would be paid with
+        let (post_allowed, pre_locked, pre_received, pre_paying_total) = {
+            let open_orders = load_open_orders(market_open_orders_ai)?;
+            match order_side {
+                serum_dex::matching::Side::Bid => (
+                    native_price.checked_div(oracle_price).unwrap() <= info.maint_liab_weight,
+                    open_orders.native_pc_total - open_orders.native_pc_free,
+                    open_orders.native_coin_free,
+                    open_orders.native_pc_total,
+                ),
+                serum_dex::matching::Side::Ask => (
+                    native_price.checked_div(oracle_price).unwrap() >= info.maint_asset_weight,
+                    open_orders.native_coin_total - open_orders.native_coin_free,
+                    open_orders.native_pc_free,
+                    open_orders.native_coin_total,
+                ),
+            }
+        };
+        let post_only = order.order_type == serum_dex::matching::OrderType::PostOnly;
+
+        // Send order to serum dex
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_new_order(
+            dex_prog_ai,
+            spot_market_ai,
+            market_open_orders_ai,
+            dex_request_queue_ai,
+            dex_event_queue_ai,
+            bids_ai,
+            asks_ai,
+            vault_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            token_prog_ai,
+            msrm_or_srm_vault_ai,
+            &[&signers_seeds],
+            order,
+        )?;
+
+        // Taker fills credit the free balance of the token being bought and are paid for out of
+        // the total of the token being sold, topped up by the deposit from our vault
+        let vault_after_order = Account::unpack(&vault_ai.try_borrow_data()?)?.amount;
+        let (post_received, post_paying_total) = {
+            let open_orders = load_open_orders(market_open_orders_ai)?;
+            match order_side {
+                serum_dex::matching::Side::Bid => {
+                    (open_orders.native_coin_free, open_orders.native_pc_total)
+                }
+                serum_dex::matching::Side::Ask => {
+                    (open_orders.native_pc_free, open_orders.native_coin_total)
+                }
+            }
+        };
+
+        // A post only order must not have matched anything; a taker fill would have credited
+        // the free balance of the token being bought before it is settled below
+        if post_only {
+            check!(post_received <= pre_received, LyraeErrorCode::WouldExecuteAsTaker)?;
         }
 
-        let settlement = a_pnl.abs().min(b_pnl.abs());
-        let a_settle = if a_pnl > 0 { settlement } else { -settlement };
-        a.transfer_quote_position(b, a_settle);
+        let filled_received = post_received.saturating_sub(pre_received);
+        let filled_paid = {
+            let pre_vault = match order_side {
+                serum_dex::matching::Side::Bid => pre_quote,
+                serum_dex::matching::Side::Ask => pre_base,
+            };
+            let deposited = pre_vault.saturating_sub(vault_after_order);
+            (pre_paying_total + deposited).saturating_sub(post_paying_total)
+        };
+        let (filled_base, filled_quote) = match order_side {
+            serum_dex::matching::Side::Bid => (filled_received, filled_paid),
+            serum_dex::matching::Side::Ask => (filled_paid, filled_received),
+        };
 
-        transfer_token_internal(
-            &root_bank_cache,
-            &mut node_bank,
-            &mut lyrae_account_b,
-            &mut lyrae_account_a,
-            lyrae_account_b_ai.key,
-            lyrae_account_a_ai.key,
+        // Settle funds for this market
+        invoke_settle_funds(
+            dex_prog_ai,
+            spot_market_ai,
+            market_open_orders_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            base_vault_ai,
+            quote_vault_ai,
+            dex_signer_ai,
+            token_prog_ai,
+            &[&signers_seeds],
+        )?;
+
+        // See if we can remove this market from margin
+        let open_orders = load_open_orders(market_open_orders_ai)?;
+        lyrae_account.update_basket(market_index, &open_orders)?;
+
+        let post_locked = match order_side {
+            serum_dex::matching::Side::Bid => {
+                open_orders.native_pc_total - open_orders.native_pc_free
+            }
+            serum_dex::matching::Side::Ask => {
+                open_orders.native_coin_total - open_orders.native_coin_free
+            }
+        };
+
+        // If not post allowed, locked amount (i.e. amount on the order book) should not increase
+        check!(post_allowed || post_locked <= pre_locked, LyraeErrorCode::InvalidParam)?;
+
+        let (post_base, post_quote) = {
+            (
+                Account::unpack(&base_vault_ai.try_borrow_data()?)?.amount,
+                Account::unpack(&quote_vault_ai.try_borrow_data()?)?.amount,
+            )
+        };
+
+        let quote_change = I80F48::from_num(post_quote) - I80F48::from_num(pre_quote);
+        let base_change = I80F48::from_num(post_base) - I80F48::from_num(pre_base);
+
+        // An order that borrows the token it pays with increases risk
+        let paying_index = match order_side {
+            serum_dex::matching::Side::Bid => QUOTE_INDEX,
+            serum_dex::matching::Side::Ask => market_index,
+        };
+        let pre_paying_borrows = lyrae_account.borrows[paying_index];
+
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+            &mut quote_node_bank,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
             QUOTE_INDEX,
-            a_settle,
+            quote_change,
         )?;
 
-        lyrae_emit!(SettlePnlLog {
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[market_index],
+            &mut base_node_bank,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            market_index,
+            base_change,
+        )?;
+
+        // Update health for tokens that may have changed
+        health_cache.update_quote(&lyrae_cache, &lyrae_account);
+        health_cache.update_spot_val(
+            &lyrae_group,
+            &lyrae_cache,
+            &lyrae_account,
+            market_open_orders_ai,
+            market_index,
+        )?;
+        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+
+        // If an account is in reduce_only mode, health must only go up
+        check!(
+            post_health >= ZERO_I80F48 || (reduce_only && post_health >= pre_health),
+            LyraeErrorCode::InsufficientFunds
+        )?;
+        if lyrae_account.borrows[paying_index] > pre_paying_borrows {
+            check_min_collateral_to_trade(&lyrae_group, &health_cache)?;
+        }
+
+        lyrae_account.set_last_activity_ts(now_ts);
+
+        lyrae_emit!(OpenOrdersBalanceLog {
             lyrae_group: *lyrae_group_ai.key,
-            lyrae_account_a: *lyrae_account_a_ai.key,
-            lyrae_account_b: *lyrae_account_b_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
             market_index: market_index as u64,
-            settlement: a_settle.to_bits(), // Will be positive if a has positive pnl and settling with b
+            base_total: open_orders.native_coin_total,
+            base_free: open_orders.native_coin_free,
+            quote_total: open_orders.native_pc_total,
+            quote_free: open_orders.native_pc_free,
+            referrer_rebates_accrued: open_orders.referrer_rebates_accrued
+        });
+
+        lyrae_emit!(SpotFillLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_index: market_index as u64,
+            side: order_side as u8,
+            filled_base,
+            filled_quote,
+            posted: post_locked > pre_locked,
+            base_change: base_change.to_bits(),
+            quote_change: quote_change.to_bits(),
         });
-        emit_perp_balances(
-            *lyrae_group_ai.key,
-            *lyrae_account_a_ai.key,
-            market_index as u64,
-            &lyrae_account_a.perp_accounts[market_index],
-            perp_market_cache,
-        );
-        emit_perp_balances(
-            *lyrae_group_ai.key,
-            *lyrae_account_b_ai.key,
-            market_index as u64,
-            &lyrae_account_b.perp_accounts[market_index],
-            perp_market_cache,
-        );
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Take an account that has losses in the selected perp market to account for fees_accrued
-    fn settle_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+    fn cancel_spot_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order: serum_dex::instruction::CancelOrderInstructionV2,
+        ok_invalid_id: bool,
+    ) -> LyraeResult<()> {
         const NUM_FIXED: usize = 10;
         let accounts = array_ref![accounts, 0, NUM_FIXED];
+
         let [
-            lyrae_group_ai,     // read
-            lyrae_cache_ai,     // read
-            perp_market_ai,     // write
-            lyrae_account_ai,   // write
-            root_bank_ai,       // read
-            node_bank_ai,       // write
-            bank_vault_ai,      // write
-            fees_vault_ai,      // write
+        lyrae_group_ai,     // read
+            owner_ai,           // signer
+        lyrae_account_ai,   // read
+            dex_prog_ai,        // read
+            spot_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+            open_orders_ai,     // write
             signer_ai,          // read
-            token_prog_ai,      // read
+            dex_event_queue_ai, // write
         ] = accounts;
-        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check!(fees_vault_ai.key == &lyrae_group.fees_vault, LyraeErrorCode::InvalidVault)?;
+        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
         check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
-
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_CANCEL),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
         check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        check!(
-            &lyrae_group.tokens[QUOTE_INDEX].root_bank == root_bank_ai.key,
-            LyraeErrorCode::InvalidRootBank
+        let market_index = lyrae_group.find_spot_market_index(spot_market_ai.key).unwrap();
+        check_eq!(
+            &lyrae_account.spot_open_orders[market_index],
+            open_orders_ai.key,
+            LyraeErrorCode::InvalidOpenOrdersAccount
         )?;
-        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
-        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
-        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
-        check!(bank_vault_ai.key == &node_bank.vault, LyraeErrorCode::InvalidVault)?;
+        check_open_orders(open_orders_ai, &lyrae_group.signer_key, &lyrae_group.dex_program_id)?;
 
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let now_ts = Clock::get()?.unix_timestamp as u64;
+        // A failed CPI aborts the whole transaction, so the dex's OrderNotFound error can't be
+        // caught after the fact. Look the order up on the book first and skip the cancel instead.
+        let order_on_book = !ok_invalid_id || {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
+            let in_open_orders = (0..128).any(|j| {
+                let slot_mask = 1u128 << j;
+                open_orders.free_slot_bits & slot_mask == 0
+                    && open_orders.orders[j] == order.order_id
+                    && (open_orders.is_bid_bits & slot_mask != 0)
+                        == (order.side == serum_dex::matching::Side::Bid)
+            });
+            in_open_orders
+                && match order.side {
+                    serum_dex::matching::Side::Bid => {
+                        load_bids_mut(&market, bids_ai)?.find_by_key(order.order_id).is_some()
+                    }
+                    serum_dex::matching::Side::Ask => {
+                        load_asks_mut(&market, asks_ai)?.find_by_key(order.order_id).is_some()
+                    }
+                }
+        };
 
-        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
-        let price_cache = &lyrae_cache.price_cache[market_index];
-        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
-
-        root_bank_cache.check_valid(&lyrae_group, now_ts)?;
-        price_cache.check_valid(&lyrae_group, now_ts)?;
-        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
-
-        let price = price_cache.price;
-
-        let pa = &mut lyrae_account.perp_accounts[market_index];
-        pa.settle_funding(&perp_market_cache);
-        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
-        let new_quote_pos = I80F48::from_num(-pa.base_position * contract_size) * price;
-        let pnl: I80F48 = pa.quote_position - new_quote_pos;
-        check!(pnl.is_negative(), LyraeErrorCode::Default)?;
-        check!(perp_market.fees_accrued.is_positive(), LyraeErrorCode::Default)?;
-
-        let settlement = pnl.abs().min(perp_market.fees_accrued).checked_floor().unwrap();
-
-        perp_market.fees_accrued -= settlement;
-        pa.quote_position += settlement;
-
-        // Transfer quote token from bank vault to fees vault owned by Lyrae DAO
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_transfer(
-            token_prog_ai,
-            bank_vault_ai,
-            fees_vault_ai,
-            signer_ai,
-            &[&signers_seeds],
-            settlement.to_num(),
-        )?;
-
-        // Decrement deposits on lyrae account
-        checked_change_net(
-            root_bank_cache,
-            &mut node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            QUOTE_INDEX,
-            -settlement,
-        )?;
+        if order_on_book {
+            let data = serum_dex::instruction::MarketInstruction::CancelOrderV2(order).pack();
+            let signer_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+            invoke_cancel_order(
+                dex_prog_ai,
+                spot_market_ai,
+                bids_ai,
+                asks_ai,
+                open_orders_ai,
+                signer_ai,
+                dex_event_queue_ai,
+                data,
+                &[&signer_seeds],
+            )?;
+        } else {
+            msg!("Order {} not found; skipping cancel", order.order_id);
+        }
 
-        lyrae_emit!(SettleFeesLog {
+        let open_orders = load_open_orders(open_orders_ai)?;
+        lyrae_emit!(OpenOrdersBalanceLog {
             lyrae_group: *lyrae_group_ai.key,
             lyrae_account: *lyrae_account_ai.key,
             market_index: market_index as u64,
-            settlement: settlement.to_bits()
+            base_total: open_orders.native_coin_total,
+            base_free: open_orders.native_coin_free,
+            quote_total: open_orders.native_pc_total,
+            quote_free: open_orders.native_pc_free,
+            referrer_rebates_accrued: open_orders.referrer_rebates_accrued
         });
 
-        emit_perp_balances(
-            *lyrae_group_ai.key,
-            *lyrae_account_ai.key,
-            market_index as u64,
-            &lyrae_account.perp_accounts[market_index],
-            perp_market_cache,
-        );
-
         Ok(())
     }
 
     #[inline(never)]
-    fn force_cancel_spot_orders(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        limit: u8,
-    ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 19;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-
+    fn settle_funds(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 18;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
             lyrae_group_ai,         // read
             lyrae_cache_ai,         // read
-            liqee_lyrae_account_ai, // write
+            owner_ai,               // signer
+            lyrae_account_ai,       // write
+            dex_prog_ai,            // read
+            spot_market_ai,         // write
+            open_orders_ai,         // write
+            signer_ai,              // read
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
             base_root_bank_ai,      // read
             base_node_bank_ai,      // write
-            base_vault_ai,          // write
             quote_root_bank_ai,     // read
             quote_node_bank_ai,     // write
+            base_vault_ai,          // write
             quote_vault_ai,         // write
-
-            spot_market_ai,         // write
-            bids_ai,                // write
-            asks_ai,                // write
-            signer_ai,              // read
-            dex_event_queue_ai,     // write
-            dex_base_ai,            // write
-            dex_quote_ai,           // write
             dex_signer_ai,          // read
-            dex_prog_ai,            // read
             token_prog_ai,          // read
-        ] = fixed_ais;
-
-        // Check token program id
-        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+        ] = accounts;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check_eq!(token_prog_ai.key, &spl_token::id(), LyraeErrorCode::InvalidProgramId)?;
         check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
         check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let mut liqee_ma =
-            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_MANAGE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-        let market_index = lyrae_group.find_spot_market_index(spot_market_ai.key).unwrap();
-        check!(liqee_ma.in_margin_basket[market_index], LyraeErrorCode::Default)?;
+        // Make sure the spot market is valid
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        check_eq!(
-            &lyrae_group.tokens[market_index].root_bank,
-            base_root_bank_ai.key,
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
+        check!(
+            base_root_bank_ai.key == &lyrae_group.tokens[market_index].root_bank,
             LyraeErrorCode::InvalidRootBank
         )?;
-        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
 
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
         check!(
             base_root_bank.node_banks.contains(base_node_bank_ai.key),
             LyraeErrorCode::InvalidNodeBank
         )?;
-        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
         check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        check_eq!(
-            &lyrae_group.tokens[QUOTE_INDEX].root_bank,
-            quote_root_bank_ai.key,
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
+        check!(
+            quote_root_bank_ai.key == &lyrae_group.tokens[QUOTE_INDEX].root_bank,
             LyraeErrorCode::InvalidRootBank
         )?;
-        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
-
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
         check!(
             quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
             LyraeErrorCode::InvalidNodeBank
         )?;
-        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
         check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-
-        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
-
-        lyrae_cache.check_valid(&lyrae_group, &liqee_active_assets, now_ts)?;
-
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        check_eq!(
+            &lyrae_account.spot_open_orders[market_index],
+            open_orders_ai.key,
+            LyraeErrorCode::Default
+        )?;
 
-        // Can only force cancel on an account already being liquidated
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
-            }
-        } else if maint_health >= ZERO_I80F48 {
-            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
-        } else {
-            liqee_ma.being_liquidated = true;
+        if *open_orders_ai.key == Pubkey::default() {
+            return Ok(());
         }
 
-        // Cancel orders up to the limit
-        let open_orders_ai = &liqee_open_orders_ais[market_index];
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_cancel_orders(
-            open_orders_ai,
-            dex_prog_ai,
-            spot_market_ai,
-            bids_ai,
-            asks_ai,
-            signer_ai,
-            dex_event_queue_ai,
-            &[&signers_seeds],
-            limit,
-        )?;
+        check_open_orders(open_orders_ai, &lyrae_group.signer_key, &lyrae_group.dex_program_id)?;
 
         let (pre_base, pre_quote) = {
             let open_orders = load_open_orders(open_orders_ai)?;
             (
                 open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+                open_orders
+                    .native_pc_free
+                    .checked_add(open_orders.referrer_rebates_accrued)
+                    .ok_or(math_err!())?,
             )
         };
 
-        if pre_base == 0 && pre_quote == 0 {
-            // margin basket may be in an invalid state; correct it before returning
-            let open_orders = load_open_orders(open_orders_ai)?;
-            liqee_ma.update_basket(market_index, &open_orders)?;
-            return Ok(());
-        }
-
-        // Settle funds released by canceling open orders
-        // TODO OPT add a new ForceSettleFunds to save compute in this instruction
+        let signer_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
         invoke_settle_funds(
             dex_prog_ai,
             spot_market_ai,
@@ -3105,15 +3554,16 @@ impl Processor {
             quote_vault_ai,
             dex_signer_ai,
             token_prog_ai,
-            &[&signers_seeds],
+            &[&signer_seeds],
         )?;
 
         let (post_base, post_quote) = {
             let open_orders = load_open_orders(open_orders_ai)?;
-            liqee_ma.update_basket(market_index, &open_orders)?;
+            // remove from margin basket if it's empty
+            lyrae_account.update_basket(market_index, &open_orders)?;
             lyrae_emit!(OpenOrdersBalanceLog {
                 lyrae_group: *lyrae_group_ai.key,
-                lyrae_account: *liqee_lyrae_account_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
                 market_index: market_index as u64,
                 base_total: open_orders.native_coin_total,
                 base_free: open_orders.native_coin_free,
@@ -3124,2873 +3574,5856 @@ impl Processor {
 
             (
                 open_orders.native_coin_free,
-                open_orders.native_pc_free + open_orders.referrer_rebates_accrued,
+                open_orders
+                    .native_pc_free
+                    .checked_add(open_orders.referrer_rebates_accrued)
+                    .ok_or(math_err!())?,
             )
         };
 
-        check!(post_base <= pre_base, LyraeErrorCode::Default)?;
-        check!(post_quote <= pre_quote, LyraeErrorCode::Default)?;
+        // TODO OPT - remove sanity check if confident
+        check!(post_base <= pre_base, LyraeErrorCode::MathError)?;
+        check!(post_quote <= pre_quote, LyraeErrorCode::MathError)?;
 
-        // Update balances from settling funds
-        let base_change = I80F48::from_num(pre_base - post_base);
-        let quote_change = I80F48::from_num(pre_quote - post_quote);
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let base_root_bank_cache = &lyrae_cache.root_bank_cache[market_index];
+        let quote_root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+
+        base_root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        quote_root_bank_cache.check_valid(&lyrae_group, now_ts)?;
 
         checked_change_net(
-            &lyrae_cache.root_bank_cache[market_index],
+            base_root_bank_cache,
             &mut base_node_bank,
-            &mut liqee_ma,
-            liqee_lyrae_account_ai.key,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
             market_index,
-            base_change,
+            I80F48::from_num(pre_base - post_base),
         )?;
         checked_change_net(
-            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+            quote_root_bank_cache,
             &mut quote_node_bank,
-            &mut liqee_ma,
-            liqee_lyrae_account_ai.key,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
             QUOTE_INDEX,
-            quote_change,
+            I80F48::from_num(pre_quote - post_quote),
         )
     }
 
     #[inline(never)]
-    fn force_cancel_perp_orders(
+    /// Move the serum referrer rebates accrued on a spot OpenOrders into the quote deposits.
+    /// invoke_settle_funds passes the quote vault as the referrer wallet so settling pays the
+    /// rebates into the quote vault, and settle_funds credits them with the rest of the free
+    /// funds. This is a no-op if there is nothing accrued.
+    fn claim_serum_referrer_rebates(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        limit: u8,
-    ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-
-        let [
-            lyrae_group_ai,         // read
-            lyrae_cache_ai,         // read
-            perp_market_ai,         // read
-            bids_ai,                // write
-            asks_ai,                // write
-            liqee_lyrae_account_ai, // write
-        ] = fixed_ais;
+    ) -> LyraeResult {
+        // Same accounts as SettleFunds; all validation is done there
+        const NUM_FIXED: usize = 18;
+        let fixed_ais = array_ref![accounts, 0, NUM_FIXED];
+        let open_orders_ai = &fixed_ais[6];
+        if *open_orders_ai.key == Pubkey::default() {
+            return Ok(());
+        }
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let referrer_rebates_accrued = load_open_orders(open_orders_ai)?.referrer_rebates_accrued;
+        if referrer_rebates_accrued == 0 {
+            msg!("No referrer rebates accrued");
+            return Ok(());
+        }
+        msg!("Claiming {} native quote of referrer rebates", referrer_rebates_accrued);
 
-        let mut liqee_ma =
-            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
-
-        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
-        let perp_market_info = &lyrae_group.perp_markets[market_index];
-        check!(!perp_market_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
-
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-
-        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
-
-        lyrae_cache.check_valid(&lyrae_group, &liqee_active_assets, now_ts)?;
-
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
-
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
-            }
-        } else if maint_health >= ZERO_I80F48 {
-            msg!(
-                "maint health {} init health {}",
-                maint_health.to_num::<f64>(),
-                init_health.to_num::<f64>()
-            );
-            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
-        } else {
-            liqee_ma.being_liquidated = true;
-        }
-
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        book.cancel_all(&mut liqee_ma, market_index, limit)
-    }
+        Self::settle_funds(program_id, accounts)
+    }
 
     #[inline(never)]
-    /// Liquidator takes some of borrows at token at `liab_index` and receives some deposits from
-    /// the token at `asset_index`
-    /// Requires: `liab_index != asset_index`
-    fn liquidate_token_and_token(
+    fn place_perp_order(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        max_liab_transfer: I80F48,
-    ) -> LyraeResult<()> {
-        // parameter checks
-        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
-
-        const NUM_FIXED: usize = 9;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
-            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+        side: Side,
+        price: i64,
+        quantity: i64,
+        client_order_id: u64,
+        order_type: OrderType,
+        reduce_only: bool,
+    ) -> LyraeResult {
+        check!(price > 0, LyraeErrorCode::InvalidParam)?;
+        check!(quantity > 0, LyraeErrorCode::InvalidParam)?;
 
+        const NUM_FIXED: usize = 8;
+        let (fixed_ais, open_orders_ais, opt_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
         let [
-            lyrae_group_ai,         // read
-            lyrae_cache_ai,         // read
-            liqee_lyrae_account_ai, // write
-            liqor_lyrae_account_ai, // write
-            liqor_ai,               // read, signer
-            asset_root_bank_ai,     // read
-            asset_node_bank_ai,     // write
-            liab_root_bank_ai,      // read
-            liab_node_bank_ai,      // write
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+            event_queue_ai,     // write
         ] = fixed_ais;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let mut liqee_ma =
-            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
 
-        let mut liqor_ma =
-            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check!(
-            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
-        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
-
-        let asset_root_bank = RootBank::load_checked(asset_root_bank_ai, program_id)?;
-        let asset_index = lyrae_group.find_root_bank_index(asset_root_bank_ai.key).unwrap();
-        let mut asset_node_bank = NodeBank::load_mut_checked(asset_node_bank_ai, program_id)?;
-        check!(
-            asset_root_bank.node_banks.contains(asset_node_bank_ai.key),
-            LyraeErrorCode::InvalidNodeBank
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+        let (referrer_lyrae_account_ai, taker_fee_discount_bps) = get_perp_order_opt_accounts(
+            program_id,
+            lyrae_group_ai.key,
+            opt_ais,
+            lyrae_account.msrm_amount,
         )?;
 
-        let liab_root_bank = RootBank::load_checked(liab_root_bank_ai, program_id)?;
-        let liab_index = lyrae_group.find_root_bank_index(liab_root_bank_ai.key).unwrap();
-        let mut liab_node_bank = NodeBank::load_mut_checked(liab_node_bank_ai, program_id)?;
-        check!(liab_root_bank.node_banks.contains(liab_node_bank_ai.key), LyraeErrorCode::Default)?;
-        check!(asset_index != liab_index, LyraeErrorCode::InvalidParam)?;
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
-        let liqor_active_assets = UserActiveAssets::new(
-            &lyrae_group,
-            &liqor_ma,
-            vec![(AssetType::Token, asset_index), (AssetType::Token, liab_index)],
-        );
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
 
-        lyrae_cache.check_valid(
+        let active_assets = UserActiveAssets::new(
             &lyrae_group,
-            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
-            now_ts,
-        )?;
-
-        // Make sure orders are cancelled for perps and check orders
-        for i in 0..lyrae_group.num_oracles {
-            if liqee_active_assets.perps[i] {
-                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
-            }
-        }
+            &lyrae_account,
+            vec![(AssetType::Perp, market_index)],
+        );
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+        perp_market.check_order_price(price, order_type, lyrae_cache.get_price(market_index))?;
 
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
-            }
-        } else if maint_health >= ZERO_I80F48 {
-            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
-        } else {
-            liqee_ma.being_liquidated = true;
-        }
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
+        let (pre_health, being_liquidated) = health_cache
+            .get_pre_health_and_update_being_liquidated(&lyrae_group, &mut lyrae_account);
+        check!(!being_liquidated, LyraeErrorCode::BeingLiquidated)?;
 
-        check!(liqee_ma.deposits[asset_index].is_positive(), LyraeErrorCode::Default)?;
-        check!(liqee_ma.borrows[liab_index].is_positive(), LyraeErrorCode::Default)?;
+        // This means health must only go up
+        let health_up_only = pre_health < ZERO_I80F48;
 
-        let asset_bank = &lyrae_cache.root_bank_cache[asset_index];
-        let liab_bank = &lyrae_cache.root_bank_cache[liab_index];
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
-        let asset_price = lyrae_cache.get_price(asset_index);
-        let liab_price = lyrae_cache.get_price(liab_index);
+        if !reduce_only {
+            check_min_collateral_to_trade(&lyrae_group, &health_cache)?;
+        }
 
-        let (asset_fee, init_asset_weight) = if asset_index == QUOTE_INDEX {
-            (ONE_I80F48, ONE_I80F48)
-        } else {
-            let asset_info = &lyrae_group.spot_markets[asset_index];
-            check!(!asset_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
-            (ONE_I80F48 + asset_info.liquidation_fee, asset_info.init_asset_weight)
-        };
+        // If reduce_only, position must only go down
+        let quantity = if reduce_only {
+            let base_pos = lyrae_account.get_complete_base_pos(
+                market_index,
+                &event_queue,
+                lyrae_account_ai.key,
+            )?;
 
-        let (liab_fee, init_liab_weight) = if liab_index == QUOTE_INDEX {
-            (ONE_I80F48, ONE_I80F48)
+            lyrae_account.get_reduce_only_quantity(market_index, side, base_pos, quantity)
         } else {
-            let liab_info = &lyrae_group.spot_markets[liab_index];
-            check!(!liab_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
-            (ONE_I80F48 - liab_info.liquidation_fee, liab_info.init_liab_weight)
+            quantity
         };
 
-        // Max liab transferred to reach init_health == 0
-        let deficit_max_liab: I80F48 = -init_health
-            / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee));
-
-        let native_deposits = liqee_ma.get_native_deposit(asset_bank, asset_index)?;
-        let native_borrows = liqee_ma.get_native_borrow(liab_bank, liab_index)?;
+        if quantity == 0 {
+            set_perp_order_return_data(0, 0, 0);
+            return Ok(());
+        }
 
-        // Max liab transferred to reach asset_i == 0
-        let asset_implied_liab_transfer =
-            native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
-        let actual_liab_transfer = min(
-            min(min(deficit_max_liab, native_borrows), max_liab_transfer),
-            asset_implied_liab_transfer,
+        let pre_pa = lyrae_account.perp_accounts[market_index];
+        book.new_order(
+            program_id,
+            &lyrae_group,
+            lyrae_group_ai.key,
+            &lyrae_cache,
+            &mut event_queue,
+            &mut perp_market,
+            lyrae_cache.get_price(market_index),
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            market_index,
+            side,
+            price,
+            quantity,
+            order_type,
+            client_order_id,
+            now_ts,
+            referrer_lyrae_account_ai,
+            taker_fee_discount_bps,
+        )?;
+        let post_pa = &lyrae_account.perp_accounts[market_index];
+        let posted_quantity = match side {
+            Side::Bid => post_pa.bids_quantity - pre_pa.bids_quantity,
+            Side::Ask => post_pa.asks_quantity - pre_pa.asks_quantity,
+        };
+        set_perp_order_return_data(
+            post_pa.taker_base - pre_pa.taker_base,
+            post_pa.taker_quote - pre_pa.taker_quote,
+            posted_quantity,
         );
 
-        // Transfer into liqee to reduce liabilities
-        checked_change_net(
-            &liab_bank,
-            &mut liab_node_bank,
-            &mut liqee_ma,
-            liqee_lyrae_account_ai.key,
-            liab_index,
-            actual_liab_transfer,
-        )?; // TODO make sure deposits for this index is == 0
-
-        // Transfer from liqor
-        checked_change_net(
-            &liab_bank,
-            &mut liab_node_bank,
-            &mut liqor_ma,
-            liqor_lyrae_account_ai.key,
-            liab_index,
-            -actual_liab_transfer,
+        health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &lyrae_account, market_index)?;
+        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(
+            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
+            LyraeErrorCode::InsufficientFunds
         )?;
 
-        let asset_transfer =
-            actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
-
-        // Transfer collater into liqor
-        checked_change_net(
-            &asset_bank,
-            &mut asset_node_bank,
-            &mut liqor_ma,
-            liqor_lyrae_account_ai.key,
-            asset_index,
-            asset_transfer,
-        )?;
+        lyrae_account.set_last_activity_ts(now_ts);
+        Ok(())
+    }
 
-        // Transfer collateral out of liqee
-        checked_change_net(
-            &asset_bank,
-            &mut asset_node_bank,
-            &mut liqee_ma,
-            liqee_lyrae_account_ai.key,
-            asset_index,
-            -asset_transfer,
+    #[inline(never)]
+    /// Cancel a resting perp order and place its replacement on the same side in one instruction.
+    /// Incentives for the cancelled order accrue like in cancel_perp_order and health is only
+    /// checked once, after the replacement. If `no_risk_increase`, the largest position the
+    /// account can reach on this market must not grow.
+    fn place_perp_order_amend(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_id: i128,
+        by_client_id: bool,
+        price: i64,
+        quantity: i64,
+        client_order_id: u64,
+        order_type: OrderType,
+        reduce_only: bool,
+        no_risk_increase: bool,
+    ) -> LyraeResult {
+        check!(price > 0, LyraeErrorCode::InvalidParam)?;
+        check!(quantity > 0, LyraeErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 8;
+        let (fixed_ais, open_orders_ais, opt_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+            event_queue_ai,     // write
+        ] = fixed_ais;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+        let (referrer_lyrae_account_ai, taker_fee_discount_bps) = get_perp_order_opt_accounts(
+            program_id,
+            lyrae_group_ai.key,
+            opt_ais,
+            lyrae_account.msrm_amount,
         )?;
 
-        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
-        liqor_health_cache.init_vals(
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+        // The replacement keeps the side of the amended order
+        let (order_id, side) = if by_client_id {
+            lyrae_account
+                .find_order_with_client_id(market_index, order_id as u64)
+                .ok_or(throw_err!(LyraeErrorCode::ClientIdNotFound))?
+        } else {
+            let side = lyrae_account
+                .find_order_side(market_index, order_id)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidOrderId))?;
+            (order_id, side)
+        };
+
+        let active_assets = UserActiveAssets::new(
             &lyrae_group,
-            &lyrae_cache,
-            &liqor_ma,
-            liqor_open_orders_ais,
+            &lyrae_account,
+            vec![(AssetType::Perp, market_index)],
+        );
+
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+        perp_market.check_order_price(price, order_type, lyrae_cache.get_price(market_index))?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
+        let (pre_health, being_liquidated) = health_cache
+            .get_pre_health_and_update_being_liquidated(&lyrae_group, &mut lyrae_account);
+        check!(!being_liquidated, LyraeErrorCode::BeingLiquidated)?;
+
+        // This means health must only go up
+        let health_up_only = pre_health < ZERO_I80F48;
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+        let pre_exposure = if no_risk_increase {
+            let base_pos = lyrae_account.get_complete_base_pos(
+                market_index,
+                &event_queue,
+                lyrae_account_ai.key,
+            )?;
+            lyrae_account.get_perp_exposure(market_index, base_pos)
+        } else {
+            0
+        };
+
+        cancel_perp_order_with_incentives(
+            lyrae_group_ai.key,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            &mut perp_market,
+            &mut book,
+            market_index,
+            order_id,
+            side,
         )?;
-        let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
-        check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
 
-        // Update liqee's health where it may have changed
-        for &i in &[asset_index, liab_index] {
-            health_cache.update_token_val(
+        if !reduce_only {
+            check_min_collateral_to_trade(&lyrae_group, &health_cache)?;
+        }
+
+        // If reduce_only, position must only go down
+        let quantity = if reduce_only {
+            let base_pos = lyrae_account.get_complete_base_pos(
+                market_index,
+                &event_queue,
+                lyrae_account_ai.key,
+            )?;
+
+            lyrae_account.get_reduce_only_quantity(market_index, side, base_pos, quantity)
+        } else {
+            quantity
+        };
+
+        if quantity != 0 {
+            book.new_order(
+                program_id,
                 &lyrae_group,
+                lyrae_group_ai.key,
                 &lyrae_cache,
-                &liqee_ma,
-                liqee_open_orders_ais,
-                i,
+                &mut event_queue,
+                &mut perp_market,
+                lyrae_cache.get_price(market_index),
+                &mut lyrae_account,
+                lyrae_account_ai.key,
+                market_index,
+                side,
+                price,
+                quantity,
+                order_type,
+                client_order_id,
+                now_ts,
+                referrer_lyrae_account_ai,
+                taker_fee_discount_bps,
             )?;
         }
-        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
-        if liqee_maint_health < ZERO_I80F48 {
-            liqee_ma.is_bankrupt =
-                liqee_ma.check_enter_bankruptcy(&lyrae_group, liqee_open_orders_ais);
-        } else {
-            let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
 
-            // this is equivalent to one native USDC or 1e-6 USDC
-            // This is used as threshold to flip flag instead of 0 because of dust issues
-            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
+        if no_risk_increase {
+            let base_pos = lyrae_account.get_complete_base_pos(
+                market_index,
+                &event_queue,
+                lyrae_account_ai.key,
+            )?;
+            let post_exposure = lyrae_account.get_perp_exposure(market_index, base_pos);
+            if post_exposure > pre_exposure {
+                msg!("Amend would increase exposure from {} to {}", pre_exposure, post_exposure);
+                return Err(throw_err!(LyraeErrorCode::InvalidParam));
+            }
         }
 
-        lyrae_emit!(LiquidateTokenAndTokenLog {
-            lyrae_group: *lyrae_group_ai.key,
-            liqee: *liqee_lyrae_account_ai.key,
-            liqor: *liqor_lyrae_account_ai.key,
-            asset_index: asset_index as u64,
-            liab_index: liab_index as u64,
-            asset_transfer: asset_transfer.to_bits(),
-            liab_transfer: actual_liab_transfer.to_bits(),
-            asset_price: asset_price.to_bits(),
-            liab_price: liab_price.to_bits(),
-            bankruptcy: liqee_ma.is_bankrupt
-        });
+        health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &lyrae_account, market_index)?;
+        let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(
+            post_health >= ZERO_I80F48 || (health_up_only && post_health >= pre_health),
+            LyraeErrorCode::InsufficientFunds
+        )?;
 
+        lyrae_account.set_last_activity_ts(now_ts);
         Ok(())
     }
 
     #[inline(never)]
-    /// swap tokens for perp quote position only and only if the base position in that market is 0
-    fn liquidate_token_and_perp(
+    /// Same as place_perp_order, but sized by a quote notional in native units
+    fn place_perp_order_by_notional(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        asset_type: AssetType,
-        asset_index: usize,
-        liab_type: AssetType,
-        liab_index: usize,
-        max_liab_transfer: I80F48,
+        side: Side,
+        price: i64,
+        quote_notional: u64,
+        client_order_id: u64,
+        order_type: OrderType,
+        reduce_only: bool,
     ) -> LyraeResult<()> {
-        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
-        check!(asset_type != liab_type, LyraeErrorCode::InvalidParam)?;
+        let quantity =
+            Self::perp_quantity_from_notional(program_id, accounts, price, quote_notional)?;
+        Self::place_perp_order(
+            program_id,
+            accounts,
+            side,
+            price,
+            quantity,
+            client_order_id,
+            order_type,
+            reduce_only,
+        )
+    }
 
-        const NUM_FIXED: usize = 7;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
-            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+    /// Convert a quote notional in native units to base lots at `price`, rounding down
+    fn perp_quantity_from_notional(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        price: i64,
+        quote_notional: u64,
+    ) -> LyraeResult<i64> {
+        check!(price > 0, LyraeErrorCode::InvalidParam)?;
+        let [lyrae_group_ai, _, _, _, perp_market_ai] = array_ref![accounts, 0, 5];
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+
+        // price is in quote lots per base lot
+        let native_per_lot = (price as i128)
+            .checked_mul(perp_market.quote_lot_size as i128)
+            .ok_or(math_err!())?;
+        let quantity =
+            i64::try_from(quote_notional as i128 / native_per_lot).map_err(|_| math_err!())?;
+        check!(quantity > 0, LyraeErrorCode::InvalidParam)?;
+        Ok(quantity)
+    }
 
+    #[inline(never)]
+    fn cancel_perp_order_by_client_id(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        client_order_id: u64,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            lyrae_group_ai,         // read
-            lyrae_cache_ai,         // read
-            liqee_lyrae_account_ai, // write
-            liqor_lyrae_account_ai, // write
-            liqor_ai,               // read, signer
-            root_bank_ai,           // read
-            node_bank_ai,           // write
-        ] = fixed_ais;
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = accounts;
+
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let mut liqee_ma =
-            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
 
-        let mut liqor_ma =
-            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check!(
-            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_CANCEL),
             LyraeErrorCode::InvalidOwner
         )?;
-        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
-
-        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
-        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
-        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
-        let liqor_active_assets = UserActiveAssets::new(
-            &lyrae_group,
-            &liqor_ma,
-            vec![(asset_type, asset_index), (liab_type, liab_index)],
-        );
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
 
-        lyrae_cache.check_valid(
-            &lyrae_group,
-            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
-            now_ts,
-        )?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
-        // Make sure orders are cancelled for perps and check orders
-        for i in 0..lyrae_group.num_oracles {
-            if liqee_active_assets.perps[i] {
-                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
-            }
-        }
+        let (order_id, side) = lyrae_account
+            .find_order_with_client_id(market_index, client_order_id)
+            .ok_or(throw_err!(LyraeErrorCode::ClientIdNotFound))?;
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        cancel_perp_order_with_incentives(
+            lyrae_group_ai.key,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            &mut perp_market,
+            &mut book,
+            market_index,
+            order_id,
+            side,
+        )
+    }
 
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
-            }
-        } else if maint_health >= ZERO_I80F48 {
-            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
-        } else {
-            liqee_ma.being_liquidated = true;
-        }
+    #[inline(never)]
+    fn cancel_perp_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_id: i128,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = accounts;
 
-        let asset_price: I80F48;
-        let liab_price: I80F48;
-        let asset_transfer: I80F48;
-        let actual_liab_transfer: I80F48;
-        if asset_type == AssetType::Token {
-            // we know asset_type != liab_type
-            asset_price = lyrae_cache.get_price(asset_index);
-            liab_price = ONE_I80F48;
-            let bank_cache = &lyrae_cache.root_bank_cache[asset_index];
-            check!(liqee_ma.deposits[asset_index].is_positive(), LyraeErrorCode::Default)?;
-            check!(liab_index != QUOTE_INDEX, LyraeErrorCode::Default)?;
-            check!(
-                lyrae_group.find_root_bank_index(root_bank_ai.key).unwrap() == asset_index,
-                LyraeErrorCode::InvalidRootBank
-            )?;
-            let native_borrows = -liqee_ma.perp_accounts[liab_index].quote_position;
-            check!(liqee_ma.perp_accounts[liab_index].base_position == 0, LyraeErrorCode::Default)?;
-            check!(native_borrows.is_positive(), LyraeErrorCode::Default)?;
+        // TODO OPT put the liquidity incentive stuff in the bids and asks accounts so perp market
+        //  doesn't have to be passed in as write
 
-            let (asset_fee, init_asset_weight) = if asset_index == QUOTE_INDEX {
-                (ONE_I80F48, ONE_I80F48)
-            } else {
-                let asset_info = &lyrae_group.spot_markets[asset_index];
-                check!(!asset_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
-                (ONE_I80F48 + asset_info.liquidation_fee, asset_info.init_asset_weight)
-            };
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
 
-            let liab_info = &lyrae_group.perp_markets[liab_index];
-            check!(!liab_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_CANCEL),
+            LyraeErrorCode::InvalidOwner
+        )?;
 
-            let (liab_fee, init_liab_weight) = (ONE_I80F48, ONE_I80F48);
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
 
-            let native_deposits = liqee_ma.get_native_deposit(bank_cache, asset_index)?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let side = lyrae_account
+            .find_order_side(market_index, order_id)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidOrderId))?;
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
 
-            // Max liab transferred to reach init_health == 0
-            let deficit_max_liab = if asset_index == QUOTE_INDEX {
-                native_deposits
-            } else {
-                -init_health
-                    / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee))
-            };
+        cancel_perp_order_with_incentives(
+            lyrae_group_ai.key,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            &mut perp_market,
+            &mut book,
+            market_index,
+            order_id,
+            side,
+        )
+    }
 
-            // Max liab transferred to reach asset_i == 0
-            let asset_implied_liab_transfer =
-                native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
-            actual_liab_transfer = deficit_max_liab
-                .min(native_borrows)
-                .min(max_liab_transfer)
-                .min(asset_implied_liab_transfer);
+    #[inline(never)]
+    fn cancel_all_perp_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = accounts;
 
-            // Transfer the negative quote position from liqee to liqor
-            liqee_ma.perp_accounts[liab_index].transfer_quote_position(
-                &mut liqor_ma.perp_accounts[liab_index],
-                -actual_liab_transfer,
-            );
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
 
-            asset_transfer =
-                actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_CANCEL),
+            LyraeErrorCode::InvalidOwner
+        )?;
 
-            // Transfer collateral from liqee to liqor
-            transfer_token_internal(
-                bank_cache,
-                &mut node_bank,
-                &mut liqee_ma,
-                &mut liqor_ma,
-                liqee_lyrae_account_ai.key,
-                liqor_lyrae_account_ai.key,
-                asset_index,
-                asset_transfer,
-            )?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
 
-            health_cache.update_token_val(
-                &lyrae_group,
-                &lyrae_cache,
-                &liqee_ma,
-                liqee_open_orders_ais,
-                asset_index,
-            )?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
-            health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, liab_index)?;
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
+
+        if perp_market.meta_data.version == 0 {
+            book.cancel_all_with_price_incentives(
+                &mut lyrae_account,
+                &mut perp_market,
+                market_index,
+                limit,
+            )?;
         } else {
-            asset_price = ONE_I80F48;
-            liab_price = lyrae_cache.get_price(liab_index);
-            check!(
-                lyrae_group.find_root_bank_index(root_bank_ai.key).unwrap() == liab_index,
-                LyraeErrorCode::InvalidRootBank
+            let (all_order_ids, canceled_order_ids) = book.cancel_all_with_size_incentives(
+                &mut lyrae_account,
+                &mut perp_market,
+                market_index,
+                limit,
             )?;
+            lyrae_emit!(CancelAllPerpOrdersLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                all_order_ids,
+                canceled_order_ids
+            });
+        }
 
-            check!(liqee_ma.borrows[liab_index].is_positive(), LyraeErrorCode::Default)?;
-            check!(asset_index != QUOTE_INDEX, LyraeErrorCode::Default)?;
-
-            check!(
-                liqee_ma.perp_accounts[asset_index].base_position == 0,
-                LyraeErrorCode::Default
-            )?;
-            let native_deposits = liqee_ma.perp_accounts[asset_index].quote_position;
-            check!(native_deposits.is_positive(), LyraeErrorCode::Default)?;
+        lyrae_emit!(LyrAccrualLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_index: market_index as u64,
+            lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
+        });
+        Ok(())
+    }
 
-            let bank_cache = &lyrae_cache.root_bank_cache[liab_index];
-            let (asset_fee, init_asset_weight) = (ONE_I80F48, ONE_I80F48);
-            let (liab_fee, init_liab_weight) = if liab_index == QUOTE_INDEX {
-                (ONE_I80F48, ONE_I80F48)
-            } else {
-                let liab_info = &lyrae_group.spot_markets[liab_index];
-                check!(!liab_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
-                (ONE_I80F48 - liab_info.liquidation_fee, liab_info.init_liab_weight)
-            };
+    #[inline(never)]
+    /// Take two LyraeAccount and settle quote currency pnl between them
+    fn settle_pnl(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        market_index: usize,
+    ) -> LyraeResult<()> {
+        // TODO - what if someone has no collateral except other perps contracts
+        //  maybe you don't allow people to withdraw if they don't have enough
+        //  when liquidating, make sure you settle their pnl first?
+        // TODO consider doing this in batches of 32 accounts that are close to zero sum
+        // TODO write unit tests for this function
 
-            let native_borrows = liqee_ma.get_native_borrow(bank_cache, liab_index)?;
-            let deficit_max_liab = if liab_index == QUOTE_INDEX {
-                native_borrows
-            } else {
-                -init_health
-                    / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee))
-            };
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_a_ai, // write
+            lyrae_account_b_ai, // write
+            lyrae_cache_ai,     // read
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+        ] = accounts;
+        check!(lyrae_account_a_ai.key != lyrae_account_b_ai.key, LyraeErrorCode::InvalidParam)?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(market_index < lyrae_group.num_oracles, LyraeErrorCode::InvalidMarket)?;
+        check!(!lyrae_group.perp_markets[market_index].is_empty(), LyraeErrorCode::InvalidMarket)?;
 
-            // Max liab transferred to reach asset_i == 0
-            let asset_implied_liab_transfer =
-                native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
-            actual_liab_transfer = deficit_max_liab
-                .min(native_borrows)
-                .min(max_liab_transfer)
-                .min(asset_implied_liab_transfer);
+        let mut lyrae_account_a =
+            LyraeAccount::load_mut_checked(lyrae_account_a_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account_a.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-            asset_transfer =
-                actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
+        let mut lyrae_account_b =
+            LyraeAccount::load_mut_checked(lyrae_account_b_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account_b.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-            // Transfer liabilities from liqee to liqor (i.e. increase liqee and decrease liqor)
-            transfer_token_internal(
-                bank_cache,
-                &mut node_bank,
-                &mut liqor_ma,
-                &mut liqee_ma,
-                liqor_lyrae_account_ai.key,
-                liqee_lyrae_account_ai.key,
-                liab_index,
-                actual_liab_transfer,
-            )?;
+        match lyrae_group.find_root_bank_index(root_bank_ai.key) {
+            None => return Err(throw_err!(LyraeErrorCode::Default)),
+            Some(i) => check!(i == QUOTE_INDEX, LyraeErrorCode::Default)?,
+        }
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::Default)?;
 
-            // Transfer positive quote position from liqee to liqor
-            liqee_ma.perp_accounts[asset_index]
-                .transfer_quote_position(&mut liqor_ma.perp_accounts[asset_index], asset_transfer);
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-            health_cache.update_token_val(
-                &lyrae_group,
-                &lyrae_cache,
-                &liqee_ma,
-                liqee_open_orders_ais,
-                liab_index,
-            )?;
+        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        let price_cache = &lyrae_cache.price_cache[market_index];
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
 
-            health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, asset_index)?;
-        }
+        root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        price_cache.check_valid(&lyrae_group, now_ts)?;
+        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
 
-        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
-        liqor_health_cache.init_vals(
-            &lyrae_group,
-            &lyrae_cache,
-            &liqor_ma,
-            liqor_open_orders_ais,
-        )?;
-        let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
-        check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+        let price = price_cache.price;
 
-        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
-        if liqee_maint_health < ZERO_I80F48 {
-            liqee_ma.is_bankrupt =
-                liqee_ma.check_enter_bankruptcy(&lyrae_group, liqee_open_orders_ais);
-        } else {
-            let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-            // this is equivalent to one native USDC or 1e-6 USDC
-            // This is used as threshold to flip flag instead of 0 because of dust issues
-            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
+        let a = &mut lyrae_account_a.perp_accounts[market_index];
+        let b = &mut lyrae_account_b.perp_accounts[market_index];
+
+        // Account for unrealized funding payments before settling
+        a.settle_funding(perp_market_cache);
+        b.settle_funding(perp_market_cache);
+
+        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
+        let new_quote_pos_a = I80F48::from_num(-a.base_position * contract_size) * price;
+        let new_quote_pos_b = I80F48::from_num(-b.base_position * contract_size) * price;
+        let a_pnl: I80F48 = a.quote_position - new_quote_pos_a;
+        let b_pnl: I80F48 = b.quote_position - new_quote_pos_b;
+
+        // pnl must be opposite signs for there to be a settlement
+        if a_pnl * b_pnl > 0 {
+            return Ok(());
         }
 
-        lyrae_emit!(LiquidateTokenAndPerpLog {
+        let settlement = a_pnl.abs().min(b_pnl.abs());
+        let a_settle = if a_pnl > 0 { settlement } else { -settlement };
+        a.transfer_quote_position(b, a_settle);
+
+        transfer_token_internal(
+            &root_bank_cache,
+            &mut node_bank,
+            &mut lyrae_account_b,
+            &mut lyrae_account_a,
+            lyrae_account_b_ai.key,
+            lyrae_account_a_ai.key,
+            QUOTE_INDEX,
+            a_settle,
+        )?;
+
+        lyrae_emit!(SettlePnlLog {
             lyrae_group: *lyrae_group_ai.key,
-            liqee: *liqee_lyrae_account_ai.key,
-            liqor: *liqor_lyrae_account_ai.key,
-            asset_index: asset_index as u64,
-            liab_index: liab_index as u64,
-            asset_type: asset_type as u8,
-            liab_type: liab_type as u8,
-            asset_transfer: asset_transfer.to_bits(),
-            liab_transfer: actual_liab_transfer.to_bits(),
-            asset_price: asset_price.to_bits(),
-            liab_price: liab_price.to_bits(),
-            bankruptcy: liqee_ma.is_bankrupt,
+            lyrae_account_a: *lyrae_account_a_ai.key,
+            lyrae_account_b: *lyrae_account_b_ai.key,
+            market_index: market_index as u64,
+            settlement: a_settle.to_bits(), // Will be positive if a has positive pnl and settling with b
         });
-
-        let perp_market_index: usize;
-        if asset_type == AssetType::Token {
-            perp_market_index = liab_index;
-        } else {
-            perp_market_index = asset_index;
-        }
         emit_perp_balances(
             *lyrae_group_ai.key,
-            *liqee_lyrae_account_ai.key,
-            perp_market_index as u64,
-            &liqee_ma.perp_accounts[perp_market_index],
-            &lyrae_cache.perp_market_cache[perp_market_index],
+            *lyrae_account_a_ai.key,
+            market_index as u64,
+            &lyrae_account_a.perp_accounts[market_index],
+            perp_market_cache,
         );
         emit_perp_balances(
             *lyrae_group_ai.key,
-            *liqor_lyrae_account_ai.key,
-            perp_market_index as u64,
-            &liqor_ma.perp_accounts[perp_market_index],
-            &lyrae_cache.perp_market_cache[perp_market_index],
+            *lyrae_account_b_ai.key,
+            market_index as u64,
+            &lyrae_account_b.perp_accounts[market_index],
+            perp_market_cache,
         );
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Reduce some of the base position in exchange for quote position in this market
-    /// Transfer will not exceed abs(base_position)
-    /// Example:
-    ///     BTC/USD price 9.4k
-    ///     liquidation_fee = 0.025
-    ///     liqee initial
-    ///         USDC deposit 10k
-    ///         BTC-PERP base_position = 10
-    ///         BTC-PERP quote_position = -100k
-    ///         maint_health = -700
-    ///         init_health = -5400
-    ///     liqee after liquidate_perp_market
-    ///         USDC deposit 10k
-    ///         BTC-PERP base_position = 2.3404
-    ///         BTC-PERP quote_position = -29799.766
-    ///         init_health = 0.018
-    ///     liqor after liquidate_perp_market
-    ///         BTC-PERP base_position = 7.6596
-    ///         BTC-PERP quote_position = -70200.234
-    fn liquidate_perp_market(
+    /// Opt the LyraeAccount in or out of crank_auto_settle_funding
+    fn set_auto_settle_funding(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        base_transfer_request: i64,
+        auto_settle_funding: bool,
     ) -> LyraeResult<()> {
-        // TODO OPT find a way to send in open orders accounts without zero keys
-        // liqor passes in his own account and the liqee lyrae account
-        // position is transfered to the liqor at favorable rate
-        check!(base_transfer_request != 0, LyraeErrorCode::InvalidParam)?;
-        const NUM_FIXED: usize = 7;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
-        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
-            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+        ] = accounts;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+
+        lyrae_account.auto_settle_funding = auto_settle_funding;
+        Ok(())
+    }
 
+    #[inline(never)]
+    /// Settle the positive pnl of opted in accounts against a counterparty with negative pnl
+    fn crank_auto_settle_funding(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        market_index: usize,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 5;
+        let (fixed_ais, lyrae_account_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            lyrae_group_ai,         // read
-            lyrae_cache_ai,         // read
-            perp_market_ai,         // write
-            event_queue_ai,         // write
-            liqee_lyrae_account_ai, // write
-            liqor_lyrae_account_ai, // write
-            liqor_ai,               // read, signer
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+            counterparty_ai,    // write
         ] = fixed_ais;
+        check!(
+            lyrae_account_ais.len() <= MAX_AUTO_SETTLE_ACCOUNTS,
+            LyraeErrorCode::InvalidParam
+        )?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(market_index < MAX_PAIRS, LyraeErrorCode::InvalidMarket)?;
+        check!(!lyrae_group.perp_markets[market_index].is_empty(), LyraeErrorCode::InvalidMarket)?;
+
+        let mut counterparty =
+            LyraeAccount::load_mut_checked(counterparty_ai, program_id, lyrae_group_ai.key)?;
+        check!(!counterparty.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+
+        match lyrae_group.find_root_bank_index(root_bank_ai.key) {
+            None => return Err(throw_err!(LyraeErrorCode::InvalidRootBank)),
+            Some(i) => check!(i == QUOTE_INDEX, LyraeErrorCode::InvalidRootBank)?,
+        }
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+
         let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-        let mut liqee_ma =
-            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
+        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        let price_cache = &lyrae_cache.price_cache[market_index];
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
 
-        let mut liqor_ma =
-            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(
-            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
+        root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        price_cache.check_valid(&lyrae_group, now_ts)?;
+        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
-        let pmi = &lyrae_group.perp_markets[market_index];
-        check!(!pmi.is_empty(), LyraeErrorCode::InvalidMarket)?;
-        let mut event_queue: EventQueue =
-            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+        let price = price_cache.price;
+        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
 
-        // Move funding into quote position. Not necessary to adjust funding settled after funding is moved
-        let cache = &lyrae_cache.perp_market_cache[market_index];
+        let b = &mut counterparty.perp_accounts[market_index];
+        b.settle_funding(perp_market_cache);
+        let new_quote_pos_b = I80F48::from_num(-b.base_position * contract_size) * price;
+        let mut b_loss = -(b.quote_position - new_quote_pos_b);
+        check!(b_loss.is_positive(), LyraeErrorCode::InvalidAccountState)?;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
-        let liqor_active_assets =
-            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Perp, market_index)]);
+        for lyrae_account_ai in lyrae_account_ais.iter() {
+            if !b_loss.is_positive() {
+                break;
+            }
+            check!(lyrae_account_ai.key != counterparty_ai.key, LyraeErrorCode::InvalidParam)?;
 
-        lyrae_cache.check_valid(
-            &lyrae_group,
-            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
-            now_ts,
-        )?;
-        liqee_ma.perp_accounts[market_index].settle_funding(cache);
-        liqor_ma.perp_accounts[market_index].settle_funding(cache);
+            let mut lyrae_account =
+                LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+            if !lyrae_account.auto_settle_funding || lyrae_account.is_bankrupt {
+                continue;
+            }
 
-        // Make sure orders are cancelled for perps before liquidation
-        for i in 0..lyrae_group.num_oracles {
-            if liqee_active_assets.perps[i] {
-                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
+            let a = &mut lyrae_account.perp_accounts[market_index];
+            a.settle_funding(perp_market_cache);
+            let new_quote_pos_a = I80F48::from_num(-a.base_position * contract_size) * price;
+            let a_pnl: I80F48 = a.quote_position - new_quote_pos_a;
+            if !a_pnl.is_positive() {
+                continue;
             }
-        }
 
-        let mut health_cache = HealthCache::new(liqee_active_assets);
-        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
-        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+            let settlement = a_pnl.min(b_loss);
+            b_loss -= settlement;
+            a.transfer_quote_position(&mut counterparty.perp_accounts[market_index], settlement);
 
-        if liqee_ma.being_liquidated {
-            if init_health > ZERO_I80F48 {
-                liqee_ma.being_liquidated = false;
-                msg!("Account init_health above zero.");
-                return Ok(());
-            }
-        } else if maint_health >= ZERO_I80F48 {
-            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
-        } else {
-            liqee_ma.being_liquidated = true;
+            transfer_token_internal(
+                &root_bank_cache,
+                &mut node_bank,
+                &mut counterparty,
+                &mut lyrae_account,
+                counterparty_ai.key,
+                lyrae_account_ai.key,
+                QUOTE_INDEX,
+                settlement,
+            )?;
+
+            lyrae_emit!(SettlePnlLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account_a: *lyrae_account_ai.key,
+                lyrae_account_b: *counterparty_ai.key,
+                market_index: market_index as u64,
+                settlement: settlement.to_bits(),
+            });
+            emit_perp_balances(
+                *lyrae_group_ai.key,
+                *lyrae_account_ai.key,
+                market_index as u64,
+                &lyrae_account.perp_accounts[market_index],
+                perp_market_cache,
+            );
         }
 
-        // TODO - what happens if base position and quote position have same sign?
-        // TODO - what if base position is 0 but quote is negative. Perhaps settle that pnl first?
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *counterparty_ai.key,
+            market_index as u64,
+            &counterparty.perp_accounts[market_index],
+            perp_market_cache,
+        );
 
-        let liqee_perp_account = &mut liqee_ma.perp_accounts[market_index];
-        let liqor_perp_account = &mut liqor_ma.perp_accounts[market_index];
+        Ok(())
+    }
 
-        let price = lyrae_cache.price_cache[market_index].price;
-        let lot_price = price * I80F48::from_num(pmi.base_lot_size);
-        let (base_transfer, quote_transfer) = if liqee_perp_account.base_position > 0 {
-            check!(base_transfer_request > 0, LyraeErrorCode::InvalidParam)?;
+    #[inline(never)]
+    /// Take an account that has losses in the selected perp market to account for fees_accrued
+    fn settle_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 10;
+        let (fixed_ais, insurance_vault_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // write
+            lyrae_account_ai,   // write
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+            bank_vault_ai,      // write
+            fees_vault_ai,      // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = fixed_ais;
+        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
-            let health_per_lot =
-                lot_price * (ONE_I80F48 - pmi.init_asset_weight - pmi.liquidation_fee);
-            let max_transfer = -init_health / health_per_lot;
-            let max_transfer: i64 = max_transfer.checked_ceil().unwrap().checked_to_num().unwrap();
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(fees_vault_ai.key == &lyrae_group.fees_vault, LyraeErrorCode::InvalidVault)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
-            let base_transfer =
-                max_transfer.min(base_transfer_request).min(liqee_perp_account.base_position);
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
-            let quote_transfer = I80F48::from_num(-base_transfer * pmi.base_lot_size)
-                * price
-                * (ONE_I80F48 - pmi.liquidation_fee);
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
 
-            (base_transfer, quote_transfer)
-        } else {
-            // We know it liqee_perp_account.base_position < 0
-            check!(base_transfer_request < 0, LyraeErrorCode::InvalidParam)?;
+        check!(
+            &lyrae_group.tokens[QUOTE_INDEX].root_bank == root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
+        check!(bank_vault_ai.key == &node_bank.vault, LyraeErrorCode::InvalidVault)?;
 
-            let health_per_lot =
-                lot_price * (ONE_I80F48 - pmi.init_liab_weight + pmi.liquidation_fee);
-            let max_transfer = -init_health / health_per_lot;
-            let max_transfer: i64 = max_transfer.checked_floor().unwrap().checked_to_num().unwrap();
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-            let base_transfer =
-                max_transfer.max(base_transfer_request).max(liqee_perp_account.base_position);
-            let quote_transfer = I80F48::from_num(-base_transfer * pmi.base_lot_size)
-                * price
-                * (ONE_I80F48 + pmi.liquidation_fee);
+        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        let price_cache = &lyrae_cache.price_cache[market_index];
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
 
-            (base_transfer, quote_transfer)
-        };
+        root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        price_cache.check_valid(&lyrae_group, now_ts)?;
+        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
 
-        liqee_perp_account.change_base_position(&mut perp_market, -base_transfer);
-        liqor_perp_account.change_base_position(&mut perp_market, base_transfer);
+        let price = price_cache.price;
 
-        liqee_perp_account.transfer_quote_position(liqor_perp_account, quote_transfer);
+        let pa = &mut lyrae_account.perp_accounts[market_index];
+        pa.settle_funding(&perp_market_cache);
+        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
+        let new_quote_pos = I80F48::from_num(-pa.base_position * contract_size) * price;
+        let pnl: I80F48 = pa.quote_position - new_quote_pos;
+        check!(pnl.is_negative(), LyraeErrorCode::Default)?;
+        check!(perp_market.fees_accrued.is_positive(), LyraeErrorCode::Default)?;
 
-        // Log this to EventQueue
-        let liquidate_event = LiquidateEvent::new(
-            now_ts,
-            event_queue.header.seq_num,
-            *liqee_lyrae_account_ai.key,
-            *liqor_lyrae_account_ai.key,
-            price,
-            base_transfer,
-            pmi.liquidation_fee,
-        );
-        event_queue.push_back(cast(liquidate_event)).unwrap();
+        let settlement = pnl.abs().min(perp_market.fees_accrued).checked_floor().unwrap();
 
-        // Calculate the health of liqor and see if liqor is still valid
-        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
-        liqor_health_cache.init_vals(
+        perp_market.fees_accrued -= settlement;
+        pa.quote_position += settlement;
+
+        let (to_fees_vault, to_insurance_vault) =
+            lyrae_group.split_settled_fees(settlement.to_num());
+        transfer_settled_fees(
             &lyrae_group,
-            &lyrae_cache,
-            &liqor_ma,
-            liqor_open_orders_ais,
+            lyrae_group_ai.key,
+            token_prog_ai,
+            bank_vault_ai,
+            fees_vault_ai,
+            insurance_vault_ais.first(),
+            signer_ai,
+            to_fees_vault,
+            to_insurance_vault,
         )?;
-        let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
-        check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
 
-        health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, market_index)?;
-        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
-        if liqee_maint_health < ZERO_I80F48 {
-            liqee_ma.is_bankrupt =
-                liqee_ma.check_enter_bankruptcy(&lyrae_group, liqee_open_orders_ais);
-        } else {
-            let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-            // this is equivalent to one native USDC or 1e-6 USDC
-            // This is used as threshold to flip flag instead of 0 because of dust issues
-            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
-        }
+        // Decrement deposits on lyrae account
+        checked_change_net(
+            root_bank_cache,
+            &mut node_bank,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            QUOTE_INDEX,
+            -settlement,
+        )?;
 
-        lyrae_emit!(LiquidatePerpMarketLog {
+        lyrae_emit!(SettleFeesLog {
             lyrae_group: *lyrae_group_ai.key,
-            liqee: *liqee_lyrae_account_ai.key,
-            liqor: *liqor_lyrae_account_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
             market_index: market_index as u64,
-            price: price.to_bits(),
-            base_transfer,
-            quote_transfer: quote_transfer.to_bits(),
-            bankruptcy: liqee_ma.is_bankrupt
+            settlement: settlement.to_bits(),
+            to_fees_vault,
+            to_insurance_vault,
         });
+
         emit_perp_balances(
             *lyrae_group_ai.key,
-            *liqee_lyrae_account_ai.key,
-            market_index as u64,
-            &liqee_ma.perp_accounts[market_index],
-            &lyrae_cache.perp_market_cache[market_index],
-        );
-        emit_perp_balances(
-            *lyrae_group_ai.key,
-            *liqor_lyrae_account_ai.key,
+            *lyrae_account_ai.key,
             market_index as u64,
-            &liqor_ma.perp_accounts[market_index],
-            &lyrae_cache.perp_market_cache[market_index],
+            &lyrae_account.perp_accounts[market_index],
+            perp_market_cache,
         );
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Claim insurance fund and then socialize loss
-    fn resolve_perp_bankruptcy(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        liab_index: usize,
-        max_liab_transfer: I80F48,
-    ) -> LyraeResult<()> {
-        // First check the account is bankrupt
-        // Determine the value of the liab transfer
-        // Check if insurance fund has enough (given the fees)
-        // If insurance fund does not have enough, start the socialize loss function
-
-        // TODO - since liquidation fee is 0 for USDC, what's the incentive for someone to call this?
-        //  just add 1bp fee
-
-        // Do parameter checks
-        check!(liab_index < QUOTE_INDEX, LyraeErrorCode::InvalidParam)?;
-        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
-
-        const NUM_FIXED: usize = 12;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, liqor_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
-
+    /// Settle fees_accrued from a batch of LyraeAccounts, skipping the ones without negative pnl
+    fn crank_settle_fees(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, candidate_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            lyrae_group_ai,         // read
-            lyrae_cache_ai,         // write
-            liqee_lyrae_account_ai, // write
-            liqor_lyrae_account_ai, // write
-            liqor_ai,               // read, signer
-            root_bank_ai,           // read
-            node_bank_ai,           // write
-            vault_ai,               // write
-            insurance_vault_ai,     // write
-            signer_ai,              // read
-            perp_market_ai,         // write
-            token_prog_ai,          // read
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // write
+            root_bank_ai,       // read
+            node_bank_ai,       // write
+            bank_vault_ai,      // write
+            fees_vault_ai,      // write
+            signer_ai,          // read
+            token_prog_ai,      // read
         ] = fixed_ais;
         check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
-
-        let mut lyrae_cache =
-            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let mut liqee_ma =
-            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(liqee_ma.is_bankrupt, LyraeErrorCode::Default)?;
 
-        let mut liqor_ma =
-            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        // The insurance vault is only passed when part of the fees go there. It can't be a
+        // LyraeAccount, so its key tells it apart from the candidates.
+        let (insurance_vault_ai, lyrae_account_ais) = match candidate_ais.first() {
+            Some(ai) if ai.key == &lyrae_group.insurance_vault => (Some(ai), &candidate_ais[1..]),
+            _ => (None, candidate_ais),
+        };
         check!(
-            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
-            LyraeErrorCode::InvalidOwner
+            lyrae_account_ais.len() <= MAX_SETTLE_FEES_ACCOUNTS,
+            LyraeErrorCode::InvalidParam
         )?;
-        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
+        check!(fees_vault_ai.key == &lyrae_group.fees_vault, LyraeErrorCode::InvalidVault)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
 
-        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
         check!(
             &lyrae_group.tokens[QUOTE_INDEX].root_bank == root_bank_ai.key,
             LyraeErrorCode::InvalidRootBank
         )?;
-
-        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
         let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
-        check!(vault_ai.key == &node_bank.vault, LyraeErrorCode::InvalidVault)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
+        check!(bank_vault_ai.key == &node_bank.vault, LyraeErrorCode::InvalidVault)?;
 
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
         let now_ts = Clock::get()?.unix_timestamp as u64;
-        let liqor_active_assets =
-            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Perp, liab_index)]);
 
-        lyrae_cache.check_valid(&lyrae_group, &liqor_active_assets, now_ts)?;
+        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        let price_cache = &lyrae_cache.price_cache[market_index];
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
 
-        check!(
-            insurance_vault_ai.key == &lyrae_group.insurance_vault,
-            LyraeErrorCode::InvalidVault
-        )?;
-        let insurance_vault = Account::unpack(&insurance_vault_ai.try_borrow_data()?)?;
+        root_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        price_cache.check_valid(&lyrae_group, now_ts)?;
+        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
 
-        let bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
-        let quote_pos = liqee_ma.perp_accounts[liab_index].quote_position;
-        check!(quote_pos.is_negative(), LyraeErrorCode::Default)?;
+        let price = price_cache.price;
+        let contract_size = lyrae_group.perp_markets[market_index].base_lot_size;
+        let mut total_to_fees_vault = 0u64;
+        let mut total_to_insurance_vault = 0u64;
 
-        let liab_transfer_u64 = max_liab_transfer
-            .min(-quote_pos) // minimum of what liqor wants and what liqee has
-            .checked_ceil() // round up and convert to native quote token
-            .unwrap()
-            .checked_to_num::<u64>()
-            .unwrap()
-            .min(insurance_vault.amount); // take min of what ins. fund has
+        for lyrae_account_ai in lyrae_account_ais.iter() {
+            if !perp_market.fees_accrued.is_positive() {
+                break;
+            }
 
-        if liab_transfer_u64 != 0 {
-            let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-            invoke_transfer(
-                token_prog_ai,
-                insurance_vault_ai,
-                vault_ai,
-                signer_ai,
-                &[&signers_seeds],
-                liab_transfer_u64,
-            )?;
-            let liab_transfer = I80F48::from_num(liab_transfer_u64);
-            liqee_ma.perp_accounts[liab_index]
-                .transfer_quote_position(&mut liqor_ma.perp_accounts[liab_index], -liab_transfer);
+            let mut lyrae_account =
+                LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+            if lyrae_account.is_bankrupt {
+                continue;
+            }
+
+            let pa = &mut lyrae_account.perp_accounts[market_index];
+            pa.settle_funding(&perp_market_cache);
+            let new_quote_pos = I80F48::from_num(-pa.base_position * contract_size) * price;
+            let pnl: I80F48 = pa.quote_position - new_quote_pos;
+            if !pnl.is_negative() {
+                continue;
+            }
+
+            let settlement = pnl.abs().min(perp_market.fees_accrued).checked_floor().unwrap();
+            if settlement.is_zero() {
+                continue;
+            }
 
+            perp_market.fees_accrued -= settlement;
+            pa.quote_position += settlement;
+
+            let (to_fees_vault, to_insurance_vault) =
+                lyrae_group.split_settled_fees(settlement.to_num());
+            total_to_fees_vault += to_fees_vault;
+            total_to_insurance_vault += to_insurance_vault;
+
+            // Decrement deposits on lyrae account
             checked_change_net(
-                bank_cache,
+                root_bank_cache,
                 &mut node_bank,
-                &mut liqor_ma,
-                liqor_lyrae_account_ai.key,
+                &mut lyrae_account,
+                lyrae_account_ai.key,
                 QUOTE_INDEX,
-                liab_transfer,
+                -settlement,
             )?;
 
-            // Make sure liqor is above init cond.
-            let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
-            liqor_health_cache.init_vals(
-                &lyrae_group,
-                &lyrae_cache,
-                &liqor_ma,
-                liqor_open_orders_ais,
-            )?;
+            lyrae_emit!(SettleFeesLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                settlement: settlement.to_bits(),
+                to_fees_vault,
+                to_insurance_vault,
+            });
 
-            let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
-            check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+            emit_perp_balances(
+                *lyrae_group_ai.key,
+                *lyrae_account_ai.key,
+                market_index as u64,
+                &lyrae_account.perp_accounts[market_index],
+                perp_market_cache,
+            );
         }
 
-        let quote_position = liqee_ma.perp_accounts[liab_index].quote_position;
-        // If we transferred everything out of insurance_vault, insurance vault is empty
-        // and if quote position is still negative
-        let socialized_loss =
-            if liab_transfer_u64 == insurance_vault.amount && quote_position.is_negative() {
-                // insurance fund empty so socialize loss
-                check!(
-                    &lyrae_group.perp_markets[liab_index].perp_market == perp_market_ai.key,
-                    LyraeErrorCode::InvalidMarket
-                )?;
-                let mut perp_market =
-                    PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        transfer_settled_fees(
+            &lyrae_group,
+            lyrae_group_ai.key,
+            token_prog_ai,
+            bank_vault_ai,
+            fees_vault_ai,
+            insurance_vault_ai,
+            signer_ai,
+            total_to_fees_vault,
+            total_to_insurance_vault,
+        )?;
 
-                perp_market.socialize_loss(
-                    &mut liqee_ma.perp_accounts[liab_index],
-                    &mut lyrae_cache.perp_market_cache[liab_index],
-                )?
-            } else {
-                ZERO_I80F48
-            };
+        Ok(())
+    }
 
-        liqee_ma.is_bankrupt = !liqee_ma.check_exit_bankruptcy(&lyrae_group);
+    #[inline(never)]
+    /// Redeem the accrued LYR of a batch of LyraeAccounts into their LYR deposits
+    fn crank_redeem_lyr(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, lyrae_account_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // read
+            lyr_perp_vault_ai,  // write
+            lyr_root_bank_ai,   // read
+            lyr_node_bank_ai,   // write
+            lyr_bank_vault_ai,  // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = fixed_ais;
+        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+        check!(
+            lyrae_account_ais.len() <= MAX_REDEEM_LYR_ACCOUNTS,
+            LyraeErrorCode::InvalidParam
+        )?;
 
-        lyrae_emit!(PerpBankruptcyLog {
-            lyrae_group: *lyrae_group_ai.key,
-            liqee: *liqee_lyrae_account_ai.key,
-            liqor: *liqor_lyrae_account_ai.key,
-            liab_index: liab_index as u64,
-            insurance_transfer: liab_transfer_u64,
-            socialized_loss: socialized_loss.to_bits(),
-            cache_long_funding: lyrae_cache.perp_market_cache[liab_index].long_funding.to_bits(),
-            cache_short_funding: lyrae_cache.perp_market_cache[liab_index].short_funding.to_bits()
-        });
-        emit_perp_balances(
-            *lyrae_group_ai.key,
-            *liqee_lyrae_account_ai.key,
-            liab_index as u64,
-            &liqee_ma.perp_accounts[liab_index],
-            &lyrae_cache.perp_market_cache[liab_index],
-        );
-        emit_perp_balances(
-            *lyrae_group_ai.key,
-            *liqor_lyrae_account_ai.key,
-            liab_index as u64,
-            &liqor_ma.perp_accounts[liab_index],
-            &lyrae_cache.perp_market_cache[liab_index],
-        );
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let lyr_index = lyrae_group
+            .find_root_bank_index(lyr_root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let lyr_bank_cache = &lyrae_cache.root_bank_cache[lyr_index];
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        lyr_bank_cache.check_valid(&lyrae_group, now_ts)?;
+
+        // Load the lyr banks
+        let root_bank = RootBank::load_checked(lyr_root_bank_ai, program_id)?;
+        check!(
+            root_bank.node_banks.contains(lyr_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut lyr_node_bank = NodeBank::load_mut_checked(lyr_node_bank_ai, program_id)?;
+        check_eq!(&lyr_node_bank.vault, lyr_bank_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        check!(lyr_perp_vault_ai.key == &perp_market.lyr_vault, LyraeErrorCode::InvalidVault)?;
+
+        let mut lyr_available = Account::unpack(&lyr_perp_vault_ai.try_borrow_data()?)?.amount;
+        let mut total_lyr = 0u64;
+
+        for lyrae_account_ai in lyrae_account_ais.iter() {
+            if lyr_available == 0 {
+                break;
+            }
+
+            let mut lyrae_account =
+                LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+            if lyrae_account.is_bankrupt {
+                continue;
+            }
+            let perp_account = &mut lyrae_account.perp_accounts[market_index];
+            if perp_account.lyr_accrued == 0 {
+                continue;
+            }
+
+            let lyr = min(perp_account.lyr_accrued, lyr_available);
+            perp_account.lyr_accrued -= lyr;
+            lyr_available -= lyr;
+            total_lyr += lyr;
+
+            checked_change_net(
+                lyr_bank_cache,
+                &mut lyr_node_bank,
+                &mut lyrae_account,
+                lyrae_account_ai.key,
+                lyr_index,
+                I80F48::from_num(lyr),
+            )?;
+
+            lyrae_emit!(RedeemLyrLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                redeemed_lyr: lyr,
+            });
+        }
+
+        if total_lyr > 0 {
+            let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+            invoke_transfer(
+                token_prog_ai,
+                lyr_perp_vault_ai,
+                lyr_bank_vault_ai,
+                signer_ai,
+                &[&signers_seeds],
+                total_lyr,
+            )?;
+        }
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Claim insurance fund and then socialize loss
-    fn resolve_token_bankruptcy(
+    fn force_cancel_spot_orders(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        max_liab_transfer: I80F48, // in native token terms
+        limit: u8,
     ) -> LyraeResult<()> {
-        // First check the account is bankrupt
-        // Determine the value of the liab transfer
-        // Check if insurance fund has enough (given the fees)
-        // If insurance fund does not have enough, start the socialize loss function
-        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
-
-        const NUM_FIXED: usize = 13;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS + MAX_NODE_BANKS];
-        let (
-            fixed_ais,
-            liqor_open_orders_ais, // read
-            liab_node_bank_ais,    // write
-        ) = array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_NODE_BANKS];
+        const NUM_FIXED: usize = 19;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
 
         let [
             lyrae_group_ai,         // read
-            lyrae_cache_ai,         // write
+            lyrae_cache_ai,         // read
             liqee_lyrae_account_ai, // write
-            liqor_lyrae_account_ai, // write
-            liqor_ai,               // read, signer
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            base_vault_ai,          // write
             quote_root_bank_ai,     // read
             quote_node_bank_ai,     // write
             quote_vault_ai,         // write
-            insurance_vault_ai,     // write
+
+            spot_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
             signer_ai,              // read
-            liab_root_bank_ai,      // write
-            liab_node_bank_ai,      // write
+            dex_event_queue_ai,     // write
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            dex_signer_ai,          // read
+            dex_prog_ai,            // read
             token_prog_ai,          // read
         ] = fixed_ais;
+
+        // Check token program id
         check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
         check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
-        let mut lyrae_cache =
-            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-
-        // Load the liqee's lyrae account
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
         let mut liqee_ma =
             LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(liqee_ma.is_bankrupt, LyraeErrorCode::Default)?;
-
-        // Load the liqor's lyrae account
-        let mut liqor_ma =
-            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &liqor_ma.owner == liqor_ai.key || &liqor_ma.delegate == liqor_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
-
-        // Load the bank for liab token
-        let liab_index = lyrae_group
-            .find_root_bank_index(liab_root_bank_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
-        let mut liab_root_bank = RootBank::load_mut_checked(liab_root_bank_ai, program_id)?;
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let liqor_active_assets =
-            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Token, liab_index)]);
+        let market_index = lyrae_group.find_spot_market_index(spot_market_ai.key).unwrap();
+        check!(liqee_ma.in_margin_basket[market_index], LyraeErrorCode::Default)?;
 
-        lyrae_cache.check_valid(&lyrae_group, &liqor_active_assets, now_ts)?;
+        check_eq!(
+            &lyrae_group.tokens[market_index].root_bank,
+            base_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
 
-        // Load the insurance vault (insurance fund)
         check!(
-            insurance_vault_ai.key == &lyrae_group.insurance_vault,
-            LyraeErrorCode::InvalidVault
+            base_root_bank.node_banks.contains(base_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
         )?;
-        let insurance_vault = Account::unpack(&insurance_vault_ai.try_borrow_data()?)?;
-
-        // Make sure there actually exist liabs here
-        check!(liqee_ma.borrows[liab_index].is_positive(), LyraeErrorCode::Default)?;
-        let liab_price = lyrae_cache.get_price(liab_index);
-        let liab_fee = if liab_index == QUOTE_INDEX {
-            ONE_I80F48
-        } else {
-            let liab_info = &lyrae_group.spot_markets[liab_index];
-            ONE_I80F48 - liab_info.liquidation_fee
-        };
-
-        let liab_bank_cache = &lyrae_cache.root_bank_cache[liab_index];
-        let native_borrows = liqee_ma.get_native_borrow(liab_bank_cache, liab_index)?;
-
-        let insured_liabs = I80F48::from_num(insurance_vault.amount) * liab_fee / liab_price;
-        let liab_transfer = max_liab_transfer.min(native_borrows).min(insured_liabs);
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
+        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-        let insurance_transfer = (liab_transfer * liab_price / liab_fee)
-            .checked_ceil()
-            .unwrap()
-            .checked_to_num::<u64>()
-            .unwrap()
-            .min(insurance_vault.amount);
+        check_eq!(
+            &lyrae_group.tokens[QUOTE_INDEX].root_bank,
+            quote_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
 
-        if insurance_transfer != 0 {
-            // First transfer quote currency into Lyrae quote vault from insurance fund
-            check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
-            let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-            invoke_transfer(
-                token_prog_ai,
-                insurance_vault_ai,
-                quote_vault_ai, // this vault is checked in conditional branch below
-                signer_ai,
-                &[&signers_seeds],
-                insurance_transfer,
-            )?;
+        check!(
+            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
 
-            // Transfer equivalent amount of liabilities adjusted for fees
-            let liab_transfer = I80F48::from_num(insurance_transfer) * liab_fee / liab_price;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-            check!(
-                liab_root_bank.node_banks.contains(liab_node_bank_ai.key),
-                LyraeErrorCode::InvalidNodeBank
-            )?;
-            let mut liab_node_bank = NodeBank::load_mut_checked(liab_node_bank_ai, program_id)?;
+        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
 
-            // Only load quote banks if they are different from liab banks to prevent double mut borrow
-            if liab_index == QUOTE_INDEX {
-                check!(quote_vault_ai.key == &liab_node_bank.vault, LyraeErrorCode::InvalidVault)?;
+        lyrae_cache.check_valid(&lyrae_group, &liqee_active_assets, now_ts)?;
 
-                // Increase the quote balance on the liqor equivalent to insurance transfer
-                checked_change_net(
-                    &lyrae_cache.root_bank_cache[QUOTE_INDEX],
-                    &mut liab_node_bank,
-                    &mut liqor_ma,
-                    liqor_lyrae_account_ai.key,
-                    QUOTE_INDEX,
-                    I80F48::from_num(insurance_transfer),
-                )?;
-            } else {
-                // Load the bank for quote token which we now know is different from liab banks
-                let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
-                check!(
-                    &lyrae_group.tokens[QUOTE_INDEX].root_bank == quote_root_bank_ai.key,
-                    LyraeErrorCode::InvalidRootBank
-                )?;
-                let mut quote_node_bank =
-                    NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
-                check!(
-                    quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
-                    LyraeErrorCode::InvalidNodeBank
-                )?;
-                check!(quote_vault_ai.key == &quote_node_bank.vault, LyraeErrorCode::InvalidVault)?;
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-                checked_change_net(
-                    &lyrae_cache.root_bank_cache[QUOTE_INDEX],
-                    &mut quote_node_bank,
-                    &mut liqor_ma,
-                    liqor_lyrae_account_ai.key,
-                    QUOTE_INDEX,
-                    I80F48::from_num(insurance_transfer),
-                )?;
+        // Can only force cancel on an account already being liquidated
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
             }
+        } else if maint_health >= ZERO_I80F48 {
+            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
+        } else {
+            liqee_ma.being_liquidated = true;
+        }
 
-            // Liqor transfers to cancel out liability on liqee
-            transfer_token_internal(
-                liab_bank_cache,
-                &mut liab_node_bank,
-                &mut liqor_ma,
-                &mut liqee_ma,
-                liqor_lyrae_account_ai.key,
-                liqee_lyrae_account_ai.key,
-                liab_index,
-                liab_transfer,
-            )?;
+        // Cancel orders up to the limit
+        let open_orders_ai = &liqee_open_orders_ais[market_index];
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_cancel_orders(
+            open_orders_ai,
+            dex_prog_ai,
+            spot_market_ai,
+            bids_ai,
+            asks_ai,
+            signer_ai,
+            dex_event_queue_ai,
+            &[&signers_seeds],
+            limit,
+        )?;
 
-            // Make sure liqor is above init health
-            let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
-            liqor_health_cache.init_vals(
-                &lyrae_group,
-                &lyrae_cache,
-                &liqor_ma,
-                liqor_open_orders_ais,
-            )?;
-            let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
-            check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+        let (pre_base, pre_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            (
+                open_orders.native_coin_free,
+                open_orders
+                    .native_pc_free
+                    .checked_add(open_orders.referrer_rebates_accrued)
+                    .ok_or(math_err!())?,
+            )
+        };
+
+        if pre_base == 0 && pre_quote == 0 {
+            // margin basket may be in an invalid state; correct it before returning
+            let open_orders = load_open_orders(open_orders_ai)?;
+            liqee_ma.update_basket(market_index, &open_orders)?;
+            return Ok(());
         }
 
-        let (socialized_loss, percentage_loss) = if insurance_transfer == insurance_vault.amount
-            && liqee_ma.borrows[liab_index].is_positive()
-        {
-            // insurance fund empty so socialize loss
-            liab_root_bank.socialize_loss(
-                program_id,
-                liab_index,
-                &mut lyrae_cache,
-                &mut liqee_ma,
-                liab_node_bank_ais,
-            )?
-        } else {
-            (ZERO_I80F48, ZERO_I80F48)
+        // Settle funds released by canceling open orders. If this runs out of compute, cancel
+        // with a low limit and settle with ForceSettleFunds in a separate transaction.
+        invoke_settle_funds(
+            dex_prog_ai,
+            spot_market_ai,
+            open_orders_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            base_vault_ai,
+            quote_vault_ai,
+            dex_signer_ai,
+            token_prog_ai,
+            &[&signers_seeds],
+        )?;
+
+        let (post_base, post_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            liqee_ma.update_basket(market_index, &open_orders)?;
+            lyrae_emit!(OpenOrdersBalanceLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *liqee_lyrae_account_ai.key,
+                market_index: market_index as u64,
+                base_total: open_orders.native_coin_total,
+                base_free: open_orders.native_coin_free,
+                quote_total: open_orders.native_pc_total,
+                quote_free: open_orders.native_pc_free,
+                referrer_rebates_accrued: open_orders.referrer_rebates_accrued
+            });
+
+            (
+                open_orders.native_coin_free,
+                open_orders
+                    .native_pc_free
+                    .checked_add(open_orders.referrer_rebates_accrued)
+                    .ok_or(math_err!())?,
+            )
         };
 
-        liqee_ma.is_bankrupt = !liqee_ma.check_exit_bankruptcy(&lyrae_group);
+        check!(post_base <= pre_base, LyraeErrorCode::Default)?;
+        check!(post_quote <= pre_quote, LyraeErrorCode::Default)?;
 
-        lyrae_emit!(TokenBankruptcyLog {
-            lyrae_group: *lyrae_group_ai.key,
-            liqee: *liqee_lyrae_account_ai.key,
-            liqor: *liqor_lyrae_account_ai.key,
-            liab_index: liab_index as u64,
-            insurance_transfer,
-            socialized_loss: socialized_loss.to_bits(),
-            percentage_loss: percentage_loss.to_bits(),
-            cache_deposit_index: lyrae_cache.root_bank_cache[liab_index].deposit_index.to_bits()
-        });
+        // Update balances from settling funds
+        let base_change = I80F48::from_num(pre_base - post_base);
+        let quote_change = I80F48::from_num(pre_quote - post_quote);
 
-        Ok(())
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[market_index],
+            &mut base_node_bank,
+            &mut liqee_ma,
+            liqee_lyrae_account_ai.key,
+            market_index,
+            base_change,
+        )?;
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+            &mut quote_node_bank,
+            &mut liqee_ma,
+            liqee_lyrae_account_ai.key,
+            QUOTE_INDEX,
+            quote_change,
+        )
     }
 
     #[inline(never)]
-    /// *** Keeper Related Instructions ***
-    /// Update the deposit and borrow index on a passed in RootBank
-    fn update_root_bank(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 3;
-        let (fixed_accounts, node_bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
-        let [
-            lyrae_group_ai, // read
-            lyrae_cache_ai, // write
-            root_bank_ai,   // write
-        ] = fixed_accounts;
+    /// Settle the free funds of a liqee's open orders into the group vaults, without the cancel
+    /// step of force_cancel_spot_orders
+    fn force_settle_funds(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 17;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let mut lyrae_cache =
-            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let [
+            lyrae_group_ai,         // read
+            lyrae_cache_ai,         // read
+            liqee_lyrae_account_ai, // write
+            base_root_bank_ai,      // read
+            base_node_bank_ai,      // write
+            base_vault_ai,          // write
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            quote_vault_ai,         // write
 
-        let index = lyrae_group
-            .find_root_bank_index(root_bank_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+            spot_market_ai,         // write
+            open_orders_ai,         // write
+            signer_ai,              // read
+            dex_base_ai,            // write
+            dex_quote_ai,           // write
+            dex_signer_ai,          // read
+            dex_prog_ai,            // read
+            token_prog_ai,          // read
+        ] = accounts;
 
-        // TODO check root bank belongs to group in load functions
-        let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
-        check_eq!(root_bank.num_node_banks, node_bank_ais.len(), LyraeErrorCode::Default)?;
-        for i in 0..root_bank.num_node_banks {
-            check!(
-                node_bank_ais.iter().any(|ai| ai.key == &root_bank.node_banks[i]),
-                LyraeErrorCode::InvalidNodeBank
-            )?;
+        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check_eq!(dex_prog_ai.key, &lyrae_group.dex_program_id, LyraeErrorCode::InvalidProgramId)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+
+        // Only a liquidation, started by any of the liquidate or force cancel instructions, can
+        // move funds of an account without its owner's signature
+        check!(liqee_ma.being_liquidated, LyraeErrorCode::NotLiquidatable)?;
+
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        check!(liqee_ma.in_margin_basket[market_index], LyraeErrorCode::Default)?;
+        check_eq!(
+            &liqee_ma.spot_open_orders[market_index],
+            open_orders_ai.key,
+            LyraeErrorCode::InvalidOpenOrdersAccount
+        )?;
+        check_open_orders(open_orders_ai, &lyrae_group.signer_key, &lyrae_group.dex_program_id)?;
+
+        check_eq!(
+            &lyrae_group.tokens[market_index].root_bank,
+            base_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let base_root_bank = RootBank::load_checked(base_root_bank_ai, program_id)?;
+
+        check!(
+            base_root_bank.node_banks.contains(base_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut base_node_bank = NodeBank::load_mut_checked(base_node_bank_ai, program_id)?;
+        check_eq!(&base_node_bank.vault, base_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+
+        check_eq!(
+            &lyrae_group.tokens[QUOTE_INDEX].root_bank,
+            quote_root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
+        )?;
+        let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
+
+        check!(
+            quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut quote_node_bank = NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+        check_eq!(&quote_node_bank.vault, quote_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        lyrae_cache.root_bank_cache[market_index].check_valid(&lyrae_group, now_ts)?;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].check_valid(&lyrae_group, now_ts)?;
+
+        let (pre_base, pre_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            (
+                open_orders.native_coin_free,
+                open_orders
+                    .native_pc_free
+                    .checked_add(open_orders.referrer_rebates_accrued)
+                    .ok_or(math_err!())?,
+            )
+        };
+
+        if pre_base == 0 && pre_quote == 0 {
+            // margin basket may be in an invalid state; correct it before returning
+            let open_orders = load_open_orders(open_orders_ai)?;
+            liqee_ma.update_basket(market_index, &open_orders)?;
+            return Ok(());
         }
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
-        root_bank.update_index(node_bank_ais, program_id, now_ts)?;
 
-        lyrae_cache.root_bank_cache[index] = RootBankCache {
-            deposit_index: root_bank.deposit_index,
-            borrow_index: root_bank.borrow_index,
-            last_update: now_ts,
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_settle_funds(
+            dex_prog_ai,
+            spot_market_ai,
+            open_orders_ai,
+            signer_ai,
+            dex_base_ai,
+            dex_quote_ai,
+            base_vault_ai,
+            quote_vault_ai,
+            dex_signer_ai,
+            token_prog_ai,
+            &[&signers_seeds],
+        )?;
+
+        let (post_base, post_quote) = {
+            let open_orders = load_open_orders(open_orders_ai)?;
+            liqee_ma.update_basket(market_index, &open_orders)?;
+            lyrae_emit!(OpenOrdersBalanceLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *liqee_lyrae_account_ai.key,
+                market_index: market_index as u64,
+                base_total: open_orders.native_coin_total,
+                base_free: open_orders.native_coin_free,
+                quote_total: open_orders.native_pc_total,
+                quote_free: open_orders.native_pc_free,
+                referrer_rebates_accrued: open_orders.referrer_rebates_accrued
+            });
+
+            (
+                open_orders.native_coin_free,
+                open_orders
+                    .native_pc_free
+                    .checked_add(open_orders.referrer_rebates_accrued)
+                    .ok_or(math_err!())?,
+            )
         };
 
-        lyrae_emit!(UpdateRootBankLog {
-            lyrae_group: *lyrae_group_ai.key,
-            token_index: index as u64,
-            deposit_index: lyrae_cache.root_bank_cache[index].deposit_index.to_bits(),
-            borrow_index: lyrae_cache.root_bank_cache[index].borrow_index.to_bits()
-        });
+        check!(post_base <= pre_base, LyraeErrorCode::Default)?;
+        check!(post_quote <= pre_quote, LyraeErrorCode::Default)?;
 
-        Ok(())
+        // Update balances from settling funds
+        let base_change = I80F48::from_num(pre_base - post_base);
+        let quote_change = I80F48::from_num(pre_quote - post_quote);
+
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[market_index],
+            &mut base_node_bank,
+            &mut liqee_ma,
+            liqee_lyrae_account_ai.key,
+            market_index,
+            base_change,
+        )?;
+        checked_change_net(
+            &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+            &mut quote_node_bank,
+            &mut liqee_ma,
+            liqee_lyrae_account_ai.key,
+            QUOTE_INDEX,
+            quote_change,
+        )
     }
 
     #[inline(never)]
-    /// similar to serum dex, but also need to do some extra magic with funding
-    fn consume_events(
+    fn force_cancel_perp_orders(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        limit: usize,
+        limit: u8,
     ) -> LyraeResult<()> {
-        // Limit may be max 4 because of compute and memory limits from logging. Increase if compute/mem goes up
-        let limit = min(limit, 4);
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
 
-        const NUM_FIXED: usize = 4;
-        let (fixed_ais, lyrae_account_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            lyrae_group_ai,     // read
-            lyrae_cache_ai,     // read
-            perp_market_ai,     // write
-            event_queue_ai,     // write
+            lyrae_group_ai,         // read
+            lyrae_cache_ai,         // read
+            perp_market_ai,         // read
+            bids_ai,                // write
+            asks_ai,                // write
+            liqee_lyrae_account_ai, // write
         ] = fixed_ais;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
         let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-        let mut event_queue: EventQueue =
-            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
-        let now_ts = Clock::get()?.unix_timestamp as u64;
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
-        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
 
-        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let perp_market_info = &lyrae_group.perp_markets[market_index];
+        check!(!perp_market_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
 
-        for _ in 0..limit {
-            let event = match event_queue.peek_front() {
-                None => break,
-                Some(e) => e,
-            };
+        let now_ts = Clock::get()?.unix_timestamp as u64;
 
-            match EventType::try_from(event.event_type).map_err(|_| throw!())? {
-                EventType::Fill => {
-                    let fill: &FillEvent = cast_ref(event);
+        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
 
-                    // handle self trade separately because of rust borrow checker
-                    if fill.maker == fill.taker {
-                        let mut ma = match lyrae_account_ais.iter().find(|ai| ai.key == &fill.maker)
-                        {
-                            None => {
-                                msg!("Unable to find account {}", fill.maker.to_string());
-                                return Ok(());
-                            }
-                            Some(account_info) => LyraeAccount::load_mut_checked(
-                                account_info,
-                                program_id,
-                                lyrae_group_ai.key,
-                            )?,
-                        };
-                        let pre_lyr = ma.perp_accounts[market_index].lyr_accrued;
-                        ma.execute_maker(market_index, &mut perp_market, perp_market_cache, fill)?;
-                        ma.execute_taker(market_index, &mut perp_market, perp_market_cache, fill)?;
-                        lyrae_emit!(LyrAccrualLog {
-                            lyrae_group: *lyrae_group_ai.key,
-                            lyrae_account: fill.maker,
-                            market_index: market_index as u64,
-                            lyr_accrual: ma.perp_accounts[market_index].lyr_accrued - pre_lyr
-                        });
-                        emit_perp_balances(
-                            *lyrae_group_ai.key,
-                            fill.maker,
-                            market_index as u64,
-                            &ma.perp_accounts[market_index],
-                            &lyrae_cache.perp_market_cache[market_index],
-                        );
-                    } else {
-                        let mut maker =
-                            match lyrae_account_ais.iter().find(|ai| ai.key == &fill.maker) {
-                                None => {
-                                    msg!("Unable to find maker account {}", fill.maker.to_string());
-                                    return Ok(());
-                                }
-                                Some(account_info) => LyraeAccount::load_mut_checked(
-                                    account_info,
-                                    program_id,
-                                    lyrae_group_ai.key,
-                                )?,
-                            };
-                        let mut taker =
-                            match lyrae_account_ais.iter().find(|ai| ai.key == &fill.taker) {
-                                None => {
-                                    msg!("Unable to find taker account {}", fill.taker.to_string());
-                                    return Ok(());
-                                }
-                                Some(account_info) => LyraeAccount::load_mut_checked(
-                                    account_info,
-                                    program_id,
-                                    lyrae_group_ai.key,
-                                )?,
-                            };
-                        let pre_lyr = maker.perp_accounts[market_index].lyr_accrued;
-
-                        maker.execute_maker(
-                            market_index,
-                            &mut perp_market,
-                            perp_market_cache,
-                            fill,
-                        )?;
-                        taker.execute_taker(
-                            market_index,
-                            &mut perp_market,
-                            perp_market_cache,
-                            fill,
-                        )?;
-                        lyrae_emit!(LyrAccrualLog {
-                            lyrae_group: *lyrae_group_ai.key,
-                            lyrae_account: fill.maker,
-                            market_index: market_index as u64,
-                            lyr_accrual: maker.perp_accounts[market_index].lyr_accrued - pre_lyr
-                        });
-                        emit_perp_balances(
-                            *lyrae_group_ai.key,
-                            fill.maker,
-                            market_index as u64,
-                            &maker.perp_accounts[market_index],
-                            &lyrae_cache.perp_market_cache[market_index],
-                        );
-                        emit_perp_balances(
-                            *lyrae_group_ai.key,
-                            fill.taker,
-                            market_index as u64,
-                            &taker.perp_accounts[market_index],
-                            &lyrae_cache.perp_market_cache[market_index],
-                        );
-                    }
-                    lyrae_emit!(fill.to_fill_log(*lyrae_group_ai.key, market_index));
-                }
-                EventType::Out => {
-                    let out: &OutEvent = cast_ref(event);
+        lyrae_cache.check_valid(&lyrae_group, &liqee_active_assets, now_ts)?;
 
-                    let mut ma = match lyrae_account_ais.iter().find(|ai| ai.key == &out.owner) {
-                        None => {
-                            msg!("Unable to find account {}", out.owner.to_string());
-                            return Ok(());
-                        }
-                        Some(account_info) => LyraeAccount::load_mut_checked(
-                            account_info,
-                            program_id,
-                            lyrae_group_ai.key,
-                        )?,
-                    };
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-                    ma.remove_order(out.slot as usize, out.quantity)?;
-                }
-                EventType::Liquidate => {
-                    // This is purely for record keeping. Can be removed if program logs are superior
-                }
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
             }
-
-            // consume this event
-            event_queue.pop_front().map_err(|_| throw!())?;
+        } else if maint_health >= ZERO_I80F48 {
+            msg!(
+                "maint health {} init health {}",
+                maint_health.to_num::<f64>(),
+                init_health.to_num::<f64>()
+            );
+            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
+        } else {
+            liqee_ma.being_liquidated = true;
         }
-        Ok(())
+
+        // No liquidity mining incentives are paid out on force cancel
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        book.cancel_all(&mut liqee_ma, market_index, limit)
     }
 
     #[inline(never)]
-    /// Update the `funding_earned` of a `PerpMarket` using the current book price, spot index price
-    /// and time since last update
-    fn update_funding(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 5;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_cache_ai,     // write
-            perp_market_ai,     // write
-            bids_ai,            // read
-            asks_ai,            // read
-        ] = accounts;
+    /// Liquidator takes some of borrows at token at `liab_index` and receives some deposits from
+    /// the token at `asset_index`
+    /// Requires: `liab_index != asset_index`
+    fn liquidate_token_and_token(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_liab_transfer: I80F48,
+        max_health_fraction: I80F48,
+    ) -> LyraeResult<()> {
+        // parameter checks
+        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
+        check!(
+            max_health_fraction.is_positive() && max_health_fraction <= ONE_I80F48,
+            LyraeErrorCode::InvalidParam
+        )?;
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-
-        let mut lyrae_cache =
-            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-
-        let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
-
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
-
-        perp_market.update_funding(&lyrae_group, &book, &lyrae_cache, market_index, now_ts)?;
-        lyrae_cache.perp_market_cache[market_index] = PerpMarketCache {
-            long_funding: perp_market.long_funding,
-            short_funding: perp_market.short_funding,
-            last_update: now_ts,
-        };
-
-        // only need to use UpdateFundingLog; don't worry about CachePerpMarket log
-        lyrae_emit!(UpdateFundingLog {
-            lyrae_group: *lyrae_group_ai.key,
-            market_index: market_index as u64,
-            long_funding: perp_market.long_funding.to_bits(),
-            short_funding: perp_market.short_funding.to_bits(),
-        });
-
-        Ok(())
-    }
+        const NUM_FIXED: usize = 9;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
 
-    #[inline(never)]
-    /// Settle the lyr_accrued in a PerpAccount for LYR tokens
-    fn redeem_lyr(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 11;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
-            lyrae_group_ai,     // read
-            lyrae_cache_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // read
-            lyr_perp_vault_ai, // write
-        lyr_root_bank_ai,  // read
-        lyr_node_bank_ai,  // write
-        lyr_bank_vault_ai, // write
-            signer_ai,          // read
-            token_prog_ai,      // read
-        ] = accounts;
-        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+            lyrae_group_ai,         // read
+            lyrae_cache_ai,         // read
+            liqee_lyrae_account_ai, // write
+            liqor_lyrae_account_ai, // write
+            liqor_ai,               // read, signer
+            asset_root_bank_ai,     // read
+            asset_node_bank_ai,     // write
+            liab_root_bank_ai,      // read
+            liab_node_bank_ai,      // write
+        ] = fixed_ais;
+        check!(
+            liqee_lyrae_account_ai.key != liqor_lyrae_account_ai.key,
+            LyraeErrorCode::InvalidParam
+        )?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
-
-        let market_index = lyrae_group
-            .find_perp_market_index(perp_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
-        let lyr_index = lyrae_group
-            .find_root_bank_index(lyr_root_bank_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
-
         let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        let lyr_bank_cache = &lyrae_cache.root_bank_cache[lyr_index];
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let mut liqor_ma =
+            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            liqor_ma.is_owner_or_delegate(liqor_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-
-        let perp_account = &mut lyrae_account.perp_accounts[market_index];
+        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
 
-        // Load the lyr banks
-        let root_bank = RootBank::load_checked(lyr_root_bank_ai, program_id)?;
+        let asset_root_bank = RootBank::load_checked(asset_root_bank_ai, program_id)?;
+        let asset_index = lyrae_group.find_root_bank_index(asset_root_bank_ai.key).unwrap();
+        let mut asset_node_bank = NodeBank::load_mut_checked(asset_node_bank_ai, program_id)?;
         check!(
-            root_bank.node_banks.contains(lyr_node_bank_ai.key),
+            asset_root_bank.node_banks.contains(asset_node_bank_ai.key),
             LyraeErrorCode::InvalidNodeBank
         )?;
-        let mut lyr_node_bank = NodeBank::load_mut_checked(lyr_node_bank_ai, program_id)?;
-        check_eq!(&lyr_node_bank.vault, lyr_bank_vault_ai.key, LyraeErrorCode::InvalidVault)?;
-
-        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
-        check!(lyr_perp_vault_ai.key == &perp_market.lyr_vault, LyraeErrorCode::InvalidVault)?;
 
-        let lyr_perp_vault = Account::unpack(&lyr_perp_vault_ai.try_borrow_data()?)?;
-
-        let lyr = min(perp_account.lyr_accrued, lyr_perp_vault.amount);
-        perp_account.lyr_accrued -= lyr;
-
-        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_transfer(
-            token_prog_ai,
-            lyr_perp_vault_ai,
-            lyr_bank_vault_ai,
-            signer_ai,
-            &[&signers_seeds],
-            lyr,
-        )?;
+        let liab_root_bank = RootBank::load_checked(liab_root_bank_ai, program_id)?;
+        let liab_index = lyrae_group.find_root_bank_index(liab_root_bank_ai.key).unwrap();
+        let mut liab_node_bank = NodeBank::load_mut_checked(liab_node_bank_ai, program_id)?;
+        check!(liab_root_bank.node_banks.contains(liab_node_bank_ai.key), LyraeErrorCode::Default)?;
+        check!(asset_index != liab_index, LyraeErrorCode::InvalidParam)?;
 
         let now_ts = Clock::get()?.unix_timestamp as u64;
-        lyr_bank_cache.check_valid(&lyrae_group, now_ts)?;
+        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
+        let liqor_active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &liqor_ma,
+            vec![(AssetType::Token, asset_index), (AssetType::Token, liab_index)],
+        );
 
-        let redeemed_lyr = I80F48::from_num(lyr);
-        checked_change_net(
-            lyr_bank_cache,
-            &mut lyr_node_bank,
-            &mut lyrae_account,
-            lyrae_account_ai.key,
-            lyr_index,
-            redeemed_lyr,
+        lyrae_cache.check_valid(
+            &lyrae_group,
+            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
+            now_ts,
         )?;
 
-        lyrae_emit!(RedeemLyrLog {
-            lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
-            market_index: market_index as u64,
-            redeemed_lyr: lyr,
-        });
+        // Make sure orders are cancelled for perps and check orders
+        for i in 0..lyrae_group.num_oracles {
+            if liqee_active_assets.perps[i] {
+                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
+            }
+        }
 
-        Ok(())
-    }
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-    #[inline(never)]
-    fn add_lyrae_account_info(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        info: [u8; INFO_LEN],
-    ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 3;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai            // signer
-        ] = accounts;
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
+            }
+        } else if maint_health >= ZERO_I80F48 {
+            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
+        } else {
+            liqee_ma.being_liquidated = true;
+        }
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(liqee_ma.deposits[asset_index].is_positive(), LyraeErrorCode::Default)?;
+        check!(liqee_ma.borrows[liab_index].is_positive(), LyraeErrorCode::Default)?;
 
-        lyrae_account.info = info;
-        Ok(())
-    }
+        let asset_bank = &lyrae_cache.root_bank_cache[asset_index];
+        let liab_bank = &lyrae_cache.root_bank_cache[liab_index];
 
-    #[inline(never)]
-    fn deposit_msrm(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        quantity: u64,
-    ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            msrm_account_ai,    // write
-            msrm_vault_ai,      // write
-            token_prog_ai,      // read
-        ] = accounts;
-        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+        let asset_price = lyrae_cache.get_price(asset_index);
+        let liab_price = lyrae_cache.get_price(liab_index);
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check!(msrm_vault_ai.key == &lyrae_group.msrm_vault, LyraeErrorCode::InvalidVault)?;
+        let (asset_liquidation_fee, init_asset_weight) = if asset_index == QUOTE_INDEX {
+            (ZERO_I80F48, ONE_I80F48)
+        } else {
+            let asset_info = &lyrae_group.spot_markets[asset_index];
+            check!(!asset_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
+            (
+                lyrae_group.get_spot_liquidation_fee(asset_index),
+                asset_info.init_asset_weight,
+            )
+        };
+        let asset_fee = ONE_I80F48 + asset_liquidation_fee;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        let (liab_liquidation_fee, init_liab_weight) = if liab_index == QUOTE_INDEX {
+            (ZERO_I80F48, ONE_I80F48)
+        } else {
+            let liab_info = &lyrae_group.spot_markets[liab_index];
+            check!(!liab_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
+            (
+                lyrae_group.get_spot_liquidation_fee(liab_index),
+                liab_info.init_liab_weight,
+            )
+        };
+        let liab_fee = ONE_I80F48 - liab_liquidation_fee;
 
-        invoke_transfer(token_prog_ai, msrm_account_ai, msrm_vault_ai, owner_ai, &[], quantity)?;
+        // Max liab transferred to reach init_health == 0, scaled down for partial liquidations so
+        // the liqee never ends up above init_health == 0
+        let deficit_max_liab: I80F48 = -init_health * max_health_fraction
+            / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee));
 
-        lyrae_account.msrm_amount += quantity;
+        let native_deposits = liqee_ma.get_native_deposit(asset_bank, asset_index)?;
+        let native_borrows = liqee_ma.get_native_borrow(liab_bank, liab_index)?;
 
-        Ok(())
-    }
+        // Max liab transferred to reach asset_i == 0
+        let asset_implied_liab_transfer =
+            native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
+        let actual_liab_transfer = min(
+            min(min(deficit_max_liab, native_borrows), max_liab_transfer),
+            asset_implied_liab_transfer,
+        );
 
-    #[inline(never)]
-    fn withdraw_msrm(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        quantity: u64,
-    ) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 7;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            msrm_account_ai,    // write
-            msrm_vault_ai,      // write
-            signer_ai,          // read
-            token_prog_ai,      // read
-        ] = accounts;
-        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
-
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        check!(msrm_vault_ai.key == &lyrae_group.msrm_vault, LyraeErrorCode::InvalidVault)?;
-        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+        // Transfer into liqee to reduce liabilities
+        checked_change_net(
+            &liab_bank,
+            &mut liab_node_bank,
+            &mut liqee_ma,
+            liqee_lyrae_account_ai.key,
+            liab_index,
+            actual_liab_transfer,
+        )?; // TODO make sure deposits for this index is == 0
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        // Transfer from liqor
+        checked_change_net(
+            &liab_bank,
+            &mut liab_node_bank,
+            &mut liqor_ma,
+            liqor_lyrae_account_ai.key,
+            liab_index,
+            -actual_liab_transfer,
+        )?;
 
-        check!(lyrae_account.msrm_amount >= quantity, LyraeErrorCode::InsufficientFunds)?;
+        let asset_transfer =
+            actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
 
-        let signer_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
-        invoke_transfer(
-            token_prog_ai,
-            msrm_vault_ai,
-            msrm_account_ai,
-            signer_ai,
-            &[&signer_seeds],
-            quantity,
+        // Transfer collater into liqor
+        checked_change_net(
+            &asset_bank,
+            &mut asset_node_bank,
+            &mut liqor_ma,
+            liqor_lyrae_account_ai.key,
+            asset_index,
+            asset_transfer,
         )?;
 
-        lyrae_account.msrm_amount -= quantity;
-
-        Ok(())
-    }
+        // Transfer collateral out of liqee
+        checked_change_net(
+            &asset_bank,
+            &mut asset_node_bank,
+            &mut liqee_ma,
+            liqee_lyrae_account_ai.key,
+            asset_index,
+            -asset_transfer,
+        )?;
 
-    #[inline(never)]
-    fn set_group_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 3;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // write
-            new_admin_ai,       // read
-            admin_ai,           // read, signer
-        ] = accounts;
+        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+        liqor_health_cache.init_vals(
+            &lyrae_group,
+            &lyrae_cache,
+            &liqor_ma,
+            liqor_open_orders_ais,
+        )?;
+        let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
 
-        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        // Update liqee's health where it may have changed
+        for &i in &[asset_index, liab_index] {
+            health_cache.update_token_val(
+                &lyrae_group,
+                &lyrae_cache,
+                &liqee_ma,
+                liqee_open_orders_ais,
+                i,
+            )?;
+        }
+        let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+
+        // A liqor capping the transfer below what the liqee needs must still improve its health
+        // meaningfully; otherwise tiny liquidations can keep the account flagged indefinitely
+        let uncapped_liab_transfer =
+            min(min(deficit_max_liab, native_borrows), asset_implied_liab_transfer);
+        if max_liab_transfer < uncapped_liab_transfer {
+            let min_improvement = lyrae_group.get_min_liquidation_health_improvement();
+            check!(
+                liqee_init_health - init_health
+                    >= lyrae_group.quote_tokens_to_native(min_improvement),
+                LyraeErrorCode::LiquidationTooSmall
+            )?;
+        }
 
-        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        if liqee_maint_health < ZERO_I80F48 {
+            liqee_ma.is_bankrupt =
+                liqee_ma.check_enter_bankruptcy(&lyrae_group, liqee_open_orders_ais);
+        } else {
+            // this is equivalent to one native USDC or 1e-6 USDC
+            // This is used as threshold to flip flag instead of 0 because of dust issues
+            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
+        }
 
-        lyrae_group.admin = *new_admin_ai.key;
+        lyrae_emit!(LiquidateTokenAndTokenLog {
+            lyrae_group: *lyrae_group_ai.key,
+            liqee: *liqee_lyrae_account_ai.key,
+            liqor: *liqor_lyrae_account_ai.key,
+            asset_index: asset_index as u64,
+            liab_index: liab_index as u64,
+            asset_transfer: asset_transfer.to_bits(),
+            liab_transfer: actual_liab_transfer.to_bits(),
+            asset_price: asset_price.to_bits(),
+            liab_price: liab_price.to_bits(),
+            bankruptcy: liqee_ma.is_bankrupt,
+            asset_liquidation_fee: asset_liquidation_fee.to_bits(),
+            liab_liquidation_fee: liab_liquidation_fee.to_bits()
+        });
 
         Ok(())
     }
 
     #[inline(never)]
-    fn init_advanced_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 5;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+    /// swap tokens for perp quote position only and only if the base position in that market is 0
+    fn liquidate_token_and_perp(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        asset_type: AssetType,
+        asset_index: usize,
+        liab_type: AssetType,
+        liab_index: usize,
+        max_liab_transfer: I80F48,
+    ) -> LyraeResult<()> {
+        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
+        check!(asset_type != liab_type, LyraeErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+
         let [
             lyrae_group_ai,         // read
-            lyrae_account_ai,       // write
-            owner_ai,               // write & signer
-            advanced_orders_ai,     // write
-            system_prog_ai,         // read
-        ] = accounts;
+            lyrae_cache_ai,         // read
+            liqee_lyrae_account_ai, // write
+            liqor_lyrae_account_ai, // write
+            liqor_ai,               // read, signer
+            root_bank_ai,           // read
+            node_bank_ai,           // write
+        ] = fixed_ais;
         check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
+            liqee_lyrae_account_ai.key != liqor_lyrae_account_ai.key,
+            LyraeErrorCode::InvalidParam
         )?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
 
-        let _lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        // Caller supplied indexes; the quote token is the only token without an oracle
+        let index_in_range = |asset_type: AssetType, index: usize| match asset_type {
+            AssetType::Token => index == QUOTE_INDEX || index < lyrae_group.num_oracles,
+            AssetType::Perp => index < lyrae_group.num_oracles,
+        };
+        check!(index_in_range(asset_type, asset_index), LyraeErrorCode::InvalidParam)?;
+        check!(index_in_range(liab_type, liab_index), LyraeErrorCode::InvalidParam)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
+
+        let mut liqor_ma =
+            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            liqor_ma.is_owner_or_delegate(liqor_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
 
-        // Make sure the LyraeAccount doesn't already have a AdvancedOrders set
-        check!(
-            lyrae_account.advanced_orders_key == Pubkey::default(),
-            LyraeErrorCode::InvalidParam
-        )?;
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
 
-        let (pda_address, bump_seed) =
-            Pubkey::find_program_address(&[&lyrae_account_ai.key.to_bytes()], program_id);
-        check!(&pda_address == advanced_orders_ai.key, LyraeErrorCode::InvalidAccount)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
+        let liqor_active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &liqor_ma,
+            vec![(asset_type, asset_index), (liab_type, liab_index)],
+        );
 
-        let pda_signer_seeds: &[&[u8]] = &[&lyrae_account_ai.key.to_bytes(), &[bump_seed]];
-        let rent = Rent::get()?;
-        create_pda_account(
-            owner_ai,
-            &rent,
-            size_of::<AdvancedOrders>(),
-            program_id,
-            system_prog_ai,
-            advanced_orders_ai,
-            pda_signer_seeds,
-            &[],
+        lyrae_cache.check_valid(
+            &lyrae_group,
+            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
+            now_ts,
         )?;
 
-        // initialize the AdvancedOrders account
-        AdvancedOrders::init(advanced_orders_ai, program_id, &rent)?;
-
-        // set the lyrae_account.advanced_orders field
-        lyrae_account.advanced_orders_key = *advanced_orders_ai.key;
-        Ok(())
-    }
-
-    #[inline(never)]
-    fn close_advanced_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // write, signer
-            advanced_orders_ai, // write
-        ] = accounts;
-
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
-        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
-        check!(!lyrae_account.being_liquidated, LyraeErrorCode::BeingLiquidated)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-
-        let mut advanced_orders =
-            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
-        for i in 0..MAX_ADVANCED_ORDERS {
-            advanced_orders.orders[i].is_active = false;
+        // Make sure orders are cancelled for perps and check orders
+        for i in 0..lyrae_group.num_oracles {
+            if liqee_active_assets.perps[i] {
+                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
+            }
         }
-        advanced_orders.meta_data.is_initialized = false;
 
-        // Transfer lamports to owner
-        program_transfer_lamports(advanced_orders_ai, owner_ai, advanced_orders_ai.lamports())?;
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-        lyrae_account.advanced_orders_key = Pubkey::default();
-
-        Ok(())
-    }
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
+            }
+        } else if maint_health >= ZERO_I80F48 {
+            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
+        } else {
+            liqee_ma.being_liquidated = true;
+        }
 
-    /// Add a perp trigger order to the AdvancedOrders account
-    /// The TriggerCondition specifies if trigger_price  must be above or below oracle price
-    /// When the condition is met, the order is executed as a regular perp order
-    #[inline(never)]
-    fn add_perp_trigger_order(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        order_type: OrderType,
-        side: Side,
-        trigger_condition: TriggerCondition,
-        reduce_only: bool,
-        client_order_id: u64,
-        price: i64,
-        quantity: i64,
-        trigger_price: I80F48,
-    ) -> LyraeResult<()> {
-        check!(price.is_positive(), LyraeErrorCode::InvalidParam)?;
-        check!(quantity.is_positive(), LyraeErrorCode::InvalidParam)?;
-        check!(trigger_price.is_positive(), LyraeErrorCode::InvalidParam)?; // Is this necessary?
+        let asset_price: I80F48;
+        let liab_price: I80F48;
+        let asset_transfer: I80F48;
+        let actual_liab_transfer: I80F48;
+        let liquidation_fee: I80F48;
+        if asset_type == AssetType::Token {
+            // we know asset_type != liab_type
+            asset_price = lyrae_cache.get_price(asset_index);
+            liab_price = ONE_I80F48;
+            let bank_cache = &lyrae_cache.root_bank_cache[asset_index];
+            check!(liqee_ma.deposits[asset_index].is_positive(), LyraeErrorCode::Default)?;
+            check!(liab_index != QUOTE_INDEX, LyraeErrorCode::Default)?;
+            check!(
+                lyrae_group.find_root_bank_index(root_bank_ai.key).unwrap() == asset_index,
+                LyraeErrorCode::InvalidRootBank
+            )?;
+            let native_borrows = -liqee_ma.perp_accounts[liab_index].quote_position;
+            check!(liqee_ma.perp_accounts[liab_index].base_position == 0, LyraeErrorCode::Default)?;
+            check!(native_borrows.is_positive(), LyraeErrorCode::Default)?;
 
-        const NUM_FIXED: usize = 7;
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
-        let [
-            lyrae_group_ai,         // read
-            lyrae_account_ai,       // read
-            owner_ai,               // write & signer
-            advanced_orders_ai,     // write
-            lyrae_cache_ai,         // read
-            perp_market_ai,         // read
-            system_prog_ai,         // read
-        ] = fixed_ais;
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
-        )?;
+            let init_asset_weight = if asset_index == QUOTE_INDEX {
+                liquidation_fee = ZERO_I80F48;
+                ONE_I80F48
+            } else {
+                let asset_info = &lyrae_group.spot_markets[asset_index];
+                check!(!asset_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
+                liquidation_fee = lyrae_group.get_spot_liquidation_fee(asset_index);
+                asset_info.init_asset_weight
+            };
+            let asset_fee = ONE_I80F48 + liquidation_fee;
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        let open_orders_ais =
-            lyrae_account.checked_unpack_open_orders(&lyrae_group, open_orders_ais)?;
-        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
+            let liab_info = &lyrae_group.perp_markets[liab_index];
+            check!(!liab_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
 
-        let market_index = lyrae_group
-            .find_perp_market_index(perp_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+            let (liab_fee, init_liab_weight) = (ONE_I80F48, ONE_I80F48);
 
-        let active_assets = UserActiveAssets::new(
-            &lyrae_group,
-            &lyrae_account,
-            vec![(AssetType::Perp, market_index)],
-        );
+            let native_deposits = liqee_ma.get_native_deposit(bank_cache, asset_index)?;
 
-        // load and validate the cache
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
-        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+            // Max liab transferred to reach init_health == 0
+            let deficit_max_liab = if asset_index == QUOTE_INDEX {
+                native_deposits
+            } else {
+                -init_health
+                    / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee))
+            };
 
-        let mut health_cache = HealthCache::new(active_assets);
-        health_cache.init_vals_with_orders_vec(
-            &lyrae_group,
-            &lyrae_cache,
-            &lyrae_account,
-            &open_orders_accounts,
-        )?;
-        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+            // Max liab transferred to reach asset_i == 0
+            let asset_implied_liab_transfer =
+                native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
+            actual_liab_transfer = deficit_max_liab
+                .min(native_borrows)
+                .min(max_liab_transfer)
+                .min(asset_implied_liab_transfer);
 
-        // Only allow placing of trigger orders if account above Maint and not being liquidated
-        check!(
-            init_health >= ZERO_I80F48
-                || (!lyrae_account.being_liquidated && maint_health >= ZERO_I80F48),
-            LyraeErrorCode::InsufficientHealth
-        )?;
-        lyrae_account.being_liquidated = false;
+            // Transfer the negative quote position from liqee to liqor
+            liqee_ma.perp_accounts[liab_index].transfer_quote_position(
+                &mut liqor_ma.perp_accounts[liab_index],
+                -actual_liab_transfer,
+            );
 
-        // Note: no need to check health here, needs to be checked on trigger
-        // TODO: make sure liquidator cancels all advanced orders (why?)
-        // Transfer lamports before so we don't hit rust borrow checker
-        // If we don't succeed in adding the order, it will be reverted anyway
-        invoke_transfer_lamports(
-            owner_ai,
-            advanced_orders_ai,
-            system_prog_ai,
-            ADVANCED_ORDER_FEE,
-            &[],
-        )?;
+            asset_transfer =
+                actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
 
-        let mut advanced_orders =
-            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
-        for i in 0..MAX_ADVANCED_ORDERS {
-            if advanced_orders.orders[i].is_active {
-                continue;
-            }
+            // Transfer collateral from liqee to liqor
+            transfer_token_internal(
+                bank_cache,
+                &mut node_bank,
+                &mut liqee_ma,
+                &mut liqor_ma,
+                liqee_lyrae_account_ai.key,
+                liqor_lyrae_account_ai.key,
+                asset_index,
+                asset_transfer,
+            )?;
 
-            advanced_orders.orders[i] = cast(PerpTriggerOrder::new(
-                market_index as u8,
-                order_type,
-                side,
-                trigger_condition,
-                reduce_only,
-                client_order_id,
-                price,
-                quantity,
-                trigger_price,
-            ));
+            health_cache.update_token_val(
+                &lyrae_group,
+                &lyrae_cache,
+                &liqee_ma,
+                liqee_open_orders_ais,
+                asset_index,
+            )?;
 
-            return Ok(());
-        }
+            health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, liab_index)?;
+        } else {
+            asset_price = ONE_I80F48;
+            liab_price = lyrae_cache.get_price(liab_index);
+            check!(
+                lyrae_group.find_root_bank_index(root_bank_ai.key).unwrap() == liab_index,
+                LyraeErrorCode::InvalidRootBank
+            )?;
 
-        Err(throw_err!(LyraeErrorCode::OutOfSpace))
-    }
+            check!(liqee_ma.borrows[liab_index].is_positive(), LyraeErrorCode::Default)?;
+            check!(asset_index != QUOTE_INDEX, LyraeErrorCode::Default)?;
 
-    /// Remove the order and refund the fee
-    #[inline(never)]
-    fn remove_advanced_order(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        order_index: u8,
-    ) -> LyraeResult<()> {
-        let order_index = order_index as usize;
-        check!(order_index < MAX_ADVANCED_ORDERS, LyraeErrorCode::InvalidParam)?;
+            check!(
+                liqee_ma.perp_accounts[asset_index].base_position == 0,
+                LyraeErrorCode::Default
+            )?;
+            let native_deposits = liqee_ma.perp_accounts[asset_index].quote_position;
+            check!(native_deposits.is_positive(), LyraeErrorCode::Default)?;
 
-        const NUM_FIXED: usize = 5;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,         // read
-            lyrae_account_ai,       // read
-            owner_ai,               // write & signer
-            advanced_orders_ai,     // write
-            system_prog_ai,         // read
-        ] = accounts;
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
-        )?;
+            let bank_cache = &lyrae_cache.root_bank_cache[liab_index];
+            let (asset_fee, init_asset_weight) = (ONE_I80F48, ONE_I80F48);
+            let init_liab_weight = if liab_index == QUOTE_INDEX {
+                liquidation_fee = ZERO_I80F48;
+                ONE_I80F48
+            } else {
+                let liab_info = &lyrae_group.spot_markets[liab_index];
+                check!(!liab_info.is_empty(), LyraeErrorCode::InvalidMarket)?;
+                liquidation_fee = lyrae_group.get_spot_liquidation_fee(liab_index);
+                liab_info.init_liab_weight
+            };
+            let liab_fee = ONE_I80F48 - liquidation_fee;
 
-        let lyrae_account =
-            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
-        // No bankruptcy check; removing order is fine
+            let native_borrows = liqee_ma.get_native_borrow(bank_cache, liab_index)?;
+            let deficit_max_liab = if liab_index == QUOTE_INDEX {
+                native_borrows
+            } else {
+                -init_health
+                    / (liab_price * (init_liab_weight - init_asset_weight * asset_fee / liab_fee))
+            };
 
-        let mut advanced_orders =
-            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
+            // Max liab transferred to reach asset_i == 0
+            let asset_implied_liab_transfer =
+                native_deposits * asset_price * liab_fee / (liab_price * asset_fee);
+            actual_liab_transfer = deficit_max_liab
+                .min(native_borrows)
+                .min(max_liab_transfer)
+                .min(asset_implied_liab_transfer);
 
-        let order = &mut advanced_orders.orders[order_index];
+            asset_transfer =
+                actual_liab_transfer * liab_price * asset_fee / (liab_fee * asset_price);
 
-        if order.is_active {
-            order.is_active = false;
-            program_transfer_lamports(advanced_orders_ai, owner_ai, ADVANCED_ORDER_FEE)
+            // Transfer liabilities from liqee to liqor (i.e. increase liqee and decrease liqor)
+            transfer_token_internal(
+                bank_cache,
+                &mut node_bank,
+                &mut liqor_ma,
+                &mut liqee_ma,
+                liqor_lyrae_account_ai.key,
+                liqee_lyrae_account_ai.key,
+                liab_index,
+                actual_liab_transfer,
+            )?;
+
+            // Transfer positive quote position from liqee to liqor
+            liqee_ma.perp_accounts[asset_index]
+                .transfer_quote_position(&mut liqor_ma.perp_accounts[asset_index], asset_transfer);
+
+            health_cache.update_token_val(
+                &lyrae_group,
+                &lyrae_cache,
+                &liqee_ma,
+                liqee_open_orders_ais,
+                liab_index,
+            )?;
+
+            health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, asset_index)?;
+        }
+
+        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+        liqor_health_cache.init_vals(
+            &lyrae_group,
+            &lyrae_cache,
+            &liqor_ma,
+            liqor_open_orders_ais,
+        )?;
+        let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+
+        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        if liqee_maint_health < ZERO_I80F48 {
+            liqee_ma.is_bankrupt =
+                liqee_ma.check_enter_bankruptcy(&lyrae_group, liqee_open_orders_ais);
         } else {
-            Ok(())
+            let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+            // this is equivalent to one native USDC or 1e-6 USDC
+            // This is used as threshold to flip flag instead of 0 because of dust issues
+            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
+        }
+
+        lyrae_emit!(LiquidateTokenAndPerpLog {
+            lyrae_group: *lyrae_group_ai.key,
+            liqee: *liqee_lyrae_account_ai.key,
+            liqor: *liqor_lyrae_account_ai.key,
+            asset_index: asset_index as u64,
+            liab_index: liab_index as u64,
+            asset_type: asset_type as u8,
+            liab_type: liab_type as u8,
+            asset_transfer: asset_transfer.to_bits(),
+            liab_transfer: actual_liab_transfer.to_bits(),
+            asset_price: asset_price.to_bits(),
+            liab_price: liab_price.to_bits(),
+            bankruptcy: liqee_ma.is_bankrupt,
+            liquidation_fee: liquidation_fee.to_bits(),
+        });
+
+        let perp_market_index: usize;
+        if asset_type == AssetType::Token {
+            perp_market_index = liab_index;
+        } else {
+            perp_market_index = asset_index;
         }
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *liqee_lyrae_account_ai.key,
+            perp_market_index as u64,
+            &liqee_ma.perp_accounts[perp_market_index],
+            &lyrae_cache.perp_market_cache[perp_market_index],
+        );
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *liqor_lyrae_account_ai.key,
+            perp_market_index as u64,
+            &liqor_ma.perp_accounts[perp_market_index],
+            &lyrae_cache.perp_market_cache[perp_market_index],
+        );
+
+        Ok(())
     }
 
     #[inline(never)]
-    fn execute_perp_trigger_order(
+    /// Reduce some of the base position in exchange for quote position in this market
+    /// Transfer will not exceed abs(base_position)
+    /// Example:
+    ///     BTC/USD price 9.4k
+    ///     liquidation_fee = 0.025
+    ///     liqee initial
+    ///         USDC deposit 10k
+    ///         BTC-PERP base_position = 10
+    ///         BTC-PERP quote_position = -100k
+    ///         maint_health = -700
+    ///         init_health = -5400
+    ///     liqee after liquidate_perp_market
+    ///         USDC deposit 10k
+    ///         BTC-PERP base_position = 2.3404
+    ///         BTC-PERP quote_position = -29799.766
+    ///         init_health = 0.018
+    ///     liqor after liquidate_perp_market
+    ///         BTC-PERP base_position = 7.6596
+    ///         BTC-PERP quote_position = -70200.234
+    fn liquidate_perp_market(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        order_index: u8,
+        base_transfer_request: i64,
+        liqor_max_base_position: Option<u64>,
     ) -> LyraeResult<()> {
-        let order_index = order_index as usize;
-        check!(order_index < MAX_ADVANCED_ORDERS, LyraeErrorCode::InvalidParam)?;
-        const NUM_FIXED: usize = 9;
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        // TODO OPT find a way to send in open orders accounts without zero keys
+        // liqor passes in his own account and the liqee lyrae account
+        // position is transfered to the liqor at favorable rate
+        check!(base_transfer_request != 0, LyraeErrorCode::InvalidParam)?;
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+
         let [
             lyrae_group_ai,         // read
-            lyrae_account_ai,       // write
-            advanced_orders_ai,     // write
-            agent_ai,               // write
             lyrae_cache_ai,         // read
             perp_market_ai,         // write
-            bids_ai,                // write
-            asks_ai,                // write
             event_queue_ai,         // write
+            liqee_lyrae_account_ai, // write
+            liqor_lyrae_account_ai, // write
+            liqor_ai,               // read, signer
         ] = fixed_ais;
-
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        let open_orders_ais =
-            lyrae_account.checked_unpack_open_orders(&lyrae_group, open_orders_ais)?;
-        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
-
-        let mut advanced_orders =
-            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
-
-        // deactivate all advanced orders if account is bankrupt
-        if lyrae_account.is_bankrupt {
-            msg!("Failed to trigger order; LyraeAccount is bankrupt.");
-            return cancel_all_advanced_orders(advanced_orders_ai, &mut advanced_orders, agent_ai);
-        }
-
-        // Select the AdvancedOrder
-        let order: &mut PerpTriggerOrder = cast_mut(&mut advanced_orders.orders[order_index]);
-        check!(order.is_active, LyraeErrorCode::InvalidParam)?;
         check!(
-            order.advanced_order_type == AdvancedOrderType::PerpTrigger,
+            liqee_lyrae_account_ai.key != liqor_lyrae_account_ai.key,
             LyraeErrorCode::InvalidParam
         )?;
-        let market_index = order.market_index as usize;
-
-        // Check the caches are valid
-        let active_assets = UserActiveAssets::new(
-            &lyrae_group,
-            &lyrae_account,
-            vec![(AssetType::Perp, market_index)],
-        );
 
-        let clock = Clock::get()?;
-        let now_ts = clock.unix_timestamp as u64;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
         let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
-        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
 
-        // Check trigger condition is met
-        let price = lyrae_cache.get_price(market_index);
-        match order.trigger_condition {
-            TriggerCondition::Above => {
-                check!(price >= order.trigger_price, LyraeErrorCode::TriggerConditionFalse)?;
-            }
-            TriggerCondition::Below => {
-                check!(price <= order.trigger_price, LyraeErrorCode::TriggerConditionFalse)?;
-            }
-        }
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
+
+        let mut liqor_ma =
+            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
         check!(
-            &lyrae_group.perp_markets[market_index].perp_market == perp_market_ai.key,
-            LyraeErrorCode::InvalidMarket
+            liqor_ma.is_owner_or_delegate(liqor_ai.key, DELEGATE_PLACE),
+            LyraeErrorCode::InvalidOwner
         )?;
+        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
+
         let mut perp_market =
             PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let pmi = &lyrae_group.perp_markets[market_index];
+        check!(!pmi.is_empty(), LyraeErrorCode::InvalidMarket)?;
+        let mut event_queue: EventQueue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
-        let mut health_cache = HealthCache::new(active_assets);
-        health_cache.init_vals_with_orders_vec(
+        // Move funding into quote position. Not necessary to adjust funding settled after funding is moved
+        let cache = &lyrae_cache.perp_market_cache[market_index];
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
+        let liqor_active_assets =
+            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Perp, market_index)]);
+
+        lyrae_cache.check_valid(
             &lyrae_group,
-            &lyrae_cache,
-            &lyrae_account,
-            &open_orders_accounts,
+            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
+            now_ts,
         )?;
-        let pre_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        liqee_ma.perp_accounts[market_index].settle_funding(cache);
+        liqor_ma.perp_accounts[market_index].settle_funding(cache);
 
-        // update the being_liquidated flag
-        if lyrae_account.being_liquidated {
-            if pre_health >= ZERO_I80F48 {
-                lyrae_account.being_liquidated = false;
-            } else {
-                msg!("Failed to trigger order; LyraeAccount is being liquidated.");
-                return cancel_all_advanced_orders(
-                    advanced_orders_ai,
-                    &mut advanced_orders,
-                    agent_ai,
-                );
+        // Make sure orders are cancelled for perps before liquidation
+        for i in 0..lyrae_group.num_oracles {
+            if liqee_active_assets.perps[i] {
+                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
             }
         }
 
-        // This means health must only go up
-        let health_up_only = pre_health < ZERO_I80F48;
-
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let mut event_queue =
-            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
-
-        // If reduce_only, position must only go down
-        let quantity = if order.reduce_only {
-            let base_pos = lyrae_account.get_complete_base_pos(
-                market_index,
-                &event_queue,
-                lyrae_account_ai.key,
-            )?;
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-            if (order.side == Side::Bid && base_pos > 0)
-                || (order.side == Side::Ask && base_pos < 0)
-            {
-                0
-            } else {
-                base_pos.abs().min(order.quantity)
+        if liqee_ma.being_liquidated {
+            if init_health > ZERO_I80F48 {
+                liqee_ma.being_liquidated = false;
+                msg!("Account init_health above zero.");
+                return Ok(());
             }
+        } else if maint_health >= ZERO_I80F48 {
+            return Err(throw_err!(LyraeErrorCode::NotLiquidatable));
         } else {
-            order.quantity
-        };
+            liqee_ma.being_liquidated = true;
+        }
 
-        if quantity != 0 {
-            let (taker_base, taker_quote, bids_quantity, asks_quantity) = match order.side {
-                Side::Bid => book.sim_new_bid(
-                    &perp_market,
-                    &lyrae_group.perp_markets[market_index],
-                    lyrae_cache.get_price(market_index),
-                    order.price,
-                    quantity,
-                    order.order_type,
-                )?,
-                Side::Ask => book.sim_new_ask(
-                    &perp_market,
-                    &lyrae_group.perp_markets[market_index],
-                    lyrae_cache.get_price(market_index),
-                    order.price,
-                    quantity,
-                    order.order_type,
-                )?,
-            };
+        // TODO - what happens if base position and quote position have same sign?
+        // TODO - what if base position is 0 but quote is negative. Perhaps settle that pnl first?
 
-            // simulate the effect on health
-            let sim_post_health = health_cache.get_health_after_sim_perp(
-                &lyrae_group,
-                &lyrae_cache,
-                &lyrae_account,
-                market_index,
-                HealthType::Init,
-                taker_base,
-                taker_quote,
-                bids_quantity,
-                asks_quantity,
-            )?;
+        let liqee_perp_account = &mut liqee_ma.perp_accounts[market_index];
+        let liqor_perp_account = &mut liqor_ma.perp_accounts[market_index];
 
-            if sim_post_health >= ZERO_I80F48 || (health_up_only && sim_post_health >= pre_health) {
-                let (taker_base, taker_quote, bids_quantity, asks_quantity) = {
-                    let pa = &lyrae_account.perp_accounts[market_index];
-                    (
-                        pa.taker_base + taker_base,
-                        pa.taker_quote + taker_quote,
-                        pa.bids_quantity.checked_add(bids_quantity).unwrap(),
-                        pa.asks_quantity.checked_add(asks_quantity).unwrap(),
-                    )
-                };
-
-                book.new_order(
-                    program_id,
-                    &lyrae_group,
-                    lyrae_group_ai.key,
-                    &lyrae_cache,
-                    &mut event_queue,
-                    &mut perp_market,
-                    lyrae_cache.get_price(market_index),
-                    &mut lyrae_account,
-                    lyrae_account_ai.key,
-                    market_index,
-                    order.side,
-                    order.price,
-                    quantity,
-                    order.order_type,
-                    order.client_order_id,
-                    now_ts,
-                    None,
-                )?;
+        let price = lyrae_cache.price_cache[market_index].price;
+        let liquidation_fee = lyrae_group.get_perp_liquidation_fee(market_index);
+        let (base_transfer, quote_transfer) = get_perp_liquidation_transfer(
+            &lyrae_group,
+            market_index,
+            price,
+            init_health,
+            liqee_perp_account,
+            liqor_perp_account,
+            base_transfer_request,
+            liqor_max_base_position,
+        )?;
 
-                // TODO OPT - unnecessary, remove after testing
-                health_cache.update_perp_val(
-                    &lyrae_group,
-                    &lyrae_cache,
-                    &lyrae_account,
-                    market_index,
-                )?;
-                let post_health = health_cache.get_health(&lyrae_group, HealthType::Init);
-                let pa = &lyrae_account.perp_accounts[market_index];
-                check!(
-                    sim_post_health == post_health
-                        && taker_base == pa.taker_base
-                        && taker_quote == pa.taker_quote
-                        && bids_quantity == pa.bids_quantity
-                        && asks_quantity == pa.asks_quantity,
-                    LyraeErrorCode::MathError
-                )?;
-            } else {
-                // normally this would be an InsufficientFunds error but we want to remove the AO and persist changes
-                msg!("Failed to place perp order due to insufficient funds")
-            }
+        liqee_perp_account.change_base_position(&mut perp_market, -base_transfer);
+        liqor_perp_account.change_base_position(&mut perp_market, base_transfer);
+
+        liqee_perp_account.transfer_quote_position(liqor_perp_account, quote_transfer);
+
+        // Log this to EventQueue
+        let liquidate_event = LiquidateEvent::new(
+            now_ts,
+            event_queue.header.seq_num,
+            *liqee_lyrae_account_ai.key,
+            *liqor_lyrae_account_ai.key,
+            price,
+            base_transfer,
+            liquidation_fee,
+        );
+        event_queue.push_back(cast(liquidate_event)).unwrap();
+
+        // Calculate the health of liqor and see if liqor is still valid
+        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+        liqor_health_cache.init_vals(
+            &lyrae_group,
+            &lyrae_cache,
+            &liqor_ma,
+            liqor_open_orders_ais,
+        )?;
+        let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+
+        health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, market_index)?;
+        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        if liqee_maint_health < ZERO_I80F48 {
+            liqee_ma.is_bankrupt =
+                liqee_ma.check_enter_bankruptcy(&lyrae_group, liqee_open_orders_ais);
+        } else {
+            let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+            // this is equivalent to one native USDC or 1e-6 USDC
+            // This is used as threshold to flip flag instead of 0 because of dust issues
+            liqee_ma.being_liquidated = liqee_init_health < NEG_ONE_I80F48;
         }
 
-        order.is_active = false;
-        program_transfer_lamports(advanced_orders_ai, agent_ai, ADVANCED_ORDER_FEE)
+        lyrae_emit!(LiquidatePerpMarketLog {
+            lyrae_group: *lyrae_group_ai.key,
+            liqee: *liqee_lyrae_account_ai.key,
+            liqor: *liqor_lyrae_account_ai.key,
+            market_index: market_index as u64,
+            price: price.to_bits(),
+            base_transfer,
+            quote_transfer: quote_transfer.to_bits(),
+            bankruptcy: liqee_ma.is_bankrupt,
+            liquidation_fee: liquidation_fee.to_bits()
+        });
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *liqee_lyrae_account_ai.key,
+            market_index as u64,
+            &liqee_ma.perp_accounts[market_index],
+            &lyrae_cache.perp_market_cache[market_index],
+        );
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *liqor_lyrae_account_ai.key,
+            market_index as u64,
+            &liqor_ma.perp_accounts[market_index],
+            &lyrae_cache.perp_market_cache[market_index],
+        );
+
+        Ok(())
     }
 
-    /// Create a LyraeAccount PDA and initialize it
     #[inline(never)]
-    fn create_lyrae_account(
+    /// Run liquidate_perp_market's checks and transfer sizing on copies of the accounts and log
+    /// the result; no state is written. The liqor's health is reported rather than checked.
+    fn simulate_liquidate_perp_market(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        account_num: u64,
-    ) -> LyraeResult {
-        const NUM_FIXED: usize = 4;
-        let fixed_accounts = array_ref![accounts, 0, NUM_FIXED];
+        base_transfer_request: i64,
+        liqor_max_base_position: Option<u64>,
+    ) -> LyraeResult<()> {
+        check!(base_transfer_request != 0, LyraeErrorCode::InvalidParam)?;
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (fixed_ais, liqee_open_orders_ais, liqor_open_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+
         let [
-            lyrae_group_ai,         // write
-            lyrae_account_ai,       // write
-            owner_ai,               // read (write if no payer passed) & signer
-            system_prog_ai,         // read
-        ] = fixed_accounts;
-        let payer_ai = if accounts.len() > NUM_FIXED {
-            &accounts[NUM_FIXED] // write & signer
-        } else {
-            owner_ai
-        };
+            lyrae_group_ai,         // read
+            lyrae_cache_ai,         // read
+            perp_market_ai,         // read
+            liqee_lyrae_account_ai, // read
+            liqor_lyrae_account_ai, // read
+        ] = fixed_ais;
         check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
+            liqee_lyrae_account_ai.key != liqor_lyrae_account_ai.key,
+            LyraeErrorCode::InvalidParam
         )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check!(payer_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
 
-        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
-        check!(
-            lyrae_group.num_lyrae_accounts < lyrae_group.max_lyrae_accounts,
-            LyraeErrorCode::MaxAccountsReached
-        )?;
-        let rent = Rent::get()?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
 
-        let lyrae_account_seeds: &[&[u8]] =
-            &[&lyrae_group_ai.key.as_ref(), &owner_ai.key.as_ref(), &account_num.to_le_bytes()];
-        seed_and_create_pda(
+        // Work on boxed copies: the two accounts and the market don't fit on the BPF stack
+        let mut liqee_ma = Box::new(*LyraeAccount::load_checked(
+            liqee_lyrae_account_ai,
             program_id,
-            payer_ai,
-            &rent,
-            size_of::<LyraeAccount>(),
+            lyrae_group_ai.key,
+        )?);
+        check!(!liqee_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqee_ma.check_open_orders(&lyrae_group, liqee_open_orders_ais)?;
+
+        let mut liqor_ma = Box::new(*LyraeAccount::load_checked(
+            liqor_lyrae_account_ai,
             program_id,
-            system_prog_ai,
-            lyrae_account_ai,
-            lyrae_account_seeds,
-            &[],
+            lyrae_group_ai.key,
+        )?);
+        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
+
+        let mut perp_market =
+            Box::new(*PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?);
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let cache = &lyrae_cache.perp_market_cache[market_index];
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let liqee_active_assets = UserActiveAssets::new(&lyrae_group, &liqee_ma, vec![]);
+        let liqor_active_assets =
+            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Perp, market_index)]);
+
+        lyrae_cache.check_valid(
+            &lyrae_group,
+            &UserActiveAssets::merge(&liqee_active_assets, &liqor_active_assets),
+            now_ts,
         )?;
-        let mut lyrae_account: RefMut<LyraeAccount> = LyraeAccount::load_mut(lyrae_account_ai)?;
-        check!(!lyrae_account.meta_data.is_initialized, LyraeErrorCode::InvalidAccountState)?;
+        liqee_ma.perp_accounts[market_index].settle_funding(cache);
+        liqor_ma.perp_accounts[market_index].settle_funding(cache);
 
-        lyrae_account.lyrae_group = *lyrae_group_ai.key;
-        lyrae_account.owner = *owner_ai.key;
-        lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
-        lyrae_account.meta_data = MetaData::new(DataType::LyraeAccount, 1, true);
+        for i in 0..lyrae_group.num_oracles {
+            if liqee_active_assets.perps[i] {
+                check!(liqee_ma.perp_accounts[i].has_no_open_orders(), LyraeErrorCode::Default)?;
+            }
+        }
 
-        lyrae_group.num_lyrae_accounts += 1;
+        let mut health_cache = HealthCache::new(liqee_active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &liqee_ma, liqee_open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-        Ok(())
-    }
+        // liquidate_perp_market would only clear being_liquidated here
+        if liqee_ma.being_liquidated {
+            check!(init_health <= ZERO_I80F48, LyraeErrorCode::NotLiquidatable)?;
+        } else {
+            check!(maint_health < ZERO_I80F48, LyraeErrorCode::NotLiquidatable)?;
+        }
 
-    #[inline(never)]
-    fn update_margin_basket(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 2;
-        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
-        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let price = lyrae_cache.price_cache[market_index].price;
+        let liquidation_fee = lyrae_group.get_perp_liquidation_fee(market_index);
+        let (base_transfer, quote_transfer) = get_perp_liquidation_transfer(
+            &lyrae_group,
+            market_index,
+            price,
+            init_health,
+            &liqee_ma.perp_accounts[market_index],
+            &liqor_ma.perp_accounts[market_index],
+            base_transfer_request,
+            liqor_max_base_position,
+        )?;
 
-        let [
-            lyrae_group_ai,         // read
-            lyrae_account_ai,       // write
-        ] = fixed_ais;
+        let liqee_perp_account = &mut liqee_ma.perp_accounts[market_index];
+        let liqor_perp_account = &mut liqor_ma.perp_accounts[market_index];
+        liqee_perp_account.change_base_position(&mut perp_market, -base_transfer);
+        liqor_perp_account.change_base_position(&mut perp_market, base_transfer);
+        liqee_perp_account.transfer_quote_position(liqor_perp_account, quote_transfer);
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+        liqor_health_cache.init_vals(
+            &lyrae_group,
+            &lyrae_cache,
+            &liqor_ma,
+            liqor_open_orders_ais,
+        )?;
+        let liqor_init_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
 
-        for i in 0..lyrae_group.num_oracles {
-            check_eq!(
-                open_orders_ais[i].key,
-                &lyrae_account.spot_open_orders[i],
-                LyraeErrorCode::InvalidOpenOrdersAccount
-            )?;
+        health_cache.update_perp_val(&lyrae_group, &lyrae_cache, &liqee_ma, market_index)?;
+        let liqee_init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let liqee_maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
 
-            if lyrae_account.spot_open_orders[i] != Pubkey::default() {
-                check_open_orders(
-                    &open_orders_ais[i],
-                    &lyrae_group.signer_key,
-                    &lyrae_group.dex_program_id,
-                )?;
-                let open_orders = load_open_orders(&open_orders_ais[i])?;
-                lyrae_account.update_basket(i, &open_orders)?;
-            }
-        }
+        lyrae_emit!(SimulatedLiquidationLog {
+            lyrae_group: *lyrae_group_ai.key,
+            liqee: *liqee_lyrae_account_ai.key,
+            liqor: *liqor_lyrae_account_ai.key,
+            market_index: market_index as u64,
+            price: price.to_bits(),
+            base_transfer,
+            quote_transfer: quote_transfer.to_bits(),
+            liquidation_fee: liquidation_fee.to_bits(),
+            liqee_init_health: liqee_init_health.to_bits(),
+            liqee_maint_health: liqee_maint_health.to_bits(),
+            liqor_init_health: liqor_init_health.to_bits(),
+        });
 
         Ok(())
     }
 
     #[inline(never)]
-    /// Change the maximum number of LyraeAccounts.v1 allowed
-    fn change_max_lyrae_accounts(
+    /// Claim insurance fund and then socialize loss
+    fn resolve_perp_bankruptcy(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        max_lyrae_accounts: u32,
-    ) -> LyraeResult {
-        const NUM_FIXED: usize = 2;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai, // write
-            admin_ai        // read, signer
-        ] = accounts;
+        liab_index: usize,
+        max_liab_transfer: I80F48,
+    ) -> LyraeResult<()> {
+        // First check the account is bankrupt
+        // Determine the value of the liab transfer
+        // Check if insurance fund has enough (given the fees)
+        // If insurance fund does not have enough, start the socialize loss function
 
-        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
-        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        // TODO - since liquidation fee is 0 for USDC, what's the incentive for someone to call this?
+        //  just add 1bp fee
 
-        lyrae_group.max_lyrae_accounts = max_lyrae_accounts;
-        Ok(())
-    }
+        // Do parameter checks
+        check!(liab_index < QUOTE_INDEX, LyraeErrorCode::InvalidParam)?;
+        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 12;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, liqor_open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
 
-    /// Create a DustAccount PDA and initialize it
-    #[inline(never)]
-    fn create_dust_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
         let [
             lyrae_group_ai,         // read
-            lyrae_account_ai,       // write
-            payer_ai,               // write & signer
-            system_prog_ai,         // read
-        ] = accounts;
+            lyrae_cache_ai,         // write
+            liqee_lyrae_account_ai, // write
+            liqor_lyrae_account_ai, // write
+            liqor_ai,               // read, signer
+            root_bank_ai,           // read
+            node_bank_ai,           // write
+            vault_ai,               // write
+            insurance_vault_ai,     // write
+            signer_ai,              // read
+            perp_market_ai,         // write
+            token_prog_ai,          // read
+        ] = fixed_ais;
         check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
+            liqee_lyrae_account_ai.key != liqor_lyrae_account_ai.key,
+            LyraeErrorCode::InvalidParam
         )?;
-        check!(payer_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
         let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let rent = Rent::get()?;
-
-        let lyrae_account_seeds: &[&[u8]] = &[&lyrae_group_ai.key.as_ref(), b"DustAccount"];
-        seed_and_create_pda(
-            program_id,
-            payer_ai,
-            &rent,
-            size_of::<LyraeAccount>(),
-            program_id,
-            system_prog_ai,
-            lyrae_account_ai,
-            lyrae_account_seeds,
-            &[],
-        )?;
-        let mut lyrae_account: RefMut<LyraeAccount> = LyraeAccount::load_mut(lyrae_account_ai)?;
-        check!(!lyrae_account.meta_data.is_initialized, LyraeErrorCode::InvalidAccountState)?;
-
-        lyrae_account.lyrae_group = *lyrae_group_ai.key;
-        lyrae_account.owner = lyrae_group.admin;
-        lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
-        lyrae_account.meta_data = MetaData::new(DataType::LyraeAccount, 0, true);
-        lyrae_account.not_upgradable = true;
-
-        Ok(())
-    }
-
-    #[inline(never)]
-    fn upgrade_lyrae_account_v0_v1(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 3;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,   // write
-            lyrae_account_ai, // write
-            owner_ai          // signer
-        ] = accounts;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+        check!(liab_index < lyrae_group.num_oracles, LyraeErrorCode::InvalidParam)?;
 
-        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let mut lyrae_cache =
+            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(liqee_ma.is_bankrupt, LyraeErrorCode::Default)?;
 
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        let mut liqor_ma =
+            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
+            liqor_ma.is_owner_or_delegate(liqor_ai.key, DELEGATE_PLACE),
             LyraeErrorCode::InvalidOwner
         )?;
-        check_eq!(lyrae_account.meta_data.version, 0, LyraeErrorCode::InvalidAccountState)?;
-        check!(!lyrae_account.not_upgradable, LyraeErrorCode::InvalidAccountState)?;
+        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
+
+        let root_bank = RootBank::load_checked(root_bank_ai, program_id)?;
         check!(
-            lyrae_group.num_lyrae_accounts < lyrae_group.max_lyrae_accounts,
-            LyraeErrorCode::MaxAccountsReached
+            &lyrae_group.tokens[QUOTE_INDEX].root_bank == root_bank_ai.key,
+            LyraeErrorCode::InvalidRootBank
         )?;
 
-        lyrae_group.num_lyrae_accounts += 1;
-        lyrae_account.meta_data.version = 1;
-
-        Ok(())
-    }
+        check!(root_bank.node_banks.contains(node_bank_ai.key), LyraeErrorCode::InvalidNodeBank)?;
+        let mut node_bank = NodeBank::load_mut_checked(node_bank_ai, program_id)?;
+        check!(vault_ai.key == &node_bank.vault, LyraeErrorCode::InvalidVault)?;
 
-    #[inline(never)]
-    fn cancel_perp_orders_side(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        side: Side,
-        limit: u8,
-    ) -> LyraeResult {
-        const NUM_FIXED: usize = 6;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,     // read
-            lyrae_account_ai,   // write
-            owner_ai,           // read, signer
-            perp_market_ai,     // write
-            bids_ai,            // write
-            asks_ai,            // write
-        ] = accounts;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let liqor_active_assets =
+            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Perp, liab_index)]);
 
-        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        lyrae_cache.check_valid(&lyrae_group, &liqor_active_assets, now_ts)?;
 
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
         check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
+            insurance_vault_ai.key == &lyrae_group.insurance_vault,
+            LyraeErrorCode::InvalidVault
         )?;
+        let insurance_vault = Account::unpack(&insurance_vault_ai.try_borrow_data()?)?;
 
-        let mut perp_market =
-            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        let quote_pos = liqee_ma.perp_accounts[liab_index].quote_position;
+        check!(quote_pos.is_negative(), LyraeErrorCode::Default)?;
 
-        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let liab_transfer_u64 = max_liab_transfer
+            .min(-quote_pos) // minimum of what liqor wants and what liqee has
+            .checked_ceil() // round up and convert to native quote token
+            .unwrap()
+            .checked_to_num::<u64>()
+            .unwrap()
+            .min(insurance_vault.amount); // take min of what ins. fund has
 
-        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
-        let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
+        if liab_transfer_u64 != 0 {
+            let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+            invoke_transfer(
+                token_prog_ai,
+                insurance_vault_ai,
+                vault_ai,
+                signer_ai,
+                &[&signers_seeds],
+                liab_transfer_u64,
+            )?;
+            emit_insurance_fund_balance(
+                *lyrae_group_ai.key,
+                *insurance_vault_ai.key,
+                insurance_vault.amount,
+                Account::unpack(&insurance_vault_ai.try_borrow_data()?)?.amount,
+            );
+            let liab_transfer = I80F48::from_num(liab_transfer_u64);
+            liqee_ma.perp_accounts[liab_index]
+                .transfer_quote_position(&mut liqor_ma.perp_accounts[liab_index], -liab_transfer);
 
-        if perp_market.meta_data.version == 0 {
-            return Err(throw_err!(LyraeErrorCode::InvalidParam));
-        } else {
-            let (all_order_ids, canceled_order_ids) = book.cancel_all_side_with_size_incentives(
-                &mut lyrae_account,
-                &mut perp_market,
-                market_index,
-                side,
-                limit,
+            checked_change_net(
+                bank_cache,
+                &mut node_bank,
+                &mut liqor_ma,
+                liqor_lyrae_account_ai.key,
+                QUOTE_INDEX,
+                liab_transfer,
             )?;
-            lyrae_emit!(CancelAllPerpOrdersLog {
-                lyrae_group: *lyrae_group_ai.key,
-                lyrae_account: *lyrae_account_ai.key,
-                market_index: market_index as u64,
-                all_order_ids,
-                canceled_order_ids
-            });
+
+            // Make sure liqor is above init cond.
+            let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+            liqor_health_cache.init_vals(
+                &lyrae_group,
+                &lyrae_cache,
+                &liqor_ma,
+                liqor_open_orders_ais,
+            )?;
+
+            let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
+            check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
         }
 
-        lyrae_emit!(LyrAccrualLog {
+        let quote_position = liqee_ma.perp_accounts[liab_index].quote_position;
+        // If we transferred everything out of insurance_vault, insurance vault is empty
+        // and if quote position is still negative
+        let socialized_loss =
+            if liab_transfer_u64 == insurance_vault.amount && quote_position.is_negative() {
+                // insurance fund empty so socialize loss
+                check!(
+                    &lyrae_group.perp_markets[liab_index].perp_market == perp_market_ai.key,
+                    LyraeErrorCode::InvalidMarket
+                )?;
+                let mut perp_market =
+                    PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+
+                perp_market.socialize_loss(
+                    &mut liqee_ma.perp_accounts[liab_index],
+                    &mut lyrae_cache.perp_market_cache[liab_index],
+                )?
+            } else {
+                ZERO_I80F48
+            };
+
+        liqee_ma.is_bankrupt = !liqee_ma.check_exit_bankruptcy(&lyrae_group);
+
+        lyrae_emit!(PerpBankruptcyLog {
             lyrae_group: *lyrae_group_ai.key,
-            lyrae_account: *lyrae_account_ai.key,
-            market_index: market_index as u64,
-            lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
+            liqee: *liqee_lyrae_account_ai.key,
+            liqor: *liqor_lyrae_account_ai.key,
+            liab_index: liab_index as u64,
+            insurance_transfer: liab_transfer_u64,
+            socialized_loss: socialized_loss.to_bits(),
+            cache_long_funding: lyrae_cache.perp_market_cache[liab_index].long_funding.to_bits(),
+            cache_short_funding: lyrae_cache.perp_market_cache[liab_index].short_funding.to_bits()
         });
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *liqee_lyrae_account_ai.key,
+            liab_index as u64,
+            &liqee_ma.perp_accounts[liab_index],
+            &lyrae_cache.perp_market_cache[liab_index],
+        );
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *liqor_lyrae_account_ai.key,
+            liab_index as u64,
+            &liqor_ma.perp_accounts[liab_index],
+            &lyrae_cache.perp_market_cache[liab_index],
+        );
+
         Ok(())
     }
 
     #[inline(never)]
-    fn set_delegate(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
-        let [
-            lyrae_group_ai,                   // read
-            lyrae_account_ai,                 // write
-            owner_ai,                         // read, signer
-            delegate_ai,                      // read
-        ] = accounts;
-
-        let mut lyrae_account =
-            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
-
-        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
-        check!(&lyrae_account.delegate != delegate_ai.key, LyraeErrorCode::InvalidAccount)?;
-
-        lyrae_account.delegate = *delegate_ai.key;
-
-        Ok(())
-    }
-
-    #[inline(never)]
-    fn change_spot_market_params(
+    /// Claim insurance fund and then socialize loss
+    fn resolve_token_bankruptcy(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        maint_leverage: Option<I80F48>,
-        init_leverage: Option<I80F48>,
-        liquidation_fee: Option<I80F48>,
-        optimal_util: Option<I80F48>,
-        optimal_rate: Option<I80F48>,
-        max_rate: Option<I80F48>,
-        version: Option<u8>,
-    ) -> LyraeResult {
-        const NUM_FIXED: usize = 4;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        max_liab_transfer: I80F48, // in native token terms
+        max_liab_price: Option<I80F48>,
+    ) -> LyraeResult<()> {
+        // First check the account is bankrupt
+        // Determine the value of the liab transfer
+        // Check if insurance fund has enough (given the fees)
+        // If insurance fund does not have enough, start the socialize loss function
+        check!(max_liab_transfer.is_positive(), LyraeErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 13;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS + MAX_NODE_BANKS];
+        let (
+            fixed_ais,
+            liqor_open_orders_ais, // read
+            liab_node_bank_ais,    // write
+        ) = array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_NODE_BANKS];
 
         let [
-        lyrae_group_ai, // write
-        spot_market_ai, // write
-        root_bank_ai,   // write
-        admin_ai        // read, signer
-        ] = accounts;
+            lyrae_group_ai,         // read
+            lyrae_cache_ai,         // write
+            liqee_lyrae_account_ai, // write
+            liqor_lyrae_account_ai, // write
+            liqor_ai,               // read, signer
+            quote_root_bank_ai,     // read
+            quote_node_bank_ai,     // write
+            quote_vault_ai,         // write
+            insurance_vault_ai,     // write
+            signer_ai,              // read
+            liab_root_bank_ai,      // write
+            liab_node_bank_ai,      // write
+            token_prog_ai,          // read
+        ] = fixed_ais;
+        check!(
+            liqee_lyrae_account_ai.key != liqor_lyrae_account_ai.key,
+            LyraeErrorCode::InvalidParam
+        )?;
+        check_eq!(token_prog_ai.key, &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
 
-        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
-        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
-        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
 
-        let market_index = lyrae_group
-            .find_spot_market_index(spot_market_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let mut lyrae_cache =
+            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
 
-        // checks rootbank is part of the group
-        let _root_bank_index = lyrae_group
-            .find_root_bank_index(root_bank_ai.key)
-            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+        // Load the liqee's lyrae account
+        let mut liqee_ma =
+            LyraeAccount::load_mut_checked(liqee_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(liqee_ma.is_bankrupt, LyraeErrorCode::Default)?;
 
-        let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
-        let mut info = &mut lyrae_group.spot_markets[market_index];
+        // Load the liqor's lyrae account
+        let mut liqor_ma =
+            LyraeAccount::load_mut_checked(liqor_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            liqor_ma.is_owner_or_delegate(liqor_ai.key, DELEGATE_PLACE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(liqor_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!liqor_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        liqor_ma.check_open_orders(&lyrae_group, liqor_open_orders_ais)?;
 
-        // Unwrap params. Default to current state if Option is None
-        let (init_asset_weight, init_liab_weight) = init_leverage
-            .map_or((info.init_asset_weight, info.init_liab_weight), |x| get_leverage_weights(x));
-        let (maint_asset_weight, maint_liab_weight) = maint_leverage
-            .map_or((info.maint_asset_weight, info.maint_liab_weight), |x| get_leverage_weights(x));
+        // Load the bank for liab token
+        let liab_index = lyrae_group
+            .find_root_bank_index(liab_root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+        let mut liab_root_bank = RootBank::load_mut_checked(liab_root_bank_ai, program_id)?;
 
-        let liquidation_fee = liquidation_fee.unwrap_or(info.liquidation_fee);
-        let optimal_util = optimal_util.unwrap_or(root_bank.optimal_util);
-        let optimal_rate = optimal_rate.unwrap_or(root_bank.optimal_rate);
-        let max_rate = max_rate.unwrap_or(root_bank.max_rate);
-        let version = version.unwrap_or(root_bank.meta_data.version);
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let liqor_active_assets =
+            UserActiveAssets::new(&lyrae_group, &liqor_ma, vec![(AssetType::Token, liab_index)]);
 
-        // params check
-        check!(init_asset_weight > ZERO_I80F48, LyraeErrorCode::InvalidParam)?;
-        check!(maint_asset_weight > init_asset_weight, LyraeErrorCode::InvalidParam)?;
-        // maint leverage may only increase to prevent unforeseen liquidations
-        check!(maint_asset_weight >= info.maint_asset_weight, LyraeErrorCode::InvalidParam)?;
+        lyrae_cache.check_valid(&lyrae_group, &liqor_active_assets, now_ts)?;
 
-        // set the params on the RootBank
-        root_bank.set_rate_params(optimal_util, optimal_rate, max_rate)?;
+        // Load the insurance vault (insurance fund)
+        check!(
+            insurance_vault_ai.key == &lyrae_group.insurance_vault,
+            LyraeErrorCode::InvalidVault
+        )?;
+        let insurance_vault = Account::unpack(&insurance_vault_ai.try_borrow_data()?)?;
 
-        // set the params on LyraeGroup SpotMarketInfo
-        info.liquidation_fee = liquidation_fee;
-        info.maint_asset_weight = maint_asset_weight;
-        info.init_asset_weight = init_asset_weight;
-        info.maint_liab_weight = maint_liab_weight;
-        info.init_liab_weight = init_liab_weight;
+        // Make sure there actually exist liabs here
+        check!(liqee_ma.borrows[liab_index].is_positive(), LyraeErrorCode::Default)?;
+        let liab_price = lyrae_cache.get_price(liab_index);
+        if let Some(max_liab_price) = max_liab_price {
+            // Protect the insurance fund from converting at a valid but unfavorable price
+            check!(liab_price <= max_liab_price, LyraeErrorCode::InvalidOraclePrice)?;
+        }
+        let liab_fee = if liab_index == QUOTE_INDEX {
+            ONE_I80F48
+        } else {
+            ONE_I80F48 - lyrae_group.get_spot_liquidation_fee(liab_index)
+        };
 
-        check!(version == 0, LyraeErrorCode::InvalidParam)?;
+        let liab_bank_cache = &lyrae_cache.root_bank_cache[liab_index];
+        let native_borrows = liqee_ma.get_native_borrow(liab_bank_cache, liab_index)?;
 
-        root_bank.meta_data.version = version;
-        Ok(())
-    }
+        let insured_liabs = I80F48::from_num(insurance_vault.amount) * liab_fee / liab_price;
+        let liab_transfer = max_liab_transfer.min(native_borrows).min(insured_liabs);
 
-    /// Set the `ref_surcharge_centibps`, `ref_share_centibps` and `ref_lyr_required` on `LyraeGroup`
-    #[inline(never)]
-    fn change_referral_fee_params(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        ref_surcharge_centibps: u32,
-        ref_share_centibps: u32,
-        ref_lyr_required: u64,
-    ) -> LyraeResult {
-        check!(ref_surcharge_centibps >= ref_share_centibps, LyraeErrorCode::InvalidParam)?;
+        let insurance_transfer = (liab_transfer * liab_price / liab_fee)
+            .checked_ceil()
+            .unwrap()
+            .checked_to_num::<u64>()
+            .unwrap()
+            .min(insurance_vault.amount);
 
-        const NUM_FIXED: usize = 2;
-        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        if insurance_transfer != 0 {
+            // First transfer quote currency into Lyrae quote vault from insurance fund
+            check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+            let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+            invoke_transfer(
+                token_prog_ai,
+                insurance_vault_ai,
+                quote_vault_ai, // this vault is checked in conditional branch below
+                signer_ai,
+                &[&signers_seeds],
+                insurance_transfer,
+            )?;
+            emit_insurance_fund_balance(
+                *lyrae_group_ai.key,
+                *insurance_vault_ai.key,
+                insurance_vault.amount,
+                Account::unpack(&insurance_vault_ai.try_borrow_data()?)?.amount,
+            );
 
-        let [
-            lyrae_group_ai, // write
-            admin_ai        // read, signer
-        ] = accounts;
+            // Transfer equivalent amount of liabilities adjusted for fees
+            let liab_transfer = I80F48::from_num(insurance_transfer) * liab_fee / liab_price;
 
-        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
-        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
-        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
-        msg!("old referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_lyr_required: {}", lyrae_group.ref_surcharge_centibps, lyrae_group.ref_share_centibps, lyrae_group.ref_lyr_required);
+            check!(
+                liab_root_bank.node_banks.contains(liab_node_bank_ai.key),
+                LyraeErrorCode::InvalidNodeBank
+            )?;
+            let mut liab_node_bank = NodeBank::load_mut_checked(liab_node_bank_ai, program_id)?;
 
-        // TODO - when this goes out, if there are any events on the EventQueue fee logging will be messed up
+            // Only load quote banks if they are different from liab banks to prevent double mut borrow
+            if liab_index == QUOTE_INDEX {
+                check!(quote_vault_ai.key == &liab_node_bank.vault, LyraeErrorCode::InvalidVault)?;
 
-        lyrae_group.ref_surcharge_centibps = ref_surcharge_centibps;
-        lyrae_group.ref_share_centibps = ref_share_centibps;
-        lyrae_group.ref_lyr_required = ref_lyr_required;
+                // Increase the quote balance on the liqor equivalent to insurance transfer
+                checked_change_net(
+                    &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+                    &mut liab_node_bank,
+                    &mut liqor_ma,
+                    liqor_lyrae_account_ai.key,
+                    QUOTE_INDEX,
+                    I80F48::from_num(insurance_transfer),
+                )?;
+            } else {
+                // Load the bank for quote token which we now know is different from liab banks
+                let quote_root_bank = RootBank::load_checked(quote_root_bank_ai, program_id)?;
+                check!(
+                    &lyrae_group.tokens[QUOTE_INDEX].root_bank == quote_root_bank_ai.key,
+                    LyraeErrorCode::InvalidRootBank
+                )?;
+                let mut quote_node_bank =
+                    NodeBank::load_mut_checked(quote_node_bank_ai, program_id)?;
+                check!(
+                    quote_root_bank.node_banks.contains(quote_node_bank_ai.key),
+                    LyraeErrorCode::InvalidNodeBank
+                )?;
+                check!(quote_vault_ai.key == &quote_node_bank.vault, LyraeErrorCode::InvalidVault)?;
 
-        msg!("new referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_lyr_required: {}", ref_surcharge_centibps, ref_share_centibps, ref_lyr_required);
-        Ok(())
-    }
+                checked_change_net(
+                    &lyrae_cache.root_bank_cache[QUOTE_INDEX],
+                    &mut quote_node_bank,
+                    &mut liqor_ma,
+                    liqor_lyrae_account_ai.key,
+                    QUOTE_INDEX,
+                    I80F48::from_num(insurance_transfer),
+                )?;
+            }
 
-    #[inline(never)]
-    /// Store the referrer's LyraeAccount pubkey on the Referrer account
-    /// It will create the Referrer account as a PDA of user's LyraeAccount if it doesn't exist
-    /// This is primarily useful for the UI; the referrer address stored here is not necessarily
-    /// who earns the ref fees.
-    fn set_referrer_memory(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
-        const NUM_FIXED: usize = 7;
-        let [
-            lyrae_group_ai,             // read
-            lyrae_account_ai,           // read
-            owner_ai,                   // signer
-            referrer_memory_ai,         // write
-            referrer_lyrae_account_ai,  // read
-            payer_ai,                   // write, signer
-            system_prog_ai,             // read
-        ] = array_ref![accounts, 0, NUM_FIXED];
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
-        )?;
-
-        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
-        let lyrae_account =
-            LyraeAccount::load_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
-        check!(
-            &lyrae_account.owner == owner_ai.key || &lyrae_account.delegate == owner_ai.key,
-            LyraeErrorCode::InvalidOwner
-        )?;
-        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+            // Liqor transfers to cancel out liability on liqee
+            transfer_token_internal(
+                liab_bank_cache,
+                &mut liab_node_bank,
+                &mut liqor_ma,
+                &mut liqee_ma,
+                liqor_lyrae_account_ai.key,
+                liqee_lyrae_account_ai.key,
+                liab_index,
+                liab_transfer,
+            )?;
 
-        let _ =
-            LyraeAccount::load_checked(referrer_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+            // Make sure liqor is above init health
+            let mut liqor_health_cache = HealthCache::new(liqor_active_assets);
+            liqor_health_cache.init_vals(
+                &lyrae_group,
+                &lyrae_cache,
+                &liqor_ma,
+                liqor_open_orders_ais,
+            )?;
+            let liqor_health = liqor_health_cache.get_health(&lyrae_group, HealthType::Init);
+            check!(liqor_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+        }
 
-        if referrer_memory_ai.data_is_empty() {
-            // initialize it if it's not initialized yet
-            let referrer_seeds: &[&[u8]] = &[&lyrae_account_ai.key.as_ref(), b"ReferrerMemory"];
-            seed_and_create_pda(
+        let (socialized_loss, percentage_loss) = if insurance_transfer == insurance_vault.amount
+            && liqee_ma.borrows[liab_index].is_positive()
+        {
+            // insurance fund empty so socialize loss
+            liab_root_bank.socialize_loss(
                 program_id,
-                payer_ai,
-                &Rent::get()?,
-                size_of::<ReferrerMemory>(),
+                liab_index,
+                &mut lyrae_cache,
+                &mut liqee_ma,
+                liab_node_bank_ais,
+            )?
+        } else {
+            (ZERO_I80F48, ZERO_I80F48)
+        };
+
+        liqee_ma.is_bankrupt = !liqee_ma.check_exit_bankruptcy(&lyrae_group);
+
+        lyrae_emit!(TokenBankruptcyLog {
+            lyrae_group: *lyrae_group_ai.key,
+            liqee: *liqee_lyrae_account_ai.key,
+            liqor: *liqor_lyrae_account_ai.key,
+            liab_index: liab_index as u64,
+            insurance_transfer,
+            socialized_loss: socialized_loss.to_bits(),
+            percentage_loss: percentage_loss.to_bits(),
+            cache_deposit_index: lyrae_cache.root_bank_cache[liab_index].deposit_index.to_bits()
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// *** Keeper Related Instructions ***
+    /// Update the deposit and borrow index on a passed in RootBank
+    fn update_root_bank(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 3;
+        let (fixed_accounts, node_bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai, // read
+            lyrae_cache_ai, // write
+            root_bank_ai,   // write
+        ] = fixed_accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_cache =
+            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+
+        let index = lyrae_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+        // TODO check root bank belongs to group in load functions
+        let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        update_root_bank_and_cache(
+            program_id,
+            lyrae_group_ai.key,
+            &mut lyrae_cache,
+            index,
+            &mut root_bank,
+            node_bank_ais,
+            now_ts,
+        )
+    }
+
+    #[inline(never)]
+    /// Same as update_root_bank for each (root_bank, node_banks...) group in the accounts
+    fn update_root_banks(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_accounts, mut bank_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai, // read
+            lyrae_cache_ai, // write
+        ] = fixed_accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_cache =
+            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        while let Some((root_bank_ai, rest)) = bank_ais.split_first() {
+            let index = lyrae_group
+                .find_root_bank_index(root_bank_ai.key)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+            let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
+            let num_node_banks = root_bank.num_node_banks;
+            check!(rest.len() >= num_node_banks, LyraeErrorCode::InvalidNodeBank)?;
+            let (node_bank_ais, rest) = rest.split_at(num_node_banks);
+            update_root_bank_and_cache(
                 program_id,
-                system_prog_ai,
-                referrer_memory_ai,
-                referrer_seeds,
-                &[],
+                lyrae_group_ai.key,
+                &mut lyrae_cache,
+                index,
+                &mut root_bank,
+                node_bank_ais,
+                now_ts,
             )?;
-            ReferrerMemory::init(referrer_memory_ai, program_id, referrer_lyrae_account_ai)
-        } else {
-            // otherwise just set referrer pubkey
-            let mut referrer_memory =
-                ReferrerMemory::load_mut_checked(referrer_memory_ai, program_id)?;
-            referrer_memory.referrer_lyrae_account = *referrer_lyrae_account_ai.key;
-            Ok(())
+
+            bank_ais = rest;
         }
+
+        Ok(())
     }
 
-    /// Associate the referrer's LyraeAccount with a human readable `referrer_id` which can be used
-    /// in a ref link
-    /// Create the `ReferrerIdRecord` PDA; if it already exists throw error
     #[inline(never)]
-    fn register_referrer_id(
+    /// similar to serum dex, but also need to do some extra magic with funding
+    fn consume_events(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        referrer_id: [u8; INFO_LEN],
-    ) -> LyraeResult {
-        const NUM_FIXED: usize = 5;
+        limit: usize,
+    ) -> LyraeResult<()> {
+        // Fill and Liquidate events may be max 4 because of compute and memory limits from
+        // logging. Increase if compute/mem goes up. Out events only touch one account and don't
+        // log, so more of them can be processed in the same call.
+        const MAX_LOGGED_EVENTS: usize = 4;
+        const MAX_EVENTS: usize = 16;
+        let limit = min(limit, MAX_EVENTS);
+        let mut num_logged = 0;
+        let mut num_consumed: u64 = 0;
+
+        const NUM_FIXED: usize = 4;
+        let (fixed_ais, lyrae_account_ais) = array_refs![accounts, NUM_FIXED; ..;];
         let [
-            lyrae_group_ai,             // read
-            referrer_lyrae_account_ai,  // read
-            referrer_id_record_ai,      // write
-            payer_ai,                   // write, signer
-            system_prog_ai,             // read
-        ] = array_ref![accounts, 0, NUM_FIXED];
-        check!(
-            system_prog_ai.key == &solana_program::system_program::id(),
-            LyraeErrorCode::InvalidProgramId
-        )?;
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // write
+            event_queue_ai,     // write
+        ] = fixed_ais;
 
-        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let mut event_queue: EventQueue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
 
-        let _ =
-            LyraeAccount::load_checked(referrer_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
 
-        // referrer_id_record must be empty; cannot be transferred
-        check!(referrer_id_record_ai.data_is_empty(), LyraeErrorCode::InvalidAccount)?;
-        let referrer_record_seeds: &[&[u8]] =
-            &[&lyrae_group_ai.key.as_ref(), b"ReferrerIdRecord", &referrer_id];
-        seed_and_create_pda(
-            program_id,
-            payer_ai,
-            &Rent::get()?,
-            size_of::<ReferrerIdRecord>(),
-            program_id,
-            system_prog_ai,
-            referrer_id_record_ai,
-            referrer_record_seeds,
-            &[],
-        )?;
+        perp_market_cache.check_valid(&lyrae_group, now_ts)?;
 
-        ReferrerIdRecord::init(
-            referrer_id_record_ai,
-            program_id,
-            referrer_lyrae_account_ai,
-            referrer_id,
-        )
-    }
-    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> LyraeResult {
-        let instruction =
-            LyraeInstruction::unpack(data).ok_or(ProgramError::InvalidInstructionData)?;
-        match instruction {
-            LyraeInstruction::InitLyraeGroup {
-                signer_nonce,
-                valid_interval,
-                quote_optimal_util,
-                quote_optimal_rate,
-                quote_max_rate,
-            } => {
-                msg!("Lyrae: InitLyraeGroup");
-                Self::init_lyrae_group(
-                    program_id,
-                    accounts,
-                    signer_nonce,
-                    valid_interval,
-                    quote_optimal_util,
-                    quote_optimal_rate,
-                    quote_max_rate,
-                )
-            }
-            LyraeInstruction::InitLyraeAccount => {
-                msg!("Lyrae: InitLyraeAccount DEPRECATED");
-                Self::init_lyrae_account(program_id, accounts)
-            }
-            LyraeInstruction::CreateLyraeAccount { account_num } => {
-                msg!("Lyrae: CreateLyraeAccount");
-                Self::create_lyrae_account(program_id, accounts, account_num)
-            }
-            LyraeInstruction::CloseLyraeAccount => {
-                msg!("Lyrae: CloseLyraeAccount");
-                Self::close_lyrae_account(program_id, accounts)
+        for _ in 0..limit {
+            let event = match event_queue.peek_front() {
+                None => break,
+                Some(e) => e,
+            };
+
+            match EventType::try_from(event.event_type).map_err(|_| throw!())? {
+                EventType::Fill => {
+                    // Events must be processed in order, so stop at the first fill over the cap
+                    if num_logged == MAX_LOGGED_EVENTS {
+                        break;
+                    }
+                    num_logged += 1;
+                    let fill: &FillEvent = cast_ref(event);
+
+                    if !execute_fill_event(
+                        program_id,
+                        lyrae_group_ai.key,
+                        lyrae_account_ais,
+                        &mut perp_market,
+                        &lyrae_cache,
+                        market_index,
+                        &lyrae_group.perp_markets[market_index],
+                        fill,
+                    )? {
+                        break;
+                    }
+                }
+                EventType::Out => {
+                    let out: &OutEvent = cast_ref(event);
+
+                    let mut ma = match lyrae_account_ais.iter().find(|ai| ai.key == &out.owner) {
+                        None => {
+                            msg!("Unable to find account {}", out.owner.to_string());
+                            break;
+                        }
+                        Some(account_info) => LyraeAccount::load_mut_checked(
+                            account_info,
+                            program_id,
+                            lyrae_group_ai.key,
+                        )?,
+                    };
+
+                    ma.remove_order(out.slot as usize, out.quantity)?;
+                }
+                EventType::Liquidate => {
+                    // Purely for record keeping; the liquidation was applied when it was pushed
+                    if num_logged == MAX_LOGGED_EVENTS {
+                        break;
+                    }
+                    num_logged += 1;
+                    let liquidate: &LiquidateEvent = cast_ref(event);
+                    lyrae_emit!(liquidate.to_liquidate_log(*lyrae_group_ai.key, market_index));
+                }
+                EventType::Consumed => {
+                    // Already applied by consume_events_for_account; just pop it
+                }
             }
-            LyraeInstruction::UpgradeLyraeAccountV0V1 => {
-                msg!("Lyrae: UpgradeLyraeAccountV0V1");
-                Self::upgrade_lyrae_account_v0_v1(program_id, accounts)
-            }
-            LyraeInstruction::Deposit { quantity } => {
-                msg!("Lyrae: Deposit");
-                Self::deposit(program_id, accounts, quantity)
-            }
-            LyraeInstruction::Withdraw { quantity, allow_borrow } => {
-                msg!("Lyrae: Withdraw");
-                Self::withdraw(program_id, accounts, quantity, allow_borrow)
-            }
-            LyraeInstruction::AddSpotMarket {
-                maint_leverage,
-                init_leverage,
-                liquidation_fee,
-                optimal_util,
-                optimal_rate,
-                max_rate,
-            } => {
-                msg!("Lyrae: AddSpotMarket");
-                Self::add_spot_market(
-                    program_id,
-                    accounts,
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    optimal_util,
-                    optimal_rate,
-                    max_rate,
-                )
-            }
-            LyraeInstruction::AddToBasket { .. } => {
-                msg!("Lyrae: AddToBasket Deprecated");
-                Ok(())
-            }
-            LyraeInstruction::Borrow { .. } => {
-                msg!("Lyrae: Borrow DEPRECATED");
-                Ok(())
-            }
-            LyraeInstruction::CachePrices => {
-                msg!("Lyrae: CachePrices");
-                Self::cache_prices(program_id, accounts)
-            }
-            LyraeInstruction::CacheRootBanks => {
-                msg!("Lyrae: CacheRootBanks");
-                Self::cache_root_banks(program_id, accounts)
-            }
-            LyraeInstruction::PlaceSpotOrder { order } => {
-                msg!("Lyrae: PlaceSpotOrder");
-                Self::place_spot_order(program_id, accounts, order)
-            }
-            LyraeInstruction::CancelSpotOrder { order, .. } => {
-                msg!("Lyrae: CancelSpotOrder");
-                let data = serum_dex::instruction::MarketInstruction::CancelOrderV2(order).pack();
-                Self::cancel_spot_order(program_id, accounts, data)
+
+            // consume this event
+            event_queue.pop_front().map_err(|_| throw!())?;
+            num_consumed += 1;
+        }
+
+        msg!("Consumed {} events", num_consumed);
+        set_return_data(&num_consumed.to_le_bytes());
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Process up to `limit` FillEvents in which `lyrae_account` is the maker or the taker,
+    /// among the first MAX_SCANNED_EVENTS of the queue. Other events are left untouched. A
+    /// processed fill that isn't at the front is marked Consumed in place, because only the front
+    /// of the ring buffer can be popped; leading Consumed events are popped before returning.
+    fn consume_events_for_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        lyrae_account: Pubkey,
+        limit: usize,
+    ) -> LyraeResult<()> {
+        // Same cap on logged fills as consume_events. The scan is capped separately, since
+        // every event looked at costs compute even if it belongs to another account.
+        const MAX_FILL_EVENTS: usize = 4;
+        const MAX_SCANNED_EVENTS: usize = 64;
+        let limit = min(limit, MAX_FILL_EVENTS);
+
+        const NUM_FIXED: usize = 4;
+        let (fixed_ais, lyrae_account_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            perp_market_ai,     // write
+            event_queue_ai,     // write
+        ] = fixed_ais;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let mut event_queue: EventQueue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        lyrae_cache.perp_market_cache[market_index].check_valid(&lyrae_group, now_ts)?;
+
+        // Fills of one account are still applied in queue order. Skipping the events of other
+        // accounts is safe because applying fills and outs commutes across accounts, and an
+        // order can't be filled after the OutEvent that removed it from the book.
+        let mut num_fills = 0;
+        for i in 0..min(event_queue.len(), MAX_SCANNED_EVENTS) {
+            if num_fills == limit {
+                break;
             }
-            LyraeInstruction::AddOracle => {
-                msg!("Lyrae: AddOracle");
-                Self::add_oracle(program_id, accounts)
+            let event = *event_queue.get_mut(i).unwrap();
+            if EventType::try_from(event.event_type).map_err(|_| throw!())? != EventType::Fill {
+                continue;
             }
-            LyraeInstruction::SettleFunds => {
-                msg!("Lyrae: SettleFunds");
-                Self::settle_funds(program_id, accounts)
+            let fill: &FillEvent = cast_ref(&event);
+            if fill.maker != lyrae_account && fill.taker != lyrae_account {
+                continue;
             }
-            LyraeInstruction::UpdateRootBank => {
-                msg!("Lyrae: UpdateRootBank");
-                Self::update_root_bank(program_id, accounts)
+
+            if !execute_fill_event(
+                program_id,
+                lyrae_group_ai.key,
+                lyrae_account_ais,
+                &mut perp_market,
+                &lyrae_cache,
+                market_index,
+                &lyrae_group.perp_markets[market_index],
+                fill,
+            )? {
+                break;
             }
+            event_queue.get_mut(i).unwrap().event_type = EventType::Consumed.into();
+            num_fills += 1;
+        }
 
-            LyraeInstruction::AddPerpMarket {
-                maint_leverage,
-                init_leverage,
-                liquidation_fee,
-                maker_fee,
-                taker_fee,
-                base_lot_size,
-                quote_lot_size,
-                rate,
-                max_depth_bps,
-                target_period_length,
-                lyr_per_period,
-                exp,
-            } => {
-                msg!("Lyrae: AddPerpMarket DEPRECATED");
-                Self::add_perp_market(
-                    program_id,
-                    accounts,
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    base_lot_size,
-                    quote_lot_size,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                    exp,
-                )
+        while let Some(event) = event_queue.peek_front() {
+            if event.event_type != EventType::Consumed as u8 {
+                break;
             }
-            LyraeInstruction::PlacePerpOrder {
-                side,
-                price,
-                quantity,
-                client_order_id,
-                order_type,
-                reduce_only,
-            } => {
-                msg!("Lyrae: PlacePerpOrder client_order_id={}", client_order_id);
-                Self::place_perp_order(
-                    program_id,
-                    accounts,
-                    side,
-                    price,
-                    quantity,
-                    client_order_id,
-                    order_type,
-                    reduce_only,
-                )
+            event_queue.pop_front().map_err(|_| throw!())?;
+        }
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Update the `funding_earned` of a `PerpMarket` using the current book price, spot index price
+    /// and time since last update
+    fn update_funding(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // write
+            perp_market_ai,     // write
+            bids_ai,            // read
+            asks_ai,            // read
+        ] = accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+
+        let mut lyrae_cache =
+            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+
+        // Fails with InvalidAccount if bids_ai or asks_ai is not this market's book
+        let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+
+        perp_market.update_funding(&lyrae_group, &book, &lyrae_cache, market_index, now_ts)?;
+        lyrae_cache.perp_market_cache[market_index] = PerpMarketCache {
+            long_funding: perp_market.long_funding,
+            short_funding: perp_market.short_funding,
+            last_update: now_ts,
+        };
+
+        // only need to use UpdateFundingLog; don't worry about CachePerpMarket log
+        lyrae_emit!(UpdateFundingLog {
+            lyrae_group: *lyrae_group_ai.key,
+            market_index: market_index as u64,
+            long_funding: perp_market.long_funding.to_bits(),
+            short_funding: perp_market.short_funding.to_bits(),
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Same as update_funding for each (perp_market, bids, asks) and update_root_bank for each
+    /// (root_bank, node_banks...) group in the accounts. Stops after MAX_UPDATE_ALL_CACHES_ITEMS
+    /// items to stay within compute limits.
+    fn update_all_caches(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        num_perp_markets: usize,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 2;
+        let (fixed_accounts, rest) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai, // read
+            lyrae_cache_ai, // write
+        ] = fixed_accounts;
+        check!(rest.len() >= 3 * num_perp_markets, LyraeErrorCode::InvalidAccount)?;
+        let (perp_market_ais, mut bank_ais) = rest.split_at(3 * num_perp_markets);
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_cache =
+            LyraeCache::load_mut_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let mut num_items = 0;
+
+        for market_ais in perp_market_ais.chunks_exact(3) {
+            if num_items == MAX_UPDATE_ALL_CACHES_ITEMS {
+                msg!("Item limit reached; remaining perp markets not updated");
+                return Ok(());
             }
-            LyraeInstruction::CancelPerpOrderByClientId { client_order_id, invalid_id_ok } => {
-                msg!("Lyrae: CancelPerpOrderByClientId client_order_id={}", client_order_id);
-                let result =
-                    Self::cancel_perp_order_by_client_id(program_id, accounts, client_order_id);
-                if invalid_id_ok {
-                    if let Err(LyraeError::LyraeErrorCode { lyrae_error_code, .. }) = result {
-                        if lyrae_error_code == LyraeErrorCode::InvalidOrderId
-                            || lyrae_error_code == LyraeErrorCode::ClientIdNotFound
-                        {
-                            return Ok(());
-                        }
-                    }
-                }
-                result
+            num_items += 1;
+
+            let [perp_market_ai, bids_ai, asks_ai] = array_ref![market_ais, 0, 3];
+            let mut perp_market =
+                PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+            let book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+            let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+
+            perp_market.update_funding(&lyrae_group, &book, &lyrae_cache, market_index, now_ts)?;
+            lyrae_cache.perp_market_cache[market_index] = PerpMarketCache {
+                long_funding: perp_market.long_funding,
+                short_funding: perp_market.short_funding,
+                last_update: now_ts,
+            };
+
+            lyrae_emit!(UpdateFundingLog {
+                lyrae_group: *lyrae_group_ai.key,
+                market_index: market_index as u64,
+                long_funding: perp_market.long_funding.to_bits(),
+                short_funding: perp_market.short_funding.to_bits(),
+            });
+        }
+
+        while let Some((root_bank_ai, rest)) = bank_ais.split_first() {
+            if num_items == MAX_UPDATE_ALL_CACHES_ITEMS {
+                msg!("Item limit reached; remaining root banks not updated");
+                return Ok(());
             }
-            LyraeInstruction::CancelPerpOrder { order_id, invalid_id_ok } => {
-                // TODO OPT this log may cost too much compute
-                msg!("Lyrae: CancelPerpOrder order_id={}", order_id);
-                let result = Self::cancel_perp_order(program_id, accounts, order_id);
-                if invalid_id_ok {
-                    if let Err(LyraeError::LyraeErrorCode { lyrae_error_code, .. }) = result {
-                        if lyrae_error_code == LyraeErrorCode::InvalidOrderId {
-                            return Ok(());
-                        }
+            num_items += 1;
+
+            let index = lyrae_group
+                .find_root_bank_index(root_bank_ai.key)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+            let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
+            let num_node_banks = root_bank.num_node_banks;
+            check!(rest.len() >= num_node_banks, LyraeErrorCode::InvalidNodeBank)?;
+            let (node_bank_ais, rest) = rest.split_at(num_node_banks);
+            update_root_bank_and_cache(
+                program_id,
+                lyrae_group_ai.key,
+                &mut lyrae_cache,
+                index,
+                &mut root_bank,
+                node_bank_ais,
+                now_ts,
+            )?;
+
+            bank_ais = rest;
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Settle the lyr_accrued in a PerpAccount for LYR tokens
+    fn redeem_lyr(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 11;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // read
+            lyr_perp_vault_ai, // write
+        lyr_root_bank_ai,  // read
+        lyr_node_bank_ai,  // write
+        lyr_bank_vault_ai, // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let lyr_index = lyrae_group
+            .find_root_bank_index(lyr_root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let lyr_bank_cache = &lyrae_cache.root_bank_cache[lyr_index];
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_MANAGE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let perp_account = &mut lyrae_account.perp_accounts[market_index];
+
+        // Load the lyr banks
+        let root_bank = RootBank::load_checked(lyr_root_bank_ai, program_id)?;
+        check!(
+            root_bank.node_banks.contains(lyr_node_bank_ai.key),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+        let mut lyr_node_bank = NodeBank::load_mut_checked(lyr_node_bank_ai, program_id)?;
+        check_eq!(&lyr_node_bank.vault, lyr_bank_vault_ai.key, LyraeErrorCode::InvalidVault)?;
+
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        check!(lyr_perp_vault_ai.key == &perp_market.lyr_vault, LyraeErrorCode::InvalidVault)?;
+
+        let lyr_perp_vault = Account::unpack(&lyr_perp_vault_ai.try_borrow_data()?)?;
+
+        let lyr = min(perp_account.lyr_accrued, lyr_perp_vault.amount);
+        if lyr == 0 {
+            // Nothing accrued or the vault is waiting on a top-up; skip the no-op transfer and log
+            msg!(
+                "Nothing to redeem: lyr_accrued={} lyr_vault_amount={}",
+                perp_account.lyr_accrued,
+                lyr_perp_vault.amount
+            );
+            return Ok(());
+        }
+        perp_account.lyr_accrued -= lyr;
+
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            lyr_perp_vault_ai,
+            lyr_bank_vault_ai,
+            signer_ai,
+            &[&signers_seeds],
+            lyr,
+        )?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        lyr_bank_cache.check_valid(&lyrae_group, now_ts)?;
+
+        let redeemed_lyr = I80F48::from_num(lyr);
+        checked_change_net(
+            lyr_bank_cache,
+            &mut lyr_node_bank,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            lyr_index,
+            redeemed_lyr,
+        )?;
+
+        lyrae_emit!(RedeemLyrLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_index: market_index as u64,
+            redeemed_lyr: lyr,
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Settle the lyr_accrued in a PerpAccount directly into an external LYR token account,
+    /// bypassing the LYR deposits of the LyraeAccount
+    fn redeem_lyr_to_token_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 8;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // read
+            lyr_perp_vault_ai,  // write
+            token_account_ai,   // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        // The LYR leaves the LyraeAccount to an arbitrary token account, so like withdraw this is
+        // restricted to the owner
+        check_eq!(&lyrae_account.owner, owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let perp_market = PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        check!(lyr_perp_vault_ai.key == &perp_market.lyr_vault, LyraeErrorCode::InvalidVault)?;
+
+        let lyr_perp_vault = Account::unpack(&lyr_perp_vault_ai.try_borrow_data()?)?;
+
+        // Make sure the destination holds the same mint as the vault paying out
+        let token_account = Account::unpack(&token_account_ai.try_borrow_data()?)?;
+        check!(token_account.mint == lyr_perp_vault.mint, LyraeErrorCode::InvalidToken)?;
+
+        let perp_account = &mut lyrae_account.perp_accounts[market_index];
+        let lyr = min(perp_account.lyr_accrued, lyr_perp_vault.amount);
+        if lyr == 0 {
+            // Nothing accrued or the vault is waiting on a top-up; skip the no-op transfer and log
+            msg!(
+                "Nothing to redeem: lyr_accrued={} lyr_vault_amount={}",
+                perp_account.lyr_accrued,
+                lyr_perp_vault.amount
+            );
+            return Ok(());
+        }
+        perp_account.lyr_accrued -= lyr;
+
+        let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            lyr_perp_vault_ai,
+            token_account_ai,
+            signer_ai,
+            &[&signers_seeds],
+            lyr,
+        )?;
+
+        lyrae_emit!(RedeemLyrLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_index: market_index as u64,
+            redeemed_lyr: lyr,
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn add_lyrae_account_info(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        info: [u8; INFO_LEN],
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai            // signer
+        ] = accounts;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_MANAGE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
+        lyrae_account.info = info;
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn deposit_msrm(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            msrm_account_ai,    // write
+            msrm_vault_ai,      // write
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(msrm_vault_ai.key == &lyrae_group.msrm_vault, LyraeErrorCode::InvalidVault)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        invoke_transfer(token_prog_ai, msrm_account_ai, msrm_vault_ai, owner_ai, &[], quantity)?;
+
+        lyrae_account.msrm_amount += quantity;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn withdraw_msrm(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        quantity: u64,
+    ) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 7;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            msrm_account_ai,    // write
+            msrm_vault_ai,      // write
+            signer_ai,          // read
+            token_prog_ai,      // read
+        ] = accounts;
+        check!(token_prog_ai.key == &spl_token::ID, LyraeErrorCode::InvalidProgramId)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(msrm_vault_ai.key == &lyrae_group.msrm_vault, LyraeErrorCode::InvalidVault)?;
+        check!(signer_ai.key == &lyrae_group.signer_key, LyraeErrorCode::InvalidSignerKey)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        check!(lyrae_account.msrm_amount >= quantity, LyraeErrorCode::InsufficientFunds)?;
+
+        let signer_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_ai.key);
+        invoke_transfer(
+            token_prog_ai,
+            msrm_vault_ai,
+            msrm_account_ai,
+            signer_ai,
+            &[&signer_seeds],
+            quantity,
+        )?;
+
+        lyrae_account.msrm_amount -= quantity;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_group_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // write
+            new_admin_ai,       // read
+            admin_ai,           // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_group.admin = *new_admin_ai.key;
+
+        lyrae_emit!(GroupAdminChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            old_admin: *admin_ai.key,
+            new_admin: *new_admin_ai.key,
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn init_advanced_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // write
+            owner_ai,               // write & signer
+            advanced_orders_ai,     // write
+            system_prog_ai,         // read
+        ] = accounts;
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let _lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_TRIGGER),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+
+        // Make sure the LyraeAccount doesn't already have a AdvancedOrders set
+        check!(
+            lyrae_account.advanced_orders_key == Pubkey::default(),
+            LyraeErrorCode::InvalidParam
+        )?;
+
+        let (pda_address, bump_seed) =
+            Pubkey::find_program_address(&[&lyrae_account_ai.key.to_bytes()], program_id);
+        check!(&pda_address == advanced_orders_ai.key, LyraeErrorCode::InvalidAccount)?;
+
+        let pda_signer_seeds: &[&[u8]] = &[&lyrae_account_ai.key.to_bytes(), &[bump_seed]];
+        let rent = Rent::get()?;
+        create_pda_account(
+            owner_ai,
+            &rent,
+            size_of::<AdvancedOrders>(),
+            program_id,
+            system_prog_ai,
+            advanced_orders_ai,
+            pda_signer_seeds,
+            &[],
+        )?;
+
+        // initialize the AdvancedOrders account
+        AdvancedOrders::init(advanced_orders_ai, program_id, &rent)?;
+
+        // set the lyrae_account.advanced_orders field
+        lyrae_account.advanced_orders_key = *advanced_orders_ai.key;
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn close_advanced_orders(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult<()> {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // write, signer
+            advanced_orders_ai, // write
+        ] = accounts;
+
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(!lyrae_account.being_liquidated, LyraeErrorCode::BeingLiquidated)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+
+        let mut advanced_orders =
+            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
+        for i in 0..MAX_ADVANCED_ORDERS {
+            advanced_orders.orders[i].is_active = false;
+        }
+        advanced_orders.meta_data.is_initialized = false;
+
+        // Transfer lamports to owner
+        program_transfer_lamports(advanced_orders_ai, owner_ai, advanced_orders_ai.lamports())?;
+
+        lyrae_account.advanced_orders_key = Pubkey::default();
+
+        Ok(())
+    }
+
+    /// Add a perp trigger order to the AdvancedOrders account
+    /// The TriggerCondition specifies if trigger_price  must be above or below oracle price
+    /// When the condition is met, the order is executed as a regular perp order
+    #[inline(never)]
+    fn add_perp_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_type: OrderType,
+        side: Side,
+        trigger_condition: TriggerCondition,
+        reduce_only: bool,
+        client_order_id: u64,
+        price: i64,
+        quantity: i64,
+        trigger_price: I80F48,
+        trail_offset: I80F48,
+    ) -> LyraeResult<()> {
+        check!(price.is_positive(), LyraeErrorCode::InvalidParam)?;
+        check!(quantity.is_positive(), LyraeErrorCode::InvalidParam)?;
+        check!(trigger_price.is_positive(), LyraeErrorCode::InvalidParam)?; // Is this necessary?
+        if trigger_condition.is_trailing() {
+            check!(trail_offset.is_positive(), LyraeErrorCode::InvalidParam)?;
+        } else {
+            check!(trail_offset.is_zero(), LyraeErrorCode::InvalidParam)?;
+        }
+
+        const NUM_FIXED: usize = 7;
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // read
+            owner_ai,               // write & signer
+            advanced_orders_ai,     // write
+            lyrae_cache_ai,         // read
+            perp_market_ai,         // read
+            system_prog_ai,         // read
+        ] = fixed_ais;
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_TRIGGER),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        let open_orders_ais =
+            lyrae_account.checked_unpack_open_orders(&lyrae_group, open_orders_ais)?;
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
+
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+        let active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &lyrae_account,
+            vec![(AssetType::Perp, market_index)],
+        );
+
+        // load and validate the cache
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals_with_orders_vec(
+            &lyrae_group,
+            &lyrae_cache,
+            &lyrae_account,
+            &open_orders_accounts,
+        )?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+
+        // Only allow placing of trigger orders if account above Maint and not being liquidated
+        check!(
+            init_health >= ZERO_I80F48
+                || (!lyrae_account.being_liquidated && maint_health >= ZERO_I80F48),
+            LyraeErrorCode::InsufficientHealth
+        )?;
+        lyrae_account.being_liquidated = false;
+
+        // Note: no need to check health here, needs to be checked on trigger
+        // TODO: make sure liquidator cancels all advanced orders (why?)
+        // Transfer lamports before so we don't hit rust borrow checker
+        // If we don't succeed in adding the order, it will be reverted anyway
+        invoke_transfer_lamports(
+            owner_ai,
+            advanced_orders_ai,
+            system_prog_ai,
+            ADVANCED_ORDER_FEE,
+            &[],
+        )?;
+
+        let mut advanced_orders =
+            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
+        for i in 0..MAX_ADVANCED_ORDERS {
+            if advanced_orders.orders[i].is_active {
+                continue;
+            }
+
+            advanced_orders.orders[i] = cast(PerpTriggerOrder::new(
+                market_index as u8,
+                order_type,
+                side,
+                trigger_condition,
+                reduce_only,
+                client_order_id,
+                price,
+                quantity,
+                trigger_price,
+                trail_offset,
+            ));
+
+            return Ok(());
+        }
+
+        Err(throw_err!(LyraeErrorCode::OutOfSpace))
+    }
+
+    /// Remove the order and refund the fee
+    #[inline(never)]
+    fn remove_advanced_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_index: u8,
+    ) -> LyraeResult<()> {
+        let order_index = order_index as usize;
+        check!(order_index < MAX_ADVANCED_ORDERS, LyraeErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 5;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // read
+            owner_ai,               // write & signer
+            advanced_orders_ai,     // write
+            system_prog_ai,         // read
+        ] = accounts;
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_TRIGGER),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+        // No bankruptcy check; removing order is fine
+
+        let mut advanced_orders =
+            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
+
+        let order = &mut advanced_orders.orders[order_index];
+
+        if order.is_active {
+            order.is_active = false;
+            program_transfer_lamports(advanced_orders_ai, owner_ai, ADVANCED_ORDER_FEE)
+        } else {
+            Ok(())
+        }
+    }
+
+    #[inline(never)]
+    fn execute_perp_trigger_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_index: u8,
+    ) -> LyraeResult<()> {
+        let order_index = order_index as usize;
+        check!(order_index < MAX_ADVANCED_ORDERS, LyraeErrorCode::InvalidParam)?;
+        const NUM_FIXED: usize = 9;
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // write
+            advanced_orders_ai,     // write
+            agent_ai,               // write
+            lyrae_cache_ai,         // read
+            perp_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            event_queue_ai,         // write
+        ] = fixed_ais;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let open_orders_ais =
+            lyrae_account.checked_unpack_open_orders(&lyrae_group, open_orders_ais)?;
+        let open_orders_accounts = load_open_orders_accounts(&open_orders_ais)?;
+
+        let mut advanced_orders =
+            AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
+
+        // deactivate all advanced orders if account is bankrupt
+        if lyrae_account.is_bankrupt {
+            msg!("Failed to trigger order; LyraeAccount is bankrupt.");
+            return cancel_all_advanced_orders(advanced_orders_ai, &mut advanced_orders, agent_ai);
+        }
+
+        // Select the AdvancedOrder
+        let order: &mut PerpTriggerOrder = cast_mut(&mut advanced_orders.orders[order_index]);
+        check!(order.is_active, LyraeErrorCode::InvalidParam)?;
+        check!(
+            order.advanced_order_type == AdvancedOrderType::PerpTrigger,
+            LyraeErrorCode::InvalidParam
+        )?;
+        let market_index = order.market_index as usize;
+
+        // Check the caches are valid
+        let active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &lyrae_account,
+            vec![(AssetType::Perp, market_index)],
+        );
+
+        let clock = Clock::get()?;
+        let now_ts = clock.unix_timestamp as u64;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+
+        // Check trigger condition is met
+        let price = lyrae_cache.get_price(market_index);
+        if !order.update_and_check_trigger(price) {
+            // A trailing order must keep its moved trigger price, so don't fail the transaction
+            if order.trigger_condition.is_trailing() {
+                msg!("Trailing trigger price moved to {}", order.trigger_price.to_num::<f64>());
+                return Ok(());
+            }
+            return Err(throw_err!(LyraeErrorCode::TriggerConditionFalse));
+        }
+        check!(
+            &lyrae_group.perp_markets[market_index].perp_market == perp_market_ai.key,
+            LyraeErrorCode::InvalidMarket
+        )?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+
+        execute_triggered_perp_order(
+            program_id,
+            &lyrae_group,
+            lyrae_group_ai.key,
+            &lyrae_cache,
+            &mut perp_market,
+            &mut book,
+            &mut event_queue,
+            &mut lyrae_account,
+            lyrae_account_ai.key,
+            &open_orders_accounts,
+            active_assets,
+            advanced_orders_ai,
+            &mut advanced_orders,
+            agent_ai,
+            order_index,
+            now_ts,
+        )
+    }
+
+    #[inline(never)]
+    /// Execute PerpTrigger orders of several LyraeAccounts on one perp market. Orders that are no
+    /// longer active, are for another market or whose trigger condition is not met are skipped.
+    fn execute_perp_trigger_orders_multi(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        order_indexes: &[u8],
+    ) -> LyraeResult<()> {
+        let num_orders = order_indexes.len();
+        check!(num_orders <= MAX_EXECUTE_TRIGGER_ORDERS, LyraeErrorCode::InvalidParam)?;
+        const NUM_FIXED: usize = 7;
+        let (fixed_ais, remaining_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,         // read
+            agent_ai,               // write
+            lyrae_cache_ai,         // read
+            perp_market_ai,         // write
+            bids_ai,                // write
+            asks_ai,                // write
+            event_queue_ai,         // write
+        ] = fixed_ais;
+        check!(remaining_ais.len() >= 2 * num_orders, LyraeErrorCode::InvalidParam)?;
+        let (order_ais, open_orders_ais) = remaining_ais.split_at(2 * num_orders);
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(!lyrae_group.is_paused(), LyraeErrorCode::GroupPaused)?;
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let mut event_queue =
+            EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+
+        for (i, &order_index) in order_indexes.iter().enumerate() {
+            let order_index = order_index as usize;
+            check!(order_index < MAX_ADVANCED_ORDERS, LyraeErrorCode::InvalidParam)?;
+            let lyrae_account_ai = &order_ais[2 * i];
+            let advanced_orders_ai = &order_ais[2 * i + 1];
+
+            let mut lyrae_account =
+                LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+            let mut advanced_orders =
+                AdvancedOrders::load_mut_checked(advanced_orders_ai, program_id, &lyrae_account)?;
+
+            // deactivate all advanced orders if account is bankrupt
+            if lyrae_account.is_bankrupt {
+                msg!("Failed to trigger order; LyraeAccount is bankrupt.");
+                cancel_all_advanced_orders(advanced_orders_ai, &mut advanced_orders, agent_ai)?;
+                continue;
+            }
+
+            let order: &mut PerpTriggerOrder = cast_mut(&mut advanced_orders.orders[order_index]);
+            if !order.is_active
+                || order.advanced_order_type != AdvancedOrderType::PerpTrigger
+                || order.market_index as usize != market_index
+            {
+                continue;
+            }
+
+            let active_assets = UserActiveAssets::new(
+                &lyrae_group,
+                &lyrae_account,
+                vec![(AssetType::Perp, market_index)],
+            );
+            // One account's stale cache or missing open orders must not fail the whole batch
+            if lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts).is_err() {
+                msg!("Failed to trigger order {}; stale cache", i);
+                continue;
+            }
+
+            // Trailing orders move their trigger price here even if they don't trigger yet
+            let price = lyrae_cache.get_price(market_index);
+            if !order.update_and_check_trigger(price) {
+                continue;
+            }
+
+            let open_orders_accounts = match lyrae_account
+                .checked_unpack_open_orders(&lyrae_group, open_orders_ais)
+                .and_then(|ais| Ok(load_open_orders_accounts(&ais)?))
+            {
+                Ok(open_orders_accounts) => open_orders_accounts,
+                Err(_) => {
+                    msg!("Failed to trigger order {}; invalid open orders", i);
+                    continue;
+                }
+            };
+
+            execute_triggered_perp_order(
+                program_id,
+                &lyrae_group,
+                lyrae_group_ai.key,
+                &lyrae_cache,
+                &mut perp_market,
+                &mut book,
+                &mut event_queue,
+                &mut lyrae_account,
+                lyrae_account_ai.key,
+                &open_orders_accounts,
+                active_assets,
+                advanced_orders_ai,
+                &mut advanced_orders,
+                agent_ai,
+                order_index,
+                now_ts,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Create a LyraeAccount PDA and initialize it
+    #[inline(never)]
+    fn create_lyrae_account(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        account_num: u64,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let fixed_accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,         // write
+            lyrae_account_ai,       // write
+            owner_ai,               // read (write if no payer passed) & signer
+            system_prog_ai,         // read
+        ] = fixed_accounts;
+        let payer_ai = if accounts.len() > NUM_FIXED {
+            &accounts[NUM_FIXED] // write & signer
+        } else {
+            owner_ai
+        };
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(payer_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(
+            lyrae_group.num_lyrae_accounts < lyrae_group.max_lyrae_accounts,
+            LyraeErrorCode::MaxAccountsReached
+        )?;
+        let rent = Rent::get()?;
+
+        let lyrae_account_seeds: &[&[u8]] =
+            &[&lyrae_group_ai.key.as_ref(), &owner_ai.key.as_ref(), &account_num.to_le_bytes()];
+        seed_and_create_pda(
+            program_id,
+            payer_ai,
+            &rent,
+            size_of::<LyraeAccount>(),
+            program_id,
+            system_prog_ai,
+            lyrae_account_ai,
+            lyrae_account_seeds,
+            &[],
+        )?;
+        let mut lyrae_account: RefMut<LyraeAccount> = LyraeAccount::load_mut(lyrae_account_ai)?;
+        check!(!lyrae_account.meta_data.is_initialized, LyraeErrorCode::InvalidAccountState)?;
+
+        lyrae_account.lyrae_group = *lyrae_group_ai.key;
+        lyrae_account.owner = *owner_ai.key;
+        lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        lyrae_account.meta_data = MetaData::new(DataType::LyraeAccount, 1, true);
+
+        lyrae_group.num_lyrae_accounts =
+            lyrae_group.num_lyrae_accounts.checked_add(1).ok_or(math_err!())?;
+
+        lyrae_emit!(CreateLyraeAccountLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            owner: *owner_ai.key,
+            account_num,
+        });
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn update_margin_basket(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // write
+        ] = fixed_ais;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+
+        for i in 0..lyrae_group.num_oracles {
+            check_eq!(
+                open_orders_ais[i].key,
+                &lyrae_account.spot_open_orders[i],
+                LyraeErrorCode::InvalidOpenOrdersAccount
+            )?;
+
+            if lyrae_account.spot_open_orders[i] != Pubkey::default() {
+                check_open_orders(
+                    &open_orders_ais[i],
+                    &lyrae_group.signer_key,
+                    &lyrae_group.dex_program_id,
+                )?;
+                let open_orders = load_open_orders(&open_orders_ais[i])?;
+                lyrae_account.update_basket(i, &open_orders)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Change the maximum number of LyraeAccounts.v1 allowed
+    fn change_max_lyrae_accounts(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_lyrae_accounts: u32,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        // Leave room so num_lyrae_accounts can always be incremented up to the cap
+        check!(max_lyrae_accounts < u32::MAX, LyraeErrorCode::InvalidParam)?;
+
+        // May be set at or below num_lyrae_accounts to stop creation of new accounts; existing
+        // accounts are unaffected and can still be closed
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::MaxLyraeAccounts as u8,
+            old_value: lyrae_group.max_lyrae_accounts as u64,
+            new_value: max_lyrae_accounts as u64,
+        });
+        lyrae_group.max_lyrae_accounts = max_lyrae_accounts;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the max fraction (in bps) of a perp position that can be liquidated at once; 0 disables
+    fn change_max_liquidation_fraction(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        max_liquidation_fraction_bps: u16,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+        check!(max_liquidation_fraction_bps <= BPS_PER_UNIT, LyraeErrorCode::InvalidParam)?;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_group.max_liquidation_fraction_bps = max_liquidation_fraction_bps;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the floor (in bps) applied to every market's liquidation_fee; 0 disables
+    fn change_min_liquidation_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_liquidation_fee_bps: u16,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+        check!(min_liquidation_fee_bps < BPS_PER_UNIT, LyraeErrorCode::InvalidParam)?;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        // Liquidations must still improve health in every listed market at the new floor
+        let min_fee = I80F48::from_num(min_liquidation_fee_bps) / I80F48::from_num(BPS_PER_UNIT);
+        check!(lyrae_group.is_valid_min_liquidation_fee(min_fee), LyraeErrorCode::InvalidParam)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::MinLiquidationFeeBps as u8,
+            old_value: lyrae_group.min_liquidation_fee_bps as u64,
+            new_value: min_liquidation_fee_bps as u64,
+        });
+
+        lyrae_group.min_liquidation_fee_bps = min_liquidation_fee_bps;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the min init health improvement, in whole quote tokens, of a capped token liquidation
+    fn change_min_liquidation_health_improvement(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_health_improvement: u32,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::MinLiquidationHealthImprovement as u8,
+            old_value: lyrae_group.get_min_liquidation_health_improvement() as u64,
+            new_value: min_health_improvement as u64,
+        });
+        lyrae_group.set_min_liquidation_health_improvement(min_health_improvement);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Change the serum dex program id used by all spot instructions
+    fn set_dex_program_id(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let (fixed_ais, spot_market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai, // write
+            admin_ai,       // read, signer
+            dex_prog_ai,    // read
+        ] = fixed_ais;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(dex_prog_ai.executable, LyraeErrorCode::InvalidProgramId)?;
+        check!(dex_prog_ai.key != &lyrae_group.dex_program_id, LyraeErrorCode::InvalidParam)?;
+
+        // Spot open orders of the old dex can't be left on markets the group still lists, so
+        // every listed spot market must have been moved to the new dex by ReassignSpotMarket
+        let mut spot_market_ais = spot_market_ais.iter();
+        for i in 0..lyrae_group.num_oracles {
+            if lyrae_group.spot_markets[i].is_empty() {
+                continue;
+            }
+            let spot_market_ai =
+                spot_market_ais.next().ok_or(throw_err!(LyraeErrorCode::InvalidAccount))?;
+            check_eq!(
+                spot_market_ai.key,
+                &lyrae_group.spot_markets[i].spot_market,
+                LyraeErrorCode::InvalidMarket
+            )?;
+            check_eq!(spot_market_ai.owner, dex_prog_ai.key, LyraeErrorCode::InvalidMarket)?;
+        }
+
+        lyrae_emit!(DexProgramIdChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            old_dex_program_id: lyrae_group.dex_program_id,
+            new_dex_program_id: *dex_prog_ai.key,
+        });
+        lyrae_group.dex_program_id = *dex_prog_ai.key;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the max Pyth confidence interval, relative to the price, accepted by read_oracle
+    fn set_pyth_conf_filter(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        conf_filter_bps: u16,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+        check!(conf_filter_bps <= BPS_PER_UNIT, LyraeErrorCode::InvalidParam)?;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::PythConfFilterBps as u8,
+            old_value: u16::from_le_bytes(lyrae_group.pyth_conf_filter_bps) as u64,
+            new_value: conf_filter_bps as u64,
+        });
+        lyrae_group.set_pyth_conf_filter_bps(conf_filter_bps);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Return init health, maint health and equity of a LyraeAccount through the return data
+    fn compute_health(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            lyrae_cache_ai,     // read
+        ] = fixed_ais;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+        let maint_health = health_cache.get_health(&lyrae_group, HealthType::Maint);
+        let equity = health_cache.get_equity(&lyrae_group);
+
+        let mut data = Vec::with_capacity(48);
+        data.extend_from_slice(&init_health.to_bits().to_le_bytes());
+        data.extend_from_slice(&maint_health.to_bits().to_le_bytes());
+        data.extend_from_slice(&equity.to_bits().to_le_bytes());
+        set_return_data(&data);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Move a perp position between two LyraeAccounts of the same owner without trading
+    fn transfer_perp_position(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + 2 * MAX_PAIRS];
+        let (fixed_ais, src_open_orders_ais, dst_open_orders_ais) =
+            array_refs![accounts, NUM_FIXED, MAX_PAIRS, MAX_PAIRS];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_cache_ai,         // read
+            perp_market_ai,         // write
+            src_lyrae_account_ai,   // write
+            dst_lyrae_account_ai,   // write
+            owner_ai,               // read, signer
+        ] = fixed_ais;
+        check!(src_lyrae_account_ai.key != dst_lyrae_account_ai.key, LyraeErrorCode::InvalidParam)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut src_ma =
+            LyraeAccount::load_mut_checked(src_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        let mut dst_ma =
+            LyraeAccount::load_mut_checked(dst_lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check_eq!(src_ma.owner, dst_ma.owner, LyraeErrorCode::InvalidOwner)?;
+        check!(
+            src_ma.is_owner_or_delegate(owner_ai.key, DELEGATE_ALL)
+                && dst_ma.is_owner_or_delegate(owner_ai.key, DELEGATE_ALL),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(!src_ma.is_bankrupt && !dst_ma.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(
+            !src_ma.being_liquidated && !dst_ma.being_liquidated,
+            LyraeErrorCode::BeingLiquidated
+        )?;
+        src_ma.check_open_orders(&lyrae_group, src_open_orders_ais)?;
+        dst_ma.check_open_orders(&lyrae_group, dst_open_orders_ais)?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+        let market_index = lyrae_group
+            .find_perp_market_index(perp_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let src_active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &src_ma,
+            vec![(AssetType::Perp, market_index)],
+        );
+        let dst_active_assets = UserActiveAssets::new(
+            &lyrae_group,
+            &dst_ma,
+            vec![(AssetType::Perp, market_index)],
+        );
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(
+            &lyrae_group,
+            &UserActiveAssets::merge(&src_active_assets, &dst_active_assets),
+            now_ts,
+        )?;
+        let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
+
+        // Pending fills and resting orders of the source refer to its base position
+        check!(
+            src_ma.perp_accounts[market_index].has_no_open_orders(),
+            LyraeErrorCode::InvalidAccountState
+        )?;
+
+        transfer_perp_position_internal(
+            &mut perp_market,
+            perp_market_cache,
+            &mut src_ma.perp_accounts[market_index],
+            &mut dst_ma.perp_accounts[market_index],
+        );
+
+        let mut src_health_cache = HealthCache::new(src_active_assets);
+        src_health_cache.init_vals(&lyrae_group, &lyrae_cache, &src_ma, src_open_orders_ais)?;
+        let src_health = src_health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(src_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+
+        let mut dst_health_cache = HealthCache::new(dst_active_assets);
+        dst_health_cache.init_vals(&lyrae_group, &lyrae_cache, &dst_ma, dst_open_orders_ais)?;
+        let dst_health = dst_health_cache.get_health(&lyrae_group, HealthType::Init);
+        check!(dst_health >= ZERO_I80F48, LyraeErrorCode::InsufficientFunds)?;
+
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *src_lyrae_account_ai.key,
+            market_index as u64,
+            &src_ma.perp_accounts[market_index],
+            perp_market_cache,
+        );
+        emit_perp_balances(
+            *lyrae_group_ai.key,
+            *dst_lyrae_account_ai.key,
+            market_index as u64,
+            &dst_ma.perp_accounts[market_index],
+            perp_market_cache,
+        );
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Log the borrows left on a LyraeAccount. Deposits and borrows of a token are netted on
+    /// every change, so there is never a same-token deposit to repay them from.
+    fn log_remaining_borrows(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            lyrae_account_ai,   // read
+        ] = accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+
+        for token_index in 0..MAX_TOKENS {
+            if !lyrae_account.borrows[token_index].is_positive() {
+                continue;
+            }
+            let root_bank_cache = &lyrae_cache.root_bank_cache[token_index];
+            let native_borrow = lyrae_account.get_native_borrow(root_bank_cache, token_index)?;
+            msg!("Borrow remains in token {}: {}", token_index, native_borrow);
+            lyrae_emit!(TokenBalanceLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                token_index: token_index as u64,
+                deposit: lyrae_account.deposits[token_index].to_bits(),
+                borrow: lyrae_account.borrows[token_index].to_bits()
+            });
+        }
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the min equity an account needs to place risk increasing orders
+    fn set_min_collateral_to_trade(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        min_collateral_to_trade: u32,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::MinCollateralToTrade as u8,
+            old_value: lyrae_group.get_min_collateral_to_trade() as u64,
+            new_value: min_collateral_to_trade as u64,
+        });
+        lyrae_group.set_min_collateral_to_trade(min_collateral_to_trade);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the percent of settled perp fees routed to the insurance vault
+    fn set_fees_insurance_share(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        insurance_share_pct: u8,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(insurance_share_pct <= 100, LyraeErrorCode::InvalidParam)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::FeesInsuranceSharePct as u8,
+            old_value: lyrae_group.fees_insurance_share_pct as u64,
+            new_value: insurance_share_pct as u64,
+        });
+        lyrae_group.fees_insurance_share_pct = insurance_share_pct;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Clear `being_liquidated` on an account whose init health is positive
+    fn admin_clear_liquidation_flag(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED + MAX_PAIRS];
+        let (fixed_ais, open_orders_ais) = array_refs![accounts, NUM_FIXED, MAX_PAIRS];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_cache_ai,     // read
+            lyrae_account_ai,   // write
+            admin_ai,           // read, signer
+        ] = fixed_ais;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        lyrae_account.check_open_orders(&lyrae_group, open_orders_ais)?;
+
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
+        let lyrae_cache = LyraeCache::load_checked(lyrae_cache_ai, program_id, &lyrae_group)?;
+        lyrae_cache.check_valid(&lyrae_group, &active_assets, now_ts)?;
+
+        let mut health_cache = HealthCache::new(active_assets);
+        health_cache.init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)?;
+        let init_health = health_cache.get_health(&lyrae_group, HealthType::Init);
+
+        // Same threshold at which the liquidate instructions end a liquidation
+        check!(init_health > ZERO_I80F48, LyraeErrorCode::InsufficientHealth)?;
+        lyrae_account.being_liquidated = false;
+
+        lyrae_emit!(LiquidationStatusLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            being_liquidated: false,
+            init_health: init_health.to_bits(),
+        });
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set whether a LyraeAccount is exempt from the referral surcharge
+    fn set_mm_exempt(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        mm_exempt: bool,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            admin_ai            // read, signer
+        ] = accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        msg!("mm_exempt: {} -> {}", lyrae_account.is_mm_exempt(), mm_exempt);
+        lyrae_account.set_mm_exempt(mm_exempt);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the deposit and withdraw fees of a token
+    fn set_token_fees(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // read
+            root_bank_ai,   // write
+            admin_ai        // read, signer
+        ] = accounts;
+        check!(deposit_fee_bps <= MAX_TOKEN_FEE_BPS, LyraeErrorCode::InvalidParam)?;
+        check!(withdraw_fee_bps <= MAX_TOKEN_FEE_BPS, LyraeErrorCode::InvalidParam)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        lyrae_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+        let mut root_bank = RootBank::load_mut_checked(root_bank_ai, program_id)?;
+        msg!(
+            "old deposit_fee_bps: {} withdraw_fee_bps: {}",
+            root_bank.deposit_fee_bps,
+            root_bank.withdraw_fee_bps
+        );
+        root_bank.deposit_fee_bps = deposit_fee_bps;
+        root_bank.withdraw_fee_bps = withdraw_fee_bps;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Halt or un-halt the group; market configuration changes are rejected while halted
+    fn set_group_halted(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        halted: bool,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::Halted as u8,
+            old_value: lyrae_group.is_halted() as u64,
+            new_value: halted as u64,
+        });
+        lyrae_group.set_pause_flag(GROUP_HALTED, halted);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Pause or unpause deposits, borrows and order placement on the group
+    fn set_group_paused(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        paused: bool,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 2;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        lyrae_emit!(GroupConfigChangeLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            param: GroupConfigParam::Paused as u8,
+            old_value: lyrae_group.is_paused() as u64,
+            new_value: paused as u64,
+        });
+        lyrae_group.set_pause_flag(GROUP_PAUSED, paused);
+        Ok(())
+    }
+
+    /// Create a DustAccount PDA and initialize it
+    #[inline(never)]
+    fn create_dust_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,         // read
+            lyrae_account_ai,       // write
+            payer_ai,               // write & signer
+            system_prog_ai,         // read
+        ] = accounts;
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+        check!(payer_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let rent = Rent::get()?;
+
+        let lyrae_account_seeds: &[&[u8]] = &[&lyrae_group_ai.key.as_ref(), b"DustAccount"];
+        seed_and_create_pda(
+            program_id,
+            payer_ai,
+            &rent,
+            size_of::<LyraeAccount>(),
+            program_id,
+            system_prog_ai,
+            lyrae_account_ai,
+            lyrae_account_seeds,
+            &[],
+        )?;
+        let mut lyrae_account: RefMut<LyraeAccount> = LyraeAccount::load_mut(lyrae_account_ai)?;
+        check!(!lyrae_account.meta_data.is_initialized, LyraeErrorCode::InvalidAccountState)?;
+
+        lyrae_account.lyrae_group = *lyrae_group_ai.key;
+        lyrae_account.owner = lyrae_group.admin;
+        lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        lyrae_account.meta_data = MetaData::new(DataType::LyraeAccount, 0, true);
+        lyrae_account.not_upgradable = true;
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn upgrade_lyrae_account_v0_v1(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,   // write
+            lyrae_account_ai, // write
+            owner_ai          // signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_MANAGE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check_eq!(lyrae_account.meta_data.version, 0, LyraeErrorCode::InvalidAccountState)?;
+        check!(!lyrae_account.not_upgradable, LyraeErrorCode::InvalidAccountState)?;
+        check!(
+            lyrae_group.num_lyrae_accounts < lyrae_group.max_lyrae_accounts,
+            LyraeErrorCode::MaxAccountsReached
+        )?;
+
+        lyrae_group.num_lyrae_accounts =
+            lyrae_group.num_lyrae_accounts.checked_add(1).ok_or(math_err!())?;
+        lyrae_account.meta_data.version = 1;
+        lyrae_account.set_last_activity_ts(Clock::get()?.unix_timestamp as u64);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn cancel_perp_orders_side(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        side: Side,
+        limit: u8,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 6;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            owner_ai,           // read, signer
+            perp_market_ai,     // write
+            bids_ai,            // write
+            asks_ai,            // write
+        ] = accounts;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_CANCEL),
+            LyraeErrorCode::InvalidOwner
+        )?;
+
+        let mut perp_market =
+            PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+
+        let market_index = lyrae_group.find_perp_market_index(perp_market_ai.key).unwrap();
+
+        let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+        let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
+
+        if perp_market.meta_data.version == 0 {
+            return Err(throw_err!(LyraeErrorCode::InvalidParam));
+        } else {
+            let (all_order_ids, canceled_order_ids) = book.cancel_all_side_with_size_incentives(
+                &mut lyrae_account,
+                &mut perp_market,
+                market_index,
+                side,
+                limit,
+            )?;
+            lyrae_emit!(CancelAllPerpOrdersLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                all_order_ids,
+                canceled_order_ids
+            });
+        }
+
+        lyrae_emit!(LyrAccrualLog {
+            lyrae_group: *lyrae_group_ai.key,
+            lyrae_account: *lyrae_account_ai.key,
+            market_index: market_index as u64,
+            lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
+        });
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn set_delegate(program_id: &Pubkey, accounts: &[AccountInfo], permissions: u8) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,                   // read
+            lyrae_account_ai,                 // write
+            owner_ai,                         // read, signer
+            delegate_ai,                      // read
+        ] = accounts;
+
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+
+        check!(owner_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(
+            &lyrae_account.delegate != delegate_ai.key
+                || lyrae_account.get_delegate_permissions() != permissions,
+            LyraeErrorCode::InvalidAccount
+        )?;
+        check!(
+            permissions != 0 && permissions & !DELEGATE_ALL == 0,
+            LyraeErrorCode::InvalidParam
+        )?;
+        check!(!lyrae_account.being_liquidated, LyraeErrorCode::BeingLiquidated)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+
+        lyrae_account.delegate = *delegate_ai.key;
+        lyrae_account.set_delegate_permissions(permissions);
+
+        Ok(())
+    }
+
+    #[inline(never)]
+    fn change_spot_market_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        maint_leverage: Option<I80F48>,
+        init_leverage: Option<I80F48>,
+        liquidation_fee: Option<I80F48>,
+        optimal_util: Option<I80F48>,
+        optimal_rate: Option<I80F48>,
+        max_rate: Option<I80F48>,
+        version: Option<u8>,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+
+        let [
+        lyrae_group_ai, // write
+        spot_market_ai, // write
+        root_bank_ai,   // write
+        admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+        // checks rootbank is part of the group
+        let _root_bank_index = lyrae_group
+            .find_root_bank_index(root_bank_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidRootBank))?;
+
+        let mut root_bank = RootBank::load_mut_checked(&root_bank_ai, program_id)?;
+        let mut info = &mut lyrae_group.spot_markets[market_index];
+
+        // Unwrap params. Default to current state if Option is None
+        let (init_asset_weight, init_liab_weight) = init_leverage
+            .map_or((info.init_asset_weight, info.init_liab_weight), |x| get_leverage_weights(x));
+        let (maint_asset_weight, maint_liab_weight) = maint_leverage
+            .map_or((info.maint_asset_weight, info.maint_liab_weight), |x| get_leverage_weights(x));
+
+        let liquidation_fee = liquidation_fee.unwrap_or(info.liquidation_fee);
+        let optimal_util = optimal_util.unwrap_or(root_bank.optimal_util);
+        let optimal_rate = optimal_rate.unwrap_or(root_bank.optimal_rate);
+        let max_rate = max_rate.unwrap_or(root_bank.max_rate);
+        let version = version.unwrap_or(root_bank.meta_data.version);
+
+        // params check
+        check!(init_asset_weight > ZERO_I80F48, LyraeErrorCode::InvalidParam)?;
+        check!(maint_asset_weight > init_asset_weight, LyraeErrorCode::InvalidParam)?;
+        // maint leverage may only increase to prevent unforeseen liquidations
+        check!(maint_asset_weight >= info.maint_asset_weight, LyraeErrorCode::InvalidParam)?;
+
+        // set the params on the RootBank
+        root_bank.set_rate_params(optimal_util, optimal_rate, max_rate)?;
+
+        // set the params on LyraeGroup SpotMarketInfo
+        info.liquidation_fee = liquidation_fee;
+        info.maint_asset_weight = maint_asset_weight;
+        info.init_asset_weight = init_asset_weight;
+        info.maint_liab_weight = maint_liab_weight;
+        info.init_liab_weight = init_liab_weight;
+        check!(
+            lyrae_group.is_valid_min_liquidation_fee(lyrae_group.get_min_liquidation_fee()),
+            LyraeErrorCode::InvalidParam
+        )?;
+
+        check!(version == 0, LyraeErrorCode::InvalidParam)?;
+
+        root_bank.meta_data.version = version;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Lower the asset weights of a spot market, bypassing the rule in change_spot_market_params
+    /// that maint_asset_weight may only increase. This can trigger liquidations.
+    fn emergency_set_asset_weight(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        init_asset_weight: I80F48,
+        maint_asset_weight: I80F48,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+
+        let [
+            lyrae_group_ai, // write
+            spot_market_ai, // read
+            admin_ai        // read, signer
+        ] = accounts;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+
+        let market_index = lyrae_group
+            .find_spot_market_index(spot_market_ai.key)
+            .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+        let info = &mut lyrae_group.spot_markets[market_index];
+
+        // Only de-risking is allowed here
+        check!(init_asset_weight >= ZERO_I80F48, LyraeErrorCode::InvalidParam)?;
+        check!(maint_asset_weight > init_asset_weight, LyraeErrorCode::InvalidParam)?;
+        check!(init_asset_weight <= info.init_asset_weight, LyraeErrorCode::InvalidParam)?;
+        check!(maint_asset_weight <= info.maint_asset_weight, LyraeErrorCode::InvalidParam)?;
+
+        lyrae_emit!(EmergencyAssetWeightLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            market_index: market_index as u64,
+            old_init_asset_weight: info.init_asset_weight.to_bits(),
+            old_maint_asset_weight: info.maint_asset_weight.to_bits(),
+            init_asset_weight: init_asset_weight.to_bits(),
+            maint_asset_weight: maint_asset_weight.to_bits(),
+        });
+
+        info.init_asset_weight = init_asset_weight;
+        info.maint_asset_weight = maint_asset_weight;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the min msrm_amount and the taker fee discount of one msrm fee tier, creating the
+    /// group's MsrmFeeTiers account on first use
+    fn set_msrm_fee_tier(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        tier: u8,
+        min_msrm: u64,
+        discount_bps: u16,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let accounts = array_ref![accounts, 0, NUM_FIXED];
+        let [
+            lyrae_group_ai,     // read
+            msrm_fee_tiers_ai,  // write
+            admin_ai,           // write, signer
+            system_prog_ai,     // read
+        ] = accounts;
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let tier = tier as usize;
+        check!(tier < MAX_MSRM_FEE_TIERS, LyraeErrorCode::InvalidParam)?;
+        check!(discount_bps <= BPS_PER_UNIT, LyraeErrorCode::InvalidParam)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+
+        let mut msrm_fee_tiers = if msrm_fee_tiers_ai.data_is_empty() {
+            let msrm_fee_tiers_seeds: &[&[u8]] = &[&lyrae_group_ai.key.as_ref(), b"MsrmFeeTiers"];
+            seed_and_create_pda(
+                program_id,
+                admin_ai,
+                &Rent::get()?,
+                size_of::<MsrmFeeTiers>(),
+                program_id,
+                system_prog_ai,
+                msrm_fee_tiers_ai,
+                msrm_fee_tiers_seeds,
+                &[],
+            )?;
+            MsrmFeeTiers::init(msrm_fee_tiers_ai, program_id, lyrae_group_ai.key)?
+        } else {
+            MsrmFeeTiers::load_mut_checked(msrm_fee_tiers_ai, program_id, lyrae_group_ai.key)?
+        };
+
+        lyrae_emit!(MsrmFeeTierLog {
+            lyrae_group: *lyrae_group_ai.key,
+            admin: *admin_ai.key,
+            tier: tier as u64,
+            old_min_msrm: msrm_fee_tiers.min_msrm[tier],
+            old_discount_bps: msrm_fee_tiers.discount_bps[tier] as u64,
+            min_msrm,
+            discount_bps: discount_bps as u64,
+        });
+
+        msrm_fee_tiers.min_msrm[tier] = min_msrm;
+        msrm_fee_tiers.discount_bps[tier] = discount_bps;
+        Ok(())
+    }
+
+    /// Set the `ref_surcharge_centibps`, `ref_share_centibps` and `ref_lyr_required` on `LyraeGroup`
+    #[inline(never)]
+    fn change_referral_fee_params(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        ref_surcharge_centibps: u32,
+        ref_share_centibps: u32,
+        ref_lyr_required: u64,
+        drain_required: bool,
+    ) -> LyraeResult {
+        check!(ref_surcharge_centibps >= ref_share_centibps, LyraeErrorCode::InvalidParam)?;
+
+        const NUM_FIXED: usize = 2;
+        let (fixed_ais, market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+
+        let [
+            lyrae_group_ai, // write
+            admin_ai        // read, signer
+        ] = fixed_ais;
+
+        let mut lyrae_group = LyraeGroup::load_mut_checked(lyrae_group_ai, program_id)?;
+        check_eq!(admin_ai.key, &lyrae_group.admin, LyraeErrorCode::InvalidAdminKey)?;
+        check!(admin_ai.is_signer, LyraeErrorCode::SignerNecessary)?;
+        msg!("old referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_lyr_required: {}", lyrae_group.ref_surcharge_centibps, lyrae_group.ref_share_centibps, lyrae_group.ref_lyr_required);
+
+        // Fee logging of events still on an EventQueue would be messed up by the new params,
+        // so optionally require every EventQueue to be drained by consume_events first
+        if drain_required {
+            check!(market_ais.len() % 2 == 0, LyraeErrorCode::InvalidParam)?;
+            let mut drained = [false; MAX_PAIRS];
+            for pair in market_ais.chunks_exact(2) {
+                let (perp_market_ai, event_queue_ai) = (&pair[0], &pair[1]);
+                let market_index = lyrae_group
+                    .find_perp_market_index(perp_market_ai.key)
+                    .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+                let perp_market =
+                    PerpMarket::load_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+                let event_queue =
+                    EventQueue::load_mut_checked(event_queue_ai, program_id, &perp_market)?;
+                check!(event_queue.empty(), LyraeErrorCode::EventQueueNotEmpty)?;
+                drained[market_index] = true;
+            }
+            for i in 0..MAX_PAIRS {
+                check!(
+                    lyrae_group.perp_markets[i].is_empty() || drained[i],
+                    LyraeErrorCode::InvalidParam
+                )?;
+            }
+        }
+
+        let changes = [
+            (
+                GroupConfigParam::RefSurchargeCentibps,
+                lyrae_group.ref_surcharge_centibps as u64,
+                ref_surcharge_centibps as u64,
+            ),
+            (
+                GroupConfigParam::RefShareCentibps,
+                lyrae_group.ref_share_centibps as u64,
+                ref_share_centibps as u64,
+            ),
+            (GroupConfigParam::RefLyrRequired, lyrae_group.ref_lyr_required, ref_lyr_required),
+        ];
+        for (param, old_value, new_value) in changes.iter() {
+            lyrae_emit!(GroupConfigChangeLog {
+                lyrae_group: *lyrae_group_ai.key,
+                admin: *admin_ai.key,
+                param: *param as u8,
+                old_value: *old_value,
+                new_value: *new_value,
+            });
+        }
+
+        lyrae_group.ref_surcharge_centibps = ref_surcharge_centibps;
+        lyrae_group.ref_share_centibps = ref_share_centibps;
+        lyrae_group.ref_lyr_required = ref_lyr_required;
+
+        msg!("new referral fee params: ref_surcharge_centibps: {} ref_share_centibps: {} ref_lyr_required: {}", ref_surcharge_centibps, ref_share_centibps, ref_lyr_required);
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Store the referrer's LyraeAccount pubkey on the Referrer account
+    /// It will create the Referrer account as a PDA of user's LyraeAccount if it doesn't exist
+    /// This is primarily useful for the UI; the referrer address stored here is not necessarily
+    /// who earns the ref fees.
+    fn set_referrer_memory(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 7;
+        let (fixed_ais, opt_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,             // read
+            lyrae_account_ai,           // read
+            owner_ai,                   // signer
+            referrer_memory_ai,         // write
+            referrer_lyrae_account_ai,  // read
+            payer_ai,                   // write, signer
+            system_prog_ai,             // read
+        ] = fixed_ais;
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, &lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_MANAGE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
+        check_referrer(program_id, referrer_lyrae_account_ai, lyrae_group_ai.key)?;
+        check!(
+            referrer_lyrae_account_ai.key != lyrae_account_ai.key,
+            LyraeErrorCode::InvalidReferrer
+        )?;
+
+        // Reject A -> B if B -> A is already recorded. Clients that don't pass the referrer's
+        // ReferrerMemory yet skip this check.
+        if let Some(referrers_referrer_memory_ai) = opt_ais.first() {
+            let (referrers_referrer_memory_pk, _) = Pubkey::find_program_address(
+                &[&referrer_lyrae_account_ai.key.as_ref(), b"ReferrerMemory"],
+                program_id,
+            );
+            check!(
+                referrers_referrer_memory_ai.key == &referrers_referrer_memory_pk,
+                LyraeErrorCode::InvalidAccount
+            )?;
+            if !referrers_referrer_memory_ai.data_is_empty() {
+                let referrers_referrer_memory =
+                    ReferrerMemory::load_checked(referrers_referrer_memory_ai, program_id)?;
+                check!(
+                    &referrers_referrer_memory.referrer_lyrae_account != lyrae_account_ai.key,
+                    LyraeErrorCode::InvalidReferrer
+                )?;
+            }
+        }
+
+        if referrer_memory_ai.data_is_empty() {
+            // initialize it if it's not initialized yet
+            let referrer_seeds: &[&[u8]] = &[&lyrae_account_ai.key.as_ref(), b"ReferrerMemory"];
+            seed_and_create_pda(
+                program_id,
+                payer_ai,
+                &Rent::get()?,
+                size_of::<ReferrerMemory>(),
+                program_id,
+                system_prog_ai,
+                referrer_memory_ai,
+                referrer_seeds,
+                &[],
+            )?;
+            ReferrerMemory::init(referrer_memory_ai, program_id, referrer_lyrae_account_ai)
+        } else {
+            // otherwise just set referrer pubkey
+            let mut referrer_memory =
+                ReferrerMemory::load_mut_checked(referrer_memory_ai, program_id)?;
+            referrer_memory.referrer_lyrae_account = *referrer_lyrae_account_ai.key;
+            Ok(())
+        }
+    }
+
+    #[inline(never)]
+    /// Set the cancel-on-disconnect TTL, creating the CancelTtl PDA if it doesn't exist
+    fn set_cancel_ttl(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        cancel_ttl_secs: u64,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 6;
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            owner_ai,           // signer
+            cancel_ttl_ai,      // write
+            payer_ai,           // write, signer
+            system_prog_ai,     // read
+        ] = array_ref![accounts, 0, NUM_FIXED];
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
+        let mut cancel_ttl = if cancel_ttl_ai.data_is_empty() {
+            let cancel_ttl_seeds: &[&[u8]] = &[&lyrae_account_ai.key.as_ref(), b"CancelTtl"];
+            seed_and_create_pda(
+                program_id,
+                payer_ai,
+                &Rent::get()?,
+                size_of::<CancelTtl>(),
+                program_id,
+                system_prog_ai,
+                cancel_ttl_ai,
+                cancel_ttl_seeds,
+                &[],
+            )?;
+            CancelTtl::init(cancel_ttl_ai, program_id, lyrae_account_ai)?
+        } else {
+            CancelTtl::load_mut_checked(cancel_ttl_ai, program_id, lyrae_account_ai.key)?
+        };
+
+        cancel_ttl.cancel_ttl_secs = cancel_ttl_secs;
+        cancel_ttl.last_heartbeat_ts = Clock::get()?.unix_timestamp as u64;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Set the UI grouping of a LyraeAccount, creating the AccountGroup PDA if it doesn't exist
+    fn set_account_group(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        account_label: [u8; 8],
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 6;
+        let parent_lyrae_account_ai = accounts.get(NUM_FIXED);
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            owner_ai,           // signer
+            account_group_ai,   // write
+            payer_ai,           // write, signer
+            system_prog_ai,     // read
+        ] = array_ref![accounts, 0, NUM_FIXED];
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(&lyrae_account.owner == owner_ai.key, LyraeErrorCode::InvalidOwner)?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
+        let parent_account = match parent_lyrae_account_ai {
+            Some(parent_lyrae_account_ai) => {
+                check!(
+                    parent_lyrae_account_ai.key != lyrae_account_ai.key,
+                    LyraeErrorCode::InvalidAccount
+                )?;
+                // load_checked also checks that the parent is program owned
+                let parent_lyrae_account = LyraeAccount::load_checked(
+                    parent_lyrae_account_ai,
+                    program_id,
+                    lyrae_group_ai.key,
+                )?;
+                check!(
+                    parent_lyrae_account.owner == lyrae_account.owner,
+                    LyraeErrorCode::InvalidOwner
+                )?;
+                *parent_lyrae_account_ai.key
+            }
+            None => Pubkey::default(),
+        };
+
+        let mut account_group = if account_group_ai.data_is_empty() {
+            let account_group_seeds: &[&[u8]] = &[&lyrae_account_ai.key.as_ref(), b"AccountGroup"];
+            seed_and_create_pda(
+                program_id,
+                payer_ai,
+                &Rent::get()?,
+                size_of::<AccountGroup>(),
+                program_id,
+                system_prog_ai,
+                account_group_ai,
+                account_group_seeds,
+                &[],
+            )?;
+            AccountGroup::init(account_group_ai, program_id, lyrae_account_ai)?
+        } else {
+            AccountGroup::load_mut_checked(account_group_ai, program_id, lyrae_account_ai.key)?
+        };
+
+        account_group.parent_account = parent_account;
+        account_group.account_label = account_label;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Refresh the heartbeat of the dead man's switch
+    fn heartbeat(program_id: &Pubkey, accounts: &[AccountInfo]) -> LyraeResult {
+        const NUM_FIXED: usize = 4;
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // read
+            owner_ai,           // signer
+            cancel_ttl_ai,      // write
+        ] = array_ref![accounts, 0, NUM_FIXED];
+
+        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let lyrae_account =
+            LyraeAccount::load_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(
+            lyrae_account.is_owner_or_delegate(owner_ai.key, DELEGATE_PLACE),
+            LyraeErrorCode::InvalidOwner
+        )?;
+        check!(owner_ai.is_signer, LyraeErrorCode::InvalidSignerKey)?;
+
+        let mut cancel_ttl =
+            CancelTtl::load_mut_checked(cancel_ttl_ai, program_id, lyrae_account_ai.key)?;
+        cancel_ttl.last_heartbeat_ts = Clock::get()?.unix_timestamp as u64;
+        Ok(())
+    }
+
+    #[inline(never)]
+    /// Cancel all resting perp orders of an account whose heartbeat expired in the passed markets
+    fn crank_expired_cancels(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        limit: u8,
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 3;
+        let (fixed_ais, market_ais) = array_refs![accounts, NUM_FIXED; ..;];
+        let [
+            lyrae_group_ai,     // read
+            lyrae_account_ai,   // write
+            cancel_ttl_ai,      // read
+        ] = fixed_ais;
+        check!(market_ais.len() % 3 == 0, LyraeErrorCode::InvalidParam)?;
+
+        let lyrae_group = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+        let mut lyrae_account =
+            LyraeAccount::load_mut_checked(lyrae_account_ai, program_id, lyrae_group_ai.key)?;
+        check!(!lyrae_account.is_bankrupt, LyraeErrorCode::Bankrupt)?;
+
+        let cancel_ttl = CancelTtl::load_checked(cancel_ttl_ai, program_id, lyrae_account_ai.key)?;
+        let now_ts = Clock::get()?.unix_timestamp as u64;
+        check!(cancel_ttl.is_expired(now_ts), LyraeErrorCode::InvalidAccountState)?;
+
+        for chunk in market_ais.chunks_exact(3) {
+            let (perp_market_ai, bids_ai, asks_ai) = (&chunk[0], &chunk[1], &chunk[2]);
+            let mut perp_market =
+                PerpMarket::load_mut_checked(perp_market_ai, program_id, lyrae_group_ai.key)?;
+            let market_index = lyrae_group
+                .find_perp_market_index(perp_market_ai.key)
+                .ok_or(throw_err!(LyraeErrorCode::InvalidMarket))?;
+
+            let mut book = Book::load_checked(program_id, bids_ai, asks_ai, &perp_market)?;
+            let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
+
+            if perp_market.meta_data.version == 0 {
+                book.cancel_all_with_price_incentives(
+                    &mut lyrae_account,
+                    &mut perp_market,
+                    market_index,
+                    limit,
+                )?;
+            } else {
+                let (all_order_ids, canceled_order_ids) = book.cancel_all_with_size_incentives(
+                    &mut lyrae_account,
+                    &mut perp_market,
+                    market_index,
+                    limit,
+                )?;
+                lyrae_emit!(CancelAllPerpOrdersLog {
+                    lyrae_group: *lyrae_group_ai.key,
+                    lyrae_account: *lyrae_account_ai.key,
+                    market_index: market_index as u64,
+                    all_order_ids,
+                    canceled_order_ids
+                });
+            }
+
+            lyrae_emit!(LyrAccrualLog {
+                lyrae_group: *lyrae_group_ai.key,
+                lyrae_account: *lyrae_account_ai.key,
+                market_index: market_index as u64,
+                lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Associate the referrer's LyraeAccount with a human readable `referrer_id` which can be used
+    /// in a ref link
+    /// Create the `ReferrerIdRecord` PDA; if it already exists throw error
+    #[inline(never)]
+    fn register_referrer_id(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        referrer_id: [u8; INFO_LEN],
+    ) -> LyraeResult {
+        const NUM_FIXED: usize = 5;
+        let [
+            lyrae_group_ai,             // read
+            referrer_lyrae_account_ai,  // read
+            referrer_id_record_ai,      // write
+            payer_ai,                   // write, signer
+            system_prog_ai,             // read
+        ] = array_ref![accounts, 0, NUM_FIXED];
+        check!(
+            system_prog_ai.key == &solana_program::system_program::id(),
+            LyraeErrorCode::InvalidProgramId
+        )?;
+
+        let _ = LyraeGroup::load_checked(lyrae_group_ai, program_id)?;
+
+        check_referrer(program_id, referrer_lyrae_account_ai, lyrae_group_ai.key)?;
+
+        // referrer_id_record must be empty; cannot be transferred
+        check!(referrer_id_record_ai.data_is_empty(), LyraeErrorCode::InvalidAccount)?;
+        let referrer_record_seeds: &[&[u8]] =
+            &[&lyrae_group_ai.key.as_ref(), b"ReferrerIdRecord", &referrer_id];
+        seed_and_create_pda(
+            program_id,
+            payer_ai,
+            &Rent::get()?,
+            size_of::<ReferrerIdRecord>(),
+            program_id,
+            system_prog_ai,
+            referrer_id_record_ai,
+            referrer_record_seeds,
+            &[],
+        )?;
+
+        ReferrerIdRecord::init(
+            referrer_id_record_ai,
+            program_id,
+            referrer_lyrae_account_ai,
+            referrer_id,
+        )
+    }
+    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], data: &[u8]) -> LyraeResult {
+        let instruction =
+            LyraeInstruction::unpack(data).ok_or(ProgramError::InvalidInstructionData)?;
+        match instruction {
+            LyraeInstruction::InitLyraeGroup {
+                signer_nonce,
+                valid_interval,
+                quote_optimal_util,
+                quote_optimal_rate,
+                quote_max_rate,
+            } => {
+                msg!("Lyrae: InitLyraeGroup");
+                Self::init_lyrae_group(
+                    program_id,
+                    accounts,
+                    signer_nonce,
+                    valid_interval,
+                    quote_optimal_util,
+                    quote_optimal_rate,
+                    quote_max_rate,
+                )
+            }
+            LyraeInstruction::InitLyraeAccount => {
+                msg!("Lyrae: InitLyraeAccount DEPRECATED");
+                Self::init_lyrae_account(program_id, accounts)
+            }
+            LyraeInstruction::CreateLyraeAccount { account_num } => {
+                msg!("Lyrae: CreateLyraeAccount");
+                Self::create_lyrae_account(program_id, accounts, account_num)
+            }
+            LyraeInstruction::CloseLyraeAccount => {
+                msg!("Lyrae: CloseLyraeAccount");
+                Self::close_lyrae_account(program_id, accounts)
+            }
+            LyraeInstruction::UpgradeLyraeAccountV0V1 => {
+                msg!("Lyrae: UpgradeLyraeAccountV0V1");
+                Self::upgrade_lyrae_account_v0_v1(program_id, accounts)
+            }
+            LyraeInstruction::Deposit { quantity } => {
+                msg!("Lyrae: Deposit");
+                Self::deposit(program_id, accounts, quantity)
+            }
+            LyraeInstruction::Withdraw { quantity, allow_borrow, close_dust } => {
+                msg!("Lyrae: Withdraw");
+                Self::withdraw(program_id, accounts, quantity, allow_borrow, close_dust)
+            }
+            LyraeInstruction::AddSpotMarket {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                optimal_util,
+                optimal_rate,
+                max_rate,
+            } => {
+                msg!("Lyrae: AddSpotMarket");
+                Self::add_spot_market(
+                    program_id,
+                    accounts,
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    optimal_util,
+                    optimal_rate,
+                    max_rate,
+                )
+            }
+            LyraeInstruction::AddToBasket { .. } => {
+                msg!("Lyrae: AddToBasket Deprecated");
+                Ok(())
+            }
+            LyraeInstruction::Borrow { .. } => {
+                msg!("Lyrae: Borrow DEPRECATED");
+                Ok(())
+            }
+            LyraeInstruction::CachePrices => {
+                msg!("Lyrae: CachePrices");
+                Self::cache_prices(program_id, accounts)
+            }
+            LyraeInstruction::CacheRootBanks => {
+                msg!("Lyrae: CacheRootBanks");
+                Self::cache_root_banks(program_id, accounts)
+            }
+            LyraeInstruction::PlaceSpotOrder { order } => {
+                msg!("Lyrae: PlaceSpotOrder");
+                Self::place_spot_order(program_id, accounts, order)
+            }
+            LyraeInstruction::CancelSpotOrder { order, ok_invalid_id } => {
+                msg!("Lyrae: CancelSpotOrder");
+                Self::cancel_spot_order(program_id, accounts, order, ok_invalid_id)
+            }
+            LyraeInstruction::AddOracle => {
+                msg!("Lyrae: AddOracle");
+                Self::add_oracle(program_id, accounts)
+            }
+            LyraeInstruction::SettleFunds => {
+                msg!("Lyrae: SettleFunds");
+                Self::settle_funds(program_id, accounts)
+            }
+            LyraeInstruction::UpdateRootBank => {
+                msg!("Lyrae: UpdateRootBank");
+                Self::update_root_bank(program_id, accounts)
+            }
+
+            LyraeInstruction::AddPerpMarket {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                maker_fee,
+                taker_fee,
+                base_lot_size,
+                quote_lot_size,
+                rate,
+                max_depth_bps,
+                target_period_length,
+                lyr_per_period,
+                exp,
+            } => {
+                msg!("Lyrae: AddPerpMarket DEPRECATED");
+                Self::add_perp_market(
+                    program_id,
+                    accounts,
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    base_lot_size,
+                    quote_lot_size,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                    exp,
+                )
+            }
+            LyraeInstruction::PlacePerpOrder {
+                side,
+                price,
+                quantity,
+                client_order_id,
+                order_type,
+                reduce_only,
+            } => {
+                msg!("Lyrae: PlacePerpOrder client_order_id={}", client_order_id);
+                Self::place_perp_order(
+                    program_id,
+                    accounts,
+                    side,
+                    price,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                    reduce_only,
+                )
+            }
+            LyraeInstruction::CancelPerpOrderByClientId { client_order_id, invalid_id_ok } => {
+                msg!("Lyrae: CancelPerpOrderByClientId client_order_id={}", client_order_id);
+                let result =
+                    Self::cancel_perp_order_by_client_id(program_id, accounts, client_order_id);
+                if invalid_id_ok {
+                    if let Err(LyraeError::LyraeErrorCode { lyrae_error_code, .. }) = result {
+                        if lyrae_error_code == LyraeErrorCode::InvalidOrderId
+                            || lyrae_error_code == LyraeErrorCode::ClientIdNotFound
+                        {
+                            return Ok(());
+                        }
+                    }
+                }
+                result
+            }
+            LyraeInstruction::CancelPerpOrder { order_id, invalid_id_ok } => {
+                // TODO OPT this log may cost too much compute
+                msg!("Lyrae: CancelPerpOrder order_id={}", order_id);
+                let result = Self::cancel_perp_order(program_id, accounts, order_id);
+                if invalid_id_ok {
+                    if let Err(LyraeError::LyraeErrorCode { lyrae_error_code, .. }) = result {
+                        if lyrae_error_code == LyraeErrorCode::InvalidOrderId {
+                            return Ok(());
+                        }
                     }
                 }
                 result
@@ -6007,10 +9440,10 @@ impl Processor {
                 msg!("Lyrae: UpdateFunding");
                 Self::update_funding(program_id, accounts)
             }
-            LyraeInstruction::SetOracle { price } => {
+            LyraeInstruction::SetOracle { price, last_update } => {
                 // msg!("Lyrae: SetOracle {:?}", price);
                 msg!("Lyrae: SetOracle");
-                Self::set_oracle(program_id, accounts, price)
+                Self::set_oracle(program_id, accounts, price, last_update)
             }
             LyraeInstruction::SettlePnl { market_index } => {
                 msg!("Lyrae: SettlePnl");
@@ -6028,9 +9461,14 @@ impl Processor {
                 msg!("Lyrae: ForceCancelPerpOrders");
                 Self::force_cancel_perp_orders(program_id, accounts, limit)
             }
-            LyraeInstruction::LiquidateTokenAndToken { max_liab_transfer } => {
+            LyraeInstruction::LiquidateTokenAndToken { max_liab_transfer, max_health_fraction } => {
                 msg!("Lyrae: LiquidateTokenAndToken");
-                Self::liquidate_token_and_token(program_id, accounts, max_liab_transfer)
+                Self::liquidate_token_and_token(
+                    program_id,
+                    accounts,
+                    max_liab_transfer,
+                    max_health_fraction,
+                )
             }
             LyraeInstruction::LiquidateTokenAndPerp {
                 asset_type,
@@ -6050,9 +9488,17 @@ impl Processor {
                     max_liab_transfer,
                 )
             }
-            LyraeInstruction::LiquidatePerpMarket { base_transfer_request } => {
+            LyraeInstruction::LiquidatePerpMarket {
+                base_transfer_request,
+                liqor_max_base_position,
+            } => {
                 msg!("Lyrae: LiquidatePerpMarket");
-                Self::liquidate_perp_market(program_id, accounts, base_transfer_request)
+                Self::liquidate_perp_market(
+                    program_id,
+                    accounts,
+                    base_transfer_request,
+                    liqor_max_base_position,
+                )
             }
             LyraeInstruction::SettleFees => {
                 msg!("Lyrae: SettleFees");
@@ -6062,9 +9508,14 @@ impl Processor {
                 msg!("Lyrae: ResolvePerpBankruptcy");
                 Self::resolve_perp_bankruptcy(program_id, accounts, liab_index, max_liab_transfer)
             }
-            LyraeInstruction::ResolveTokenBankruptcy { max_liab_transfer } => {
+            LyraeInstruction::ResolveTokenBankruptcy { max_liab_transfer, max_liab_price } => {
                 msg!("Lyrae: ResolveTokenBankruptcy");
-                Self::resolve_token_bankruptcy(program_id, accounts, max_liab_transfer)
+                Self::resolve_token_bankruptcy(
+                    program_id,
+                    accounts,
+                    max_liab_transfer,
+                    max_liab_price,
+                )
             }
             LyraeInstruction::InitSpotOpenOrders => {
                 msg!("Lyrae: InitSpotOpenOrders");
@@ -6127,6 +9578,7 @@ impl Processor {
                 price,
                 quantity,
                 trigger_price,
+                trail_offset,
             } => {
                 msg!(
                     "Lyrae: AddPerpTriggerOrder client_order_id={} type={:?} side={:?} trigger_condition={:?} price={} quantity={} trigger={}",
@@ -6149,6 +9601,7 @@ impl Processor {
                     price,
                     quantity,
                     trigger_price,
+                    trail_offset,
                 )
             }
             LyraeInstruction::RemoveAdvancedOrder { order_index } => {
@@ -6180,876 +9633,3769 @@ impl Processor {
                 Self::create_perp_market(
                     program_id,
                     accounts,
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    base_lot_size,
-                    quote_lot_size,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                    exp,
-                    version,
-                    lm_size_shift,
-                    base_decimals,
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    base_lot_size,
+                    quote_lot_size,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                    exp,
+                    version,
+                    lm_size_shift,
+                    base_decimals,
+                )
+            }
+            LyraeInstruction::ChangePerpMarketParams2 {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                maker_fee,
+                taker_fee,
+                rate,
+                max_depth_bps,
+                target_period_length,
+                lyr_per_period,
+                exp,
+                version,
+                lm_size_shift,
+                max_order_price_deviation_bps,
+                funding_impact_notional,
+            } => {
+                msg!("Lyrae: ChangePerpMarketParams2");
+                Self::change_perp_market_params2(
+                    program_id,
+                    accounts,
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    maker_fee,
+                    taker_fee,
+                    rate,
+                    max_depth_bps,
+                    target_period_length,
+                    lyr_per_period,
+                    exp,
+                    version,
+                    lm_size_shift,
+                    max_order_price_deviation_bps,
+                    funding_impact_notional,
+                )
+            }
+            LyraeInstruction::UpdateMarginBasket => {
+                msg!("Lyrae: UpdateMarginBasket");
+                Self::update_margin_basket(program_id, accounts)
+            }
+            LyraeInstruction::ChangeMaxLyraeAccounts { max_lyrae_accounts } => {
+                msg!("Lyrae: ChangeMaxLyraeAccounts");
+                Self::change_max_lyrae_accounts(program_id, accounts, max_lyrae_accounts)
+            }
+            LyraeInstruction::CreateDustAccount => {
+                msg!("Lyrae: CreateDustAccount");
+                Self::create_dust_account(program_id, accounts)
+            }
+            LyraeInstruction::ResolveDust => {
+                msg!("Lyrae: ResolveDust");
+                Self::resolve_dust(program_id, accounts)
+            }
+            LyraeInstruction::CancelPerpOrdersSide { side, limit } => {
+                msg!("Lyrae: CancelSidePerpOrders");
+                Self::cancel_perp_orders_side(program_id, accounts, side, limit)
+            }
+            LyraeInstruction::SetDelegate { permissions } => {
+                msg!("Lyrae: SetDelegate");
+                Self::set_delegate(program_id, accounts, permissions)
+            }
+            LyraeInstruction::ChangeSpotMarketParams {
+                maint_leverage,
+                init_leverage,
+                liquidation_fee,
+                optimal_util,
+                optimal_rate,
+                max_rate,
+                version,
+            } => {
+                msg!("Lyrae: ChangeSpotMarketParams");
+                Self::change_spot_market_params(
+                    program_id,
+                    accounts,
+                    maint_leverage,
+                    init_leverage,
+                    liquidation_fee,
+                    optimal_util,
+                    optimal_rate,
+                    max_rate,
+                    version,
+                )
+            }
+            LyraeInstruction::CreateSpotOpenOrders => {
+                msg!("Lyrae: CreateSpotOpenOrders");
+                Self::create_spot_open_orders(program_id, accounts)
+            }
+            LyraeInstruction::ChangeReferralFeeParams {
+                ref_surcharge_centibps,
+                ref_share_centibps,
+                ref_lyr_required,
+                drain_required,
+            } => {
+                msg!("Lyrae: ChangeReferralFeeParams");
+                Self::change_referral_fee_params(
+                    program_id,
+                    accounts,
+                    ref_surcharge_centibps,
+                    ref_share_centibps,
+                    ref_lyr_required,
+                    drain_required,
+                )
+            }
+            LyraeInstruction::SetReferrerMemory => {
+                msg!("Lyrae: SetReferrerMemory");
+                Self::set_referrer_memory(program_id, accounts)
+            }
+            LyraeInstruction::RegisterReferrerId { referrer_id } => {
+                msg!("Lyrae: RegisterReferrerId");
+                Self::register_referrer_id(program_id, accounts, referrer_id)
+            }
+            LyraeInstruction::RedeemLyrToTokenAccount => {
+                msg!("Lyrae: RedeemLyrToTokenAccount");
+                Self::redeem_lyr_to_token_account(program_id, accounts)
+            }
+            LyraeInstruction::SimulateWithdraw { quantity, allow_borrow } => {
+                msg!("Lyrae: SimulateWithdraw");
+                Self::simulate_withdraw(program_id, accounts, quantity, allow_borrow)
+            }
+            LyraeInstruction::LogNodeBankHealth => {
+                msg!("Lyrae: LogNodeBankHealth");
+                Self::log_node_bank_health(program_id, accounts)
+            }
+            LyraeInstruction::ChangeMaxLiquidationFraction { max_liquidation_fraction_bps } => {
+                msg!("Lyrae: ChangeMaxLiquidationFraction");
+                Self::change_max_liquidation_fraction(
+                    program_id,
+                    accounts,
+                    max_liquidation_fraction_bps,
+                )
+            }
+            LyraeInstruction::ClaimSerumReferrerRebates => {
+                msg!("Lyrae: ClaimSerumReferrerRebates");
+                Self::claim_serum_referrer_rebates(program_id, accounts)
+            }
+            LyraeInstruction::ChangeMinLiquidationFee { min_liquidation_fee_bps } => {
+                msg!("Lyrae: ChangeMinLiquidationFee");
+                Self::change_min_liquidation_fee(program_id, accounts, min_liquidation_fee_bps)
+            }
+            LyraeInstruction::LogAdvancedOrders => {
+                msg!("Lyrae: LogAdvancedOrders");
+                Self::log_advanced_orders(program_id, accounts)
+            }
+            LyraeInstruction::CrankSettleFees => {
+                msg!("Lyrae: CrankSettleFees");
+                Self::crank_settle_fees(program_id, accounts)
+            }
+            LyraeInstruction::LogBookCapacity => {
+                msg!("Lyrae: LogBookCapacity");
+                Self::log_book_capacity(program_id, accounts)
+            }
+            LyraeInstruction::DepositAndCreateSpotOpenOrders { quantity } => {
+                msg!("Lyrae: DepositAndCreateSpotOpenOrders");
+                Self::deposit_and_create_spot_open_orders(program_id, accounts, quantity)
+            }
+            LyraeInstruction::SetCancelTtl { cancel_ttl_secs } => {
+                msg!("Lyrae: SetCancelTtl {}", cancel_ttl_secs);
+                Self::set_cancel_ttl(program_id, accounts, cancel_ttl_secs)
+            }
+            LyraeInstruction::Heartbeat => {
+                msg!("Lyrae: Heartbeat");
+                Self::heartbeat(program_id, accounts)
+            }
+            LyraeInstruction::CrankExpiredCancels { limit } => {
+                msg!("Lyrae: CrankExpiredCancels");
+                Self::crank_expired_cancels(program_id, accounts, limit)
+            }
+            LyraeInstruction::LogPerpPnl => {
+                msg!("Lyrae: LogPerpPnl");
+                Self::log_perp_pnl(program_id, accounts)
+            }
+            LyraeInstruction::LogCacheConsistency => {
+                msg!("Lyrae: LogCacheConsistency");
+                Self::log_cache_consistency(program_id, accounts)
+            }
+            LyraeInstruction::SetDexProgramId => {
+                msg!("Lyrae: SetDexProgramId");
+                Self::set_dex_program_id(program_id, accounts)
+            }
+            LyraeInstruction::PlacePerpOrderByNotional {
+                price,
+                quote_notional,
+                client_order_id,
+                side,
+                order_type,
+                reduce_only,
+            } => {
+                msg!("Lyrae: PlacePerpOrderByNotional client_order_id={}", client_order_id);
+                Self::place_perp_order_by_notional(
+                    program_id,
+                    accounts,
+                    side,
+                    price,
+                    quote_notional,
+                    client_order_id,
+                    order_type,
+                    reduce_only,
+                )
+            }
+            LyraeInstruction::CrankRedeemLyr => {
+                msg!("Lyrae: CrankRedeemLyr");
+                Self::crank_redeem_lyr(program_id, accounts)
+            }
+            LyraeInstruction::LogGroupTvl => {
+                msg!("Lyrae: LogGroupTvl");
+                Self::log_group_tvl(program_id, accounts)
+            }
+            LyraeInstruction::SetGroupHalted { halted } => {
+                msg!("Lyrae: SetGroupHalted");
+                Self::set_group_halted(program_id, accounts, halted)
+            }
+            LyraeInstruction::SetAutoSettleFunding { auto_settle_funding } => {
+                msg!("Lyrae: SetAutoSettleFunding");
+                Self::set_auto_settle_funding(program_id, accounts, auto_settle_funding)
+            }
+            LyraeInstruction::CrankAutoSettleFunding { market_index } => {
+                msg!("Lyrae: CrankAutoSettleFunding");
+                Self::crank_auto_settle_funding(program_id, accounts, market_index)
+            }
+            LyraeInstruction::PrepareAccountClose { num_perp_markets } => {
+                msg!("Lyrae: PrepareAccountClose");
+                Self::prepare_account_close(program_id, accounts, num_perp_markets)
+            }
+            LyraeInstruction::LogAllOpenOrders => {
+                msg!("Lyrae: LogAllOpenOrders");
+                Self::log_all_open_orders(program_id, accounts)
+            }
+            LyraeInstruction::SetTokenFees { deposit_fee_bps, withdraw_fee_bps } => {
+                msg!("Lyrae: SetTokenFees");
+                Self::set_token_fees(program_id, accounts, deposit_fee_bps, withdraw_fee_bps)
+            }
+            LyraeInstruction::SetPythConfFilter { conf_filter_bps } => {
+                msg!("Lyrae: SetPythConfFilter");
+                Self::set_pyth_conf_filter(program_id, accounts, conf_filter_bps)
+            }
+            LyraeInstruction::SimulateLiquidatePerpMarket {
+                base_transfer_request,
+                liqor_max_base_position,
+            } => {
+                msg!("Lyrae: SimulateLiquidatePerpMarket");
+                Self::simulate_liquidate_perp_market(
+                    program_id,
+                    accounts,
+                    base_transfer_request,
+                    liqor_max_base_position,
+                )
+            }
+            LyraeInstruction::SetAccountGroup { account_label } => {
+                msg!("Lyrae: SetAccountGroup");
+                Self::set_account_group(program_id, accounts, account_label)
+            }
+            LyraeInstruction::UpdateRootBanks => {
+                msg!("Lyrae: UpdateRootBanks");
+                Self::update_root_banks(program_id, accounts)
+            }
+            LyraeInstruction::RemoveSpotMarket => {
+                msg!("Lyrae: RemoveSpotMarket");
+                Self::remove_spot_market(program_id, accounts)
+            }
+            LyraeInstruction::ExecutePerpTriggerOrdersMulti { num_orders, order_indexes } => {
+                msg!("Lyrae: ExecutePerpTriggerOrdersMulti");
+                Self::execute_perp_trigger_orders_multi(
+                    program_id,
+                    accounts,
+                    &order_indexes[..num_orders as usize],
+                )
+            }
+            LyraeInstruction::PlacePerpOrderAmend {
+                order_id,
+                by_client_id,
+                price,
+                quantity,
+                client_order_id,
+                order_type,
+                reduce_only,
+            } => {
+                msg!("Lyrae: PlacePerpOrderAmend");
+                Self::place_perp_order_amend(
+                    program_id,
+                    accounts,
+                    order_id,
+                    by_client_id,
+                    price,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                    reduce_only,
+                    false,
+                )
+            }
+            LyraeInstruction::SetMinCollateralToTrade { min_collateral_to_trade } => {
+                msg!("Lyrae: SetMinCollateralToTrade");
+                Self::set_min_collateral_to_trade(program_id, accounts, min_collateral_to_trade)
+            }
+            LyraeInstruction::ConsumeEventsForAccount { lyrae_account, limit } => {
+                msg!("Lyrae: ConsumeEventsForAccount");
+                Self::consume_events_for_account(program_id, accounts, lyrae_account, limit)
+            }
+            LyraeInstruction::ModifyPerpOrderReduceOnly {
+                order_id,
+                by_client_id,
+                price,
+                quantity,
+                client_order_id,
+                order_type,
+            } => {
+                msg!("Lyrae: ModifyPerpOrderReduceOnly");
+                Self::place_perp_order_amend(
+                    program_id,
+                    accounts,
+                    order_id,
+                    by_client_id,
+                    price,
+                    quantity,
+                    client_order_id,
+                    order_type,
+                    false,
+                    true,
+                )
+            }
+            LyraeInstruction::LogTokenBalances => {
+                msg!("Lyrae: LogTokenBalances");
+                Self::log_token_balances(program_id, accounts)
+            }
+            LyraeInstruction::UpdateAllCaches { num_perp_markets } => {
+                msg!("Lyrae: UpdateAllCaches");
+                Self::update_all_caches(program_id, accounts, num_perp_markets as usize)
+            }
+            LyraeInstruction::SetMmExempt { mm_exempt } => {
+                msg!("Lyrae: SetMmExempt");
+                Self::set_mm_exempt(program_id, accounts, mm_exempt)
+            }
+            LyraeInstruction::LogHealthComputeEstimate => {
+                msg!("Lyrae: LogHealthComputeEstimate");
+                Self::log_health_compute_estimate(program_id, accounts)
+            }
+            LyraeInstruction::ForceSettleFunds => {
+                msg!("Lyrae: ForceSettleFunds");
+                Self::force_settle_funds(program_id, accounts)
+            }
+            LyraeInstruction::AdminClearLiquidationFlag => {
+                msg!("Lyrae: AdminClearLiquidationFlag");
+                Self::admin_clear_liquidation_flag(program_id, accounts)
+            }
+            LyraeInstruction::LogRemainingBorrows => {
+                msg!("Lyrae: LogRemainingBorrows");
+                Self::log_remaining_borrows(program_id, accounts)
+            }
+            LyraeInstruction::ComputeHealth => {
+                msg!("Lyrae: ComputeHealth");
+                Self::compute_health(program_id, accounts)
+            }
+            LyraeInstruction::SetFeesInsuranceShare { insurance_share_pct } => {
+                msg!("Lyrae: SetFeesInsuranceShare");
+                Self::set_fees_insurance_share(program_id, accounts, insurance_share_pct)
+            }
+            LyraeInstruction::CancelAllSpotOrders { limit } => {
+                msg!("Lyrae: CancelAllSpotOrders");
+                Self::cancel_all_spot_orders(program_id, accounts, limit)
+            }
+            LyraeInstruction::LogLyrAccrued => {
+                msg!("Lyrae: LogLyrAccrued");
+                Self::log_lyr_accrued(program_id, accounts)
+            }
+            LyraeInstruction::SetGroupPaused { paused } => {
+                msg!("Lyrae: SetGroupPaused");
+                Self::set_group_paused(program_id, accounts, paused)
+            }
+            LyraeInstruction::ReassignSpotOpenOrders => {
+                msg!("Lyrae: ReassignSpotOpenOrders");
+                Self::reassign_spot_open_orders(program_id, accounts)
+            }
+            LyraeInstruction::TransferPerpPosition => {
+                msg!("Lyrae: TransferPerpPosition");
+                Self::transfer_perp_position(program_id, accounts)
+            }
+            LyraeInstruction::ChangeMinLiquidationHealthImprovement { min_health_improvement } => {
+                msg!("Lyrae: ChangeMinLiquidationHealthImprovement");
+                Self::change_min_liquidation_health_improvement(
+                    program_id,
+                    accounts,
+                    min_health_improvement,
                 )
             }
-            LyraeInstruction::ChangePerpMarketParams2 {
-                maint_leverage,
-                init_leverage,
-                liquidation_fee,
-                maker_fee,
-                taker_fee,
-                rate,
-                max_depth_bps,
-                target_period_length,
-                lyr_per_period,
-                exp,
-                version,
-                lm_size_shift,
-            } => {
-                msg!("Lyrae: ChangePerpMarketParams2");
-                Self::change_perp_market_params2(
+            LyraeInstruction::EmergencySetAssetWeight { init_asset_weight, maint_asset_weight } => {
+                msg!("Lyrae: EmergencySetAssetWeight");
+                Self::emergency_set_asset_weight(
                     program_id,
                     accounts,
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    maker_fee,
-                    taker_fee,
-                    rate,
-                    max_depth_bps,
-                    target_period_length,
-                    lyr_per_period,
-                    exp,
-                    version,
-                    lm_size_shift,
+                    init_asset_weight,
+                    maint_asset_weight,
                 )
             }
-            LyraeInstruction::UpdateMarginBasket => {
-                msg!("Lyrae: UpdateMarginBasket");
-                Self::update_margin_basket(program_id, accounts)
+            LyraeInstruction::SetMsrmFeeTier { tier, min_msrm, discount_bps } => {
+                msg!("Lyrae: SetMsrmFeeTier");
+                Self::set_msrm_fee_tier(program_id, accounts, tier, min_msrm, discount_bps)
+            }
+            LyraeInstruction::ReassignSpotMarket => {
+                msg!("Lyrae: ReassignSpotMarket");
+                Self::reassign_spot_market(program_id, accounts)
+            }
+        }
+    }
+}
+
+fn init_root_bank(
+    program_id: &Pubkey,
+    lyrae_group: &LyraeGroup,
+    mint_ai: &AccountInfo,
+    vault_ai: &AccountInfo,
+    root_bank_ai: &AccountInfo,
+    node_bank_ai: &AccountInfo,
+    rent: &Rent,
+
+    optimal_util: I80F48,
+    optimal_rate: I80F48,
+    max_rate: I80F48,
+) -> LyraeResult<RootBank> {
+    let vault = Account::unpack(&vault_ai.try_borrow_data()?)?;
+    check!(vault.is_initialized(), LyraeErrorCode::InvalidVault)?;
+    check!(vault.delegate.is_none(), LyraeErrorCode::InvalidVault)?;
+    check!(vault.close_authority.is_none(), LyraeErrorCode::InvalidVault)?;
+    check_eq!(vault.owner, lyrae_group.signer_key, LyraeErrorCode::InvalidVault)?;
+    check_eq!(&vault.mint, mint_ai.key, LyraeErrorCode::InvalidVault)?;
+    check_eq!(vault_ai.owner, &spl_token::id(), LyraeErrorCode::InvalidVault)?;
+
+    let _node_bank = NodeBank::load_and_init(&node_bank_ai, &program_id, &vault_ai, rent)?;
+    let root_bank = RootBank::load_and_init(
+        &root_bank_ai,
+        &program_id,
+        node_bank_ai,
+        rent,
+        optimal_util,
+        optimal_rate,
+        max_rate,
+    )?;
+
+    Ok(*root_bank)
+}
+
+fn invoke_settle_funds<'a>(
+    dex_prog_ai: &AccountInfo<'a>,
+    spot_market_ai: &AccountInfo<'a>,
+    open_orders_ai: &AccountInfo<'a>,
+    signer_ai: &AccountInfo<'a>,
+    dex_base_ai: &AccountInfo<'a>,
+    dex_quote_ai: &AccountInfo<'a>,
+    base_vault_ai: &AccountInfo<'a>,
+    quote_vault_ai: &AccountInfo<'a>,
+    dex_signer_ai: &AccountInfo<'a>,
+    token_prog_ai: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let data = serum_dex::instruction::MarketInstruction::SettleFunds.pack();
+    let instruction = Instruction {
+        program_id: *dex_prog_ai.key,
+        data,
+        accounts: vec![
+            AccountMeta::new(*spot_market_ai.key, false),
+            AccountMeta::new(*open_orders_ai.key, false),
+            AccountMeta::new_readonly(*signer_ai.key, true),
+            AccountMeta::new(*dex_base_ai.key, false),
+            AccountMeta::new(*dex_quote_ai.key, false),
+            AccountMeta::new(*base_vault_ai.key, false),
+            AccountMeta::new(*quote_vault_ai.key, false),
+            AccountMeta::new_readonly(*dex_signer_ai.key, false),
+            AccountMeta::new_readonly(*token_prog_ai.key, false),
+            AccountMeta::new(*quote_vault_ai.key, false),
+        ],
+    };
+
+    let account_infos = [
+        dex_prog_ai.clone(),
+        spot_market_ai.clone(),
+        open_orders_ai.clone(),
+        signer_ai.clone(),
+        dex_base_ai.clone(),
+        dex_quote_ai.clone(),
+        base_vault_ai.clone(),
+        quote_vault_ai.clone(),
+        dex_signer_ai.clone(),
+        token_prog_ai.clone(),
+        quote_vault_ai.clone(),
+    ];
+    solana_program::program::invoke_signed_unchecked(&instruction, &account_infos, signers_seeds)
+}
+
+fn invoke_cancel_order<'a>(
+    dex_prog_ai: &AccountInfo<'a>,
+    spot_market_ai: &AccountInfo<'a>,
+    bids_ai: &AccountInfo<'a>,
+    asks_ai: &AccountInfo<'a>,
+    open_orders_ai: &AccountInfo<'a>,
+    signer_ai: &AccountInfo<'a>,
+    dex_event_queue_ai: &AccountInfo<'a>,
+    data: Vec<u8>,
+    signers_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *dex_prog_ai.key,
+        data,
+        accounts: vec![
+            AccountMeta::new(*spot_market_ai.key, false),
+            AccountMeta::new(*bids_ai.key, false),
+            AccountMeta::new(*asks_ai.key, false),
+            AccountMeta::new(*open_orders_ai.key, false),
+            AccountMeta::new_readonly(*signer_ai.key, true),
+            AccountMeta::new(*dex_event_queue_ai.key, false),
+        ],
+    };
+
+    let account_infos = [
+        dex_prog_ai.clone(),
+        spot_market_ai.clone(),
+        bids_ai.clone(),
+        asks_ai.clone(),
+        open_orders_ai.clone(),
+        signer_ai.clone(),
+        dex_event_queue_ai.clone(),
+    ];
+    solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
+}
+
+/// Transfer settled perp fees, in native quote, from the bank vault to the fees vault owned by
+/// Lyrae DAO and the insurance vault. The insurance vault is only needed for a non-zero amount.
+fn transfer_settled_fees<'a>(
+    lyrae_group: &LyraeGroup,
+    lyrae_group_pk: &Pubkey,
+    token_prog_ai: &AccountInfo<'a>,
+    bank_vault_ai: &AccountInfo<'a>,
+    fees_vault_ai: &AccountInfo<'a>,
+    insurance_vault_ai: Option<&AccountInfo<'a>>,
+    signer_ai: &AccountInfo<'a>,
+    to_fees_vault: u64,
+    to_insurance_vault: u64,
+) -> LyraeResult {
+    let signers_seeds = gen_signer_seeds(&lyrae_group.signer_nonce, lyrae_group_pk);
+    if to_fees_vault > 0 {
+        invoke_transfer(
+            token_prog_ai,
+            bank_vault_ai,
+            fees_vault_ai,
+            signer_ai,
+            &[&signers_seeds],
+            to_fees_vault,
+        )?;
+    }
+    if to_insurance_vault > 0 {
+        let insurance_vault_ai =
+            insurance_vault_ai.ok_or(throw_err!(LyraeErrorCode::InvalidVault))?;
+        check!(
+            insurance_vault_ai.key == &lyrae_group.insurance_vault,
+            LyraeErrorCode::InvalidVault
+        )?;
+        invoke_transfer(
+            token_prog_ai,
+            bank_vault_ai,
+            insurance_vault_ai,
+            signer_ai,
+            &[&signers_seeds],
+            to_insurance_vault,
+        )?;
+    }
+    Ok(())
+}
+
+fn invoke_transfer<'a>(
+    token_prog_ai: &AccountInfo<'a>,
+    source_ai: &AccountInfo<'a>,
+    dest_ai: &AccountInfo<'a>,
+    authority_ai: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+    quantity: u64,
+) -> ProgramResult {
+    let transfer_instruction = spl_token::instruction::transfer(
+        &spl_token::ID,
+        source_ai.key,
+        dest_ai.key,
+        authority_ai.key,
+        &[],
+        quantity,
+    )?;
+    let accs = [
+        token_prog_ai.clone(), // TODO check if passing in program_id is necessary
+        source_ai.clone(),
+        dest_ai.clone(),
+        authority_ai.clone(),
+    ];
+
+    solana_program::program::invoke_signed(&transfer_instruction, &accs, signers_seeds)
+}
+
+#[inline(never)]
+fn read_oracle(
+    lyrae_group: &LyraeGroup,
+    token_index: usize,
+    oracle_ai: &AccountInfo,
+    clock: &Clock,
+) -> LyraeResult<I80F48> {
+    let quote_decimals = lyrae_group.tokens[QUOTE_INDEX].decimals as i32;
+    let base_decimals = lyrae_group.tokens[token_index].decimals as i32;
+
+    let oracle_type = determine_oracle_type(oracle_ai);
+
+    let price = match oracle_type {
+        OracleType::Pyth => {
+            let price_account = Price::get_price(oracle_ai)?;
+            let value = I80F48::from_num(price_account.agg.price);
+
+            // Filter out bad prices on mainnet
+            #[cfg(not(feature = "devnet"))]
+            let conf = I80F48::from_num(price_account.agg.conf).checked_div(value).unwrap();
+
+            #[cfg(not(feature = "devnet"))]
+            if price_account.agg.status != PriceStatus::Trading {
+                msg!("Pyth status invalid: {}", price_account.agg.status as u8);
+                return Err(throw_err!(LyraeErrorCode::InvalidOraclePrice));
+            } else if conf > lyrae_group.get_pyth_conf_filter() {
+                msg!(
+                    "Pyth conf interval too high; oracle index: {} value: {} conf: {}",
+                    token_index,
+                    value.to_num::<f64>(),
+                    conf.to_num::<f64>()
+                );
+                return Err(throw_err!(LyraeErrorCode::InvalidOraclePrice));
+            }
+
+            let decimals = quote_decimals
+                .checked_add(price_account.expo)
+                .unwrap()
+                .checked_sub(base_decimals)
+                .unwrap();
+
+            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+            if decimals < 0 {
+                value.checked_div(decimal_adj).unwrap()
+            } else {
+                value.checked_mul(decimal_adj).unwrap()
+            }
+        }
+        OracleType::Stub => {
+            let oracle = StubOracle::load(oracle_ai)?;
+            I80F48::from_num(oracle.price)
+        }
+        OracleType::Switchboard => {
+            let result =
+                FastRoundResultAccountData::deserialize(&oracle_ai.try_borrow_data()?).unwrap();
+            let value = I80F48::from_num(result.result.result);
+
+            let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
+            if decimals < 0 {
+                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+                value.checked_div(decimal_adj).unwrap()
+            } else if decimals > 0 {
+                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+                value.checked_mul(decimal_adj).unwrap()
+            } else {
+                value
             }
-            LyraeInstruction::ChangeMaxLyraeAccounts { max_lyrae_accounts } => {
-                msg!("Lyrae: ChangeMaxLyraeAccounts");
-                Self::change_max_lyrae_accounts(program_id, accounts, max_lyrae_accounts)
+        }
+        OracleType::SwitchboardV2 => {
+            let round = SwitchboardV2Round::get_latest_confirmed_round(oracle_ai)?;
+            let value = round.get_result(clock.unix_timestamp as u64, clock.slot)?;
+
+            // Filter out bad prices on mainnet, same as for pyth
+            #[cfg(not(feature = "devnet"))]
+            let conf = round
+                .std_deviation
+                .to_i80f48()
+                .ok_or(math_err!())?
+                .checked_div(value)
+                .ok_or(math_err!())?;
+
+            #[cfg(not(feature = "devnet"))]
+            if conf > lyrae_group.get_pyth_conf_filter() {
+                msg!(
+                    "Switchboard std deviation too high; oracle index: {} value: {} std: {}",
+                    token_index,
+                    value.to_num::<f64>(),
+                    conf.to_num::<f64>()
+                );
+                return Err(throw_err!(LyraeErrorCode::InvalidOraclePrice));
             }
-            LyraeInstruction::CreateDustAccount => {
-                msg!("Lyrae: CreateDustAccount");
-                Self::create_dust_account(program_id, accounts)
+
+            let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
+            if decimals < 0 {
+                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+                value.checked_div(decimal_adj).unwrap()
+            } else if decimals > 0 {
+                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
+                value.checked_mul(decimal_adj).unwrap()
+            } else {
+                value
             }
-            LyraeInstruction::ResolveDust => {
-                msg!("Lyrae: ResolveDust");
-                Self::resolve_dust(program_id, accounts)
+        }
+        OracleType::Unknown => return Err(throw_err!(LyraeErrorCode::InvalidOracleType)),
+    };
+    Ok(price)
+}
+
+/// Transfer token deposits/borrows between two LyraeAccounts
+/// `native_quantity` is subtracted from src and added to dst
+/// Make sure to credit deposits first in case Node bank is fully utilized
+fn transfer_token_internal(
+    root_bank_cache: &RootBankCache,
+    node_bank: &mut NodeBank,
+    src: &mut LyraeAccount,
+    dst: &mut LyraeAccount,
+    src_pk: &Pubkey,
+    dst_pk: &Pubkey,
+    token_index: usize,
+    native_quantity: I80F48,
+) -> LyraeResult<()> {
+    let neg_quantity = native_quantity.checked_neg().ok_or(math_err!())?;
+    if native_quantity.is_positive() {
+        // increase dst first before decreasing from src
+        checked_change_net(root_bank_cache, node_bank, dst, dst_pk, token_index, native_quantity)?;
+        checked_change_net(root_bank_cache, node_bank, src, src_pk, token_index, neg_quantity)?;
+    } else if native_quantity.is_negative() {
+        // increase src first before decreasing from dst
+        checked_change_net(root_bank_cache, node_bank, src, src_pk, token_index, neg_quantity)?;
+        checked_change_net(root_bank_cache, node_bank, dst, dst_pk, token_index, native_quantity)?;
+    }
+    Ok(())
+}
+
+/// Check that `node_bank_ais` are all the NodeBanks of `root_bank`, update its deposit and
+/// borrow indexes and write them to the cache
+fn update_root_bank_and_cache(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache: &mut LyraeCache,
+    token_index: usize,
+    root_bank: &mut RootBank,
+    node_bank_ais: &[AccountInfo],
+    now_ts: u64,
+) -> LyraeResult {
+    check_eq!(root_bank.num_node_banks, node_bank_ais.len(), LyraeErrorCode::Default)?;
+    for i in 0..root_bank.num_node_banks {
+        check!(
+            node_bank_ais.iter().any(|ai| ai.key == &root_bank.node_banks[i]),
+            LyraeErrorCode::InvalidNodeBank
+        )?;
+    }
+    root_bank.update_index(node_bank_ais, program_id, now_ts)?;
+
+    lyrae_cache.root_bank_cache[token_index] = RootBankCache {
+        deposit_index: root_bank.deposit_index,
+        borrow_index: root_bank.borrow_index,
+        last_update: now_ts,
+    };
+
+    lyrae_emit!(UpdateRootBankLog {
+        lyrae_group: *lyrae_group_pk,
+        token_index: token_index as u64,
+        deposit_index: root_bank.deposit_index.to_bits(),
+        borrow_index: root_bank.borrow_index.to_bits()
+    });
+    Ok(())
+}
+
+/// Check the account collecting a token's deposit and withdraw fees: the group's fees_vault for
+/// the quote token, otherwise a token account of that mint owned by the group admin
+fn check_token_fee_vault(
+    lyrae_group: &LyraeGroup,
+    token_index: usize,
+    fee_vault_ai: &AccountInfo,
+) -> LyraeResult<()> {
+    if token_index == QUOTE_INDEX {
+        return check!(fee_vault_ai.key == &lyrae_group.fees_vault, LyraeErrorCode::InvalidVault);
+    }
+    let fee_vault = Account::unpack(&fee_vault_ai.try_borrow_data()?)?;
+    check!(fee_vault.mint == lyrae_group.tokens[token_index].mint, LyraeErrorCode::InvalidVault)?;
+    check!(fee_vault.owner == lyrae_group.admin, LyraeErrorCode::InvalidVault)
+}
+
+/// Check that `dust_account_ai` is the group's DustAccount PDA
+fn check_dust_account(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    dust_account_ai: &AccountInfo,
+) -> LyraeResult<()> {
+    let (pda_address, _bump_seed) =
+        Pubkey::find_program_address(&[lyrae_group_pk.as_ref(), b"DustAccount"], program_id);
+    check!(&pda_address == dust_account_ai.key, LyraeErrorCode::InvalidAccount)
+}
+
+/// Move the whole base and quote position of `src_pa` to `dst_pa`, after settling the funding
+/// of both
+fn transfer_perp_position_internal(
+    perp_market: &mut PerpMarket,
+    perp_market_cache: &PerpMarketCache,
+    src_pa: &mut PerpAccount,
+    dst_pa: &mut PerpAccount,
+) {
+    src_pa.settle_funding(perp_market_cache);
+    dst_pa.settle_funding(perp_market_cache);
+
+    let base_position = src_pa.base_position;
+    src_pa.change_base_position(perp_market, -base_position);
+    dst_pa.change_base_position(perp_market, base_position);
+    let quote_position = src_pa.quote_position;
+    src_pa.transfer_quote_position(dst_pa, quote_position);
+}
+
+/// Size a perp liquidation: the base lots moved from liqee to liqor (capped by the liqee's init
+/// health, the request, the group's max liquidation fraction and the liqor's inventory cap) and
+/// the quote position moved in the opposite direction, including the liquidation fee
+fn get_perp_liquidation_transfer(
+    lyrae_group: &LyraeGroup,
+    market_index: usize,
+    price: I80F48,
+    init_health: I80F48,
+    liqee_perp_account: &PerpAccount,
+    liqor_perp_account: &PerpAccount,
+    base_transfer_request: i64,
+    liqor_max_base_position: Option<u64>,
+) -> LyraeResult<(i64, I80F48)> {
+    let pmi = &lyrae_group.perp_markets[market_index];
+    let lot_price = price * I80F48::from_num(pmi.base_lot_size);
+    let liquidation_fee = lyrae_group.get_perp_liquidation_fee(market_index);
+
+    // Partial liquidation policy: only a fraction of the position can move per liquidation
+    let max_fraction_transfer =
+        lyrae_group.max_liquidation_base_transfer(liqee_perp_account.base_position);
+
+    // Optional liqor inventory cap: the liqor's resulting position must stay within it
+    let liqor_max_base_position =
+        liqor_max_base_position.map_or(i64::MAX, |cap| cap.min(i64::MAX as u64) as i64);
+    let liqor_base_position = liqor_perp_account.base_position;
+    let (base_transfer, quote_transfer) = if liqee_perp_account.base_position > 0 {
+        check!(base_transfer_request > 0, LyraeErrorCode::InvalidParam)?;
+
+        let health_per_lot = lot_price * (ONE_I80F48 - pmi.init_asset_weight - liquidation_fee);
+        let max_transfer = -init_health / health_per_lot;
+        let max_transfer: i64 = max_transfer.checked_ceil().unwrap().checked_to_num().unwrap();
+
+        let base_transfer = max_transfer
+            .min(base_transfer_request)
+            .min(liqee_perp_account.base_position)
+            .min(max_fraction_transfer)
+            .min(liqor_max_base_position.saturating_sub(liqor_base_position));
+        check!(base_transfer > 0, LyraeErrorCode::InvalidParam)?;
+
+        let quote_transfer = I80F48::from_num(-base_transfer * pmi.base_lot_size)
+            * price
+            * (ONE_I80F48 - liquidation_fee);
+
+        (base_transfer, quote_transfer)
+    } else {
+        // We know it liqee_perp_account.base_position < 0
+        check!(base_transfer_request < 0, LyraeErrorCode::InvalidParam)?;
+
+        let health_per_lot = lot_price * (ONE_I80F48 - pmi.init_liab_weight + liquidation_fee);
+        let max_transfer = -init_health / health_per_lot;
+        let max_transfer: i64 = max_transfer.checked_floor().unwrap().checked_to_num().unwrap();
+
+        let base_transfer = max_transfer
+            .max(base_transfer_request)
+            .max(liqee_perp_account.base_position)
+            .max(-max_fraction_transfer)
+            .max((-liqor_max_base_position).saturating_sub(liqor_base_position));
+        check!(base_transfer < 0, LyraeErrorCode::InvalidParam)?;
+        let quote_transfer = I80F48::from_num(-base_transfer * pmi.base_lot_size)
+            * price
+            * (ONE_I80F48 + liquidation_fee);
+
+        (base_transfer, quote_transfer)
+    };
+    Ok((base_transfer, quote_transfer))
+}
+
+/// Apply a FillEvent to its maker and taker and emit the logs of consume_events.
+/// Returns false without changing anything if an account is missing from `lyrae_account_ais`.
+fn execute_fill_event(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    lyrae_account_ais: &[AccountInfo],
+    perp_market: &mut PerpMarket,
+    lyrae_cache: &LyraeCache,
+    market_index: usize,
+    perp_market_info: &PerpMarketInfo,
+    fill: &FillEvent,
+) -> LyraeResult<bool> {
+    let perp_market_cache = &lyrae_cache.perp_market_cache[market_index];
+    // handle self trade separately because of rust borrow checker
+    // The base and quote changes of both sides cancel out. The taker fee (and any referral fee)
+    // was charged once when the order matched and execute_taker charges none, so a self trade
+    // only pays the taker fee plus the maker fee (or rebate) applied by execute_maker.
+    if fill.maker == fill.taker {
+        let mut ma = match lyrae_account_ais.iter().find(|ai| ai.key == &fill.maker) {
+            None => {
+                msg!("Unable to find account {}", fill.maker.to_string());
+                return Ok(false);
             }
-            LyraeInstruction::CancelPerpOrdersSide { side, limit } => {
-                msg!("Lyrae: CancelSidePerpOrders");
-                Self::cancel_perp_orders_side(program_id, accounts, side, limit)
+            Some(account_info) => {
+                LyraeAccount::load_mut_checked(account_info, program_id, lyrae_group_pk)?
             }
-            LyraeInstruction::SetDelegate => {
-                msg!("Lyrae: SetDelegate");
-                Self::set_delegate(program_id, accounts)
+        };
+        let pre_lyr = ma.perp_accounts[market_index].lyr_accrued;
+        ma.execute_maker(market_index, perp_market, perp_market_cache, fill)?;
+        ma.execute_taker(market_index, perp_market, perp_market_cache, fill)?;
+        lyrae_emit!(LyrAccrualLog {
+            lyrae_group: *lyrae_group_pk,
+            lyrae_account: fill.maker,
+            market_index: market_index as u64,
+            lyr_accrual: ma.perp_accounts[market_index].lyr_accrued - pre_lyr
+        });
+        emit_perp_balances(
+            *lyrae_group_pk,
+            fill.maker,
+            market_index as u64,
+            &ma.perp_accounts[market_index],
+            perp_market_cache,
+        );
+    } else {
+        let mut maker = match lyrae_account_ais.iter().find(|ai| ai.key == &fill.maker) {
+            None => {
+                msg!("Unable to find maker account {}", fill.maker.to_string());
+                return Ok(false);
             }
-            LyraeInstruction::ChangeSpotMarketParams {
-                maint_leverage,
-                init_leverage,
-                liquidation_fee,
-                optimal_util,
-                optimal_rate,
-                max_rate,
-                version,
-            } => {
-                msg!("Lyrae: ChangeSpotMarketParams");
-                Self::change_spot_market_params(
-                    program_id,
-                    accounts,
-                    maint_leverage,
-                    init_leverage,
-                    liquidation_fee,
-                    optimal_util,
-                    optimal_rate,
-                    max_rate,
-                    version,
-                )
+            Some(account_info) => {
+                LyraeAccount::load_mut_checked(account_info, program_id, lyrae_group_pk)?
             }
-            LyraeInstruction::CreateSpotOpenOrders => {
-                msg!("Lyrae: CreateSpotOpenOrders");
-                Self::create_spot_open_orders(program_id, accounts)
+        };
+        let mut taker = match lyrae_account_ais.iter().find(|ai| ai.key == &fill.taker) {
+            None => {
+                msg!("Unable to find taker account {}", fill.taker.to_string());
+                return Ok(false);
             }
-            LyraeInstruction::ChangeReferralFeeParams {
-                ref_surcharge_centibps,
-                ref_share_centibps,
-                ref_lyr_required,
-            } => {
-                msg!("Lyrae: ChangeReferralFeeParams");
-                Self::change_referral_fee_params(
-                    program_id,
-                    accounts,
-                    ref_surcharge_centibps,
-                    ref_share_centibps,
-                    ref_lyr_required,
-                )
+            Some(account_info) => {
+                LyraeAccount::load_mut_checked(account_info, program_id, lyrae_group_pk)?
             }
-            LyraeInstruction::SetReferrerMemory => {
-                msg!("Lyrae: SetReferrerMemory");
-                Self::set_referrer_memory(program_id, accounts)
+        };
+        let pre_lyr = maker.perp_accounts[market_index].lyr_accrued;
+
+        maker.execute_maker(market_index, perp_market, perp_market_cache, fill)?;
+        taker.execute_taker(market_index, perp_market, perp_market_cache, fill)?;
+        lyrae_emit!(LyrAccrualLog {
+            lyrae_group: *lyrae_group_pk,
+            lyrae_account: fill.maker,
+            market_index: market_index as u64,
+            lyr_accrual: maker.perp_accounts[market_index].lyr_accrued - pre_lyr
+        });
+        emit_perp_balances(
+            *lyrae_group_pk,
+            fill.maker,
+            market_index as u64,
+            &maker.perp_accounts[market_index],
+            perp_market_cache,
+        );
+        emit_perp_balances(
+            *lyrae_group_pk,
+            fill.taker,
+            market_index as u64,
+            &taker.perp_accounts[market_index],
+            perp_market_cache,
+        );
+    }
+    let market_taker_fee = perp_market_info.discounted_taker_fee(fill.taker_fee_discount_bps);
+    lyrae_emit!(fill.to_fill_log(*lyrae_group_pk, market_index, market_taker_fee));
+    Ok(true)
+}
+
+/// Return the taker fills, in signed base and quote lots, and the base lots posted to the book
+/// by a perp order as three little endian i64s, so a calling program can read them via CPI
+fn set_perp_order_return_data(base_filled: i64, quote_filled: i64, posted_quantity: i64) {
+    let mut data = Vec::with_capacity(24);
+    data.extend_from_slice(&base_filled.to_le_bytes());
+    data.extend_from_slice(&quote_filled.to_le_bytes());
+    data.extend_from_slice(&posted_quantity.to_le_bytes());
+    set_return_data(&data);
+}
+
+/// Check that the account has the equity the group requires to place risk increasing orders
+fn check_min_collateral_to_trade(
+    lyrae_group: &LyraeGroup,
+    health_cache: &HealthCache,
+) -> LyraeResult<()> {
+    let min_collateral = lyrae_group.get_min_collateral_to_trade();
+    check!(
+        min_collateral == 0
+            || health_cache.get_equity(lyrae_group)
+                > lyrae_group.quote_tokens_to_native(min_collateral),
+        LyraeErrorCode::InsufficientCollateral
+    )
+}
+
+/// Check that a referrer is a program owned LyraeAccount of this group
+fn check_referrer(
+    program_id: &Pubkey,
+    referrer_lyrae_account_ai: &AccountInfo,
+    lyrae_group_pk: &Pubkey,
+) -> LyraeResult<()> {
+    check_eq!(referrer_lyrae_account_ai.owner, program_id, LyraeErrorCode::InvalidReferrer)?;
+    LyraeAccount::load_checked(referrer_lyrae_account_ai, program_id, lyrae_group_pk)
+        .map_err(|_| throw_err!(LyraeErrorCode::InvalidReferrer))?;
+    Ok(())
+}
+
+fn checked_change_net(
+    root_bank_cache: &RootBankCache,
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    lyrae_account_pk: &Pubkey,
+    token_index: usize,
+    native_quantity: I80F48,
+) -> LyraeResult<()> {
+    let was_deposit = lyrae_account.deposits[token_index].is_positive();
+    let was_borrow = lyrae_account.borrows[token_index].is_positive();
+    if native_quantity.is_negative() {
+        let native_quantity = native_quantity.checked_neg().ok_or(math_err!())?;
+        checked_sub_net(root_bank_cache, node_bank, lyrae_account, token_index, native_quantity)?;
+    } else if native_quantity.is_positive() {
+        checked_add_net(root_bank_cache, node_bank, lyrae_account, token_index, native_quantity)?;
+    }
+
+    // Let indexers know which index now applies to this token position
+    let is_deposit = lyrae_account.deposits[token_index].is_positive();
+    let is_borrow = lyrae_account.borrows[token_index].is_positive();
+    if (was_deposit && is_borrow) || (was_borrow && is_deposit) {
+        lyrae_emit!(TokenPositionFlipLog {
+            lyrae_group: lyrae_account.lyrae_group,
+            lyrae_account: *lyrae_account_pk,
+            token_index: token_index as u64,
+            is_borrow,
+        });
+    }
+
+    lyrae_emit!(TokenBalanceLog {
+        lyrae_group: lyrae_account.lyrae_group,
+        lyrae_account: *lyrae_account_pk,
+        token_index: token_index as u64,
+        deposit: lyrae_account.deposits[token_index].to_bits(),
+        borrow: lyrae_account.borrows[token_index].to_bits()
+    });
+
+    Ok(()) // This is an optimization to prevent unnecessary I80F48 calculations
+}
+
+/// If there are borrows, pay down borrows first then increase deposits
+/// WARNING: won't work if native_quantity is less than zero
+fn checked_add_net(
+    root_bank_cache: &RootBankCache,
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    token_index: usize,
+    mut native_quantity: I80F48,
+) -> LyraeResult<()> {
+    if lyrae_account.borrows[token_index].is_positive() {
+        let native_borrows = lyrae_account.get_native_borrow(root_bank_cache, token_index)?;
+
+        if native_quantity < native_borrows {
+            return checked_sub_borrow(
+                node_bank,
+                lyrae_account,
+                token_index,
+                native_quantity.checked_div(root_bank_cache.borrow_index).ok_or(math_err!())?,
+            );
+        } else {
+            let borrows = lyrae_account.borrows[token_index];
+            checked_sub_borrow(node_bank, lyrae_account, token_index, borrows)?;
+            native_quantity = native_quantity.checked_sub(native_borrows).ok_or(math_err!())?;
+        }
+    }
+
+    checked_add_deposit(
+        node_bank,
+        lyrae_account,
+        token_index,
+        native_quantity.checked_div(root_bank_cache.deposit_index).ok_or(math_err!())?,
+    )
+}
+
+/// If there are deposits, draw down deposits first then increase borrows
+/// WARNING: won't work if native_quantity is less than zero
+fn checked_sub_net(
+    root_bank_cache: &RootBankCache,
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    token_index: usize,
+    mut native_quantity: I80F48,
+) -> LyraeResult<()> {
+    if lyrae_account.deposits[token_index].is_positive() {
+        let native_deposits = lyrae_account.get_native_deposit(root_bank_cache, token_index)?;
+
+        if native_quantity < native_deposits {
+            return checked_sub_deposit(
+                node_bank,
+                lyrae_account,
+                token_index,
+                native_quantity.checked_div(root_bank_cache.deposit_index).ok_or(math_err!())?,
+            );
+        } else {
+            let deposits = lyrae_account.deposits[token_index];
+            checked_sub_deposit(node_bank, lyrae_account, token_index, deposits)?;
+            native_quantity = native_quantity.checked_sub(native_deposits).ok_or(math_err!())?;
+        }
+    }
+
+    checked_add_borrow(
+        node_bank,
+        lyrae_account,
+        token_index,
+        native_quantity.checked_div(root_bank_cache.borrow_index).ok_or(math_err!())?,
+    )?;
+
+    check!(
+        node_bank.has_valid_deposits_borrows(root_bank_cache),
+        LyraeErrorCode::InsufficientLiquidity
+    )
+}
+
+/// TODO - although these values are I8048, they must never be less than zero
+fn checked_add_deposit(
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    token_index: usize,
+    quantity: I80F48,
+) -> LyraeResult<()> {
+    lyrae_account.checked_add_deposit(token_index, quantity)?;
+    node_bank.checked_add_deposit(quantity)
+}
+
+fn checked_sub_deposit(
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    token_index: usize,
+    quantity: I80F48,
+) -> LyraeResult<()> {
+    lyrae_account.checked_sub_deposit(token_index, quantity)?;
+    node_bank.checked_sub_deposit(quantity)
+}
+
+fn checked_add_borrow(
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    token_index: usize,
+    quantity: I80F48,
+) -> LyraeResult<()> {
+    lyrae_account.checked_add_borrow(token_index, quantity)?;
+    node_bank.checked_add_borrow(quantity)
+}
+
+fn checked_sub_borrow(
+    node_bank: &mut NodeBank,
+    lyrae_account: &mut LyraeAccount,
+    token_index: usize,
+    quantity: I80F48,
+) -> LyraeResult<()> {
+    lyrae_account.checked_sub_borrow(token_index, quantity)?;
+    node_bank.checked_sub_borrow(quantity)
+}
+
+fn invoke_cancel_orders<'a>(
+    open_orders_ai: &AccountInfo<'a>,
+    dex_prog_ai: &AccountInfo<'a>,
+    spot_market_ai: &AccountInfo<'a>,
+    bids_ai: &AccountInfo<'a>,
+    asks_ai: &AccountInfo<'a>,
+    signer_ai: &AccountInfo<'a>,
+    dex_event_queue_ai: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+
+    mut limit: u8,
+) -> LyraeResult<()> {
+    let mut cancels = vec![];
+    {
+        let open_orders = load_open_orders(open_orders_ai)?;
+
+        let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
+        let bids = load_bids_mut(&market, bids_ai)?;
+        let asks = load_asks_mut(&market, asks_ai)?;
+
+        limit = min(limit, open_orders.free_slot_bits.count_zeros() as u8);
+        if limit == 0 {
+            return Ok(());
+        }
+        for j in 0..128 {
+            let slot_mask = 1u128 << j;
+            if open_orders.free_slot_bits & slot_mask != 0 {
+                // means slot is free
+                continue;
             }
-            LyraeInstruction::RegisterReferrerId { referrer_id } => {
-                msg!("Lyrae: RegisterReferrerId");
-                Self::register_referrer_id(program_id, accounts, referrer_id)
+            let order_id = open_orders.orders[j];
+
+            let side = if open_orders.is_bid_bits & slot_mask != 0 {
+                match bids.find_by_key(order_id) {
+                    None => continue,
+                    Some(_) => serum_dex::matching::Side::Bid,
+                }
+            } else {
+                match asks.find_by_key(order_id) {
+                    None => continue,
+                    Some(_) => serum_dex::matching::Side::Ask,
+                }
+            };
+
+            let cancel_instruction =
+                serum_dex::instruction::CancelOrderInstructionV2 { side, order_id };
+
+            cancels.push(cancel_instruction);
+
+            limit -= 1;
+            if limit == 0 {
+                break;
             }
         }
     }
+
+    let mut instruction = Instruction {
+        program_id: *dex_prog_ai.key,
+        data: vec![],
+        accounts: vec![
+            AccountMeta::new(*spot_market_ai.key, false),
+            AccountMeta::new(*bids_ai.key, false),
+            AccountMeta::new(*asks_ai.key, false),
+            AccountMeta::new(*open_orders_ai.key, false),
+            AccountMeta::new_readonly(*signer_ai.key, true),
+            AccountMeta::new(*dex_event_queue_ai.key, false),
+        ],
+    };
+
+    let account_infos = [
+        dex_prog_ai.clone(),
+        spot_market_ai.clone(),
+        bids_ai.clone(),
+        asks_ai.clone(),
+        open_orders_ai.clone(),
+        signer_ai.clone(),
+        dex_event_queue_ai.clone(),
+    ];
+
+    for cancel in cancels.iter() {
+        let cancel_instruction =
+            serum_dex::instruction::MarketInstruction::CancelOrderV2(cancel.clone());
+        instruction.data = cancel_instruction.pack();
+        solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)?;
+    }
+
+    Ok(())
+}
+
+fn invoke_new_order<'a>(
+    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
+    spot_market_ai: &AccountInfo<'a>,
+    open_orders_ai: &AccountInfo<'a>,
+    dex_request_queue_ai: &AccountInfo<'a>,
+    dex_event_queue_ai: &AccountInfo<'a>,
+    bids_ai: &AccountInfo<'a>,
+    asks_ai: &AccountInfo<'a>,
+    vault_ai: &AccountInfo<'a>,
+    signer_ai: &AccountInfo<'a>,
+    dex_base_ai: &AccountInfo<'a>,
+    dex_quote_ai: &AccountInfo<'a>,
+    token_prog_ai: &AccountInfo<'a>,
+    msrm_or_srm_vault_ai: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+
+    order: NewOrderInstructionV3,
+) -> ProgramResult {
+    let data = serum_dex::instruction::MarketInstruction::NewOrderV3(order).pack();
+    let mut instruction = Instruction {
+        program_id: *dex_prog_ai.key,
+        data,
+        accounts: vec![
+            AccountMeta::new(*spot_market_ai.key, false),
+            AccountMeta::new(*open_orders_ai.key, false),
+            AccountMeta::new(*dex_request_queue_ai.key, false),
+            AccountMeta::new(*dex_event_queue_ai.key, false),
+            AccountMeta::new(*bids_ai.key, false),
+            AccountMeta::new(*asks_ai.key, false),
+            AccountMeta::new(*vault_ai.key, false),
+            AccountMeta::new_readonly(*signer_ai.key, true),
+            AccountMeta::new(*dex_base_ai.key, false),
+            AccountMeta::new(*dex_quote_ai.key, false),
+            AccountMeta::new_readonly(*token_prog_ai.key, false),
+            AccountMeta::new_readonly(*signer_ai.key, false),
+        ],
+    };
+
+    if msrm_or_srm_vault_ai.key != &Pubkey::default() {
+        instruction.accounts.push(AccountMeta::new_readonly(*msrm_or_srm_vault_ai.key, false));
+        let account_infos = [
+            dex_prog_ai.clone(), // Have to add account of the program id
+            spot_market_ai.clone(),
+            open_orders_ai.clone(),
+            dex_request_queue_ai.clone(),
+            dex_event_queue_ai.clone(),
+            bids_ai.clone(),
+            asks_ai.clone(),
+            vault_ai.clone(),
+            signer_ai.clone(),
+            dex_base_ai.clone(),
+            dex_quote_ai.clone(),
+            token_prog_ai.clone(),
+            signer_ai.clone(),
+            msrm_or_srm_vault_ai.clone(),
+        ];
+        solana_program::program::invoke_signed_unchecked(
+            &instruction,
+            &account_infos,
+            signers_seeds,
+        )
+    } else {
+        let account_infos = [
+            dex_prog_ai.clone(), // Have to add account of the program id
+            spot_market_ai.clone(),
+            open_orders_ai.clone(),
+            dex_request_queue_ai.clone(),
+            dex_event_queue_ai.clone(),
+            bids_ai.clone(),
+            asks_ai.clone(),
+            vault_ai.clone(),
+            signer_ai.clone(),
+            dex_base_ai.clone(),
+            dex_quote_ai.clone(),
+            token_prog_ai.clone(),
+            signer_ai.clone(),
+        ];
+        solana_program::program::invoke_signed_unchecked(
+            &instruction,
+            &account_infos,
+            signers_seeds,
+        )
+    }
 }
 
-fn init_root_bank(
-    program_id: &Pubkey,
-    lyrae_group: &LyraeGroup,
-    mint_ai: &AccountInfo,
-    vault_ai: &AccountInfo,
-    root_bank_ai: &AccountInfo,
-    node_bank_ai: &AccountInfo,
-    rent: &Rent,
-
-    optimal_util: I80F48,
-    optimal_rate: I80F48,
-    max_rate: I80F48,
-) -> LyraeResult<RootBank> {
-    let vault = Account::unpack(&vault_ai.try_borrow_data()?)?;
-    check!(vault.is_initialized(), LyraeErrorCode::InvalidVault)?;
-    check!(vault.delegate.is_none(), LyraeErrorCode::InvalidVault)?;
-    check!(vault.close_authority.is_none(), LyraeErrorCode::InvalidVault)?;
-    check_eq!(vault.owner, lyrae_group.signer_key, LyraeErrorCode::InvalidVault)?;
-    check_eq!(&vault.mint, mint_ai.key, LyraeErrorCode::InvalidVault)?;
-    check_eq!(vault_ai.owner, &spl_token::id(), LyraeErrorCode::InvalidVault)?;
-
-    let _node_bank = NodeBank::load_and_init(&node_bank_ai, &program_id, &vault_ai, rent)?;
-    let root_bank = RootBank::load_and_init(
-        &root_bank_ai,
-        &program_id,
-        node_bank_ai,
-        rent,
-        optimal_util,
-        optimal_rate,
-        max_rate,
-    )?;
-
-    Ok(*root_bank)
-}
-
-fn invoke_settle_funds<'a>(
-    dex_prog_ai: &AccountInfo<'a>,
-    spot_market_ai: &AccountInfo<'a>,
+fn invoke_init_open_orders<'a>(
+    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
     open_orders_ai: &AccountInfo<'a>,
     signer_ai: &AccountInfo<'a>,
-    dex_base_ai: &AccountInfo<'a>,
-    dex_quote_ai: &AccountInfo<'a>,
-    base_vault_ai: &AccountInfo<'a>,
-    quote_vault_ai: &AccountInfo<'a>,
-    dex_signer_ai: &AccountInfo<'a>,
-    token_prog_ai: &AccountInfo<'a>,
+    spot_market_ai: &AccountInfo<'a>,
+    rent_ai: &AccountInfo<'a>,
     signers_seeds: &[&[&[u8]]],
 ) -> ProgramResult {
-    let data = serum_dex::instruction::MarketInstruction::SettleFunds.pack();
+    let data = serum_dex::instruction::MarketInstruction::InitOpenOrders.pack();
     let instruction = Instruction {
         program_id: *dex_prog_ai.key,
         data,
         accounts: vec![
-            AccountMeta::new(*spot_market_ai.key, false),
             AccountMeta::new(*open_orders_ai.key, false),
             AccountMeta::new_readonly(*signer_ai.key, true),
-            AccountMeta::new(*dex_base_ai.key, false),
-            AccountMeta::new(*dex_quote_ai.key, false),
-            AccountMeta::new(*base_vault_ai.key, false),
-            AccountMeta::new(*quote_vault_ai.key, false),
-            AccountMeta::new_readonly(*dex_signer_ai.key, false),
-            AccountMeta::new_readonly(*token_prog_ai.key, false),
-            AccountMeta::new(*quote_vault_ai.key, false),
+            AccountMeta::new_readonly(*spot_market_ai.key, false),
+            AccountMeta::new_readonly(*rent_ai.key, false),
         ],
     };
 
     let account_infos = [
         dex_prog_ai.clone(),
-        spot_market_ai.clone(),
         open_orders_ai.clone(),
         signer_ai.clone(),
-        dex_base_ai.clone(),
-        dex_quote_ai.clone(),
-        base_vault_ai.clone(),
-        quote_vault_ai.clone(),
-        dex_signer_ai.clone(),
-        token_prog_ai.clone(),
-        quote_vault_ai.clone(),
+        spot_market_ai.clone(),
+        rent_ai.clone(),
     ];
-    solana_program::program::invoke_signed_unchecked(&instruction, &account_infos, signers_seeds)
+    solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
 }
 
-fn invoke_cancel_order<'a>(
-    dex_prog_ai: &AccountInfo<'a>,
-    spot_market_ai: &AccountInfo<'a>,
-    bids_ai: &AccountInfo<'a>,
-    asks_ai: &AccountInfo<'a>,
+fn invoke_close_open_orders<'a>(
+    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
     open_orders_ai: &AccountInfo<'a>,
     signer_ai: &AccountInfo<'a>,
-    dex_event_queue_ai: &AccountInfo<'a>,
-    data: Vec<u8>,
+    destination_ai: &AccountInfo<'a>,
+    spot_market_ai: &AccountInfo<'a>,
     signers_seeds: &[&[&[u8]]],
 ) -> ProgramResult {
+    let data = serum_dex::instruction::MarketInstruction::CloseOpenOrders.pack();
+
     let instruction = Instruction {
         program_id: *dex_prog_ai.key,
         data,
         accounts: vec![
-            AccountMeta::new(*spot_market_ai.key, false),
-            AccountMeta::new(*bids_ai.key, false),
-            AccountMeta::new(*asks_ai.key, false),
             AccountMeta::new(*open_orders_ai.key, false),
             AccountMeta::new_readonly(*signer_ai.key, true),
-            AccountMeta::new(*dex_event_queue_ai.key, false),
+            AccountMeta::new(*destination_ai.key, false),
+            AccountMeta::new_readonly(*spot_market_ai.key, false),
         ],
     };
 
     let account_infos = [
         dex_prog_ai.clone(),
-        spot_market_ai.clone(),
-        bids_ai.clone(),
-        asks_ai.clone(),
         open_orders_ai.clone(),
         signer_ai.clone(),
-        dex_event_queue_ai.clone(),
+        destination_ai.clone(),
+        spot_market_ai.clone(),
     ];
     solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
 }
 
-fn invoke_transfer<'a>(
-    token_prog_ai: &AccountInfo<'a>,
-    source_ai: &AccountInfo<'a>,
-    dest_ai: &AccountInfo<'a>,
-    authority_ai: &AccountInfo<'a>,
-    signers_seeds: &[&[&[u8]]],
+/*
+TODO test order types
+ */
+fn invoke_transfer_lamports<'a>(
+    src_ai: &AccountInfo<'a>,
+    dst_ai: &AccountInfo<'a>,
+    system_prog_ai: &AccountInfo<'a>,
     quantity: u64,
+    signers_seeds: &[&[&[u8]]],
 ) -> ProgramResult {
-    let transfer_instruction = spl_token::instruction::transfer(
-        &spl_token::ID,
-        source_ai.key,
-        dest_ai.key,
-        authority_ai.key,
-        &[],
-        quantity,
-    )?;
-    let accs = [
-        token_prog_ai.clone(), // TODO check if passing in program_id is necessary
-        source_ai.clone(),
-        dest_ai.clone(),
-        authority_ai.clone(),
-    ];
-
-    solana_program::program::invoke_signed(&transfer_instruction, &accs, signers_seeds)
+    solana_program::program::invoke_signed(
+        &solana_program::system_instruction::transfer(src_ai.key, dst_ai.key, quantity),
+        &[src_ai.clone(), dst_ai.clone(), system_prog_ai.clone()],
+        signers_seeds,
+    )
 }
 
-#[inline(never)]
-fn read_oracle(
-    lyrae_group: &LyraeGroup,
-    token_index: usize,
-    oracle_ai: &AccountInfo,
-) -> LyraeResult<I80F48> {
-    let quote_decimals = lyrae_group.tokens[QUOTE_INDEX].decimals as i32;
-    let base_decimals = lyrae_group.tokens[token_index].decimals as i32;
-
-    let oracle_type = determine_oracle_type(oracle_ai);
-
-    let price = match oracle_type {
-        OracleType::Pyth => {
-            let price_account = Price::get_price(oracle_ai)?;
-            let value = I80F48::from_num(price_account.agg.price);
-
-            // Filter out bad prices on mainnet
-            #[cfg(not(feature = "devnet"))]
-            let conf = I80F48::from_num(price_account.agg.conf).checked_div(value).unwrap();
-
-            #[cfg(not(feature = "devnet"))]
-            if price_account.agg.status != PriceStatus::Trading {
-                msg!("Pyth status invalid: {}", price_account.agg.status as u8);
-                return Err(throw_err!(LyraeErrorCode::InvalidOraclePrice));
-            } else if conf > PYTH_CONF_FILTER {
-                msg!(
-                    "Pyth conf interval too high; oracle index: {} value: {} conf: {}",
-                    token_index,
-                    value.to_num::<f64>(),
-                    conf.to_num::<f64>()
-                );
-                return Err(throw_err!(LyraeErrorCode::InvalidOraclePrice));
-            }
+/// Sort the optional trailing accounts of a perp order, which may come in any order: the
+/// referrer's LyraeAccount and the group's MsrmFeeTiers. Returns the referrer and the taker fee
+/// discount, in bps, of a taker with `msrm_amount`.
+fn get_perp_order_opt_accounts<'a, 'b>(
+    program_id: &Pubkey,
+    lyrae_group_pk: &Pubkey,
+    opt_ais: &'a [AccountInfo<'b>],
+    msrm_amount: u64,
+) -> LyraeResult<(Option<&'a AccountInfo<'b>>, u16)> {
+    let mut referrer_lyrae_account_ai = None;
+    let mut taker_fee_discount_bps = 0;
+    for ai in opt_ais.iter().take(2) {
+        if ai.data_len() == size_of::<MsrmFeeTiers>() {
+            let msrm_fee_tiers = MsrmFeeTiers::load_checked(ai, program_id, lyrae_group_pk)?;
+            taker_fee_discount_bps = msrm_fee_tiers.get_taker_fee_discount_bps(msrm_amount);
+        } else if referrer_lyrae_account_ai.is_none() {
+            referrer_lyrae_account_ai = Some(ai);
+        }
+    }
+    Ok((referrer_lyrae_account_ai, taker_fee_discount_bps))
+}
 
-            let decimals = quote_decimals
-                .checked_add(price_account.expo)
-                .unwrap()
-                .checked_sub(base_decimals)
-                .unwrap();
+fn seed_and_create_pda<'a>(
+    program_id: &Pubkey,
+    funder: &AccountInfo<'a>,
+    rent: &Rent,
+    space: usize,
+    owner: &Pubkey,
+    system_program: &AccountInfo<'a>,
+    pda_account: &AccountInfo<'a>,
+    seeds: &[&[u8]],
+    funder_seeds: &[&[u8]],
+) -> LyraeResult {
+    let (pda_address, bump) = Pubkey::find_program_address(seeds, program_id);
+    check!(&pda_address == pda_account.key, LyraeErrorCode::InvalidAccount)?;
+    create_pda_account(
+        funder,
+        rent,
+        space,
+        owner,
+        system_program,
+        pda_account,
+        &[seeds, &[&[bump]]].concat(),
+        funder_seeds,
+    )?;
+    Ok(())
+}
+fn create_pda_account<'a>(
+    funder: &AccountInfo<'a>,
+    rent: &Rent,
+    space: usize,
+    owner: &Pubkey,
+    system_program: &AccountInfo<'a>,
+    new_pda_account: &AccountInfo<'a>,
+    new_pda_signer_seeds: &[&[u8]],
+    funder_seeds: &[&[u8]],
+) -> ProgramResult {
+    if new_pda_account.lamports() > 0 {
+        let required_lamports =
+            rent.minimum_balance(space).max(1).saturating_sub(new_pda_account.lamports());
 
-            let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-            if decimals < 0 {
-                value.checked_div(decimal_adj).unwrap()
-            } else {
-                value.checked_mul(decimal_adj).unwrap()
-            }
-        }
-        OracleType::Stub => {
-            let oracle = StubOracle::load(oracle_ai)?;
-            I80F48::from_num(oracle.price)
+        let transfer_seeds = if funder_seeds.is_empty() { vec![] } else { vec![funder_seeds] };
+        if required_lamports > 0 {
+            invoke_transfer_lamports(
+                funder,
+                new_pda_account,
+                system_program,
+                required_lamports,
+                transfer_seeds.as_slice(),
+            )?;
         }
-        OracleType::Switchboard => {
-            let result =
-                FastRoundResultAccountData::deserialize(&oracle_ai.try_borrow_data()?).unwrap();
-            let value = I80F48::from_num(result.result.result);
 
-            let decimals = quote_decimals.checked_sub(base_decimals).unwrap();
-            if decimals < 0 {
-                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                value.checked_div(decimal_adj).unwrap()
-            } else if decimals > 0 {
-                let decimal_adj = I80F48::from_num(10u64.pow(decimals.abs() as u32));
-                value.checked_mul(decimal_adj).unwrap()
-            } else {
-                value
-            }
-        }
-        OracleType::Unknown => return Err(throw_err!(LyraeErrorCode::InvalidOracleType)),
-    };
-    Ok(price)
+        solana_program::program::invoke_signed(
+            &solana_program::system_instruction::allocate(new_pda_account.key, space as u64),
+            &[new_pda_account.clone(), system_program.clone()],
+            &[new_pda_signer_seeds],
+        )?;
+
+        solana_program::program::invoke_signed(
+            &solana_program::system_instruction::assign(new_pda_account.key, owner),
+            &[new_pda_account.clone(), system_program.clone()],
+            &[new_pda_signer_seeds],
+        )
+    } else {
+        let all_signer_seeds = if funder_seeds.is_empty() {
+            vec![new_pda_signer_seeds]
+        } else {
+            vec![funder_seeds, new_pda_signer_seeds]
+        };
+        solana_program::program::invoke_signed_unchecked(
+            &solana_program::system_instruction::create_account(
+                funder.key,
+                new_pda_account.key,
+                rent.minimum_balance(space).max(1),
+                space as u64,
+                owner,
+            ),
+            &[funder.clone(), new_pda_account.clone(), system_program.clone()],
+            all_signer_seeds.as_slice(),
+        )
+    }
 }
 
-/// Transfer token deposits/borrows between two LyraeAccounts
-/// `native_quantity` is subtracted from src and added to dst
-/// Make sure to credit deposits first in case Node bank is fully utilized
-fn transfer_token_internal(
-    root_bank_cache: &RootBankCache,
-    node_bank: &mut NodeBank,
-    src: &mut LyraeAccount,
-    dst: &mut LyraeAccount,
-    src_pk: &Pubkey,
-    dst_pk: &Pubkey,
-    token_index: usize,
-    native_quantity: I80F48,
+/// Transfer lamports from a src account owned by the currently executing program id
+fn program_transfer_lamports(
+    src_ai: &AccountInfo,
+    dst_ai: &AccountInfo,
+    quantity: u64,
 ) -> LyraeResult<()> {
-    if native_quantity.is_positive() {
-        // increase dst first before decreasing from src
-        checked_change_net(root_bank_cache, node_bank, dst, dst_pk, token_index, native_quantity)?;
-        checked_change_net(root_bank_cache, node_bank, src, src_pk, token_index, -native_quantity)?;
-    } else if native_quantity.is_negative() {
-        // increase src first before decreasing from dst
-        checked_change_net(root_bank_cache, node_bank, src, src_pk, token_index, -native_quantity)?;
-        checked_change_net(root_bank_cache, node_bank, dst, dst_pk, token_index, native_quantity)?;
-    }
+    let src_lamports = src_ai.lamports().checked_sub(quantity).ok_or(math_err!())?;
+    **src_ai.lamports.borrow_mut() = src_lamports;
+
+    let dst_lamports = dst_ai.lamports().checked_add(quantity).ok_or(math_err!())?;
+    **dst_ai.lamports.borrow_mut() = dst_lamports;
     Ok(())
 }
 
-fn checked_change_net(
-    root_bank_cache: &RootBankCache,
-    node_bank: &mut NodeBank,
+/// Cancel a perp order of the LyraeAccount and apply the liquidity mining incentives it earned
+/// while resting on the book
+fn cancel_perp_order_with_incentives(
+    lyrae_group_pk: &Pubkey,
     lyrae_account: &mut LyraeAccount,
     lyrae_account_pk: &Pubkey,
-    token_index: usize,
-    native_quantity: I80F48,
+    perp_market: &mut PerpMarket,
+    book: &mut Book,
+    market_index: usize,
+    order_id: i128,
+    side: Side,
 ) -> LyraeResult<()> {
-    if native_quantity.is_negative() {
-        checked_sub_net(root_bank_cache, node_bank, lyrae_account, token_index, -native_quantity)?;
-    } else if native_quantity.is_positive() {
-        checked_add_net(root_bank_cache, node_bank, lyrae_account, token_index, native_quantity)?;
+    let best_final = if perp_market.meta_data.version == 0 {
+        match side {
+            Side::Bid => book.get_best_bid_price().unwrap(),
+            Side::Ask => book.get_best_ask_price().unwrap(),
+        }
+    } else {
+        let max_depth: i64 = perp_market.liquidity_mining_info.max_depth_bps.to_num();
+        match side {
+            Side::Bid => book.get_bids_size_above_order(order_id, max_depth),
+            Side::Ask => book.get_asks_size_below_order(order_id, max_depth),
+        }
+    };
+
+    let order = book.cancel_order(order_id, side)?;
+    check_eq!(&order.owner, lyrae_account_pk, LyraeErrorCode::InvalidOrderId)?;
+    lyrae_account.remove_order(order.owner_slot as usize, order.quantity)?;
+
+    // If order version doesn't match the perp market version, no incentives
+    if order.version != perp_market.meta_data.version {
+        return Ok(());
     }
-    lyrae_emit!(TokenBalanceLog {
-        lyrae_group: lyrae_account.lyrae_group,
+
+    let lyr_start = lyrae_account.perp_accounts[market_index].lyr_accrued;
+    if perp_market.meta_data.version == 0 {
+        lyrae_account.perp_accounts[market_index].apply_price_incentives(
+            perp_market,
+            side,
+            order.price(),
+            order.best_initial,
+            best_final,
+            order.timestamp,
+            Clock::get()?.unix_timestamp as u64,
+            order.quantity,
+        )?;
+    } else {
+        lyrae_account.perp_accounts[market_index].apply_size_incentives(
+            perp_market,
+            order.best_initial,
+            best_final,
+            order.timestamp,
+            Clock::get()?.unix_timestamp as u64,
+            order.quantity,
+        )?;
+    }
+
+    lyrae_emit!(LyrAccrualLog {
+        lyrae_group: *lyrae_group_pk,
         lyrae_account: *lyrae_account_pk,
-        token_index: token_index as u64,
-        deposit: lyrae_account.deposits[token_index].to_bits(),
-        borrow: lyrae_account.borrows[token_index].to_bits()
+        market_index: market_index as u64,
+        lyr_accrual: lyrae_account.perp_accounts[market_index].lyr_accrued - lyr_start
     });
 
-    Ok(()) // This is an optimization to prevent unnecessary I80F48 calculations
+    Ok(())
 }
 
-/// If there are borrows, pay down borrows first then increase deposits
-/// WARNING: won't work if native_quantity is less than zero
-fn checked_add_net(
-    root_bank_cache: &RootBankCache,
-    node_bank: &mut NodeBank,
+/// Place the PerpTrigger order at `order_index` whose trigger condition has been checked, then
+/// deactivate it and pay its fee to the agent. If the account fails the health checks all its
+/// advanced orders are cancelled instead.
+fn execute_triggered_perp_order<'a, T: Deref<Target = serum_dex::state::OpenOrders>>(
+    program_id: &Pubkey,
+    lyrae_group: &LyraeGroup,
+    lyrae_group_pk: &Pubkey,
+    lyrae_cache: &LyraeCache,
+    perp_market: &mut PerpMarket,
+    book: &mut Book,
+    event_queue: &mut EventQueue,
     lyrae_account: &mut LyraeAccount,
-    token_index: usize,
-    mut native_quantity: I80F48,
+    lyrae_account_pk: &Pubkey,
+    open_orders_accounts: &[Option<T>],
+    active_assets: UserActiveAssets,
+    advanced_orders_ai: &AccountInfo<'a>,
+    advanced_orders: &mut AdvancedOrders,
+    agent_ai: &AccountInfo<'a>,
+    order_index: usize,
+    now_ts: u64,
 ) -> LyraeResult<()> {
-    if lyrae_account.borrows[token_index].is_positive() {
-        let native_borrows = lyrae_account.get_native_borrow(root_bank_cache, token_index)?;
+    let order: &mut PerpTriggerOrder = cast_mut(&mut advanced_orders.orders[order_index]);
+    let market_index = order.market_index as usize;
 
-        if native_quantity < native_borrows {
-            return checked_sub_borrow(
-                node_bank,
-                lyrae_account,
-                token_index,
-                native_quantity / root_bank_cache.borrow_index,
-            );
-        } else {
-            let borrows = lyrae_account.borrows[token_index];
-            checked_sub_borrow(node_bank, lyrae_account, token_index, borrows)?;
-            native_quantity -= native_borrows;
-        }
+    let mut health_cache = HealthCache::new(active_assets);
+    health_cache.init_vals_with_orders_vec(
+        lyrae_group,
+        lyrae_cache,
+        lyrae_account,
+        open_orders_accounts,
+    )?;
+    let (pre_health, being_liquidated) = health_cache
+        .get_pre_health_and_update_being_liquidated(lyrae_group, lyrae_account);
+    if being_liquidated {
+        msg!("Failed to trigger order; LyraeAccount is being liquidated.");
+        return cancel_all_advanced_orders(advanced_orders_ai, advanced_orders, agent_ai);
     }
 
-    checked_add_deposit(
-        node_bank,
-        lyrae_account,
-        token_index,
-        native_quantity / root_bank_cache.deposit_index,
-    )
-}
+    // This means health must only go up
+    let health_up_only = pre_health < ZERO_I80F48;
 
-/// If there are deposits, draw down deposits first then increase borrows
-/// WARNING: won't work if native_quantity is less than zero
-fn checked_sub_net(
-    root_bank_cache: &RootBankCache,
-    node_bank: &mut NodeBank,
-    lyrae_account: &mut LyraeAccount,
-    token_index: usize,
-    mut native_quantity: I80F48,
-) -> LyraeResult<()> {
-    if lyrae_account.deposits[token_index].is_positive() {
-        let native_deposits = lyrae_account.get_native_deposit(root_bank_cache, token_index)?;
+    // If reduce_only, position must only go down
+    let quantity = if order.reduce_only {
+        let base_pos =
+            lyrae_account.get_complete_base_pos(market_index, event_queue, lyrae_account_pk)?;
 
-        if native_quantity < native_deposits {
-            return checked_sub_deposit(
-                node_bank,
+        lyrae_account.get_reduce_only_quantity(market_index, order.side, base_pos, order.quantity)
+    } else {
+        order.quantity
+    };
+
+    if quantity != 0 {
+        let (taker_base, taker_quote, bids_quantity, asks_quantity) = match order.side {
+            Side::Bid => book.sim_new_bid(
+                perp_market,
+                &lyrae_group.perp_markets[market_index],
+                lyrae_cache.get_price(market_index),
+                order.price,
+                quantity,
+                order.order_type,
+            )?,
+            Side::Ask => book.sim_new_ask(
+                perp_market,
+                &lyrae_group.perp_markets[market_index],
+                lyrae_cache.get_price(market_index),
+                order.price,
+                quantity,
+                order.order_type,
+            )?,
+        };
+
+        // simulate the effect on health
+        let sim_post_health = health_cache.get_health_after_sim_perp(
+            lyrae_group,
+            lyrae_cache,
+            lyrae_account,
+            market_index,
+            HealthType::Init,
+            taker_base,
+            taker_quote,
+            bids_quantity,
+            asks_quantity,
+        )?;
+
+        if sim_post_health >= ZERO_I80F48 || (health_up_only && sim_post_health >= pre_health) {
+            let (taker_base, taker_quote, bids_quantity, asks_quantity) = {
+                let pa = &lyrae_account.perp_accounts[market_index];
+                (
+                    pa.taker_base + taker_base,
+                    pa.taker_quote + taker_quote,
+                    pa.bids_quantity.checked_add(bids_quantity).unwrap(),
+                    pa.asks_quantity.checked_add(asks_quantity).unwrap(),
+                )
+            };
+
+            book.new_order(
+                program_id,
+                lyrae_group,
+                lyrae_group_pk,
+                lyrae_cache,
+                event_queue,
+                perp_market,
+                lyrae_cache.get_price(market_index),
                 lyrae_account,
-                token_index,
-                native_quantity / root_bank_cache.deposit_index,
-            );
+                lyrae_account_pk,
+                market_index,
+                order.side,
+                order.price,
+                quantity,
+                order.order_type,
+                order.client_order_id,
+                now_ts,
+                None,
+                0,
+            )?;
+
+            // TODO OPT - unnecessary, remove after testing
+            health_cache.update_perp_val(lyrae_group, lyrae_cache, lyrae_account, market_index)?;
+            let post_health = health_cache.get_health(lyrae_group, HealthType::Init);
+            let pa = &lyrae_account.perp_accounts[market_index];
+            check!(
+                sim_post_health == post_health
+                    && taker_base == pa.taker_base
+                    && taker_quote == pa.taker_quote
+                    && bids_quantity == pa.bids_quantity
+                    && asks_quantity == pa.asks_quantity,
+                LyraeErrorCode::MathError
+            )?;
         } else {
-            let deposits = lyrae_account.deposits[token_index];
-            checked_sub_deposit(node_bank, lyrae_account, token_index, deposits)?;
-            native_quantity -= native_deposits;
+            // normally this would be an InsufficientFunds error but we want to remove the AO and persist changes
+            msg!("Failed to place perp order due to insufficient funds")
         }
     }
 
-    checked_add_borrow(
-        node_bank,
-        lyrae_account,
-        token_index,
-        native_quantity / root_bank_cache.borrow_index,
-    )?;
-
-    check!(
-        node_bank.has_valid_deposits_borrows(root_bank_cache),
-        LyraeErrorCode::InsufficientLiquidity
-    )
+    order.is_active = false;
+    program_transfer_lamports(advanced_orders_ai, agent_ai, ADVANCED_ORDER_FEE)
 }
 
-/// TODO - although these values are I8048, they must never be less than zero
-fn checked_add_deposit(
-    node_bank: &mut NodeBank,
-    lyrae_account: &mut LyraeAccount,
-    token_index: usize,
-    quantity: I80F48,
+fn cancel_all_advanced_orders<'a>(
+    advanced_orders_ai: &AccountInfo<'a>,
+    advanced_orders: &mut AdvancedOrders,
+    agent_ai: &AccountInfo<'a>,
 ) -> LyraeResult<()> {
-    lyrae_account.checked_add_deposit(token_index, quantity)?;
-    node_bank.checked_add_deposit(quantity)
+    let mut total_fee = 0u64;
+    for i in 0..MAX_ADVANCED_ORDERS {
+        if advanced_orders.orders[i].is_active {
+            advanced_orders.orders[i].is_active = false;
+            total_fee += ADVANCED_ORDER_FEE;
+        }
+    }
+    program_transfer_lamports(advanced_orders_ai, agent_ai, total_fee)
 }
 
-fn checked_sub_deposit(
-    node_bank: &mut NodeBank,
-    lyrae_account: &mut LyraeAccount,
-    token_index: usize,
-    quantity: I80F48,
-) -> LyraeResult<()> {
-    lyrae_account.checked_sub_deposit(token_index, quantity)?;
-    node_bank.checked_sub_deposit(quantity)
+// Returns asset_weight and liab_weight
+pub fn get_leverage_weights(leverage: I80F48) -> (I80F48, I80F48) {
+    (
+        (leverage - ONE_I80F48).checked_div(leverage).unwrap(),
+        (leverage + ONE_I80F48).checked_div(leverage).unwrap(),
+    )
 }
 
-fn checked_add_borrow(
-    node_bank: &mut NodeBank,
-    lyrae_account: &mut LyraeAccount,
-    token_index: usize,
-    quantity: I80F48,
-) -> LyraeResult<()> {
-    lyrae_account.checked_add_borrow(token_index, quantity)?;
-    node_bank.checked_add_borrow(quantity)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_syscalls;
+    use bytemuck::{bytes_of_mut, Zeroable};
+
+    fn new_lyrae_group(admin: &Pubkey) -> Box<LyraeGroup> {
+        let mut lyrae_group = Box::new(LyraeGroup::zeroed());
+        lyrae_group.meta_data = MetaData::new(DataType::LyraeGroup, 0, true);
+        lyrae_group.admin = *admin;
+        lyrae_group
+    }
+
+    /// A writable, non-signer account; set the other flags on the result as needed
+    fn new_account_info<'a>(
+        key: &'a Pubkey,
+        lamports: &'a mut u64,
+        data: &'a mut [u8],
+        owner: &'a Pubkey,
+    ) -> AccountInfo<'a> {
+        AccountInfo::new(key, false, true, lamports, data, owner, false, 0)
+    }
+
+    #[test]
+    fn set_dex_program_id_validates_accounts() {
+        let program_id = Pubkey::new_unique();
+        let (group_pk, admin_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (old_dex_pk, new_dex_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (spot_market_pk, signer_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&admin_pk);
+        lyrae_group.dex_program_id = old_dex_pk;
+        lyrae_group.signer_key = signer_pk;
+        lyrae_group.num_oracles = 1;
+        lyrae_group.spot_markets[0].spot_market = spot_market_pk;
+
+        let (mut group_lamports, mut admin_lamports, mut dex_lamports) = (0, 0, 0);
+        let (mut spot_market_lamports, mut signer_lamports) = (0, 0);
+        let (mut admin_data, mut dex_data) = ([0u8; 0], [0u8; 0]);
+        let (mut spot_market_data, mut signer_data) = ([0u8; 0], [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let mut admin_ai =
+            new_account_info(&admin_pk, &mut admin_lamports, &mut admin_data, &program_id);
+        admin_ai.is_signer = true;
+        let mut dex_ai =
+            new_account_info(&new_dex_pk, &mut dex_lamports, &mut dex_data, &program_id);
+        dex_ai.executable = true;
+        // still listed under the old dex
+        let mut spot_market_ai = new_account_info(
+            &spot_market_pk,
+            &mut spot_market_lamports,
+            &mut spot_market_data,
+            &old_dex_pk,
+        );
+        let set_dex_program_id =
+            |accounts: &[AccountInfo]| Processor::set_dex_program_id(&program_id, accounts);
+
+        let mut unsigned_admin_ai = admin_ai.clone();
+        unsigned_admin_ai.is_signer = false;
+        assert!(set_dex_program_id(&[
+            group_ai.clone(),
+            unsigned_admin_ai,
+            dex_ai.clone(),
+            spot_market_ai.clone()
+        ])
+        .is_err());
+
+        let mut not_admin_ai = admin_ai.clone();
+        not_admin_ai.key = &new_dex_pk;
+        assert!(set_dex_program_id(&[
+            group_ai.clone(),
+            not_admin_ai,
+            dex_ai.clone(),
+            spot_market_ai.clone()
+        ])
+        .is_err());
+
+        let mut not_executable_ai = dex_ai.clone();
+        not_executable_ai.executable = false;
+        assert!(set_dex_program_id(&[
+            group_ai.clone(),
+            admin_ai.clone(),
+            not_executable_ai,
+            spot_market_ai.clone()
+        ])
+        .is_err());
+
+        let mut same_dex_ai = dex_ai.clone();
+        same_dex_ai.key = &old_dex_pk;
+        assert!(set_dex_program_id(&[
+            group_ai.clone(),
+            admin_ai.clone(),
+            same_dex_ai,
+            spot_market_ai.clone()
+        ])
+        .is_err());
+
+        // the listed spot market must be passed and already be under the new dex
+        assert_eq!(
+            error_code(set_dex_program_id(&[group_ai.clone(), admin_ai.clone(), dex_ai.clone()])),
+            Some(LyraeErrorCode::InvalidAccount)
+        );
+        assert_eq!(
+            error_code(set_dex_program_id(&[
+                group_ai.clone(),
+                admin_ai.clone(),
+                dex_ai.clone(),
+                spot_market_ai.clone()
+            ])),
+            Some(LyraeErrorCode::InvalidMarket)
+        );
+        spot_market_ai.owner = &new_dex_pk;
+        assert!(set_dex_program_id(&[
+            group_ai.clone(),
+            admin_ai.clone(),
+            dex_ai.clone(),
+            spot_market_ai.clone()
+        ])
+        .is_ok());
+        assert_eq!(
+            LyraeGroup::load_checked(&group_ai, &program_id).unwrap().dex_program_id,
+            new_dex_pk
+        );
+
+        // spot instructions now take the new dex and reject the old one
+        let account_pk = Pubkey::new_unique();
+        let (open_orders_pk, _) = Pubkey::find_program_address(
+            &[account_pk.as_ref(), &0usize.to_le_bytes(), b"OpenOrders"],
+            &program_id,
+        );
+        let mut lyrae_account = new_lyrae_account(&admin_pk);
+        lyrae_account.lyrae_group = group_pk;
+        let (mut account_lamports, mut open_orders_lamports) = (0, 0);
+        let mut open_orders_data = [0u8; 0];
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let open_orders_ai = new_account_info(
+            &open_orders_pk,
+            &mut open_orders_lamports,
+            &mut open_orders_data,
+            &new_dex_pk,
+        );
+        let signer_ai =
+            new_account_info(&signer_pk, &mut signer_lamports, &mut signer_data, &program_id);
+        let mut system_prog_ai = signer_ai.clone();
+        system_prog_ai.key = &solana_program::system_program::ID;
+        let create_spot_open_orders = |dex_ai| {
+            let accounts = [
+                group_ai.clone(),
+                account_ai.clone(),
+                admin_ai.clone(),
+                dex_ai,
+                open_orders_ai.clone(),
+                spot_market_ai.clone(),
+                signer_ai.clone(),
+                system_prog_ai.clone(),
+            ];
+            error_code(Processor::create_spot_open_orders(&program_id, &accounts))
+        };
+        let mut old_dex_ai = dex_ai.clone();
+        old_dex_ai.key = &old_dex_pk;
+        assert_eq!(create_spot_open_orders(old_dex_ai), Some(LyraeErrorCode::InvalidProgramId));
+        // with the new dex every check passes; without syscall stubs only getting Rent fails
+        assert_eq!(create_spot_open_orders(dex_ai), None);
+    }
+
+    fn error_code(result: LyraeResult) -> Option<LyraeErrorCode> {
+        match result {
+            Err(LyraeError::LyraeErrorCode { lyrae_error_code, .. }) => Some(lyrae_error_code),
+            _ => None,
+        }
+    }
+
+    fn new_lyrae_account(owner: &Pubkey) -> Box<LyraeAccount> {
+        let mut lyrae_account = Box::new(LyraeAccount::zeroed());
+        lyrae_account.meta_data = MetaData::new(DataType::LyraeAccount, 0, true);
+        lyrae_account.owner = *owner;
+        lyrae_account
+    }
+
+    /// Place and cancel a perp order as `signer`. The perp market is not initialized, so
+    /// anything that gets past the account checks fails on the market
+    fn place_and_cancel_perp_order(
+        mut lyrae_group: Box<LyraeGroup>,
+        mut lyrae_account: Box<LyraeAccount>,
+        signer_pk: &Pubkey,
+    ) -> (LyraeResult, LyraeResult) {
+        let program_id = Pubkey::new_unique();
+        let (group_pk, account_pk, perp_market_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        lyrae_account.lyrae_group = group_pk;
+        let mut perp_market = Box::new(PerpMarket::zeroed());
+
+        let (mut group_lamports, mut account_lamports, mut signer_lamports) = (0, 0, 0);
+        let (mut perp_market_lamports, mut signer_data) = (0, [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let mut signer_ai =
+            new_account_info(signer_pk, &mut signer_lamports, &mut signer_data, &program_id);
+        signer_ai.is_signer = true;
+        let perp_market_ai = new_account_info(
+            &perp_market_pk,
+            &mut perp_market_lamports,
+            bytes_of_mut(&mut *perp_market),
+            &program_id,
+        );
+        let mut accounts = vec![group_ai, account_ai, signer_ai];
+        accounts.resize(8 + MAX_PAIRS, perp_market_ai);
+
+        let placed = Processor::place_perp_order(
+            &program_id,
+            &accounts,
+            Side::Bid,
+            1,
+            1,
+            0,
+            OrderType::Limit,
+            false,
+        );
+        let cancelled = Processor::cancel_perp_order(&program_id, &accounts[..6], 0);
+        (placed, cancelled)
+    }
+
+    #[test]
+    fn cancel_only_delegate_cannot_place_perp_orders() {
+        let (owner_pk, delegate_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.delegate = delegate_pk;
+        lyrae_account.set_delegate_permissions(DELEGATE_CANCEL);
+
+        let lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        let (placed, cancelled) =
+            place_and_cancel_perp_order(lyrae_group, lyrae_account, &delegate_pk);
+        assert_eq!(error_code(placed), Some(LyraeErrorCode::InvalidOwner));
+        // cancel_perp_order only gets to the perp market with the delegate's permission
+        assert!(cancelled.is_err());
+        assert_ne!(error_code(cancelled), Some(LyraeErrorCode::InvalidOwner));
+    }
+
+    #[test]
+    fn paused_group_blocks_place_but_allows_cancel() {
+        let owner_pk = Pubkey::new_unique();
+        let lyrae_account = new_lyrae_account(&owner_pk);
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.set_pause_flag(GROUP_PAUSED, true);
+        lyrae_group.set_pause_flag(GROUP_HALTED, true);
+        lyrae_group.set_pause_flag(GROUP_HALTED, false);
+        assert!(lyrae_group.is_paused() && !lyrae_group.is_halted());
+
+        let (placed, cancelled) =
+            place_and_cancel_perp_order(lyrae_group, lyrae_account, &owner_pk);
+        assert_eq!(error_code(placed), Some(LyraeErrorCode::GroupPaused));
+        assert!(cancelled.is_err());
+        assert_ne!(error_code(cancelled), Some(LyraeErrorCode::GroupPaused));
+    }
+
+    #[test]
+    fn transfer_perp_position_rejects_accounts_of_different_owners() {
+        let program_id = Pubkey::new_unique();
+        let (group_pk, src_pk, dst_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let owner_pk = Pubkey::new_unique();
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        let mut src_account = new_lyrae_account(&owner_pk);
+        let mut dst_account = new_lyrae_account(&Pubkey::new_unique());
+        // the owner of the source is the delegate of the destination
+        dst_account.delegate = owner_pk;
+        src_account.lyrae_group = group_pk;
+        dst_account.lyrae_group = group_pk;
+
+        let (mut group_lamports, mut src_lamports, mut dst_lamports) = (0, 0, 0);
+        let (mut owner_lamports, mut owner_data) = (0, [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let src_ai = new_account_info(
+            &src_pk,
+            &mut src_lamports,
+            bytes_of_mut(&mut *src_account),
+            &program_id,
+        );
+        let dst_ai = new_account_info(
+            &dst_pk,
+            &mut dst_lamports,
+            bytes_of_mut(&mut *dst_account),
+            &program_id,
+        );
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let mut accounts =
+            vec![group_ai.clone(), group_ai.clone(), group_ai, src_ai, dst_ai, owner_ai.clone()];
+        accounts.resize(6 + 2 * MAX_PAIRS, owner_ai);
+
+        let transferred = Processor::transfer_perp_position(&program_id, &accounts);
+        assert_eq!(error_code(transferred), Some(LyraeErrorCode::InvalidOwner));
+    }
+
+    #[test]
+    fn transfer_perp_position_preserves_total_base() {
+        let mut perp_market = Box::new(PerpMarket::zeroed());
+        let mut cache = PerpMarketCache::zeroed();
+        cache.long_funding = I80F48::from_num(2);
+        cache.short_funding = I80F48::from_num(2);
+
+        let mut src_pa = PerpAccount::zeroed();
+        let mut dst_pa = PerpAccount::zeroed();
+        src_pa.change_base_position(&mut perp_market, 7);
+        src_pa.quote_position = I80F48::from_num(-700);
+        dst_pa.change_base_position(&mut perp_market, -3);
+        dst_pa.quote_position = I80F48::from_num(310);
+        let total_base = src_pa.base_position + dst_pa.base_position;
+
+        transfer_perp_position_internal(&mut perp_market, &cache, &mut src_pa, &mut dst_pa);
+
+        assert_eq!(src_pa.base_position, 0);
+        assert_eq!(dst_pa.base_position, total_base);
+        assert_eq!(src_pa.quote_position, ZERO_I80F48);
+        // funding settled on both: 7 * 2 paid by the long, 3 * 2 received by the short
+        assert_eq!(dst_pa.quote_position, I80F48::from_num(-700 - 14 + 310 + 6));
+        assert_eq!(perp_market.open_interest, total_base.abs());
+    }
+
+    #[test]
+    fn set_referrer_memory_rejects_invalid_referrers() {
+        let program_id = Pubkey::new_unique();
+        let (group_pk, other_group_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (owner_pk, account_pk, referrer_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (referrer_memory_pk, _) =
+            Pubkey::find_program_address(&[account_pk.as_ref(), b"ReferrerMemory"], &program_id);
+        let (referrers_referrer_memory_pk, _) =
+            Pubkey::find_program_address(&[referrer_pk.as_ref(), b"ReferrerMemory"], &program_id);
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        let mut referrer_account = new_lyrae_account(&Pubkey::new_unique());
+        referrer_account.lyrae_group = other_group_pk;
+        // the referrer already has the referred account stored as its own referrer
+        let mut referrers_referrer_memory = ReferrerMemory::zeroed();
+        referrers_referrer_memory.meta_data = MetaData::new(DataType::ReferrerMemory, 0, true);
+        referrers_referrer_memory.referrer_lyrae_account = account_pk;
+
+        let (mut group_lamports, mut account_lamports, mut owner_lamports) = (0, 0, 0);
+        let (mut memory_lamports, mut referrer_lamports, mut referrers_memory_lamports) = (0, 0, 0);
+        // allocated but not initialized, so the handler fails the same with or without stubs
+        let mut referrer_memory = ReferrerMemory::zeroed();
+        let mut owner_data = [0u8; 0];
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let memory_ai = new_account_info(
+            &referrer_memory_pk,
+            &mut memory_lamports,
+            bytes_of_mut(&mut referrer_memory),
+            &program_id,
+        );
+        let mut referrer_ai = new_account_info(
+            &referrer_pk,
+            &mut referrer_lamports,
+            bytes_of_mut(&mut *referrer_account),
+            &program_id,
+        );
+        let referrers_memory_ai = new_account_info(
+            &referrers_referrer_memory_pk,
+            &mut referrers_memory_lamports,
+            bytes_of_mut(&mut referrers_referrer_memory),
+            &program_id,
+        );
+        let mut system_prog_ai = owner_ai.clone();
+        system_prog_ai.key = &solana_program::system_program::ID;
+        let set_referrer_memory = |referrer_ai, extra_ais: &[_]| {
+            let mut accounts = vec![
+                group_ai.clone(),
+                account_ai.clone(),
+                owner_ai.clone(),
+                memory_ai.clone(),
+                referrer_ai,
+                owner_ai.clone(),
+                system_prog_ai.clone(),
+            ];
+            accounts.extend_from_slice(extra_ais);
+            error_code(Processor::set_referrer_memory(&program_id, &accounts))
+        };
+
+        // a LyraeAccount of another group
+        assert_eq!(
+            set_referrer_memory(referrer_ai.clone(), &[]),
+            Some(LyraeErrorCode::InvalidReferrer)
+        );
+
+        // not a LyraeAccount of this program
+        LyraeAccount::load_mut(&referrer_ai).unwrap().lyrae_group = group_pk;
+        let other_program_id = Pubkey::new_unique();
+        referrer_ai.owner = &other_program_id;
+        assert_eq!(
+            set_referrer_memory(referrer_ai.clone(), &[]),
+            Some(LyraeErrorCode::InvalidReferrer)
+        );
+        referrer_ai.owner = &program_id;
+
+        // the referred account itself
+        assert_eq!(
+            set_referrer_memory(account_ai.clone(), &[]),
+            Some(LyraeErrorCode::InvalidReferrer)
+        );
+
+        // circular, only detected when the referrer's ReferrerMemory is passed
+        assert_eq!(
+            set_referrer_memory(referrer_ai.clone(), &[referrers_memory_ai.clone()]),
+            Some(LyraeErrorCode::InvalidReferrer)
+        );
+        assert_ne!(
+            set_referrer_memory(referrer_ai.clone(), &[]),
+            Some(LyraeErrorCode::InvalidReferrer)
+        );
+        let mut wrong_memory_ai = referrers_memory_ai;
+        wrong_memory_ai.key = &referrer_memory_pk;
+        assert_eq!(
+            set_referrer_memory(referrer_ai.clone(), &[wrong_memory_ai]),
+            Some(LyraeErrorCode::InvalidAccount)
+        );
+    }
+
+    #[test]
+    fn log_remaining_borrows_logs_only_borrowed_tokens() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk, account_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.lyrae_cache = cache_pk;
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        lyrae_cache.root_bank_cache[1].borrow_index = I80F48::from_num(2);
+        let mut lyrae_account = new_lyrae_account(&Pubkey::new_unique());
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.deposits[0] = I80F48::from_num(10);
+        lyrae_account.borrows[1] = I80F48::from_num(3);
+
+        let (mut group_lamports, mut cache_lamports, mut account_lamports) = (0, 0, 0);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let cache_ai = new_account_info(
+            &cache_pk,
+            &mut cache_lamports,
+            bytes_of_mut(&mut *lyrae_cache),
+            &program_id,
+        );
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
 
-fn checked_sub_borrow(
-    node_bank: &mut NodeBank,
-    lyrae_account: &mut LyraeAccount,
-    token_index: usize,
-    quantity: I80F48,
-) -> LyraeResult<()> {
-    lyrae_account.checked_sub_borrow(token_index, quantity)?;
-    node_bank.checked_sub_borrow(quantity)
-}
+        Processor::log_remaining_borrows(&program_id, &[group_ai, cache_ai, account_ai]).unwrap();
+        let logs = test_syscalls::emitted::<TokenBalanceLog>();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].token_index, 1);
+        assert_eq!(logs[0].deposit, 0);
+        assert_eq!(I80F48::from_bits(logs[0].borrow), I80F48::from_num(3));
+    }
 
-fn invoke_cancel_orders<'a>(
-    open_orders_ai: &AccountInfo<'a>,
-    dex_prog_ai: &AccountInfo<'a>,
-    spot_market_ai: &AccountInfo<'a>,
-    bids_ai: &AccountInfo<'a>,
-    asks_ai: &AccountInfo<'a>,
-    signer_ai: &AccountInfo<'a>,
-    dex_event_queue_ai: &AccountInfo<'a>,
-    signers_seeds: &[&[&[u8]]],
+    #[test]
+    fn halted_group_rejects_market_additions() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, admin_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&admin_pk);
+        lyrae_group.signer_key = admin_pk;
+        lyrae_group.set_pause_flag(GROUP_HALTED, true);
+
+        let (mut group_lamports, mut admin_lamports) = (0, 0);
+        let mut admin_data = [0u8; 0];
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let mut admin_ai =
+            new_account_info(&admin_pk, &mut admin_lamports, &mut admin_data, &program_id);
+        admin_ai.is_signer = true;
+        // the admin fills every other account, except the programs create_perp_market checks
+        let mut accounts = vec![admin_ai.clone(); 13];
+        accounts[0] = group_ai;
+        for (i, key) in [
+            (10, &solana_program::system_program::ID),
+            (11, &spl_token::ID),
+            (12, &solana_program::sysvar::rent::ID),
+        ] {
+            accounts[i].key = key;
+        }
 
-    mut limit: u8,
-) -> LyraeResult<()> {
-    let mut cancels = vec![];
-    {
-        let open_orders = load_open_orders(open_orders_ai)?;
+        let (ten, five, one) = (I80F48::from_num(10), I80F48::from_num(5), ONE_I80F48);
+        let added_spot_market =
+            Processor::add_spot_market(&program_id, &accounts, ten, five, one, one, one, one);
+        assert_eq!(error_code(added_spot_market), Some(LyraeErrorCode::GroupHalted));
+        let added_perp_market = Processor::add_perp_market(
+            &program_id,
+            &accounts,
+            ten,
+            five,
+            one,
+            ZERO_I80F48,
+            ZERO_I80F48,
+            1,
+            1,
+            ZERO_I80F48,
+            ZERO_I80F48,
+            1,
+            0,
+            1,
+        );
+        assert_eq!(error_code(added_perp_market), Some(LyraeErrorCode::GroupHalted));
+        let created_perp_market = Processor::create_perp_market(
+            &program_id,
+            &accounts,
+            ten,
+            five,
+            one,
+            ZERO_I80F48,
+            ZERO_I80F48,
+            1,
+            1,
+            ZERO_I80F48,
+            ZERO_I80F48,
+            1,
+            0,
+            1,
+            1,
+            0,
+            6,
+        );
+        assert_eq!(error_code(created_perp_market), Some(LyraeErrorCode::GroupHalted));
+        let added_oracle = Processor::add_oracle(&program_id, &accounts[..3]);
+        assert_eq!(error_code(added_oracle), Some(LyraeErrorCode::GroupHalted));
+
+        let (oracle_pk, mut oracle_lamports, mut oracle_data) = (Pubkey::new_unique(), 0, [0u8; 8]);
+        let oracle_ai =
+            new_account_info(&oracle_pk, &mut oracle_lamports, &mut oracle_data, &program_id);
+        LyraeGroup::load_mut(&accounts[0]).unwrap().set_pause_flag(GROUP_HALTED, false);
+        let added_oracle =
+            Processor::add_oracle(&program_id, &[accounts[0].clone(), oracle_ai, admin_ai]);
+        assert_ne!(error_code(added_oracle), Some(LyraeErrorCode::GroupHalted));
+    }
 
-        let market = load_market_state(spot_market_ai, dex_prog_ai.key)?;
-        let bids = load_bids_mut(&market, bids_ai)?;
-        let asks = load_asks_mut(&market, asks_ai)?;
+    #[test]
+    fn remove_spot_market_requires_empty_node_banks() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, admin_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (root_bank_pk, node_bank_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&admin_pk);
+        lyrae_group.num_oracles = 2;
+        lyrae_group.tokens[1].root_bank = root_bank_pk;
+        lyrae_group.spot_markets[1].spot_market = Pubkey::new_unique();
+        let mut root_bank = RootBank::zeroed();
+        root_bank.meta_data = MetaData::new(DataType::RootBank, 0, true);
+        root_bank.num_node_banks = 1;
+        root_bank.node_banks[0] = node_bank_pk;
+        let mut node_bank = NodeBank::zeroed();
+        node_bank.meta_data = MetaData::new(DataType::NodeBank, 0, true);
+        node_bank.deposits = I80F48::from_num(1);
+
+        let (mut group_lamports, mut admin_lamports, mut admin_data) = (0, 0, [0u8; 0]);
+        let (mut root_bank_lamports, mut node_bank_lamports) = (0, 0);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let mut admin_ai =
+            new_account_info(&admin_pk, &mut admin_lamports, &mut admin_data, &program_id);
+        admin_ai.is_signer = true;
+        let root_bank_ai = new_account_info(
+            &root_bank_pk,
+            &mut root_bank_lamports,
+            bytes_of_mut(&mut root_bank),
+            &program_id,
+        );
+        let node_bank_ai = new_account_info(
+            &node_bank_pk,
+            &mut node_bank_lamports,
+            bytes_of_mut(&mut node_bank),
+            &program_id,
+        );
+        let accounts = [group_ai, admin_ai, root_bank_ai, node_bank_ai];
 
-        limit = min(limit, open_orders.free_slot_bits.count_zeros() as u8);
-        if limit == 0 {
-            return Ok(());
+        let removed = Processor::remove_spot_market(&program_id, &accounts);
+        assert_eq!(error_code(removed), Some(LyraeErrorCode::InvalidAccountState));
+        assert!(!LyraeGroup::load(&accounts[0]).unwrap().spot_markets[1].is_empty());
+
+        NodeBank::load_mut(&accounts[3]).unwrap().deposits = ZERO_I80F48;
+        Processor::remove_spot_market(&program_id, &accounts).unwrap();
+        let lyrae_group = LyraeGroup::load(&accounts[0]).unwrap();
+        assert!(lyrae_group.spot_markets[1].is_empty());
+        assert_eq!(lyrae_group.find_root_bank_index(&root_bank_pk), None);
+    }
+
+    #[test]
+    fn settle_pnl_rejects_out_of_range_and_empty_markets() {
+        let program_id = Pubkey::new_unique();
+        let (group_pk, a_pk, b_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 2;
+        lyrae_group.perp_markets[0].perp_market = Pubkey::new_unique();
+        let mut account_a = new_lyrae_account(&Pubkey::new_unique());
+        account_a.lyrae_group = group_pk;
+        let mut account_b = new_lyrae_account(&Pubkey::new_unique());
+        account_b.lyrae_group = group_pk;
+
+        let (mut group_lamports, mut a_lamports, mut b_lamports) = (0, 0, 0);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let a_ai =
+            new_account_info(&a_pk, &mut a_lamports, bytes_of_mut(&mut *account_a), &program_id);
+        let b_ai =
+            new_account_info(&b_pk, &mut b_lamports, bytes_of_mut(&mut *account_b), &program_id);
+        let accounts = [group_ai.clone(), a_ai, b_ai, group_ai.clone(), group_ai.clone(), group_ai];
+        let settle_pnl =
+            |market_index| error_code(Processor::settle_pnl(&program_id, &accounts, market_index));
+
+        // the market has no perp market, is past num_oracles or is past MAX_PAIRS
+        for market_index in [1, 2, MAX_PAIRS, usize::MAX] {
+            assert_eq!(settle_pnl(market_index), Some(LyraeErrorCode::InvalidMarket));
         }
-        for j in 0..128 {
-            let slot_mask = 1u128 << j;
-            if open_orders.free_slot_bits & slot_mask != 0 {
-                // means slot is free
-                continue;
-            }
-            let order_id = open_orders.orders[j];
+        // a listed market gets to the quote root bank check
+        assert_eq!(settle_pnl(0), Some(LyraeErrorCode::Default));
+    }
 
-            let side = if open_orders.is_bid_bits & slot_mask != 0 {
-                match bids.find_by_key(order_id) {
-                    None => continue,
-                    Some(_) => serum_dex::matching::Side::Bid,
-                }
-            } else {
-                match asks.find_by_key(order_id) {
-                    None => continue,
-                    Some(_) => serum_dex::matching::Side::Ask,
-                }
-            };
+    /// An initialized EventQueue with room for `len` events. The data is kept in u64s so the
+    /// header is aligned; cast it to bytes for the AccountInfo.
+    fn new_event_queue_data(len: usize) -> Vec<u64> {
+        use crate::queue::{AnyEvent, EventQueueHeader};
+        let header_len = size_of::<EventQueueHeader>();
+        let mut data = vec![0u64; (header_len + len * size_of::<AnyEvent>()) / 8];
+        let header: &mut EventQueueHeader =
+            bytemuck::from_bytes_mut(&mut bytemuck::cast_slice_mut(&mut data)[..header_len]);
+        header.meta_data = MetaData::new(DataType::EventQueue, 0, true);
+        data
+    }
 
-            let cancel_instruction =
-                serum_dex::instruction::CancelOrderInstructionV2 { side, order_id };
+    #[test]
+    fn place_perp_order_returns_fills_of_a_crossing_order() {
+        use crate::matching::LeafNode;
+
+        test_syscalls::init();
+        let now_ts = test_syscalls::NOW_TS;
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (account_pk, owner_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (perp_market_pk, bids_pk, asks_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let event_queue_pk = Pubkey::new_unique();
+
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 1;
+        lyrae_group.lyrae_cache = cache_pk;
+        let info = &mut lyrae_group.perp_markets[0];
+        info.perp_market = perp_market_pk;
+        info.maint_asset_weight = I80F48::from_num(0.95);
+        info.init_asset_weight = I80F48::from_num(0.9);
+        info.maint_liab_weight = I80F48::from_num(1.05);
+        info.init_liab_weight = I80F48::from_num(1.1);
+        info.base_lot_size = 10;
+        info.quote_lot_size = 1;
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        lyrae_cache.price_cache[0].price = I80F48::from_num(10);
+        lyrae_cache.price_cache[0].last_update = now_ts;
+        lyrae_cache.perp_market_cache[0].last_update = now_ts;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].deposit_index = ONE_I80F48;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].last_update = now_ts;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+        lyrae_account.deposits[QUOTE_INDEX] = I80F48::from_num(1_000_000);
+
+        let mut perp_market = Box::new(PerpMarket::zeroed());
+        perp_market.meta_data = MetaData::new(DataType::PerpMarket, 0, true);
+        perp_market.lyrae_group = group_pk;
+        perp_market.bids = bids_pk;
+        perp_market.asks = asks_pk;
+        perp_market.event_queue = event_queue_pk;
+        perp_market.base_lot_size = 10;
+        perp_market.quote_lot_size = 1;
+        let mut bids = Box::new(BookSide::zeroed());
+        bids.meta_data = MetaData::new(DataType::Bids, 0, true);
+        let mut asks = Box::new(BookSide::zeroed());
+        asks.meta_data = MetaData::new(DataType::Asks, 0, true);
+        let order_id = perp_market.gen_order_id(Side::Ask, 100);
+        let maker_pk = Pubkey::new_unique();
+        let ask = LeafNode::new(1, 0, order_id, maker_pk, 3, 0, now_ts, 0, OrderType::Limit);
+        asks.insert_leaf(&ask).unwrap();
+        let mut event_queue = new_event_queue_data(8);
+
+        let (mut group_lamports, mut cache_lamports, mut account_lamports) = (0, 0, 0);
+        let (mut owner_lamports, mut owner_data) = (0, [0u8; 0]);
+        let (mut perp_market_lamports, mut bids_lamports, mut asks_lamports) = (0, 0, 0);
+        let mut event_queue_lamports = 0;
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let cache_ai = new_account_info(
+            &cache_pk,
+            &mut cache_lamports,
+            bytes_of_mut(&mut *lyrae_cache),
+            &program_id,
+        );
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let perp_market_ai = new_account_info(
+            &perp_market_pk,
+            &mut perp_market_lamports,
+            bytes_of_mut(&mut *perp_market),
+            &program_id,
+        );
+        let bids_ai =
+            new_account_info(&bids_pk, &mut bids_lamports, bytes_of_mut(&mut *bids), &program_id);
+        let asks_ai =
+            new_account_info(&asks_pk, &mut asks_lamports, bytes_of_mut(&mut *asks), &program_id);
+        let event_queue_ai = new_account_info(
+            &event_queue_pk,
+            &mut event_queue_lamports,
+            bytemuck::cast_slice_mut(&mut event_queue),
+            &program_id,
+        );
+        let mut accounts = vec![
+            group_ai,
+            account_ai,
+            owner_ai,
+            cache_ai,
+            perp_market_ai,
+            bids_ai,
+            asks_ai,
+            event_queue_ai,
+        ];
+        // no spot open orders are in the margin basket
+        accounts.resize(8 + MAX_PAIRS, accounts[0].clone());
+
+        // 3 of the 5 lots fill against the ask and the other 2 rest on the book
+        Processor::place_perp_order(
+            &program_id,
+            &accounts,
+            Side::Bid,
+            101,
+            5,
+            0,
+            OrderType::Limit,
+            false,
+        )
+        .unwrap();
+        let return_data = test_syscalls::return_data().unwrap();
+        assert_eq!(return_data.len(), 24);
+        let filled: Vec<i64> = return_data
+            .chunks(8)
+            .map(|bytes| i64::from_le_bytes(<[u8; 8]>::try_from(bytes).unwrap()))
+            .collect();
+        assert_eq!(filled, [3, -300, 2]);
+    }
 
-            cancels.push(cancel_instruction);
+    #[test]
+    fn withdraw_all_sweeps_dust_only_with_close_dust() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (account_pk, owner_pk, signer_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (root_bank_pk, node_bank_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (vault_pk, token_account_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (dust_account_pk, _) =
+            Pubkey::find_program_address(&[group_pk.as_ref(), b"DustAccount"], &program_id);
+
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.lyrae_cache = cache_pk;
+        lyrae_group.signer_key = signer_pk;
+        lyrae_group.tokens[QUOTE_INDEX].root_bank = root_bank_pk;
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].deposit_index = ONE_I80F48;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].borrow_index = ONE_I80F48;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].last_update = test_syscalls::NOW_TS;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.deposits[QUOTE_INDEX] = I80F48::from_num(10.5);
+        let mut dust_account = new_lyrae_account(&Pubkey::new_unique());
+        dust_account.lyrae_group = group_pk;
+        let mut root_bank = RootBank::zeroed();
+        root_bank.meta_data = MetaData::new(DataType::RootBank, 0, true);
+        root_bank.num_node_banks = 1;
+        root_bank.node_banks[0] = node_bank_pk;
+        let mut node_bank = NodeBank::zeroed();
+        node_bank.meta_data = MetaData::new(DataType::NodeBank, 0, true);
+        node_bank.vault = vault_pk;
+        node_bank.deposits = I80F48::from_num(10.5);
+
+        let (mut group_lamports, mut cache_lamports, mut account_lamports) = (0, 0, 0);
+        let (mut dust_account_lamports, mut root_bank_lamports, mut node_bank_lamports) = (0, 0, 0);
+        let (mut owner_lamports, mut vault_lamports, mut token_account_lamports) = (0, 0, 0);
+        let (mut signer_lamports, mut token_prog_lamports) = (0, 0);
+        let (mut owner_data, mut vault_data, mut token_account_data) =
+            ([0u8; 0], [0u8; 0], [0u8; 0]);
+        let (mut signer_data, mut token_prog_data) = ([0u8; 0], [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let cache_ai = new_account_info(
+            &cache_pk,
+            &mut cache_lamports,
+            bytes_of_mut(&mut *lyrae_cache),
+            &program_id,
+        );
+        let root_bank_ai = new_account_info(
+            &root_bank_pk,
+            &mut root_bank_lamports,
+            bytes_of_mut(&mut root_bank),
+            &program_id,
+        );
+        let node_bank_ai = new_account_info(
+            &node_bank_pk,
+            &mut node_bank_lamports,
+            bytes_of_mut(&mut node_bank),
+            &program_id,
+        );
+        let vault_ai =
+            new_account_info(&vault_pk, &mut vault_lamports, &mut vault_data, &spl_token::ID);
+        let token_account_ai = new_account_info(
+            &token_account_pk,
+            &mut token_account_lamports,
+            &mut token_account_data,
+            &spl_token::ID,
+        );
+        let signer_ai =
+            new_account_info(&signer_pk, &mut signer_lamports, &mut signer_data, &program_id);
+        let token_prog_ai = new_account_info(
+            &spl_token::ID,
+            &mut token_prog_lamports,
+            &mut token_prog_data,
+            &program_id,
+        );
+        let dust_account_ai = new_account_info(
+            &dust_account_pk,
+            &mut dust_account_lamports,
+            bytes_of_mut(&mut *dust_account),
+            &program_id,
+        );
+        let mut accounts = vec![
+            group_ai,
+            account_ai,
+            owner_ai,
+            cache_ai,
+            root_bank_ai,
+            node_bank_ai,
+            vault_ai,
+            token_account_ai,
+            signer_ai,
+            token_prog_ai,
+        ];
+        // no spot open orders are in the margin basket
+        accounts.resize(10 + MAX_PAIRS, accounts[0].clone());
+        let deposit = |accounts: &[AccountInfo]| {
+            LyraeAccount::load(&accounts[1]).unwrap().deposits[QUOTE_INDEX]
+        };
 
-            limit -= 1;
-            if limit == 0 {
-                break;
-            }
+        // Without close_dust no DustAccount is needed and the remainder stays deposited
+        Processor::withdraw(&program_id, &accounts, 4, false, false).unwrap();
+        assert_eq!(deposit(&accounts), I80F48::from_num(6.5));
+        Processor::withdraw(&program_id, &accounts, u64::MAX, false, false).unwrap();
+        assert_eq!(deposit(&accounts), I80F48::from_num(0.5));
+        let withdrawals = test_syscalls::emitted::<WithdrawLog>();
+        assert_eq!(withdrawals.iter().map(|log| log.quantity).collect::<Vec<_>>(), [4, 6]);
+
+        // With it the remainder moves to the DustAccount
+        LyraeAccount::load_mut(&accounts[1]).unwrap().deposits[QUOTE_INDEX] =
+            I80F48::from_num(3.25);
+        NodeBank::load_mut(&accounts[5]).unwrap().deposits = I80F48::from_num(3.25);
+        accounts.push(dust_account_ai);
+        Processor::withdraw(&program_id, &accounts, u64::MAX, false, true).unwrap();
+        assert_eq!(deposit(&accounts), ZERO_I80F48);
+        let dust_account = LyraeAccount::load(accounts.last().unwrap()).unwrap();
+        assert_eq!(dust_account.deposits[QUOTE_INDEX], I80F48::from_num(0.25));
+    }
+
+    /// The account data of a dex account holding `state`
+    fn dex_account_data<T: bytemuck::Pod>(state: &T) -> Vec<u8> {
+        [&b"serum"[..], bytemuck::bytes_of(state), &b"padding"[..]].concat()
+    }
+
+    /// An initialized OpenOrders owned by `owner` with every order slot free
+    fn new_open_orders(owner: &Pubkey) -> serum_dex::state::OpenOrders {
+        let mut open_orders = serum_dex::state::OpenOrders::zeroed();
+        open_orders.account_flags = (serum_dex::state::AccountFlag::Initialized
+            | serum_dex::state::AccountFlag::OpenOrders)
+            .bits();
+        open_orders.owner = owner.to_aligned_bytes();
+        open_orders.free_slot_bits = u128::MAX;
+        open_orders
+    }
+
+    #[test]
+    fn cancel_spot_order_skips_a_stale_order_id_with_ok_invalid_id() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, account_pk, owner_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (dex_pk, spot_market_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (open_orders_pk, signer_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 1;
+        lyrae_group.dex_program_id = dex_pk;
+        lyrae_group.signer_key = signer_pk;
+        lyrae_group.spot_markets[0].spot_market = spot_market_pk;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.spot_open_orders[0] = open_orders_pk;
+        // The only order left on the OpenOrders is bid 42; 41 was filled before the cancel landed
+        let mut open_orders = new_open_orders(&signer_pk);
+        open_orders.free_slot_bits = !1;
+        open_orders.is_bid_bits = 1;
+        open_orders.orders[0] = 42;
+        open_orders.native_coin_total = 7;
+        let mut open_orders_data = dex_account_data(&open_orders);
+        let mut market = serum_dex::state::MarketState::zeroed();
+        market.account_flags = (serum_dex::state::AccountFlag::Initialized
+            | serum_dex::state::AccountFlag::Market)
+            .bits();
+        let mut market_data = dex_account_data(&market);
+
+        let (mut group_lamports, mut account_lamports, mut owner_lamports) = (0, 0, 0);
+        let (mut open_orders_lamports, mut market_lamports, mut owner_data) = (0, 0, [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let open_orders_ai = new_account_info(
+            &open_orders_pk,
+            &mut open_orders_lamports,
+            &mut open_orders_data,
+            &dex_pk,
+        );
+        let market_ai =
+            new_account_info(&spot_market_pk, &mut market_lamports, &mut market_data, &dex_pk);
+        // The owner stands in for the other dex accounts; the book is never read for an order
+        // the OpenOrders doesn't have
+        let (bids_pk, asks_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut accounts = vec![owner_ai.clone(); 10];
+        for (i, key) in [(3, &dex_pk), (5, &bids_pk), (6, &asks_pk), (8, &signer_pk)] {
+            accounts[i].key = key;
         }
+        accounts[0] = group_ai;
+        accounts[2] = account_ai;
+        accounts[4] = market_ai;
+        accounts[7] = open_orders_ai;
+
+        let order = serum_dex::instruction::CancelOrderInstructionV2 {
+            side: serum_dex::matching::Side::Bid,
+            order_id: 41,
+        };
+        Processor::cancel_spot_order(&program_id, &accounts, order, true).unwrap();
+        let logs = test_syscalls::emitted::<OpenOrdersBalanceLog>();
+        assert_eq!(logs.len(), 1);
+        assert_eq!((logs[0].market_index, logs[0].base_total), (0, 7));
     }
 
-    let mut instruction = Instruction {
-        program_id: *dex_prog_ai.key,
-        data: vec![],
-        accounts: vec![
-            AccountMeta::new(*spot_market_ai.key, false),
-            AccountMeta::new(*bids_ai.key, false),
-            AccountMeta::new(*asks_ai.key, false),
-            AccountMeta::new(*open_orders_ai.key, false),
-            AccountMeta::new_readonly(*signer_ai.key, true),
-            AccountMeta::new(*dex_event_queue_ai.key, false),
-        ],
-    };
+    #[test]
+    fn update_all_caches_stops_at_the_item_limit() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let num_perp_markets = MAX_UPDATE_ALL_CACHES_ITEMS + 1;
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = num_perp_markets;
+        lyrae_group.lyrae_cache = cache_pk;
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
 
-    let account_infos = [
-        dex_prog_ai.clone(),
-        spot_market_ai.clone(),
-        bids_ai.clone(),
-        asks_ai.clone(),
-        open_orders_ai.clone(),
-        signer_ai.clone(),
-        dex_event_queue_ai.clone(),
-    ];
+        // Every market has an empty book and a fresh price, so no funding accrues
+        let keys: Vec<[Pubkey; 3]> = (0..num_perp_markets)
+            .map(|_| [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()])
+            .collect();
+        let mut markets = vec![];
+        for (i, [perp_market_pk, bids_pk, asks_pk]) in keys.iter().enumerate() {
+            lyrae_group.perp_markets[i].perp_market = *perp_market_pk;
+            lyrae_cache.price_cache[i].price = ONE_I80F48;
+            lyrae_cache.price_cache[i].last_update = test_syscalls::NOW_TS;
+            let mut perp_market = Box::new(PerpMarket::zeroed());
+            perp_market.meta_data = MetaData::new(DataType::PerpMarket, 0, true);
+            perp_market.lyrae_group = group_pk;
+            perp_market.bids = *bids_pk;
+            perp_market.asks = *asks_pk;
+            let mut bids = Box::new(BookSide::zeroed());
+            bids.meta_data = MetaData::new(DataType::Bids, 0, true);
+            let mut asks = Box::new(BookSide::zeroed());
+            asks.meta_data = MetaData::new(DataType::Asks, 0, true);
+            markets.push((perp_market, bids, asks, [0u64; 3]));
+        }
 
-    for cancel in cancels.iter() {
-        let cancel_instruction =
-            serum_dex::instruction::MarketInstruction::CancelOrderV2(cancel.clone());
-        instruction.data = cancel_instruction.pack();
-        solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)?;
+        let (mut group_lamports, mut cache_lamports) = (0, 0);
+        let mut accounts = vec![
+            new_account_info(
+                &group_pk,
+                &mut group_lamports,
+                bytes_of_mut(&mut *lyrae_group),
+                &program_id,
+            ),
+            new_account_info(
+                &cache_pk,
+                &mut cache_lamports,
+                bytes_of_mut(&mut *lyrae_cache),
+                &program_id,
+            ),
+        ];
+        for ([perp_market_pk, bids_pk, asks_pk], (perp_market, bids, asks, lamports)) in
+            keys.iter().zip(markets.iter_mut())
+        {
+            let [perp_market_lamports, bids_lamports, asks_lamports] = lamports;
+            accounts.extend([
+                new_account_info(
+                    perp_market_pk,
+                    perp_market_lamports,
+                    bytes_of_mut(&mut **perp_market),
+                    &program_id,
+                ),
+                new_account_info(bids_pk, bids_lamports, bytes_of_mut(&mut **bids), &program_id),
+                new_account_info(asks_pk, asks_lamports, bytes_of_mut(&mut **asks), &program_id),
+            ]);
+        }
+        // A root bank after the markets isn't reached, so it isn't even loaded
+        accounts.push(accounts[0].clone());
+
+        Processor::update_all_caches(&program_id, &accounts, num_perp_markets).unwrap();
+        let logs = test_syscalls::emitted::<UpdateFundingLog>();
+        let market_indexes: Vec<u64> = logs.iter().map(|log| log.market_index).collect();
+        assert_eq!(market_indexes, (0..MAX_UPDATE_ALL_CACHES_ITEMS as u64).collect::<Vec<_>>());
+        let lyrae_cache = LyraeCache::load(&accounts[1]).unwrap();
+        for (i, perp_market_cache) in lyrae_cache.perp_market_cache.iter().enumerate() {
+            let updated = i < MAX_UPDATE_ALL_CACHES_ITEMS;
+            assert_eq!(perp_market_cache.last_update == test_syscalls::NOW_TS, updated);
+        }
     }
 
-    Ok(())
-}
+    /// Liquidate a liqee with 1000 of token 0 deposited at a price of 1 and 900 quote borrowed,
+    /// taking the quote borrow. Returns the result and the liqee afterwards.
+    fn liquidate_quote_borrow(
+        mut lyrae_group: Box<LyraeGroup>,
+        max_liab_transfer: I80F48,
+        max_health_fraction: I80F48,
+    ) -> (LyraeResult, Box<LyraeAccount>) {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (liqee_pk, liqor_account_pk, liqor_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let bank_pks: Vec<(Pubkey, Pubkey)> =
+            (0..2).map(|_| (Pubkey::new_unique(), Pubkey::new_unique())).collect();
+
+        lyrae_group.num_oracles = 1;
+        lyrae_group.lyrae_cache = cache_pk;
+        lyrae_group.tokens[0].root_bank = bank_pks[0].0;
+        lyrae_group.tokens[QUOTE_INDEX].root_bank = bank_pks[1].0;
+        lyrae_group.spot_markets[0] = SpotMarketInfo {
+            spot_market: Pubkey::new_unique(),
+            maint_asset_weight: I80F48::from_num(0.875),
+            init_asset_weight: I80F48::from_num(0.75),
+            maint_liab_weight: I80F48::from_num(1.125),
+            init_liab_weight: I80F48::from_num(1.25),
+            liquidation_fee: I80F48::from_num(0.0625),
+        };
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        lyrae_cache.price_cache[0].price = ONE_I80F48;
+        lyrae_cache.price_cache[0].last_update = test_syscalls::NOW_TS;
+        for token_index in [0, QUOTE_INDEX] {
+            let root_bank_cache = &mut lyrae_cache.root_bank_cache[token_index];
+            root_bank_cache.deposit_index = ONE_I80F48;
+            root_bank_cache.borrow_index = ONE_I80F48;
+            root_bank_cache.last_update = test_syscalls::NOW_TS;
+        }
+        let mut liqee = new_lyrae_account(&Pubkey::new_unique());
+        liqee.lyrae_group = group_pk;
+        liqee.deposits[0] = I80F48::from_num(1000);
+        liqee.borrows[QUOTE_INDEX] = I80F48::from_num(900);
+        let mut liqor = new_lyrae_account(&liqor_pk);
+        liqor.lyrae_group = group_pk;
+        liqor.deposits[QUOTE_INDEX] = I80F48::from_num(10_000);
+        let mut banks: Vec<(RootBank, NodeBank)> = bank_pks
+            .iter()
+            .map(|(_, node_bank_pk)| {
+                let mut root_bank = RootBank::zeroed();
+                root_bank.meta_data = MetaData::new(DataType::RootBank, 0, true);
+                root_bank.num_node_banks = 1;
+                root_bank.node_banks[0] = *node_bank_pk;
+                let mut node_bank = NodeBank::zeroed();
+                node_bank.meta_data = MetaData::new(DataType::NodeBank, 0, true);
+                (root_bank, node_bank)
+            })
+            .collect();
+        banks[0].1.deposits = I80F48::from_num(1000);
+        banks[1].1.deposits = I80F48::from_num(10_000);
+        banks[1].1.borrows = I80F48::from_num(900);
+
+        let (mut group_lamports, mut cache_lamports) = (0, 0);
+        let (mut liqee_lamports, mut liqor_account_lamports) = (0, 0);
+        let (mut liqor_lamports, mut liqor_data) = (0, [0u8; 0]);
+        let mut bank_lamports = [[0u64; 2]; 2];
+        let mut accounts = vec![
+            new_account_info(
+                &group_pk,
+                &mut group_lamports,
+                bytes_of_mut(&mut *lyrae_group),
+                &program_id,
+            ),
+            new_account_info(
+                &cache_pk,
+                &mut cache_lamports,
+                bytes_of_mut(&mut *lyrae_cache),
+                &program_id,
+            ),
+            new_account_info(
+                &liqee_pk,
+                &mut liqee_lamports,
+                bytes_of_mut(&mut *liqee),
+                &program_id,
+            ),
+            new_account_info(
+                &liqor_account_pk,
+                &mut liqor_account_lamports,
+                bytes_of_mut(&mut *liqor),
+                &program_id,
+            ),
+            new_account_info(&liqor_pk, &mut liqor_lamports, &mut liqor_data, &program_id),
+        ];
+        accounts[4].is_signer = true;
+        for (((root_bank_pk, node_bank_pk), (root_bank, node_bank)), lamports) in
+            bank_pks.iter().zip(banks.iter_mut()).zip(bank_lamports.iter_mut())
+        {
+            let [root_bank_lamports, node_bank_lamports] = lamports;
+            accounts.extend([
+                new_account_info(
+                    root_bank_pk,
+                    root_bank_lamports,
+                    bytes_of_mut(root_bank),
+                    &program_id,
+                ),
+                new_account_info(
+                    node_bank_pk,
+                    node_bank_lamports,
+                    bytes_of_mut(node_bank),
+                    &program_id,
+                ),
+            ]);
+        }
+        // no spot open orders are in either margin basket
+        let no_open_orders_pk = Pubkey::default();
+        let mut no_open_orders_ai = accounts[4].clone();
+        no_open_orders_ai.key = &no_open_orders_pk;
+        accounts.resize(9 + 2 * MAX_PAIRS, no_open_orders_ai);
+
+        let result = Processor::liquidate_token_and_token(
+            &program_id,
+            &accounts,
+            max_liab_transfer,
+            max_health_fraction,
+        );
+        let liqee = Box::new(*LyraeAccount::load(&accounts[2]).unwrap());
+        (result, liqee)
+    }
 
-fn invoke_new_order<'a>(
-    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
-    spot_market_ai: &AccountInfo<'a>,
-    open_orders_ai: &AccountInfo<'a>,
-    dex_request_queue_ai: &AccountInfo<'a>,
-    dex_event_queue_ai: &AccountInfo<'a>,
-    bids_ai: &AccountInfo<'a>,
-    asks_ai: &AccountInfo<'a>,
-    vault_ai: &AccountInfo<'a>,
-    signer_ai: &AccountInfo<'a>,
-    dex_base_ai: &AccountInfo<'a>,
-    dex_quote_ai: &AccountInfo<'a>,
-    token_prog_ai: &AccountInfo<'a>,
-    msrm_or_srm_vault_ai: &AccountInfo<'a>,
-    signers_seeds: &[&[&[u8]]],
+    #[test]
+    fn liquidate_token_and_token_stops_at_the_deficit() {
+        // init health is 1000 * 0.75 - 900 = -150
+        let init_health = |liqee: &LyraeAccount| {
+            liqee.deposits[0] * I80F48::from_num(0.75) - liqee.borrows[QUOTE_INDEX]
+        };
+        let max_liab_transfer = I80F48::from_num(10_000);
+
+        // Far less than the whole borrow is taken, just enough to bring init health to zero
+        let lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        let (result, liqee) = liquidate_quote_borrow(lyrae_group, max_liab_transfer, ONE_I80F48);
+        result.unwrap();
+        assert!(init_health(&liqee).abs() < I80F48::from_num(0.001));
+        assert!(liqee.borrows[QUOTE_INDEX] > I80F48::from_num(100));
+        assert!(!liqee.being_liquidated);
+
+        // A partial liquidation takes that fraction of the deficit
+        let lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        let half = I80F48::from_num(0.5);
+        let (result, liqee) = liquidate_quote_borrow(lyrae_group, max_liab_transfer, half);
+        result.unwrap();
+        assert!((init_health(&liqee) + I80F48::from_num(75)).abs() < I80F48::from_num(0.001));
+        assert!(liqee.being_liquidated);
+    }
 
-    order: NewOrderInstructionV3,
-) -> ProgramResult {
-    let data = serum_dex::instruction::MarketInstruction::NewOrderV3(order).pack();
-    let mut instruction = Instruction {
-        program_id: *dex_prog_ai.key,
-        data,
-        accounts: vec![
-            AccountMeta::new(*spot_market_ai.key, false),
-            AccountMeta::new(*open_orders_ai.key, false),
-            AccountMeta::new(*dex_request_queue_ai.key, false),
-            AccountMeta::new(*dex_event_queue_ai.key, false),
-            AccountMeta::new(*bids_ai.key, false),
-            AccountMeta::new(*asks_ai.key, false),
-            AccountMeta::new(*vault_ai.key, false),
-            AccountMeta::new_readonly(*signer_ai.key, true),
-            AccountMeta::new(*dex_base_ai.key, false),
-            AccountMeta::new(*dex_quote_ai.key, false),
-            AccountMeta::new_readonly(*token_prog_ai.key, false),
-            AccountMeta::new_readonly(*signer_ai.key, false),
-        ],
-    };
+    #[test]
+    fn consume_events_finds_accounts_among_extra_ones() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (perp_market_pk, event_queue_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 1;
+        lyrae_group.lyrae_cache = cache_pk;
+        lyrae_group.perp_markets[0].perp_market = perp_market_pk;
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        lyrae_cache.perp_market_cache[0].last_update = test_syscalls::NOW_TS;
+        let mut perp_market = Box::new(PerpMarket::zeroed());
+        perp_market.meta_data = MetaData::new(DataType::PerpMarket, 0, true);
+        perp_market.lyrae_group = group_pk;
+        perp_market.event_queue = event_queue_pk;
+        let mut event_queue = new_event_queue_data(8);
+
+        // Every account has a bid of 2 in slot 0
+        let pks: Vec<Pubkey> = (0..4).map(|_| Pubkey::new_unique()).collect();
+        let mut lyrae_accounts: Vec<Box<LyraeAccount>> = pks
+            .iter()
+            .map(|_| {
+                let mut lyrae_account = new_lyrae_account(&Pubkey::new_unique());
+                lyrae_account.lyrae_group = group_pk;
+                lyrae_account.order_market = [FREE_ORDER_SLOT; MAX_PERP_OPEN_ORDERS];
+                lyrae_account.order_market[0] = 0;
+                lyrae_account.orders[0] = 1;
+                lyrae_account.perp_accounts[0].bids_quantity = 2;
+                lyrae_account
+            })
+            .collect();
+
+        let (mut group_lamports, mut cache_lamports) = (0, 0);
+        let (mut perp_market_lamports, mut event_queue_lamports) = (0, 0);
+        let mut lamports = vec![0; pks.len()];
+        let mut accounts = vec![
+            new_account_info(
+                &group_pk,
+                &mut group_lamports,
+                bytes_of_mut(&mut *lyrae_group),
+                &program_id,
+            ),
+            new_account_info(
+                &cache_pk,
+                &mut cache_lamports,
+                bytes_of_mut(&mut *lyrae_cache),
+                &program_id,
+            ),
+            new_account_info(
+                &perp_market_pk,
+                &mut perp_market_lamports,
+                bytes_of_mut(&mut *perp_market),
+                &program_id,
+            ),
+            new_account_info(
+                &event_queue_pk,
+                &mut event_queue_lamports,
+                bytemuck::cast_slice_mut(&mut event_queue),
+                &program_id,
+            ),
+        ];
+        let mut lyrae_account_ais: Vec<AccountInfo> = pks
+            .iter()
+            .zip(lyrae_accounts.iter_mut())
+            .zip(lamports.iter_mut())
+            .map(|((pk, lyrae_account), lamports)| {
+                new_account_info(pk, lamports, bytes_of_mut(&mut **lyrae_account), &program_id)
+            })
+            .collect();
+
+        // Accounts 1 and 2 are passed out of order along with unrelated account 0. Account 3 is
+        // missing, so consuming stops at its event and leaves the rest on the queue.
+        let mut queue = EventQueue::load_mut(&accounts[3]).unwrap();
+        for (seq_num, &owner) in [2, 1, 3, 2].iter().enumerate() {
+            let out = OutEvent::new(Side::Bid, 0, 0, seq_num, pks[owner], 2);
+            queue.push_back(cast(out)).unwrap();
+        }
+        drop(queue);
+        lyrae_account_ais.truncate(3);
+        lyrae_account_ais.swap(1, 2);
+        accounts.extend(lyrae_account_ais);
+
+        Processor::consume_events(&program_id, &accounts, 8).unwrap();
+        assert_eq!(test_syscalls::return_data(), Some(2u64.to_le_bytes().to_vec()));
+        assert_eq!(EventQueue::load_mut(&accounts[3]).unwrap().len(), 2);
+        for ai in &accounts[4..] {
+            let lyrae_account = LyraeAccount::load(ai).unwrap();
+            let removed = ai.key != &pks[0];
+            assert_eq!(lyrae_account.order_market[0] == FREE_ORDER_SLOT, removed);
+            assert_eq!(lyrae_account.perp_accounts[0].bids_quantity, if removed { 0 } else { 2 });
+        }
+    }
 
-    if msrm_or_srm_vault_ai.key != &Pubkey::default() {
-        instruction.accounts.push(AccountMeta::new_readonly(*msrm_or_srm_vault_ai.key, false));
-        let account_infos = [
-            dex_prog_ai.clone(), // Have to add account of the program id
-            spot_market_ai.clone(),
-            open_orders_ai.clone(),
-            dex_request_queue_ai.clone(),
-            dex_event_queue_ai.clone(),
-            bids_ai.clone(),
-            asks_ai.clone(),
-            vault_ai.clone(),
-            signer_ai.clone(),
-            dex_base_ai.clone(),
-            dex_quote_ai.clone(),
-            token_prog_ai.clone(),
-            signer_ai.clone(),
-            msrm_or_srm_vault_ai.clone(),
+    #[test]
+    fn set_oracle_only_takes_an_explicit_timestamp_on_devnet() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, oracle_pk, admin_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&admin_pk);
+        lyrae_group.oracles[0] = oracle_pk;
+        lyrae_group.num_oracles = 1;
+        let mut oracle = StubOracle::zeroed();
+        oracle.magic = 0x6F676E4D;
+
+        let (mut group_lamports, mut oracle_lamports) = (0, 0);
+        let (mut admin_lamports, mut admin_data) = (0, [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let oracle_ai = new_account_info(
+            &oracle_pk,
+            &mut oracle_lamports,
+            bytes_of_mut(&mut oracle),
+            &program_id,
+        );
+        let mut admin_ai =
+            new_account_info(&admin_pk, &mut admin_lamports, &mut admin_data, &program_id);
+        admin_ai.is_signer = true;
+        let accounts = [group_ai, oracle_ai, admin_ai];
+
+        let price = I80F48::from_num(2);
+        let result = Processor::set_oracle(&program_id, &accounts, price, 1234);
+        if cfg!(feature = "devnet") {
+            result.unwrap();
+            assert_eq!(StubOracle::load(&accounts[1]).unwrap().last_update, 1234);
+        } else {
+            assert_eq!(error_code(result), Some(LyraeErrorCode::InvalidParam));
+            assert_eq!(StubOracle::load(&accounts[1]).unwrap().last_update, 0);
+        }
+
+        Processor::set_oracle(&program_id, &accounts, price, 0).unwrap();
+        let oracle = StubOracle::load(&accounts[1]).unwrap();
+        assert_eq!(oracle.price, price);
+        assert_eq!(oracle.last_update, test_syscalls::NOW_TS);
+    }
+
+    #[test]
+    fn compute_health_returns_the_health_cache_values() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, account_pk, cache_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (dex_pk, open_orders_pk, signer_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 2;
+        lyrae_group.lyrae_cache = cache_pk;
+        lyrae_group.dex_program_id = dex_pk;
+        lyrae_group.signer_key = signer_pk;
+        lyrae_group.spot_markets[0] = SpotMarketInfo {
+            spot_market: Pubkey::new_unique(),
+            maint_asset_weight: I80F48::from_num(0.9),
+            init_asset_weight: I80F48::from_num(0.8),
+            maint_liab_weight: I80F48::from_num(1.1),
+            init_liab_weight: I80F48::from_num(1.2),
+            liquidation_fee: I80F48::from_num(0.05),
+        };
+        let perp_market_info = &mut lyrae_group.perp_markets[1];
+        perp_market_info.perp_market = Pubkey::new_unique();
+        perp_market_info.maint_asset_weight = I80F48::from_num(0.95);
+        perp_market_info.init_asset_weight = I80F48::from_num(0.9);
+        perp_market_info.maint_liab_weight = I80F48::from_num(1.05);
+        perp_market_info.init_liab_weight = I80F48::from_num(1.1);
+        perp_market_info.base_lot_size = 10;
+        perp_market_info.quote_lot_size = 1;
+
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        for (i, price) in [(0, 2), (1, 3)] {
+            lyrae_cache.price_cache[i].price = I80F48::from_num(price);
+            lyrae_cache.price_cache[i].last_update = test_syscalls::NOW_TS;
+        }
+        for i in [0, QUOTE_INDEX] {
+            lyrae_cache.root_bank_cache[i].deposit_index = ONE_I80F48;
+            lyrae_cache.root_bank_cache[i].borrow_index = ONE_I80F48;
+            lyrae_cache.root_bank_cache[i].last_update = test_syscalls::NOW_TS;
+        }
+        lyrae_cache.perp_market_cache[1].last_update = test_syscalls::NOW_TS;
+
+        // 10 of token 0 deposited plus 5 more free on the dex, and long 2 lots of perp 1
+        let mut lyrae_account = new_lyrae_account(&Pubkey::new_unique());
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.deposits[0] = I80F48::from_num(10);
+        lyrae_account.deposits[QUOTE_INDEX] = I80F48::from_num(100);
+        lyrae_account.in_margin_basket[0] = true;
+        lyrae_account.num_in_margin_basket = 1;
+        lyrae_account.spot_open_orders[0] = open_orders_pk;
+        lyrae_account.perp_accounts[1].base_position = 2;
+        lyrae_account.perp_accounts[1].quote_position = I80F48::from_num(-50);
+        let mut open_orders = new_open_orders(&signer_pk);
+        open_orders.native_coin_free = 5;
+        open_orders.native_coin_total = 5;
+        let mut open_orders_data = dex_account_data(&open_orders);
+
+        let (mut group_lamports, mut account_lamports, mut cache_lamports) = (0, 0, 0);
+        let (mut open_orders_lamports, mut filler_lamports, mut filler_data) = (0, 0, [0u8; 0]);
+        let filler_pk = Pubkey::default();
+        let mut accounts = vec![
+            new_account_info(
+                &group_pk,
+                &mut group_lamports,
+                bytes_of_mut(&mut *lyrae_group),
+                &program_id,
+            ),
+            new_account_info(
+                &account_pk,
+                &mut account_lamports,
+                bytes_of_mut(&mut *lyrae_account),
+                &program_id,
+            ),
+            new_account_info(
+                &cache_pk,
+                &mut cache_lamports,
+                bytes_of_mut(&mut *lyrae_cache),
+                &program_id,
+            ),
+            new_account_info(
+                &open_orders_pk,
+                &mut open_orders_lamports,
+                &mut open_orders_data,
+                &dex_pk,
+            ),
         ];
-        solana_program::program::invoke_signed_unchecked(
-            &instruction,
-            &account_infos,
-            signers_seeds,
-        )
-    } else {
-        let account_infos = [
-            dex_prog_ai.clone(), // Have to add account of the program id
-            spot_market_ai.clone(),
-            open_orders_ai.clone(),
-            dex_request_queue_ai.clone(),
-            dex_event_queue_ai.clone(),
-            bids_ai.clone(),
-            asks_ai.clone(),
-            vault_ai.clone(),
-            signer_ai.clone(),
-            dex_base_ai.clone(),
-            dex_quote_ai.clone(),
-            token_prog_ai.clone(),
-            signer_ai.clone(),
+        let filler_ai =
+            new_account_info(&filler_pk, &mut filler_lamports, &mut filler_data, &program_id);
+        accounts.resize(3 + MAX_PAIRS, filler_ai);
+
+        Processor::compute_health(&program_id, &accounts).unwrap();
+        let data = test_syscalls::return_data().unwrap();
+        let values: Vec<I80F48> = data
+            .chunks(16)
+            .map(|bytes| {
+                I80F48::from_bits(i128::from_le_bytes(<[u8; 16]>::try_from(bytes).unwrap()))
+            })
+            .collect();
+
+        let lyrae_group = LyraeGroup::load(&accounts[0]).unwrap();
+        let lyrae_account = LyraeAccount::load(&accounts[1]).unwrap();
+        let lyrae_cache = LyraeCache::load(&accounts[2]).unwrap();
+        let active_assets = UserActiveAssets::new(&lyrae_group, &lyrae_account, vec![]);
+        assert!(active_assets.spot[0] && active_assets.perps[1]);
+        let mut health_cache = HealthCache::new(active_assets);
+        let open_orders_ais = array_ref![accounts, 3, MAX_PAIRS];
+        health_cache
+            .init_vals(&lyrae_group, &lyrae_cache, &lyrae_account, open_orders_ais)
+            .unwrap();
+        let expected = vec![
+            health_cache.get_health(&lyrae_group, HealthType::Init),
+            health_cache.get_health(&lyrae_group, HealthType::Maint),
+            health_cache.get_equity(&lyrae_group),
         ];
-        solana_program::program::invoke_signed_unchecked(
-            &instruction,
-            &account_infos,
-            signers_seeds,
-        )
+        assert_eq!(values, expected);
+        // 100 + 15 * 2 - 50 + 2 * 10 * 3
+        assert!((values[2] - I80F48::from_num(140)).abs() < I80F48::from_num(0.000001));
+        assert!(values[0] < values[1] && values[1] < values[2]);
     }
-}
 
-fn invoke_init_open_orders<'a>(
-    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
-    open_orders_ai: &AccountInfo<'a>,
-    signer_ai: &AccountInfo<'a>,
-    spot_market_ai: &AccountInfo<'a>,
-    rent_ai: &AccountInfo<'a>,
-    signers_seeds: &[&[&[u8]]],
-) -> ProgramResult {
-    let data = serum_dex::instruction::MarketInstruction::InitOpenOrders.pack();
-    let instruction = Instruction {
-        program_id: *dex_prog_ai.key,
-        data,
-        accounts: vec![
-            AccountMeta::new(*open_orders_ai.key, false),
-            AccountMeta::new_readonly(*signer_ai.key, true),
-            AccountMeta::new_readonly(*spot_market_ai.key, false),
-            AccountMeta::new_readonly(*rent_ai.key, false),
-        ],
-    };
+    #[test]
+    fn close_spot_open_orders_rejects_open_orders_of_another_owner() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, account_pk, owner_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (dex_pk, spot_market_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (open_orders_pk, signer_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 1;
+        lyrae_group.dex_program_id = dex_pk;
+        lyrae_group.signer_key = signer_pk;
+        lyrae_group.spot_markets[0].spot_market = spot_market_pk;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.spot_open_orders[0] = open_orders_pk;
+        lyrae_account.in_margin_basket[0] = true;
+        lyrae_account.num_in_margin_basket = 1;
+        // An empty OpenOrders that some other program controls on the dex
+        let mut open_orders_data = dex_account_data(&new_open_orders(&Pubkey::new_unique()));
+
+        let (mut group_lamports, mut account_lamports, mut owner_lamports) = (0, 0, 0);
+        let (mut open_orders_lamports, mut owner_data) = (0, [0u8; 0]);
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let account_ai = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let open_orders_ai = new_account_info(
+            &open_orders_pk,
+            &mut open_orders_lamports,
+            &mut open_orders_data,
+            &dex_pk,
+        );
+        // The owner stands in for the dex accounts that are only passed on to the dex
+        let mut accounts = vec![group_ai, account_ai, owner_ai.clone(), owner_ai.clone()];
+        accounts.extend([open_orders_ai, owner_ai.clone(), owner_ai]);
+        for (i, key) in [(3, &dex_pk), (5, &spot_market_pk), (6, &signer_pk)] {
+            accounts[i].key = key;
+        }
 
-    let account_infos = [
-        dex_prog_ai.clone(),
-        open_orders_ai.clone(),
-        signer_ai.clone(),
-        spot_market_ai.clone(),
-        rent_ai.clone(),
-    ];
-    solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
-}
+        let result = Processor::close_spot_open_orders(&program_id, &accounts);
+        assert_eq!(error_code(result), Some(LyraeErrorCode::Default));
+        let lyrae_account = LyraeAccount::load(&accounts[1]).unwrap();
+        assert!(lyrae_account.in_margin_basket[0]);
+        assert_eq!(lyrae_account.spot_open_orders[0], open_orders_pk);
+        drop(lyrae_account);
 
-fn invoke_close_open_orders<'a>(
-    dex_prog_ai: &AccountInfo<'a>, // Have to add account of the program id
-    open_orders_ai: &AccountInfo<'a>,
-    signer_ai: &AccountInfo<'a>,
-    destination_ai: &AccountInfo<'a>,
-    spot_market_ai: &AccountInfo<'a>,
-    signers_seeds: &[&[&[u8]]],
-) -> ProgramResult {
-    let data = serum_dex::instruction::MarketInstruction::CloseOpenOrders.pack();
+        accounts[4]
+            .try_borrow_mut_data()
+            .unwrap()
+            .copy_from_slice(&dex_account_data(&new_open_orders(&signer_pk)));
+        Processor::close_spot_open_orders(&program_id, &accounts).unwrap();
+        let lyrae_account = LyraeAccount::load(&accounts[1]).unwrap();
+        assert!(!lyrae_account.in_margin_basket[0]);
+        assert_eq!(lyrae_account.spot_open_orders[0], Pubkey::default());
+    }
 
-    let instruction = Instruction {
-        program_id: *dex_prog_ai.key,
-        data,
-        accounts: vec![
-            AccountMeta::new(*open_orders_ai.key, false),
-            AccountMeta::new_readonly(*signer_ai.key, true),
-            AccountMeta::new(*destination_ai.key, false),
-            AccountMeta::new_readonly(*spot_market_ai.key, false),
-        ],
-    };
+    #[test]
+    fn deposit_updates_a_stale_root_bank_when_given_the_node_banks() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, cache_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (account_pk, owner_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (root_bank_pk, node_bank_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (vault_pk, token_account_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.lyrae_cache = cache_pk;
+        lyrae_group.valid_interval = 10;
+        lyrae_group.tokens[QUOTE_INDEX].root_bank = root_bank_pk;
+        // Both the cache and the root bank were last updated a year ago
+        let last_update = test_syscalls::NOW_TS - 31_536_000;
+        let mut lyrae_cache = Box::new(LyraeCache::zeroed());
+        lyrae_cache.meta_data = MetaData::new(DataType::LyraeCache, 0, true);
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].deposit_index = ONE_I80F48;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].borrow_index = ONE_I80F48;
+        lyrae_cache.root_bank_cache[QUOTE_INDEX].last_update = last_update;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        let mut root_bank = RootBank::zeroed();
+        root_bank.meta_data = MetaData::new(DataType::RootBank, 0, true);
+        root_bank.optimal_util = I80F48::from_num(0.5);
+        root_bank.optimal_rate = I80F48::from_num(0.1);
+        root_bank.max_rate = I80F48::from_num(1);
+        root_bank.num_node_banks = 1;
+        root_bank.node_banks[0] = node_bank_pk;
+        root_bank.deposit_index = ONE_I80F48;
+        root_bank.borrow_index = ONE_I80F48;
+        root_bank.last_updated = last_update;
+        let mut node_bank = NodeBank::zeroed();
+        node_bank.meta_data = MetaData::new(DataType::NodeBank, 0, true);
+        node_bank.vault = vault_pk;
+        node_bank.deposits = I80F48::from_num(100);
+        node_bank.borrows = I80F48::from_num(50);
+
+        let (mut group_lamports, mut cache_lamports, mut account_lamports) = (0, 0, 0);
+        let (mut root_bank_lamports, mut node_bank_lamports) = (0, 0);
+        let (mut owner_lamports, mut vault_lamports, mut token_account_lamports) = (0, 0, 0);
+        let (mut owner_data, mut vault_data, mut token_account_data) =
+            ([0u8; 0], [0u8; 0], [0u8; 0]);
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let token_account_ai = new_account_info(
+            &token_account_pk,
+            &mut token_account_lamports,
+            &mut token_account_data,
+            &spl_token::ID,
+        );
+        let mut token_prog_ai = owner_ai.clone();
+        token_prog_ai.key = &spl_token::ID;
+        let node_bank_ai = new_account_info(
+            &node_bank_pk,
+            &mut node_bank_lamports,
+            bytes_of_mut(&mut node_bank),
+            &program_id,
+        );
+        let mut accounts = vec![
+            new_account_info(
+                &group_pk,
+                &mut group_lamports,
+                bytes_of_mut(&mut *lyrae_group),
+                &program_id,
+            ),
+            new_account_info(
+                &account_pk,
+                &mut account_lamports,
+                bytes_of_mut(&mut *lyrae_account),
+                &program_id,
+            ),
+            owner_ai,
+            new_account_info(
+                &cache_pk,
+                &mut cache_lamports,
+                bytes_of_mut(&mut *lyrae_cache),
+                &program_id,
+            ),
+            new_account_info(
+                &root_bank_pk,
+                &mut root_bank_lamports,
+                bytes_of_mut(&mut root_bank),
+                &program_id,
+            ),
+            node_bank_ai.clone(),
+            new_account_info(&vault_pk, &mut vault_lamports, &mut vault_data, &spl_token::ID),
+            token_prog_ai,
+            token_account_ai.clone(),
+        ];
 
-    let account_infos = [
-        dex_prog_ai.clone(),
-        open_orders_ai.clone(),
-        signer_ai.clone(),
-        destination_ai.clone(),
-        spot_market_ai.clone(),
-    ];
-    solana_program::program::invoke_signed(&instruction, &account_infos, signers_seeds)
-}
+        // Without the node banks the stale cache can't be used
+        let result = Processor::deposit(&program_id, &accounts, 10);
+        assert_eq!(error_code(result), Some(LyraeErrorCode::InvalidRootBankCache));
 
-/*
-TODO test order types
- */
-fn invoke_transfer_lamports<'a>(
-    src_ai: &AccountInfo<'a>,
-    dst_ai: &AccountInfo<'a>,
-    system_prog_ai: &AccountInfo<'a>,
-    quantity: u64,
-    signers_seeds: &[&[&[u8]]],
-) -> ProgramResult {
-    solana_program::program::invoke_signed(
-        &solana_program::system_instruction::transfer(src_ai.key, dst_ai.key, quantity),
-        &[src_ai.clone(), dst_ai.clone(), system_prog_ai.clone()],
-        signers_seeds,
-    )
-}
+        // The root bank has no deposit fee, so the fee vault slot is never read
+        accounts.extend([token_account_ai, node_bank_ai]);
+        Processor::deposit(&program_id, &accounts, 10).unwrap();
+        let lyrae_cache = LyraeCache::load(&accounts[3]).unwrap();
+        let root_bank_cache = &lyrae_cache.root_bank_cache[QUOTE_INDEX];
+        assert_eq!(root_bank_cache.last_update, test_syscalls::NOW_TS);
+        assert!(root_bank_cache.deposit_index > ONE_I80F48);
+        assert!(root_bank_cache.borrow_index > root_bank_cache.deposit_index);
+        let lyrae_account = LyraeAccount::load(&accounts[1]).unwrap();
+        assert_eq!(
+            lyrae_account.deposits[QUOTE_INDEX],
+            I80F48::from_num(10).checked_div(root_bank_cache.deposit_index).unwrap()
+        );
+        let logs = test_syscalls::emitted::<UpdateRootBankLog>();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].deposit_index, root_bank_cache.deposit_index.to_bits());
+    }
 
-fn seed_and_create_pda<'a>(
-    program_id: &Pubkey,
-    funder: &AccountInfo<'a>,
-    rent: &Rent,
-    space: usize,
-    owner: &Pubkey,
-    system_program: &AccountInfo<'a>,
-    pda_account: &AccountInfo<'a>,
-    seeds: &[&[u8]],
-    funder_seeds: &[&[u8]],
-) -> LyraeResult {
-    let (pda_address, bump) = Pubkey::find_program_address(seeds, program_id);
-    check!(&pda_address == pda_account.key, LyraeErrorCode::InvalidAccount)?;
-    create_pda_account(
-        funder,
-        rent,
-        space,
-        owner,
-        system_program,
-        pda_account,
-        &[seeds, &[&[bump]]].concat(),
-        funder_seeds,
-    )?;
-    Ok(())
-}
-fn create_pda_account<'a>(
-    funder: &AccountInfo<'a>,
-    rent: &Rent,
-    space: usize,
-    owner: &Pubkey,
-    system_program: &AccountInfo<'a>,
-    new_pda_account: &AccountInfo<'a>,
-    new_pda_signer_seeds: &[&[u8]],
-    funder_seeds: &[&[u8]],
-) -> ProgramResult {
-    if new_pda_account.lamports() > 0 {
-        let required_lamports =
-            rent.minimum_balance(space).max(1).saturating_sub(new_pda_account.lamports());
+    /// The data of an empty dex order book side with `flags`, in a u64 buffer. The account data
+    /// has to start at byte 3 of the buffer so the slab after the "serum" prefix and the header
+    /// is aligned.
+    fn new_dex_book_data(flags: serum_dex::state::AccountFlag) -> Vec<u64> {
+        let mut data = vec![0u64; 64];
+        let bytes: &mut [u8] = bytemuck::cast_slice_mut(&mut data);
+        bytes[3..8].copy_from_slice(b"serum");
+        let flags = (serum_dex::state::AccountFlag::Initialized | flags).bits();
+        bytes[8..16].copy_from_slice(&flags.to_le_bytes());
+        data
+    }
 
-        let transfer_seeds = if funder_seeds.is_empty() { vec![] } else { vec![funder_seeds] };
-        if required_lamports > 0 {
-            invoke_transfer_lamports(
-                funder,
-                new_pda_account,
-                system_program,
-                required_lamports,
-                transfer_seeds.as_slice(),
-            )?;
+    #[test]
+    fn cancel_all_spot_orders_cancels_in_each_market_with_orders() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, account_pk, owner_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (dex_pk, signer_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let spot_market_pks: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let open_orders_pks: Vec<Pubkey> = (0..3).map(|_| Pubkey::new_unique()).collect();
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 3;
+        lyrae_group.dex_program_id = dex_pk;
+        lyrae_group.signer_key = signer_pk;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        for i in 0..3 {
+            lyrae_group.spot_markets[i].spot_market = spot_market_pks[i];
+            lyrae_account.spot_open_orders[i] = open_orders_pks[i];
+        }
+        // Market 0 has an order, market 1 is in the basket without any and market 2 is not in
+        // the basket at all
+        lyrae_account.in_margin_basket[0] = true;
+        lyrae_account.in_margin_basket[1] = true;
+        lyrae_account.num_in_margin_basket = 2;
+        let mut open_orders = new_open_orders(&signer_pk);
+        open_orders.free_slot_bits = !1;
+        open_orders.native_coin_total = 3;
+        let mut open_orders_data: Vec<Vec<u8>> = vec![
+            dex_account_data(&open_orders),
+            dex_account_data(&new_open_orders(&signer_pk)),
+            dex_account_data(&new_open_orders(&signer_pk)),
+        ];
+        // The order is no longer on the book, so there is nothing to pass on to the dex
+        let (bids_pk, asks_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut market = serum_dex::state::MarketState::zeroed();
+        market.account_flags = (serum_dex::state::AccountFlag::Initialized
+            | serum_dex::state::AccountFlag::Market)
+            .bits();
+        market.bids = bids_pk.to_aligned_bytes();
+        market.asks = asks_pk.to_aligned_bytes();
+        let mut market_data = dex_account_data(&market);
+        let mut bids_data = new_dex_book_data(serum_dex::state::AccountFlag::Bids);
+        let mut asks_data = new_dex_book_data(serum_dex::state::AccountFlag::Asks);
+
+        let (mut group_lamports, mut account_lamports, mut owner_lamports) = (0, 0, 0);
+        let (mut open_orders_lamports, mut owner_data) = (vec![0; 3], [0u8; 0]);
+        let (mut market_lamports, mut bids_lamports, mut asks_lamports) = (0, 0, 0);
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        let mut accounts = vec![
+            new_account_info(
+                &group_pk,
+                &mut group_lamports,
+                bytes_of_mut(&mut *lyrae_group),
+                &program_id,
+            ),
+            owner_ai.clone(),
+            new_account_info(
+                &account_pk,
+                &mut account_lamports,
+                bytes_of_mut(&mut *lyrae_account),
+                &program_id,
+            ),
+            owner_ai.clone(),
+            owner_ai.clone(),
+        ];
+        accounts[3].key = &dex_pk;
+        accounts[4].key = &signer_pk;
+        // The owner stands in for the dex accounts that are only passed on to the dex
+        let open_orders_ais: Vec<AccountInfo> = open_orders_pks
+            .iter()
+            .zip(open_orders_lamports.iter_mut().zip(open_orders_data.iter_mut()))
+            .map(|(pk, (lamports, data))| new_account_info(pk, lamports, data, &dex_pk))
+            .collect();
+        for (spot_market_pk, open_orders_ai) in
+            spot_market_pks[1..].iter().zip(open_orders_ais[1..].iter()).rev()
+        {
+            let mut spot_market_ai = owner_ai.clone();
+            spot_market_ai.key = spot_market_pk;
+            accounts.extend([spot_market_ai, owner_ai.clone(), owner_ai.clone()]);
+            accounts.extend([open_orders_ai.clone(), owner_ai.clone()]);
         }
+        accounts.extend([
+            new_account_info(&spot_market_pks[0], &mut market_lamports, &mut market_data, &dex_pk),
+            new_account_info(
+                &bids_pk,
+                &mut bids_lamports,
+                &mut bytemuck::cast_slice_mut(&mut bids_data)[3..],
+                &dex_pk,
+            ),
+            new_account_info(
+                &asks_pk,
+                &mut asks_lamports,
+                &mut bytemuck::cast_slice_mut(&mut asks_data)[3..],
+                &dex_pk,
+            ),
+            open_orders_ais[0].clone(),
+            owner_ai,
+        ]);
 
-        solana_program::program::invoke_signed(
-            &solana_program::system_instruction::allocate(new_pda_account.key, space as u64),
-            &[new_pda_account.clone(), system_program.clone()],
-            &[new_pda_signer_seeds],
-        )?;
+        Processor::cancel_all_spot_orders(&program_id, &accounts, 8).unwrap();
+        let logs = test_syscalls::emitted::<OpenOrdersBalanceLog>();
+        assert_eq!(logs.len(), 1);
+        assert_eq!((logs[0].market_index, logs[0].base_total), (0, 3));
 
-        solana_program::program::invoke_signed(
-            &solana_program::system_instruction::assign(new_pda_account.key, owner),
-            &[new_pda_account.clone(), system_program.clone()],
-            &[new_pda_signer_seeds],
-        )
-    } else {
-        let all_signer_seeds = if funder_seeds.is_empty() {
-            vec![new_pda_signer_seeds]
-        } else {
-            vec![funder_seeds, new_pda_signer_seeds]
-        };
-        solana_program::program::invoke_signed_unchecked(
-            &solana_program::system_instruction::create_account(
-                funder.key,
-                new_pda_account.key,
-                rent.minimum_balance(space).max(1),
-                space as u64,
-                owner,
-            ),
-            &[funder.clone(), new_pda_account.clone(), system_program.clone()],
-            all_signer_seeds.as_slice(),
-        )
+        // Every market's open orders must be the account's, even the ones that are skipped
+        accounts.swap(8, 13);
+        let result = Processor::cancel_all_spot_orders(&program_id, &accounts, 8);
+        assert_eq!(error_code(result), Some(LyraeErrorCode::InvalidOpenOrdersAccount));
     }
-}
 
-/// Transfer lamports from a src account owned by the currently executing program id
-fn program_transfer_lamports(
-    src_ai: &AccountInfo,
-    dst_ai: &AccountInfo,
-    quantity: u64,
-) -> LyraeResult<()> {
-    let src_lamports = src_ai.lamports().checked_sub(quantity).ok_or(math_err!())?;
-    **src_ai.lamports.borrow_mut() = src_lamports;
+    #[test]
+    fn capped_token_liquidation_must_improve_health_enough() {
+        // Each quote token of liab taken improves init health by about 0.2
+        let min_improvement_group = || {
+            let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+            lyrae_group.set_min_liquidation_health_improvement(10);
+            lyrae_group
+        };
 
-    let dst_lamports = dst_ai.lamports().checked_add(quantity).ok_or(math_err!())?;
-    **dst_ai.lamports.borrow_mut() = dst_lamports;
-    Ok(())
-}
+        let (result, _) = liquidate_quote_borrow(min_improvement_group(), ONE_I80F48, ONE_I80F48);
+        assert_eq!(error_code(result), Some(LyraeErrorCode::LiquidationTooSmall));
 
-fn cancel_all_advanced_orders<'a>(
-    advanced_orders_ai: &AccountInfo<'a>,
-    advanced_orders: &mut AdvancedOrders,
-    agent_ai: &AccountInfo<'a>,
-) -> LyraeResult<()> {
-    let mut total_fee = 0u64;
-    for i in 0..MAX_ADVANCED_ORDERS {
-        if advanced_orders.orders[i].is_active {
-            advanced_orders.orders[i].is_active = false;
-            total_fee += ADVANCED_ORDER_FEE;
+        let max_liab_transfer = I80F48::from_num(100);
+        let (result, liqee) =
+            liquidate_quote_borrow(min_improvement_group(), max_liab_transfer, ONE_I80F48);
+        result.unwrap();
+        assert_eq!(liqee.borrows[QUOTE_INDEX], I80F48::from_num(800));
+    }
+
+    #[test]
+    fn reassign_spot_open_orders_replaces_an_empty_old_dex_open_orders() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, account_pk, owner_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (old_dex_pk, old_open_orders_pk, old_spot_market_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (dex_pk, open_orders_pk, spot_market_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let signer_pk = Pubkey::new_unique();
+        let mut lyrae_group = new_lyrae_group(&Pubkey::new_unique());
+        lyrae_group.num_oracles = 1;
+        lyrae_group.dex_program_id = dex_pk;
+        lyrae_group.signer_key = signer_pk;
+        lyrae_group.spot_markets[0].spot_market = spot_market_pk;
+        let mut lyrae_account = new_lyrae_account(&owner_pk);
+        lyrae_account.lyrae_group = group_pk;
+        lyrae_account.spot_open_orders[0] = old_open_orders_pk;
+        lyrae_account.in_margin_basket[0] = true;
+        lyrae_account.num_in_margin_basket = 1;
+        // The old OpenOrders still holds some base
+        let mut old_open_orders = new_open_orders(&signer_pk);
+        old_open_orders.market = old_spot_market_pk.to_aligned_bytes();
+        old_open_orders.native_coin_total = 1;
+        let mut old_open_orders_data = dex_account_data(&old_open_orders);
+        let mut open_orders_data = dex_account_data(&serum_dex::state::OpenOrders::zeroed());
+
+        let (mut group_lamports, mut account_lamports, mut owner_lamports) = (0, 0, 0);
+        let (mut old_open_orders_lamports, mut open_orders_lamports) = (0, 0);
+        let mut owner_data = [0u8; 0];
+        let mut owner_ai =
+            new_account_info(&owner_pk, &mut owner_lamports, &mut owner_data, &program_id);
+        owner_ai.is_signer = true;
+        // The owner stands in for the accounts that are only passed on to the dex
+        let mut accounts = vec![owner_ai.clone(); 11];
+        let keys = [
+            (3, &old_dex_pk),
+            (5, &old_spot_market_pk),
+            (6, &dex_pk),
+            (8, &spot_market_pk),
+            (9, &signer_pk),
+        ];
+        for (i, key) in keys {
+            accounts[i].key = key;
         }
+        accounts[0] = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        accounts[1] = new_account_info(
+            &account_pk,
+            &mut account_lamports,
+            bytes_of_mut(&mut *lyrae_account),
+            &program_id,
+        );
+        accounts[4] = new_account_info(
+            &old_open_orders_pk,
+            &mut old_open_orders_lamports,
+            &mut old_open_orders_data,
+            &old_dex_pk,
+        );
+        accounts[7] = new_account_info(
+            &open_orders_pk,
+            &mut open_orders_lamports,
+            &mut open_orders_data,
+            &dex_pk,
+        );
+
+        let result = Processor::reassign_spot_open_orders(&program_id, &accounts);
+        assert_eq!(error_code(result), Some(LyraeErrorCode::InvalidAccountState));
+
+        old_open_orders.native_coin_total = 0;
+        accounts[4]
+            .try_borrow_mut_data()
+            .unwrap()
+            .copy_from_slice(&dex_account_data(&old_open_orders));
+        // Only the old dex program can close the old OpenOrders
+        accounts[3].key = &dex_pk;
+        let result = Processor::reassign_spot_open_orders(&program_id, &accounts);
+        assert_eq!(error_code(result), Some(LyraeErrorCode::InvalidProgramId));
+
+        accounts[3].key = &old_dex_pk;
+        Processor::reassign_spot_open_orders(&program_id, &accounts).unwrap();
+        let lyrae_account = LyraeAccount::load(&accounts[1]).unwrap();
+        assert_eq!(lyrae_account.spot_open_orders[0], open_orders_pk);
+        assert!(!lyrae_account.in_margin_basket[0]);
+        assert_eq!(lyrae_account.num_in_margin_basket, 0);
     }
-    program_transfer_lamports(advanced_orders_ai, agent_ai, total_fee)
-}
 
-// Returns asset_weight and liab_weight
-pub fn get_leverage_weights(leverage: I80F48) -> (I80F48, I80F48) {
-    (
-        (leverage - ONE_I80F48).checked_div(leverage).unwrap(),
-        (leverage + ONE_I80F48).checked_div(leverage).unwrap(),
-    )
+    #[test]
+    fn emergency_set_asset_weight_only_lowers_weights() {
+        test_syscalls::init();
+        let program_id = Pubkey::new_unique();
+        let (group_pk, spot_market_pk, admin_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let mut lyrae_group = new_lyrae_group(&admin_pk);
+        lyrae_group.num_oracles = 1;
+        lyrae_group.spot_markets[0].spot_market = spot_market_pk;
+        lyrae_group.spot_markets[0].maint_asset_weight = I80F48::from_num(0.9);
+        lyrae_group.spot_markets[0].init_asset_weight = I80F48::from_num(0.8);
+
+        let (mut group_lamports, mut admin_lamports, mut admin_data) = (0, 0, [0u8; 0]);
+        let mut admin_ai =
+            new_account_info(&admin_pk, &mut admin_lamports, &mut admin_data, &program_id);
+        admin_ai.is_signer = true;
+        let mut spot_market_ai = admin_ai.clone();
+        spot_market_ai.key = &spot_market_pk;
+        let group_ai = new_account_info(
+            &group_pk,
+            &mut group_lamports,
+            bytes_of_mut(&mut *lyrae_group),
+            &program_id,
+        );
+        let accounts = [group_ai, spot_market_ai, admin_ai];
+        let set_weights = |init: f64, maint: f64| {
+            Processor::emergency_set_asset_weight(
+                &program_id,
+                &accounts,
+                I80F48::from_num(init),
+                I80F48::from_num(maint),
+            )
+        };
+
+        for &(init, maint) in &[(0.85, 0.9), (0.5, 0.95), (0.6, 0.6), (-0.1, 0.5)] {
+            assert_eq!(error_code(set_weights(init, maint)), Some(LyraeErrorCode::InvalidParam));
+        }
+        set_weights(0.5, 0.75).unwrap();
+
+        let logs = test_syscalls::emitted::<EmergencyAssetWeightLog>();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].old_init_asset_weight, I80F48::from_num(0.8).to_bits());
+        assert_eq!(logs[0].init_asset_weight, I80F48::from_num(0.5).to_bits());
+        let lyrae_group = LyraeGroup::load(&accounts[0]).unwrap();
+        assert_eq!(lyrae_group.spot_markets[0].init_asset_weight, I80F48::from_num(0.5));
+        assert_eq!(lyrae_group.spot_markets[0].maint_asset_weight, I80F48::from_num(0.75));
+    }
 }
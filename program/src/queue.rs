@@ -1,10 +1,10 @@
 use crate::error::{check_assert, LyraeErrorCode, LyraeResult, SourceFileId};
 use crate::matching::Side;
-use crate::state::{DataType, MetaData, PerpMarket};
+use crate::state::{DataType, MetaData, PerpMarket, ZERO_I80F48};
 use crate::utils::strip_header_mut;
 
 use fixed::types::I80F48;
-use lyrae_logs::FillLog;
+use lyrae_logs::{FillLog, LiquidateEventLog};
 use lyrae_macro::Pod;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use safe_transmute::{self, trivial::TriviallyTransmutable};
@@ -90,6 +90,15 @@ impl<'a, H: QueueHeader> Queue<'a, H> {
         Some(&mut self.buf[self.header.head()])
     }
 
+    /// Item at position `index` counted from the front
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut H::Item> {
+        if index >= self.len() {
+            return None;
+        }
+        let slot = (self.header.head() + index) % self.buf.len();
+        Some(&mut self.buf[slot])
+    }
+
     pub fn pop_front(&mut self) -> Result<H::Item, ()> {
         if self.empty() {
             return Err(());
@@ -220,6 +229,9 @@ pub enum EventType {
     Fill,
     Out,
     Liquidate,
+    /// A fill already processed by ConsumeEventsForAccount ahead of the events before it; it
+    /// stays in place until it reaches the front of the queue and is popped
+    Consumed,
 }
 
 const EVENT_SIZE: usize = 200;
@@ -240,7 +252,7 @@ pub struct FillEvent {
     pub maker_out: bool, // true if maker order quantity == 0
     pub version: u8,
     pub market_fees_applied: bool,
-    pub padding: [u8; 2],
+    pub taker_fee_discount_bps: u16, // msrm fee tier discount included in taker_fee
     pub timestamp: u64,
     pub seq_num: usize, // note: usize same as u64
 
@@ -286,6 +298,7 @@ impl FillEvent {
         price: i64,
         quantity: i64,
         version: u8,
+        taker_fee_discount_bps: u16,
     ) -> FillEvent {
         Self {
             event_type: EventType::Fill as u8,
@@ -294,7 +307,7 @@ impl FillEvent {
             maker_out,
             version,
             market_fees_applied: true, // Since lyrae v3.3.5, market fees are adjusted at matching time
-            padding: [0u8; 2],
+            taker_fee_discount_bps,
             timestamp,
             seq_num,
             maker,
@@ -325,7 +338,14 @@ impl FillEvent {
         }
     }
 
-    pub fn to_fill_log(&self, lyrae_group: Pubkey, market_index: usize) -> FillLog {
+    /// `market_taker_fee` is the market's taker fee, after the taker's msrm fee tier discount,
+    /// without the referral fee, so the referral part of `taker_fee` can be logged separately
+    pub fn to_fill_log(
+        &self,
+        lyrae_group: Pubkey,
+        market_index: usize,
+        market_taker_fee: I80F48,
+    ) -> FillLog {
         FillLog {
             lyrae_group,
             market_index: market_index as u64,
@@ -346,6 +366,8 @@ impl FillEvent {
             taker_fee: self.taker_fee.to_bits(),
             price: self.price,
             quantity: self.quantity,
+            referrer_fee: (self.taker_fee - market_taker_fee).to_bits(),
+            maker_rebate: (-self.maker_fee).max(ZERO_I80F48).to_bits(),
         }
     }
 }
@@ -426,7 +448,43 @@ impl LiquidateEvent {
             padding1: [0u8; EVENT_SIZE - 128],
         }
     }
+
+    pub fn to_liquidate_log(&self, lyrae_group: Pubkey, market_index: usize) -> LiquidateEventLog {
+        LiquidateEventLog {
+            lyrae_group,
+            market_index: market_index as u64,
+            timestamp: self.timestamp,
+            seq_num: self.seq_num as u64,
+            liqee: self.liqee,
+            liqor: self.liqor,
+            price: self.price.to_bits(),
+            quantity: self.quantity,
+            liquidation_fee: self.liquidation_fee.to_bits(),
+        }
+    }
 }
 const_assert_eq!(size_of::<AnyEvent>(), size_of::<FillEvent>());
 const_assert_eq!(size_of::<AnyEvent>(), size_of::<OutEvent>());
 const_assert_eq!(size_of::<AnyEvent>(), size_of::<LiquidateEvent>());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn liquidate_log_mirrors_the_event() {
+        let (group_pk, liqee_pk, liqor_pk) =
+            (Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique());
+        let (price, liquidation_fee) = (I80F48::from_num(25), I80F48::from_num(0.5));
+        let event = LiquidateEvent::new(1_000, 7, liqee_pk, liqor_pk, price, -3, liquidation_fee);
+
+        let log = event.to_liquidate_log(group_pk, 2);
+        assert_eq!(log.lyrae_group, group_pk);
+        assert_eq!(log.market_index, 2);
+        assert_eq!((log.timestamp, log.seq_num), (1_000, 7));
+        assert_eq!((log.liqee, log.liqor), (liqee_pk, liqor_pk));
+        assert_eq!(I80F48::from_bits(log.price), price);
+        assert_eq!(log.quantity, -3);
+        assert_eq!(I80F48::from_bits(log.liquidation_fee), liquidation_fee);
+    }
+}
@@ -45,9 +45,25 @@ pub const INFO_LEN: usize = 32;
 pub const MAX_PERP_OPEN_ORDERS: usize = 64;
 pub const FREE_ORDER_SLOT: u8 = u8::MAX;
 pub const MAX_NUM_IN_MARGIN_BASKET: u8 = 9;
+pub const MAX_SETTLE_FEES_ACCOUNTS: usize = 8; // bound on crank_settle_fees batch size for compute
+pub const MAX_REDEEM_LYR_ACCOUNTS: usize = 8; // bound on crank_redeem_lyr batch size for compute
+pub const MAX_AUTO_SETTLE_ACCOUNTS: usize = 8; // bound on crank_auto_settle_funding batch size
+pub const MAX_EXECUTE_TRIGGER_ORDERS: usize = 8; // bound on execute_perp_trigger_orders_multi batch
+pub const MAX_UPDATE_ALL_CACHES_ITEMS: usize = 8; // bound on update_all_caches markets + root banks
+pub const DELEGATE_PLACE: u8 = 1 << 0; // place and amend spot and perp orders, liquidate as liqor
+pub const DELEGATE_CANCEL: u8 = 1 << 1; // cancel spot and perp orders
+pub const DELEGATE_TRIGGER: u8 = 1 << 2; // add and remove perp trigger orders
+pub const DELEGATE_MANAGE: u8 = 1 << 3; // settle funds, repay borrows, redeem LYR, account upkeep
+pub const DELEGATE_ALL: u8 =
+    DELEGATE_PLACE | DELEGATE_CANCEL | DELEGATE_TRIGGER | DELEGATE_MANAGE;
+pub const GROUP_HALTED: u8 = 1 << 0; // reject adding oracles and adding or removing markets
+pub const GROUP_PAUSED: u8 = 1 << 1; // reject deposits, borrows and order placement
 pub const INDEX_START: I80F48 = I80F48!(1_000_000);
 pub const PYTH_CONF_FILTER: I80F48 = I80F48!(0.10); // filter out pyth prices with conf > 10% of price
 pub const CENTIBPS_PER_UNIT: I80F48 = I80F48!(1_000_000);
+pub const BPS_PER_UNIT: u16 = 10_000;
+pub const MAX_MSRM_FEE_TIERS: usize = 3; // taker fee discount tiers by LyraeAccount msrm_amount
+pub const MAX_TOKEN_FEE_BPS: u16 = 100; // cap on RootBank deposit_fee_bps and withdraw_fee_bps
 
 declare_check_assert_macros!(SourceFileId::State);
 
@@ -81,6 +97,9 @@ pub enum DataType {
     AdvancedOrders,
     ReferrerMemory,
     ReferrerIdRecord,
+    CancelTtl,
+    AccountGroup,
+    MsrmFeeTiers,
 }
 
 const NUM_HEALTHS: usize = 2;
@@ -142,7 +161,10 @@ pub struct TokenInfo {
     pub mint: Pubkey,
     pub root_bank: Pubkey,
     pub decimals: u8,
-    pub padding: [u8; 7],
+    /// Only set on the quote token; the LyraeGroup's min_liquidation_health_improvement in whole
+    /// quote tokens (u32 LE). Stored as bytes to keep TokenInfo unaligned; use the accessors.
+    pub min_liquidation_health_improvement: [u8; 4],
+    pub padding: [u8; 3],
 }
 
 impl TokenInfo {
@@ -187,6 +209,21 @@ impl PerpMarketInfo {
     pub fn is_empty(&self) -> bool {
         self.perp_market == Pubkey::default()
     }
+
+    /// taker_fee reduced by `discount_bps`; negative taker fees (rebates) are not reduced
+    pub fn discounted_taker_fee(&self, discount_bps: u16) -> I80F48 {
+        if discount_bps == 0 || !self.taker_fee.is_positive() {
+            return self.taker_fee;
+        }
+        self.taker_fee * I80F48::from_num(BPS_PER_UNIT - discount_bps)
+            / I80F48::from_num(BPS_PER_UNIT)
+    }
+}
+
+/// Largest liquidation fee at which liquidating a market with these init weights still
+/// improves health, for both longs and shorts
+pub fn max_liquidation_fee(init_asset_weight: I80F48, init_liab_weight: I80F48) -> I80F48 {
+    (ONE_I80F48 - init_asset_weight).min(init_liab_weight - ONE_I80F48)
 }
 
 #[derive(Copy, Clone, Pod, Loadable)]
@@ -220,7 +257,19 @@ pub struct LyraeGroup {
     pub ref_surcharge_centibps: u32, // 100
     pub ref_share_centibps: u32,     // 80 (must be less than surcharge)
     pub ref_lyr_required: u64,
-    pub padding: [u8; 8], // padding used for future expansions
+
+    // max fraction of a liqee's perp position transferable in one liquidation; 0 means no cap
+    pub max_liquidation_fraction_bps: u16,
+    // floor applied to every market's liquidation_fee during liquidations; 0 means no floor
+    pub min_liquidation_fee_bps: u16,
+    // GROUP_HALTED and GROUP_PAUSED, set by the admin during incidents; use the accessors
+    pub pause_flags: u8,
+    /// Max Pyth conf / price in bps (u16 LE) before a price is rejected; 0 means PYTH_CONF_FILTER.
+    /// Stored as bytes because the remaining padding is not aligned; use the accessors.
+    pub pyth_conf_filter_bps: [u8; 2],
+    /// Percent of settled perp fees sent to insurance_vault instead of fees_vault; 0 sends all
+    /// of it to fees_vault. This used the last byte of padding.
+    pub fees_insurance_share_pct: u8,
 }
 
 impl LyraeGroup {
@@ -263,6 +312,65 @@ impl LyraeGroup {
         Ok(lyrae_group)
     }
 
+    pub fn get_pyth_conf_filter(&self) -> I80F48 {
+        match u16::from_le_bytes(self.pyth_conf_filter_bps) {
+            0 => PYTH_CONF_FILTER,
+            bps => I80F48::from_num(bps) / I80F48::from_num(BPS_PER_UNIT),
+        }
+    }
+
+    pub fn set_pyth_conf_filter_bps(&mut self, bps: u16) {
+        self.pyth_conf_filter_bps = bps.to_le_bytes();
+    }
+
+    /// Convert whole quote tokens, e.g. 10 for 10 USDC, to native quote
+    pub fn quote_tokens_to_native(&self, quote_tokens: u32) -> I80F48 {
+        let unit = 10u64.pow(self.tokens[QUOTE_INDEX].decimals as u32);
+        I80F48::from_num(quote_tokens) * I80F48::from_num(unit)
+    }
+
+    /// Min equity, in whole quote tokens, needed to place risk increasing orders; 0 means no
+    /// minimum. LyraeGroup has no padding left for it, so it is kept in
+    /// meta_data.extra_info[0..4].
+    pub fn get_min_collateral_to_trade(&self) -> u32 {
+        let bytes = <[u8; 4]>::try_from(&self.meta_data.extra_info[..4]).unwrap();
+        u32::from_le_bytes(bytes)
+    }
+
+    pub fn set_min_collateral_to_trade(&mut self, quote_tokens: u32) {
+        self.meta_data.extra_info[..4].copy_from_slice(&quote_tokens.to_le_bytes());
+    }
+
+    /// Min init health improvement, in whole quote tokens, that a LiquidateTokenAndToken capped
+    /// by the liqor's max_liab_transfer must achieve; 0 means no minimum. LyraeGroup has no
+    /// padding left, so it is kept on the quote TokenInfo.
+    pub fn get_min_liquidation_health_improvement(&self) -> u32 {
+        u32::from_le_bytes(self.tokens[QUOTE_INDEX].min_liquidation_health_improvement)
+    }
+
+    pub fn set_min_liquidation_health_improvement(&mut self, quote_tokens: u32) {
+        self.tokens[QUOTE_INDEX].min_liquidation_health_improvement = quote_tokens.to_le_bytes();
+    }
+
+    /// While halted, adding oracles and adding or removing markets fail
+    pub fn is_halted(&self) -> bool {
+        self.pause_flags & GROUP_HALTED != 0
+    }
+
+    /// While paused, deposits, borrows and order placement fail; cancels, settling and
+    /// liquidations still work
+    pub fn is_paused(&self) -> bool {
+        self.pause_flags & GROUP_PAUSED != 0
+    }
+
+    pub fn set_pause_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.pause_flags |= flag;
+        } else {
+            self.pause_flags &= !flag;
+        }
+    }
+
     pub fn find_oracle_index(&self, oracle_pk: &Pubkey) -> Option<usize> {
         self.oracles.iter().position(|pk| pk == oracle_pk) // TODO OPT profile
     }
@@ -287,6 +395,75 @@ impl LyraeGroup {
             .iter()
             .position(|perp_market_info| &perp_market_info.perp_market == perp_market_pk)
     }
+
+    /// Max number of base lots (absolute) that may be transferred out of a position of
+    /// `base_position` in a single liquidation. Always allows at least one lot so small
+    /// positions can still be liquidated.
+    pub fn max_liquidation_base_transfer(&self, base_position: i64) -> i64 {
+        if self.max_liquidation_fraction_bps == 0 {
+            return i64::MAX;
+        }
+        let bps = self.max_liquidation_fraction_bps as i128;
+        let max_bps = BPS_PER_UNIT as i128;
+        let cap = ((base_position as i128).abs() * bps + max_bps - 1) / max_bps;
+        (cap as i64).max(1)
+    }
+
+    /// Split a fee settlement, in native quote, into the amounts for (fees_vault, insurance_vault)
+    pub fn split_settled_fees(&self, settlement: u64) -> (u64, u64) {
+        let to_insurance =
+            (settlement as u128 * self.fees_insurance_share_pct as u128 / 100) as u64;
+        (settlement - to_insurance, to_insurance)
+    }
+
+    pub fn get_min_liquidation_fee(&self) -> I80F48 {
+        I80F48::from_num(self.min_liquidation_fee_bps) / I80F48::from_num(BPS_PER_UNIT)
+    }
+
+    /// The liquidation fee to use for a market after applying the group's floor. The floor is
+    /// ignored for a market whose init weights leave it no room, so that liquidating a long
+    /// (1 - init_asset_weight - fee) or a short (init_liab_weight - 1 - fee) still improves health
+    pub fn get_liquidation_fee(
+        &self,
+        market_liquidation_fee: I80F48,
+        init_asset_weight: I80F48,
+        init_liab_weight: I80F48,
+    ) -> I80F48 {
+        let min_fee = self.get_min_liquidation_fee();
+        if min_fee >= max_liquidation_fee(init_asset_weight, init_liab_weight) {
+            return market_liquidation_fee;
+        }
+        market_liquidation_fee.max(min_fee)
+    }
+
+    pub fn get_spot_liquidation_fee(&self, market_index: usize) -> I80F48 {
+        let info = &self.spot_markets[market_index];
+        let (init_asset_weight, init_liab_weight) = (info.init_asset_weight, info.init_liab_weight);
+        self.get_liquidation_fee(info.liquidation_fee, init_asset_weight, init_liab_weight)
+    }
+
+    pub fn get_perp_liquidation_fee(&self, market_index: usize) -> I80F48 {
+        let info = &self.perp_markets[market_index];
+        let (init_asset_weight, init_liab_weight) = (info.init_asset_weight, info.init_liab_weight);
+        self.get_liquidation_fee(info.liquidation_fee, init_asset_weight, init_liab_weight)
+    }
+
+    /// Check that a liquidation fee floor leaves room in the init weights of every listed market
+    pub fn is_valid_min_liquidation_fee(&self, min_fee: I80F48) -> bool {
+        if min_fee.is_zero() {
+            return true;
+        }
+        (0..self.num_oracles).all(|i| {
+            let spot = &self.spot_markets[i];
+            let perp = &self.perp_markets[i];
+            let spot_ok = spot.is_empty()
+                || min_fee < max_liquidation_fee(spot.init_asset_weight, spot.init_liab_weight);
+            let perp_ok = perp.is_empty()
+                || min_fee < max_liquidation_fee(perp.init_asset_weight, perp.init_liab_weight);
+            spot_ok && perp_ok
+        })
+    }
+
     pub fn get_token_asset_weight(&self, token_index: usize, health_type: HealthType) -> I80F48 {
         if token_index == QUOTE_INDEX {
             ONE_I80F48
@@ -316,7 +493,11 @@ pub struct RootBank {
     pub borrow_index: I80F48,
     pub last_updated: u64,
 
-    padding: [u8; 64], // used for future expansions
+    // fees taken on deposits and withdrawals of this token; 0 disables
+    pub deposit_fee_bps: u16,
+    pub withdraw_fee_bps: u16,
+
+    padding: [u8; 60], // used for future expansions
 }
 
 impl RootBank {
@@ -346,6 +527,11 @@ impl RootBank {
         root_bank.set_rate_params(optimal_util, optimal_rate, max_rate)?;
         Ok(root_bank)
     }
+    /// Fee in native units on a deposit (or withdrawal) of `quantity`, rounded down
+    pub fn get_fee(quantity: u64, fee_bps: u16) -> u64 {
+        (quantity as u128 * fee_bps as u128 / BPS_PER_UNIT as u128) as u64
+    }
+
     pub fn set_rate_params(
         &mut self,
         optimal_util: I80F48,
@@ -619,16 +805,16 @@ impl NodeBank {
 
     // TODO - Add checks to these math methods to prevent result from being < 0
     pub fn checked_add_borrow(&mut self, v: I80F48) -> LyraeResult<()> {
-        Ok(self.borrows = self.borrows.checked_add(v).ok_or(throw!())?)
+        Ok(self.borrows = self.borrows.checked_add(v).ok_or(math_err!())?)
     }
     pub fn checked_sub_borrow(&mut self, v: I80F48) -> LyraeResult<()> {
-        Ok(self.borrows = self.borrows.checked_sub(v).ok_or(throw!())?)
+        Ok(self.borrows = self.borrows.checked_sub(v).ok_or(math_err!())?)
     }
     pub fn checked_add_deposit(&mut self, v: I80F48) -> LyraeResult<()> {
-        Ok(self.deposits = self.deposits.checked_add(v).ok_or(throw!())?)
+        Ok(self.deposits = self.deposits.checked_add(v).ok_or(math_err!())?)
     }
     pub fn checked_sub_deposit(&mut self, v: I80F48) -> LyraeResult<()> {
-        Ok(self.deposits = self.deposits.checked_sub(v).ok_or(throw!())?)
+        Ok(self.deposits = self.deposits.checked_sub(v).ok_or(math_err!())?)
     }
     pub fn has_valid_deposits_borrows(&self, root_bank_cache: &RootBankCache) -> bool {
         self.get_total_native_deposit(root_bank_cache)
@@ -653,6 +839,8 @@ pub struct PriceCache {
 
 impl PriceCache {
     pub fn check_valid(&self, lyrae_group: &LyraeGroup, now_ts: u64) -> LyraeResult<()> {
+        // Distinguish a market whose oracle was added but never cached from a stale price
+        check!(self.last_update != 0, LyraeErrorCode::PriceNotCached)?;
         check!(
             self.last_update >= now_ts - lyrae_group.valid_interval,
             LyraeErrorCode::InvalidPriceCache
@@ -874,11 +1062,15 @@ impl HealthCache {
         self.quote = lyrae_account.get_net(&lyrae_cache.root_bank_cache[QUOTE_INDEX], QUOTE_INDEX);
         for i in 0..lyrae_group.num_oracles {
             if self.active_assets.spot[i] {
+                // get_spot_val ignores open orders outside the margin basket, so don't pay the
+                // compute to load them
                 self.spot[i] = lyrae_account.get_spot_val(
                     &lyrae_cache.root_bank_cache[i],
                     lyrae_cache.price_cache[i].price,
                     i,
-                    &if *open_orders_ais[i].key == Pubkey::default() {
+                    &if !lyrae_account.in_margin_basket[i]
+                        || *open_orders_ais[i].key == Pubkey::default()
+                    {
                         None
                     } else {
                         Some(load_open_orders(&open_orders_ais[i])?)
@@ -979,6 +1171,37 @@ impl HealthCache {
         }
     }
 
+    /// Value of the account's assets minus its liabilities, without any weighting
+    pub fn get_equity(&self, lyrae_group: &LyraeGroup) -> I80F48 {
+        let mut equity = self.quote;
+        for i in 0..lyrae_group.num_oracles {
+            if self.active_assets.spot[i] {
+                let (base, quote) = self.spot[i];
+                equity += base + quote;
+            }
+            if self.active_assets.perps[i] {
+                let (base, quote) = self.perp[i];
+                equity += base + quote;
+            }
+        }
+        equity
+    }
+
+    /// Get the Init health used as the pre-health of trading instructions and clear the
+    /// account's being_liquidated flag if it is non-negative.
+    /// Returns the Init health and whether the account is still being liquidated.
+    pub fn get_pre_health_and_update_being_liquidated(
+        &mut self,
+        lyrae_group: &LyraeGroup,
+        lyrae_account: &mut LyraeAccount,
+    ) -> (I80F48, bool) {
+        let pre_health = self.get_health(lyrae_group, HealthType::Init);
+        if lyrae_account.being_liquidated && pre_health >= ZERO_I80F48 {
+            lyrae_account.being_liquidated = false;
+        }
+        (pre_health, lyrae_account.being_liquidated)
+    }
+
     #[cfg(feature = "client")]
     pub fn get_health_components(
         &mut self,
@@ -1128,6 +1351,58 @@ impl HealthCache {
         }
     }
 
+    /// Simulate health after adding `native_change` (negative for a withdrawal) to the
+    /// net position of a token
+    pub fn get_health_after_sim_token(
+        &self,
+        lyrae_group: &LyraeGroup,
+        lyrae_cache: &LyraeCache,
+        lyrae_account: &LyraeAccount,
+        open_orders_ais: &[AccountInfo; MAX_PAIRS],
+        token_index: usize,
+        health_type: HealthType,
+        native_change: I80F48,
+    ) -> LyraeResult<I80F48> {
+        let h = self.health[health_type as usize].ok_or(throw!())?;
+        if token_index == QUOTE_INDEX {
+            return Ok(h + native_change);
+        }
+
+        let open_orders_ai = &open_orders_ais[token_index];
+        let (base, quote) = lyrae_account.sim_get_spot_val(
+            &lyrae_cache.root_bank_cache[token_index],
+            lyrae_cache.price_cache[token_index].price,
+            token_index,
+            &if *open_orders_ai.key == Pubkey::default() {
+                None
+            } else {
+                Some(load_open_orders(open_orders_ai)?)
+            },
+            native_change,
+        )?;
+
+        let (prev_base, prev_quote) = self.spot[token_index];
+        let smi = &lyrae_group.spot_markets[token_index];
+        let (asset_weight, liab_weight) = match health_type {
+            HealthType::Maint => (smi.maint_asset_weight, smi.maint_liab_weight),
+            HealthType::Init => (smi.init_asset_weight, smi.init_liab_weight),
+        };
+
+        let prev_spot_health = if prev_base.is_negative() {
+            prev_base * liab_weight + prev_quote
+        } else {
+            prev_base * asset_weight + prev_quote
+        };
+
+        let curr_spot_health = if base.is_negative() {
+            base * liab_weight + quote
+        } else {
+            base * asset_weight + quote
+        };
+
+        Ok(h + curr_spot_health - prev_spot_health)
+    }
+
     /// Simulate health after changes to taker base, taker quote, bids quantity and asks quantity
     pub fn get_health_after_sim_perp(
         &self,
@@ -1176,7 +1451,7 @@ impl HealthCache {
 
         let h = self.health[health_type as usize].ok_or(throw!())?;
 
-        // Apply taker fees; Assume no referrer
+        // Apply taker fees; Assume no referrer and no msrm fee tier discount
         let taker_fees = if taker_quote != 0 {
             let taker_quote_native =
                 I80F48::from_num(info.quote_lot_size.checked_mul(taker_quote.abs()).unwrap());
@@ -1185,7 +1460,7 @@ impl HealthCache {
                 let lyr_cache = &lyrae_cache.root_bank_cache[lyr_index];
                 let lyr_deposits = lyrae_account.get_native_deposit(lyr_cache, lyr_index)?;
                 let ref_lyr_req = I80F48::from_num(lyrae_group.ref_lyr_required);
-                if lyr_deposits < ref_lyr_req {
+                if lyr_deposits < ref_lyr_req && !lyrae_account.is_mm_exempt() {
                     market_fees += (I80F48::from_num(lyrae_group.ref_surcharge_centibps)
                         / CENTIBPS_PER_UNIT)
                         * taker_quote_native;
@@ -1289,11 +1564,17 @@ pub struct LyraeAccount {
     // Alternative authority/signer of transactions for a lyrae account
     pub delegate: Pubkey,
 
-    /// padding for expansions
-    /// Note: future expansion can also be just done via isolated PDAs
-    /// which can be computed independently and dont need to be linked from
-    /// this account
-    pub padding: [u8; 5],
+    /// Unix timestamp, in seconds, of the last deposit, withdraw or order placement; use the
+    /// accessors, which take and return a u64. Only 5 bytes of padding were left, so rather than a
+    /// u64 it is kept as a u32 LE, which lasts until 2106, and as bytes because that padding
+    /// is not aligned. Zero means the account has not been active since this field was
+    /// introduced; UpgradeLyraeAccountV0V1 also sets it.
+    pub last_activity_ts: [u8; 4],
+
+    /// Opt in to crank_auto_settle_funding settling positive perp pnl into quote deposits
+    /// Note: this used the last byte of padding; future expansion must be done via isolated PDAs
+    /// which can be computed independently and dont need to be linked from this account
+    pub auto_settle_funding: bool,
 }
 
 impl LyraeAccount {
@@ -1324,6 +1605,46 @@ impl LyraeAccount {
 
         Ok(lyrae_account)
     }
+
+    pub fn get_last_activity_ts(&self) -> u64 {
+        u32::from_le_bytes(self.last_activity_ts) as u64
+    }
+
+    pub fn set_last_activity_ts(&mut self, now_ts: u64) {
+        // u32 seconds is good until 2106; saturate rather than wrap
+        self.last_activity_ts = (now_ts.min(u32::MAX as u64) as u32).to_le_bytes();
+    }
+
+    /// True if the admin exempted this account from the referral surcharge on taker fees.
+    /// LyraeAccount has no padding left for it, so it is kept in meta_data.extra_info[0].
+    pub fn is_mm_exempt(&self) -> bool {
+        self.meta_data.extra_info[0] != 0
+    }
+
+    pub fn set_mm_exempt(&mut self, mm_exempt: bool) {
+        self.meta_data.extra_info[0] = mm_exempt as u8;
+    }
+
+    /// DELEGATE_* mask of what the delegate may do. Kept in meta_data.extra_info[1]; 0 is what
+    /// accounts whose delegate was set before permissions existed hold, and means DELEGATE_ALL.
+    pub fn get_delegate_permissions(&self) -> u8 {
+        match self.meta_data.extra_info[1] {
+            0 => DELEGATE_ALL,
+            permissions => permissions,
+        }
+    }
+
+    pub fn set_delegate_permissions(&mut self, permissions: u8) {
+        self.meta_data.extra_info[1] = permissions;
+    }
+
+    /// True if `signer` is the owner, or the delegate with all of the `permissions`
+    pub fn is_owner_or_delegate(&self, signer: &Pubkey, permissions: u8) -> bool {
+        &self.owner == signer
+            || (&self.delegate == signer
+                && self.get_delegate_permissions() & permissions == permissions)
+    }
+
     pub fn load_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
@@ -1442,7 +1763,19 @@ impl LyraeAccount {
         market_index: usize,
         open_orders: &Option<T>,
     ) -> LyraeResult<(I80F48, I80F48)> {
-        let base_net = self.get_net(bank_cache, market_index);
+        self.sim_get_spot_val(bank_cache, price, market_index, open_orders, ZERO_I80F48)
+    }
+
+    /// Same as `get_spot_val` but as if `native_change` had been added to the net position
+    fn sim_get_spot_val<T: Deref<Target = serum_dex::state::OpenOrders>>(
+        &self,
+        bank_cache: &RootBankCache,
+        price: I80F48,
+        market_index: usize,
+        open_orders: &Option<T>,
+        native_change: I80F48,
+    ) -> LyraeResult<(I80F48, I80F48)> {
+        let base_net = self.get_net(bank_cache, market_index) + native_change;
         if !self.in_margin_basket[market_index] || open_orders.is_none() {
             Ok((base_net * price, ZERO_I80F48))
         } else {
@@ -1491,6 +1824,7 @@ impl LyraeAccount {
     /// Add a market to margin basket
     /// This function should be called any time you place a spot order
     pub fn add_to_basket(&mut self, market_index: usize) -> LyraeResult<()> {
+        check!(market_index < MAX_PAIRS, LyraeErrorCode::InvalidMarket)?;
         if self.num_in_margin_basket == MAX_NUM_IN_MARGIN_BASKET {
             check!(
                 self.in_margin_basket[market_index],
@@ -1505,6 +1839,26 @@ impl LyraeAccount {
         }
     }
 
+    /// Verify `num_in_margin_basket` agrees with the `in_margin_basket` flags and that every
+    /// market in the basket has an OpenOrders account
+    pub fn check_basket_consistency(&self, lyrae_group: &LyraeGroup) -> LyraeResult {
+        let mut num_in_basket = 0u8;
+        for i in 0..lyrae_group.num_oracles {
+            if self.in_margin_basket[i] {
+                check!(
+                    self.spot_open_orders[i] != Pubkey::default(),
+                    LyraeErrorCode::InvalidAccountState
+                )?;
+                num_in_basket += 1;
+            }
+        }
+        check!(
+            num_in_basket == self.num_in_margin_basket
+                && num_in_basket <= MAX_NUM_IN_MARGIN_BASKET,
+            LyraeErrorCode::InvalidAccountState
+        )
+    }
+
     /// Determine if margin basket should be updated.
     /// This function should be called any time you settle funds on serum dex
     pub fn update_basket(
@@ -1512,6 +1866,7 @@ impl LyraeAccount {
         market_index: usize,
         open_orders: &serum_dex::state::OpenOrders,
     ) -> LyraeResult {
+        check!(market_index < MAX_PAIRS, LyraeErrorCode::InvalidMarket)?;
         let is_empty = open_orders.native_pc_total == 0
             && open_orders.native_coin_total == 0
             && open_orders.referrer_rebates_accrued == 0
@@ -1863,6 +2218,30 @@ impl LyraeAccount {
         }
         Ok(base_pos)
     }
+
+    /// Largest absolute base position the account can reach on a perp market if all of its
+    /// resting orders on one side get filled
+    pub fn get_perp_exposure(&self, market_index: usize, base_pos: i64) -> i64 {
+        let pa = &self.perp_accounts[market_index];
+        max((base_pos + pa.bids_quantity).abs(), (base_pos - pa.asks_quantity).abs())
+    }
+
+    /// Clamp the quantity of a reduce-only order so that even if it and every resting order on
+    /// the same side get filled, `base_pos` can only move towards zero
+    pub fn get_reduce_only_quantity(
+        &self,
+        market_index: usize,
+        side: Side,
+        base_pos: i64,
+        quantity: i64,
+    ) -> i64 {
+        let pa = &self.perp_accounts[market_index];
+        let (reducible, resting) = match side {
+            Side::Bid => (-base_pos, pa.bids_quantity),
+            Side::Ask => (base_pos, pa.asks_quantity),
+        };
+        reducible.checked_sub(resting).unwrap().max(0).min(quantity)
+    }
 }
 
 #[derive(Copy, Clone, Pod)]
@@ -2352,8 +2731,22 @@ impl PerpMarket {
         const IMPACT_QUANTITY: i64 = 100;
 
         // Get current book price & compare it to index price
-        let bid = book.get_impact_price(Side::Bid, IMPACT_QUANTITY);
-        let ask = book.get_impact_price(Side::Ask, IMPACT_QUANTITY);
+        let impact_notional = book.bids.get_funding_impact_notional();
+        let (bid, ask) = if impact_notional == 0 {
+            (
+                book.get_impact_price(Side::Bid, IMPACT_QUANTITY),
+                book.get_impact_price(Side::Ask, IMPACT_QUANTITY),
+            )
+        } else {
+            let quote_decimals = lyrae_group.tokens[QUOTE_INDEX].decimals as u32;
+            let native_notional = (impact_notional as u128) * 10u128.pow(quote_decimals);
+            let quote_lots =
+                (native_notional / self.quote_lot_size as u128).min(i64::MAX as u128) as i64;
+            (
+                book.get_average_impact_price(Side::Bid, quote_lots),
+                book.get_average_impact_price(Side::Ask, quote_lots),
+            )
+        };
 
         const MAX_FUNDING: I80F48 = I80F48!(0.05);
         const MIN_FUNDING: I80F48 = I80F48!(-0.05);
@@ -2387,6 +2780,33 @@ impl PerpMarket {
         Ok(())
     }
 
+    /// Max distance in bps of an order's price from the oracle price; 0 means no limit.
+    /// PerpMarket has no padding left, so it is kept in meta_data.extra_info[2..4].
+    pub fn get_max_order_price_deviation_bps(&self) -> u16 {
+        u16::from_le_bytes([self.meta_data.extra_info[2], self.meta_data.extra_info[3]])
+    }
+
+    pub fn set_max_order_price_deviation_bps(&mut self, bps: u16) {
+        self.meta_data.extra_info[2..4].copy_from_slice(&bps.to_le_bytes());
+    }
+
+    /// Reject orders with a limit price too far from the oracle price; market orders have none
+    pub fn check_order_price(
+        &self,
+        price: i64,
+        order_type: OrderType,
+        oracle_price: I80F48,
+    ) -> LyraeResult<()> {
+        let max_deviation_bps = self.get_max_order_price_deviation_bps();
+        if max_deviation_bps == 0 || order_type == OrderType::Market {
+            return Ok(());
+        }
+        let deviation = (self.lot_to_native_price(price) - oracle_price).abs();
+        let max_deviation =
+            oracle_price * I80F48::from_num(max_deviation_bps) / I80F48::from_num(BPS_PER_UNIT);
+        check!(deviation <= max_deviation, LyraeErrorCode::OrderPriceTooFarFromOracle)
+    }
+
     /// Convert from the price stored on the book to the price used in value calculations
     pub fn lot_to_native_price(&self, price: i64) -> I80F48 {
         I80F48::from_num(price)
@@ -2597,6 +3017,16 @@ pub enum AdvancedOrderType {
 pub enum TriggerCondition {
     Above,
     Below,
+    /// Like Below, but the trigger price follows the oracle up to trail_offset below its high
+    TrailingBelow,
+    /// Like Above, but the trigger price follows the oracle down to trail_offset above its low
+    TrailingAbove,
+}
+
+impl TriggerCondition {
+    pub fn is_trailing(&self) -> bool {
+        matches!(self, TriggerCondition::TrailingBelow | TriggerCondition::TrailingAbove)
+    }
 }
 
 const ADVANCED_ORDER_SIZE: usize = size_of::<PerpTriggerOrder>();
@@ -2623,10 +3053,11 @@ pub struct PerpTriggerOrder {
     pub client_order_id: u64,
     pub price: i64,
     pub quantity: i64,
-    pub trigger_price: I80F48,
+    pub trigger_price: I80F48, // moved by each execution attempt for trailing conditions
+    pub trail_offset: I80F48,  // 0 unless the trigger condition is trailing
 
     /// Padding for expansion
-    pub padding1: [u8; 32],
+    pub padding1: [u8; 16],
 }
 
 impl PerpTriggerOrder {
@@ -2640,6 +3071,7 @@ impl PerpTriggerOrder {
         price: i64,
         quantity: i64,
         trigger_price: I80F48,
+        trail_offset: I80F48,
     ) -> Self {
         Self {
             advanced_order_type: AdvancedOrderType::PerpTrigger,
@@ -2654,7 +3086,25 @@ impl PerpTriggerOrder {
             price,
             quantity,
             trigger_price,
-            padding1: [0u8; 32],
+            trail_offset,
+            padding1: [0u8; 16],
+        }
+    }
+
+    /// Move the trigger price of a trailing order along with the oracle price, then return
+    /// whether the trigger condition is met at that price
+    pub fn update_and_check_trigger(&mut self, price: I80F48) -> bool {
+        match self.trigger_condition {
+            TriggerCondition::Above => price >= self.trigger_price,
+            TriggerCondition::Below => price <= self.trigger_price,
+            TriggerCondition::TrailingBelow => {
+                self.trigger_price = self.trigger_price.max(price - self.trail_offset);
+                price <= self.trigger_price
+            }
+            TriggerCondition::TrailingAbove => {
+                self.trigger_price = self.trigger_price.min(price + self.trail_offset);
+                price >= self.trigger_price
+            }
         }
     }
 }
@@ -2687,6 +3137,8 @@ impl AdvancedOrders {
         Ok(())
     }
 
+    /// Also checks that `account` is the AdvancedOrders registered on `lyrae_account`, so callers
+    /// can't substitute another user's AdvancedOrders
     pub fn load_mut_checked<'a>(
         account: &'a AccountInfo,
         program_id: &Pubkey,
@@ -2708,6 +3160,28 @@ impl AdvancedOrders {
         )?;
         Ok(state)
     }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_account: &LyraeAccount,
+    ) -> LyraeResult<Ref<'a, Self>> {
+        let state: Ref<'a, Self> = Self::load(account)?;
+        check!(account.owner == program_id, LyraeErrorCode::InvalidOwner)?;
+        check!(
+            state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            state.meta_data.data_type == DataType::AdvancedOrders as u8,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            &lyrae_account.advanced_orders_key == account.key,
+            LyraeErrorCode::InvalidAccount
+        )?;
+        Ok(state)
+    }
 }
 
 /// Store the referrer's lyrae account
@@ -2756,6 +3230,161 @@ impl ReferrerMemory {
 
         Ok(state)
     }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+    ) -> LyraeResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, LyraeErrorCode::InvalidOwner)?;
+
+        let state: Ref<'a, Self> = Self::load(account)?;
+
+        check!(
+            state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            state.meta_data.data_type == DataType::ReferrerMemory as u8,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+
+        Ok(state)
+    }
+}
+
+/// Dead man's switch for market makers; a PDA of the LyraeAccount. If the owner doesn't send a
+/// heartbeat for `cancel_ttl_secs`, anyone can cancel the account's resting perp orders.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct CancelTtl {
+    pub meta_data: MetaData,
+    pub lyrae_account: Pubkey,
+    /// 0 disables the switch
+    pub cancel_ttl_secs: u64,
+    pub last_heartbeat_ts: u64,
+}
+
+impl CancelTtl {
+    pub fn init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_account_ai: &AccountInfo,
+    ) -> LyraeResult<RefMut<'a, Self>> {
+        let mut state: RefMut<'a, Self> = Self::load_mut(account)?;
+        check!(account.owner == program_id, LyraeErrorCode::InvalidOwner)?;
+        check!(
+            !state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+
+        state.meta_data = MetaData::new(DataType::CancelTtl, 0, true);
+        state.lyrae_account = *lyrae_account_ai.key;
+
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_account_pk: &Pubkey,
+    ) -> LyraeResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, LyraeErrorCode::InvalidOwner)?;
+
+        let state: RefMut<'a, Self> = Self::load_mut(account)?;
+
+        check!(
+            state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            state.meta_data.data_type == DataType::CancelTtl as u8,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(&state.lyrae_account == lyrae_account_pk, LyraeErrorCode::InvalidAccount)?;
+
+        Ok(state)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_account_pk: &Pubkey,
+    ) -> LyraeResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, LyraeErrorCode::InvalidOwner)?;
+
+        let state: Ref<'a, Self> = Self::load(account)?;
+
+        check!(
+            state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            state.meta_data.data_type == DataType::CancelTtl as u8,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(&state.lyrae_account == lyrae_account_pk, LyraeErrorCode::InvalidAccount)?;
+
+        Ok(state)
+    }
+
+    pub fn is_expired(&self, now_ts: u64) -> bool {
+        self.cancel_ttl_secs != 0
+            && now_ts.saturating_sub(self.last_heartbeat_ts) > self.cancel_ttl_secs
+    }
+}
+
+/// UI grouping of LyraeAccounts under one wallet; a PDA of the LyraeAccount. Purely
+/// organizational: the parent's positions are never netted with this account's.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct AccountGroup {
+    pub meta_data: MetaData,
+    pub lyrae_account: Pubkey,
+    /// LyraeAccount of the same owner this account is grouped under; zero pubkey if none
+    pub parent_account: Pubkey,
+    pub account_label: [u8; 8],
+}
+
+impl AccountGroup {
+    pub fn init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_account_ai: &AccountInfo,
+    ) -> LyraeResult<RefMut<'a, Self>> {
+        let mut state: RefMut<'a, Self> = Self::load_mut(account)?;
+        check!(account.owner == program_id, LyraeErrorCode::InvalidOwner)?;
+        check!(
+            !state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+
+        state.meta_data = MetaData::new(DataType::AccountGroup, 0, true);
+        state.lyrae_account = *lyrae_account_ai.key;
+
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_account_pk: &Pubkey,
+    ) -> LyraeResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, LyraeErrorCode::InvalidOwner)?;
+
+        let state: RefMut<'a, Self> = Self::load_mut(account)?;
+
+        check!(
+            state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            state.meta_data.data_type == DataType::AccountGroup as u8,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(&state.lyrae_account == lyrae_account_pk, LyraeErrorCode::InvalidAccount)?;
+
+        Ok(state)
+    }
 }
 
 /// Register the referrer's id to be used in the URL
@@ -2788,3 +3417,330 @@ impl ReferrerIdRecord {
         Ok(())
     }
 }
+
+/// Perp taker fee discount tiers by the msrm_amount a LyraeAccount deposited via DepositMsrm; a
+/// PDA of the LyraeGroup with seeds [lyrae_group, "MsrmFeeTiers"], written only by
+/// SetMsrmFeeTier. Takers pass it with their perp orders to get the discount.
+#[derive(Copy, Clone, Pod, Loadable)]
+#[repr(C)]
+pub struct MsrmFeeTiers {
+    pub meta_data: MetaData,
+    pub lyrae_group: Pubkey,
+    pub min_msrm: [u64; MAX_MSRM_FEE_TIERS],
+    /// 0 disables the tier
+    pub discount_bps: [u16; MAX_MSRM_FEE_TIERS],
+    pub padding: [u8; 2],
+}
+
+impl MsrmFeeTiers {
+    pub fn init<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_group_pk: &Pubkey,
+    ) -> LyraeResult<RefMut<'a, Self>> {
+        let mut state: RefMut<'a, Self> = Self::load_mut(account)?;
+        check!(account.owner == program_id, LyraeErrorCode::InvalidOwner)?;
+        check!(
+            !state.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+
+        state.meta_data = MetaData::new(DataType::MsrmFeeTiers, 0, true);
+        state.lyrae_group = *lyrae_group_pk;
+
+        Ok(state)
+    }
+
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_group_pk: &Pubkey,
+    ) -> LyraeResult<RefMut<'a, Self>> {
+        check_eq!(account.owner, program_id, LyraeErrorCode::InvalidOwner)?;
+        check_eq!(account.data_len(), size_of::<Self>(), LyraeErrorCode::InvalidAccount)?;
+
+        let state: RefMut<'a, Self> = Self::load_mut(account)?;
+        state.check_valid(lyrae_group_pk)?;
+        Ok(state)
+    }
+
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        lyrae_group_pk: &Pubkey,
+    ) -> LyraeResult<Ref<'a, Self>> {
+        check_eq!(account.owner, program_id, LyraeErrorCode::InvalidOwner)?;
+        check_eq!(account.data_len(), size_of::<Self>(), LyraeErrorCode::InvalidAccount)?;
+
+        let state: Ref<'a, Self> = Self::load(account)?;
+        state.check_valid(lyrae_group_pk)?;
+        Ok(state)
+    }
+
+    fn check_valid(&self, lyrae_group_pk: &Pubkey) -> LyraeResult {
+        check!(
+            self.meta_data.is_initialized,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(
+            self.meta_data.data_type == DataType::MsrmFeeTiers as u8,
+            LyraeErrorCode::InvalidAccountState
+        )?;
+        check!(&self.lyrae_group == lyrae_group_pk, LyraeErrorCode::InvalidAccount)
+    }
+
+    /// Largest taker fee discount, in bps, of the tiers `msrm_amount` qualifies for
+    pub fn get_taker_fee_discount_bps(&self, msrm_amount: u64) -> u16 {
+        (0..MAX_MSRM_FEE_TIERS)
+            .filter(|&tier| msrm_amount >= self.min_msrm[tier])
+            .map(|tier| self.discount_bps[tier])
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+    use fixed_macro::types::I80F48;
+
+    fn group_with_markets() -> LyraeGroup {
+        let mut group = LyraeGroup::zeroed();
+        group.num_oracles = 1;
+        group.spot_markets[0] = SpotMarketInfo {
+            spot_market: Pubkey::new_unique(),
+            maint_asset_weight: I80F48!(0.9),
+            init_asset_weight: I80F48!(0.8),
+            maint_liab_weight: I80F48!(1.1),
+            init_liab_weight: I80F48!(1.2),
+            liquidation_fee: I80F48!(0.05),
+        };
+        group
+    }
+
+    #[test]
+    fn min_liquidation_fee_floors_market_fee() {
+        let mut group = group_with_markets();
+        assert_eq!(group.get_spot_liquidation_fee(0), I80F48!(0.05));
+
+        group.min_liquidation_fee_bps = 1000;
+        assert!(group.is_valid_min_liquidation_fee(group.get_min_liquidation_fee()));
+        assert_eq!(group.get_spot_liquidation_fee(0), group.get_min_liquidation_fee());
+    }
+
+    #[test]
+    fn min_liquidation_fee_is_clamped_to_init_weights() {
+        let mut group = group_with_markets();
+
+        // 1 - init_asset_weight == 0.2 leaves no room for a 20% floor
+        group.min_liquidation_fee_bps = 2000;
+        assert!(!group.is_valid_min_liquidation_fee(group.get_min_liquidation_fee()));
+        assert_eq!(group.get_spot_liquidation_fee(0), I80F48!(0.05));
+
+        // Markets that are not listed are not constrained
+        group.spot_markets[0].spot_market = Pubkey::default();
+        assert!(group.is_valid_min_liquidation_fee(group.get_min_liquidation_fee()));
+    }
+
+    #[test]
+    fn reduce_only_quantity_accounts_for_resting_orders() {
+        let mut lyrae_account = Box::new(LyraeAccount::zeroed());
+
+        // Long 10: asks reduce, bids never do
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Ask, 10, 4), 4);
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Ask, 10, 15), 10);
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Bid, 10, 4), 0);
+
+        // Resting asks already reduce part of the position
+        lyrae_account.perp_accounts[0].asks_quantity = 7;
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Ask, 10, 15), 3);
+        lyrae_account.perp_accounts[0].asks_quantity = 12;
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Ask, 10, 15), 0);
+
+        // Short 10: bids reduce
+        lyrae_account.perp_accounts[0].bids_quantity = 2;
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Bid, -10, 15), 8);
+        assert_eq!(lyrae_account.get_reduce_only_quantity(0, Side::Ask, -10, 15), 0);
+    }
+
+    #[test]
+    fn settled_fees_split_by_insurance_share() {
+        let mut group = LyraeGroup::zeroed();
+        assert_eq!(group.split_settled_fees(1_000), (1_000, 0));
+
+        group.fees_insurance_share_pct = 25;
+        assert_eq!(group.split_settled_fees(1_000), (750, 250));
+        // the insurance share is rounded down
+        assert_eq!(group.split_settled_fees(3), (3, 0));
+        assert_eq!(group.split_settled_fees(u64::MAX), (u64::MAX - u64::MAX / 4, u64::MAX / 4));
+
+        group.fees_insurance_share_pct = 100;
+        assert_eq!(group.split_settled_fees(1_000), (0, 1_000));
+    }
+
+    #[test]
+    fn trailing_trigger_follows_the_price() {
+        let trigger_order = |trigger_condition, trigger_price, trail_offset| {
+            PerpTriggerOrder::new(
+                0,
+                OrderType::Market,
+                Side::Ask,
+                trigger_condition,
+                true,
+                0,
+                1,
+                1,
+                trigger_price,
+                trail_offset,
+            )
+        };
+
+        let mut stop = trigger_order(TriggerCondition::Below, I80F48!(90), ZERO_I80F48);
+        assert!(!stop.update_and_check_trigger(I80F48!(95)));
+        assert!(stop.update_and_check_trigger(I80F48!(90)));
+        assert_eq!(stop.trigger_price, I80F48!(90));
+
+        // A trailing stop moves up with the price but never down
+        let mut trailing = trigger_order(TriggerCondition::TrailingBelow, I80F48!(90), I80F48!(10));
+        assert!(!trailing.update_and_check_trigger(I80F48!(120)));
+        assert_eq!(trailing.trigger_price, I80F48!(110));
+        assert!(!trailing.update_and_check_trigger(I80F48!(115)));
+        assert_eq!(trailing.trigger_price, I80F48!(110));
+        assert!(trailing.update_and_check_trigger(I80F48!(110)));
+
+        // and a trailing stop on a short moves down with the price but never up
+        let mut trailing =
+            trigger_order(TriggerCondition::TrailingAbove, I80F48!(110), I80F48!(10));
+        assert!(!trailing.update_and_check_trigger(I80F48!(80)));
+        assert_eq!(trailing.trigger_price, I80F48!(90));
+        assert!(!trailing.update_and_check_trigger(I80F48!(85)));
+        assert_eq!(trailing.trigger_price, I80F48!(90));
+        assert!(trailing.update_and_check_trigger(I80F48!(91)));
+    }
+
+    #[test]
+    fn never_cached_price_is_not_reported_as_stale() {
+        let mut group = LyraeGroup::zeroed();
+        group.valid_interval = 10;
+        let mut price_cache = PriceCache::zeroed();
+        let now_ts = 1_000;
+        let error_code = |price_cache: &PriceCache| match price_cache.check_valid(&group, now_ts) {
+            Err(LyraeError::LyraeErrorCode { lyrae_error_code, .. }) => Some(lyrae_error_code),
+            _ => None,
+        };
+
+        assert_eq!(error_code(&price_cache), Some(LyraeErrorCode::PriceNotCached));
+        price_cache.last_update = now_ts - 11;
+        assert_eq!(error_code(&price_cache), Some(LyraeErrorCode::InvalidPriceCache));
+        price_cache.last_update = now_ts - 10;
+        assert!(price_cache.check_valid(&group, now_ts).is_ok());
+    }
+
+    #[test]
+    fn basket_rejects_market_indexes_past_max_pairs() {
+        let mut lyrae_account = Box::new(LyraeAccount::zeroed());
+        let mut empty_open_orders = serum_dex::state::OpenOrders::zeroed();
+        empty_open_orders.free_slot_bits = u128::MAX;
+        let mut open_orders = empty_open_orders;
+        open_orders.native_coin_total = 1;
+        let is_invalid_market = |result: LyraeResult| {
+            matches!(
+                result,
+                Err(LyraeError::LyraeErrorCode {
+                    lyrae_error_code: LyraeErrorCode::InvalidMarket,
+                    ..
+                })
+            )
+        };
+
+        lyrae_account.add_to_basket(MAX_PAIRS - 1).unwrap();
+        assert!(lyrae_account.in_margin_basket[MAX_PAIRS - 1]);
+        assert!(is_invalid_market(lyrae_account.add_to_basket(MAX_PAIRS)));
+        assert!(is_invalid_market(lyrae_account.update_basket(MAX_PAIRS, &open_orders)));
+        assert_eq!(lyrae_account.num_in_margin_basket, 1);
+
+        lyrae_account.update_basket(MAX_PAIRS - 2, &open_orders).unwrap();
+        assert_eq!(lyrae_account.num_in_margin_basket, 2);
+        lyrae_account.update_basket(MAX_PAIRS - 1, &empty_open_orders).unwrap();
+        assert!(!lyrae_account.in_margin_basket[MAX_PAIRS - 1]);
+        assert_eq!(lyrae_account.num_in_margin_basket, 1);
+    }
+
+    #[test]
+    fn health_skips_open_orders_outside_the_margin_basket() {
+        let group = group_with_markets();
+        let mut cache = Box::new(LyraeCache::zeroed());
+        for i in [0, QUOTE_INDEX].iter() {
+            cache.root_bank_cache[*i].deposit_index = ONE_I80F48;
+            cache.root_bank_cache[*i].borrow_index = ONE_I80F48;
+        }
+        cache.price_cache[0].price = I80F48!(2);
+        let mut lyrae_account = Box::new(LyraeAccount::zeroed());
+        lyrae_account.deposits[0] = I80F48!(10);
+        lyrae_account.deposits[QUOTE_INDEX] = I80F48!(5);
+
+        // 5 + 10 * 2 * init_asset_weight, with no open orders accounts at all. Then the open
+        // orders account of market 0 is one that can't be loaded
+        let (owner, open_orders_pk) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (mut filler_lamports, mut open_orders_lamports) = (0, 0);
+        let (mut filler_data, mut open_orders_data) = ([0u8; 0], [0u8; 0]);
+        let filler_key = Pubkey::default();
+        let filler_ai = AccountInfo::new(
+            &filler_key,
+            false,
+            false,
+            &mut filler_lamports,
+            &mut filler_data,
+            &owner,
+            false,
+            0,
+        );
+        let mut open_orders_ais = vec![filler_ai; MAX_PAIRS];
+        let init_health = |lyrae_account: &LyraeAccount,
+                           open_orders_ais: &[AccountInfo]|
+         -> LyraeResult<I80F48> {
+            let open_orders_ais = <&[AccountInfo; MAX_PAIRS]>::try_from(open_orders_ais).unwrap();
+            let mut health_cache =
+                HealthCache::new(UserActiveAssets::new(&group, lyrae_account, vec![]));
+            health_cache.init_vals(&group, &cache, lyrae_account, open_orders_ais)?;
+            Ok(health_cache.get_health(&group, HealthType::Init))
+        };
+        let expected = init_health(&lyrae_account, &open_orders_ais).unwrap();
+        assert!((expected - I80F48!(21)).abs() < I80F48!(0.000001));
+
+        open_orders_ais[0] = AccountInfo::new(
+            &open_orders_pk,
+            false,
+            false,
+            &mut open_orders_lamports,
+            &mut open_orders_data,
+            &owner,
+            false,
+            0,
+        );
+        assert_eq!(init_health(&lyrae_account, &open_orders_ais).unwrap(), expected);
+
+        // It has to be loaded once the market is in the margin basket
+        lyrae_account.in_margin_basket[0] = true;
+        assert!(init_health(&lyrae_account, &open_orders_ais).is_err());
+    }
+
+    #[test]
+    fn spot_val_adds_referrer_rebates_without_overflow() {
+        let mut lyrae_account = Box::new(LyraeAccount::zeroed());
+        lyrae_account.in_margin_basket[0] = true;
+        let mut bank_cache = RootBankCache::zeroed();
+        bank_cache.deposit_index = ONE_I80F48;
+        bank_cache.borrow_index = ONE_I80F48;
+        let mut open_orders = serum_dex::state::OpenOrders::zeroed();
+        open_orders.native_pc_free = u64::MAX - 1;
+        open_orders.native_pc_total = u64::MAX - 1;
+        open_orders.referrer_rebates_accrued = 2;
+
+        let (base, quote) =
+            lyrae_account.get_spot_val(&bank_cache, ONE_I80F48, 0, &Some(&open_orders)).unwrap();
+        assert_eq!(base, ZERO_I80F48);
+        assert_eq!(quote, I80F48::from_num(u64::MAX) + ONE_I80F48);
+    }
+}
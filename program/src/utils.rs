@@ -14,7 +14,7 @@ use std::mem::size_of;
 
 use crate::state::{PerpAccount, PerpMarketCache};
 
-use lyrae_logs::{lyrae_emit, PerpBalanceLog};
+use lyrae_logs::{lyrae_emit, InsuranceFundDrainedLog, InsuranceFundLog, PerpBalanceLog};
 
 pub fn gen_signer_seeds<'a>(nonce: &'a u64, acc_pk: &'a Pubkey) -> [&'a [u8]; 2] {
     [acc_pk.as_ref(), bytes_of(nonce)]
@@ -68,7 +68,10 @@ pub fn split_open_orders(
     open_orders: &serum_dex::state::OpenOrders,
 ) -> (I80F48, I80F48, I80F48, I80F48) {
     (
-        I80F48::from_num(open_orders.native_pc_free + open_orders.referrer_rebates_accrued),
+        // u128 so the sum can't overflow for long lived open orders accounts
+        I80F48::from_num(
+            open_orders.native_pc_free as u128 + open_orders.referrer_rebates_accrued as u128,
+        ),
         I80F48::from_num(open_orders.native_pc_total - open_orders.native_pc_free),
         I80F48::from_num(open_orders.native_coin_free),
         I80F48::from_num(open_orders.native_coin_total - open_orders.native_coin_free),
@@ -109,3 +112,97 @@ pub fn emit_perp_balances(
         short_funding: perp_market_cache.short_funding.to_bits(),
     });
 }
+
+/// Log a change of the insurance vault balance and warn when it has been drained
+pub fn emit_insurance_fund_balance(
+    lyrae_group: Pubkey,
+    insurance_vault: Pubkey,
+    pre_balance: u64,
+    post_balance: u64,
+) {
+    lyrae_emit!(InsuranceFundLog { lyrae_group, insurance_vault, pre_balance, post_balance });
+    if post_balance == 0 {
+        msg!("Insurance fund drained; losses will be socialized");
+        lyrae_emit!(InsuranceFundDrainedLog { lyrae_group, insurance_vault });
+    }
+}
+
+/// Syscall stubs for unit tests: a fixed clock and rent, plus the return data and the events
+/// emitted on the calling thread, so handlers can be run off-chain
+#[cfg(test)]
+pub mod test_syscalls {
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    use anchor_lang::__private::base64;
+    use anchor_lang::{AnchorDeserialize, Discriminator};
+    use solana_program::clock::Clock;
+    use solana_program::entrypoint::SUCCESS;
+    use solana_program::program_stubs::{set_syscall_stubs, SyscallStubs};
+    use solana_program::pubkey::Pubkey;
+    use solana_program::rent::Rent;
+
+    pub const NOW_TS: u64 = 1_650_000_000;
+
+    thread_local! {
+        static RETURN_DATA: RefCell<Option<Vec<u8>>> = RefCell::new(None);
+        static EVENTS: RefCell<Vec<Vec<u8>>> = RefCell::new(vec![]);
+    }
+
+    struct TestSyscallStubs;
+
+    impl SyscallStubs for TestSyscallStubs {
+        fn sol_log(&self, message: &str) {
+            // Older anchor versions emit events as base64 log lines
+            if let Ok(data) = base64::decode(message) {
+                EVENTS.with(|events| events.borrow_mut().push(data));
+            }
+        }
+        fn sol_log_data(&self, fields: &[&[u8]]) {
+            EVENTS.with(|events| events.borrow_mut().extend(fields.iter().map(|f| f.to_vec())));
+        }
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock { unix_timestamp: NOW_TS as i64, ..Clock::default() };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            SUCCESS
+        }
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = Some(data.to_vec()));
+        }
+        fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+            RETURN_DATA.with(|return_data| {
+                return_data.borrow().clone().map(|data| (Pubkey::default(), data))
+            })
+        }
+    }
+
+    /// Install the stubs and clear what the calling thread has recorded so far
+    pub fn init() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            set_syscall_stubs(Box::new(TestSyscallStubs));
+        });
+        RETURN_DATA.with(|return_data| *return_data.borrow_mut() = None);
+        EVENTS.with(|events| events.borrow_mut().clear());
+    }
+
+    pub fn return_data() -> Option<Vec<u8>> {
+        RETURN_DATA.with(|return_data| return_data.borrow().clone())
+    }
+
+    /// The events of type `T` emitted on the calling thread since `init`
+    pub fn emitted<T: AnchorDeserialize + Discriminator>() -> Vec<T> {
+        EVENTS.with(|events| {
+            events
+                .borrow()
+                .iter()
+                .filter(|data| data.len() >= 8 && data[..8] == T::discriminator())
+                .map(|data| T::try_from_slice(&data[8..]).unwrap())
+                .collect()
+        })
+    }
+}